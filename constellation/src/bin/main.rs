@@ -1,21 +1,29 @@
 use anyhow::{bail, Result};
 use clap::{Parser, ValueEnum};
+use links::normalize::UrlNormalization;
 use metrics_exporter_prometheus::PrometheusBuilder;
+use microcosm_estimates::SketchSecret;
+use std::net::SocketAddr;
 use std::num::NonZero;
 use std::path::PathBuf;
 use std::sync::{atomic::AtomicU32, Arc};
 use std::thread;
 use std::time;
 use tokio::runtime;
+use tokio::sync::watch;
 use tokio_util::sync::CancellationToken;
 
-use constellation::consumer::consume;
-use constellation::server::serve;
+use constellation::consumer::{consume, CollectionFilter, ProcessedCursor};
+use constellation::server::{admin, serve};
 #[cfg(feature = "rocks")]
 use constellation::storage::RocksStorage;
-use constellation::storage::{LinkReader, LinkStorage, MemStorage, StorageStats};
+use constellation::storage::{
+    Aliased, LinkReader, LinkStorage, MemStorage, StorageStats, TargetAliases,
+};
 
 const MONITOR_INTERVAL: time::Duration = time::Duration::from_secs(15);
+#[cfg(feature = "rocks")]
+const REPLICA_CATCH_UP_INTERVAL: time::Duration = time::Duration::from_secs(1);
 
 /// Aggregate links in the at-mosphere
 #[derive(Parser, Debug)]
@@ -46,6 +54,67 @@ struct Args {
     /// Saved jsonl from jetstream to use instead of a live subscription
     #[arg(short, long)]
     fixture: Option<PathBuf>,
+    /// Number of threads writing to storage. Events are sharded between them by did
+    #[arg(long, default_value_t = 1)]
+    writers: usize,
+    /// Serve reads from a replica of the rocksdb at --data instead of consuming jetstream.
+    /// Another process must be running as the primary. The replica keeps its own state in this dir
+    #[cfg(feature = "rocks")]
+    #[arg(long)]
+    replica: Option<PathBuf>,
+    /// Secret (32 hex chars) for distinct-dids sketches, enabling `sketch=true` on
+    /// /links/count/distinct-dids. Use the same one as ufos' --sketch-secret to get
+    /// sketches that can be merged with its estimates
+    #[arg(long, value_parser = microcosm_estimates::parse_secret)]
+    sketch_secret: Option<SketchSecret>,
+    /// Serve the admin API (target aliases) at this address, eg. 127.0.0.1:6790
+    ///
+    /// The admin API is unauthenticated, so keep it somewhere private. Disabled if omitted.
+    #[arg(long)]
+    admin_listen: Option<SocketAddr>,
+    /// Only index links from these collections, comma-separated. A trailing `*`
+    /// matches by prefix, eg. `app.bsky.feed.*`. Changing this only affects
+    /// events indexed afterwards
+    #[arg(long, value_delimiter = ',')]
+    only_collections: Vec<String>,
+    /// Never index links from these collections, comma-separated (same patterns as
+    /// --only-collections), eg. `app.bsky.graph.follow`
+    #[arg(long, value_delimiter = ',')]
+    skip_collections: Vec<String>,
+    /// Query params to strip from url link targets, comma-separated. A trailing `*`
+    /// matches by prefix, eg. `utm_*`. Changing url normalization only affects
+    /// links indexed afterwards
+    #[arg(long, value_delimiter = ',')]
+    url_strip_params: Vec<String>,
+    /// Strip common tracking params (utm_*, fbclid, gclid, ...) and default ports
+    /// from url link targets
+    #[arg(long, action)]
+    url_strip_tracking: bool,
+    /// Strip #fragments from url link targets
+    #[arg(long, action)]
+    url_strip_fragment: bool,
+    /// Convert internationalized hostnames of url link targets to punycode
+    #[cfg(feature = "punycode")]
+    #[arg(long, action)]
+    url_punycode: bool,
+}
+
+impl Args {
+    fn url_normalization(&self) -> UrlNormalization {
+        let mut urls = if self.url_strip_tracking {
+            UrlNormalization::tracking()
+        } else {
+            UrlNormalization::default()
+        };
+        urls.strip_params
+            .extend(self.url_strip_params.iter().cloned());
+        urls.strip_fragment = self.url_strip_fragment;
+        #[cfg(feature = "punycode")]
+        {
+            urls.punycode = self.url_punycode;
+        }
+        urls
+    }
 }
 
 #[derive(Debug, Clone, ValueEnum)]
@@ -67,9 +136,26 @@ fn jetstream_url(provided: &str) -> String {
 
 fn main() -> Result<()> {
     let args = Args::parse();
+    let urls = args.url_normalization();
+    if !urls.is_noop() {
+        println!("normalizing url targets: {urls:?}");
+    }
+    let serving = Serving {
+        sketch_secret: args.sketch_secret,
+        urls,
+        admin_listen: args.admin_listen,
+    };
 
     println!("starting with storage backend: {:?}...", args.backend);
 
+    let collections = CollectionFilter {
+        only: args.only_collections,
+        skip: args.skip_collections,
+    };
+    if !collections.only.is_empty() || !collections.skip.is_empty() {
+        println!("filtering collections: {collections:?}");
+    }
+
     let fixture = args.fixture;
     if let Some(ref p) = fixture {
         println!("using fixture at {p:?}...");
@@ -81,7 +167,33 @@ fn main() -> Result<()> {
     let stay_alive = CancellationToken::new();
 
     match args.backend {
-        StorageBackend::Memory => run(MemStorage::new(), fixture, None, stream, stay_alive),
+        StorageBackend::Memory => ingest(
+            MemStorage::new(),
+            fixture,
+            None,
+            stream,
+            args.writers,
+            collections,
+            serving,
+            stay_alive,
+        ),
+        #[cfg(feature = "rocks")]
+        StorageBackend::Rocks if args.replica.is_some() => {
+            let Some(primary_dir) = args.data else {
+                bail!("--replica needs --data pointing at the primary's rocksdb");
+            };
+            let replica_dir = args.replica.unwrap();
+            println!("starting rocksdb replica of {primary_dir:?} in {replica_dir:?}...");
+            let replica = RocksStorage::open_secondary(&primary_dir, replica_dir)?;
+            println!("rocks replica ready.");
+            run(
+                replica.clone(),
+                move |processed, staying_alive| follow_primary(replica, processed, staying_alive),
+                Some(primary_dir),
+                serving,
+                stay_alive,
+            )
+        }
         #[cfg(feature = "rocks")]
         StorageBackend::Rocks => {
             let storage_dir = args.data.clone().unwrap_or("rocks.test".into());
@@ -96,16 +208,84 @@ fn main() -> Result<()> {
                 rocks.start_backup(backup_dir, auto_backup, stay_alive.clone())?;
             }
             println!("rocks ready.");
-            run(rocks, fixture, args.data, stream, stay_alive)
+            ingest(
+                rocks,
+                fixture,
+                args.data,
+                stream,
+                args.writers,
+                collections,
+                serving,
+                stay_alive,
+            )
         }
     }
 }
 
-fn run(
-    mut storage: impl LinkStorage,
+/// how the apis should serve the storage
+struct Serving {
+    sketch_secret: Option<SketchSecret>,
+    urls: UrlNormalization,
+    admin_listen: Option<SocketAddr>,
+}
+
+/// consume jetstream into the storage while serving it
+#[allow(clippy::too_many_arguments)]
+fn ingest(
+    mut storage: impl LinkStorage + 'static,
     fixture: Option<PathBuf>,
     data_dir: Option<PathBuf>,
     stream: String,
+    writers: usize,
+    collections: CollectionFilter,
+    serving: Serving,
+    stay_alive: CancellationToken,
+) -> Result<()> {
+    let readable = storage.to_readable();
+    let urls = serving.urls.clone();
+    run(
+        readable,
+        move |processed, staying_alive| {
+            let qsize = Arc::new(AtomicU32::new(0));
+            consume(
+                storage,
+                qsize,
+                fixture,
+                stream,
+                staying_alive,
+                processed,
+                writers,
+                urls,
+                collections,
+            )
+        },
+        data_dir,
+        serving,
+        stay_alive,
+    )
+}
+
+/// keep a replica up to date with whatever the primary has written so far
+#[cfg(feature = "rocks")]
+fn follow_primary(
+    replica: RocksStorage,
+    processed: watch::Sender<Option<u64>>,
+    stay_alive: CancellationToken,
+) -> Result<()> {
+    while !stay_alive.is_cancelled() {
+        if let Some(cursor) = replica.catch_up_with_primary()? {
+            processed.send_replace(Some(cursor));
+        }
+        thread::sleep(REPLICA_CATCH_UP_INTERVAL);
+    }
+    Ok(())
+}
+
+fn run(
+    readable: impl LinkReader,
+    ingest: impl FnOnce(watch::Sender<Option<u64>>, CancellationToken) -> Result<()> + Send,
+    data_dir: Option<PathBuf>,
+    serving: Serving,
     stay_alive: CancellationToken,
 ) -> Result<()> {
     ctrlc::set_handler({
@@ -121,18 +301,28 @@ fn run(
         }
     })?;
 
-    let qsize = Arc::new(AtomicU32::new(0));
+    let (processed_sender, processed) = ProcessedCursor::channel();
 
-    thread::scope(|s| {
-        let readable = storage.to_readable();
+    let Serving {
+        sketch_secret,
+        urls,
+        admin_listen,
+    } = serving;
+    let aliases = TargetAliases::load(&readable)?;
+    if !aliases.list().is_empty() {
+        println!(
+            "consolidating counts over {} target aliases",
+            aliases.list().len()
+        );
+    }
 
+    thread::scope(|s| {
         s.spawn({
-            let qsize = qsize.clone();
             let stay_alive = stay_alive.clone();
             let staying_alive = stay_alive.clone();
             move || {
-                if let Err(e) = consume(storage, qsize, fixture, stream, staying_alive) {
-                    eprintln!("jetstream finished with error: {e}");
+                if let Err(e) = ingest(processed_sender, staying_alive) {
+                    eprintln!("ingest finished with error: {e}");
                 }
                 stay_alive.drop_guard();
             }
@@ -151,7 +341,29 @@ fn run(
                     .expect("axum startup")
                     .block_on(async {
                         install_metrics_server()?;
-                        serve(readable, "0.0.0.0:6789", staying_alive).await
+                        if let Some(addr) = admin_listen {
+                            let admin_serving = admin::serve(
+                                readable.clone(),
+                                aliases.clone(),
+                                urls.clone(),
+                                addr,
+                                staying_alive.clone(),
+                            );
+                            tokio::spawn(async move {
+                                if let Err(e) = admin_serving.await {
+                                    eprintln!("admin api ended: {e}");
+                                }
+                            });
+                        }
+                        serve(
+                            Aliased::new(readable, aliases),
+                            "0.0.0.0:6789",
+                            processed,
+                            sketch_secret,
+                            urls,
+                            staying_alive,
+                        )
+                        .await
                     })
                     .unwrap();
                 stay_alive.drop_guard();
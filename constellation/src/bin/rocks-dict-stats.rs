@@ -0,0 +1,103 @@
+use anyhow::Result;
+use clap::Parser;
+use rocksdb::IteratorMode;
+use std::path::PathBuf;
+
+use constellation::storage::RocksStorage;
+
+/// Estimate how much a trained zstd dictionary shrinks target_links values
+///
+/// Values are compressed in rocksdb-sized blocks, like they are in ssts, with
+/// and without a dictionary trained on a separate set of samples.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// where is rocksdb's data
+    #[arg(short, long)]
+    data: PathBuf,
+    /// how many values to sample (half train the dictionary, half are measured)
+    #[arg(short, long, default_value_t = 200_000)]
+    samples: usize,
+    /// dictionary size in bytes
+    #[arg(long, default_value_t = 16 * 1024)]
+    dict_bytes: usize,
+    /// zstd compression level
+    #[arg(long, default_value_t = 3)]
+    level: i32,
+}
+
+static TARGET_LINKERS_CF: &str = "target_links";
+
+const BLOCK_SIZE: usize = 4 * 1024; // rocksdb's default
+
+fn blocks(values: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    let mut blocks = vec![];
+    let mut block = Vec::with_capacity(BLOCK_SIZE);
+    for v in values {
+        block.extend_from_slice(v);
+        if block.len() >= BLOCK_SIZE {
+            blocks.push(std::mem::replace(
+                &mut block,
+                Vec::with_capacity(BLOCK_SIZE),
+            ));
+        }
+    }
+    if !block.is_empty() {
+        blocks.push(block);
+    }
+    blocks
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    eprintln!("starting rocksdb...");
+    let rocks = RocksStorage::open_readonly(args.data)?;
+    eprintln!("rocks ready.");
+
+    let RocksStorage { ref db, .. } = rocks;
+    let target_links_cf = db.cf_handle(TARGET_LINKERS_CF).unwrap();
+
+    let (mut train, mut measure) = (vec![], vec![]);
+    for (i, item) in db
+        .iterator_cf(&target_links_cf, IteratorMode::Start)
+        .take(args.samples)
+        .enumerate()
+    {
+        let (_, v) = item?;
+        if i % 2 == 0 {
+            train.push(v.to_vec());
+        } else {
+            measure.push(v.to_vec());
+        }
+    }
+    eprintln!(
+        "sampled {} values, training a {}-byte dictionary...",
+        train.len() + measure.len(),
+        args.dict_bytes
+    );
+
+    let dict = zstd::dict::from_samples(&train, args.dict_bytes)?;
+    let mut plain = zstd::bulk::Compressor::new(args.level)?;
+    let mut with_dict = zstd::bulk::Compressor::with_dictionary(args.level, &dict)?;
+
+    let (mut raw_bytes, mut plain_bytes, mut dict_bytes) = (0, 0, 0);
+    for block in blocks(&measure) {
+        raw_bytes += block.len();
+        plain_bytes += plain.compress(&block)?.len();
+        dict_bytes += with_dict.compress(&block)?.len();
+    }
+
+    let pct = |n: usize| 100. * n as f64 / raw_bytes.max(1) as f64;
+    println!("uncompressed:     {raw_bytes:>12} bytes");
+    println!(
+        "zstd:             {plain_bytes:>12} bytes ({:.1}%)",
+        pct(plain_bytes)
+    );
+    println!(
+        "zstd+dictionary:  {dict_bytes:>12} bytes ({:.1}%)",
+        pct(dict_bytes)
+    );
+
+    Ok(())
+}
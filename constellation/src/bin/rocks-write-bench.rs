@@ -0,0 +1,84 @@
+use anyhow::{bail, Result};
+use clap::Parser;
+use std::fs::File;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+use std::time;
+use tinyjson::JsonValue;
+
+use constellation::consumer::get_actionable;
+use constellation::storage::{LinkStorage, RocksStorage, ShardedWriter};
+use constellation::ActionableEvent;
+
+/// Replay a jetstream fixture into fresh rocksdb dbs with different numbers of
+/// writer threads, and compare how fast each one gets through it
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Saved jsonl from jetstream to replay
+    #[arg(short, long)]
+    fixture: PathBuf,
+    /// Empty dir to create the benchmark dbs in (one per writer count)
+    #[arg(short, long)]
+    data: PathBuf,
+    /// Writer thread counts to compare
+    #[arg(short, long, value_delimiter = ',', default_value = "1,2,4,8")]
+    writers: Vec<usize>,
+}
+
+fn load_events(fixture: &Path) -> Result<Vec<(ActionableEvent, u64)>> {
+    let file = File::open(fixture)?;
+    let mut events = Vec::new();
+    for line in io::BufReader::new(file).lines().map_while(Result::ok) {
+        let update: JsonValue = line.parse()?;
+        if let Some(actionable) = get_actionable(&update) {
+            events.push(actionable);
+        }
+    }
+    Ok(events)
+}
+
+fn bench(writers: usize, events: Vec<(ActionableEvent, u64)>, path: &Path) -> Result<f64> {
+    let mut store = RocksStorage::new(path)?;
+    let n = events.len();
+    let t0 = time::Instant::now();
+    if writers == 1 {
+        // the plain single-writer path, for the baseline
+        for (event, cursor) in events {
+            store.push(&event, cursor)?;
+        }
+        drop(store); // flushes, like finishing the sharded writer does
+    } else {
+        let mut sharded = ShardedWriter::new(store, writers)?;
+        for (event, cursor) in events {
+            sharded.push(event, cursor)?;
+        }
+        sharded.finish()?;
+    }
+    Ok(n as f64 / t0.elapsed().as_secs_f64())
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    if args.data.exists() && args.data.read_dir()?.next().is_some() {
+        bail!("benchmark data dir {:?} must be empty", args.data);
+    }
+
+    let n = load_events(&args.fixture)?.len();
+    println!("replaying {n} actionable events from {:?}", args.fixture);
+
+    let mut baseline = None;
+    for writers in args.writers {
+        let events = load_events(&args.fixture)?; // not Clone, and not part of the timing
+        let rate = bench(
+            writers,
+            events,
+            &args.data.join(format!("writers-{writers}")),
+        )?;
+        let speedup = rate / *baseline.get_or_insert(rate);
+        println!("{writers:>3} writers: {rate:>10.0} events/s ({speedup:.2}x)");
+    }
+
+    Ok(())
+}
@@ -1,32 +1,122 @@
 mod jetstream;
 mod jsonl_file;
 
-use crate::storage::LinkStorage;
+use crate::storage::{LinkStorage, ShardedWriter};
 use crate::{ActionableEvent, RecordId};
 use anyhow::Result;
 use jetstream::consume_jetstream;
 use jsonl_file::consume_jsonl_file;
 use links::collect_links;
+use links::normalize::UrlNormalization;
 use metrics::{counter, describe_counter, describe_histogram, histogram, Unit};
 use std::path::PathBuf;
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 use tinyjson::JsonValue;
+use tokio::sync::watch;
 use tokio_util::sync::CancellationToken;
 
-pub fn consume(
-    mut store: impl LinkStorage,
-    qsize: Arc<AtomicU32>,
-    fixture: Option<PathBuf>,
-    stream: String,
-    staying_alive: CancellationToken,
-) -> Result<()> {
+/// The latest jetstream cursor whose event has been fully written to storage
+///
+/// The consumer bumps this after every event, actionable or not, so readers can
+/// wait for the index to catch up to a known cursor before answering.
+#[derive(Debug, Clone)]
+pub struct ProcessedCursor(watch::Receiver<Option<u64>>);
+
+impl ProcessedCursor {
+    pub fn channel() -> (watch::Sender<Option<u64>>, Self) {
+        let (sender, receiver) = watch::channel(None);
+        (sender, Self(receiver))
+    }
+
+    pub fn get(&self) -> Option<u64> {
+        *self.0.borrow()
+    }
+
+    /// Wait until events up to (and including) `cursor` have been processed
+    ///
+    /// Returns false if the timeout hits first, or if the consumer has stopped
+    /// without getting there.
+    pub async fn wait_for(&self, cursor: u64, timeout: Duration) -> bool {
+        let mut receiver = self.0.clone();
+        let reached = receiver.wait_for(|processed| processed.is_some_and(|c| c >= cursor));
+        matches!(tokio::time::timeout(timeout, reached).await, Ok(Ok(_)))
+    }
+}
+
+// how long the sharded dispatcher waits for events before re-checking the watermark
+const WATERMARK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Which collections get their links indexed
+///
+/// Patterns are NSIDs, or a prefix ending with `*` like `app.bsky.feed.*`. Account
+/// events are never filtered. Changing the filter only affects events indexed afterwards.
+#[derive(Debug, Clone, Default)]
+pub struct CollectionFilter {
+    /// if any are set, only index these
+    pub only: Vec<String>,
+    /// never index these
+    pub skip: Vec<String>,
+}
+
+impl CollectionFilter {
+    fn matches(pattern: &str, collection: &str) -> bool {
+        match pattern.strip_suffix('*') {
+            Some(prefix) => collection.starts_with(prefix),
+            None => collection == pattern,
+        }
+    }
+
+    pub fn allows(&self, collection: &str) -> bool {
+        (self.only.is_empty() || self.only.iter().any(|p| Self::matches(p, collection)))
+            && !self.skip.iter().any(|p| Self::matches(p, collection))
+    }
+}
+
+/// an event's action, ready to store, unless it's not actionable or filtered out
+fn prepare(
+    update: &JsonValue,
+    urls: &UrlNormalization,
+    collections: &CollectionFilter,
+) -> Option<(ActionableEvent, u64)> {
+    let Some((mut action, ts)) = get_actionable(update) else {
+        counter!("consumer_events_non_actionable").increment(1);
+        return None;
+    };
+    if let Some(collection) = action.collection() {
+        if !collections.allows(collection) {
+            counter!("consumer_events_skipped", "collection" => collection.to_string())
+                .increment(1);
+            return None;
+        }
+    }
+    action.normalize_urls(urls);
+    Some((action, ts))
+}
+
+fn advance(processed: &watch::Sender<Option<u64>>, cursor: u64) {
+    processed.send_if_modified(|current| {
+        if current.is_some_and(|c| c >= cursor) {
+            return false;
+        }
+        *current = Some(cursor);
+        true
+    });
+}
+
+fn describe_metrics() {
     describe_counter!(
         "consumer_events_non_actionable",
         Unit::Count,
         "count of non-actionable events"
     );
+    describe_counter!(
+        "consumer_events_skipped",
+        Unit::Count,
+        "actionable events not indexed because their collection is filtered out"
+    );
     describe_counter!(
         "consumer_events_actionable",
         Unit::Count,
@@ -42,6 +132,21 @@ pub fn consume(
         Unit::Count,
         "number of links per message"
     );
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn consume(
+    mut store: impl LinkStorage + 'static,
+    qsize: Arc<AtomicU32>,
+    fixture: Option<PathBuf>,
+    stream: String,
+    staying_alive: CancellationToken,
+    processed: watch::Sender<Option<u64>>,
+    writers: usize,
+    urls: UrlNormalization,
+    collections: CollectionFilter,
+) -> Result<()> {
+    describe_metrics();
 
     let (receiver, consumer_handle) = if let Some(f) = fixture {
         let (sender, receiver) = flume::bounded(21);
@@ -52,34 +157,136 @@ pub fn consume(
     } else {
         let (sender, receiver) = flume::bounded(32_768); // eek
         let cursor = store.get_cursor().unwrap();
+        if let Some(c) = cursor {
+            advance(&processed, c); // everything before the resume point is already indexed
+        }
         (
             receiver,
             thread::spawn(move || consume_jetstream(sender, cursor, stream, staying_alive)),
         )
     };
 
+    index(
+        store,
+        writers,
+        &receiver,
+        &qsize,
+        &processed,
+        &urls,
+        &collections,
+    )?;
+
+    consumer_handle.join().unwrap()
+}
+
+/// Index jetstream events from someone else's connection, until their sender hangs up
+///
+/// For running next to something that already consumes jetstream, so both can
+/// share one subscription. Events at or before the store's cursor should be
+/// left out: they're already indexed.
+pub fn consume_events(
+    mut store: impl LinkStorage + 'static,
+    receiver: flume::Receiver<JsonValue>,
+    processed: watch::Sender<Option<u64>>,
+    writers: usize,
+    urls: UrlNormalization,
+    collections: CollectionFilter,
+) -> Result<()> {
+    describe_metrics();
+    if let Some(c) = store.get_cursor()? {
+        advance(&processed, c);
+    }
+    let qsize = AtomicU32::new(0);
+    index(
+        store,
+        writers,
+        &receiver,
+        &qsize,
+        &processed,
+        &urls,
+        &collections,
+    )
+}
+
+fn index(
+    mut store: impl LinkStorage + 'static,
+    writers: usize,
+    receiver: &flume::Receiver<JsonValue>,
+    qsize: &AtomicU32,
+    processed: &watch::Sender<Option<u64>>,
+    urls: &UrlNormalization,
+    collections: &CollectionFilter,
+) -> Result<()> {
+    if writers > 1 {
+        return consume_sharded(
+            store,
+            writers,
+            receiver,
+            qsize,
+            processed,
+            urls,
+            collections,
+        );
+    }
     for update in receiver.iter() {
-        if let Some((action, ts)) = get_actionable(&update) {
-            {
-                store.push(&action, ts).unwrap();
-                qsize.store(receiver.len().try_into().unwrap(), Ordering::Relaxed);
-            }
-        } else {
-            counter!("consumer_events_non_actionable").increment(1);
+        if let Some((action, ts)) = prepare(&update, urls, collections) {
+            store.push(&action, ts).unwrap();
+            qsize.store(receiver.len().try_into().unwrap(), Ordering::Relaxed);
+        }
+        if let Some(cursor) = get_event_cursor(&update) {
+            advance(processed, cursor);
         }
     }
+    Ok(())
+}
 
-    consumer_handle.join().unwrap()
+fn consume_sharded(
+    store: impl LinkStorage + 'static,
+    writers: usize,
+    receiver: &flume::Receiver<JsonValue>,
+    qsize: &AtomicU32,
+    processed: &watch::Sender<Option<u64>>,
+    urls: &UrlNormalization,
+    collections: &CollectionFilter,
+) -> Result<()> {
+    println!("sharding writes across {writers} writer threads");
+    let mut sharded = ShardedWriter::new(store, writers)?;
+    loop {
+        match receiver.recv_timeout(WATERMARK_INTERVAL) {
+            Ok(update) => {
+                if let Some((action, ts)) = prepare(&update, urls, collections) {
+                    sharded.push(action, ts)?;
+                    qsize.store(receiver.len().try_into().unwrap(), Ordering::Relaxed);
+                } else if let Some(cursor) = get_event_cursor(&update) {
+                    sharded.saw(cursor);
+                }
+            }
+            Err(flume::RecvTimeoutError::Timeout) => {}
+            Err(flume::RecvTimeoutError::Disconnected) => break,
+        }
+        // writers finish out of order: readers only get to see the low-water mark
+        if let Some(mark) = sharded.checkpoint()? {
+            advance(processed, mark);
+        }
+    }
+    if let Some(last) = sharded.finish()? {
+        advance(processed, last);
+    }
+    Ok(())
 }
 
-pub fn get_actionable(event: &JsonValue) -> Option<(ActionableEvent, u64)> {
+fn get_event_cursor(event: &JsonValue) -> Option<u64> {
     let JsonValue::Object(root) = event else {
         return None;
     };
     let JsonValue::Number(time_us) = root.get("time_us")? else {
         return None;
     };
-    let cursor = *time_us as u64;
+    Some(*time_us as u64)
+}
+
+pub fn get_actionable(event: &JsonValue) -> Option<(ActionableEvent, u64)> {
+    let cursor = get_event_cursor(event)?;
     // todo: clean up
     match event {
         JsonValue::Object(root)
@@ -202,6 +409,38 @@ mod tests {
     use super::*;
     use links::{CollectedLink, Link};
 
+    #[test]
+    fn test_collection_filter() {
+        let everything = CollectionFilter::default();
+        assert!(everything.allows("app.bsky.graph.follow"));
+
+        let no_follows = CollectionFilter {
+            only: vec![],
+            skip: vec!["app.bsky.graph.follow".into()],
+        };
+        assert!(!no_follows.allows("app.bsky.graph.follow"));
+        assert!(no_follows.allows("app.bsky.graph.followx"));
+        assert!(no_follows.allows("app.bsky.feed.like"));
+
+        let feed_only = CollectionFilter {
+            only: vec!["app.bsky.feed.*".into()],
+            skip: vec!["app.bsky.feed.repost".into()],
+        };
+        assert!(feed_only.allows("app.bsky.feed.like"));
+        assert!(!feed_only.allows("app.bsky.feed.repost"));
+        assert!(!feed_only.allows("app.bsky.graph.follow"));
+
+        let delete: JsonValue = r#"{
+            "did":"did:plc:asdf",
+            "time_us":1736448492661668,
+            "kind":"commit",
+            "commit":{"rev":"3lfddpt5qa62c","operation":"delete","collection":"app.bsky.graph.follow","rkey":"3lfddpt5djw2c"}
+        }"#.parse().unwrap();
+        let urls = UrlNormalization::default();
+        assert!(prepare(&delete, &urls, &feed_only).is_none());
+        assert!(prepare(&delete, &urls, &everything).is_some());
+    }
+
     #[test]
     fn test_create_like() {
         let rec = r#"{
@@ -352,4 +591,27 @@ mod tests {
             ))
         )
     }
+
+    #[tokio::test]
+    async fn test_processed_cursor_wait() {
+        let (sender, processed) = ProcessedCursor::channel();
+        assert_eq!(processed.get(), None);
+        assert!(!processed.wait_for(10, Duration::from_millis(1)).await);
+
+        advance(&sender, 10);
+        advance(&sender, 5); // never goes backwards
+        assert_eq!(processed.get(), Some(10));
+        assert!(processed.wait_for(10, Duration::from_millis(1)).await);
+        assert!(!processed.wait_for(11, Duration::from_millis(1)).await);
+
+        let waiting = tokio::spawn({
+            let processed = processed.clone();
+            async move { processed.wait_for(20, Duration::from_secs(10)).await }
+        });
+        advance(&sender, 20);
+        assert!(waiting.await.unwrap());
+
+        drop(sender);
+        assert!(!processed.wait_for(30, Duration::from_secs(10)).await);
+    }
 }
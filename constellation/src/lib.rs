@@ -2,7 +2,9 @@ pub mod consumer;
 pub mod server;
 pub mod storage;
 
+use links::normalize::UrlNormalization;
 use links::CollectedLink;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::convert::From;
 
@@ -22,7 +24,30 @@ pub enum ActionableEvent {
     DeleteAccount(Did),
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
+impl ActionableEvent {
+    /// apply the configured normalization to any plain url link targets
+    pub fn normalize_urls(&mut self, urls: &UrlNormalization) {
+        match self {
+            ActionableEvent::CreateLinks { links, .. }
+            | ActionableEvent::UpdateLinks {
+                new_links: links, ..
+            } => urls.normalize_links(links),
+            _ => {}
+        }
+    }
+
+    /// the collection of the record this is about, if it's about a record
+    pub fn collection(&self) -> Option<&str> {
+        match self {
+            ActionableEvent::CreateLinks { record_id, .. }
+            | ActionableEvent::UpdateLinks { record_id, .. }
+            | ActionableEvent::DeleteRecord(record_id) => Some(&record_id.collection),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct Did(pub String);
 
 impl<T: Into<String>> From<T> for Did {
@@ -31,7 +56,7 @@ impl<T: Into<String>> From<T> for Did {
     }
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct RecordId {
     pub did: Did,
     pub collection: String,
@@ -51,7 +76,7 @@ impl RecordId {
 }
 
 /// maybe the worst type in this repo, and there are some bad types
-#[derive(Debug, Serialize, PartialEq)]
+#[derive(Debug, Serialize, PartialEq, JsonSchema)]
 pub struct CountsByCount {
     pub records: u64,
     pub distinct_dids: u64,
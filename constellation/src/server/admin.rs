@@ -0,0 +1,125 @@
+//! The admin api, for managing target aliases
+//!
+//! It's unauthenticated, so only serve it somewhere private.
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    routing::get,
+    Json, Router,
+};
+use links::normalize::UrlNormalization;
+use links::{parse_any_link, Link};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::net::{TcpListener, ToSocketAddrs};
+use tokio::task::block_in_place;
+use tokio_util::sync::CancellationToken;
+
+use crate::storage::{LinkReader, TargetAliases};
+
+#[derive(Clone)]
+struct AdminState<S> {
+    store: S,
+    aliases: TargetAliases,
+    urls: UrlNormalization,
+}
+
+type AdminResult<T> = Result<T, (StatusCode, String)>;
+
+pub async fn serve<S, A>(
+    store: S,
+    aliases: TargetAliases,
+    urls: UrlNormalization,
+    addr: A,
+    stay_alive: CancellationToken,
+) -> anyhow::Result<()>
+where
+    S: LinkReader,
+    A: ToSocketAddrs,
+{
+    let app = Router::new()
+        .route(
+            "/aliases",
+            get(list_aliases).put(set_alias).delete(remove_alias),
+        )
+        .with_state(AdminState {
+            store,
+            aliases,
+            urls,
+        });
+
+    let listener = TcpListener::bind(addr).await?;
+    println!(
+        "admin api: listening at http://{:?}",
+        listener.local_addr()?
+    );
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async move { stay_alive.cancelled().await })
+        .await?;
+
+    Ok(())
+}
+
+/// store alias targets the way the api will look them up
+fn parse_target(target: &str, urls: &UrlNormalization) -> AdminResult<String> {
+    match parse_any_link(target) {
+        Some(Link::Uri(uri)) => Ok(urls.normalize(&uri)),
+        Some(link) => Ok(link.into_string()),
+        None => Err((
+            StatusCode::BAD_REQUEST,
+            format!("not a valid target: {target:?}"),
+        )),
+    }
+}
+
+fn internal(e: anyhow::Error) -> (StatusCode, String) {
+    (StatusCode::INTERNAL_SERVER_ERROR, format!("{e:?}"))
+}
+
+#[derive(Serialize)]
+struct AliasesResponse {
+    /// alias target -> canonical target
+    aliases: HashMap<String, String>,
+}
+async fn list_aliases<S: LinkReader>(State(state): State<AdminState<S>>) -> Json<AliasesResponse> {
+    Json(AliasesResponse {
+        aliases: state.aliases.list(),
+    })
+}
+
+#[derive(Deserialize)]
+struct SetAliasQuery {
+    alias: String,
+    canonical: String,
+}
+async fn set_alias<S: LinkReader>(
+    State(state): State<AdminState<S>>,
+    Query(query): Query<SetAliasQuery>,
+) -> AdminResult<StatusCode> {
+    let alias = parse_target(&query.alias, &state.urls)?;
+    let canonical = parse_target(&query.canonical, &state.urls)?;
+    if let Some(conflict) = state.aliases.conflict(&alias, &canonical) {
+        return Err((StatusCode::CONFLICT, conflict));
+    }
+    println!("admin: aliasing {alias:?} to {canonical:?}");
+    block_in_place(|| state.aliases.set(&state.store, &alias, &canonical)).map_err(internal)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct RemoveAliasQuery {
+    alias: String,
+}
+async fn remove_alias<S: LinkReader>(
+    State(state): State<AdminState<S>>,
+    Query(query): Query<RemoveAliasQuery>,
+) -> AdminResult<StatusCode> {
+    let alias = parse_target(&query.alias, &state.urls)?;
+    println!("admin: removing alias {alias:?}");
+    if block_in_place(|| state.aliases.remove(&state.store, &alias)).map_err(internal)? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err((StatusCode::NOT_FOUND, format!("no alias for {alias:?}")))
+    }
+}
@@ -22,3 +22,30 @@ pub fn to_browseable(s: &str) -> askama::Result<Option<String>> {
 pub fn human_number(n: &u64) -> askama::Result<String> {
     Ok(n.to_formatted_string(&Locale::en))
 }
+
+/// days since the unix epoch as a `YYYY-MM-DD` date
+pub fn day_date(days: &u64) -> askama::Result<String> {
+    // howard hinnant's civil_from_days
+    let z = *days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    Ok(format!("{y:04}-{m:02}-{d:02}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_day_date() {
+        assert_eq!(day_date(&0).unwrap(), "1970-01-01");
+        assert_eq!(day_date(&19_417).unwrap(), "2023-03-01");
+        assert_eq!(day_date(&20_089).unwrap(), "2025-01-01");
+    }
+}
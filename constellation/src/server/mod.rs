@@ -1,6 +1,6 @@
 use askama::Template;
 use axum::{
-    extract::{Query, Request},
+    extract::{Query, Request, State},
     http::{self, header},
     middleware::{self, Next},
     response::{IntoResponse, Response},
@@ -9,19 +9,30 @@ use axum::{
 };
 use axum_metrics::{ExtraMetricLabels, MetricLayer};
 use bincode::Options;
+use links::normalize::UrlNormalization;
+use links::{parse_any_link, Link};
+use microcosm_estimates::SketchSecret;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
 use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::{Duration, UNIX_EPOCH};
 use tokio::net::{TcpListener, ToSocketAddrs};
 use tokio::task::block_in_place;
 use tokio_util::sync::CancellationToken;
 
-use crate::storage::{LinkReader, StorageStats};
+use crate::consumer::ProcessedCursor;
+use crate::storage::{
+    DatasetStats, LinkHistoryEvent, LinkReader, RecentLinker, ScanBudget, SketchResume,
+    StorageStats, RECENT_LINKERS_KEPT,
+};
 use crate::{CountsByCount, Did, RecordId};
 
 mod acceptable;
+pub mod admin;
 mod filters;
+mod openapi;
 
 use acceptable::{acceptable, ExtractAccept};
 
@@ -30,13 +41,58 @@ const DEFAULT_CURSOR_LIMIT_MAX: u64 = 100;
 
 const INDEX_BEGAN_AT_TS: u64 = 1738083600; // TODO: not this
 
-pub async fn serve<S, A>(store: S, addr: A, stay_alive: CancellationToken) -> anyhow::Result<()>
+// how long a paging snapshot stays pinned without a request for its next page
+const PAGING_SNAPSHOT_TTL: Duration = Duration::from_secs(5 * 60);
+
+// longest a request with `min_cursor` will wait for the consumer to catch up
+const MIN_CURSOR_TIMEOUT: Duration = Duration::from_secs(10);
+
+// most keys one request can touch: longer walks return partial results to continue
+const SCAN_BUDGET: u64 = 100_000;
+
+pub async fn serve<S, A>(
+    store: S,
+    addr: A,
+    processed: ProcessedCursor,
+    sketch_secret: Option<SketchSecret>,
+    urls: UrlNormalization,
+    stay_alive: CancellationToken,
+) -> anyhow::Result<()>
 where
     S: LinkReader,
     A: ToSocketAddrs,
 {
-    let app = Router::new()
+    let app = router(store, processed, sketch_secret, urls);
+
+    let listener = TcpListener::bind(addr).await?;
+    println!("api: listening at http://{:?}", listener.local_addr()?);
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async move { stay_alive.cancelled().await })
+        .await?;
+
+    Ok(())
+}
+
+/// All the API routes over a store, without binding a listener
+///
+/// Any [`LinkReader`] works, so a [`MemStorage`](crate::storage::MemStorage) can back
+/// it for tests and demos.
+pub fn router<S: LinkReader>(
+    store: S,
+    processed: ProcessedCursor,
+    sketch_secret: Option<SketchSecret>,
+    urls: UrlNormalization,
+) -> Router {
+    let openapi = openapi::document();
+    Router::new()
         .route("/robots.txt", get(robots))
+        .route(
+            "/openapi",
+            get(move || {
+                let doc = openapi.clone();
+                async { axum::Json(doc) }
+            }),
+        )
         .route(
             "/",
             get({
@@ -44,6 +100,13 @@ where
                 move |accept| async { block_in_place(|| hello(accept, store)) }
             }),
         )
+        .route(
+            "/stats",
+            get({
+                let store = store.clone();
+                move |accept| async { block_in_place(|| get_dataset_stats(accept, store)) }
+            }),
+        )
         .route(
             "/links/count",
             get({
@@ -55,8 +118,8 @@ where
             "/links/count/distinct-dids",
             get({
                 let store = store.clone();
-                move |accept, query| async {
-                    block_in_place(|| count_distinct_dids(accept, query, store))
+                move |accept, query| async move {
+                    block_in_place(|| count_distinct_dids(accept, query, store, sketch_secret))
                 }
             }),
         )
@@ -76,6 +139,47 @@ where
                 }
             }),
         )
+        .route(
+            "/links/received",
+            get({
+                let store = store.clone();
+                move |accept, query| async {
+                    block_in_place(|| get_received_counts(accept, query, store))
+                }
+            }),
+        )
+        .route(
+            "/links/types",
+            get({
+                let store = store.clone();
+                move |accept| async { block_in_place(|| get_link_types(accept, store)) }
+            }),
+        )
+        .route(
+            "/links/history",
+            get({
+                let store = store.clone();
+                move |accept, query| async {
+                    block_in_place(|| get_link_history(accept, query, store))
+                }
+            }),
+        )
+        .route(
+            "/links/recent",
+            get({
+                let store = store.clone();
+                move |accept, query| async {
+                    block_in_place(|| get_recent_linkers(accept, query, store))
+                }
+            }),
+        )
+        .route(
+            "/links/exists",
+            get({
+                let store = store.clone();
+                move |query| async { block_in_place(|| target_exists(query, store)) }
+            }),
+        )
         .route(
             // deprecated
             "/links/all/count",
@@ -95,17 +199,17 @@ where
                 }
             }),
         )
+        .layer(middleware::from_fn_with_state(
+            processed,
+            wait_for_min_cursor,
+        ))
+        .layer(middleware::from_fn_with_state(
+            Arc::new(urls),
+            normalize_target,
+        ))
         .layer(tower_http::cors::CorsLayer::permissive())
         .layer(middleware::from_fn(add_lables))
-        .layer(MetricLayer::default());
-
-    let listener = TcpListener::bind(addr).await?;
-    println!("api: listening at http://{:?}", listener.local_addr()?);
-    axum::serve(listener, app)
-        .with_graceful_shutdown(async move { stay_alive.cancelled().await })
-        .await?;
-
-    Ok(())
+        .layer(MetricLayer::default())
 }
 
 async fn add_lables(request: Request, next: Next) -> Response {
@@ -138,6 +242,69 @@ async fn add_lables(request: Request, next: Next) -> Response {
     res
 }
 
+/// url targets are stored normalized, so look them up the same way
+async fn normalize_target(
+    State(urls): State<Arc<UrlNormalization>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if let Some(uri) = with_normalized_target(request.uri(), &urls) {
+        *request.uri_mut() = uri;
+    }
+    next.run(request).await
+}
+
+fn with_normalized_target(uri: &http::Uri, urls: &UrlNormalization) -> Option<http::Uri> {
+    let Query(mut params) = Query::<Vec<(String, String)>>::try_from_uri(uri).ok()?;
+    let (_, target) = params.iter_mut().find(|(k, _)| k == "target")?;
+    let Some(Link::Uri(parsed)) = parse_any_link(target) else {
+        return None;
+    };
+    let normalized = urls.normalize(&parsed);
+    if normalized == *target {
+        return None;
+    }
+    *target = normalized;
+    let query = serde_urlencoded::to_string(&params).ok()?;
+    format!("{}?{query}", uri.path()).parse().ok()
+}
+
+#[derive(Deserialize)]
+struct MinCursorQuery {
+    min_cursor: Option<u64>,
+}
+/// read-your-writes: any endpoint accepts `?min_cursor=<jetstream cursor>` and
+/// will hold the response until the consumer has processed at least that far.
+///
+/// the latest processed cursor is always reported back in a response header.
+async fn wait_for_min_cursor(
+    State(processed): State<ProcessedCursor>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let min_cursor = Query::<MinCursorQuery>::try_from_uri(request.uri())
+        .ok()
+        .and_then(|Query(q)| q.min_cursor);
+
+    if let Some(cursor) = min_cursor {
+        if !processed.wait_for(cursor, MIN_CURSOR_TIMEOUT).await {
+            return (
+                http::StatusCode::SERVICE_UNAVAILABLE,
+                [(header::RETRY_AFTER, "1")],
+                "timed out waiting for the index to reach min_cursor",
+            )
+                .into_response();
+        }
+    }
+
+    let mut res = next.run(request).await;
+    if let Some(cursor) = processed.get() {
+        res.headers_mut()
+            .insert("x-constellation-cursor", http::HeaderValue::from(cursor));
+    }
+    res
+}
+
 async fn robots() -> &'static str {
     "\
 User-agent: *
@@ -146,7 +313,7 @@ Disallow: /links/
     "
 }
 
-#[derive(Template, Serialize, Deserialize)]
+#[derive(Template, Serialize, Deserialize, JsonSchema)]
 #[template(path = "hello.html.j2")]
 struct HelloReponse {
     help: &'static str,
@@ -172,17 +339,38 @@ fn hello(
     }))
 }
 
-#[derive(Clone, Deserialize)]
+#[derive(Template, Serialize, JsonSchema)]
+#[template(path = "stats.html.j2")]
+struct GetDatasetStatsResponse {
+    stats: DatasetStats,
+}
+fn get_dataset_stats(
+    accept: ExtractAccept,
+    store: impl LinkReader,
+) -> Result<impl IntoResponse, http::StatusCode> {
+    let stats = store
+        .get_dataset_stats()
+        .map_err(|_| http::StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(acceptable(accept, GetDatasetStatsResponse { stats }))
+}
+
+#[derive(Clone, Deserialize, JsonSchema)]
 struct GetLinksCountQuery {
+    /// the link target: a URI, AT-URI, or DID
     target: String,
+    /// NSID of the collection of linking records
     collection: String,
+    /// dot-prefixed path to the link in linking records, like `.subject.uri`
     path: String,
+    /// don't count links from the target's own account, like self-likes and self-replies
+    exclude_self: Option<bool>,
 }
-#[derive(Template, Serialize)]
+#[derive(Template, Serialize, JsonSchema)]
 #[template(path = "links-count.html.j2")]
 struct GetLinksCountResponse {
     total: u64,
     #[serde(skip_serializing)]
+    #[schemars(skip)]
     query: GetLinksCountQuery,
 }
 fn count_links(
@@ -190,9 +378,15 @@ fn count_links(
     query: Query<GetLinksCountQuery>,
     store: impl LinkReader,
 ) -> Result<impl IntoResponse, http::StatusCode> {
-    let total = store
+    let mut total = store
         .get_count(&query.target, &query.collection, &query.path)
         .map_err(|_| http::StatusCode::INTERNAL_SERVER_ERROR)?;
+    if query.exclude_self.unwrap_or(false) {
+        let self_links = store
+            .get_self_link_count(&query.target, &query.collection, &query.path)
+            .map_err(|_| http::StatusCode::INTERNAL_SERVER_ERROR)?;
+        total = total.saturating_sub(self_links);
+    }
     Ok(acceptable(
         accept,
         GetLinksCountResponse {
@@ -202,46 +396,127 @@ fn count_links(
     ))
 }
 
-#[derive(Clone, Deserialize)]
+#[derive(Clone, Deserialize, JsonSchema)]
 struct GetDidsCountQuery {
+    /// the link target: a URI, AT-URI, or DID
     target: String,
+    /// NSID of the collection of linking records
     collection: String,
+    /// dot-prefixed path to the link in linking records, like `.subject.uri`
     path: String,
+    /// also return a distinct-dids sketch that can be merged with other services'
+    ///
+    /// only available when the server was started with a sketch secret, and not with `exclude_self`
+    sketch: Option<bool>,
+    /// continue a sketch that was `truncated`, from the previous response's `sketch_cursor`
+    ///
+    /// the sketch returned is just for the rest: merge it into the earlier ones
+    sketch_cursor: Option<OpaqueApiCursor>,
+    /// don't count the target's own account, if it links to its own target
+    exclude_self: Option<bool>,
 }
-#[derive(Template, Serialize)]
+#[serde_as]
+#[derive(Template, Serialize, JsonSchema)]
 #[template(path = "dids-count.html.j2")]
 struct GetDidsCountResponse {
     total: u64,
+    /// the sketch's estimate of `total`, comparable with other services' estimates
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dids_estimate: Option<u64>,
+    /// hex bytes of the distinct-dids sketch, if requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde_as(as = "Option<serde_with::hex::Hex>")]
+    #[schemars(with = "Option<String>")]
+    sketch: Option<Vec<u8>>,
+    /// if requested, whether the sketch stopped early: it only has some of the dids,
+    /// and `sketch_cursor` gets a sketch of more
+    #[serde(skip_serializing_if = "Option::is_none")]
+    truncated: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sketch_cursor: Option<OpaqueApiCursor>,
     #[serde(skip_serializing)]
+    #[schemars(skip)]
     query: GetDidsCountQuery,
 }
 fn count_distinct_dids(
     accept: ExtractAccept,
     query: Query<GetDidsCountQuery>,
     store: impl LinkReader,
+    sketch_secret: Option<SketchSecret>,
 ) -> Result<impl IntoResponse, http::StatusCode> {
-    let total = store
+    let exclude_self = query.exclude_self.unwrap_or(false);
+    let mut total = store
         .get_distinct_did_count(&query.target, &query.collection, &query.path)
         .map_err(|_| http::StatusCode::INTERNAL_SERVER_ERROR)?;
+    if exclude_self {
+        let self_links = store
+            .get_self_link_count(&query.target, &query.collection, &query.path)
+            .map_err(|_| http::StatusCode::INTERNAL_SERVER_ERROR)?;
+        if self_links > 0 {
+            total = total.saturating_sub(1);
+        }
+    }
+    let (dids_estimate, sketch, sketch_cursor) = if query.sketch.unwrap_or(false) {
+        if exclude_self {
+            // sketches can't have an account taken back out
+            return Err(http::StatusCode::BAD_REQUEST);
+        }
+        let Some(secret) = sketch_secret else {
+            return Err(http::StatusCode::NOT_IMPLEMENTED);
+        };
+        let resume = query
+            .sketch_cursor
+            .clone()
+            .map(|oc| SketchResume::try_from(oc).map_err(|_| http::StatusCode::BAD_REQUEST))
+            .transpose()?;
+        let (sketch, next) = store
+            .get_distinct_dids_sketch(
+                &query.target,
+                &query.collection,
+                &query.path,
+                &secret,
+                resume.as_ref(),
+                &mut ScanBudget::new(SCAN_BUDGET),
+            )
+            .map_err(|_| http::StatusCode::INTERNAL_SERVER_ERROR)?;
+        let bytes = microcosm_estimates::to_bytes(&sketch)
+            .map_err(|_| http::StatusCode::INTERNAL_SERVER_ERROR)?;
+        (
+            Some(microcosm_estimates::estimate(&sketch)),
+            Some(bytes),
+            Some(next.map(OpaqueApiCursor::from)),
+        )
+    } else {
+        (None, None, None)
+    };
     Ok(acceptable(
         accept,
         GetDidsCountResponse {
             total,
+            dids_estimate,
+            sketch,
+            truncated: sketch_cursor.as_ref().map(Option::is_some),
+            sketch_cursor: sketch_cursor.flatten(),
             query: (*query).clone(),
         },
     ))
 }
 
-#[derive(Clone, Deserialize)]
+#[derive(Clone, Deserialize, JsonSchema)]
 struct GetLinkItemsQuery {
+    /// the link target: a URI, AT-URI, or DID
     target: String,
+    /// NSID of the collection of linking records
     collection: String,
+    /// dot-prefixed path to the link in linking records, like `.subject.uri`
     path: String,
+    /// from the previous page's response
     cursor: Option<OpaqueApiCursor>,
+    /// page size: default 16, max 100
     limit: Option<u64>,
     // TODO: allow reverse (er, forward) order as well
 }
-#[derive(Template, Serialize)]
+#[derive(Template, Serialize, JsonSchema)]
 #[template(path = "links.html.j2")]
 struct GetLinkItemsResponse {
     // what does staleness mean?
@@ -249,8 +524,10 @@ struct GetLinkItemsResponse {
     // - links have been deleted. hmm.
     total: u64,
     linking_records: Vec<RecordId>,
+    /// for the next page, if there is one
     cursor: Option<OpaqueApiCursor>,
     #[serde(skip_serializing)]
+    #[schemars(skip)]
     query: GetLinkItemsQuery,
 }
 fn get_links(
@@ -258,18 +535,20 @@ fn get_links(
     query: Query<GetLinkItemsQuery>,
     store: impl LinkReader,
 ) -> Result<impl IntoResponse, http::StatusCode> {
-    let until = query
+    let cursor = query
         .cursor
         .clone()
         .map(|oc| ApiCursor::try_from(oc).map_err(|_| http::StatusCode::BAD_REQUEST))
-        .transpose()?
-        .map(|c| c.next);
+        .transpose()?;
 
     let limit = query.limit.unwrap_or(DEFAULT_CURSOR_LIMIT);
     if limit > DEFAULT_CURSOR_LIMIT_MAX {
         return Err(http::StatusCode::BAD_REQUEST);
     }
 
+    let until = cursor.as_ref().map(|c| c.next);
+    let (store, snapshot) = paging_reader(store, cursor.as_ref())?;
+
     let paged = store
         .get_links(&query.target, &query.collection, &query.path, limit, until)
         .map_err(|_| http::StatusCode::INTERNAL_SERVER_ERROR)?;
@@ -278,9 +557,13 @@ fn get_links(
         ApiCursor {
             version: paged.version,
             next,
+            snapshot,
         }
         .into()
     });
+    if let (None, Some(snapshot)) = (paged.next, snapshot) {
+        store.release_snapshot(snapshot); // last page
+    }
 
     Ok(acceptable(
         accept,
@@ -293,16 +576,21 @@ fn get_links(
     ))
 }
 
-#[derive(Clone, Deserialize)]
+#[derive(Clone, Deserialize, JsonSchema)]
 struct GetDidItemsQuery {
+    /// the link target: a URI, AT-URI, or DID
     target: String,
+    /// NSID of the collection of linking records
     collection: String,
+    /// dot-prefixed path to the link in linking records, like `.subject.uri`
     path: String,
+    /// from the previous page's response
     cursor: Option<OpaqueApiCursor>,
+    /// page size: default 16, max 100
     limit: Option<u64>,
     // TODO: allow reverse (er, forward) order as well
 }
-#[derive(Template, Serialize)]
+#[derive(Template, Serialize, JsonSchema)]
 #[template(path = "dids.html.j2")]
 struct GetDidItemsResponse {
     // what does staleness mean?
@@ -310,8 +598,10 @@ struct GetDidItemsResponse {
     // - links have been deleted. hmm.
     total: u64,
     linking_dids: Vec<Did>,
+    /// for the next page, if there is one
     cursor: Option<OpaqueApiCursor>,
     #[serde(skip_serializing)]
+    #[schemars(skip)]
     query: GetDidItemsQuery,
 }
 fn get_distinct_dids(
@@ -319,18 +609,20 @@ fn get_distinct_dids(
     query: Query<GetDidItemsQuery>,
     store: impl LinkReader,
 ) -> Result<impl IntoResponse, http::StatusCode> {
-    let until = query
+    let cursor = query
         .cursor
         .clone()
         .map(|oc| ApiCursor::try_from(oc).map_err(|_| http::StatusCode::BAD_REQUEST))
-        .transpose()?
-        .map(|c| c.next);
+        .transpose()?;
 
     let limit = query.limit.unwrap_or(DEFAULT_CURSOR_LIMIT);
     if limit > DEFAULT_CURSOR_LIMIT_MAX {
         return Err(http::StatusCode::BAD_REQUEST);
     }
 
+    let until = cursor.as_ref().map(|c| c.next);
+    let (store, snapshot) = paging_reader(store, cursor.as_ref())?;
+
     let paged = store
         .get_distinct_dids(&query.target, &query.collection, &query.path, limit, until)
         .map_err(|_| http::StatusCode::INTERNAL_SERVER_ERROR)?;
@@ -339,9 +631,13 @@ fn get_distinct_dids(
         ApiCursor {
             version: paged.version,
             next,
+            snapshot,
         }
         .into()
     });
+    if let (None, Some(snapshot)) = (paged.next, snapshot) {
+        store.release_snapshot(snapshot); // last page
+    }
 
     Ok(acceptable(
         accept,
@@ -354,15 +650,216 @@ fn get_distinct_dids(
     ))
 }
 
-#[derive(Clone, Deserialize)]
+#[derive(Clone, Deserialize, JsonSchema)]
+struct GetReceivedQuery {
+    /// the account whose records' backlinks are counted
+    did: String,
+}
+#[derive(Template, Serialize, JsonSchema)]
+#[template(path = "links-received.html.j2")]
+struct GetReceivedResponse {
+    /// counts keyed by linking collection, then by path
+    links: HashMap<String, HashMap<String, u64>>,
+    #[serde(skip_serializing)]
+    #[schemars(skip)]
+    query: GetReceivedQuery,
+}
+fn get_received_counts(
+    accept: ExtractAccept,
+    query: Query<GetReceivedQuery>,
+    store: impl LinkReader,
+) -> Result<impl IntoResponse, http::StatusCode> {
+    if links::did::parse_did(&query.did).is_none() {
+        return Err(http::StatusCode::BAD_REQUEST);
+    }
+    let links = store
+        .get_received_counts(&query.did)
+        .map_err(|_| http::StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(acceptable(
+        accept,
+        GetReceivedResponse {
+            links,
+            query: (*query).clone(),
+        },
+    ))
+}
+
+#[derive(Template, Serialize, JsonSchema)]
+#[template(path = "links-types.html.j2")]
+struct GetLinkTypesResponse {
+    /// how many links have ever been seen, keyed by linking collection, then by path
+    link_types: HashMap<String, HashMap<String, u64>>,
+}
+fn get_link_types(
+    accept: ExtractAccept,
+    store: impl LinkReader,
+) -> Result<impl IntoResponse, http::StatusCode> {
+    let link_types = store
+        .get_link_types()
+        .map_err(|_| http::StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(acceptable(accept, GetLinkTypesResponse { link_types }))
+}
+
+#[derive(Clone, Deserialize, JsonSchema)]
+struct GetLinkHistoryQuery {
+    /// the link target: a URI, AT-URI, or DID
+    target: String,
+    /// NSID of the collection of linking records
+    collection: String,
+    /// dot-prefixed path to the link in linking records, like `.subject.uri`
+    path: String,
+    /// from the previous page's response
+    cursor: Option<OpaqueApiCursor>,
+    /// page size: default 16, max 100
+    limit: Option<u64>,
+}
+#[derive(Template, Serialize, JsonSchema)]
+#[template(path = "links-history.html.j2")]
+struct GetLinkHistoryResponse {
+    total: u64,
+    history: Vec<LinkHistoryEvent>,
+    /// for the next page, if there is one
+    cursor: Option<OpaqueApiCursor>,
+    #[serde(skip_serializing)]
+    #[schemars(skip)]
+    query: GetLinkHistoryQuery,
+}
+fn get_link_history(
+    accept: ExtractAccept,
+    query: Query<GetLinkHistoryQuery>,
+    store: impl LinkReader,
+) -> Result<impl IntoResponse, http::StatusCode> {
+    if !cfg!(feature = "history") {
+        return Err(http::StatusCode::NOT_FOUND);
+    }
+
+    let cursor = query
+        .cursor
+        .clone()
+        .map(|oc| ApiCursor::try_from(oc).map_err(|_| http::StatusCode::BAD_REQUEST))
+        .transpose()?;
+
+    let limit = query.limit.unwrap_or(DEFAULT_CURSOR_LIMIT);
+    if limit > DEFAULT_CURSOR_LIMIT_MAX {
+        return Err(http::StatusCode::BAD_REQUEST);
+    }
+
+    // history is append-only, so index cursors are already stable without a snapshot
+    let paged = store
+        .get_link_history(
+            &query.target,
+            &query.collection,
+            &query.path,
+            limit,
+            cursor.map(|c| c.next),
+        )
+        .map_err(|_| http::StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let cursor = paged.next.map(|next| {
+        ApiCursor {
+            version: paged.version,
+            next,
+            snapshot: None,
+        }
+        .into()
+    });
+
+    Ok(acceptable(
+        accept,
+        GetLinkHistoryResponse {
+            total: paged.total,
+            history: paged.items,
+            cursor,
+            query: (*query).clone(),
+        },
+    ))
+}
+
+#[derive(Clone, Deserialize, JsonSchema)]
+struct GetRecentLinkersQuery {
+    /// the link target: a URI, AT-URI, or DID
+    target: String,
+    /// NSID of the collection of linking records
+    collection: String,
+    /// dot-prefixed path to the link in linking records, like `.subject.uri`
+    path: String,
+    /// how many to return: default and max 16
+    limit: Option<u64>,
+}
+#[derive(Template, Serialize, JsonSchema)]
+#[template(path = "links-recent.html.j2")]
+struct GetRecentLinkersResponse {
+    /// most recent first
+    recent: Vec<RecentLinker>,
+    #[serde(skip_serializing)]
+    #[schemars(skip)]
+    query: GetRecentLinkersQuery,
+}
+fn get_recent_linkers(
+    accept: ExtractAccept,
+    query: Query<GetRecentLinkersQuery>,
+    store: impl LinkReader,
+) -> Result<impl IntoResponse, http::StatusCode> {
+    if !cfg!(feature = "recent-linkers") {
+        return Err(http::StatusCode::NOT_FOUND);
+    }
+
+    let limit = query.limit.unwrap_or(RECENT_LINKERS_KEPT as u64);
+    if limit > RECENT_LINKERS_KEPT as u64 {
+        return Err(http::StatusCode::BAD_REQUEST);
+    }
+
+    let recent = store
+        .get_recent_linkers(&query.target, &query.collection, &query.path, limit)
+        .map_err(|_| http::StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(acceptable(
+        accept,
+        GetRecentLinkersResponse {
+            recent,
+            query: (*query).clone(),
+        },
+    ))
+}
+
+#[derive(Clone, Deserialize, JsonSchema)]
+struct TargetExistsQuery {
+    /// the link target: a URI, AT-URI, or DID
+    target: String,
+}
+#[derive(Serialize, JsonSchema)]
+struct TargetExistsResponse {
+    /// whether anything has ever linked to the target
+    exists: bool,
+}
+/// json only, and `404` when nothing links to the target, so `HEAD` works as a cheap check
+fn target_exists(
+    query: Query<TargetExistsQuery>,
+    store: impl LinkReader,
+) -> Result<impl IntoResponse, http::StatusCode> {
+    let exists = store
+        .target_exists(&query.target)
+        .map_err(|_| http::StatusCode::INTERNAL_SERVER_ERROR)?;
+    let status = if exists {
+        http::StatusCode::OK
+    } else {
+        http::StatusCode::NOT_FOUND
+    };
+    Ok((status, axum::Json(TargetExistsResponse { exists })))
+}
+
+#[derive(Clone, Deserialize, JsonSchema)]
 struct GetAllLinksQuery {
+    /// the link target: a URI, AT-URI, or DID
     target: String,
 }
-#[derive(Template, Serialize)]
+#[derive(Template, Serialize, JsonSchema)]
 #[template(path = "links-all-count.html.j2")]
 struct GetAllLinksResponse {
+    /// counts keyed by linking collection, then by path
     links: HashMap<String, HashMap<String, u64>>,
     #[serde(skip_serializing)]
+    #[schemars(skip)]
     query: GetAllLinksQuery,
 }
 fn count_all_links(
@@ -382,15 +879,18 @@ fn count_all_links(
     ))
 }
 
-#[derive(Clone, Deserialize)]
+#[derive(Clone, Deserialize, JsonSchema)]
 struct ExploreLinksQuery {
+    /// the link target: a URI, AT-URI, or DID
     target: String,
 }
-#[derive(Template, Serialize)]
+#[derive(Template, Serialize, JsonSchema)]
 #[template(path = "explore-links.html.j2")]
 struct ExploreLinksResponse {
+    /// counts keyed by linking collection, then by path
     links: HashMap<String, HashMap<String, CountsByCount>>,
     #[serde(skip_serializing)]
+    #[schemars(skip)]
     query: ExploreLinksQuery,
 }
 fn explore_links(
@@ -410,21 +910,71 @@ fn explore_links(
     ))
 }
 
+/// The reader to use for one page of results
+///
+/// Pages after the first read from the snapshot pinned by the first, so that
+/// links added or removed mid-pagination can't shift items between pages.
+/// Cursors without a snapshot (older cursors, or when storage couldn't pin
+/// one) read live, as before.
+fn paging_reader<S: LinkReader>(
+    store: S,
+    cursor: Option<&ApiCursor>,
+) -> Result<(S, Option<u64>), http::StatusCode> {
+    let snapshot = match cursor {
+        Some(ApiCursor { snapshot, .. }) => *snapshot,
+        None => store
+            .pin_snapshot(PAGING_SNAPSHOT_TTL)
+            .map_err(|_| http::StatusCode::INTERNAL_SERVER_ERROR)?,
+    };
+    let Some(snapshot) = snapshot else {
+        return Ok((store, None));
+    };
+    match store
+        .at_snapshot(snapshot)
+        .map_err(|_| http::StatusCode::INTERNAL_SERVER_ERROR)?
+    {
+        Some(pinned) => Ok((pinned, Some(snapshot))),
+        None if cursor.is_none() => Ok((store, None)), // expired already?? just read live
+        None => Err(http::StatusCode::GONE), // cursor expired: the client has to start over
+    }
+}
+
+/// Opaque paging cursor: pass it back unchanged to get the next page
 #[serde_as]
-#[derive(Clone, Serialize, Deserialize)] // for json
-struct OpaqueApiCursor(#[serde_as(as = "serde_with::hex::Hex")] Vec<u8>);
+#[derive(Clone, Serialize, Deserialize, JsonSchema)] // for json
+struct OpaqueApiCursor(
+    #[serde_as(as = "serde_with::hex::Hex")]
+    #[schemars(with = "String")]
+    Vec<u8>,
+);
 
 #[derive(Serialize, Deserialize)] // for bincode
 struct ApiCursor {
     version: (u64, u64), // (collection length, deleted item count)
     next: u64,
+    snapshot: Option<u64>, // pinned storage snapshot for stable paging
+}
+
+/// cursors handed out before snapshots were pinned for paging
+#[derive(Deserialize)]
+struct LegacyApiCursor {
+    version: (u64, u64),
+    next: u64,
 }
 
 impl TryFrom<OpaqueApiCursor> for ApiCursor {
     type Error = bincode::Error;
 
     fn try_from(item: OpaqueApiCursor) -> Result<Self, Self::Error> {
-        bincode::DefaultOptions::new().deserialize(&item.0)
+        let opts = bincode::DefaultOptions::new();
+        opts.deserialize(&item.0).or_else(|e| {
+            let LegacyApiCursor { version, next } = opts.deserialize(&item.0).map_err(|_| e)?;
+            Ok(ApiCursor {
+                version,
+                next,
+                snapshot: None,
+            })
+        })
     }
 }
 
@@ -433,3 +983,136 @@ impl From<ApiCursor> for OpaqueApiCursor {
         OpaqueApiCursor(bincode::DefaultOptions::new().serialize(&item).unwrap())
     }
 }
+
+impl TryFrom<OpaqueApiCursor> for SketchResume {
+    type Error = bincode::Error;
+
+    fn try_from(item: OpaqueApiCursor) -> Result<Self, Self::Error> {
+        bincode::DefaultOptions::new().deserialize(&item.0)
+    }
+}
+
+impl From<SketchResume> for OpaqueApiCursor {
+    fn from(item: SketchResume) -> Self {
+        OpaqueApiCursor(bincode::DefaultOptions::new().serialize(&item).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{LinkStorage, MemStorage};
+    use crate::ActionableEvent;
+    use axum::body::{to_bytes, Body};
+    use links::CollectedLink;
+    use tower::ServiceExt;
+
+    async fn get_json(
+        app: Router,
+        uri: &str,
+    ) -> (http::StatusCode, Option<u64>, serde_json::Value) {
+        let request = http::Request::builder()
+            .uri(uri)
+            .header(header::ACCEPT, "application/json")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        let cursor = response
+            .headers()
+            .get("x-constellation-cursor")
+            .map(|v| v.to_str().unwrap().parse().unwrap());
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, cursor, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_routes_over_mem_storage() -> anyhow::Result<()> {
+        let mut storage = MemStorage::new();
+        for (i, did) in ["did:plc:asdf", "did:plc:fdsa"].into_iter().enumerate() {
+            storage.push(
+                &ActionableEvent::CreateLinks {
+                    record_id: RecordId {
+                        did: did.into(),
+                        collection: "app.t.c".into(),
+                        rkey: "rkey".into(),
+                    },
+                    links: vec![CollectedLink {
+                        target: Link::AtUri("at://did:plc:zzz/app.t.c/post".into()),
+                        path: ".subject.uri".into(),
+                    }],
+                },
+                i as u64,
+            )?;
+        }
+        let (processed_sender, processed) = ProcessedCursor::channel();
+        processed_sender.send(Some(1))?;
+        let app = router(
+            storage.to_readable(),
+            processed,
+            None,
+            UrlNormalization::default(),
+        );
+
+        let target = "at%3A%2F%2Fdid%3Aplc%3Azzz%2Fapp.t.c%2Fpost";
+        let (status, cursor, body) = get_json(
+            app.clone(),
+            &format!("/links/count?target={target}&collection=app.t.c&path=.subject.uri"),
+        )
+        .await;
+        assert_eq!(status, http::StatusCode::OK);
+        assert_eq!(cursor, Some(1));
+        assert_eq!(body["total"], 2);
+
+        let (status, _, body) = get_json(
+            app.clone(),
+            &format!("/links/distinct-dids?target={target}&collection=app.t.c&path=.subject.uri"),
+        )
+        .await;
+        assert_eq!(status, http::StatusCode::OK);
+        assert_eq!(body["total"], 2);
+        assert_eq!(body["linking_dids"].as_array().map(Vec::len), Some(2));
+
+        // did:plc:zzz never liked its own post
+        let (status, _, body) = get_json(
+            app.clone(),
+            &format!("/links/count/distinct-dids?target={target}&collection=app.t.c&path=.subject.uri&exclude_self=true"),
+        )
+        .await;
+        assert_eq!(status, http::StatusCode::OK);
+        assert_eq!(body["total"], 2);
+
+        let (status, _, body) =
+            get_json(app.clone(), &format!("/links/exists?target={target}")).await;
+        assert_eq!(status, http::StatusCode::OK);
+        assert_eq!(body["exists"], true);
+
+        let (status, _, body) = get_json(app.clone(), "/links/exists?target=did:plc:nope").await;
+        assert_eq!(status, http::StatusCode::NOT_FOUND);
+        assert_eq!(body["exists"], false);
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_normalized_target() {
+        let urls = UrlNormalization::tracking();
+        let uri: http::Uri = "/links/count?target=HTTPS%3A%2F%2Fexample.com%2F%3Futm_source%3Dx&collection=app.t.c&path=.uri"
+            .parse()
+            .unwrap();
+        let rewritten = with_normalized_target(&uri, &urls).unwrap();
+        let Query(params) = Query::<HashMap<String, String>>::try_from_uri(&rewritten).unwrap();
+        assert_eq!(params["target"], "https://example.com/");
+        assert_eq!(params["collection"], "app.t.c");
+        assert_eq!(rewritten.path(), "/links/count");
+
+        // already normalized, or not a url at all: left alone
+        for unchanged in [
+            "/links/count?target=https%3A%2F%2Fexample.com%2F&collection=a&path=.b",
+            "/links/count?target=did%3Aplc%3Aasdf&collection=a&path=.b",
+            "/links/received?did=did%3Aplc%3Aasdf",
+        ] {
+            let uri: http::Uri = unchanged.parse().unwrap();
+            assert!(with_normalized_target(&uri, &urls).is_none());
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! An OpenAPI document for the links api, generated from its query and
+//! response types so that it can't drift from what's actually served
+
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::schema::{ObjectValidation, Schema};
+use schemars::JsonSchema;
+use serde_json::{json, Map, Value};
+
+use super::*;
+
+struct Document {
+    gen: SchemaGenerator,
+    paths: Map<String, Value>,
+}
+
+impl Document {
+    fn new() -> Self {
+        Self {
+            gen: SchemaSettings::openapi3().into_generator(),
+            paths: Map::new(),
+        }
+    }
+
+    /// query params come from the fields of `Q`, the json body from `R`
+    fn get<Q: JsonSchema, R: JsonSchema>(&mut self, path: &str, summary: &str) -> &mut Self {
+        let query = self.gen.root_schema_for::<Q>().schema;
+        let ObjectValidation {
+            properties,
+            required,
+            ..
+        } = query.object.map(|o| *o).unwrap_or_default();
+        let mut parameters: Vec<Value> = properties
+            .into_iter()
+            .map(|(name, schema)| param(&name, required.contains(&name), schema))
+            .collect();
+        let min_cursor = self.gen.root_schema_for::<MinCursorParam>().schema;
+        parameters.push(param("min_cursor", false, min_cursor.into()));
+
+        let response = self.gen.subschema_for::<R>();
+        self.paths.insert(
+            path.into(),
+            json!({
+                "get": {
+                    "summary": summary,
+                    "parameters": parameters,
+                    "responses": {
+                        "200": {
+                            "description": "ok",
+                            "content": { "application/json": { "schema": response } },
+                        },
+                        "400": { "description": "invalid query" },
+                    },
+                },
+            }),
+        );
+        self
+    }
+
+    fn finish(&mut self) -> Value {
+        json!({
+            "openapi": "3.0.3",
+            "info": {
+                "title": "constellation",
+                "description": "A global atproto backlink index",
+                "version": env!("CARGO_PKG_VERSION"),
+            },
+            "paths": self.paths,
+            "components": { "schemas": self.gen.take_definitions() },
+        })
+    }
+}
+
+/// Hold the response until the index has processed jetstream events up to this cursor
+#[allow(dead_code)] // only here for its schema
+#[derive(JsonSchema)]
+struct MinCursorParam(u64);
+
+fn param(name: &str, required: bool, schema: Schema) -> Value {
+    let description = schema
+        .clone()
+        .into_object()
+        .metadata
+        .and_then(|m| m.description);
+    json!({
+        "name": name,
+        "in": "query",
+        "required": required,
+        "description": description,
+        "schema": schema,
+    })
+}
+
+pub fn document() -> Value {
+    let mut doc = Document::new();
+    doc.get::<(), HelloReponse>("/", "API info and index stats")
+        .get::<(), GetDatasetStatsResponse>(
+            "/stats",
+            "Index totals, with links by collection and growth by day",
+        )
+        .get::<GetLinksCountQuery, GetLinksCountResponse>(
+            "/links/count",
+            "Count the records linking to a target from a collection and path",
+        )
+        .get::<GetDidsCountQuery, GetDidsCountResponse>(
+            "/links/count/distinct-dids",
+            "Count the distinct accounts linking to a target from a collection and path",
+        )
+        .get::<GetLinkItemsQuery, GetLinkItemsResponse>(
+            "/links",
+            "List the records linking to a target from a collection and path, newest first",
+        )
+        .get::<GetDidItemsQuery, GetDidItemsResponse>(
+            "/links/distinct-dids",
+            "List the distinct accounts linking to a target from a collection and path",
+        )
+        .get::<GetReceivedQuery, GetReceivedResponse>(
+            "/links/received",
+            "Count the links received by all records of an account",
+        )
+        .get::<(), GetLinkTypesResponse>(
+            "/links/types",
+            "Every collection and path links have been seen at, with how many links",
+        )
+        .get::<TargetExistsQuery, TargetExistsResponse>(
+            "/links/exists",
+            "Whether anything has ever linked to a target (404 if not, so HEAD works too)",
+        )
+        .get::<GetAllLinksQuery, GetAllLinksResponse>(
+            "/links/all/count",
+            "Deprecated: count links to a target from every collection and path",
+        )
+        .get::<ExploreLinksQuery, ExploreLinksResponse>(
+            "/links/all",
+            "Count links and distinct linking accounts to a target from every collection and path",
+        );
+    if cfg!(feature = "history") {
+        doc.get::<GetLinkHistoryQuery, GetLinkHistoryResponse>(
+            "/links/history",
+            "List the links added to and removed from a target, newest first",
+        );
+    }
+    if cfg!(feature = "recent-linkers") {
+        doc.get::<GetRecentLinkersQuery, GetRecentLinkersResponse>(
+            "/links/recent",
+            "The latest accounts to link to a target from a collection and path, newest first",
+        );
+    }
+    doc.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_document_refs_resolve() {
+        let doc = document();
+        let schemas = doc["components"]["schemas"].as_object().unwrap();
+        assert!(schemas.contains_key("GetLinkItemsResponse"));
+        assert!(schemas.contains_key("RecordId"));
+
+        let links = &doc["paths"]["/links"]["get"];
+        let params: Vec<_> = links["parameters"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|p| {
+                (
+                    p["name"].as_str().unwrap(),
+                    p["required"].as_bool().unwrap(),
+                )
+            })
+            .collect();
+        assert!(params.contains(&("target", true)));
+        assert!(params.contains(&("cursor", false)));
+        assert!(params.contains(&("min_cursor", false)));
+
+        // every $ref points at something in components
+        fn check(v: &Value, schemas: &Map<String, Value>) {
+            match v {
+                Value::Object(o) => {
+                    if let Some(Value::String(r)) = o.get("$ref") {
+                        let name = r.strip_prefix("#/components/schemas/").unwrap();
+                        assert!(schemas.contains_key(name), "missing schema {name}");
+                    }
+                    o.values().for_each(|v| check(v, schemas));
+                }
+                Value::Array(a) => a.iter().for_each(|v| check(v, schemas)),
+                _ => {}
+            }
+        }
+        check(&doc, schemas);
+    }
+}
@@ -0,0 +1,421 @@
+//! Admin-managed target aliases, applied at query time
+//!
+//! An alias points a target at a canonical one, like an old post at-uri after a
+//! repo migration, or the http version of an https url. Links stay stored
+//! under whatever target they were made to: counts for a canonical target and
+//! its aliases are consolidated when they're read.
+
+use super::{
+    DailyGrowth, LinkHistoryEvent, LinkReader, PagedAppendingCollection, RecentLinker, ScanBudget,
+    SketchResume, StorageStats,
+};
+use crate::{CountsByCount, Did, RecordId};
+use anyhow::Result;
+use microcosm_estimates::{DidsSketch, SketchSecret};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+// page size when walking dids to union them across aliased targets
+const ALIAS_DIDS_PAGE: u64 = 1000;
+
+/// The alias table, shared between the api and the admin api
+#[derive(Debug, Clone, Default)]
+pub struct TargetAliases(Arc<RwLock<HashMap<String, String>>>); // alias -> canonical
+
+impl TargetAliases {
+    pub fn load(store: &impl LinkReader) -> Result<Self> {
+        Ok(Self(Arc::new(RwLock::new(store.get_target_aliases()?))))
+    }
+
+    pub fn list(&self) -> HashMap<String, String> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn canonical(&self, target: &str) -> String {
+        let aliases = self.0.read().unwrap();
+        aliases
+            .get(target)
+            .cloned()
+            .unwrap_or_else(|| target.to_string())
+    }
+
+    /// The canonical target for a target, then all of its aliases
+    pub fn group(&self, target: &str) -> Vec<String> {
+        let aliases = self.0.read().unwrap();
+        let canonical = aliases.get(target).map(String::as_str).unwrap_or(target);
+        let mut group: Vec<String> = aliases
+            .iter()
+            .filter(|(_, c)| *c == canonical)
+            .map(|(alias, _)| alias.clone())
+            .collect();
+        // a stable order, for walks that resume part way through the group
+        group.sort();
+        group.insert(0, canonical.to_string());
+        group
+    }
+
+    /// Why an alias can't be added, if it can't
+    ///
+    /// Aliases only go one level deep, so a canonical target can't itself be an
+    /// alias, and a target that others alias to can't become an alias.
+    pub fn conflict(&self, alias: &str, canonical: &str) -> Option<String> {
+        let aliases = self.0.read().unwrap();
+        if alias == canonical {
+            Some("a target can't be an alias of itself".into())
+        } else if let Some(c) = aliases.get(canonical) {
+            Some(format!("{canonical:?} is already an alias of {c:?}"))
+        } else if aliases.values().any(|c| c == alias) {
+            Some(format!(
+                "{alias:?} is already the canonical target of other aliases"
+            ))
+        } else {
+            None
+        }
+    }
+
+    pub fn set(&self, store: &impl LinkReader, alias: &str, canonical: &str) -> Result<()> {
+        store.set_target_alias(alias, Some(canonical))?;
+        self.0
+            .write()
+            .unwrap()
+            .insert(alias.to_string(), canonical.to_string());
+        Ok(())
+    }
+
+    /// Returns whether there was an alias to remove
+    pub fn remove(&self, store: &impl LinkReader, alias: &str) -> Result<bool> {
+        store.set_target_alias(alias, None)?;
+        Ok(self.0.write().unwrap().remove(alias).is_some())
+    }
+}
+
+/// A reader that answers for canonical targets and their aliases together
+///
+/// Counts are consolidated across the alias group. Paged listings can't be
+/// merged under one cursor, so they only list the canonical target.
+#[derive(Debug, Clone)]
+pub struct Aliased<R> {
+    inner: R,
+    aliases: TargetAliases,
+}
+
+impl<R: LinkReader> Aliased<R> {
+    pub fn new(inner: R, aliases: TargetAliases) -> Self {
+        Self { inner, aliases }
+    }
+
+    fn union_dids(&self, group: &[String], collection: &str, path: &str) -> Result<u64> {
+        let mut dids: HashSet<Did> = HashSet::new();
+        for target in group {
+            let mut until = None;
+            loop {
+                let page = self.inner.get_distinct_dids(
+                    target,
+                    collection,
+                    path,
+                    ALIAS_DIDS_PAGE,
+                    until,
+                )?;
+                dids.extend(page.items);
+                match page.next {
+                    Some(next) => until = Some(next),
+                    None => break,
+                }
+            }
+        }
+        Ok(dids.len() as u64)
+    }
+}
+
+impl<R: LinkReader> LinkReader for Aliased<R> {
+    fn get_count(&self, target: &str, collection: &str, path: &str) -> Result<u64> {
+        let mut total = 0;
+        for target in self.aliases.group(target) {
+            total += self.inner.get_count(&target, collection, path)?;
+        }
+        Ok(total)
+    }
+
+    fn get_distinct_did_count(&self, target: &str, collection: &str, path: &str) -> Result<u64> {
+        match &self.aliases.group(target)[..] {
+            [only] => self.inner.get_distinct_did_count(only, collection, path),
+            group => self.union_dids(group, collection, path),
+        }
+    }
+
+    fn get_count_from_did(
+        &self,
+        target: &str,
+        collection: &str,
+        path: &str,
+        did: &Did,
+    ) -> Result<u64> {
+        let mut total = 0;
+        for target in self.aliases.group(target) {
+            total += self
+                .inner
+                .get_count_from_did(&target, collection, path, did)?;
+        }
+        Ok(total)
+    }
+
+    /// Self-links are from the canonical target's account, even to an alias
+    /// that belonged to another account (eg. from before a repo migration)
+    fn get_self_link_count(&self, target: &str, collection: &str, path: &str) -> Result<u64> {
+        let canonical = self.aliases.canonical(target);
+        match target_owner(&canonical) {
+            Some(owner) => self.get_count_from_did(&canonical, collection, path, &owner),
+            None => Ok(0),
+        }
+    }
+
+    fn get_links(
+        &self,
+        target: &str,
+        collection: &str,
+        path: &str,
+        limit: u64,
+        until: Option<u64>,
+    ) -> Result<PagedAppendingCollection<RecordId>> {
+        let target = self.aliases.canonical(target);
+        self.inner
+            .get_links(&target, collection, path, limit, until)
+    }
+
+    fn get_distinct_dids(
+        &self,
+        target: &str,
+        collection: &str,
+        path: &str,
+        limit: u64,
+        until: Option<u64>,
+    ) -> Result<PagedAppendingCollection<Did>> {
+        let target = self.aliases.canonical(target);
+        self.inner
+            .get_distinct_dids(&target, collection, path, limit, until)
+    }
+
+    fn get_distinct_dids_sketch(
+        &self,
+        target: &str,
+        collection: &str,
+        path: &str,
+        secret: &SketchSecret,
+        resume: Option<&SketchResume>,
+        budget: &mut ScanBudget,
+    ) -> Result<(DidsSketch, Option<SketchResume>)> {
+        let mut sketch = DidsSketch::default();
+        // the group is walked in order, so skip the targets done before a resume
+        let mut resume = resume;
+        for target in self.aliases.group(target) {
+            let from = match resume {
+                Some(r) if r.target != target => continue,
+                Some(r) => Some(r),
+                None => None,
+            };
+            resume = None;
+            let (other, next) = self
+                .inner
+                .get_distinct_dids_sketch(&target, collection, path, secret, from, budget)?;
+            sketch.merge(&other);
+            if next.is_some() {
+                return Ok((sketch, next));
+            }
+        }
+        Ok((sketch, None))
+    }
+
+    fn get_link_history(
+        &self,
+        target: &str,
+        collection: &str,
+        path: &str,
+        limit: u64,
+        until: Option<u64>,
+    ) -> Result<PagedAppendingCollection<LinkHistoryEvent>> {
+        let target = self.aliases.canonical(target);
+        self.inner
+            .get_link_history(&target, collection, path, limit, until)
+    }
+
+    fn get_recent_linkers(
+        &self,
+        target: &str,
+        collection: &str,
+        path: &str,
+        limit: u64,
+    ) -> Result<Vec<RecentLinker>> {
+        let target = self.aliases.canonical(target);
+        self.inner
+            .get_recent_linkers(&target, collection, path, limit)
+    }
+
+    fn get_received_counts(&self, did: &str) -> Result<HashMap<String, HashMap<String, u64>>> {
+        self.inner.get_received_counts(did)
+    }
+
+    fn get_link_types(&self) -> Result<HashMap<String, HashMap<String, u64>>> {
+        self.inner.get_link_types()
+    }
+
+    fn get_all_record_counts(&self, target: &str) -> Result<HashMap<String, HashMap<String, u64>>> {
+        let mut out: HashMap<String, HashMap<String, u64>> = HashMap::new();
+        for target in self.aliases.group(target) {
+            for (collection, paths) in self.inner.get_all_record_counts(&target)? {
+                let merged = out.entry(collection).or_default();
+                for (path, count) in paths {
+                    *merged.entry(path).or_default() += count;
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    fn get_all_counts(
+        &self,
+        target: &str,
+    ) -> Result<HashMap<String, HashMap<String, CountsByCount>>> {
+        let group = self.aliases.group(target);
+        if group.len() == 1 {
+            return self.inner.get_all_counts(&group[0]);
+        }
+        let mut out: HashMap<String, HashMap<String, CountsByCount>> = HashMap::new();
+        for target in &group {
+            for (collection, paths) in self.inner.get_all_counts(target)? {
+                let merged = out.entry(collection).or_default();
+                for (path, counts) in paths {
+                    merged
+                        .entry(path)
+                        .or_insert(CountsByCount {
+                            records: 0,
+                            distinct_dids: 0,
+                        })
+                        .records += counts.records;
+                }
+            }
+        }
+        // the same did can link to several targets in the group: union them
+        for (collection, paths) in out.iter_mut() {
+            for (path, counts) in paths.iter_mut() {
+                counts.distinct_dids = self.union_dids(&group, collection, path)?;
+            }
+        }
+        Ok(out)
+    }
+
+    fn target_exists(&self, target: &str) -> Result<bool> {
+        for target in self.aliases.group(target) {
+            if self.inner.target_exists(&target)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn pin_snapshot(&self, ttl: Duration) -> Result<Option<u64>> {
+        self.inner.pin_snapshot(ttl)
+    }
+
+    fn at_snapshot(&self, snapshot: u64) -> Result<Option<Self>> {
+        Ok(self
+            .inner
+            .at_snapshot(snapshot)?
+            .map(|inner| Self::new(inner, self.aliases.clone())))
+    }
+
+    fn release_snapshot(&self, snapshot: u64) {
+        self.inner.release_snapshot(snapshot)
+    }
+
+    fn get_stats(&self) -> Result<StorageStats> {
+        self.inner.get_stats()
+    }
+
+    fn get_daily_growth(&self) -> Result<Vec<DailyGrowth>> {
+        self.inner.get_daily_growth()
+    }
+
+    fn get_target_aliases(&self) -> Result<HashMap<String, String>> {
+        Ok(self.aliases.list())
+    }
+
+    fn set_target_alias(&self, alias: &str, canonical: Option<&str>) -> Result<()> {
+        match canonical {
+            Some(canonical) => self.aliases.set(&self.inner, alias, canonical),
+            None => self.aliases.remove(&self.inner, alias).map(|_| ()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{LinkStorage, MemStorage};
+    use crate::ActionableEvent;
+    use links::{CollectedLink, Link};
+
+    fn link(did: &str, rkey: &str, target: &str) -> ActionableEvent {
+        ActionableEvent::CreateLinks {
+            record_id: RecordId {
+                did: did.into(),
+                collection: "app.t.c".into(),
+                rkey: rkey.into(),
+            },
+            links: vec![CollectedLink {
+                target: Link::Uri(target.into()),
+                path: ".abc.uri".into(),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_aliased_counts() -> Result<()> {
+        let mut storage = MemStorage::new();
+        storage.push(&link("did:plc:a", "1", "https://a.com"), 0)?;
+        storage.push(&link("did:plc:b", "2", "http://a.com"), 0)?;
+        storage.push(&link("did:plc:a", "3", "http://a.com"), 0)?; // a links to both
+        storage.push(&link("did:plc:c", "4", "https://b.com"), 0)?;
+
+        let aliases = TargetAliases::load(&storage)?;
+        let reader = Aliased::new(storage.clone(), aliases.clone());
+        assert_eq!(reader.get_count("https://a.com", "app.t.c", ".abc.uri")?, 1);
+
+        assert_eq!(
+            aliases.conflict("https://a.com", "https://a.com"),
+            Some("a target can't be an alias of itself".into())
+        );
+        reader.set_target_alias("http://a.com", Some("https://a.com"))?;
+        assert!(aliases.conflict("https://b.com", "http://a.com").is_some());
+        assert!(aliases.conflict("https://a.com", "https://b.com").is_some());
+
+        // consolidated, from either side
+        for target in ["https://a.com", "http://a.com"] {
+            assert_eq!(reader.get_count(target, "app.t.c", ".abc.uri")?, 3);
+            assert_eq!(
+                reader.get_distinct_did_count(target, "app.t.c", ".abc.uri")?,
+                2
+            );
+            let all = reader.get_all_counts(target)?;
+            assert_eq!(
+                all["app.t.c"][".abc.uri"],
+                CountsByCount {
+                    records: 3,
+                    distinct_dids: 2
+                }
+            );
+        }
+        // listings only show the canonical target
+        let links = reader.get_links("http://a.com", "app.t.c", ".abc.uri", 10, None)?;
+        assert_eq!(links.items.len(), 1);
+
+        // persisted in storage, so a fresh table picks it up
+        let reloaded = TargetAliases::load(&storage)?;
+        assert_eq!(reloaded.canonical("http://a.com"), "https://a.com");
+
+        reader.set_target_alias("http://a.com", None)?;
+        assert_eq!(reader.get_count("https://a.com", "app.t.c", ".abc.uri")?, 1);
+        assert!(storage.get_target_aliases()?.is_empty());
+        Ok(())
+    }
+}
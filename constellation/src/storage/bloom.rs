@@ -0,0 +1,98 @@
+//! An in-memory bloom filter over every target that's ever been linked
+//!
+//! Lets existence checks for targets nobody has linked to (most of them, for
+//! cold content) skip the target_ids scan. It's rebuilt from the target_ids
+//! table on startup, so until that finishes it can't say no to anything.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+// 2^29 bits (64MiB) with 7 hashes stays under ~1% false positives up to ~50M targets
+const BLOOM_BITS_LOG2: u32 = 29;
+const BLOOM_HASHES: u64 = 7;
+
+pub struct TargetBloom {
+    bits: Box<[AtomicU64]>,
+    ready: AtomicBool,
+}
+
+impl std::fmt::Debug for TargetBloom {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TargetBloom")
+            .field("bits", &(self.bits.len() * 64))
+            .field("ready", &self.is_ready())
+            .finish()
+    }
+}
+
+impl Default for TargetBloom {
+    fn default() -> Self {
+        Self::with_bits_log2(BLOOM_BITS_LOG2)
+    }
+}
+
+impl TargetBloom {
+    fn with_bits_log2(bits_log2: u32) -> Self {
+        let words = 1 << (bits_log2 - 6);
+        Self {
+            bits: (0..words).map(|_| AtomicU64::new(0)).collect(),
+            ready: AtomicBool::new(false),
+        }
+    }
+
+    // double hashing: bit i is h1 + i * h2
+    fn positions(&self, target: &str) -> impl Iterator<Item = usize> {
+        let mut hasher = DefaultHasher::new();
+        target.hash(&mut hasher);
+        let h1 = hasher.finish();
+        1_u8.hash(&mut hasher);
+        let h2 = hasher.finish() | 1;
+        let mask = (self.bits.len() as u64 * 64) - 1;
+        (0..BLOOM_HASHES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) & mask) as usize)
+    }
+
+    pub fn insert(&self, target: &str) {
+        for bit in self.positions(target) {
+            self.bits[bit / 64].fetch_or(1 << (bit % 64), Ordering::Relaxed);
+        }
+    }
+
+    /// False only if the target was never inserted
+    pub fn may_contain(&self, target: &str) -> bool {
+        self.positions(target)
+            .all(|bit| self.bits[bit / 64].load(Ordering::Relaxed) & (1 << (bit % 64)) != 0)
+    }
+
+    /// Everything already stored has been inserted
+    pub fn mark_ready(&self) {
+        self.ready.store(true, Ordering::Release);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Acquire)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target_bloom() {
+        let bloom = TargetBloom::with_bits_log2(16);
+        assert!(!bloom.is_ready());
+        for i in 0..1000 {
+            bloom.insert(&format!("at://did:plc:asdf/app.t.c/{i}"));
+        }
+        bloom.mark_ready();
+        assert!(bloom.is_ready());
+        for i in 0..1000 {
+            assert!(bloom.may_contain(&format!("at://did:plc:asdf/app.t.c/{i}")));
+        }
+        let false_positives = (1000..11000)
+            .filter(|i| bloom.may_contain(&format!("at://did:plc:asdf/app.t.c/{i}")))
+            .count();
+        assert!(false_positives < 100, "{false_positives} false positives");
+    }
+}
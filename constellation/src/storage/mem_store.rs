@@ -1,8 +1,11 @@
-use super::{LinkReader, LinkStorage, PagedAppendingCollection, StorageStats};
+use super::{
+    cursor_day, record_owner, DailyGrowth, LinkAction, LinkHistoryEvent, LinkReader, LinkStorage,
+    PagedAppendingCollection, RecentLinker, StorageStats, RECENT_LINKERS_KEPT,
+};
 use crate::{ActionableEvent, CountsByCount, Did, RecordId};
 use anyhow::Result;
 use links::CollectedLink;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::{Arc, Mutex};
 
 // hopefully-correct simple hashmap version, intended only for tests to verify disk impl
@@ -10,12 +13,73 @@ use std::sync::{Arc, Mutex};
 pub struct MemStorage(Arc<Mutex<MemStorageData>>);
 
 type Linkers = Vec<Option<(Did, RKey)>>; // optional because we replace with None for deleted links to keep cursors stable
+type History = Vec<(u64, Did, RKey, LinkAction)>; // (cursor, linker, linker rkey, action)
+type Recent = Vec<(u64, Did, RKey)>; // (cursor, linker, linker rkey), most recent first
 
 #[derive(Debug, Default)]
 struct MemStorageData {
     dids: HashMap<Did, bool>,                           // bool: active or nah
     targets: HashMap<Target, HashMap<Source, Linkers>>, // target -> (collection, path) -> (did, rkey)?[]
     links: HashMap<Did, HashMap<RepoId, Vec<(RecordPath, Target)>>>, // did -> collection:rkey -> (path, target)[]
+    history: HashMap<Target, HashMap<Source, History>>, // only with the `history` feature
+    recent: HashMap<Target, HashMap<Source, Recent>>,   // only with the `recent-linkers` feature
+    received: HashMap<Did, HashMap<Source, u64>>, // record owner -> (collection, path) -> links to any of their records
+    link_types: HashMap<Source, u64>,             // (collection, path) -> links ever seen there
+    aliases: HashMap<String, String>,             // alias target -> canonical target
+    daily: BTreeMap<u64, DailyGrowth>,            // day -> growth
+}
+
+impl MemStorageData {
+    fn growth(&mut self, cursor: u64) -> &mut DailyGrowth {
+        let day = cursor_day(cursor);
+        self.daily.entry(day).or_insert_with(|| DailyGrowth {
+            day,
+            ..Default::default()
+        })
+    }
+
+    fn record_history(
+        &mut self,
+        target: &Target,
+        source: Source,
+        entry: (u64, Did, RKey, LinkAction),
+    ) {
+        if cfg!(feature = "history") {
+            self.history
+                .entry(target.clone())
+                .or_default()
+                .entry(source)
+                .or_default()
+                .push(entry);
+        }
+    }
+
+    fn record_recent(&mut self, target: &Target, source: Source, entry: (u64, Did, RKey)) {
+        if cfg!(feature = "recent-linkers") {
+            let recent = self
+                .recent
+                .entry(target.clone())
+                .or_default()
+                .entry(source)
+                .or_default();
+            recent.push(entry);
+            recent.sort_by_key(|(cursor, _, _)| std::cmp::Reverse(*cursor));
+            recent.truncate(RECENT_LINKERS_KEPT);
+        }
+    }
+
+    fn remove_received(&mut self, target: &Target, source: Source) {
+        let Some(owner) = record_owner(&target.0) else {
+            return;
+        };
+        if let Some(count) = self
+            .received
+            .get_mut(&owner)
+            .and_then(|sources| sources.get_mut(&source))
+        {
+            *count = count.saturating_sub(1);
+        }
+    }
 }
 
 impl MemStorage {
@@ -23,9 +87,46 @@ impl MemStorage {
         Self(Arc::new(Mutex::new(MemStorageData::default())))
     }
 
-    fn add_links(&mut self, record_id: &RecordId, links: &[CollectedLink]) {
+    fn add_links(&mut self, record_id: &RecordId, links: &[CollectedLink], cursor: u64) {
         let mut data = self.0.lock().unwrap();
+        data.growth(cursor).links_added += links.len() as u64;
+        if !links.is_empty() && !data.dids.contains_key(&record_id.did) {
+            data.growth(cursor).new_dids += 1;
+        }
         for link in links {
+            if !data
+                .targets
+                .contains_key(&Target::new(link.target.as_str()))
+            {
+                data.growth(cursor).new_targets += 1;
+            }
+            *data
+                .link_types
+                .entry(Source::new(&record_id.collection, &link.path))
+                .or_default() += 1;
+            if let Some(owner) = record_owner(link.target.as_str()) {
+                *data
+                    .received
+                    .entry(owner)
+                    .or_default()
+                    .entry(Source::new(&record_id.collection, &link.path))
+                    .or_default() += 1;
+            }
+            data.record_history(
+                &Target::new(link.target.as_str()),
+                Source::new(&record_id.collection, &link.path),
+                (
+                    cursor,
+                    record_id.did(),
+                    RKey(record_id.rkey()),
+                    LinkAction::Added,
+                ),
+            );
+            data.record_recent(
+                &Target::new(link.target.as_str()),
+                Source::new(&record_id.collection, &link.path),
+                (cursor, record_id.did(), RKey(record_id.rkey())),
+            );
             data.dids.entry(record_id.did()).or_insert(true); // if they are inserting a link, presumably they are active
             data.targets
                 .entry(Target::new(link.target.as_str()))
@@ -45,12 +146,13 @@ impl MemStorage {
         }
     }
 
-    fn remove_links(&mut self, record_id: &RecordId) {
+    fn remove_links(&mut self, record_id: &RecordId, cursor: u64) {
         let mut data = self.0.lock().unwrap();
         let repo_id = RepoId::from_record_id(record_id);
         if let Some(Some(link_targets)) = data.links.get(&record_id.did).map(|cr| cr.get(&repo_id))
         {
             let link_targets = link_targets.clone(); // satisfy borrowck
+            data.growth(cursor).links_removed += link_targets.len() as u64;
             for (record_path, target) in link_targets {
                 data.targets
                     .get_mut(&target)
@@ -61,6 +163,17 @@ impl MemStorage {
                     .rfind(|d| **d == Some((record_id.did(), RKey(record_id.rkey()))))
                     .expect("must be in dids list if we have a link to it")
                     .take();
+                data.record_history(
+                    &target,
+                    Source::new(&record_id.collection, &record_path.0),
+                    (
+                        cursor,
+                        record_id.did(),
+                        RKey(record_id.rkey()),
+                        LinkAction::Removed,
+                    ),
+                );
+                data.remove_received(&target, Source::new(&record_id.collection, &record_path.0));
             }
         }
         data.links
@@ -68,9 +181,9 @@ impl MemStorage {
             .map(|cr| cr.remove(&repo_id));
     }
 
-    fn update_links(&mut self, record_id: &RecordId, new_links: &[CollectedLink]) {
-        self.remove_links(record_id);
-        self.add_links(record_id, new_links);
+    fn update_links(&mut self, record_id: &RecordId, new_links: &[CollectedLink], cursor: u64) {
+        self.remove_links(record_id, cursor);
+        self.add_links(record_id, new_links, cursor);
     }
 
     fn set_account(&mut self, did: &Did, active: bool) {
@@ -80,12 +193,13 @@ impl MemStorage {
         }
     }
 
-    fn delete_account(&mut self, did: &Did) {
+    fn delete_account(&mut self, did: &Did, cursor: u64) {
         let mut data = self.0.lock().unwrap();
         if let Some(links) = data.links.get(did) {
             let links = links.clone();
             for (repo_id, targets) in links {
                 let targets = targets.clone();
+                data.growth(cursor).links_removed += targets.len() as u64;
                 for (record_path, target) in targets {
                     data.targets
                         .get_mut(&target)
@@ -96,6 +210,17 @@ impl MemStorage {
                         .find(|d| **d == Some((did.clone(), repo_id.rkey.clone())))
                         .expect("lkasjdlfkj")
                         .take();
+                    data.record_history(
+                        &target,
+                        Source::new(&repo_id.collection, &record_path.0),
+                        (
+                            cursor,
+                            did.clone(),
+                            repo_id.rkey.clone(),
+                            LinkAction::Removed,
+                        ),
+                    );
+                    data.remove_received(&target, Source::new(&repo_id.collection, &record_path.0));
                 }
             }
         }
@@ -111,21 +236,27 @@ impl Default for MemStorage {
 }
 
 impl LinkStorage for MemStorage {
-    fn push(&mut self, event: &ActionableEvent, _cursor: u64) -> Result<()> {
+    fn push(&mut self, event: &ActionableEvent, cursor: u64) -> Result<()> {
         match event {
-            ActionableEvent::CreateLinks { record_id, links } => self.add_links(record_id, links),
+            ActionableEvent::CreateLinks { record_id, links } => {
+                self.add_links(record_id, links, cursor)
+            }
             ActionableEvent::UpdateLinks {
                 record_id,
                 new_links,
-            } => self.update_links(record_id, new_links),
-            ActionableEvent::DeleteRecord(record_id) => self.remove_links(record_id),
+            } => self.update_links(record_id, new_links, cursor),
+            ActionableEvent::DeleteRecord(record_id) => self.remove_links(record_id, cursor),
             ActionableEvent::ActivateAccount(did) => self.set_account(did, true),
             ActionableEvent::DeactivateAccount(did) => self.set_account(did, false),
-            ActionableEvent::DeleteAccount(did) => self.delete_account(did),
+            ActionableEvent::DeleteAccount(did) => self.delete_account(did, cursor),
         }
         Ok(())
     }
 
+    fn shard_writer(&mut self) -> Result<Self> {
+        Ok(self.clone()) // every push takes the whole lock anyway
+    }
+
     fn to_readable(&mut self) -> impl LinkReader {
         self.clone()
     }
@@ -159,6 +290,23 @@ impl LinkReader for MemStorage {
             .len() as u64)
     }
 
+    fn get_count_from_did(
+        &self,
+        target: &str,
+        collection: &str,
+        path: &str,
+        did: &Did,
+    ) -> Result<u64> {
+        let data = self.0.lock().unwrap();
+        let Some(paths) = data.targets.get(&Target::new(target)) else {
+            return Ok(0);
+        };
+        let Some(linkers) = paths.get(&Source::new(collection, path)) else {
+            return Ok(0);
+        };
+        Ok(linkers.iter().flatten().filter(|(d, _)| d == did).count() as u64)
+    }
+
     fn get_links(
         &self,
         target: &str,
@@ -284,6 +432,114 @@ impl LinkReader for MemStorage {
         })
     }
 
+    fn get_link_history(
+        &self,
+        target: &str,
+        collection: &str,
+        path: &str,
+        limit: u64,
+        until: Option<u64>,
+    ) -> Result<PagedAppendingCollection<LinkHistoryEvent>> {
+        let data = self.0.lock().unwrap();
+        let Some(history) = data
+            .history
+            .get(&Target::new(target))
+            .and_then(|paths| paths.get(&Source::new(collection, path)))
+        else {
+            return Ok(PagedAppendingCollection {
+                version: (0, 0),
+                items: Vec::new(),
+                next: None,
+                total: 0,
+            });
+        };
+
+        let total = history.len();
+        let end = until
+            .map(|u| std::cmp::min(u as usize, total))
+            .unwrap_or(total);
+        let begin = end.saturating_sub(limit as usize);
+        let next = if begin == 0 { None } else { Some(begin as u64) };
+
+        let items = history[begin..end]
+            .iter()
+            .rev()
+            .map(|(cursor, did, rkey, action)| LinkHistoryEvent {
+                cursor: *cursor,
+                did: data
+                    .dids
+                    .get(did)
+                    .is_some_and(|active| *active)
+                    .then(|| did.clone()),
+                rkey: rkey.0.clone(),
+                action: *action,
+            })
+            .collect();
+
+        Ok(PagedAppendingCollection {
+            version: (total as u64, 0),
+            items,
+            next,
+            total: total as u64,
+        })
+    }
+
+    fn get_recent_linkers(
+        &self,
+        target: &str,
+        collection: &str,
+        path: &str,
+        limit: u64,
+    ) -> Result<Vec<RecentLinker>> {
+        let data = self.0.lock().unwrap();
+        let target = Target::new(target);
+        let source = Source::new(collection, path);
+        let Some(recent) = data.recent.get(&target).and_then(|s| s.get(&source)) else {
+            return Ok(Vec::new());
+        };
+        let linkers = data.targets.get(&target).and_then(|s| s.get(&source));
+        Ok(recent
+            .iter()
+            .filter(|(_, did, rkey)| {
+                data.dids.get(did).is_some_and(|active| *active)
+                    && linkers.is_some_and(|l| l.contains(&Some((did.clone(), rkey.clone()))))
+            })
+            .take(limit as usize)
+            .map(|(cursor, did, rkey)| RecentLinker {
+                cursor: *cursor,
+                did: did.clone(),
+                rkey: rkey.0.clone(),
+            })
+            .collect())
+    }
+
+    fn get_received_counts(&self, did: &str) -> Result<HashMap<String, HashMap<String, u64>>> {
+        let data = self.0.lock().unwrap();
+        let mut out: HashMap<String, HashMap<String, u64>> = HashMap::new();
+        if let Some(sources) = data.received.get(&Did::from(did)) {
+            for (Source { collection, path }, count) in sources {
+                if *count == 0 {
+                    continue;
+                }
+                out.entry(collection.to_string())
+                    .or_default()
+                    .insert(path.to_string(), *count);
+            }
+        }
+        Ok(out)
+    }
+
+    fn get_link_types(&self) -> Result<HashMap<String, HashMap<String, u64>>> {
+        let data = self.0.lock().unwrap();
+        let mut out: HashMap<String, HashMap<String, u64>> = HashMap::new();
+        for (Source { collection, path }, count) in &data.link_types {
+            out.entry(collection.to_string())
+                .or_default()
+                .insert(path.to_string(), *count);
+        }
+        Ok(out)
+    }
+
     fn get_all_record_counts(&self, target: &str) -> Result<HashMap<String, HashMap<String, u64>>> {
         let data = self.0.lock().unwrap();
         let mut out: HashMap<String, HashMap<String, u64>> = HashMap::new();
@@ -340,6 +596,25 @@ impl LinkReader for MemStorage {
             linking_records,
         })
     }
+
+    fn get_daily_growth(&self) -> Result<Vec<DailyGrowth>> {
+        Ok(self.0.lock().unwrap().daily.values().cloned().collect())
+    }
+
+    fn get_target_aliases(&self) -> Result<HashMap<String, String>> {
+        Ok(self.0.lock().unwrap().aliases.clone())
+    }
+
+    fn set_target_alias(&self, alias: &str, canonical: Option<&str>) -> Result<()> {
+        let mut data = self.0.lock().unwrap();
+        match canonical {
+            Some(canonical) => data
+                .aliases
+                .insert(alias.to_string(), canonical.to_string()),
+            None => data.aliases.remove(alias),
+        };
+        Ok(())
+    }
 }
 
 #[derive(Debug, PartialEq, Hash, Eq, Clone)]
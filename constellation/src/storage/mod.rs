@@ -1,7 +1,10 @@
 use crate::{ActionableEvent, CountsByCount, Did, RecordId};
 use anyhow::Result;
+use microcosm_estimates::{did_element, DidsSketch, SketchSecret};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::time::Duration;
 
 pub mod mem_store;
 pub use mem_store::MemStorage;
@@ -11,6 +14,18 @@ pub mod rocks_store;
 #[cfg(feature = "rocks")]
 pub use rocks_store::RocksStorage;
 
+#[cfg(feature = "rocks")]
+mod bloom;
+
+mod aliased;
+pub use aliased::{Aliased, TargetAliases};
+
+mod sharded;
+pub use sharded::{shard_for, ShardedWriter};
+
+// page size when walking every distinct did into a sketch
+const DIDS_SKETCH_PAGE: u64 = 1000;
+
 #[derive(Debug, PartialEq)]
 pub struct PagedAppendingCollection<T> {
     pub version: (u64, u64), // (collection length, deleted item count) // TODO: change to (total, active)? since dedups isn't "deleted"
@@ -19,7 +34,7 @@ pub struct PagedAppendingCollection<T> {
     pub total: u64,
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[derive(Debug, Deserialize, Serialize, PartialEq, JsonSchema)]
 pub struct StorageStats {
     /// estimate of how many accounts we've seen create links. the _subjects_ of any links are not represented here.
     /// for example: new user A follows users B and C. this count will only increment by one, for A.
@@ -35,6 +50,138 @@ pub struct StorageStats {
     pub linking_records: u64,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkAction {
+    Added,
+    Removed,
+}
+
+/// One change to the links pointing at a target
+#[derive(Debug, PartialEq, Serialize, JsonSchema)]
+pub struct LinkHistoryEvent {
+    /// jetstream cursor (unix microseconds) of the event that changed the link
+    pub cursor: u64,
+    /// `None` if the linking account is no longer active
+    pub did: Option<Did>,
+    pub rkey: String,
+    pub action: LinkAction,
+}
+
+/// How many of the latest links are kept for each target, collection, and path
+pub const RECENT_LINKERS_KEPT: usize = 16;
+
+/// A link recently added to a target
+#[derive(Debug, PartialEq, Serialize, JsonSchema)]
+pub struct RecentLinker {
+    /// jetstream cursor (unix microseconds) of the event that added the link
+    pub cursor: u64,
+    pub did: Did,
+    pub rkey: String,
+}
+
+/// Corpus-wide totals, for tracking the size of the index over time
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct DatasetStats {
+    /// estimate of accounts that have created links: see [`StorageStats::dids`]
+    pub dids: u64,
+    /// distinct targets that have ever been linked to, from any collection and path.
+    /// summed from `daily`, so it only covers links indexed since daily growth was tracked.
+    pub targets: u64,
+    /// estimate of targets * distinct (collection, path)s: see [`StorageStats::targetables`]
+    pub targetables: u64,
+    /// links ever seen, including ones removed since
+    pub links: u64,
+    /// estimate of records seen containing links: see [`StorageStats::linking_records`]
+    pub linking_records: u64,
+    /// links ever seen, by linking collection
+    pub collections: HashMap<String, CollectionStats>,
+    /// growth per UTC day, oldest first
+    pub daily: Vec<DailyGrowth>,
+}
+
+/// Links ever seen from one collection
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct CollectionStats {
+    /// across every path
+    pub links: u64,
+    /// by dot-prefixed path to the link in linking records
+    pub paths: HashMap<String, u64>,
+}
+
+/// What changed in the index over one day
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct DailyGrowth {
+    /// days since the unix epoch, by the jetstream cursors of the events
+    pub day: u64,
+    pub links_added: u64,
+    /// by record updates, deletes, and account deletes
+    pub links_removed: u64,
+    /// accounts that created their first link
+    pub new_dids: u64,
+    /// targets that got their first link from any collection and path
+    pub new_targets: u64,
+}
+
+/// A cap on how many keys one request can have storage touch
+///
+/// Walks that use it up stop early with what they have so far, and say where
+/// the next request can pick up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScanBudget(u64);
+
+impl ScanBudget {
+    pub fn new(keys: u64) -> Self {
+        Self(keys)
+    }
+
+    /// Take up to `want` keys, or whatever's left
+    pub fn take(&mut self, want: u64) -> u64 {
+        let got = want.min(self.0);
+        self.0 -= got;
+        got
+    }
+}
+
+/// Where a distinct-dids sketch walk ran out of budget
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SketchResume {
+    /// the target being walked, which may be an alias of the one asked for
+    pub target: String,
+    /// the distinct-dids paging cursor to continue from
+    pub until: Option<u64>,
+}
+
+/// The [`DailyGrowth::day`] of a jetstream cursor (unix microseconds)
+pub(crate) fn cursor_day(cursor: u64) -> u64 {
+    cursor / (86_400 * 1_000_000)
+}
+
+/// The account owning a linked record, for counting links received by an account
+///
+/// Only at-uris with a DID authority that point at (or into) a record count:
+/// links to handles or bare DIDs aren't links to one of the account's records.
+pub(crate) fn record_owner(target: &str) -> Option<Did> {
+    links::at_uri::at_uri_collection(target)?; // also checks for the at:// prefix
+    let (_, rest) = target.split_at_checked(5)?;
+    let (authority, _) = rest.split_once('/')?;
+    authority
+        .starts_with("did:")
+        .then(|| Did(authority.to_string()))
+}
+
+/// The account a target belongs to, for telling self-links apart
+///
+/// Unlike [`record_owner`], a bare DID belongs to itself, so following or
+/// mentioning yourself is a self-link too.
+pub(crate) fn target_owner(target: &str) -> Option<Did> {
+    if target.starts_with("did:") {
+        Some(Did(target.to_string()))
+    } else {
+        record_owner(target)
+    }
+}
+
 pub trait LinkStorage: Send + Sync {
     /// jetstream cursor from last saved actions, if available
     fn get_cursor(&mut self) -> Result<Option<u64>> {
@@ -43,6 +190,20 @@ pub trait LinkStorage: Send + Sync {
 
     fn push(&mut self, event: &ActionableEvent, cursor: u64) -> Result<()>;
 
+    /// save a jetstream cursor outside of `push`, for when writes are sharded
+    fn set_cursor(&mut self, _cursor: u64) -> Result<()> {
+        Ok(())
+    }
+
+    /// another writer into the same storage, to run the write path on more threads
+    ///
+    /// each writer must only be pushed events for its own disjoint set of dids
+    /// (see [`ShardedWriter`]). once a storage has shard writers, `push` stops
+    /// saving the cursor: whoever holds the original saves a low-water mark.
+    fn shard_writer(&mut self) -> Result<Self>
+    where
+        Self: Sized;
+
     // readers are  off from the writer instance
     fn to_readable(&mut self) -> impl LinkReader;
 }
@@ -52,6 +213,27 @@ pub trait LinkReader: Clone + Send + Sync + 'static {
 
     fn get_distinct_did_count(&self, target: &str, collection: &str, path: &str) -> Result<u64>;
 
+    /// Links to a target from one account's records
+    fn get_count_from_did(
+        &self,
+        target: &str,
+        collection: &str,
+        path: &str,
+        did: &Did,
+    ) -> Result<u64>;
+
+    /// Links to a target from the account it belongs to, like self-likes and self-replies
+    ///
+    /// Subtracting this from [`LinkReader::get_count`] gives the count without
+    /// self-links. They all come from one DID, so the distinct-DID count without
+    /// them is one less if this isn't zero.
+    fn get_self_link_count(&self, target: &str, collection: &str, path: &str) -> Result<u64> {
+        match target_owner(target) {
+            Some(owner) => self.get_count_from_did(target, collection, path, &owner),
+            None => Ok(0),
+        }
+    }
+
     fn get_links(
         &self,
         target: &str,
@@ -70,6 +252,80 @@ pub trait LinkReader: Clone + Send + Sync + 'static {
         until: Option<u64>,
     ) -> Result<PagedAppendingCollection<Did>>; // TODO: reflect dedups in cursor
 
+    /// A distinct-dids sketch of everything linking to a target
+    ///
+    /// Hashed the same way as ufos does it, so with a shared secret the sketch
+    /// can be merged with sketches from other services. This walks every
+    /// linking did, one key each, so for very popular targets it can run out of
+    /// `budget`: then the sketch only has the dids so far, and walking again
+    /// from the returned resume point gets a sketch of the rest to merge in.
+    fn get_distinct_dids_sketch(
+        &self,
+        target: &str,
+        collection: &str,
+        path: &str,
+        secret: &SketchSecret,
+        resume: Option<&SketchResume>,
+        budget: &mut ScanBudget,
+    ) -> Result<(DidsSketch, Option<SketchResume>)> {
+        let mut sketch = DidsSketch::default();
+        let mut until = resume.and_then(|r| r.until);
+        loop {
+            let limit = budget.take(DIDS_SKETCH_PAGE);
+            if limit == 0 {
+                let resume = SketchResume {
+                    target: target.to_string(),
+                    until,
+                };
+                return Ok((sketch, Some(resume)));
+            }
+            let page = self.get_distinct_dids(target, collection, path, limit, until)?;
+            for Did(did) in &page.items {
+                sketch.insert(did_element(secret, did));
+            }
+            match page.next {
+                Some(next) => until = Some(next),
+                None => return Ok((sketch, None)),
+            }
+        }
+    }
+
+    /// Links added to and removed from a target, most recent first
+    ///
+    /// Only recorded when built with the `history` feature: empty otherwise.
+    fn get_link_history(
+        &self,
+        target: &str,
+        collection: &str,
+        path: &str,
+        limit: u64,
+        until: Option<u64>,
+    ) -> Result<PagedAppendingCollection<LinkHistoryEvent>>;
+
+    /// The latest links to a target that are still there, most recent first
+    ///
+    /// Only the latest [`RECENT_LINKERS_KEPT`] links are kept, so links that were
+    /// removed or whose accounts are inactive can leave fewer than that. Only
+    /// recorded when built with the `recent-linkers` feature: empty otherwise.
+    fn get_recent_linkers(
+        &self,
+        target: &str,
+        collection: &str,
+        path: &str,
+        limit: u64,
+    ) -> Result<Vec<RecentLinker>>;
+
+    /// Links to any of an account's records, summed by linking collection and path
+    ///
+    /// For example, `received["app.bsky.feed.like"][".subject.uri"]` is every like
+    /// on every record (posts, mostly) of the account.
+    fn get_received_counts(&self, did: &str) -> Result<HashMap<String, HashMap<String, u64>>>;
+
+    /// Every collection and path that a link has ever been seen at, with how many
+    ///
+    /// These counts only go up: links that were later removed are still counted.
+    fn get_link_types(&self) -> Result<HashMap<String, HashMap<String, u64>>>;
+
     fn get_all_record_counts(&self, _target: &str)
         -> Result<HashMap<String, HashMap<String, u64>>>;
 
@@ -78,8 +334,72 @@ pub trait LinkReader: Clone + Send + Sync + 'static {
         _target: &str,
     ) -> Result<HashMap<String, HashMap<String, CountsByCount>>>;
 
+    /// Whether anything has ever linked to the target, from any collection and path
+    ///
+    /// Meant to be cheap, so that clients can skip asking for counts of cold
+    /// targets. Targets whose links were all removed may still exist.
+    fn target_exists(&self, target: &str) -> Result<bool> {
+        Ok(!self.get_all_record_counts(target)?.is_empty())
+    }
+
+    /// Pin a consistent view of storage for paging through results
+    ///
+    /// Returns an id for `at_snapshot`, or `None` if this storage can't pin one
+    /// right now. Pins expire when they go unused for `ttl`.
+    fn pin_snapshot(&self, _ttl: Duration) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// A reader that sees storage as of a pinned snapshot, if it hasn't expired
+    fn at_snapshot(&self, _snapshot: u64) -> Result<Option<Self>> {
+        Ok(None)
+    }
+
+    /// Let go of a pinned snapshot early
+    fn release_snapshot(&self, _snapshot: u64) {}
+
     /// assume all stats are estimates, since exact counts are very challenging for LSMs
     fn get_stats(&self) -> Result<StorageStats>;
+
+    /// Links added and removed, and first links from accounts and to targets, per day
+    ///
+    /// Oldest first. Days with nothing indexed are left out.
+    fn get_daily_growth(&self) -> Result<Vec<DailyGrowth>>;
+
+    /// [`get_stats`](LinkReader::get_stats), with per-collection and per-day breakdowns
+    fn get_dataset_stats(&self) -> Result<DatasetStats> {
+        let StorageStats {
+            dids,
+            targetables,
+            linking_records,
+        } = self.get_stats()?;
+        let daily = self.get_daily_growth()?;
+        let collections: HashMap<String, CollectionStats> = self
+            .get_link_types()?
+            .into_iter()
+            .map(|(collection, paths)| {
+                let links = paths.values().sum();
+                (collection, CollectionStats { links, paths })
+            })
+            .collect();
+        Ok(DatasetStats {
+            dids,
+            targets: daily.iter().map(|day| day.new_targets).sum(),
+            targetables,
+            links: collections.values().map(|c| c.links).sum(),
+            linking_records,
+            collections,
+            daily,
+        })
+    }
+
+    /// Admin-managed aliases: alias target -> canonical target
+    fn get_target_aliases(&self) -> Result<HashMap<String, String>>;
+
+    /// Save an alias, or remove it with `None`
+    ///
+    /// Aliases aren't link data, so any handle on writable storage can change them.
+    fn set_target_alias(&self, alias: &str, canonical: Option<&str>) -> Result<()>;
 }
 
 #[cfg(test)]
@@ -167,6 +487,7 @@ mod tests {
             storage.get_all_record_counts("bad-example.com")?,
             HashMap::new()
         );
+        assert!(!storage.target_exists("bad-example.com")?);
 
         assert_stats(storage.get_stats()?, 0..=0, 0..=0, 0..=0);
     });
@@ -187,6 +508,8 @@ mod tests {
             0,
         )?;
         assert_eq!(storage.get_count("e.com", "app.t.c", ".abc.uri")?, 1);
+        assert!(storage.target_exists("e.com")?);
+        assert!(!storage.target_exists("f.com")?);
         assert_eq!(
             storage.get_distinct_did_count("e.com", "app.t.c", ".abc.uri")?,
             1
@@ -1082,4 +1405,525 @@ mod tests {
         });
         assert_stats(storage.get_stats()?, 1..=1, 2..=2, 1..=1);
     });
+
+    #[cfg(feature = "history")]
+    test_each_storage!(link_history, |storage| {
+        let record_id = || RecordId {
+            did: "did:plc:asdf".into(),
+            collection: "app.t.c".into(),
+            rkey: "fdsa".into(),
+        };
+        let links = || {
+            vec![CollectedLink {
+                target: Link::Uri("e.com".into()),
+                path: ".abc.uri".into(),
+            }]
+        };
+        storage.push(
+            &ActionableEvent::CreateLinks {
+                record_id: record_id(),
+                links: links(),
+            },
+            10,
+        )?;
+        storage.push(&ActionableEvent::DeleteRecord(record_id()), 20)?;
+        storage.push(
+            &ActionableEvent::CreateLinks {
+                record_id: record_id(),
+                links: links(),
+            },
+            30,
+        )?;
+
+        let event = |cursor, action| LinkHistoryEvent {
+            cursor,
+            did: Some("did:plc:asdf".into()),
+            rkey: "fdsa".into(),
+            action,
+        };
+        assert_eq!(
+            storage.get_link_history("e.com", "app.t.c", ".abc.uri", 2, None)?,
+            PagedAppendingCollection {
+                version: (3, 0),
+                items: vec![event(30, LinkAction::Added), event(20, LinkAction::Removed)],
+                next: Some(1),
+                total: 3,
+            }
+        );
+        assert_eq!(
+            storage.get_link_history("e.com", "app.t.c", ".abc.uri", 2, Some(1))?,
+            PagedAppendingCollection {
+                version: (3, 0),
+                items: vec![event(10, LinkAction::Added)],
+                next: None,
+                total: 3,
+            }
+        );
+
+        // deactivated linkers stay in the history, but anonymously
+        storage.push(
+            &ActionableEvent::DeactivateAccount("did:plc:asdf".into()),
+            40,
+        )?;
+        let history = storage.get_link_history("e.com", "app.t.c", ".abc.uri", 10, None)?;
+        assert_eq!(history.items.len(), 3);
+        assert!(history.items.iter().all(|e| e.did.is_none()));
+
+        assert_eq!(
+            storage.get_link_history("e.com", "app.t.c", ".bad.uri", 10, None)?,
+            PagedAppendingCollection {
+                version: (0, 0),
+                items: vec![],
+                next: None,
+                total: 0,
+            }
+        );
+    });
+
+    #[cfg(feature = "recent-linkers")]
+    test_each_storage!(recent_linkers, |storage| {
+        let record_id = |i: u64| RecordId {
+            did: "did:plc:asdf".into(),
+            collection: "app.t.c".into(),
+            rkey: format!("rkey-{i}"),
+        };
+        for i in 0..20 {
+            storage.push(
+                &ActionableEvent::CreateLinks {
+                    record_id: record_id(i),
+                    links: vec![CollectedLink {
+                        target: Link::Uri("e.com".into()),
+                        path: ".abc.uri".into(),
+                    }],
+                },
+                i,
+            )?;
+        }
+        let rkeys = |recent: Vec<RecentLinker>| {
+            recent
+                .into_iter()
+                .map(|r| (r.cursor, r.rkey))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            rkeys(storage.get_recent_linkers("e.com", "app.t.c", ".abc.uri", 3)?),
+            vec![
+                (19, "rkey-19".into()),
+                (18, "rkey-18".into()),
+                (17, "rkey-17".into())
+            ]
+        );
+
+        // removed links are skipped, leaving fewer than were kept
+        storage.push(&ActionableEvent::DeleteRecord(record_id(19)), 20)?;
+        let recent = storage.get_recent_linkers("e.com", "app.t.c", ".abc.uri", 100)?;
+        assert_eq!(recent.len(), RECENT_LINKERS_KEPT - 1);
+        assert_eq!(recent[0].rkey, "rkey-18");
+        assert_eq!(recent[0].did, "did:plc:asdf".into());
+
+        assert_eq!(
+            storage.get_recent_linkers("e.com", "app.t.c", ".bad.uri", 100)?,
+            vec![]
+        );
+
+        storage.push(
+            &ActionableEvent::DeactivateAccount("did:plc:asdf".into()),
+            21,
+        )?;
+        assert_eq!(
+            storage.get_recent_linkers("e.com", "app.t.c", ".abc.uri", 100)?,
+            vec![]
+        );
+    });
+
+    test_each_storage!(self_links, |storage| {
+        let post = "at://did:plc:asdf/app.bsky.feed.post/3lf6yc4drhk2f";
+        let like = |did: &str, rkey: &str| ActionableEvent::CreateLinks {
+            record_id: RecordId {
+                did: did.into(),
+                collection: "app.t.c".into(),
+                rkey: rkey.into(),
+            },
+            links: vec![CollectedLink {
+                target: Link::AtUri(post.into()),
+                path: ".subject.uri".into(),
+            }],
+        };
+        storage.push(&like("did:plc:asdf", "a"), 0)?;
+        storage.push(&like("did:plc:asdf", "b"), 1)?;
+        storage.push(&like("did:plc:fdsa", "c"), 2)?;
+        assert_eq!(storage.get_count(post, "app.t.c", ".subject.uri")?, 3);
+        assert_eq!(
+            storage.get_self_link_count(post, "app.t.c", ".subject.uri")?,
+            2
+        );
+        assert_eq!(
+            storage.get_count_from_did(post, "app.t.c", ".subject.uri", &"did:plc:fdsa".into())?,
+            1
+        );
+        assert_eq!(
+            storage.get_count_from_did(post, "app.t.c", ".subject.uri", &"did:plc:nope".into())?,
+            0
+        );
+
+        // a bare did target belongs to itself
+        storage.push(
+            &ActionableEvent::CreateLinks {
+                record_id: RecordId {
+                    did: "did:plc:fdsa".into(),
+                    collection: "app.t.f".into(),
+                    rkey: "f".into(),
+                },
+                links: vec![CollectedLink {
+                    target: Link::Did("did:plc:fdsa".into()),
+                    path: ".subject".into(),
+                }],
+            },
+            3,
+        )?;
+        assert_eq!(
+            storage.get_self_link_count("did:plc:fdsa", "app.t.f", ".subject")?,
+            1
+        );
+
+        // removed self-links don't count
+        storage.push(
+            &ActionableEvent::DeleteRecord(RecordId {
+                did: "did:plc:asdf".into(),
+                collection: "app.t.c".into(),
+                rkey: "a".into(),
+            }),
+            4,
+        )?;
+        assert_eq!(
+            storage.get_self_link_count(post, "app.t.c", ".subject.uri")?,
+            1
+        );
+        assert_eq!(
+            storage.get_self_link_count("e.com", "app.t.c", ".subject.uri")?,
+            0
+        );
+    });
+
+    test_each_storage!(received_counts, |storage| {
+        let like = |rkey: &str, target: &str| ActionableEvent::CreateLinks {
+            record_id: RecordId {
+                did: "did:plc:liker".into(),
+                collection: "app.t.like".into(),
+                rkey: rkey.into(),
+            },
+            links: vec![CollectedLink {
+                target: Link::AtUri(target.into()),
+                path: ".subject.uri".into(),
+            }],
+        };
+        storage.push(&like("1", "at://did:plc:author/app.t.post/a"), 0)?;
+        storage.push(&like("2", "at://did:plc:author/app.t.post/b"), 0)?;
+        storage.push(&like("3", "at://did:plc:someone-else/app.t.post/c"), 0)?;
+        // not links to the author's records
+        storage.push(&like("4", "at://author.example.com/app.t.post/d"), 0)?;
+        storage.push(
+            &ActionableEvent::CreateLinks {
+                record_id: RecordId {
+                    did: "did:plc:liker".into(),
+                    collection: "app.t.follow".into(),
+                    rkey: "5".into(),
+                },
+                links: vec![CollectedLink {
+                    target: Link::Did("did:plc:author".into()),
+                    path: ".subject".into(),
+                }],
+            },
+            0,
+        )?;
+
+        let received = |n| {
+            let mut out: HashMap<String, HashMap<String, u64>> = HashMap::new();
+            out.entry("app.t.like".into())
+                .or_default()
+                .insert(".subject.uri".into(), n);
+            out
+        };
+        assert_eq!(storage.get_received_counts("did:plc:author")?, received(2));
+        assert_eq!(
+            storage.get_received_counts("did:plc:someone-else")?,
+            received(1)
+        );
+
+        storage.push(
+            &ActionableEvent::DeleteRecord(RecordId {
+                did: "did:plc:liker".into(),
+                collection: "app.t.like".into(),
+                rkey: "1".into(),
+            }),
+            0,
+        )?;
+        assert_eq!(storage.get_received_counts("did:plc:author")?, received(1));
+
+        storage.push(&ActionableEvent::DeleteAccount("did:plc:liker".into()), 0)?;
+        assert_eq!(
+            storage.get_received_counts("did:plc:author")?,
+            HashMap::new()
+        );
+        assert_eq!(
+            storage.get_received_counts("did:plc:nobody")?,
+            HashMap::new()
+        );
+    });
+
+    test_each_storage!(link_types, |storage| {
+        assert_eq!(storage.get_link_types()?, HashMap::new());
+
+        let record =
+            |collection: &str, rkey: &str, links: Vec<(&str, &str)>| ActionableEvent::CreateLinks {
+                record_id: RecordId {
+                    did: "did:plc:asdf".into(),
+                    collection: collection.into(),
+                    rkey: rkey.into(),
+                },
+                links: links
+                    .into_iter()
+                    .map(|(path, target)| CollectedLink {
+                        target: Link::Uri(target.into()),
+                        path: path.into(),
+                    })
+                    .collect(),
+            };
+        storage.push(&record("app.t.c", "1", vec![(".abc.uri", "a.com")]), 0)?;
+        storage.push(
+            &record(
+                "app.t.c",
+                "2",
+                vec![(".abc.uri", "b.com"), (".def", "c.com")],
+            ),
+            0,
+        )?;
+        storage.push(&record("app.t.d", "3", vec![(".abc.uri", "a.com")]), 0)?;
+        // removed links stay counted
+        storage.push(
+            &ActionableEvent::DeleteRecord(RecordId {
+                did: "did:plc:asdf".into(),
+                collection: "app.t.c".into(),
+                rkey: "1".into(),
+            }),
+            0,
+        )?;
+
+        let mut expected: HashMap<String, HashMap<String, u64>> = HashMap::new();
+        let c = expected.entry("app.t.c".into()).or_default();
+        c.insert(".abc.uri".into(), 2);
+        c.insert(".def".into(), 1);
+        expected
+            .entry("app.t.d".into())
+            .or_default()
+            .insert(".abc.uri".into(), 1);
+        assert_eq!(storage.get_link_types()?, expected);
+    });
+
+    test_each_storage!(dataset_stats, |storage| {
+        assert_eq!(storage.get_daily_growth()?, vec![]);
+
+        let day = 86_400 * 1_000_000;
+        let record =
+            |did: &str, rkey: &str, links: Vec<(&str, &str)>| ActionableEvent::CreateLinks {
+                record_id: RecordId {
+                    did: did.into(),
+                    collection: "app.t.c".into(),
+                    rkey: rkey.into(),
+                },
+                links: links
+                    .into_iter()
+                    .map(|(path, target)| CollectedLink {
+                        target: Link::Uri(target.into()),
+                        path: path.into(),
+                    })
+                    .collect(),
+            };
+        storage.push(
+            &record("did:plc:asdf", "1", vec![(".abc.uri", "a.com")]),
+            3 * day,
+        )?;
+        storage.push(
+            &record(
+                "did:plc:asdf",
+                "2",
+                vec![(".abc.uri", "b.com"), (".def", "a.com")],
+            ),
+            3 * day + 1,
+        )?;
+        storage.push(
+            &record("did:plc:fdsa", "1", vec![(".abc.uri", "b.com")]),
+            5 * day,
+        )?;
+        storage.push(
+            &ActionableEvent::DeleteRecord(RecordId {
+                did: "did:plc:asdf".into(),
+                collection: "app.t.c".into(),
+                rkey: "2".into(),
+            }),
+            5 * day + 1,
+        )?;
+
+        assert_eq!(
+            storage.get_daily_growth()?,
+            vec![
+                DailyGrowth {
+                    day: 3,
+                    links_added: 3,
+                    links_removed: 0,
+                    new_dids: 1,
+                    new_targets: 2,
+                },
+                DailyGrowth {
+                    day: 5,
+                    links_added: 1,
+                    links_removed: 2,
+                    new_dids: 1,
+                    new_targets: 0,
+                },
+            ]
+        );
+
+        let stats = storage.get_dataset_stats()?;
+        assert_eq!(stats.targets, 2);
+        assert_eq!(stats.links, 4);
+        assert_eq!(stats.collections["app.t.c"].links, 4);
+        assert_eq!(stats.collections["app.t.c"].paths[".abc.uri"], 3);
+        assert_eq!(stats.collections["app.t.c"].paths[".def"], 1);
+        assert_eq!(stats.daily.len(), 2);
+    });
+
+    test_each_storage!(target_aliases, |storage| {
+        assert_eq!(storage.get_target_aliases()?, HashMap::new());
+        storage.set_target_alias("http://a.com", Some("https://a.com"))?;
+        storage.set_target_alias("at://old/app.t.c/1", Some("at://new/app.t.c/1"))?;
+        storage.set_target_alias("http://a.com", None)?;
+        assert_eq!(
+            storage.get_target_aliases()?,
+            HashMap::from([("at://old/app.t.c/1".into(), "at://new/app.t.c/1".into())])
+        );
+    });
+
+    test_each_storage!(distinct_dids_sketch, |storage| {
+        let secret = [7; 16];
+        let dids: Vec<String> = (0..1200).map(|i| format!("did:plc:{i}")).collect();
+        for (i, did) in dids.iter().enumerate() {
+            storage.push(
+                &ActionableEvent::CreateLinks {
+                    record_id: RecordId {
+                        did: did.into(),
+                        collection: "app.t.c".into(),
+                        rkey: "fake-rkey".into(),
+                    },
+                    links: vec![CollectedLink {
+                        target: Link::Uri("a.com".into()),
+                        path: ".abc.uri".into(),
+                    }],
+                },
+                i as u64,
+            )?;
+        }
+        // spans more than one page of dids
+        let (sketch, resume) = storage.get_distinct_dids_sketch(
+            "a.com",
+            "app.t.c",
+            ".abc.uri",
+            &secret,
+            None,
+            &mut ScanBudget::new(10_000),
+        )?;
+        let expected = microcosm_estimates::sketch_dids(&secret, dids.iter().map(|d| d.as_str()));
+        assert_eq!(
+            microcosm_estimates::estimate(&sketch),
+            microcosm_estimates::estimate(&expected)
+        );
+        assert_eq!(resume, None);
+
+        // a small budget takes a few walks, whose sketches merge into the same thing
+        let mut merged = DidsSketch::default();
+        let mut resume = None;
+        let mut walks = 0;
+        loop {
+            let (sketch, next) = storage.get_distinct_dids_sketch(
+                "a.com",
+                "app.t.c",
+                ".abc.uri",
+                &secret,
+                resume.as_ref(),
+                &mut ScanBudget::new(500),
+            )?;
+            merged.merge(&sketch);
+            walks += 1;
+            match next {
+                Some(next) => resume = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(walks, 3);
+        assert_eq!(
+            microcosm_estimates::estimate(&merged),
+            microcosm_estimates::estimate(&expected)
+        );
+
+        let (empty, resume) = storage.get_distinct_dids_sketch(
+            "b.com",
+            "app.t.c",
+            ".abc.uri",
+            &secret,
+            None,
+            &mut ScanBudget::new(10_000),
+        )?;
+        assert_eq!(microcosm_estimates::estimate(&empty), 0);
+        assert_eq!(resume, None);
+    });
+
+    test_each_storage!(sharded_writes, |storage| {
+        let reader = storage.to_readable();
+        let mut sharded = ShardedWriter::new(storage, 4)?;
+        let like = |did: &str, rkey: &str, target: &str| ActionableEvent::CreateLinks {
+            record_id: RecordId {
+                did: did.into(),
+                collection: "app.t.c".into(),
+                rkey: rkey.into(),
+            },
+            links: vec![CollectedLink {
+                target: Link::Uri(target.into()),
+                path: ".abc.uri".into(),
+            }],
+        };
+        let mut cursor = 0;
+        let mut next = || {
+            cursor += 1;
+            cursor
+        };
+        // lots of dids landing on every shard, all linking the same targets
+        for i in 0..200 {
+            let did = format!("did:plc:{i}");
+            sharded.push(like(&did, "a", "a.com"), next())?;
+            sharded.push(like(&did, "b", "b.com"), next())?;
+        }
+        for i in (0..200).step_by(2) {
+            let record_id = RecordId {
+                did: format!("did:plc:{i}").into(),
+                collection: "app.t.c".into(),
+                rkey: "a".into(),
+            };
+            sharded.push(ActionableEvent::DeleteRecord(record_id), next())?;
+        }
+        for i in (0..200).step_by(4) {
+            sharded.push(
+                ActionableEvent::DeleteAccount(format!("did:plc:{i}").into()),
+                next(),
+            )?;
+        }
+        assert_eq!(sharded.finish()?, Some(550));
+
+        assert_eq!(reader.get_count("a.com", "app.t.c", ".abc.uri")?, 100);
+        assert_eq!(reader.get_count("b.com", "app.t.c", ".abc.uri")?, 150);
+        assert_eq!(
+            reader.get_distinct_did_count("b.com", "app.t.c", ".abc.uri")?,
+            150
+        );
+        assert_stats(reader.get_stats()?, 150..=200, 2..=2, 250..=400);
+    });
 }
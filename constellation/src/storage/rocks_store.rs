@@ -1,4 +1,9 @@
-use super::{ActionableEvent, LinkReader, LinkStorage, PagedAppendingCollection, StorageStats};
+use super::bloom::TargetBloom;
+use super::{
+    cursor_day, record_owner, ActionableEvent, DailyGrowth, LinkAction, LinkHistoryEvent,
+    LinkReader, LinkStorage, PagedAppendingCollection, RecentLinker, StorageStats,
+    RECENT_LINKERS_KEPT,
+};
 use crate::{CountsByCount, Did, RecordId};
 use anyhow::{bail, Result};
 use bincode::Options as BincodeOptions;
@@ -8,28 +13,45 @@ use ratelimit::Ratelimiter;
 use rocksdb::backup::{BackupEngine, BackupEngineOptions};
 use rocksdb::{
     AsColumnFamilyRef, ColumnFamilyDescriptor, DBWithThreadMode, IteratorMode, MergeOperands,
-    MultiThreaded, Options, PrefixRange, ReadOptions, WriteBatch,
+    MultiThreaded, Options, PrefixRange, ReadOptions, SnapshotWithThreadMode, WriteBatch,
 };
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::io::Read;
 use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
 use std::path::{Path, PathBuf};
 use std::sync::{
     atomic::{AtomicU64, Ordering},
-    Arc,
+    Arc, Mutex, MutexGuard,
 };
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio_util::sync::CancellationToken;
 
 static DID_IDS_CF: &str = "did_ids";
 static TARGET_IDS_CF: &str = "target_ids";
 static TARGET_LINKERS_CF: &str = "target_links";
 static LINK_TARGETS_CF: &str = "link_targets";
+static TARGET_HISTORY_CF: &str = "target_history";
+static TARGET_RECENT_CF: &str = "target_recent";
+static DID_RECEIVED_CF: &str = "did_received";
+static RECORD_LINK_OWNERS_CF: &str = "record_link_owners";
+static LINK_TYPES_CF: &str = "link_types";
+static TARGET_ALIASES_CF: &str = "target_aliases";
+static DAILY_GROWTH_CF: &str = "daily_growth";
 
 static JETSTREAM_CURSOR_KEY: &str = "jetstream_cursor";
 
+// each pinned snapshot holds back compaction, so don't let them pile up
+const MAX_PINNED_SNAPSHOTS: usize = 1024;
+
+// lock stripes shared by sharded writers for target linker updates
+const TARGET_LOCK_STRIPES: usize = 4096;
+
+// full and partial merges have to be the same type
+type MergeOp = fn(&[u8], Option<&[u8]>, &MergeOperands) -> Option<Vec<u8>>;
+
 // todo: actually understand and set these options probably better
 fn rocks_opts_base() -> Options {
     let mut opts = Options::default();
@@ -40,6 +62,26 @@ fn rocks_opts_base() -> Options {
                                                                             // TODO: actually enable the bottommost compression. but after other changes run for a bit in case zstd is cpu- or mem-expensive.
     opts
 }
+
+// target_links values are lots of small, very similar lists of (did id, rkey),
+// so a zstd dictionary trained per sst (see `bin/rocks-dict-stats`) pays off.
+const LINKERS_ZSTD_LEVEL: i32 = 3;
+const LINKERS_DICT_BYTES: i32 = 16 * 1024;
+const LINKERS_DICT_TRAIN_BYTES: i32 = 100 * LINKERS_DICT_BYTES; // zstd suggests ~100x the dict size
+fn linkers_opts() -> Options {
+    let mut opts = rocks_opts_base();
+    // only the bottommost level: that's where nearly all of the data ends up,
+    // and it's compacted rarely enough that training the dictionary is cheap.
+    opts.set_bottommost_compression_options(
+        -14, // window bits (rocksdb's default)
+        LINKERS_ZSTD_LEVEL,
+        0, // strategy (default)
+        LINKERS_DICT_BYTES,
+        true,
+    );
+    opts.set_bottommost_zstd_max_train_bytes(LINKERS_DICT_TRAIN_BYTES, true);
+    opts
+}
 fn get_db_opts() -> Options {
     let mut opts = rocks_opts_base();
     opts.create_missing_column_families(true);
@@ -52,6 +94,19 @@ fn get_db_read_opts() -> Options {
     opts.optimize_for_point_lookup(16_384); // mb (run this on big machines)
     opts
 }
+fn get_db_secondary_opts() -> Options {
+    let mut opts = get_db_read_opts();
+    opts.set_max_open_files(-1); // secondaries have to keep every table file open to follow the primary
+    opts.set_allow_mmap_reads(true);
+    opts
+}
+
+enum OpenMode<'a> {
+    Writer,
+    ReadOnly,
+    /// follows a primary that's writing to the db, keeping its own state in this dir
+    Secondary(&'a Path),
+}
 
 #[derive(Debug, Clone)]
 pub struct RocksStorage {
@@ -59,7 +114,110 @@ pub struct RocksStorage {
     did_id_table: IdTable<Did, DidIdValue, true>,
     target_id_table: IdTable<TargetKey, TargetId, false>,
     is_writer: bool,
+    writes_cursor: bool,
+    target_locks: Option<Arc<TargetLocks>>, // only when there are shard writers
     backup_task: Arc<Option<thread::JoinHandle<Result<()>>>>,
+    snapshots: Arc<Mutex<PinnedSnapshots>>,
+    at_snapshot: Option<Arc<PinnedSnapshot>>,
+    target_bloom: Option<Arc<TargetBloom>>, // only when opened as the writer
+}
+
+/// Striped locks over target ids for writers on different threads
+///
+/// Shard writers own disjoint dids, but the targets they link to are shared.
+/// Adding a linker is a merge, but removing one is read-modify-write, so a
+/// writer holds the stripes of every target it touches until its batch is in.
+#[derive(Debug)]
+struct TargetLocks(Vec<Mutex<()>>);
+
+impl TargetLocks {
+    fn new() -> Self {
+        Self((0..TARGET_LOCK_STRIPES).map(|_| Mutex::new(())).collect())
+    }
+    fn lock<'t>(&self, targets: impl IntoIterator<Item = &'t TargetId>) -> Vec<MutexGuard<'_, ()>> {
+        let stripes: BTreeSet<usize> = targets
+            .into_iter()
+            .map(|TargetId(id)| (id % self.0.len() as u64) as usize)
+            .collect();
+        // always taken in order, so writers can't deadlock each other
+        stripes
+            .into_iter()
+            .map(|i| self.0[i].lock().unwrap())
+            .collect()
+    }
+}
+
+/// A rocksdb snapshot that owns the db it was taken from
+///
+/// rocksdb's snapshots borrow the db, which can't be expressed for one that
+/// outlives the request that pinned it. This keeps an Arc of the db next to
+/// the snapshot and only ever hands the snapshot out borrowed from `self`.
+///
+/// Invariant: `snapshot` is released in [`Drop`], before `db` is, so the db
+/// it borrows is always still open.
+struct PinnedSnapshot {
+    /// Not really `'static`: it borrows from `db`. Never moved out of `self`.
+    snapshot: ManuallyDrop<SnapshotWithThreadMode<'static, DBWithThreadMode<MultiThreaded>>>,
+    db: Arc<DBWithThreadMode<MultiThreaded>>,
+}
+impl PinnedSnapshot {
+    fn new(db: &Arc<DBWithThreadMode<MultiThreaded>>) -> Self {
+        let db = db.clone();
+        let snapshot = db.snapshot();
+        // SAFETY: the snapshot borrows the db behind the Arc that we store
+        // alongside it, and the Arc's target doesn't move when the Arc does.
+        // `Drop` releases the snapshot before that Arc is dropped, and
+        // `snapshot()` never lets the 'static lifetime escape.
+        let snapshot = unsafe {
+            std::mem::transmute::<
+                SnapshotWithThreadMode<'_, DBWithThreadMode<MultiThreaded>>,
+                SnapshotWithThreadMode<'static, DBWithThreadMode<MultiThreaded>>,
+            >(snapshot)
+        };
+        Self {
+            snapshot: ManuallyDrop::new(snapshot),
+            db,
+        }
+    }
+    fn snapshot(&self) -> &SnapshotWithThreadMode<'_, DBWithThreadMode<MultiThreaded>> {
+        &self.snapshot
+    }
+}
+impl Drop for PinnedSnapshot {
+    fn drop(&mut self) {
+        // SAFETY: `snapshot` isn't used again after this, and `db` (which it
+        // borrows) is only dropped after this returns.
+        unsafe { ManuallyDrop::drop(&mut self.snapshot) };
+    }
+}
+impl std::fmt::Debug for PinnedSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PinnedSnapshot")
+    }
+}
+
+/// snapshots pinned for api pagination, by id
+#[derive(Debug)]
+struct PinnedSnapshots {
+    next_id: u64,
+    pinned: HashMap<u64, (Arc<PinnedSnapshot>, Duration, Instant)>, // (snapshot, ttl, expires)
+}
+impl PinnedSnapshots {
+    fn new() -> Self {
+        // start ids from the clock so that cursors from before a restart are unlikely to collide
+        let next_id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(1);
+        Self {
+            next_id,
+            pinned: HashMap::new(),
+        }
+    }
+    fn expire(&mut self) {
+        let now = Instant::now();
+        self.pinned.retain(|_, (_, _, expires)| *expires > now);
+    }
 }
 
 trait IdTableValue: ValueFromRocks + Clone {
@@ -76,6 +234,7 @@ where
     _val_marker: PhantomData<IdVal>,
     name: String,
     id_seq: Arc<AtomicU64>,
+    create_lock: Arc<Mutex<()>>,
 }
 impl<Orig, IdVal: IdTableValue> IdTableBase<Orig, IdVal>
 where
@@ -92,7 +251,11 @@ where
         if db.cf_handle(&self.name).is_none() {
             bail!("failed to get cf handle from db -- was the db open with our .cf_descriptor()?");
         }
-        let priv_id_seq = if let Some(seq_bytes) = db.get(self.seq_key())? {
+        self.id_seq.store(self.read_seq(db)?, Ordering::SeqCst);
+        Ok(IdTable { base: self })
+    }
+    fn read_seq(&self, db: &DBWithThreadMode<MultiThreaded>) -> Result<u64> {
+        let id_seq = if let Some(seq_bytes) = db.get(self.seq_key())? {
             if seq_bytes.len() != 8 {
                 bail!(
                     "reading bytes for u64 id seq {:?}: found the wrong number of bytes",
@@ -106,11 +269,7 @@ where
         } else {
             1
         };
-        self.id_seq.store(priv_id_seq, Ordering::SeqCst);
-        Ok(IdTable {
-            base: self,
-            priv_id_seq,
-        })
+        Ok(id_seq)
     }
     fn seq_key(&self) -> Vec<u8> {
         let mut k = b"__id_seq_key_plz_be_unique:".to_vec();
@@ -125,7 +284,6 @@ where
     for<'a> &'a Orig: AsRocksKey,
 {
     base: IdTableBase<Orig, IdVal>,
-    priv_id_seq: u64,
 }
 impl<Orig: Clone, IdVal: IdTableValue, const WITH_REVERSE: bool> IdTable<Orig, IdVal, WITH_REVERSE>
 where
@@ -140,47 +298,61 @@ where
             _val_marker: PhantomData,
             name: name.into(),
             id_seq: Arc::new(AtomicU64::new(0)), // zero is "uninint", first seq num will be 1
+            create_lock: Arc::new(Mutex::new(())),
         }
     }
     fn get_id_val(
         &self,
         db: &DBWithThreadMode<MultiThreaded>,
         orig: &Orig,
+        read_opts: &ReadOptions,
     ) -> Result<Option<IdVal>> {
         let cf = db.cf_handle(&self.base.name).unwrap();
-        if let Some(_id_bytes) = db.get_cf(&cf, _rk(orig))? {
+        if let Some(_id_bytes) = db.get_cf_opt(&cf, _rk(orig), read_opts)? {
             Ok(Some(_vr(&_id_bytes)?))
         } else {
             Ok(None)
         }
     }
+    /// ids are shared by every writer, so creating one is serialized and written
+    /// right away instead of going out with the caller's batch. if that batch
+    /// fails, the new id is just never referenced.
     fn __get_or_create_id_val<CF>(
-        &mut self,
+        &self,
         cf: &CF,
         db: &DBWithThreadMode<MultiThreaded>,
-        batch: &mut WriteBatch,
         orig: &Orig,
+        also: impl FnOnce(&IdVal, &mut WriteBatch),
     ) -> Result<IdVal>
     where
         CF: AsColumnFamilyRef,
     {
-        Ok(self.get_id_val(db, orig)?.unwrap_or_else(|| {
-            let prev_priv_seq = self.priv_id_seq;
-            self.priv_id_seq += 1;
-            let prev_public_seq = self.base.id_seq.swap(self.priv_id_seq, Ordering::SeqCst);
-            assert_eq!(
-                prev_public_seq, prev_priv_seq,
-                "public seq may have been modified??"
-            );
-            let id_value = IdVal::new(self.priv_id_seq);
-            batch.put(self.base.seq_key(), self.priv_id_seq.to_le_bytes());
-            batch.put_cf(cf, _rk(orig), _rv(&id_value));
-            id_value
-        }))
+        if let Some(id_value) = self.get_id_val(db, orig, &ReadOptions::default())? {
+            return Ok(id_value);
+        }
+        let _creating = self.base.create_lock.lock().unwrap();
+        if let Some(id_value) = self.get_id_val(db, orig, &ReadOptions::default())? {
+            return Ok(id_value); // another writer got here first
+        }
+        let seq = self.base.id_seq.fetch_add(1, Ordering::SeqCst) + 1;
+        let id_value = IdVal::new(seq);
+        let mut batch = WriteBatch::default();
+        batch.put(self.base.seq_key(), seq.to_le_bytes());
+        batch.put_cf(cf, _rk(orig), _rv(&id_value));
+        also(&id_value, &mut batch);
+        db.write(batch)?;
+        Ok(id_value)
     }
     fn estimate_count(&self) -> u64 {
         self.base.id_seq.load(Ordering::SeqCst) - 1 // -1 because seq zero is reserved
     }
+    /// for secondaries: pick up ids the primary created since we last looked
+    fn refresh_seq(&self, db: &DBWithThreadMode<MultiThreaded>) -> Result<()> {
+        self.base
+            .id_seq
+            .store(self.base.read_seq(db)?, Ordering::SeqCst);
+        Ok(())
+    }
 }
 impl<Orig: Clone, IdVal: IdTableValue> IdTable<Orig, IdVal, true>
 where
@@ -188,26 +360,29 @@ where
     for<'v> &'v IdVal: AsRocksValue,
     for<'k> &'k Orig: AsRocksKey,
 {
+    /// `on_create` can add to the batch that saves a new id
     fn get_or_create_id_val(
-        &mut self,
+        &self,
         db: &DBWithThreadMode<MultiThreaded>,
-        batch: &mut WriteBatch,
         orig: &Orig,
+        on_create: impl FnOnce(&mut WriteBatch),
     ) -> Result<IdVal> {
         let cf = db.cf_handle(&self.base.name).unwrap();
-        let id_val = self.__get_or_create_id_val(&cf, db, batch, orig)?;
-        // TODO: assert that the original is never a u64 that could collide
-        batch.put_cf(&cf, id_val.id().to_be_bytes(), _rk(orig)); // reversed rk/rv on purpose here :/
-        Ok(id_val)
+        self.__get_or_create_id_val(&cf, db, orig, |id_val, batch| {
+            // TODO: assert that the original is never a u64 that could collide
+            batch.put_cf(&cf, id_val.id().to_be_bytes(), _rk(orig)); // reversed rk/rv on purpose here :/
+            on_create(batch);
+        })
     }
 
     fn get_val_from_id(
         &self,
         db: &DBWithThreadMode<MultiThreaded>,
         id: u64,
+        read_opts: &ReadOptions,
     ) -> Result<Option<Orig>> {
         let cf = db.cf_handle(&self.base.name).unwrap();
-        if let Some(orig_bytes) = db.get_cf(&cf, id.to_be_bytes())? {
+        if let Some(orig_bytes) = db.get_cf_opt(&cf, id.to_be_bytes(), read_opts)? {
             // HACK ish
             Ok(Some(_kr(&orig_bytes)?))
         } else {
@@ -221,14 +396,15 @@ where
     for<'v> &'v IdVal: AsRocksValue,
     for<'k> &'k Orig: AsRocksKey,
 {
+    /// `on_create` can add to the batch that saves a new id
     fn get_or_create_id_val(
-        &mut self,
+        &self,
         db: &DBWithThreadMode<MultiThreaded>,
-        batch: &mut WriteBatch,
         orig: &Orig,
+        on_create: impl FnOnce(&mut WriteBatch),
     ) -> Result<IdVal> {
         let cf = db.cf_handle(&self.base.name).unwrap();
-        self.__get_or_create_id_val(&cf, db, batch, orig)
+        self.__get_or_create_id_val(&cf, db, orig, |_, batch| on_create(batch))
     }
 }
 
@@ -252,14 +428,38 @@ impl IdTableValue for TargetId {
 impl RocksStorage {
     pub fn new(path: impl AsRef<Path>) -> Result<Self> {
         Self::describe_metrics();
-        RocksStorage::open_readmode(path, false)
+        RocksStorage::open_readmode(path, OpenMode::Writer)
     }
 
     pub fn open_readonly(path: impl AsRef<Path>) -> Result<Self> {
-        RocksStorage::open_readmode(path, true)
+        RocksStorage::open_readmode(path, OpenMode::ReadOnly)
     }
 
-    fn open_readmode(path: impl AsRef<Path>, readonly: bool) -> Result<Self> {
+    /// Open a read replica of a db that another process is writing to
+    ///
+    /// Replicas only see the primary's writes as of the last call to
+    /// [`RocksStorage::catch_up_with_primary`].
+    pub fn open_secondary(
+        primary_path: impl AsRef<Path>,
+        secondary_path: impl AsRef<Path>,
+    ) -> Result<Self> {
+        RocksStorage::open_readmode(primary_path, OpenMode::Secondary(secondary_path.as_ref()))
+    }
+
+    /// Replay the primary's latest writes into a secondary
+    ///
+    /// Returns the jetstream cursor the primary had saved as of those writes.
+    pub fn catch_up_with_primary(&self) -> Result<Option<u64>> {
+        self.db.try_catch_up_with_primary()?;
+        self.did_id_table.refresh_seq(&self.db)?;
+        self.target_id_table.refresh_seq(&self.db)?;
+        self.db
+            .get(JETSTREAM_CURSOR_KEY)?
+            .map(|b| _vr(&b))
+            .transpose()
+    }
+
+    fn open_readmode(path: impl AsRef<Path>, mode: OpenMode) -> Result<Self> {
         let did_id_table = IdTable::<_, _, true>::setup(DID_IDS_CF);
         let target_id_table = IdTable::<_, _, false>::setup(TARGET_IDS_CF);
 
@@ -269,35 +469,135 @@ impl RocksStorage {
             target_id_table.cf_descriptor(),
             // the reverse links:
             ColumnFamilyDescriptor::new(TARGET_LINKERS_CF, {
-                let mut opts = rocks_opts_base();
-                opts.set_merge_operator_associative(
+                let mut opts = linkers_opts();
+                // same merge for both, but only full merges produce stored values worth measuring
+                opts.set_merge_operator(
                     "merge_op_extend_did_ids",
-                    Self::merge_op_extend_did_ids,
+                    Self::merge_op_extend_did_ids_full as MergeOp,
+                    Self::merge_op_extend_did_ids as MergeOp,
                 );
                 opts
             }),
             // unfortunately we also need forward links to handle deletes
             ColumnFamilyDescriptor::new(LINK_TARGETS_CF, rocks_opts_base()),
+            // add/remove log per target. always opened, only written with the `history` feature.
+            ColumnFamilyDescriptor::new(TARGET_HISTORY_CF, {
+                let mut opts = rocks_opts_base();
+                opts.set_merge_operator_associative(
+                    "merge_op_extend_history",
+                    Self::merge_op_extend_history,
+                );
+                opts
+            }),
+            // the latest few linkers per target. always opened, only written with the `recent-linkers` feature.
+            ColumnFamilyDescriptor::new(TARGET_RECENT_CF, {
+                let mut opts = rocks_opts_base();
+                opts.set_merge_operator_associative(
+                    "merge_op_keep_recent",
+                    Self::merge_op_keep_recent,
+                );
+                opts
+            }),
+            // links received by any record of an account, and what to undo when a linking record goes away
+            ColumnFamilyDescriptor::new(DID_RECEIVED_CF, {
+                let mut opts = rocks_opts_base();
+                opts.set_merge_operator_associative("merge_op_sum", Self::merge_op_sum);
+                opts
+            }),
+            ColumnFamilyDescriptor::new(RECORD_LINK_OWNERS_CF, rocks_opts_base()),
+            // every (collection, path) links have been seen at
+            ColumnFamilyDescriptor::new(LINK_TYPES_CF, {
+                let mut opts = rocks_opts_base();
+                opts.set_merge_operator_associative("merge_op_sum", Self::merge_op_sum);
+                opts
+            }),
+            // admin-managed alias target -> canonical target, as plain utf8
+            ColumnFamilyDescriptor::new(TARGET_ALIASES_CF, rocks_opts_base()),
+            // (day, what) -> count, for watching the index grow
+            ColumnFamilyDescriptor::new(DAILY_GROWTH_CF, {
+                let mut opts = rocks_opts_base();
+                opts.set_merge_operator_associative("merge_op_sum", Self::merge_op_sum);
+                opts
+            }),
         ];
 
-        let db = if readonly {
-            DBWithThreadMode::open_cf_descriptors_read_only(&get_db_read_opts(), path, cfs, false)?
-        } else {
-            DBWithThreadMode::open_cf_descriptors(&get_db_opts(), path, cfs)?
+        let is_writer = matches!(mode, OpenMode::Writer);
+        let db = match mode {
+            OpenMode::Writer => DBWithThreadMode::open_cf_descriptors(&get_db_opts(), path, cfs)?,
+            OpenMode::ReadOnly => DBWithThreadMode::open_cf_descriptors_read_only(
+                &get_db_read_opts(),
+                path,
+                cfs,
+                false,
+            )?,
+            OpenMode::Secondary(secondary_path) => {
+                DBWithThreadMode::open_cf_descriptors_as_secondary(
+                    &get_db_secondary_opts(),
+                    path,
+                    secondary_path,
+                    cfs,
+                )?
+            }
         };
 
         let db = Arc::new(db);
         let did_id_table = did_id_table.init(&db)?;
         let target_id_table = target_id_table.init(&db)?;
+        // replicas can't see the writer's inserts, so only the writer gets a bloom
+        let target_bloom = is_writer.then(|| Self::start_target_bloom(db.clone()));
         Ok(Self {
             db,
             did_id_table,
             target_id_table,
-            is_writer: !readonly,
+            is_writer,
+            writes_cursor: is_writer,
+            target_locks: None,
             backup_task: None.into(),
+            snapshots: Arc::new(Mutex::new(PinnedSnapshots::new())),
+            at_snapshot: None,
+            target_bloom,
         })
     }
 
+    /// Fill a bloom filter of every stored target in the background
+    ///
+    /// New targets are inserted as they're created, so it's usable as soon as
+    /// the scan catches up with what was already stored.
+    fn start_target_bloom(db: Arc<DBWithThreadMode<MultiThreaded>>) -> Arc<TargetBloom> {
+        let bloom = Arc::new(TargetBloom::default());
+        thread::spawn({
+            let bloom = bloom.clone();
+            move || {
+                let t0 = Instant::now();
+                let cf = db.cf_handle(TARGET_IDS_CF).unwrap();
+                let mut scanned: u64 = 0;
+                for item in db.iterator_cf(&cf, IteratorMode::Start) {
+                    let (k, _) = match item {
+                        Ok(kv) => kv,
+                        Err(e) => {
+                            eprintln!("target bloom: scan failed, leaving it unused: {e:?}");
+                            return;
+                        }
+                    };
+                    // ids are never deleted, so every key is a target that was linked
+                    if let Ok(TargetKey(Target(target), _, _)) = _kr::<TargetKey>(&k) {
+                        bloom.insert(&target);
+                    }
+                    scanned += 1;
+                    if scanned % 1_000_000 == 0 && Arc::strong_count(&bloom) == 1 {
+                        return; // the storage was dropped
+                    }
+                }
+                bloom.mark_ready();
+                eprintln!(
+                    "target bloom: ready after {scanned} target keys in {:?}",
+                    t0.elapsed()
+                );
+            }
+        });
+        bloom
+    }
+
     pub fn start_backup(
         &mut self,
         path: PathBuf,
@@ -415,6 +715,45 @@ impl RocksStorage {
             Unit::Count,
             "total batched ops for account deletions"
         );
+        describe_histogram!(
+            "storage_rocksdb_target_linkers_len",
+            Unit::Count,
+            "linkers in target_links values as they're merged or rewritten"
+        );
+        describe_histogram!(
+            "storage_rocksdb_target_linkers_bytes",
+            Unit::Bytes,
+            "serialized size of target_links values as they're merged or rewritten"
+        );
+        describe_counter!(
+            "storage_rocksdb_snapshots_pinned",
+            Unit::Count,
+            "snapshots pinned for stable pagination"
+        );
+        describe_counter!(
+            "storage_rocksdb_snapshots_refused",
+            Unit::Count,
+            "snapshots not pinned because too many were already pinned"
+        );
+        describe_counter!(
+            "storage_rocksdb_target_exists_bloom_skips",
+            Unit::Count,
+            "existence checks answered by the target bloom filter without a read"
+        );
+    }
+
+    /// full merges (on reads and compactions) produce whole values, so track their size.
+    ///
+    /// every linker for a target lives in one value, so big ones are the main scaling hazard.
+    fn merge_op_extend_did_ids_full(
+        key: &[u8],
+        existing: Option<&[u8]>,
+        operands: &MergeOperands,
+    ) -> Option<Vec<u8>> {
+        let linkers = Self::extend_did_ids(key, existing, operands);
+        let merged = _rv(&linkers);
+        Self::record_linkers_size(&linkers, &merged, "merge");
+        Some(merged)
     }
 
     fn merge_op_extend_did_ids(
@@ -422,6 +761,20 @@ impl RocksStorage {
         existing: Option<&[u8]>,
         operands: &MergeOperands,
     ) -> Option<Vec<u8>> {
+        Some(_rv(&Self::extend_did_ids(key, existing, operands)))
+    }
+
+    fn record_linkers_size(linkers: &TargetLinkers, bytes: &[u8], via: &'static str) {
+        histogram!("storage_rocksdb_target_linkers_len", "via" => via)
+            .record(linkers.0.len() as f64);
+        histogram!("storage_rocksdb_target_linkers_bytes", "via" => via).record(bytes.len() as f64);
+    }
+
+    fn extend_did_ids(
+        key: &[u8],
+        existing: Option<&[u8]>,
+        operands: &MergeOperands,
+    ) -> TargetLinkers {
         let mut linkers: Vec<_> = if let Some(existing_bytes) = existing {
             match _vr(existing_bytes) {
                 Ok(TargetLinkers(mut existing_linkers)) => {
@@ -454,7 +807,93 @@ impl RocksStorage {
                 }
             }
         }
-        Some(_rv(&TargetLinkers(linkers)))
+        TargetLinkers(linkers)
+    }
+
+    /// read options for this instance: at its pinned snapshot if it has one
+    fn read_opts(&self) -> ReadOptions {
+        let mut read_opts = ReadOptions::default();
+        if let Some(pinned) = &self.at_snapshot {
+            read_opts.set_snapshot(pinned.snapshot());
+        }
+        read_opts
+    }
+
+    // newest first, and keeping only the newest is the same however operands are grouped
+    fn merge_op_keep_recent(
+        key: &[u8],
+        existing: Option<&[u8]>,
+        operands: &MergeOperands,
+    ) -> Option<Vec<u8>> {
+        let mut recent = match existing.map(_vr) {
+            Some(Ok(RecentLinkers(existing))) => existing,
+            Some(Err(e)) => {
+                eprintln!("bug? could not deserialize existing recent linkers: {e:?}. key={key:?}. starting over.");
+                Vec::new()
+            }
+            None => Vec::with_capacity(operands.len()),
+        };
+        for new_recent in operands {
+            match _vr(new_recent) {
+                Ok(RecentLinkers(new_recent)) => recent.extend(new_recent),
+                Err(e) => eprintln!(
+                    "bug? could not deserialize new recent linkers: {e:?}. key={key:?}. skipping it."
+                ),
+            }
+        }
+        recent.sort_by_key(|RecentLinkerEntry(cursor, _, _)| std::cmp::Reverse(*cursor));
+        recent.truncate(RECENT_LINKERS_KEPT);
+        Some(_rv(&RecentLinkers(recent)))
+    }
+
+    fn merge_op_extend_history(
+        key: &[u8],
+        existing: Option<&[u8]>,
+        operands: &MergeOperands,
+    ) -> Option<Vec<u8>> {
+        let mut history = match existing.map(_vr) {
+            Some(Ok(LinkHistory(existing))) => existing,
+            Some(Err(e)) => {
+                eprintln!("bug? could not deserialize existing link history: {e:?}. key={key:?}. continuing, but history will be lost!");
+                Vec::new()
+            }
+            None => Vec::with_capacity(operands.len()),
+        };
+        for new_history in operands {
+            match _vr(new_history) {
+                Ok(LinkHistory(new_history)) => history.extend(new_history),
+                Err(e) => eprintln!(
+                    "bug? could not deserialize new link history: {e:?}. key={key:?}. skipping it."
+                ),
+            }
+        }
+        Some(_rv(&LinkHistory(history)))
+    }
+
+    fn merge_op_sum(
+        key: &[u8],
+        existing: Option<&[u8]>,
+        operands: &MergeOperands,
+    ) -> Option<Vec<u8>> {
+        let mut total: i64 = match existing.map(_vr) {
+            Some(Ok(n)) => n,
+            Some(Err(e)) => {
+                eprintln!(
+                    "bug? could not deserialize existing sum: {e:?}. key={key:?}. resetting it!"
+                );
+                0
+            }
+            None => 0,
+        };
+        for delta in operands {
+            match _vr::<i64>(delta) {
+                Ok(delta) => total += delta,
+                Err(e) => eprintln!(
+                    "bug? could not deserialize sum delta: {e:?}. key={key:?}. skipping it."
+                ),
+            }
+        }
+        Some(_rv(total))
     }
 
     fn prefix_iter_cf<K, V, CF, P>(
@@ -468,7 +907,7 @@ impl RocksStorage {
         CF: AsColumnFamilyRef,
         for<'a> &'a P: AsRocksKeyPrefix<K>,
     {
-        let mut read_opts = ReadOptions::default();
+        let mut read_opts = self.read_opts();
         read_opts.set_iterate_range(PrefixRange(_rkp(&pre))); // TODO verify: inclusive bounds?
         self.db
             .iterator_cf_opt(cf, read_opts, IteratorMode::Start)
@@ -481,7 +920,10 @@ impl RocksStorage {
         F: FnOnce(DidIdValue) -> Option<DidIdValue>,
     {
         let cf = self.db.cf_handle(DID_IDS_CF).unwrap();
-        let Some(did_id_value) = self.did_id_table.get_id_val(&self.db, did)? else {
+        let Some(did_id_value) =
+            self.did_id_table
+                .get_id_val(&self.db, did, &ReadOptions::default())?
+        else {
             return Ok(false);
         };
         let Some(new_did_id_value) = update(did_id_value) else {
@@ -497,7 +939,8 @@ impl RocksStorage {
 
     fn get_target_linkers(&self, target_id: &TargetId) -> Result<TargetLinkers> {
         let cf = self.db.cf_handle(TARGET_LINKERS_CF).unwrap();
-        let Some(linkers_bytes) = self.db.get_cf(&cf, _rk(target_id))? else {
+        let Some(linkers_bytes) = self.db.get_cf_opt(&cf, _rk(target_id), &self.read_opts())?
+        else {
             return Ok(TargetLinkers::default());
         };
         _vr(&linkers_bytes)
@@ -543,10 +986,73 @@ impl RocksStorage {
         let Some(new_linkers) = update(existing_linkers) else {
             return Ok(false);
         };
-        batch.put_cf(&cf, _rk(target_id), _rv(&new_linkers));
+        let bytes = _rv(&new_linkers);
+        Self::record_linkers_size(&new_linkers, &bytes, "update");
+        batch.put_cf(&cf, _rk(target_id), bytes);
         Ok(true)
     }
 
+    fn append_recent_linker(
+        &self,
+        batch: &mut WriteBatch,
+        target_id: &TargetId,
+        entry: RecentLinkerEntry,
+    ) {
+        if cfg!(feature = "recent-linkers") {
+            let cf = self.db.cf_handle(TARGET_RECENT_CF).unwrap();
+            batch.merge_cf(&cf, _rk(target_id), _rv(&RecentLinkers(vec![entry])));
+        }
+    }
+    fn get_recent_linker_entries(&self, target_id: &TargetId) -> Result<RecentLinkers> {
+        let cf = self.db.cf_handle(TARGET_RECENT_CF).unwrap();
+        let Some(bytes) = self.db.get_cf_opt(&cf, _rk(target_id), &self.read_opts())? else {
+            return Ok(RecentLinkers::default());
+        };
+        _vr(&bytes)
+    }
+
+    fn append_link_history(
+        &self,
+        batch: &mut WriteBatch,
+        target_id: &TargetId,
+        entry: LinkHistoryEntry,
+    ) {
+        if cfg!(feature = "history") {
+            let cf = self.db.cf_handle(TARGET_HISTORY_CF).unwrap();
+            batch.merge_cf(&cf, _rk(target_id), _rv(&LinkHistory(vec![entry])));
+        }
+    }
+    fn get_link_history_entries(&self, target_id: &TargetId) -> Result<LinkHistory> {
+        let cf = self.db.cf_handle(TARGET_HISTORY_CF).unwrap();
+        let Some(bytes) = self.db.get_cf_opt(&cf, _rk(target_id), &self.read_opts())? else {
+            return Ok(LinkHistory::default());
+        };
+        _vr(&bytes)
+    }
+
+    fn add_received(&self, batch: &mut WriteBatch, key: &DidReceivedKey, delta: i64) {
+        let cf = self.db.cf_handle(DID_RECEIVED_CF).unwrap();
+        batch.merge_cf(&cf, _rk(key), _rv(delta));
+    }
+    /// undo received counts for a linking record that's going away
+    fn remove_received(
+        &self,
+        batch: &mut WriteBatch,
+        record_link_key: &RecordLinkKey,
+    ) -> Result<()> {
+        let cf = self.db.cf_handle(RECORD_LINK_OWNERS_CF).unwrap();
+        let Some(bytes) = self.db.get_cf(&cf, _rk(record_link_key))? else {
+            return Ok(()); // no links to records of accounts
+        };
+        let RecordLinkOwners(owners) = _vr(&bytes)?;
+        for (owner, path) in owners {
+            let key = DidReceivedKey(owner, record_link_key.1.clone(), path);
+            self.add_received(batch, &key, -1);
+        }
+        batch.delete_cf(&cf, _rk(record_link_key));
+        Ok(())
+    }
+
     fn put_link_targets(
         &self,
         batch: &mut WriteBatch,
@@ -593,6 +1099,27 @@ impl RocksStorage {
         self.prefix_iter_cf(&cf, TargetIdTargetPrefix(target.clone()))
     }
 
+    /// a target's id for a (collection, path), counting the target as new if
+    /// this is the first (collection, path) anything has linked to it from
+    fn get_or_create_target_id(&self, target_key: &TargetKey, cursor: u64) -> Result<TargetId> {
+        self.target_id_table
+            .get_or_create_id_val(&self.db, target_key, |batch| {
+                let TargetKey(target, _, _) = target_key;
+                if self.iter_targets_for_target(target).next().is_none() {
+                    self.count_growth(batch, cursor, Growth::NewTargets, 1);
+                }
+            })
+    }
+
+    fn count_growth(&self, batch: &mut WriteBatch, cursor: u64, what: Growth, n: usize) {
+        if n == 0 {
+            return;
+        }
+        let cf = self.db.cf_handle(DAILY_GROWTH_CF).unwrap();
+        let key = DailyGrowthKey(cursor_day(cursor), what);
+        batch.merge_cf(&cf, _rk(&key), _rv(n as i64));
+    }
+
     //
     // higher-level event action handlers
     //
@@ -601,11 +1128,15 @@ impl RocksStorage {
         &mut self,
         record_id: &RecordId,
         links: &[CollectedLink],
+        cursor: u64,
         batch: &mut WriteBatch,
     ) -> Result<()> {
         let DidIdValue(did_id, _) =
             self.did_id_table
-                .get_or_create_id_val(&self.db, batch, &record_id.did)?;
+                .get_or_create_id_val(&self.db, &record_id.did, |batch| {
+                    self.count_growth(batch, cursor, Growth::NewDids, 1)
+                })?;
+        self.count_growth(batch, cursor, Growth::LinksAdded, links.len());
 
         let record_link_key = RecordLinkKey(
             did_id,
@@ -613,28 +1144,65 @@ impl RocksStorage {
             RKey(record_id.rkey()),
         );
         let mut record_link_targets = RecordLinkTargets::with_capacity(links.len());
+        let mut record_link_owners = RecordLinkOwners::default();
 
+        let link_types_cf = self.db.cf_handle(LINK_TYPES_CF).unwrap();
         for CollectedLink { target, path } in links {
+            let link_type = LinkTypeKey(Collection(record_id.collection()), RPath(path.clone()));
+            batch.merge_cf(&link_types_cf, _rk(&link_type), _rv(1_i64));
+
             let target_key = TargetKey(
                 Target(target.clone().into_string()),
                 Collection(record_id.collection()),
                 RPath(path.clone()),
             );
-            let target_id =
-                self.target_id_table
-                    .get_or_create_id_val(&self.db, batch, &target_key)?;
+            let target_id = self.get_or_create_target_id(&target_key, cursor)?;
+            if let Some(bloom) = &self.target_bloom {
+                bloom.insert(target.as_str());
+            }
             self.merge_target_linker(batch, &target_id, &did_id, &RKey(record_id.rkey()));
+            self.append_link_history(
+                batch,
+                &target_id,
+                LinkHistoryEntry(cursor, did_id, RKey(record_id.rkey()), LinkAction::Added),
+            );
+            self.append_recent_linker(
+                batch,
+                &target_id,
+                RecentLinkerEntry(cursor, did_id, RKey(record_id.rkey())),
+            );
+
+            if let Some(owner) = record_owner(target.as_str()) {
+                let key = DidReceivedKey(
+                    owner.clone(),
+                    Collection(record_id.collection()),
+                    RPath(path.clone()),
+                );
+                self.add_received(batch, &key, 1);
+                record_link_owners.0.push((owner, RPath(path.clone())));
+            }
 
             record_link_targets.add(RecordLinkTarget(RPath(path.clone()), target_id))
         }
 
+        if !record_link_owners.0.is_empty() {
+            let cf = self.db.cf_handle(RECORD_LINK_OWNERS_CF).unwrap();
+            batch.put_cf(&cf, _rk(&record_link_key), _rv(&record_link_owners));
+        }
+
         self.put_link_targets(batch, &record_link_key, &record_link_targets);
         Ok(())
     }
 
-    fn remove_links(&mut self, record_id: &RecordId, batch: &mut WriteBatch) -> Result<()> {
+    fn remove_links(
+        &mut self,
+        record_id: &RecordId,
+        cursor: u64,
+        batch: &mut WriteBatch,
+    ) -> Result<()> {
         let Some(DidIdValue(linking_did_id, _)) =
-            self.did_id_table.get_id_val(&self.db, &record_id.did)?
+            self.did_id_table
+                .get_id_val(&self.db, &record_id.did, &ReadOptions::default())?
         else {
             return Ok(()); // we don't know her: nothing to do
         };
@@ -647,6 +1215,12 @@ impl RocksStorage {
         let Some(record_link_targets) = self.get_record_link_targets(&record_link_key)? else {
             return Ok(()); // we don't have these links
         };
+        self.count_growth(
+            batch,
+            cursor,
+            Growth::LinksRemoved,
+            record_link_targets.0.len(),
+        );
 
         // we do read -> modify -> write here: could merge-op in the deletes instead?
         // otherwise it's another single-thread-constraining thing.
@@ -660,12 +1234,66 @@ impl RocksStorage {
                 }
                 Some(linkers)
             })?;
+            self.append_link_history(
+                batch,
+                &target_id,
+                LinkHistoryEntry(
+                    cursor,
+                    linking_did_id,
+                    RKey(record_id.rkey()),
+                    LinkAction::Removed,
+                ),
+            );
         }
 
+        self.remove_received(batch, &record_link_key)?;
         self.delete_record_link(batch, &record_link_key);
         Ok(())
     }
 
+    /// every target an event will update linkers for, creating ids for new ones
+    fn touched_targets(&self, event: &ActionableEvent, cursor: u64) -> Result<Vec<TargetId>> {
+        let mut targets = Vec::new();
+        let (record_id, removing, adding) = match event {
+            ActionableEvent::CreateLinks { record_id, links } => {
+                (record_id, false, links.as_slice())
+            }
+            ActionableEvent::UpdateLinks {
+                record_id,
+                new_links,
+            } => (record_id, true, new_links.as_slice()),
+            ActionableEvent::DeleteRecord(record_id) => (record_id, true, [].as_slice()),
+            _ => return Ok(targets), // account deletes lock their own chunks
+        };
+        if removing {
+            if let Some(DidIdValue(did_id, _)) =
+                self.did_id_table
+                    .get_id_val(&self.db, &record_id.did, &ReadOptions::default())?
+            {
+                let record_link_key = RecordLinkKey(
+                    did_id,
+                    Collection(record_id.collection()),
+                    RKey(record_id.rkey()),
+                );
+                if let Some(existing) = self.get_record_link_targets(&record_link_key)? {
+                    targets.extend(existing.0.into_iter().map(|RecordLinkTarget(_, t)| t));
+                }
+            }
+        }
+        for CollectedLink { target, path } in adding {
+            if let Some(bloom) = &self.target_bloom {
+                bloom.insert(target.as_str());
+            }
+            let target_key = TargetKey(
+                Target(target.clone().into_string()),
+                Collection(record_id.collection()),
+                RPath(path.clone()),
+            );
+            targets.push(self.get_or_create_target_id(&target_key, cursor)?);
+        }
+        Ok(targets)
+    }
+
     fn set_account(&mut self, did: &Did, active: bool, batch: &mut WriteBatch) -> Result<()> {
         // this needs to be read-modify-write since the did_id needs to stay the same,
         // which has a benefit of allowing to avoid adding entries for dids we don't
@@ -677,9 +1305,12 @@ impl RocksStorage {
         Ok(())
     }
 
-    fn delete_account(&mut self, did: &Did, batch: &mut WriteBatch) -> Result<usize> {
+    fn delete_account(&mut self, did: &Did, cursor: u64, batch: &mut WriteBatch) -> Result<usize> {
         let mut total_batched_ops = 0;
-        let Some(DidIdValue(did_id, _)) = self.did_id_table.get_id_val(&self.db, did)? else {
+        let Some(DidIdValue(did_id, _)) =
+            self.did_id_table
+                .get_id_val(&self.db, did, &ReadOptions::default())?
+        else {
             return Ok(total_batched_ops); // ignore updates for dids we don't know about
         };
         self.delete_did_id_value(batch, did);
@@ -691,10 +1322,21 @@ impl RocksStorage {
         // TODO: queue a background delete task or whatever
         // TODO: test delete account with more links than chunk size
         let stuff: Vec<_> = self.iter_links_for_did_id(&did_id).collect();
+        let target_locks = self.target_locks.clone();
         for chunk in stuff.chunks(1024) {
             let mut mini_batch = WriteBatch::default();
+            let _locked = target_locks.as_ref().map(|locks| {
+                locks.lock(chunk.iter().flat_map(|(_, links)| {
+                    links
+                        .0
+                        .iter()
+                        .map(|RecordLinkTarget(_, target_id)| target_id)
+                }))
+            });
 
             for (record_link_key, links) in chunk {
+                self.count_growth(&mut mini_batch, cursor, Growth::LinksRemoved, links.0.len());
+                self.remove_received(&mut mini_batch, record_link_key)?;
                 self.delete_record_link(&mut mini_batch, record_link_key); // _could_ use delete range here instead of individual deletes, but since we have to scan anyway it's not obvious if it's better
 
                 for RecordLinkTarget(_, target_link_id) in links.0.iter() {
@@ -704,6 +1346,16 @@ impl RocksStorage {
                         }
                         Some(linkers)
                     })?;
+                    self.append_link_history(
+                        &mut mini_batch,
+                        target_link_id,
+                        LinkHistoryEntry(
+                            cursor,
+                            did_id,
+                            record_link_key.2.clone(),
+                            LinkAction::Removed,
+                        ),
+                    );
                 }
             }
             total_batched_ops += mini_batch.len();
@@ -755,24 +1407,30 @@ impl LinkStorage for RocksStorage {
     }
 
     fn push(&mut self, event: &ActionableEvent, cursor: u64) -> Result<()> {
+        let target_locks = self.target_locks.clone();
+        let _locked = match target_locks {
+            Some(ref locks) => Some(locks.lock(&self.touched_targets(event, cursor)?)),
+            None => None,
+        };
+
         // normal ops
         let mut batch = WriteBatch::default();
         let t0 = Instant::now();
         if let Some(action) = match event {
             ActionableEvent::CreateLinks { record_id, links } => {
-                self.add_links(record_id, links, &mut batch)?;
+                self.add_links(record_id, links, cursor, &mut batch)?;
                 Some("create_links")
             }
             ActionableEvent::UpdateLinks {
                 record_id,
                 new_links,
             } => {
-                self.remove_links(record_id, &mut batch)?;
-                self.add_links(record_id, new_links, &mut batch)?;
+                self.remove_links(record_id, cursor, &mut batch)?;
+                self.add_links(record_id, new_links, cursor, &mut batch)?;
                 Some("update_links")
             }
             ActionableEvent::DeleteRecord(record_id) => {
-                self.remove_links(record_id, &mut batch)?;
+                self.remove_links(record_id, cursor, &mut batch)?;
                 Some("delete_record")
             }
             ActionableEvent::ActivateAccount(did) => {
@@ -786,7 +1444,9 @@ impl LinkStorage for RocksStorage {
             ActionableEvent::DeleteAccount(_) => None, // delete account is handled specially
         } {
             let t_read = t0.elapsed();
-            batch.put(JETSTREAM_CURSOR_KEY.as_bytes(), _rv(cursor));
+            if self.writes_cursor {
+                batch.put(JETSTREAM_CURSOR_KEY.as_bytes(), _rv(cursor));
+            }
             let batch_ops = batch.len();
             self.db.write(batch)?;
             let t_total = t0.elapsed();
@@ -803,7 +1463,7 @@ impl LinkStorage for RocksStorage {
         let mut outer_batch = WriteBatch::default();
         let t0 = Instant::now();
         if let ActionableEvent::DeleteAccount(did) = event {
-            let inner_batch_ops = self.delete_account(did, &mut outer_batch)?;
+            let inner_batch_ops = self.delete_account(did, cursor, &mut outer_batch)?;
             let total_batch_ops = inner_batch_ops + outer_batch.len();
             self.db.write(outer_batch)?;
             let t_total = t0.elapsed();
@@ -818,6 +1478,26 @@ impl LinkStorage for RocksStorage {
         Ok(())
     }
 
+    fn set_cursor(&mut self, cursor: u64) -> Result<()> {
+        self.db.put(JETSTREAM_CURSOR_KEY, _rv(cursor))?;
+        Ok(())
+    }
+
+    fn shard_writer(&mut self) -> Result<Self> {
+        if !self.is_writer {
+            bail!("can't get a shard writer from a reader");
+        }
+        let locks = self
+            .target_locks
+            .get_or_insert_with(|| Arc::new(TargetLocks::new()))
+            .clone();
+        self.writes_cursor = false;
+        let mut shard = self.clone();
+        shard.is_writer = false; // the original still does the shutdown cleanup
+        shard.target_locks = Some(locks);
+        Ok(shard)
+    }
+
     fn to_readable(&mut self) -> impl LinkReader {
         let mut readable = self.clone();
         readable.is_writer = false;
@@ -832,7 +1512,10 @@ impl LinkReader for RocksStorage {
             Collection(collection.to_string()),
             RPath(path.to_string()),
         );
-        if let Some(target_id) = self.target_id_table.get_id_val(&self.db, &target_key)? {
+        if let Some(target_id) =
+            self.target_id_table
+                .get_id_val(&self.db, &target_key, &self.read_opts())?
+        {
             let (alive, _) = self.get_target_linkers(&target_id)?.count();
             Ok(alive)
         } else {
@@ -846,13 +1529,48 @@ impl LinkReader for RocksStorage {
             Collection(collection.to_string()),
             RPath(path.to_string()),
         );
-        if let Some(target_id) = self.target_id_table.get_id_val(&self.db, &target_key)? {
+        if let Some(target_id) =
+            self.target_id_table
+                .get_id_val(&self.db, &target_key, &self.read_opts())?
+        {
             Ok(self.get_target_linkers(&target_id)?.count_distinct_dids())
         } else {
             Ok(0)
         }
     }
 
+    fn get_count_from_did(
+        &self,
+        target: &str,
+        collection: &str,
+        path: &str,
+        did: &Did,
+    ) -> Result<u64> {
+        let target_key = TargetKey(
+            Target(target.to_string()),
+            Collection(collection.to_string()),
+            RPath(path.to_string()),
+        );
+        let read_opts = self.read_opts();
+        let Some(target_id) = self
+            .target_id_table
+            .get_id_val(&self.db, &target_key, &read_opts)?
+        else {
+            return Ok(0);
+        };
+        let Some(DidIdValue(did_id, _)) =
+            self.did_id_table.get_id_val(&self.db, did, &read_opts)?
+        else {
+            return Ok(0);
+        };
+        Ok(self
+            .get_target_linkers(&target_id)?
+            .0
+            .iter()
+            .filter(|(d, _)| *d == did_id)
+            .count() as u64)
+    }
+
     fn get_links(
         &self,
         target: &str,
@@ -867,7 +1585,10 @@ impl LinkReader for RocksStorage {
             RPath(path.to_string()),
         );
 
-        let Some(target_id) = self.target_id_table.get_id_val(&self.db, &target_key)? else {
+        let Some(target_id) =
+            self.target_id_table
+                .get_id_val(&self.db, &target_key, &self.read_opts())?
+        else {
             return Ok(PagedAppendingCollection {
                 version: (0, 0),
                 items: Vec::new(),
@@ -885,6 +1606,7 @@ impl LinkReader for RocksStorage {
         let next = if begin == 0 { None } else { Some(begin as u64) };
 
         let did_id_rkeys = linkers.0[begin..end].iter().rev().collect::<Vec<_>>();
+        let read_opts = self.read_opts();
 
         let mut items = Vec::with_capacity(did_id_rkeys.len());
         // TODO: use get-many (or multi-get or whatever it's called)
@@ -892,8 +1614,12 @@ impl LinkReader for RocksStorage {
             if did_id.is_empty() {
                 continue;
             }
-            if let Some(did) = self.did_id_table.get_val_from_id(&self.db, did_id.0)? {
-                let Some(DidIdValue(_, active)) = self.did_id_table.get_id_val(&self.db, &did)?
+            if let Some(did) = self
+                .did_id_table
+                .get_val_from_id(&self.db, did_id.0, &read_opts)?
+            {
+                let Some(DidIdValue(_, active)) =
+                    self.did_id_table.get_id_val(&self.db, &did, &read_opts)?
                 else {
                     eprintln!("failed to look up did_value from did_id {did_id:?}: {did:?}: data consistency bug?");
                     continue;
@@ -933,7 +1659,10 @@ impl LinkReader for RocksStorage {
             RPath(path.to_string()),
         );
 
-        let Some(target_id) = self.target_id_table.get_id_val(&self.db, &target_key)? else {
+        let Some(target_id) =
+            self.target_id_table
+                .get_id_val(&self.db, &target_key, &self.read_opts())?
+        else {
             return Ok(PagedAppendingCollection {
                 version: (0, 0),
                 items: Vec::new(),
@@ -951,6 +1680,7 @@ impl LinkReader for RocksStorage {
         let next = if begin == 0 { None } else { Some(begin as u64) };
 
         let did_id_rkeys = linkers.0[begin..end].iter().rev().collect::<Vec<_>>();
+        let read_opts = self.read_opts();
 
         let mut items = Vec::with_capacity(did_id_rkeys.len());
         // TODO: use get-many (or multi-get or whatever it's called)
@@ -958,8 +1688,12 @@ impl LinkReader for RocksStorage {
             if did_id.is_empty() {
                 continue;
             }
-            if let Some(did) = self.did_id_table.get_val_from_id(&self.db, did_id.0)? {
-                let Some(DidIdValue(_, active)) = self.did_id_table.get_id_val(&self.db, &did)?
+            if let Some(did) = self
+                .did_id_table
+                .get_val_from_id(&self.db, did_id.0, &read_opts)?
+            {
+                let Some(DidIdValue(_, active)) =
+                    self.did_id_table.get_id_val(&self.db, &did, &read_opts)?
                 else {
                     eprintln!("failed to look up did_value from did_id {did_id:?}: {did:?}: data consistency bug?");
                     continue;
@@ -981,6 +1715,173 @@ impl LinkReader for RocksStorage {
         })
     }
 
+    fn get_link_history(
+        &self,
+        target: &str,
+        collection: &str,
+        path: &str,
+        limit: u64,
+        until: Option<u64>,
+    ) -> Result<PagedAppendingCollection<LinkHistoryEvent>> {
+        let target_key = TargetKey(
+            Target(target.to_string()),
+            Collection(collection.to_string()),
+            RPath(path.to_string()),
+        );
+
+        let Some(target_id) =
+            self.target_id_table
+                .get_id_val(&self.db, &target_key, &self.read_opts())?
+        else {
+            return Ok(PagedAppendingCollection {
+                version: (0, 0),
+                items: Vec::new(),
+                next: None,
+                total: 0,
+            });
+        };
+
+        let history = self.get_link_history_entries(&target_id)?;
+
+        let total = history.0.len() as u64;
+        let end = until.map(|u| std::cmp::min(u, total)).unwrap_or(total) as usize;
+        let begin = end.saturating_sub(limit as usize);
+        let next = if begin == 0 { None } else { Some(begin as u64) };
+
+        let read_opts = self.read_opts();
+        let mut visible_dids: HashMap<DidId, Option<Did>> = HashMap::new();
+        let mut items = Vec::with_capacity(end - begin);
+        for LinkHistoryEntry(cursor, did_id, rkey, action) in history.0[begin..end].iter().rev() {
+            let did = match visible_dids.get(did_id) {
+                Some(did) => did.clone(),
+                None => {
+                    // only show linkers that are still active, like get_links does
+                    let did = match self
+                        .did_id_table
+                        .get_val_from_id(&self.db, did_id.0, &read_opts)?
+                    {
+                        Some(did) => {
+                            match self.did_id_table.get_id_val(&self.db, &did, &read_opts)? {
+                                Some(DidIdValue(_, true)) => Some(did),
+                                _ => None,
+                            }
+                        }
+                        None => None,
+                    };
+                    visible_dids.insert(*did_id, did.clone());
+                    did
+                }
+            };
+            items.push(LinkHistoryEvent {
+                cursor: *cursor,
+                did,
+                rkey: rkey.0.clone(),
+                action: *action,
+            });
+        }
+
+        Ok(PagedAppendingCollection {
+            version: (total, 0),
+            items,
+            next,
+            total,
+        })
+    }
+
+    fn get_recent_linkers(
+        &self,
+        target: &str,
+        collection: &str,
+        path: &str,
+        limit: u64,
+    ) -> Result<Vec<RecentLinker>> {
+        let target_key = TargetKey(
+            Target(target.to_string()),
+            Collection(collection.to_string()),
+            RPath(path.to_string()),
+        );
+        let read_opts = self.read_opts();
+        let Some(target_id) = self
+            .target_id_table
+            .get_id_val(&self.db, &target_key, &read_opts)?
+        else {
+            return Ok(Vec::new());
+        };
+
+        let link_targets_cf = self.db.cf_handle(LINK_TARGETS_CF).unwrap();
+        let mut items = Vec::new();
+        for RecentLinkerEntry(cursor, did_id, rkey) in self.get_recent_linker_entries(&target_id)?.0
+        {
+            if items.len() as u64 >= limit {
+                break;
+            }
+            // the record could have been deleted or edited since: only show links still there
+            let record_link_key = RecordLinkKey(did_id, Collection(collection.to_string()), rkey);
+            let Some(bytes) =
+                self.db
+                    .get_cf_opt(&link_targets_cf, _rk(&record_link_key), &read_opts)?
+            else {
+                continue;
+            };
+            let RecordLinkTargets(targets) = _vr(&bytes)?;
+            if !targets
+                .iter()
+                .any(|RecordLinkTarget(RPath(p), TargetId(id))| p == path && *id == target_id.0)
+            {
+                continue;
+            }
+            // and only linkers that are still active, like get_links does
+            let Some(did) = self
+                .did_id_table
+                .get_val_from_id(&self.db, did_id.0, &read_opts)?
+            else {
+                continue;
+            };
+            if !matches!(
+                self.did_id_table.get_id_val(&self.db, &did, &read_opts)?,
+                Some(DidIdValue(_, true))
+            ) {
+                continue;
+            }
+            let RecordLinkKey(_, _, RKey(rkey)) = record_link_key;
+            items.push(RecentLinker { cursor, did, rkey });
+        }
+        Ok(items)
+    }
+
+    fn get_received_counts(&self, did: &str) -> Result<HashMap<String, HashMap<String, u64>>> {
+        let cf = self.db.cf_handle(DID_RECEIVED_CF).unwrap();
+        let mut out: HashMap<String, HashMap<String, u64>> = HashMap::new();
+        for (DidReceivedKey(_, Collection(collection), RPath(path)), count) in
+            self.prefix_iter_cf::<_, i64, _, _>(&cf, DidReceivedKeyDidPrefix(Did::from(did)))
+        {
+            if count <= 0 {
+                continue;
+            }
+            out.entry(collection)
+                .or_default()
+                .insert(path, count as u64);
+        }
+        Ok(out)
+    }
+
+    fn get_link_types(&self) -> Result<HashMap<String, HashMap<String, u64>>> {
+        let cf = self.db.cf_handle(LINK_TYPES_CF).unwrap();
+        let mut out: HashMap<String, HashMap<String, u64>> = HashMap::new();
+        for item in self
+            .db
+            .iterator_cf_opt(&cf, self.read_opts(), IteratorMode::Start)
+        {
+            let (k, v) = item?;
+            let LinkTypeKey(Collection(collection), RPath(path)) = _kr(&k)?;
+            let count: i64 = _vr(&v)?;
+            out.entry(collection)
+                .or_default()
+                .insert(path, count.max(0) as u64);
+        }
+        Ok(out)
+    }
+
     fn get_all_record_counts(&self, target: &str) -> Result<HashMap<String, HashMap<String, u64>>> {
         let mut out: HashMap<String, HashMap<String, u64>> = HashMap::new();
         for (target_key, target_id) in self.iter_targets_for_target(&Target(target.into())) {
@@ -1014,6 +1915,53 @@ impl LinkReader for RocksStorage {
         Ok(out)
     }
 
+    fn target_exists(&self, target: &str) -> Result<bool> {
+        if let Some(bloom) = &self.target_bloom {
+            if bloom.is_ready() && !bloom.may_contain(target) {
+                counter!("storage_rocksdb_target_exists_bloom_skips").increment(1);
+                return Ok(false);
+            }
+        }
+        Ok(self
+            .iter_targets_for_target(&Target(target.into()))
+            .next()
+            .is_some())
+    }
+
+    fn pin_snapshot(&self, ttl: Duration) -> Result<Option<u64>> {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        snapshots.expire();
+        if snapshots.pinned.len() >= MAX_PINNED_SNAPSHOTS {
+            counter!("storage_rocksdb_snapshots_refused").increment(1);
+            return Ok(None);
+        }
+        let id = snapshots.next_id;
+        snapshots.next_id += 1;
+        let pinned = Arc::new(PinnedSnapshot::new(&self.db));
+        snapshots
+            .pinned
+            .insert(id, (pinned, ttl, Instant::now() + ttl));
+        counter!("storage_rocksdb_snapshots_pinned").increment(1);
+        Ok(Some(id))
+    }
+
+    fn at_snapshot(&self, snapshot: u64) -> Result<Option<Self>> {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        snapshots.expire();
+        let Some((pinned, ttl, expires)) = snapshots.pinned.get_mut(&snapshot) else {
+            return Ok(None);
+        };
+        *expires = Instant::now() + *ttl; // using it keeps it alive
+        let mut reader = self.clone();
+        reader.is_writer = false;
+        reader.at_snapshot = Some(pinned.clone());
+        Ok(Some(reader))
+    }
+
+    fn release_snapshot(&self, snapshot: u64) {
+        self.snapshots.lock().unwrap().pinned.remove(&snapshot);
+    }
+
     fn get_stats(&self) -> Result<StorageStats> {
         let dids = self.did_id_table.estimate_count();
         let targetables = self.target_id_table.estimate_count();
@@ -1030,6 +1978,55 @@ impl LinkReader for RocksStorage {
             linking_records,
         })
     }
+
+    fn get_daily_growth(&self) -> Result<Vec<DailyGrowth>> {
+        let cf = self.db.cf_handle(DAILY_GROWTH_CF).unwrap();
+        let mut days: BTreeMap<u64, DailyGrowth> = BTreeMap::new();
+        for item in self
+            .db
+            .iterator_cf_opt(&cf, self.read_opts(), IteratorMode::Start)
+        {
+            let (k, v) = item?;
+            let DailyGrowthKey(day, what) = _kr(&k)?;
+            let count = _vr::<i64>(&v)?.max(0) as u64;
+            let growth = days.entry(day).or_insert_with(|| DailyGrowth {
+                day,
+                ..Default::default()
+            });
+            match what {
+                Growth::LinksAdded => growth.links_added = count,
+                Growth::LinksRemoved => growth.links_removed = count,
+                Growth::NewDids => growth.new_dids = count,
+                Growth::NewTargets => growth.new_targets = count,
+            }
+        }
+        Ok(days.into_values().collect())
+    }
+
+    fn get_target_aliases(&self) -> Result<HashMap<String, String>> {
+        let cf = self.db.cf_handle(TARGET_ALIASES_CF).unwrap();
+        let mut out = HashMap::new();
+        for item in self
+            .db
+            .iterator_cf_opt(&cf, self.read_opts(), IteratorMode::Start)
+        {
+            let (alias, canonical) = item?;
+            out.insert(
+                String::from_utf8(alias.to_vec())?,
+                String::from_utf8(canonical.to_vec())?,
+            );
+        }
+        Ok(out)
+    }
+
+    fn set_target_alias(&self, alias: &str, canonical: Option<&str>) -> Result<()> {
+        let cf = self.db.cf_handle(TARGET_ALIASES_CF).unwrap();
+        match canonical {
+            Some(canonical) => self.db.put_cf(&cf, alias, canonical)?,
+            None => self.db.delete_cf(&cf, alias)?,
+        }
+        Ok(())
+    }
 }
 
 trait AsRocksKey: Serialize {}
@@ -1060,6 +2057,26 @@ impl AsRocksValue for &TargetLinkers {}
 impl ValueFromRocks for TargetLinkers {}
 
 // record_link_targets table
+impl AsRocksKey for &DidReceivedKey {}
+impl AsRocksKeyPrefix<DidReceivedKey> for &DidReceivedKeyDidPrefix {}
+impl KeyFromRocks for DidReceivedKey {}
+impl AsRocksValue for i64 {}
+impl ValueFromRocks for i64 {}
+impl AsRocksValue for &RecordLinkOwners {}
+
+impl AsRocksKey for &LinkTypeKey {}
+impl KeyFromRocks for LinkTypeKey {}
+
+impl AsRocksKey for &DailyGrowthKey {}
+impl KeyFromRocks for DailyGrowthKey {}
+impl ValueFromRocks for RecordLinkOwners {}
+
+impl AsRocksValue for &LinkHistory {}
+impl ValueFromRocks for LinkHistory {}
+
+impl AsRocksValue for &RecentLinkers {}
+impl ValueFromRocks for RecentLinkers {}
+
 impl AsRocksKey for &RecordLinkKey {}
 impl AsRocksKeyPrefix<RecordLinkKey> for &RecordLinkKeyDidIdPrefix {}
 impl AsRocksValue for &RecordLinkTargets {}
@@ -1162,6 +2179,47 @@ impl TargetLinkers {
     }
 }
 
+// links received by any record of an account: (record owner, linking collection, linking path) -> count
+#[derive(Debug, Serialize, Deserialize)]
+struct DidReceivedKey(Did, Collection, RPath);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DidReceivedKeyDidPrefix(Did);
+
+// links ever seen at a (linking collection, linking path) -> count
+#[derive(Debug, Serialize, Deserialize)]
+struct LinkTypeKey(Collection, RPath);
+
+// (day, what grew) -> count
+#[derive(Debug, Serialize, Deserialize)]
+struct DailyGrowthKey(u64, Growth);
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Growth {
+    LinksAdded,
+    LinksRemoved,
+    NewDids,
+    NewTargets,
+}
+
+// which received counts a linking record contributed to, so they can be undone
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RecordLinkOwners(Vec<(Did, RPath)>);
+
+// add/remove log for a target, appended with a merge op
+#[derive(Debug, Serialize, Deserialize)]
+struct LinkHistoryEntry(u64, DidId, RKey, LinkAction); // (cursor, linker, linker rkey, action)
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct LinkHistory(Vec<LinkHistoryEntry>);
+
+// the latest links to a target, kept bounded by a merge op
+#[derive(Debug, Serialize, Deserialize)]
+struct RecentLinkerEntry(u64, DidId, RKey); // (cursor, linker, linker rkey)
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RecentLinkers(Vec<RecentLinkerEntry>); // most recent first
+
 // forward links to targets so we can delete links
 #[derive(Debug, Serialize, Deserialize)]
 struct RecordLinkKey(DidId, Collection, RKey);
@@ -1240,6 +2298,28 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn pinned_snapshot_outlives_storage() -> Result<()> {
+        let store = RocksStorage::new(tempdir()?)?;
+        let pinned = PinnedSnapshot::new(&store.db);
+        let cf = store.db.cf_handle(DID_IDS_CF).unwrap();
+        store.db.put_cf(&cf, b"after", b"pinning")?;
+        drop(cf);
+        // the snapshot's Arc now keeps the db open
+        drop(store);
+
+        let cf = pinned.db.cf_handle(DID_IDS_CF).unwrap();
+        assert_eq!(pinned.snapshot().get_cf(&cf, b"after")?, None);
+        assert_eq!(
+            pinned.db.get_cf(&cf, b"after")?.as_deref(),
+            Some(&b"pinning"[..])
+        );
+        drop(cf);
+        // releases the snapshot, then closes the db
+        drop(pinned);
+        Ok(())
+    }
+
     #[test]
     fn rocks_prefix_iteration_helper() -> Result<()> {
         #[derive(Serialize, Deserialize)]
@@ -1289,5 +2369,120 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn rocks_snapshot_paging_is_stable() -> Result<()> {
+        let mut store = RocksStorage::new(tempdir()?)?;
+        let link = |rkey: &str| ActionableEvent::CreateLinks {
+            record_id: RecordId {
+                did: "did:plc:asdf".into(),
+                collection: "a.b.c".into(),
+                rkey: rkey.into(),
+            },
+            links: vec![CollectedLink {
+                target: Link::Uri("example.com".into()),
+                path: ".uri".into(),
+            }],
+        };
+        for i in 0..4 {
+            store.push(&link(&format!("before-{i}")), 0)?;
+        }
+
+        let reader = store.to_readable();
+        let snapshot = reader.pin_snapshot(Duration::from_secs(60))?.unwrap();
+        let pinned = reader.at_snapshot(snapshot)?.unwrap();
+        let first = pinned.get_links("example.com", "a.b.c", ".uri", 2, None)?;
+        assert_eq!(first.next, Some(2));
+
+        // new links arrive mid-pagination
+        for i in 0..3 {
+            store.push(&link(&format!("after-{i}")), 0)?;
+        }
+
+        let pinned = reader.at_snapshot(snapshot)?.unwrap();
+        let second = pinned.get_links("example.com", "a.b.c", ".uri", 2, first.next)?;
+        let rkeys: Vec<_> = first
+            .items
+            .iter()
+            .chain(second.items.iter())
+            .map(|r| r.rkey.as_str())
+            .collect();
+        assert_eq!(rkeys, ["before-3", "before-2", "before-1", "before-0"]);
+        assert_eq!(second.next, None);
+        assert_eq!(second.total, 4);
+
+        // live reads see everything
+        assert_eq!(reader.get_count("example.com", "a.b.c", ".uri")?, 7);
+
+        reader.release_snapshot(snapshot);
+        assert!(reader.at_snapshot(snapshot)?.is_none());
+        assert!(reader.at_snapshot(snapshot + 1)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn rocks_snapshot_expires() -> Result<()> {
+        let store = RocksStorage::new(tempdir()?)?;
+        let snapshot = store.pin_snapshot(Duration::ZERO)?.unwrap();
+        assert!(store.at_snapshot(snapshot)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn rocks_secondary_catches_up() -> Result<()> {
+        let primary_dir = tempdir()?;
+        let secondary_dir = tempdir()?;
+        let mut primary = RocksStorage::new(primary_dir.path())?;
+        let link = |rkey: &str| ActionableEvent::CreateLinks {
+            record_id: RecordId {
+                did: "did:plc:asdf".into(),
+                collection: "a.b.c".into(),
+                rkey: rkey.into(),
+            },
+            links: vec![CollectedLink {
+                target: Link::Uri("example.com".into()),
+                path: ".uri".into(),
+            }],
+        };
+        primary.push(&link("a"), 100)?;
+
+        let replica = RocksStorage::open_secondary(primary_dir.path(), secondary_dir.path())?;
+        assert_eq!(replica.catch_up_with_primary()?, Some(100));
+        assert_eq!(replica.get_count("example.com", "a.b.c", ".uri")?, 1);
+
+        primary.push(&link("b"), 101)?;
+        assert_eq!(replica.catch_up_with_primary()?, Some(101));
+        assert_eq!(replica.get_count("example.com", "a.b.c", ".uri")?, 2);
+        assert_eq!(replica.get_stats()?.dids, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn rocks_linkers_dictionary_compaction() -> Result<()> {
+        let dir = tempdir()?;
+        let mut store = RocksStorage::new(dir.path())?;
+        for i in 0..2_000 {
+            store.push(
+                &ActionableEvent::CreateLinks {
+                    record_id: RecordId {
+                        did: format!("did:plc:{i}").into(),
+                        collection: "a.b.c".into(),
+                        rkey: format!("rkey-{i}"),
+                    },
+                    links: vec![CollectedLink {
+                        target: Link::Uri(format!("example.com/{}", i % 100)),
+                        path: ".uri".into(),
+                    }],
+                },
+                0,
+            )?;
+        }
+        // push everything down to the bottommost level, where the dictionary gets trained
+        let cf = store.db.cf_handle(TARGET_LINKERS_CF).unwrap();
+        store.db.flush_cf(&cf)?;
+        store.db.compact_range_cf(&cf, None::<&[u8]>, None::<&[u8]>);
+        assert_eq!(store.get_count("example.com/7", "a.b.c", ".uri")?, 20);
+        Ok(())
+    }
+
     // TODO: add tests for key prefixes actually prefixing (bincode encoding _should_...)
 }
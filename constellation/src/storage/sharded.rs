@@ -0,0 +1,153 @@
+//! Spread the write path over several threads
+//!
+//! Events are routed by a hash of their did, so every writer owns a disjoint
+//! set of did-keyed rows. Storage takes care of the rows that are shared
+//! between dids (ids and target linkers) itself.
+
+use super::LinkStorage;
+use crate::ActionableEvent;
+use anyhow::{anyhow, bail, Result};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// per-writer backlog before the dispatcher blocks
+const SHARD_QUEUE_SIZE: usize = 4096;
+
+// how often the low-water mark is written out as the jetstream cursor
+const CURSOR_SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Which of `shards` writers owns an event: decided by its did
+pub fn shard_for(event: &ActionableEvent, shards: usize) -> usize {
+    let did = match event {
+        ActionableEvent::CreateLinks { record_id, .. }
+        | ActionableEvent::UpdateLinks { record_id, .. }
+        | ActionableEvent::DeleteRecord(record_id) => &record_id.did,
+        ActionableEvent::ActivateAccount(did)
+        | ActionableEvent::DeactivateAccount(did)
+        | ActionableEvent::DeleteAccount(did) => did,
+    };
+    let mut hasher = DefaultHasher::new();
+    did.hash(&mut hasher);
+    (hasher.finish() % shards as u64) as usize
+}
+
+struct Shard {
+    sender: flume::Sender<(ActionableEvent, u64)>,
+    dispatched: u64,           // cursor of the last event handed to this writer
+    completed: Arc<AtomicU64>, // cursor of the last event it finished writing
+    handle: Option<thread::JoinHandle<Result<()>>>,
+}
+
+/// Fan events out to one writer thread per shard
+///
+/// Writers finish events out of order, so the jetstream cursor can't be saved
+/// with each event anymore. Instead the cursor is a low-water mark: the newest
+/// cursor where every earlier event has been written by every writer.
+pub struct ShardedWriter<S: LinkStorage> {
+    store: S,
+    shards: Vec<Shard>,
+    latest: u64, // newest cursor seen, whether it needed writing or not
+    saved: (u64, Instant),
+}
+
+impl<S: LinkStorage + 'static> ShardedWriter<S> {
+    pub fn new(mut store: S, writers: usize) -> Result<Self> {
+        if writers == 0 {
+            bail!("need at least one writer");
+        }
+        let mut shards = Vec::with_capacity(writers);
+        for i in 0..writers {
+            let mut writer = store.shard_writer()?;
+            let (sender, receiver) = flume::bounded::<(ActionableEvent, u64)>(SHARD_QUEUE_SIZE);
+            let completed = Arc::new(AtomicU64::new(0));
+            let handle = thread::Builder::new().name(format!("writer-{i}")).spawn({
+                let completed = completed.clone();
+                move || {
+                    for (event, cursor) in receiver.iter() {
+                        writer.push(&event, cursor)?;
+                        completed.store(cursor, Ordering::Release);
+                    }
+                    Ok(())
+                }
+            })?;
+            shards.push(Shard {
+                sender,
+                dispatched: 0,
+                completed,
+                handle: Some(handle),
+            });
+        }
+        Ok(Self {
+            store,
+            shards,
+            latest: 0,
+            saved: (0, Instant::now()),
+        })
+    }
+
+    /// Hand an event to its writer, blocking while that writer is backed up
+    pub fn push(&mut self, event: ActionableEvent, cursor: u64) -> Result<()> {
+        let i = shard_for(&event, self.shards.len());
+        let shard = &mut self.shards[i];
+        if shard.sender.send((event, cursor)).is_err() {
+            let stopped = shard.handle.take().map(|h| h.join());
+            bail!("writer {i} stopped: {stopped:?}");
+        }
+        shard.dispatched = cursor;
+        self.saw(cursor);
+        Ok(())
+    }
+
+    /// Note the cursor of an event that had nothing to write
+    pub fn saw(&mut self, cursor: u64) {
+        self.latest = self.latest.max(cursor);
+    }
+
+    /// The newest cursor where every earlier event has been written
+    pub fn watermark(&self) -> Option<u64> {
+        let mut mark = self.latest;
+        for shard in &self.shards {
+            let completed = shard.completed.load(Ordering::Acquire);
+            if completed < shard.dispatched {
+                // still busy: its queued events are all newer than what it finished
+                mark = mark.min(completed);
+            }
+        }
+        (mark > 0).then_some(mark)
+    }
+
+    /// Get the watermark, saving it as the jetstream cursor every so often
+    pub fn checkpoint(&mut self) -> Result<Option<u64>> {
+        let mark = self.watermark();
+        if let Some(mark) = mark {
+            let (saved, at) = self.saved;
+            if mark > saved && at.elapsed() >= CURSOR_SAVE_INTERVAL {
+                self.store.set_cursor(mark)?;
+                self.saved = (mark, Instant::now());
+            }
+        }
+        Ok(mark)
+    }
+
+    /// Wait for the writers to drain their queues and save the final cursor
+    pub fn finish(mut self) -> Result<Option<u64>> {
+        for (i, shard) in std::mem::take(&mut self.shards).into_iter().enumerate() {
+            drop(shard.sender);
+            if let Some(handle) = shard.handle {
+                handle
+                    .join()
+                    .map_err(|e| anyhow!("writer {i} panicked: {e:?}"))??;
+            }
+        }
+        let last = (self.latest > 0).then_some(self.latest);
+        if let Some(cursor) = last {
+            self.store.set_cursor(cursor)?;
+        }
+        Ok(last)
+    }
+}
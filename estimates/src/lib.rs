@@ -0,0 +1,143 @@
+//! Distinct-DID estimates shared by microcosm services
+//!
+//! Every service estimating "how many different accounts" uses the same HLL
+//! sketch, the same way of hashing DIDs into it, and the same bytes on the
+//! wire, so numbers agree between services and sketches can be merged across
+//! them -- as long as they share a [`SketchSecret`].
+
+use bincode::config::{standard, Config};
+use cardinality_estimator_safe::{Element, Sketch};
+use sha2::Sha256;
+use thiserror::Error;
+
+/// HLL precision: 2^14 registers, about 0.8% standard error
+pub const PRECISION: usize = 14;
+
+/// An estimate of distinct DIDs
+pub type DidsSketch = Sketch<PRECISION>;
+
+/// Mixed into every hashed DID, so nobody can craft DIDs to skew an estimate
+///
+/// Sketches are only comparable and mergeable if they were built with the same
+/// secret.
+pub type SketchSecret = [u8; 16];
+
+#[derive(Debug, Error)]
+pub enum EstimateError {
+    #[error("sketch secret must be 32 hex characters")]
+    BadSecret,
+    #[error("failed to encode sketch: {0}")]
+    EncodeFailed(#[from] bincode::error::EncodeError),
+    #[error("failed to decode sketch: {0}")]
+    DecodeFailed(#[from] bincode::error::DecodeError),
+    #[error("found {0} extra bytes after the sketch")]
+    DecodeTooManyBytes(usize),
+}
+
+fn bincode_conf() -> impl Config {
+    // must match how ufos has always stored its sketches
+    standard()
+        .with_big_endian()
+        .with_fixed_int_encoding()
+        .with_limit::<{ 2_usize.pow(20) }>() // 1MB
+}
+
+/// Parse a secret from its hex form, eg. from a cli flag
+pub fn parse_secret(hex: &str) -> Result<SketchSecret, EstimateError> {
+    let hex = hex.trim();
+    if hex.len() != 32 || !hex.is_ascii() {
+        return Err(EstimateError::BadSecret);
+    }
+    let mut secret = SketchSecret::default();
+    for (i, byte) in secret.iter_mut().enumerate() {
+        *byte =
+            u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| EstimateError::BadSecret)?;
+    }
+    Ok(secret)
+}
+
+/// The hex form of a secret, for handing it to another service
+pub fn secret_to_hex(secret: &SketchSecret) -> String {
+    secret.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Hash a DID for insertion into a sketch
+pub fn did_element(secret: &SketchSecret, did: &str) -> Element<PRECISION> {
+    Element::from_digest_with_prefix::<Sha256>(secret, did.as_bytes())
+}
+
+/// Build a sketch from some DIDs
+pub fn sketch_dids<'a>(
+    secret: &SketchSecret,
+    dids: impl IntoIterator<Item = &'a str>,
+) -> DidsSketch {
+    let mut sketch = DidsSketch::default();
+    for did in dids {
+        sketch.insert(did_element(secret, did));
+    }
+    sketch
+}
+
+/// The estimated number of distinct DIDs, as every service reports it
+pub fn estimate(sketch: &DidsSketch) -> u64 {
+    sketch.estimate() as u64
+}
+
+pub fn to_bytes(sketch: &DidsSketch) -> Result<Vec<u8>, EstimateError> {
+    Ok(bincode::serde::encode_to_vec(sketch, bincode_conf())?)
+}
+
+pub fn from_bytes(bytes: &[u8]) -> Result<DidsSketch, EstimateError> {
+    let (sketch, n) = bincode::serde::decode_from_slice(bytes, bincode_conf())?;
+    if n < bytes.len() {
+        return Err(EstimateError::DecodeTooManyBytes(bytes.len() - n));
+    }
+    Ok(sketch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: SketchSecret = [7; 16];
+
+    #[test]
+    fn test_secret_hex_roundtrip() -> Result<(), EstimateError> {
+        let hex = secret_to_hex(&SECRET);
+        assert_eq!(hex, "07070707070707070707070707070707");
+        assert_eq!(parse_secret(&hex)?, SECRET);
+        assert!(parse_secret("0707").is_err());
+        assert!(parse_secret("zz070707070707070707070707070707").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_across_sketches() -> Result<(), EstimateError> {
+        let a = sketch_dids(&SECRET, ["did:plc:a", "did:plc:b"]);
+        let b = sketch_dids(&SECRET, ["did:plc:b", "did:plc:c"]);
+
+        // eg. one from each service, shipped over the wire
+        let mut merged = from_bytes(&to_bytes(&a)?)?;
+        merged.merge(&from_bytes(&to_bytes(&b)?)?);
+        assert_eq!(estimate(&merged), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_secret_changes_hashing() {
+        let a = sketch_dids(&SECRET, ["did:plc:a"]);
+        let b = sketch_dids(&[8; 16], ["did:plc:a"]);
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn test_from_bytes_rejects_trailing() -> Result<(), EstimateError> {
+        let mut bytes = to_bytes(&sketch_dids(&SECRET, ["did:plc:a"]))?;
+        bytes.push(0);
+        assert!(matches!(
+            from_bytes(&bytes),
+            Err(EstimateError::DecodeTooManyBytes(1))
+        ));
+        Ok(())
+    }
+}
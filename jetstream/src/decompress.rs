@@ -0,0 +1,70 @@
+//! Decoding zstd-compressed Jetstream messages.
+//!
+//! Compressed messages need a custom dictionary, sourced from the
+//! [official Bluesky Jetstream repo.](https://github.com/bluesky-social/jetstream/tree/main/pkg/models)
+//!
+//! The `zstd` feature decodes them with the zstd C library (via `zstd-sys`, which needs a C
+//! toolchain to build), and the `ruzstd` feature with a pure-Rust decoder. If both are enabled,
+//! `zstd` is used.
+use std::io::{
+    self,
+    Read,
+};
+
+/// Whether this build can decode compressed messages at all.
+pub(crate) const AVAILABLE: bool = cfg!(any(feature = "zstd", feature = "ruzstd"));
+
+/// The custom `zstd` dictionary used for decoding compressed Jetstream messages.
+#[cfg(any(feature = "zstd", feature = "ruzstd"))]
+const JETSTREAM_ZSTD_DICTIONARY: &[u8] = include_bytes!("../zstd/dictionary");
+
+#[cfg(feature = "zstd")]
+pub(crate) struct Decompressor(zstd::dict::DecoderDictionary<'static>);
+
+#[cfg(feature = "zstd")]
+impl Decompressor {
+    pub(crate) fn new() -> Self {
+        Self(zstd::dict::DecoderDictionary::copy(JETSTREAM_ZSTD_DICTIONARY))
+    }
+
+    pub(crate) fn decode<'a>(&'a mut self, compressed: &'a [u8]) -> io::Result<impl Read + 'a> {
+        zstd::stream::Decoder::with_prepared_dictionary(io::Cursor::new(compressed), &self.0)
+    }
+}
+
+#[cfg(all(feature = "ruzstd", not(feature = "zstd")))]
+pub(crate) struct Decompressor(ruzstd::decoding::FrameDecoder);
+
+#[cfg(all(feature = "ruzstd", not(feature = "zstd")))]
+impl Decompressor {
+    pub(crate) fn new() -> Self {
+        let dict = ruzstd::decoding::Dictionary::decode_dict(JETSTREAM_ZSTD_DICTIONARY)
+            .expect("the built-in dictionary is valid");
+        let mut decoder = ruzstd::decoding::FrameDecoder::new();
+        decoder
+            .add_dict(dict)
+            .expect("the built-in dictionary is only added once");
+        Self(decoder)
+    }
+
+    pub(crate) fn decode<'a>(&'a mut self, compressed: &'a [u8]) -> io::Result<impl Read + 'a> {
+        ruzstd::decoding::StreamingDecoder::new_with_decoder(compressed, &mut self.0)
+            .map_err(io::Error::other)
+    }
+}
+
+/// Stand-in for builds without a zstd decoder. Compression is rejected when validating the
+/// config, so nothing should ever be sent to it.
+#[cfg(not(any(feature = "zstd", feature = "ruzstd")))]
+pub(crate) struct Decompressor;
+
+#[cfg(not(any(feature = "zstd", feature = "ruzstd")))]
+impl Decompressor {
+    pub(crate) fn new() -> Self {
+        Self
+    }
+
+    pub(crate) fn decode<'a>(&'a mut self, _compressed: &'a [u8]) -> io::Result<impl Read + 'a> {
+        Err::<io::Empty, _>(io::Error::other("this build has no zstd decoder"))
+    }
+}
@@ -13,6 +13,8 @@ pub enum ConfigValidationError {
     TooManyDids(usize),
     #[error("invalid endpoint: {0}")]
     InvalidEndpoint(#[from] url::ParseError),
+    #[error("zstd compression needs jetstream built with the `zstd` or `ruzstd` feature")]
+    CompressionUnavailable,
 }
 
 /// Possible errors that can occur in the process of connecting to a Jetstream instance over
@@ -1,13 +1,11 @@
+mod decompress;
 pub mod error;
 pub mod events;
 pub mod exports;
 
-use std::{
-    io::Cursor as IoCursor,
-    time::{
-        Duration,
-        Instant,
-    },
+use std::time::{
+    Duration,
+    Instant,
 };
 
 use futures_util::{
@@ -43,9 +41,9 @@ use tokio_tungstenite::{
     WebSocketStream,
 };
 use url::Url;
-use zstd::dict::DecoderDictionary;
 
 use crate::{
+    decompress::Decompressor,
     error::{
         ConfigValidationError,
         ConnectionError,
@@ -119,11 +117,6 @@ const MAX_WANTED_COLLECTIONS: usize = 100;
 /// The maximum number of wanted DIDs that can be requested on a single Jetstream connection.
 const MAX_WANTED_DIDS: usize = 10_000;
 
-/// The custom `zstd` dictionary used for decoding compressed Jetstream messages.
-///
-/// Sourced from the [official Bluesky Jetstream repo.](https://github.com/bluesky-social/jetstream/tree/main/pkg/models)
-const JETSTREAM_ZSTD_DICTIONARY: &[u8] = include_bytes!("../zstd/dictionary");
-
 /// A receiver channel for consuming Jetstream events.
 pub type JetstreamReceiver = Receiver<JetstreamEvent>;
 
@@ -142,6 +135,9 @@ pub enum JetstreamCompression {
     None,
     /// Use the `zstd` compression algorithm, which can result in a ~56% smaller messages on
     /// average. See [here](https://github.com/bluesky-social/jetstream?tab=readme-ov-file#compression) for more info.
+    ///
+    /// Needs the `zstd` (on by default) or `ruzstd` feature: without either, configs asking for
+    /// compression fail validation.
     Zstd,
 }
 
@@ -305,6 +301,10 @@ impl JetstreamConfig {
             return Err(ConfigValidationError::TooManyDids(dids));
         }
 
+        if !decompress::AVAILABLE && matches!(self.compression, JetstreamCompression::Zstd) {
+            return Err(ConfigValidationError::CompressionUnavailable);
+        }
+
         let _ = self.endpoint.parse::<Url>()?;
 
         Ok(())
@@ -399,7 +399,7 @@ impl JetstreamConnector {
             let mut retry_attempt = 0;
             let mut connect_cursor = cursor;
             loop {
-                let dict = DecoderDictionary::copy(JETSTREAM_ZSTD_DICTIONARY);
+                let decompressor = Decompressor::new();
 
                 let req = match build_request(connect_cursor) {
                     Ok(req) => req,
@@ -422,7 +422,7 @@ impl JetstreamConnector {
                     let t_connected = Instant::now();
                     log::info!("jetstream connected. starting websocket task...");
                     if let Err(e) = websocket_task(
-                        dict,
+                        decompressor,
                         ws_stream,
                         send_channel.clone(),
                         &mut last_cursor,
@@ -493,7 +493,7 @@ impl JetstreamConnector {
 /// The main task that handles the WebSocket connection and sends [JetstreamEvent]'s to any
 /// receivers that are listening for them.
 async fn websocket_task(
-    dictionary: DecoderDictionary<'_>,
+    mut decompressor: Decompressor,
     ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
     send_channel: JetstreamSender,
     last_cursor: &mut Option<Cursor>,
@@ -567,10 +567,9 @@ async fn websocket_task(
                         counter!("jetstream_total_bytes_received", "compressed" => "true")
                             .increment(zstd_json.len() as u64);
                     }
-                    let mut cursor = IoCursor::new(zstd_json);
-                    let decoder =
-                        zstd::stream::Decoder::with_prepared_dictionary(&mut cursor, &dictionary)
-                            .map_err(JetstreamEventError::CompressionDictionaryError)?;
+                    let decoder = decompressor
+                        .decode(&zstd_json)
+                        .map_err(JetstreamEventError::CompressionDictionaryError)?;
 
                     let event: JetstreamEvent = match serde_json::from_reader(decoder) {
                         Ok(ev) => ev,
@@ -2,6 +2,7 @@ use fluent_uri::Uri;
 
 pub mod at_uri;
 pub mod did;
+pub mod normalize;
 pub mod record;
 
 pub use record::collect_links;
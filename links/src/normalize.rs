@@ -0,0 +1,270 @@
+//! Extra, configurable normalization for plain URL link targets
+//!
+//! `parse_uri` already does the RFC 3986 normalization (scheme and host case,
+//! percent-encoding, dot segments). This goes further and drops the parts of a
+//! URL that don't change what it points at, so that counts for one page aren't
+//! spread across many trivially different URLs.
+
+use crate::{CollectedLink, Link};
+
+/// Query params that only track where a click came from
+pub const TRACKING_PARAMS: &[&str] = &[
+    "utm_*", "fbclid", "gclid", "dclid", "gbraid", "wbraid", "msclkid", "mc_cid", "mc_eid",
+    "igshid", "_ga", "_gl",
+];
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UrlNormalization {
+    /// query params to drop. a trailing `*` matches any param with that prefix
+    pub strip_params: Vec<String>,
+    /// drop the `#fragment`
+    pub strip_fragment: bool,
+    /// drop `:80` from http and `:443` from https urls
+    pub strip_default_port: bool,
+    /// convert internationalized hostnames to punycode
+    #[cfg(feature = "punycode")]
+    pub punycode: bool,
+}
+
+impl UrlNormalization {
+    /// Strip tracking params and default ports, keep everything else
+    pub fn tracking() -> Self {
+        Self {
+            strip_params: TRACKING_PARAMS.iter().map(|p| p.to_string()).collect(),
+            strip_default_port: true,
+            ..Default::default()
+        }
+    }
+
+    /// Whether this would leave every url untouched
+    pub fn is_noop(&self) -> bool {
+        *self == Self::default()
+    }
+
+    fn strips_param(&self, name: &str) -> bool {
+        self.strip_params
+            .iter()
+            .any(|rule| match rule.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == rule,
+            })
+    }
+
+    /// Normalize a url that already went through `parse_uri`
+    pub fn normalize(&self, uri: &str) -> String {
+        let (rest, fragment) = match uri.split_once('#') {
+            Some((rest, fragment)) => (rest, Some(fragment)),
+            None => (uri, None),
+        };
+        let (rest, query) = match rest.split_once('?') {
+            Some((rest, query)) => (rest, Some(query)),
+            None => (rest, None),
+        };
+
+        let mut out = match rest.split_once("://") {
+            Some((scheme, hier)) => {
+                let (authority, path) = hier.split_at(hier.find('/').unwrap_or(hier.len()));
+                format!(
+                    "{scheme}://{}{path}",
+                    self.normalize_authority(scheme, authority)
+                )
+            }
+            None => rest.to_string(), // no authority, eg. mailto:
+        };
+
+        if let Some(query) = query {
+            let kept: Vec<&str> = query
+                .split('&')
+                .filter(|param| {
+                    let name = param.split_once('=').map_or(*param, |(name, _)| name);
+                    !self.strips_param(name)
+                })
+                .collect();
+            if !kept.is_empty() {
+                out.push('?');
+                out.push_str(&kept.join("&"));
+            }
+        }
+
+        if let Some(fragment) = fragment {
+            if !self.strip_fragment {
+                out.push('#');
+                out.push_str(fragment);
+            }
+        }
+        out
+    }
+
+    fn normalize_authority(&self, scheme: &str, authority: &str) -> String {
+        let (userinfo, hostport) = match authority.rsplit_once('@') {
+            Some((userinfo, hostport)) => (Some(userinfo), hostport),
+            None => (None, authority),
+        };
+        let (host, port) = match hostport.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (host, Some(port)),
+            _ => (hostport, None),
+        };
+
+        let port = port.filter(|port| {
+            let default = matches!((scheme, *port), ("http", "80") | ("https", "443"));
+            !(self.strip_default_port && default)
+        });
+
+        #[cfg(feature = "punycode")]
+        let punycoded = self.punycode.then(|| to_punycode(host)).flatten();
+        #[cfg(feature = "punycode")]
+        let host = punycoded.as_deref().unwrap_or(host);
+
+        let mut out = String::with_capacity(authority.len());
+        if let Some(userinfo) = userinfo {
+            out.push_str(userinfo);
+            out.push('@');
+        }
+        out.push_str(host);
+        if let Some(port) = port {
+            out.push(':');
+            out.push_str(port);
+        }
+        out
+    }
+
+    /// Normalize a link if it's a plain url. at-uris and dids are left alone.
+    pub fn normalize_link(&self, link: Link) -> Link {
+        match link {
+            Link::Uri(uri) => Link::Uri(self.normalize(&uri)),
+            other => other,
+        }
+    }
+
+    pub fn normalize_links(&self, links: &mut [CollectedLink]) {
+        if self.is_noop() {
+            return;
+        }
+        for link in links {
+            if let Link::Uri(uri) = &link.target {
+                link.target = Link::Uri(self.normalize(uri));
+            }
+        }
+    }
+}
+
+/// Percent-decode a hostname and convert it to its ascii form
+#[cfg(feature = "punycode")]
+fn to_punycode(host: &str) -> Option<String> {
+    if !host.contains('%') {
+        return None; // parse_uri only lets through ascii hosts
+    }
+    let mut bytes = Vec::with_capacity(host.len());
+    let mut rest = host.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' && tail.len() >= 2 {
+            let hex = std::str::from_utf8(&tail[..2]).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    idna::domain_to_ascii(&String::from_utf8(bytes).ok()?).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_uri;
+
+    fn norm(n: &UrlNormalization, s: &str) -> String {
+        n.normalize(&parse_uri(s).unwrap())
+    }
+
+    #[test]
+    fn test_default_is_noop() {
+        let n = UrlNormalization::default();
+        assert!(n.is_noop());
+        for s in [
+            "https://example.com/a?utm_source=x#frag",
+            "https://user@example.com:443/",
+            "mailto:someone@example.com",
+        ] {
+            assert_eq!(norm(&n, s), parse_uri(s).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_strip_tracking_params() {
+        let n = UrlNormalization::tracking();
+        assert_eq!(
+            norm(&n, "HTTPS://example.com/a?utm_source=bsky&id=3&fbclid=abc"),
+            "https://example.com/a?id=3"
+        );
+        assert_eq!(
+            norm(
+                &n,
+                "https://example.com/a?utm_source=bsky&utm_medium=social"
+            ),
+            "https://example.com/a"
+        );
+        // only whole param names (or prefixes with `*`) match
+        assert_eq!(
+            norm(&n, "https://example.com/?gclidx=1"),
+            "https://example.com/?gclidx=1"
+        );
+    }
+
+    #[test]
+    fn test_strip_default_port() {
+        let n = UrlNormalization::tracking();
+        assert_eq!(norm(&n, "https://example.com:443/"), "https://example.com/");
+        assert_eq!(norm(&n, "http://example.com:80/a"), "http://example.com/a");
+        assert_eq!(
+            norm(&n, "http://example.com:443/"),
+            "http://example.com:443/"
+        );
+        assert_eq!(norm(&n, "https://[::1]:443/x"), "https://[::1]/x");
+    }
+
+    #[test]
+    fn test_strip_fragment() {
+        let n = UrlNormalization {
+            strip_fragment: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            norm(&n, "https://example.com/a?b=c#d"),
+            "https://example.com/a?b=c"
+        );
+    }
+
+    #[test]
+    fn test_normalize_links_skips_non_urls() {
+        let n = UrlNormalization::tracking();
+        let mut links = vec![
+            CollectedLink {
+                path: ".a".into(),
+                target: Link::Uri("https://example.com/?utm_campaign=z".into()),
+            },
+            CollectedLink {
+                path: ".b".into(),
+                target: Link::Did("did:plc:asdf".into()),
+            },
+        ];
+        n.normalize_links(&mut links);
+        assert_eq!(links[0].target, Link::Uri("https://example.com/".into()));
+        assert_eq!(links[1].target, Link::Did("did:plc:asdf".into()));
+    }
+
+    #[cfg(feature = "punycode")]
+    #[test]
+    fn test_punycode() {
+        let n = UrlNormalization {
+            punycode: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            norm(&n, "https://b%C3%BCcher.example/"),
+            "https://xn--bcher-kva.example/"
+        );
+        assert_eq!(norm(&n, "https://example.com/"), "https://example.com/");
+    }
+}
@@ -0,0 +1,395 @@
+use anyhow::{anyhow, bail, Context};
+use axum::{
+    body::{to_bytes, Body},
+    extract::{Request, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Router,
+};
+use clap::Parser;
+use jetstream::events::{CommitOp, Cursor, EventKind, JetstreamEvent};
+use jetstream::{JetstreamConnector, JetstreamReceiver};
+use links::normalize::UrlNormalization;
+use metrics_exporter_prometheus::PrometheusBuilder;
+use microcosm_estimates::SketchSecret;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tinyjson::JsonValue;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+
+use constellation::consumer::{consume_events, CollectionFilter, ProcessedCursor};
+use constellation::storage::{Aliased, LinkStorage, RocksStorage, TargetAliases};
+use ufos::consumer::{self, Batcher};
+use ufos::server::auth::Auth;
+use ufos::storage::{StorageWhatever, StoreBackground, StoreWriter};
+use ufos::storage_fjall::{FjallConfig, FjallStorage};
+
+// biggest request body passed through to the ufos api
+const MAX_PROXIED_BODY: usize = 1024 * 1024;
+
+/// Constellation and UFOs in one process, over one jetstream subscription
+#[derive(Debug, Parser)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Jetstream server to connect to: a wss:// URL, or a shortcut like 'us-east-1'
+    #[arg(long)]
+    jetstream: String,
+    /// Don't request zstd-compressed jetstream
+    #[arg(long, action)]
+    jetstream_no_zstd: bool,
+    /// Where both services keep their data, in `ufos/` and `constellation/`
+    #[arg(long)]
+    data: PathBuf,
+    /// Serve both APIs here: constellation at /links/*, and ufos under /ufos/*
+    #[arg(long, default_value = "0.0.0.0:6789")]
+    bind: SocketAddr,
+    /// Loopback address for the ufos api that /ufos/* is proxied to
+    #[arg(long, default_value = "127.0.0.1:9999")]
+    ufos_internal: SocketAddr,
+    /// Serve prometheus metrics for both services here
+    #[arg(long, default_value = "0.0.0.0:8765")]
+    metrics_listen: SocketAddr,
+    /// Secret (32 hex chars) for distinct-dids sketches, shared by both services so
+    /// their estimates can be merged. Generated for a fresh ufos db if omitted
+    #[arg(long, value_parser = microcosm_estimates::parse_secret)]
+    sketch_secret: Option<SketchSecret>,
+    /// Number of threads writing to constellation's storage
+    #[arg(long, default_value_t = 1)]
+    links_writers: usize,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    env_logger::init();
+    let args = Args::parse();
+
+    PrometheusBuilder::new()
+        .set_quantiles(&[0.5, 0.9, 0.99, 1.0])?
+        .set_bucket_duration(Duration::from_secs(60))?
+        .set_bucket_count(std::num::NonZero::new(10).unwrap())
+        .set_enable_unit_suffix(false)
+        .with_http_listener(args.metrics_listen)
+        .install()?;
+    println!("metrics: listening at http://{}", args.metrics_listen);
+
+    let endpoint = jetstream::DefaultJetstreamEndpoints::endpoint_or_shortcut(&args.jetstream);
+
+    println!("starting ufos storage...");
+    let (ufos_read, mut ufos_write, ufos_cursor, sketch_secret) = FjallStorage::init(
+        args.data.join("ufos"),
+        endpoint,
+        false,
+        FjallConfig {
+            sketch_secret: args.sketch_secret,
+            ..Default::default()
+        },
+    )?;
+
+    println!("starting constellation storage...");
+    let mut links_store = RocksStorage::new(args.data.join("constellation"))?;
+    let links_cursor = links_store.get_cursor()?;
+    let links_read = links_store.to_readable();
+    let aliases = TargetAliases::load(&links_read)?;
+
+    // resume from whichever is further behind: the other skips what it already has
+    let start = match (ufos_cursor, links_cursor.map(Cursor::from_raw_u64)) {
+        (Some(a), Some(b)) => Some(if a < b { a } else { b }),
+        (a, b) => a.or(b),
+    };
+    println!("connecting to jetstream from cursor {start:?}...");
+    let jetstream = JetstreamConnector::new(consumer::jetstream_config(
+        &args.jetstream,
+        args.jetstream_no_zstd,
+    ))?
+    .connect_cursor(start)
+    .await?;
+
+    let mut tasks: JoinSet<anyhow::Result<()>> = JoinSet::new();
+
+    let (ufos_sender, ufos_events) = mpsc::channel(consumer::BATCH_QUEUE_SIZE);
+    let (links_sender, links_events) = flume::bounded(32_768);
+    tasks.spawn(tee(
+        jetstream,
+        ufos_sender,
+        ufos_cursor,
+        links_sender,
+        links_cursor,
+    ));
+
+    let (batch_sender, batches) = mpsc::channel(consumer::BATCH_QUEUE_SIZE);
+    let mut batcher = Batcher::new(
+        ufos_events,
+        batch_sender,
+        sketch_secret,
+        consumer::MAX_BATCHED_COLLECTIONS,
+    )
+    .detect_gaps(ufos_cursor);
+    tasks.spawn(async move { batcher.run().await.context("ufos batcher") });
+    let rolling = ufos_write.background_tasks(false)?.run(false);
+    tasks.spawn(async move { rolling.await.context("ufos rollups") });
+    tasks.spawn(async move {
+        ufos_write
+            .receive_batches(batches)
+            .await
+            .context("ufos writer")
+    });
+
+    let (processed_sender, processed) = ProcessedCursor::channel();
+    let writers = args.links_writers;
+    let links_indexing = tokio::task::spawn_blocking(move || {
+        consume_events(
+            links_store,
+            links_events,
+            processed_sender,
+            writers,
+            UrlNormalization::default(),
+            CollectionFilter::default(),
+        )
+    });
+    tasks.spawn(async move { links_indexing.await?.context("constellation indexer") });
+
+    let ufos_internal = args.ufos_internal;
+    tasks.spawn(async move {
+        ufos::server::serve_at(ufos_read, Arc::new(Auth::open()), None, None, ufos_internal)
+            .await
+            .map_err(|e| anyhow!("ufos api: {e}"))
+    });
+
+    let app = Router::new()
+        .nest(
+            "/ufos",
+            Router::new().fallback(proxy_ufos).with_state(UfosUpstream {
+                client: reqwest::Client::new(),
+                addr: ufos_internal,
+            }),
+        )
+        .merge(constellation::server::router(
+            Aliased::new(links_read, aliases),
+            processed,
+            Some(sketch_secret),
+            UrlNormalization::default(),
+        ));
+    let listener = TcpListener::bind(args.bind).await?;
+    println!("api: listening at http://{}", listener.local_addr()?);
+    tasks.spawn(async move { Ok(axum::serve(listener, app).await?) });
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => println!("ok, shutting down..."),
+        Some(ended) = tasks.join_next() => match ended {
+            Ok(Ok(())) => log::warn!("a task ended early"),
+            Ok(Err(e)) => log::error!("a task failed: {e:?}"),
+            Err(e) => log::error!("a task panicked: {e:?}"),
+        },
+    }
+    // dropping the jetstream side lets both writers drain and flush
+    tasks.shutdown().await;
+    println!("bye!");
+    Ok(())
+}
+
+/// Send each jetstream event to both indexes, unless it's at or before their cursor
+async fn tee(
+    mut jetstream: JetstreamReceiver,
+    ufos: mpsc::Sender<JetstreamEvent>,
+    ufos_cursor: Option<Cursor>,
+    links: flume::Sender<JsonValue>,
+    links_cursor: Option<u64>,
+) -> anyhow::Result<()> {
+    while let Some(event) = jetstream.recv().await {
+        let time_us = event.cursor.to_raw_u64();
+        if links_cursor.is_none_or(|c| time_us > c) {
+            if let Some(json) = constellation_json(&event) {
+                links
+                    .send_async(json)
+                    .await
+                    .map_err(|_| anyhow!("constellation indexer hung up"))?;
+            }
+        }
+        if ufos_cursor.is_none_or(|c| event.cursor > c) {
+            ufos.send(event)
+                .await
+                .map_err(|_| anyhow!("ufos batcher hung up"))?;
+        }
+    }
+    bail!("jetstream ended")
+}
+
+/// An event as the json that constellation's consumer reads from jetstream
+///
+/// Only has what constellation looks at. `None` for an event that's missing its
+/// commit or account, or with a record that doesn't parse.
+fn constellation_json(event: &JetstreamEvent) -> Option<JsonValue> {
+    let mut root = HashMap::from([
+        (
+            "time_us".to_string(),
+            JsonValue::Number(event.cursor.to_raw_u64() as f64),
+        ),
+        (
+            "did".to_string(),
+            JsonValue::String(event.did.as_str().to_string()),
+        ),
+    ]);
+    match event.kind {
+        EventKind::Commit => {
+            let commit = event.commit.as_ref()?;
+            let operation = match commit.operation {
+                CommitOp::Create => "create",
+                CommitOp::Update => "update",
+                CommitOp::Delete => "delete",
+            };
+            let mut fields = HashMap::from([
+                (
+                    "collection".to_string(),
+                    JsonValue::String(commit.collection.as_str().to_string()),
+                ),
+                (
+                    "rkey".to_string(),
+                    JsonValue::String(commit.rkey.as_str().to_string()),
+                ),
+                (
+                    "operation".to_string(),
+                    JsonValue::String(operation.to_string()),
+                ),
+            ]);
+            if let Some(record) = &commit.record {
+                fields.insert("record".to_string(), record.get().parse().ok()?);
+            }
+            root.insert("kind".to_string(), JsonValue::String("commit".to_string()));
+            root.insert("commit".to_string(), JsonValue::Object(fields));
+        }
+        EventKind::Account => {
+            let account = event.account.as_ref()?;
+            let mut fields = HashMap::from([
+                (
+                    "did".to_string(),
+                    JsonValue::String(account.did.as_str().to_string()),
+                ),
+                ("active".to_string(), JsonValue::Boolean(account.active)),
+            ]);
+            if let Some(status) = &account.status {
+                fields.insert("status".to_string(), JsonValue::String(status.clone()));
+            }
+            root.insert("kind".to_string(), JsonValue::String("account".to_string()));
+            root.insert("account".to_string(), JsonValue::Object(fields));
+        }
+        // not actionable, but still moves constellation's processed cursor along
+        EventKind::Identity => {
+            root.insert(
+                "kind".to_string(),
+                JsonValue::String("identity".to_string()),
+            );
+        }
+    }
+    Some(JsonValue::Object(root))
+}
+
+#[derive(Clone)]
+struct UfosUpstream {
+    client: reqwest::Client,
+    addr: SocketAddr,
+}
+
+/// Pass a request under /ufos through to the ufos api, without the prefix
+async fn proxy_ufos(State(upstream): State<UfosUpstream>, request: Request) -> Response {
+    let (parts, body) = request.into_parts();
+    let path_and_query = parts
+        .uri
+        .path_and_query()
+        .map(|pq| pq.as_str())
+        .unwrap_or("/");
+    let url = format!("http://{}{path_and_query}", upstream.addr);
+    let Ok(body) = to_bytes(body, MAX_PROXIED_BODY).await else {
+        return (StatusCode::PAYLOAD_TOO_LARGE, "request body too large").into_response();
+    };
+    let mut headers = parts.headers;
+    headers.remove(header::HOST);
+    let res = match upstream
+        .client
+        .request(parts.method, url)
+        .headers(headers)
+        .body(body)
+        .send()
+        .await
+    {
+        Ok(res) => res,
+        Err(e) => {
+            log::warn!("ufos proxy: {e}");
+            return (StatusCode::BAD_GATEWAY, "ufos api unavailable").into_response();
+        }
+    };
+    let status = res.status();
+    let mut headers = res.headers().clone();
+    headers.remove(header::TRANSFER_ENCODING);
+    headers.remove(header::CONNECTION);
+    match res.bytes().await {
+        Ok(bytes) => (status, headers, Body::from(bytes)).into_response(),
+        Err(e) => {
+            log::warn!("ufos proxy: reading response: {e}");
+            (StatusCode::BAD_GATEWAY, "ufos api response failed").into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use constellation::consumer::get_actionable;
+    use constellation::{ActionableEvent, RecordId};
+
+    #[test]
+    fn test_constellation_json() {
+        let event: JetstreamEvent = serde_json::from_str(
+            r#"{
+            "did":"did:plc:icprmty6ticzracr5urz4uum",
+            "time_us":1736448492661668,
+            "kind":"commit",
+            "commit":{"rev":"3lfddpt5qa62c","operation":"create","collection":"app.bsky.feed.like","rkey":"3lfddpt5djw2c","record":{
+                "$type":"app.bsky.feed.like",
+                "createdAt":"2025-01-09T18:48:10.412Z",
+                "subject":{"cid":"bafyreihazf62qvmusup55ojhkzwbmzee6rxtsug3e6eg33mnjrgthxvozu","uri":"at://did:plc:lphckw3dz4mnh3ogmfpdgt6z/app.bsky.feed.post/3lfdau5f7wk23"}
+            },
+            "cid":"bafyreidgcs2id7nsbp6co42ind2wcig3riwcvypwan6xdywyfqklovhdjq"}
+        }"#,
+        )
+        .unwrap();
+        let json = constellation_json(&event).unwrap();
+        let Some((ActionableEvent::CreateLinks { record_id, links }, cursor)) =
+            get_actionable(&json)
+        else {
+            panic!("expected links to be created");
+        };
+        assert_eq!(cursor, 1736448492661668);
+        assert_eq!(
+            record_id,
+            RecordId {
+                did: "did:plc:icprmty6ticzracr5urz4uum".into(),
+                collection: "app.bsky.feed.like".into(),
+                rkey: "3lfddpt5djw2c".into(),
+            }
+        );
+        assert_eq!(links.len(), 1);
+
+        let event: JetstreamEvent = serde_json::from_str(
+            r#"{
+            "did":"did:plc:icprmty6ticzracr5urz4uum",
+            "time_us":1736448492661669,
+            "kind":"account",
+            "account":{"active":false,"did":"did:plc:icprmty6ticzracr5urz4uum","seq":1,"time":"2025-01-09T18:48:10.412Z","status":"deleted"}
+        }"#,
+        )
+        .unwrap();
+        let json = constellation_json(&event).unwrap();
+        assert_eq!(
+            get_actionable(&json),
+            Some((
+                ActionableEvent::DeleteAccount("did:plc:icprmty6ticzracr5urz4uum".into()),
+                1736448492661669
+            ))
+        );
+    }
+}
@@ -34,6 +34,10 @@ pub enum IdentityError {
 
     #[error("failed to resolve: {0}")]
     ResolutionFailed(#[from] atrium_identity::Error),
+    #[error("failed to fetch did:web doc from {0}: {1}")]
+    DidWebFailed(String, reqwest::Error),
+    #[error("resolving {0} failed {1} times in a row, backing off")]
+    BackingOff(String, u32),
     // #[error("identity resolved but no handle found for user")]
     // NoHandle,
     #[error("found handle {0:?} but it appears invalid: {1}")]
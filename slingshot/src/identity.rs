@@ -1,5 +1,6 @@
 use hickory_resolver::{ResolveError, TokioResolver};
 use std::collections::{HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
 use std::sync::Arc;
 /// for now we're gonna just keep doing more cache
@@ -33,6 +34,20 @@ use time::UtcDateTime;
 /// once we have something resolved, don't re-resolve until after this period
 const MIN_TTL: Duration = Duration::from_secs(4 * 3600); // probably shoudl have a max ttl
 const MIN_NOT_FOUND_TTL: Duration = Duration::from_secs(60);
+/// after failing to resolve something we had nothing cached for, wait this long to retry
+///
+/// doubles with each consecutive failure. did:web hosts especially can just be down,
+/// and we don't want every request for them to hang on another attempt.
+const MIN_FAILURE_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_FAILURE_BACKOFF: Duration = Duration::from_secs(3600);
+/// did:web docs come from arbitrary servers: don't wait on slow ones for long
+const DID_WEB_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn failure_backoff(failures: u32) -> Duration {
+    MIN_FAILURE_BACKOFF
+        .saturating_mul(2_u32.saturating_pow(failures.saturating_sub(1)))
+        .min(MAX_FAILURE_BACKOFF)
+}
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
 enum IdentityKey {
@@ -48,6 +63,8 @@ enum IdentityData {
     NotFound,
     Did(Did),
     Doc(PartialMiniDoc),
+    /// resolution failed this many times in a row, with nothing good cached from before
+    Failed(u32),
 }
 
 /// partial representation of a com.bad-example.identity mini atproto doc
@@ -139,6 +156,152 @@ impl TryFrom<DidDocument> for PartialMiniDoc {
     }
 }
 
+/// The url to fetch a did:web's doc from
+///
+/// atproto only allows hostname-level did:webs (no paths), and ports only for
+/// localhost development, where the doc is fetched over plain http. we only
+/// follow those when `allow_local` is set (a dev-only flag), and otherwise
+/// refuse anything that names a loopback or private host.
+fn did_web_url(did: &Did, allow_local: bool) -> Result<String, &'static str> {
+    let Some(host) = did.as_str().strip_prefix("did:web:") else {
+        return Err("not a did:web");
+    };
+    if host.contains(':') {
+        return Err("path-based did:web is not supported in atproto");
+    }
+    if let Some(port) = host.strip_prefix("localhost%3A") {
+        if !allow_local {
+            return Err("localhost did:web is only allowed in development");
+        }
+        if port.is_empty() || !port.chars().all(|c| c.is_ascii_digit()) {
+            return Err("invalid port for localhost did:web");
+        }
+        return Ok(format!("http://localhost:{port}/.well-known/did.json"));
+    }
+    if host.contains('%') {
+        return Err("did:web ports are only allowed for localhost");
+    }
+    if host.is_empty() || host.starts_with('.') || host.ends_with('.') {
+        return Err("did:web hostname is invalid");
+    }
+    if !allow_local {
+        let host = host.to_ascii_lowercase();
+        if host == "localhost" || host.ends_with(".localhost") {
+            return Err("localhost did:web is only allowed in development");
+        }
+        // anything ending in a numeric label is an ip literal to a url parser
+        let last_label = host.rsplit('.').next().unwrap_or_default();
+        if last_label.chars().all(|c| c.is_ascii_digit()) {
+            match host.parse() {
+                Ok(ip) if is_public_ip(IpAddr::V4(ip)) => {}
+                _ => return Err("did:web must not point at a private address"),
+            }
+        }
+    }
+    Ok(format!("https://{host}/.well-known/did.json"))
+}
+
+/// Whether an address is reachable on the public internet
+///
+/// conservative: anything loopback, private, link-local, or otherwise special
+/// is refused.
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                || (a == 100 && (64..128).contains(&b)) // carrier-grade nat
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(v4) = ip.to_ipv4_mapped() {
+                return is_public_ip(IpAddr::V4(v4));
+            }
+            let first = ip.segments()[0];
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                || (first & 0xfe00) == 0xfc00 // unique local
+                || (first & 0xffc0) == 0xfe80) // link-local
+        }
+    }
+}
+
+/// DNS for did:web fetches that drops non-public addresses
+///
+/// hostname checks alone can't stop a public name from resolving to a private
+/// address, so the http client only ever connects to what survives this.
+struct PublicOnlyResolver;
+
+impl reqwest::dns::Resolve for PublicOnlyResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        Box::pin(async move {
+            let public: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_public_ip(addr.ip()))
+                .collect();
+            if public.is_empty() {
+                return Err("did:web host has no public addresses".into());
+            }
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(
+                Box::new(public.into_iter()) as reqwest::dns::Addrs
+            )
+        })
+    }
+}
+
+/// did:plc goes to the plc directory, did:web is fetched over https
+///
+/// atrium's common resolver doesn't handle did:web localhost ports, and we
+/// want our own timeout for arbitrary hosts, so we fetch those docs ourselves.
+///
+/// `None` means the doc doesn't exist.
+struct DidResolver {
+    plc: CommonDidResolver<DefaultHttpClient>,
+    web_client: reqwest::Client,
+    /// dev-only: allow localhost did:webs over http
+    allow_local_did_web: bool,
+}
+
+impl DidResolver {
+    async fn resolve(&self, did: &Did) -> Result<Option<DidDocument>, IdentityError> {
+        if !did.as_str().starts_with("did:web:") {
+            return match self.plc.resolve(did).await {
+                Ok(doc) => Ok(Some(doc)),
+                Err(atrium_identity::Error::NotFound) => Ok(None),
+                Err(other) => Err(IdentityError::ResolutionFailed(other)),
+            };
+        }
+        let url = did_web_url(did, self.allow_local_did_web).map_err(IdentityError::BadDid)?;
+        let fetched = async {
+            let res = self.web_client.get(&url).send().await?;
+            if matches!(
+                res.status(),
+                reqwest::StatusCode::NOT_FOUND | reqwest::StatusCode::GONE
+            ) {
+                return Ok(None);
+            }
+            let doc = res.error_for_status()?.json().await?;
+            Ok(Some(doc))
+        }
+        .await;
+        let success = match &fetched {
+            Ok(Some(_)) => "true",
+            Ok(None) => "not found",
+            Err(_) => "false",
+        };
+        metrics::counter!("slingshot_resolve_did_web", "success" => success).increment(1);
+        fetched.map_err(|e: reqwest::Error| IdentityError::DidWebFailed(url, e))
+    }
+}
+
 /// multi-producer *single-consumer* queue structures (wrap in arc-mutex plz)
 ///
 /// the hashset allows testing for presense of items in the queue.
@@ -152,7 +315,7 @@ struct RefreshQueue {
 #[derive(Clone)]
 pub struct Identity {
     handle_resolver: Arc<AtprotoHandleResolver<HickoryDnsTxtResolver, DefaultHttpClient>>,
-    did_resolver: Arc<CommonDidResolver<DefaultHttpClient>>,
+    did_resolver: Arc<DidResolver>,
     cache: HybridCache<IdentityKey, IdentityVal>,
     /// multi-producer *single consumer* queue
     refresh_queue: Arc<Mutex<RefreshQueue>>,
@@ -161,16 +324,35 @@ pub struct Identity {
 }
 
 impl Identity {
-    pub async fn new(cache_dir: impl AsRef<Path>) -> Result<Self, IdentityError> {
+    pub async fn new(
+        cache_dir: impl AsRef<Path>,
+        allow_local_did_web: bool,
+    ) -> Result<Self, IdentityError> {
         let http_client = Arc::new(DefaultHttpClient::default());
         let handle_resolver = AtprotoHandleResolver::new(AtprotoHandleResolverConfig {
             dns_txt_resolver: HickoryDnsTxtResolver::new().unwrap(),
             http_client: http_client.clone(),
         });
-        let did_resolver = CommonDidResolver::new(CommonDidResolverConfig {
-            plc_directory_url: DEFAULT_PLC_DIRECTORY_URL.to_string(),
-            http_client: http_client.clone(),
-        });
+        let mut web_client = reqwest::Client::builder()
+            .user_agent(format!(
+                "microcosm slingshot v{} (dev: @bad-example.com)",
+                env!("CARGO_PKG_VERSION")
+            ))
+            .timeout(DID_WEB_TIMEOUT);
+        if !allow_local_did_web {
+            // redirects could point straight at an ip literal, skipping our dns
+            web_client = web_client
+                .dns_resolver(Arc::new(PublicOnlyResolver))
+                .redirect(reqwest::redirect::Policy::none());
+        }
+        let did_resolver = DidResolver {
+            plc: CommonDidResolver::new(CommonDidResolverConfig {
+                plc_directory_url: DEFAULT_PLC_DIRECTORY_URL.to_string(),
+                http_client: http_client.clone(),
+            }),
+            web_client: web_client.build().unwrap(),
+            allow_local_did_web,
+        };
 
         let cache = HybridCacheBuilder::new()
             .with_name("identity")
@@ -240,10 +422,10 @@ impl Identity {
                         Err(atrium_identity::Error::NotFound) => {
                             Ok(IdentityVal(UtcDateTime::now(), IdentityData::NotFound))
                         }
-                        Err(other) => Err(foyer::Error::Other(Box::new({
+                        Err(other) => {
                             log::debug!("other error resolving handle: {other:?}");
-                            IdentityError::ResolutionFailed(other)
-                        }))),
+                            Ok(IdentityVal(UtcDateTime::now(), IdentityData::Failed(1)))
+                        }
                     }
                 }
             })
@@ -256,6 +438,12 @@ impl Identity {
                 log::error!("identity value mixup: got a doc from a handle key (should be a did)");
                 Err(IdentityError::IdentityValTypeMixup(handle.to_string()))
             }
+            IdentityData::Failed(failures) => {
+                if (now - *last_fetch) >= failure_backoff(*failures) {
+                    self.queue_refresh(key).await;
+                }
+                Err(IdentityError::BackingOff(handle.to_string(), *failures))
+            }
             IdentityData::NotFound => {
                 if (now - *last_fetch) >= MIN_NOT_FOUND_TTL {
                     self.queue_refresh(key).await;
@@ -284,7 +472,7 @@ impl Identity {
                 let resolver = self.did_resolver.clone();
                 || async move {
                     match resolver.resolve(&did).await {
-                        Ok(did_doc) => {
+                        Ok(Some(did_doc)) => {
                             // TODO: fix in atrium: should verify id is did
                             if did_doc.id != did.to_string() {
                                 return Err(foyer::Error::other(Box::new(
@@ -298,12 +486,16 @@ impl Identity {
                             })?;
                             Ok(IdentityVal(UtcDateTime::now(), IdentityData::Doc(mini_doc)))
                         }
-                        Err(atrium_identity::Error::NotFound) => {
+                        Ok(None) => Ok(IdentityVal(UtcDateTime::now(), IdentityData::NotFound)),
+                        Err(IdentityError::BadDid(reason)) => {
+                            // malformed rather than unreachable: retrying won't help
+                            log::debug!("unresolvable did {did:?}: {reason}");
                             Ok(IdentityVal(UtcDateTime::now(), IdentityData::NotFound))
                         }
-                        Err(other) => Err(foyer::Error::Other(Box::new(
-                            IdentityError::ResolutionFailed(other),
-                        ))),
+                        Err(other) => {
+                            log::debug!("error resolving did: {other:?}");
+                            Ok(IdentityVal(UtcDateTime::now(), IdentityData::Failed(1)))
+                        }
                     }
                 }
             })
@@ -316,6 +508,12 @@ impl Identity {
                 log::error!("identity value mixup: got a did from a did key (should be a doc)");
                 Err(IdentityError::IdentityValTypeMixup(did.to_string()))
             }
+            IdentityData::Failed(failures) => {
+                if (now - *last_fetch) >= failure_backoff(*failures) {
+                    self.queue_refresh(key).await;
+                }
+                Err(IdentityError::BackingOff(did.to_string(), *failures))
+            }
             IdentityData::NotFound => {
                 if (now - *last_fetch) >= MIN_NOT_FOUND_TTL {
                     self.queue_refresh(key).await;
@@ -408,6 +606,31 @@ impl Identity {
         }
     }
 
+    /// a refresh failed: if all we had cached was earlier failures, back off for longer
+    ///
+    /// anything good that was cached is left alone: stale beats nothing.
+    async fn record_refresh_failure(&self, key: &IdentityKey) {
+        let entry = match self.cache.get(key).await {
+            Ok(Some(entry)) => entry,
+            Ok(None) => return,
+            Err(e) => {
+                log::warn!("identity refresh: failed to check cache after a failure: {e}");
+                return;
+            }
+        };
+        if let IdentityVal(_, IdentityData::Failed(failures)) = entry.value() {
+            let failures = failures.saturating_add(1);
+            log::debug!(
+                "identity refresh: {key:?} has failed {failures} times, backing off {:?}",
+                failure_backoff(failures)
+            );
+            self.cache.insert(
+                key.clone(),
+                IdentityVal(UtcDateTime::now(), IdentityData::Failed(failures)),
+            );
+        }
+    }
+
     /// run the refresh queue consumer
     pub async fn run_refresher(&self, shutdown: CancellationToken) -> Result<(), IdentityError> {
         let _guard = self
@@ -448,6 +671,7 @@ impl Identity {
                             log::warn!(
                                 "failed to refresh handle: {err:?}. leaving stale (should we eventually do something?)"
                             );
+                            self.record_refresh_failure(&task_key).await;
                         }
                     }
                     self.complete_refresh(&task_key).await?; // failures are bugs, so break loop
@@ -456,7 +680,7 @@ impl Identity {
                     log::trace!("refreshing did doc: {did:?}");
 
                     match self.did_resolver.resolve(did).await {
-                        Ok(did_doc) => {
+                        Ok(Some(did_doc)) => {
                             // TODO: fix in atrium: should verify id is did
                             if did_doc.id != did.to_string() {
                                 log::warn!(
@@ -478,7 +702,7 @@ impl Identity {
                                 IdentityVal(UtcDateTime::now(), IdentityData::Doc(mini_doc)),
                             );
                         }
-                        Err(atrium_identity::Error::NotFound) => {
+                        Ok(None) | Err(IdentityError::BadDid(_)) => {
                             self.cache.insert(
                                 task_key.clone(),
                                 IdentityVal(UtcDateTime::now(), IdentityData::NotFound),
@@ -488,6 +712,7 @@ impl Identity {
                             log::warn!(
                                 "failed to refresh did doc: {err:?}. leaving stale (should we eventually do something?)"
                             );
+                            self.record_refresh_failure(&task_key).await;
                         }
                     }
 
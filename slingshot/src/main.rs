@@ -49,6 +49,12 @@ struct Args {
     /// an web address to send healtcheck pings to every ~51s or so
     #[arg(long)]
     healthcheck: Option<String>,
+    /// development only: resolve did:web:localhost%3A<port> dids over plain http
+    ///
+    /// off by default. without it, did:webs pointing at loopback or private
+    /// addresses are refused.
+    #[arg(long, action)]
+    dev_allow_local_did_web: bool,
 }
 
 #[tokio::main]
@@ -89,7 +95,7 @@ async fn main() -> Result<(), String> {
     let mut tasks: tokio::task::JoinSet<Result<(), MainTaskError>> = tokio::task::JoinSet::new();
 
     log::info!("starting identity service...");
-    let identity = Identity::new(cache_dir.join("./identity"))
+    let identity = Identity::new(cache_dir.join("./identity"), args.dev_allow_local_did_web)
         .await
         .map_err(|e| format!("identity setup failed: {e:?}"))?;
     log::info!("identity service ready.");
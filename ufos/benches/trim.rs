@@ -0,0 +1,85 @@
+//! Trimming a collection that's far over its limit, with the batched range
+//! removal against the old per-key trim
+//!
+//! run with `cargo bench --features bench --bench trim`
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use jetstream::events::{CommitEvent, CommitOp, Cursor};
+use jetstream::exports::{Did, Nsid, RecordKey};
+use serde_json::value::RawValue;
+use tempfile::TempDir;
+use ufos::storage::{StorageWhatever, StoreWriter};
+use ufos::storage_fjall::{FjallConfig, FjallStorage, FjallWriter};
+use ufos::{EventBatch, UFOsCommit};
+
+const BATCH: usize = 1024;
+const RECORDS: usize = 200 * BATCH;
+const KEEP: usize = 1_000;
+
+fn filled_collection() -> (TempDir, FjallWriter, Nsid) {
+    let dir = tempfile::tempdir().unwrap();
+    let (_, mut write, _, _) = FjallStorage::init(
+        dir.path(),
+        "offline bench (no real jetstream endpoint)".to_string(),
+        false,
+        FjallConfig {
+            ephemeral: true,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    let collection = Nsid::new("a.b.c".to_string()).unwrap();
+    let cid = "bafyreidofvwoqvd2cnzbun6dkzgfucxh57tirf3ohhde7lsvh4fu3jehgy"
+        .parse()
+        .unwrap();
+    for b in 0..(RECORDS / BATCH) {
+        let mut batch = EventBatch::<BATCH>::default();
+        let commits = batch.commits_by_nsid.entry(collection.clone()).or_default();
+        for i in (b * BATCH)..((b + 1) * BATCH) {
+            let event = CommitEvent {
+                collection: collection.clone(),
+                rkey: RecordKey::new(format!("rkey-{i:08}")).unwrap(),
+                rev: "asdf".to_string(),
+                operation: CommitOp::Create,
+                record: Some(RawValue::from_string(format!(r#"{{"n": {i}}}"#)).unwrap()),
+                cid: Some(cid),
+            };
+            let did = Did::new(format!("did:plc:bench{:019}", i % 5_000)).unwrap();
+            let cursor = Cursor::from_raw_u64(1_000_000 + i as u64);
+            let (commit, _) = UFOsCommit::from_commit_info(event, did, cursor).unwrap();
+            commits.truncating_insert(commit, &[0u8; 16]).unwrap();
+        }
+        write.insert_batch(batch).unwrap();
+    }
+    (dir, write, collection)
+}
+
+fn trim(c: &mut Criterion) {
+    let mut group = c.benchmark_group("trim 200k records down to 1k");
+    group.sample_size(10);
+    group.bench_function("per key", |b| {
+        b.iter_batched(
+            filled_collection,
+            |(_dir, mut write, collection)| {
+                let (_, deleted) = write.trim_collection_per_key(&collection, KEEP).unwrap();
+                assert_eq!(deleted, RECORDS - KEEP);
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.bench_function("range below cutoff", |b| {
+        b.iter_batched(
+            filled_collection,
+            |(_dir, mut write, collection)| {
+                let (_, deleted, _) = write.trim_collection(&collection, KEEP, false).unwrap();
+                assert_eq!(deleted, RECORDS - KEEP);
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, trim);
+criterion_main!(benches);
@@ -0,0 +1,118 @@
+use crate::encoding::{bincode_conf, CoreEncodingError, CoreResult};
+use bincode::{Decode, Encode};
+use microcosm_estimates::DidsSketch;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Decode, Encode)]
+pub struct CommitCounts {
+    pub creates: u64,
+    pub updates: u64,
+    pub deletes: u64,
+}
+impl CommitCounts {
+    pub fn merge(&mut self, other: &Self) {
+        self.creates += other.creates;
+        self.updates += other.updates;
+        self.deletes += other.deletes;
+    }
+}
+
+/// A decoded rollup value: commit counts plus the estimated-dids sketch
+#[derive(Debug, Default, PartialEq)]
+pub struct RollupCounts {
+    pub counts: CommitCounts,
+    pub dids: DidsSketch,
+}
+impl RollupCounts {
+    /// Decode the value bytes of an hourly, weekly, or all-time rollup
+    pub fn from_bytes(bytes: &[u8]) -> CoreResult<Self> {
+        let (counts, n) = bincode::decode_from_slice(bytes, bincode_conf())?;
+        let rest = &bytes[n..];
+        if rest.is_empty() {
+            return Err(CoreEncodingError::DecodeNotEnoughBytes);
+        }
+        let (dids, m) = bincode::serde::decode_from_slice(rest, bincode_conf())?;
+        if m < rest.len() {
+            return Err(CoreEncodingError::DecodeTooManyBytes(rest.len() - m));
+        }
+        Ok(Self { counts, dids })
+    }
+    pub fn merge(&mut self, other: &Self) {
+        self.counts.merge(&other.counts);
+        self.dids.merge(&other.dids);
+    }
+    pub fn dids_estimate(&self) -> u64 {
+        microcosm_estimates::estimate(&self.dids)
+    }
+}
+
+/// Merge rollups across buckets into one total per collection
+pub fn merge_by_collection<'a>(
+    rollups: impl IntoIterator<Item = (&'a str, &'a RollupCounts)>,
+) -> BTreeMap<String, RollupCounts> {
+    let mut merged: BTreeMap<String, RollupCounts> = BTreeMap::new();
+    for (nsid, counts) in rollups {
+        merged.entry(nsid.to_string()).or_default().merge(counts);
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cardinality_estimator_safe::Element;
+    use sha2::Sha256;
+
+    fn did(d: &str) -> Element<14> {
+        Element::from_digest_oneshot::<Sha256>(d.as_bytes())
+    }
+
+    fn encode(counts: &RollupCounts) -> Vec<u8> {
+        let mut bytes = bincode::encode_to_vec(counts.counts, bincode_conf()).unwrap();
+        bytes.extend(bincode::serde::encode_to_vec(&counts.dids, bincode_conf()).unwrap());
+        bytes
+    }
+
+    #[test]
+    fn test_rollup_counts_roundtrip() {
+        let mut dids = DidsSketch::default();
+        dids.insert(did("did:plc:a"));
+        dids.insert(did("did:plc:b"));
+        let original = RollupCounts {
+            counts: CommitCounts {
+                creates: 3,
+                updates: 2,
+                deletes: 1,
+            },
+            dids,
+        };
+        let decoded = RollupCounts::from_bytes(&encode(&original)).unwrap();
+        assert_eq!(decoded, original);
+        assert_eq!(decoded.dids_estimate(), 2);
+
+        let mut bytes = encode(&original);
+        bytes.push(0xFF);
+        assert!(matches!(
+            RollupCounts::from_bytes(&bytes),
+            Err(CoreEncodingError::DecodeTooManyBytes(1))
+        ));
+    }
+
+    #[test]
+    fn test_merge_by_collection() {
+        let mut dids = DidsSketch::default();
+        dids.insert(did("did:plc:a"));
+        let one = RollupCounts {
+            counts: CommitCounts {
+                creates: 1,
+                ..Default::default()
+            },
+            dids,
+        };
+        let merged = merge_by_collection([("a.b.c", &one), ("a.b.c", &one), ("d.e.f", &one)]);
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged["a.b.c"].counts.creates, 2);
+        assert_eq!(merged["a.b.c"].dids_estimate(), 1);
+        assert_eq!(merged["d.e.f"].counts.creates, 1);
+    }
+}
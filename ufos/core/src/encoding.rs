@@ -0,0 +1,70 @@
+use bincode::config::{standard, Config};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum CoreEncodingError {
+    #[error("failed to bincode-decode: {0}")]
+    BincodeDecodeFailed(#[from] bincode::error::DecodeError),
+    #[error("could not convert from utf8: {0}")]
+    NotUtf8(#[from] std::str::Utf8Error),
+    #[error("string was not terminated with null byte")]
+    UnterminatedString,
+    #[error("decode ran out of bytes")]
+    DecodeNotEnoughBytes,
+    #[error("unexpected extra bytes ({0} bytes) left after decoding")]
+    DecodeTooManyBytes(usize),
+}
+
+pub type CoreResult<T> = Result<T, CoreEncodingError>;
+
+/// The bincode config for every bincoded key and value in storage
+pub fn bincode_conf() -> impl Config {
+    standard()
+        .with_big_endian()
+        .with_fixed_int_encoding()
+        .with_limit::<{ 2_usize.pow(20) }>() // 1MB
+}
+
+/// Decode a null-terminated string
+///
+/// Returns the string and the number of bytes consumed, including the null.
+pub fn decode_null_terminated(bytes: &[u8]) -> CoreResult<(&str, usize)> {
+    let Some(i) = bytes.iter().position(|b| *b == 0x00) else {
+        return Err(CoreEncodingError::UnterminatedString);
+    };
+    let s = std::str::from_utf8(&bytes[..i])?;
+    Ok((s, i + 1)) // +1 for the null byte
+}
+
+/// Decode a big-endian u64 (cursors and truncated cursors in keys)
+pub fn decode_u64_be(bytes: &[u8]) -> CoreResult<(u64, usize)> {
+    let Some(bytes8) = bytes.get(..8) else {
+        return Err(CoreEncodingError::DecodeNotEnoughBytes);
+    };
+    // unwrap: we just took exactly 8 bytes
+    Ok((u64::from_be_bytes(bytes8.try_into().unwrap()), 8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_null_terminated() {
+        assert_eq!(decode_null_terminated(b"abc\0def").unwrap(), ("abc", 4));
+        assert_eq!(decode_null_terminated(b"\0").unwrap(), ("", 1));
+        assert!(matches!(
+            decode_null_terminated(b"abc"),
+            Err(CoreEncodingError::UnterminatedString)
+        ));
+    }
+
+    #[test]
+    fn test_u64_be() {
+        assert_eq!(
+            decode_u64_be(&[0, 0, 0, 0, 0, 0, 1, 2, 3]).unwrap(),
+            (258, 8)
+        );
+        assert!(decode_u64_be(&[0, 0, 1]).is_err());
+    }
+}
@@ -0,0 +1,93 @@
+use crate::encoding::{decode_null_terminated, decode_u64_be, CoreEncodingError, CoreResult};
+
+pub const HOURLY_ROLLUP_PREFIX: &str = "hourly_counts";
+pub const WEEKLY_ROLLUP_PREFIX: &str = "weekly_counts";
+pub const ALL_TIME_ROLLUP_PREFIX: &str = "ever_counts";
+
+/// A key from the rollups partition that holds counts
+///
+/// Bucket times are the truncated cursor, in microseconds since the epoch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RollupKey {
+    Hourly { hour: u64, nsid: String },
+    Weekly { week: u64, nsid: String },
+    AllTime { nsid: String },
+}
+impl RollupKey {
+    /// Decode a rollup key
+    ///
+    /// Returns `None` for other keys in the partition, like the ranking indexes.
+    pub fn from_bytes(bytes: &[u8]) -> CoreResult<Option<Self>> {
+        let (prefix, mut eaten) = decode_null_terminated(bytes)?;
+        let me = match prefix {
+            HOURLY_ROLLUP_PREFIX => {
+                let (hour, n) = decode_u64_be(&bytes[eaten..])?;
+                eaten += n;
+                let (nsid, n) = decode_null_terminated(&bytes[eaten..])?;
+                eaten += n;
+                Self::Hourly {
+                    hour,
+                    nsid: nsid.to_string(),
+                }
+            }
+            WEEKLY_ROLLUP_PREFIX => {
+                let (week, n) = decode_u64_be(&bytes[eaten..])?;
+                eaten += n;
+                let (nsid, n) = decode_null_terminated(&bytes[eaten..])?;
+                eaten += n;
+                Self::Weekly {
+                    week,
+                    nsid: nsid.to_string(),
+                }
+            }
+            ALL_TIME_ROLLUP_PREFIX => {
+                let (nsid, n) = decode_null_terminated(&bytes[eaten..])?;
+                eaten += n;
+                Self::AllTime {
+                    nsid: nsid.to_string(),
+                }
+            }
+            _ => return Ok(None),
+        };
+        if eaten < bytes.len() {
+            return Err(CoreEncodingError::DecodeTooManyBytes(bytes.len() - eaten));
+        }
+        Ok(Some(me))
+    }
+    pub fn nsid(&self) -> &str {
+        match self {
+            Self::Hourly { nsid, .. } | Self::Weekly { nsid, .. } | Self::AllTime { nsid } => nsid,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_rollup_keys() {
+        let mut hourly = b"hourly_counts\0".to_vec();
+        hourly.extend(3_600_000_000_u64.to_be_bytes());
+        hourly.extend(b"a.b.c\0");
+        assert_eq!(
+            RollupKey::from_bytes(&hourly).unwrap(),
+            Some(RollupKey::Hourly {
+                hour: 3_600_000_000,
+                nsid: "a.b.c".to_string()
+            })
+        );
+
+        let all_time = b"ever_counts\0a.b.c\0";
+        let key = RollupKey::from_bytes(all_time).unwrap().unwrap();
+        assert_eq!(key.nsid(), "a.b.c");
+
+        assert_eq!(
+            RollupKey::from_bytes(b"hourly_rank_records\0").unwrap(),
+            None
+        );
+
+        assert!(RollupKey::from_bytes(b"ever_counts\0a.b.c").is_err());
+        assert!(RollupKey::from_bytes(b"ever_counts\0a.b.c\0extra").is_err());
+    }
+}
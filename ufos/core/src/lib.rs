@@ -0,0 +1,14 @@
+//! Read-only pieces of the UFOs storage format
+//!
+//! There's no storage engine, async runtime, or networking in here, so this
+//! builds for `wasm32-unknown-unknown`: browser tools can decode exported
+//! rollup keys and values and merge counts client-side, with the same code
+//! that the server uses.
+
+pub mod counts;
+pub mod encoding;
+pub mod keys;
+
+pub use counts::{merge_by_collection, CommitCounts, RollupCounts};
+pub use encoding::{CoreEncodingError, CoreResult};
+pub use keys::RollupKey;
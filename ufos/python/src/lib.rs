@@ -0,0 +1,261 @@
+//! Python bindings for UFOs
+//!
+//! A thin wrapper over [`ufos::embed::Ufos`]: each `Ufos` object owns a tokio
+//! runtime, ingests in the background once started, and answers read queries
+//! straight from storage. See the README for usage.
+
+use jetstream::events::Cursor;
+use jetstream::exports::Nsid;
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use tokio::runtime::Runtime;
+use tokio::task::JoinHandle;
+use ufos::embed;
+use ufos::storage::{QueryResult, ScanBudget, StoreReader};
+use ufos::storage_fjall::FjallReader;
+use ufos::store_types::{HourTruncatedCursor, HourWindow};
+use ufos::{JustCount, OrderCollectionsBy};
+
+/// queries from python run on the caller's own data, so scans aren't capped
+const NO_SCAN_LIMIT: ScanBudget = ScanBudget::new(usize::MAX);
+
+fn runtime_err(e: impl std::fmt::Display) -> PyErr {
+    PyRuntimeError::new_err(e.to_string())
+}
+
+/// serialize through serde_json first so that raw record json comes out as real objects
+fn to_py(py: Python<'_>, v: &impl Serialize) -> PyResult<PyObject> {
+    let value = serde_json::to_value(v).map_err(runtime_err)?;
+    Ok(pythonize::pythonize(py, &value)
+        .map_err(runtime_err)?
+        .unbind())
+}
+
+fn nsid(s: &str) -> PyResult<Nsid> {
+    Nsid::new(s.to_string()).map_err(|e| PyValueError::new_err(format!("bad nsid {s:?}: {e}")))
+}
+
+fn hour(micros: u64) -> HourTruncatedCursor {
+    HourTruncatedCursor::truncate_cursor(Cursor::from_raw_u64(micros))
+}
+
+fn order(order: Option<&str>, cursor: Option<Vec<u8>>) -> PyResult<OrderCollectionsBy> {
+    match order {
+        None | Some("lexi") => Ok(OrderCollectionsBy::Lexi { cursor }),
+        Some("records-created") => Ok(OrderCollectionsBy::RecordsCreated { cursor }),
+        Some("dids-estimate") => Ok(OrderCollectionsBy::DidsEstimate { cursor }),
+        Some(other) => Err(PyValueError::new_err(format!(
+            "unknown order {other:?}: expected lexi, records-created, or dids-estimate"
+        ))),
+    }
+}
+
+/// An embedded UFOs instance
+#[pyclass(name = "Ufos")]
+struct PyUfos {
+    runtime: Runtime,
+    reader: FjallReader,
+    pending: Option<embed::Ufos>,
+    running: Option<JoinHandle<anyhow::Result<()>>>,
+}
+
+impl PyUfos {
+    fn build(builder: embed::UfosBuilder) -> PyResult<Self> {
+        let runtime = Runtime::new().map_err(runtime_err)?;
+        let ufos = runtime.block_on(builder.build()).map_err(runtime_err)?;
+        Ok(Self {
+            runtime,
+            reader: ufos.reader(),
+            pending: Some(ufos),
+            running: None,
+        })
+    }
+
+    /// run a query on the runtime, without holding the GIL
+    fn query<T, Fut>(
+        &self,
+        py: Python<'_>,
+        f: impl FnOnce(FjallReader) -> Fut + Send,
+    ) -> PyResult<T>
+    where
+        T: Send,
+        Fut: Future<Output = QueryResult<T>>,
+    {
+        let handle = self.runtime.handle().clone();
+        let reader = self.reader.clone();
+        py.allow_threads(move || handle.block_on(f(reader)))
+            .map_err(runtime_err)
+    }
+}
+
+#[pymethods]
+impl PyUfos {
+    /// Replay a jsonl file of jetstream events into the data directory
+    #[staticmethod]
+    #[pyo3(signature = (data, fixture, max_batched_collections=None))]
+    fn fixture(
+        data: &str,
+        fixture: &str,
+        max_batched_collections: Option<usize>,
+    ) -> PyResult<Self> {
+        let mut builder = embed::Ufos::builder(data).fixture(fixture);
+        if let Some(max) = max_batched_collections {
+            builder = builder.max_batched_collections(max);
+        }
+        Self::build(builder)
+    }
+
+    /// Consume a jetstream server into the data directory
+    #[staticmethod]
+    #[pyo3(signature = (data, endpoint, force_endpoint=false))]
+    fn jetstream(data: &str, endpoint: &str, force_endpoint: bool) -> PyResult<Self> {
+        let mut builder = embed::Ufos::builder(data).jetstream(endpoint);
+        if force_endpoint {
+            builder = builder.force_endpoint();
+        }
+        Self::build(builder)
+    }
+
+    /// Start ingesting and rolling up in the background
+    fn start(&mut self) -> PyResult<()> {
+        let ufos = self
+            .pending
+            .take()
+            .ok_or_else(|| PyRuntimeError::new_err("already started"))?;
+        self.running = Some(self.runtime.spawn(ufos.run()));
+        Ok(())
+    }
+
+    /// Stop background ingestion. Queries keep working.
+    fn stop(&mut self) {
+        if let Some(running) = self.running.take() {
+            running.abort();
+        }
+    }
+
+    /// Whether background ingestion is going
+    fn is_running(&self) -> bool {
+        self.running.as_ref().is_some_and(|r| !r.is_finished())
+    }
+
+    fn consumer_info(&self, py: Python<'_>) -> PyResult<PyObject> {
+        let info = self.query(py, |r| async move { r.get_consumer_info().await })?;
+        to_py(py, &info)
+    }
+
+    /// Collections with counts. Returns `(collections, next_cursor)`.
+    ///
+    /// Pass `next_cursor` back with the same `order` to get the next page.
+    #[pyo3(signature = (limit=100, order=None, cursor=None, since=None, until=None))]
+    fn collections(
+        &self,
+        py: Python<'_>,
+        limit: usize,
+        order: Option<&str>,
+        cursor: Option<Vec<u8>>,
+        since: Option<u64>,
+        until: Option<u64>,
+    ) -> PyResult<(PyObject, Option<Py<PyBytes>>)> {
+        let order = self::order(order, cursor)?;
+        let window = HourWindow::new(since.map(hour), until.map(hour))
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        let (collections, next) = self.query(py, |r| async move {
+            r.get_collections(limit, order, window, NO_SCAN_LIMIT).await
+        })?;
+        let next = next.map(|c| PyBytes::new(py, &c).unbind());
+        Ok((to_py(py, &collections)?, next))
+    }
+
+    /// Total counts for one collection
+    #[pyo3(signature = (collection, since=0, until=None))]
+    fn counts(
+        &self,
+        py: Python<'_>,
+        collection: &str,
+        since: u64,
+        until: Option<u64>,
+    ) -> PyResult<PyObject> {
+        let collection = nsid(collection)?;
+        let counts = self.query(py, |r| async move {
+            r.get_collection_counts(&collection, hour(since), until.map(hour))
+                .await
+        })?;
+        to_py(py, &counts)
+    }
+
+    /// Counts per time bucket. Returns `(bucket_starts, {collection: [counts]})`.
+    ///
+    /// `step` is the bucket size in seconds, a multiple of one hour.
+    #[pyo3(signature = (collections, since, until=None, step=3600))]
+    fn timeseries(
+        &self,
+        py: Python<'_>,
+        collections: Vec<String>,
+        since: u64,
+        until: Option<u64>,
+        step: u64,
+    ) -> PyResult<(Vec<u64>, PyObject)> {
+        let collections = collections
+            .iter()
+            .map(|c| nsid(c))
+            .collect::<PyResult<Vec<_>>>()?;
+        let (buckets, series) = self.query(py, |r| async move {
+            r.get_timeseries(collections, hour(since), until.map(hour), step)
+                .await
+        })?;
+        let buckets = buckets
+            .into_iter()
+            .map(|b| Cursor::from(b).to_raw_u64())
+            .collect();
+        let series: HashMap<String, Vec<JustCount>> = series
+            .into_iter()
+            .map(|(nsid, counts)| (nsid.to_string(), counts.iter().map(Into::into).collect()))
+            .collect();
+        Ok((buckets, to_py(py, &series)?))
+    }
+
+    /// Recent records from some collections, newest first
+    #[pyo3(signature = (collections, limit=42, expand=false))]
+    fn records(
+        &self,
+        py: Python<'_>,
+        collections: Vec<String>,
+        limit: usize,
+        expand: bool,
+    ) -> PyResult<PyObject> {
+        let collections = collections
+            .iter()
+            .map(|c| nsid(c))
+            .collect::<PyResult<HashSet<_>>>()?;
+        let (records, _, _) = self.query(py, |r| async move {
+            r.get_records_by_collections(collections, limit, expand, NO_SCAN_LIMIT)
+                .await
+        })?;
+        to_py(py, &records)
+    }
+
+    /// Collections matching all of the search terms
+    fn search(&self, py: Python<'_>, terms: Vec<String>) -> PyResult<PyObject> {
+        let (found, _) = self.query(py, |r| async move {
+            r.search_collections(terms, NO_SCAN_LIMIT).await
+        })?;
+        to_py(py, &found)
+    }
+}
+
+impl Drop for PyUfos {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[pymodule]
+#[pyo3(name = "ufos")]
+fn ufos_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyUfos>()?;
+    Ok(())
+}
@@ -0,0 +1,292 @@
+//! Optional redis cache for the hottest read endpoints
+//!
+//! Top-collections lists and per-collection counts are cached with a TTL,
+//! and dropped early when their collections get new counts: the background
+//! rollup reports dirty NSIDs, which are published on a redis channel so that
+//! every ufos instance sharing the cache drops the affected entries.
+//!
+//! The cache is best-effort. Redis errors are logged and counted, and the
+//! request falls through to storage.
+//!
+//! Needs the `redis` feature (on by default). Without it, [`RedisCache`] can't
+//! be connected, so the server never has one.
+
+use crate::Nsid;
+#[cfg(feature = "redis")]
+use futures_util::StreamExt;
+#[cfg(feature = "redis")]
+use metrics::{counter, describe_counter, Unit};
+#[cfg(feature = "redis")]
+use redis::aio::ConnectionManager;
+#[cfg(feature = "redis")]
+use redis::AsyncCommands;
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::HashSet;
+#[cfg(feature = "redis")]
+use std::pin::pin;
+use std::time::Duration;
+use tokio::sync::mpsc::{channel, error::TrySendError, Receiver, Sender};
+
+/// Batches of dirty NSIDs waiting to be published, before new ones get dropped
+const QUEUE_SIZE: usize = 64;
+
+/// What a cache entry depends on
+#[derive(Debug, Clone, PartialEq)]
+pub enum CacheKey {
+    /// A sorted top-collections list, dropped whenever any collection changes
+    TopCollections(String),
+    /// Counts for one collection, dropped when that collection changes
+    Collection(Nsid, String),
+}
+
+#[cfg_attr(not(feature = "redis"), allow(dead_code))]
+impl CacheKey {
+    fn index(&self, prefix: &str) -> String {
+        match self {
+            Self::TopCollections(_) => format!("{prefix}:keys:top"),
+            Self::Collection(nsid, _) => format!("{prefix}:keys:{nsid}"),
+        }
+    }
+    fn key(&self, prefix: &str) -> String {
+        match self {
+            Self::TopCollections(q) => format!("{prefix}:top:{q}"),
+            Self::Collection(nsid, q) => format!("{prefix}:nsid:{nsid}:{q}"),
+        }
+    }
+}
+
+/// A shared redis cache. Cheap to clone.
+#[cfg(feature = "redis")]
+#[derive(Clone)]
+pub struct RedisCache {
+    client: redis::Client,
+    conn: ConnectionManager,
+    prefix: String,
+    ttl: Duration,
+}
+
+#[cfg(feature = "redis")]
+impl std::fmt::Debug for RedisCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisCache")
+            .field("prefix", &self.prefix)
+            .field("ttl", &self.ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "redis")]
+impl RedisCache {
+    /// Connect to redis at `url`, with all keys starting with `prefix`
+    pub async fn connect(url: &str, prefix: &str, ttl: Duration) -> redis::RedisResult<Self> {
+        describe_counter!(
+            "cache_lookups",
+            Unit::Count,
+            "redis cache lookups, by result (hit, miss, error)"
+        );
+        describe_counter!(
+            "cache_invalidated",
+            Unit::Count,
+            "redis cache entries dropped because their collections changed"
+        );
+        let client = redis::Client::open(url)?;
+        let conn = client.get_connection_manager().await?;
+        Ok(Self {
+            client,
+            conn,
+            prefix: prefix.to_string(),
+            ttl,
+        })
+    }
+
+    fn channel(&self) -> String {
+        format!("{}:dirty", self.prefix)
+    }
+
+    /// Get a cached value, if there is one
+    pub async fn get<T: DeserializeOwned>(&self, key: &CacheKey) -> Option<T> {
+        let mut conn = self.conn.clone();
+        let found: redis::RedisResult<Option<String>> = conn.get(key.key(&self.prefix)).await;
+        let result = match found {
+            Ok(Some(json)) => match serde_json::from_str(&json) {
+                Ok(value) => Ok(Some(value)),
+                Err(e) => Err(e.to_string()),
+            },
+            Ok(None) => Ok(None),
+            Err(e) => Err(e.to_string()),
+        };
+        match result {
+            Ok(Some(value)) => {
+                counter!("cache_lookups", "result" => "hit").increment(1);
+                Some(value)
+            }
+            Ok(None) => {
+                counter!("cache_lookups", "result" => "miss").increment(1);
+                None
+            }
+            Err(e) => {
+                log::warn!("redis cache lookup failed: {e}");
+                counter!("cache_lookups", "result" => "error").increment(1);
+                None
+            }
+        }
+    }
+
+    /// Cache a value until its TTL is up or its collections change
+    pub async fn put<T: Serialize>(&self, key: &CacheKey, value: &T) {
+        let json = match serde_json::to_string(value) {
+            Ok(json) => json,
+            Err(e) => {
+                log::warn!("failed to serialize value for the redis cache: {e}");
+                return;
+            }
+        };
+        let (key_name, index) = (key.key(&self.prefix), key.index(&self.prefix));
+        let ttl = self.ttl.as_secs().max(1);
+        let mut conn = self.conn.clone();
+        if let Err(e) = redis::pipe()
+            .set_ex(&key_name, json, ttl)
+            .ignore()
+            .sadd(&index, &key_name)
+            .ignore()
+            .expire(&index, ttl as i64)
+            .ignore()
+            .query_async::<()>(&mut conn)
+            .await
+        {
+            log::warn!("redis cache put failed: {e}");
+        }
+    }
+
+    /// Drop the cached entries for some changed collections
+    ///
+    /// Also drops all top-collections lists, since any change can reorder them.
+    async fn invalidate(&self, nsids: &[&str]) -> redis::RedisResult<usize> {
+        let mut conn = self.conn.clone();
+        let mut indexes = vec![format!("{}:keys:top", self.prefix)];
+        indexes.extend(nsids.iter().map(|n| format!("{}:keys:{n}", self.prefix)));
+        let mut dropped = 0;
+        for index in indexes {
+            let keys: Vec<String> = conn.smembers(&index).await?;
+            if !keys.is_empty() {
+                dropped += keys.len();
+                conn.del::<_, ()>(&keys).await?;
+            }
+            conn.del::<_, ()>(&index).await?;
+        }
+        Ok(dropped)
+    }
+
+    /// Publish dirty NSIDs from this instance's rollups, and drop cache
+    /// entries for dirty NSIDs from any instance
+    ///
+    /// Only ends if the subscription does.
+    pub async fn run_invalidation(self, mut dirty: Option<DirtyReceiver>) -> anyhow::Result<()> {
+        let mut pubsub = self.client.get_async_pubsub().await?;
+        pubsub.subscribe(self.channel()).await?;
+        let mut messages = pin!(pubsub.on_message());
+        let mut publisher = self.conn.clone();
+        loop {
+            tokio::select! {
+                batch = async { dirty.as_mut()?.0.recv().await }, if dirty.is_some() => {
+                    let Some(batch) = batch else {
+                        // the writer is gone, but other instances might still publish
+                        dirty = None;
+                        continue;
+                    };
+                    let message = batch.iter().map(Nsid::as_str).collect::<Vec<_>>().join("\n");
+                    publisher.publish::<_, _, ()>(self.channel(), message).await?;
+                }
+                message = messages.next() => {
+                    let Some(message) = message else {
+                        anyhow::bail!("redis invalidation subscription ended");
+                    };
+                    let payload: String = message.get_payload()?;
+                    let nsids: Vec<&str> = payload.lines().filter(|l| !l.is_empty()).collect();
+                    match self.invalidate(&nsids).await {
+                        Ok(n) => counter!("cache_invalidated").increment(n as u64),
+                        Err(e) => log::warn!("failed to invalidate redis cache entries: {e}"),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Stand-in for builds without the `redis` feature: it can't be connected, so
+/// there is never one to use
+#[cfg(not(feature = "redis"))]
+#[derive(Debug, Clone)]
+pub enum RedisCache {}
+
+#[cfg(not(feature = "redis"))]
+impl RedisCache {
+    pub async fn connect(_url: &str, _prefix: &str, _ttl: Duration) -> anyhow::Result<Self> {
+        anyhow::bail!("caching in redis needs ufos built with the \"redis\" feature")
+    }
+    pub async fn get<T: DeserializeOwned>(&self, _key: &CacheKey) -> Option<T> {
+        match *self {}
+    }
+    pub async fn put<T: Serialize>(&self, _key: &CacheKey, _value: &T) {
+        match *self {}
+    }
+    pub async fn run_invalidation(self, _dirty: Option<DirtyReceiver>) -> anyhow::Result<()> {
+        match self {}
+    }
+}
+
+/// Receives batches of dirty NSIDs from the writer's rollups
+#[derive(Debug)]
+#[cfg_attr(not(feature = "redis"), allow(dead_code))]
+pub struct DirtyReceiver(Receiver<Vec<Nsid>>);
+
+/// Hands NSIDs with new rolled-up counts to the cache invalidation. Cheap to clone.
+#[derive(Debug, Clone)]
+pub struct DirtyTap(Sender<Vec<Nsid>>);
+
+impl DirtyTap {
+    pub fn new() -> (Self, DirtyReceiver) {
+        let (sender, receiver) = channel(QUEUE_SIZE);
+        (Self(sender), DirtyReceiver(receiver))
+    }
+    /// Queue dirty NSIDs for invalidation
+    ///
+    /// Never waits: if invalidation is too far behind, entries are left to expire.
+    pub fn offer(&self, nsids: HashSet<Nsid>) {
+        if nsids.is_empty() {
+            return;
+        }
+        match self.0.try_send(nsids.into_iter().collect()) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                log::warn!("cache invalidation is behind, dropping dirty nsids");
+            }
+            Err(TrySendError::Closed(_)) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys() {
+        let nsid = Nsid::new("a.b.c".to_string()).unwrap();
+        let k = CacheKey::Collection(nsid, "0:".to_string());
+        assert_eq!(k.key("ufos"), "ufos:nsid:a.b.c:0:");
+        assert_eq!(k.index("ufos"), "ufos:keys:a.b.c");
+        let k = CacheKey::TopCollections("dids-estimate:32::".to_string());
+        assert_eq!(k.key("ufos"), "ufos:top:dids-estimate:32::");
+        assert_eq!(k.index("ufos"), "ufos:keys:top");
+    }
+
+    #[test]
+    fn test_tap_skips_empty() {
+        let (tap, mut receiver) = DirtyTap::new();
+        tap.offer(HashSet::new());
+        tap.offer([Nsid::new("a.b.c".to_string()).unwrap()].into());
+        assert_eq!(receiver.0.try_recv().unwrap().len(), 1);
+        assert!(receiver.0.try_recv().is_err());
+    }
+}
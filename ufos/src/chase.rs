@@ -0,0 +1,183 @@
+//! Follow another UFOs instance instead of jetstream
+//!
+//! An instance running with a change feed publishes every batch it inserts at
+//! `/changes`. A chasing instance polls that feed and inserts the same
+//! batches, so read replicas can run in other regions without their own
+//! jetstream connection. Upstream redaction and deny rules have already been
+//! applied to the published batches.
+
+use crate::consumer::{LimitedBatch, BATCH_QUEUE_SIZE};
+use crate::spill;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use jetstream::events::Cursor;
+use metrics::{counter, describe_counter, Unit};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+/// Bumped whenever the encoding of published batches changes
+pub const CHANGE_FEED_FORMAT: u32 = 1;
+
+/// Max batches per `/changes` request
+pub const MAX_CHANGES_LIMIT: usize = 100;
+const POLL_LIMIT: usize = 50;
+const POLL_IDLE: Duration = Duration::from_secs(1);
+const MAX_RETRY_WAIT: Duration = Duration::from_secs(30);
+
+/// An instance's identity and progress, from `/cursor`
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct CursorInfo {
+    /// What this instance consumes: a jetstream endpoint or an upstream UFOs instance
+    pub endpoint: String,
+    /// When this instance started consuming (jetstream cursor, microseconds)
+    pub started_at: u64,
+    /// The latest inserted event (jetstream cursor, microseconds)
+    pub latest_cursor: Option<u64>,
+}
+
+/// One published batch
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ChangeBatch {
+    /// The latest event in the batch: use as `after` to continue
+    pub cursor: u64,
+    /// The encoded batch (url-safe base64, no padding)
+    pub batch: String,
+}
+
+/// A page of the change feed, from `/changes`
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ChangesResponse {
+    /// Encoding version of the batches, see [`CHANGE_FEED_FORMAT`]
+    pub format: u32,
+    /// Published batches, oldest first
+    pub batches: Vec<ChangeBatch>,
+    /// Batches up to this cursor have been dropped from the feed
+    ///
+    /// Chasing from before it would miss events.
+    pub trimmed_through: Option<u64>,
+}
+
+/// Start chasing an upstream UFOs instance (its base URL) from a cursor
+///
+/// The upstream's change feed needs a trusted api key if it has an auth config.
+pub async fn consume(
+    upstream: &str,
+    api_key: Option<&str>,
+    cursor: Option<Cursor>,
+) -> anyhow::Result<Receiver<LimitedBatch>> {
+    describe_counter!(
+        "chase_batches_received",
+        Unit::Count,
+        "batches received from the upstream change feed"
+    );
+    describe_counter!(
+        "chase_poll_failures",
+        Unit::Count,
+        "failed requests to the upstream change feed"
+    );
+    let upstream = upstream.trim_end_matches('/').to_string();
+    let mut headers = HeaderMap::new();
+    if let Some(key) = api_key {
+        let mut auth = HeaderValue::from_str(&format!("Bearer {key}"))?;
+        auth.set_sensitive(true);
+        headers.insert(AUTHORIZATION, auth);
+    }
+    let client = reqwest::Client::builder()
+        .user_agent(concat!("ufos/", env!("CARGO_PKG_VERSION"), " (chase)"))
+        .default_headers(headers)
+        .timeout(Duration::from_secs(30))
+        .build()?;
+    let info: CursorInfo = client
+        .get(format!("{upstream}/cursor"))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    log::info!(
+        "chasing {upstream} (consuming {}, at {:?}) from {cursor:?}",
+        info.endpoint,
+        info.latest_cursor,
+    );
+    let (sender, receiver) = channel(BATCH_QUEUE_SIZE);
+    tokio::task::spawn(async move {
+        let r = chase(client, upstream, cursor, sender).await;
+        log::warn!("chase ended: {r:?}");
+    });
+    Ok(receiver)
+}
+
+async fn chase(
+    client: reqwest::Client,
+    upstream: String,
+    mut cursor: Option<Cursor>,
+    sender: Sender<LimitedBatch>,
+) -> anyhow::Result<()> {
+    let mut retry_wait = POLL_IDLE;
+    loop {
+        let changes = match poll(&client, &upstream, cursor).await {
+            Ok(changes) => {
+                retry_wait = POLL_IDLE;
+                changes
+            }
+            Err(e) => {
+                counter!("chase_poll_failures").increment(1);
+                log::warn!(
+                    "failed to get changes from {upstream}, retrying in {retry_wait:?}: {e}"
+                );
+                tokio::time::sleep(retry_wait).await;
+                retry_wait = (retry_wait * 2).min(MAX_RETRY_WAIT);
+                continue;
+            }
+        };
+        if changes.format != CHANGE_FEED_FORMAT {
+            anyhow::bail!(
+                "upstream change feed format {} is not supported (expected {CHANGE_FEED_FORMAT})",
+                changes.format
+            );
+        }
+        if let (Some(at), Some(trimmed)) = (cursor, changes.trimmed_through) {
+            if at.to_raw_u64() < trimmed {
+                anyhow::bail!(
+                    "fell behind the upstream change feed: at {}, but it's trimmed through {trimmed}",
+                    at.to_raw_u64()
+                );
+            }
+        }
+        let n = changes.batches.len();
+        for change in changes.batches {
+            let batch: LimitedBatch = spill::decode(&URL_SAFE_NO_PAD.decode(&change.batch)?)?;
+            sender
+                .send(batch)
+                .await
+                .map_err(|_| anyhow::anyhow!("batch receiver closed"))?;
+            cursor = Some(Cursor::from_raw_u64(change.cursor));
+        }
+        counter!("chase_batches_received").increment(n as u64);
+        if n < POLL_LIMIT {
+            // caught up
+            tokio::time::sleep(POLL_IDLE).await;
+        }
+    }
+}
+
+async fn poll(
+    client: &reqwest::Client,
+    upstream: &str,
+    after: Option<Cursor>,
+) -> reqwest::Result<ChangesResponse> {
+    let mut query = vec![("limit", POLL_LIMIT.to_string())];
+    if let Some(after) = after {
+        query.push(("after", after.to_raw_u64().to_string()));
+    }
+    client
+        .get(format!("{upstream}/changes"))
+        .query(&query)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await
+}
@@ -1,28 +1,32 @@
 use crate::store_types::SketchSecretPrefix;
 use jetstream::{
     events::{Cursor, EventKind, JetstreamEvent},
-    exports::{Did, Nsid},
+    exports::{Did, Nsid, RecordKey},
     DefaultJetstreamEndpoints, JetstreamCompression, JetstreamConfig, JetstreamConnector,
     JetstreamReceiver,
 };
 use metrics::{
     counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram, Unit,
 };
+use serde::Serialize;
+use std::collections::hash_map::{Entry, HashMap};
 use std::mem;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::oneshot;
 use tokio::time::{timeout, Interval};
 
 use crate::error::{BatchInsertError, FirehoseEventError};
-use crate::{DeleteAccount, EventBatch, UFOsCommit};
+use crate::{CursorGap, DeleteAccount, EventBatch, JetstreamShard, UFOsCommit};
 
 pub const MAX_BATCHED_RECORDS: usize = 128; // *non-blocking* limit. drops oldest batched record per collection once reached.
 pub const MAX_ACCOUNT_REMOVES: usize = 1024; // hard limit, extremely unlikely to reach, but just in case
-pub const MAX_BATCHED_COLLECTIONS: usize = 64; // hard limit, MAX_BATCHED_RECORDS applies per-collection
+pub const MAX_BATCHED_COLLECTIONS: usize = 64; // default hard limit, MAX_BATCHED_RECORDS applies per-collection
 pub const MIN_BATCH_SPAN_SECS: f64 = 2.; // breathe
 pub const MAX_BATCH_SPAN_SECS: f64 = 60.; // hard limit, pause consumer if we're unable to send by now
 pub const SEND_TIMEOUT_S: f64 = 150.; // if the channel is blocked longer than this, something is probably up
 pub const BATCH_QUEUE_SIZE: usize = 64; // used to be 1, but sometimes inserts are just really slow????????
+pub const MAX_CURSOR_GAP_SECS: f64 = 600.; // a bigger jump between events means some were missed
 
 pub type LimitedBatch = EventBatch<MAX_BATCHED_RECORDS>;
 
@@ -38,24 +42,24 @@ pub struct Batcher {
     batch_sender: Sender<LimitedBatch>,
     current_batch: CurrentBatch,
     sketch_secret: SketchSecretPrefix,
+    max_collections: usize,
     rate_limit: Interval,
+    /// the latest event cursor so far (or the one we resumed from), when watching for gaps
+    gap_check: Option<Option<Cursor>>,
 }
 
-pub async fn consume(
-    jetstream_endpoint: &str,
-    cursor: Option<Cursor>,
-    no_compress: bool,
-    sketch_secret: SketchSecretPrefix,
-) -> anyhow::Result<Receiver<LimitedBatch>> {
+/// How ufos connects to jetstream, for an endpoint url or shortcut like `us-east-1`
+pub fn jetstream_config(jetstream_endpoint: &str, no_compress: bool) -> JetstreamConfig {
     let endpoint = DefaultJetstreamEndpoints::endpoint_or_shortcut(jetstream_endpoint);
     if endpoint == jetstream_endpoint {
         log::info!("connecting to jetstream at {endpoint}");
     } else {
         log::info!("connecting to jetstream at {jetstream_endpoint} => {endpoint}");
     }
-    let config: JetstreamConfig = JetstreamConfig {
+    JetstreamConfig {
         endpoint,
-        compression: if no_compress {
+        // builds without a zstd decoder can't read compressed messages
+        compression: if no_compress || !cfg!(any(feature = "zstd", feature = "ruzstd")) {
             JetstreamCompression::None
         } else {
             JetstreamCompression::Zstd
@@ -63,17 +67,306 @@ pub async fn consume(
         replay_on_reconnect: true,
         channel_size: 1024, // buffer up to ~1s of jetstream events
         ..Default::default()
-    };
+    }
+}
+
+pub async fn consume(
+    jetstream_endpoint: &str,
+    cursor: Option<Cursor>,
+    no_compress: bool,
+    sketch_secret: SketchSecretPrefix,
+    max_collections: usize,
+) -> anyhow::Result<Receiver<LimitedBatch>> {
+    let config = jetstream_config(jetstream_endpoint, no_compress);
     let jetstream_receiver = JetstreamConnector::new(config)?
         .connect_cursor(cursor)
         .await?;
+    Ok(spawn_batcher(
+        jetstream_receiver,
+        cursor,
+        sketch_secret,
+        max_collections,
+    ))
+}
+
+/// Consume over one jetstream connection per shard, merged into one stream of batches
+///
+/// Each shard connects with its own cursor and only asks for its own
+/// collections, so the firehose's load is split across connections.
+/// Collections that aren't in any shard are not consumed at all. Account
+/// events reach every connection, so only the first shard's are kept.
+pub async fn consume_sharded(
+    jetstream_endpoint: &str,
+    shards: Vec<(JetstreamShard, Option<Cursor>)>,
+    no_compress: bool,
+    sketch_secret: SketchSecretPrefix,
+    max_collections: usize,
+) -> anyhow::Result<Receiver<LimitedBatch>> {
+    describe_counter!(
+        "consumer_shard_events",
+        Unit::Count,
+        "jetstream events received, by shard"
+    );
+    // shards that are behind the furthest one just catch up to it
+    let resume_cursor = shards
+        .iter()
+        .filter_map(|(_, c)| *c)
+        .reduce(|a, b| if a < b { b } else { a });
+    let (merged_sender, merged_receiver) = channel::<JetstreamEvent>(1024);
+    for (i, (shard, cursor)) in shards.into_iter().enumerate() {
+        let config = JetstreamConfig {
+            wanted_collections: shard.collections().to_vec(),
+            ..jetstream_config(jetstream_endpoint, no_compress)
+        };
+        log::info!(
+            "jetstream shard {i} ({} collections) starting at {cursor:?}",
+            shard.collections().len()
+        );
+        let mut receiver = JetstreamConnector::new(config)?
+            .connect_cursor(cursor)
+            .await?;
+        let sender = merged_sender.clone();
+        tokio::task::spawn(async move {
+            while let Some(event) = receiver.recv().await {
+                if i > 0 && event.kind != EventKind::Commit {
+                    continue; // already coming from shard 0
+                }
+                counter!("consumer_shard_events", "shard" => i.to_string()).increment(1);
+                if sender.send(event).await.is_err() {
+                    break;
+                }
+            }
+            log::warn!("jetstream shard {i} ended");
+        });
+    }
+    Ok(spawn_batcher(
+        merged_receiver,
+        resume_cursor,
+        sketch_secret,
+        max_collections,
+    ))
+}
+
+/// How long past the overlap window to wait for the slower endpoint to get there
+pub const MIGRATION_GRACE: Duration = Duration::from_secs(120);
+
+/// The outcome of overlapping two jetstream endpoints while switching between them
+#[derive(Debug, Clone, Serialize)]
+pub struct MigrationReport {
+    pub from: String,
+    pub to: String,
+    /// Where both endpoints were started from
+    pub started_at: Option<u64>,
+    /// The end of the overlap window: from here on, everything is from the new endpoint
+    pub boundary: u64,
+    /// Commits delivered by both endpoints and only passed on once
+    pub duplicates: u64,
+    /// Commits in the window that only the old endpoint delivered
+    pub old_only: u64,
+    /// Commits in the window that only the new endpoint delivered
+    pub new_only: u64,
+    /// Whether both endpoints made it through the window before the grace period ran out
+    ///
+    /// If not, the counts stop at the timeout. The new endpoint's commits are
+    /// still deduplicated up to the boundary, but commits that only the old
+    /// endpoint had, after the point it reached, may be missing.
+    pub complete: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Side {
+    Old,
+    New,
+}
+
+impl Side {
+    fn other(self) -> Self {
+        match self {
+            Side::Old => Side::New,
+            Side::New => Side::Old,
+        }
+    }
+}
+
+type CommitKey = (Did, Nsid, RecordKey, String);
+
+fn commit_key(event: &JetstreamEvent) -> Option<CommitKey> {
+    let commit = event.commit.as_ref()?;
+    Some((
+        event.did.clone(),
+        commit.collection.clone(),
+        commit.rkey.clone(),
+        commit.rev.clone(),
+    ))
+}
+
+/// Drops commits seen from both endpoints, keyed on (did, collection, rkey, rev)
+#[derive(Debug, Default)]
+struct OverlapDedupe {
+    /// Which side sent each commit, or `None` once both have
+    seen: HashMap<CommitKey, Option<Side>>,
+    duplicates: u64,
+}
+
+impl OverlapDedupe {
+    /// True if the commit should be passed on: the first time either side sends it
+    fn admit(&mut self, key: CommitKey, side: Side) -> bool {
+        match self.seen.entry(key) {
+            Entry::Vacant(e) => {
+                e.insert(Some(side));
+                true
+            }
+            Entry::Occupied(mut e) => {
+                if *e.get() == Some(side.other()) {
+                    e.insert(None);
+                    self.duplicates += 1;
+                } // else a replay on reconnect: the first copy was already passed on
+                false
+            }
+        }
+    }
+    /// Commits that only one side delivered: (old only, new only)
+    fn unmatched(&self) -> (u64, u64) {
+        let only = |side| self.seen.values().filter(|s| **s == Some(side)).count() as u64;
+        (only(Side::Old), only(Side::New))
+    }
+}
+
+/// Switch jetstream endpoints without losing or duplicating events
+///
+/// Cursors from different jetstream instances aren't exactly comparable, so
+/// both endpoints are consumed from `cursor` until each has passed
+/// `cursor + overlap`. Commits in that window are deduplicated, and only the
+/// new endpoint's account and identity events are kept. After the window the
+/// old connection is dropped, and the report is sent with the numbers, for
+/// recording where the migration happened.
+#[allow(clippy::too_many_arguments)]
+pub async fn consume_migrating(
+    old_endpoint: &str,
+    new_endpoint: &str,
+    cursor: Option<Cursor>,
+    overlap: Duration,
+    no_compress: bool,
+    sketch_secret: SketchSecretPrefix,
+    max_collections: usize,
+) -> anyhow::Result<(Receiver<LimitedBatch>, oneshot::Receiver<MigrationReport>)> {
+    let start = cursor.unwrap_or_else(|| Cursor::at(SystemTime::now()));
+    let boundary = start.to_raw_u64() + overlap.as_micros() as u64;
+    let mut old = JetstreamConnector::new(jetstream_config(old_endpoint, no_compress))?
+        .connect_cursor(cursor)
+        .await?;
+    let mut new = JetstreamConnector::new(jetstream_config(new_endpoint, no_compress))?
+        .connect_cursor(cursor)
+        .await?;
+    log::info!("migrating jetstream endpoints, overlapping until cursor {boundary}");
+
+    let (merged_sender, merged_receiver) = channel::<JetstreamEvent>(1024);
+    let (report_sender, report_receiver) = oneshot::channel();
+    let mut report = MigrationReport {
+        from: old_endpoint.to_string(),
+        to: new_endpoint.to_string(),
+        started_at: cursor.map(|c| c.to_raw_u64()),
+        boundary,
+        duplicates: 0,
+        old_only: 0,
+        new_only: 0,
+        complete: false,
+    };
+    tokio::task::spawn(async move {
+        let mut dedupe = OverlapDedupe::default();
+        let (mut old_done, mut new_done) = (false, false);
+        let grace = tokio::time::sleep(
+            overlap.saturating_sub(start.elapsed().unwrap_or_default()) + MIGRATION_GRACE,
+        );
+        tokio::pin!(grace);
+        while !(old_done && new_done) {
+            let (event, side) = tokio::select! {
+                event = old.recv(), if !old_done => match event {
+                    Some(event) => (event, Side::Old),
+                    None => {
+                        log::warn!("old jetstream endpoint ended during the migration overlap");
+                        old_done = true;
+                        continue;
+                    }
+                },
+                event = new.recv(), if !new_done => match event {
+                    Some(event) => (event, Side::New),
+                    None => {
+                        log::warn!("new jetstream endpoint ended during the migration overlap");
+                        return;
+                    }
+                },
+                _ = &mut grace => {
+                    log::warn!("jetstream migration overlap ran out of time");
+                    break;
+                }
+            };
+            let past = event.cursor.to_raw_u64() > boundary;
+            let keep = match (side, commit_key(&event)) {
+                (Side::Old, _) if past => {
+                    old_done = true;
+                    false
+                }
+                (Side::New, _) if past => {
+                    new_done = true;
+                    true
+                }
+                (side, Some(key)) => dedupe.admit(key, side),
+                (Side::Old, None) => false, // non-commits come from the new endpoint
+                (Side::New, None) => true,
+            };
+            if keep && merged_sender.send(event).await.is_err() {
+                return;
+            }
+        }
+        drop(old);
+        (report.old_only, report.new_only) = dedupe.unmatched();
+        report.duplicates = dedupe.duplicates;
+        report.complete = old_done && new_done;
+        log::info!("jetstream migration overlap done: {report:?}");
+        let _ = report_sender.send(report);
+        // after a timeout the new endpoint can still be inside the window, so
+        // keep dropping the commits the old one already passed on until it's out
+        let mut dedupe = (!new_done).then_some(dedupe);
+        while let Some(event) = new.recv().await {
+            if event.cursor.to_raw_u64() > boundary {
+                dedupe = None;
+            }
+            let keep = match (&mut dedupe, commit_key(&event)) {
+                (Some(dedupe), Some(key)) => dedupe.admit(key, Side::New),
+                _ => true,
+            };
+            if keep && merged_sender.send(event).await.is_err() {
+                break;
+            }
+        }
+        log::warn!("new jetstream endpoint ended");
+    });
+    Ok((
+        spawn_batcher(merged_receiver, cursor, sketch_secret, max_collections),
+        report_receiver,
+    ))
+}
+
+fn spawn_batcher(
+    jetstream_receiver: JetstreamReceiver,
+    resume_cursor: Option<Cursor>,
+    sketch_secret: SketchSecretPrefix,
+    max_collections: usize,
+) -> Receiver<LimitedBatch> {
     let (batch_sender, batch_reciever) = channel::<LimitedBatch>(BATCH_QUEUE_SIZE);
-    let mut batcher = Batcher::new(jetstream_receiver, batch_sender, sketch_secret);
+    let mut batcher = Batcher::new(
+        jetstream_receiver,
+        batch_sender,
+        sketch_secret,
+        max_collections,
+    )
+    .detect_gaps(resume_cursor);
     tokio::task::spawn(async move {
         let r = batcher.run().await;
         log::warn!("batcher ended: {r:?}");
     });
-    Ok(batch_reciever)
+    batch_reciever
 }
 
 impl Batcher {
@@ -81,6 +374,7 @@ impl Batcher {
         jetstream_receiver: JetstreamReceiver,
         batch_sender: Sender<LimitedBatch>,
         sketch_secret: SketchSecretPrefix,
+        max_collections: usize,
     ) -> Self {
         describe_counter!(
             "batcher_batches_sent",
@@ -102,6 +396,26 @@ impl Batcher {
             Unit::Count,
             "how many collections are in this batch"
         );
+        describe_counter!(
+            "batcher_overflow_flushes",
+            Unit::Count,
+            "batches sent early because a commit did not fit, by reason"
+        );
+        describe_counter!(
+            "batcher_overflow_dropped",
+            Unit::Count,
+            "commits dropped because they did not fit even in a fresh batch (should be zero)"
+        );
+        describe_counter!(
+            "consumer_events",
+            Unit::Count,
+            "events received by the batcher, by kind"
+        );
+        describe_gauge!(
+            "consumer_cursor_age",
+            Unit::Microseconds,
+            "microseconds between our clock and the cursor of the latest event received"
+        );
         let mut rate_limit = tokio::time::interval(std::time::Duration::from_millis(3));
         rate_limit.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
         Self {
@@ -109,7 +423,54 @@ impl Batcher {
             batch_sender,
             current_batch: Default::default(),
             sketch_secret,
+            max_collections,
             rate_limit,
+            gap_check: None,
+        }
+    }
+
+    /// Watch for events skipping ahead: first from `resume_cursor`, then from each other
+    ///
+    /// A jump of more than [`MAX_CURSOR_GAP_SECS`] means events were missed,
+    /// usually because the cursor we resumed from had fallen out of jetstream's
+    /// replay window. Gaps are logged and go out with the next batch, so storage
+    /// can report them instead of the loss going unnoticed.
+    pub fn detect_gaps(mut self, resume_cursor: Option<Cursor>) -> Self {
+        describe_counter!(
+            "consumer_cursor_gaps",
+            Unit::Count,
+            "times the events received skipped ahead, missing some"
+        );
+        self.gap_check = Some(resume_cursor);
+        self
+    }
+
+    /// Note a gap before this event, if there was one
+    fn check_gap(&mut self, cursor: Cursor) {
+        let Some(last) = &mut self.gap_check else {
+            return;
+        };
+        let Some(previous) = last.replace(cursor) else {
+            return;
+        };
+        if cursor < previous {
+            *last = Some(previous); // sharded or replayed: we're already past here
+            return;
+        }
+        let Ok(skipped) = cursor.duration_since(&previous) else {
+            return;
+        };
+        if skipped.as_secs_f64() > MAX_CURSOR_GAP_SECS {
+            log::warn!(
+                "jetstream events skipped ahead by {skipped:?}: missed events between cursors {} and {}",
+                previous.to_raw_u64(),
+                cursor.to_raw_u64(),
+            );
+            counter!("consumer_cursor_gaps").increment(1);
+            self.current_batch.batch.cursor_gap = Some(CursorGap {
+                last_seen: previous.to_raw_u64(),
+                resumed_at: cursor.to_raw_u64(),
+            });
         }
     }
 
@@ -134,6 +495,14 @@ impl Batcher {
     }
 
     async fn handle_event(&mut self, event: JetstreamEvent) -> anyhow::Result<()> {
+        let kind = match event.kind {
+            EventKind::Commit => "commit",
+            EventKind::Account => "account",
+            EventKind::Identity => "identity",
+        };
+        counter!("consumer_events", "kind" => kind).increment(1);
+        gauge!("consumer_cursor_age").set(event.cursor.elapsed_micros_f64());
+
         if let Some(earliest) = &self.current_batch.initial_cursor {
             if event.cursor.duration_since(earliest)? > Duration::from_secs_f64(MAX_BATCH_SPAN_SECS)
             {
@@ -143,6 +512,8 @@ impl Batcher {
         } else {
             self.current_batch.initial_cursor = Some(event.cursor);
         }
+        // after any flush for the batch span: a gap is always longer, so it starts a fresh batch
+        self.check_gap(event.cursor);
 
         match event.kind {
             EventKind::Commit => {
@@ -151,6 +522,8 @@ impl Batcher {
                     .ok_or(FirehoseEventError::CommitEventMissingCommit)?;
                 let (commit, nsid) = UFOsCommit::from_commit_info(commit, event.did, event.cursor)?;
                 self.handle_commit(commit, nsid).await?;
+                // after any early flush, so it's counted with the batch the commit went into
+                self.current_batch.batch.event_kinds.commits += 1;
             }
             EventKind::Account => {
                 let account = event
@@ -159,8 +532,11 @@ impl Batcher {
                 if !account.active {
                     self.handle_delete_account(event.did, event.cursor).await?;
                 }
+                self.current_batch.batch.event_kinds.accounts += 1;
+            }
+            EventKind::Identity => {
+                self.current_batch.batch.event_kinds.identities += 1;
             }
-            _ => {}
         }
 
         // if the queue is empty and we have enough, send immediately. otherewise, let the current batch fill up.
@@ -178,20 +554,36 @@ impl Batcher {
         let optimistic_res = self.current_batch.batch.insert_commit_by_nsid(
             &collection,
             commit,
-            MAX_BATCHED_COLLECTIONS,
+            self.max_collections,
             &self.sketch_secret,
         );
 
-        if let Err(BatchInsertError::BatchFull(commit)) = optimistic_res {
-            self.send_current_batch_now(false, "handle commit").await?;
-            self.current_batch.batch.insert_commit_by_nsid(
-                &collection,
-                commit,
-                MAX_BATCHED_COLLECTIONS,
-                &self.sketch_secret,
-            )?;
-        } else {
-            optimistic_res?;
+        let (overflowed, reason) = match optimistic_res {
+            Ok(()) => return Ok(()),
+            Err(BatchInsertError::BatchFull(commit)) => (commit, "collection_full"),
+            Err(BatchInsertError::TooManyCollections(commit)) => {
+                self.current_batch.batch.overflowed_collections += 1;
+                (commit, "too_many_collections")
+            }
+            Err(bug) => return Err(bug.into()),
+        };
+
+        // flush early so that the commit can start the next batch instead of being lost
+        counter!("batcher_overflow_flushes", "reason" => reason).increment(1);
+        self.send_current_batch_now(false, "handle commit").await?;
+        self.current_batch.initial_cursor = Some(overflowed.cursor);
+
+        if let Err(e) = self.current_batch.batch.insert_commit_by_nsid(
+            &collection,
+            overflowed,
+            self.max_collections,
+            &self.sketch_secret,
+        ) {
+            // a fresh batch always has room for one commit, so this really shouldn't happen
+            counter!("batcher_overflow_dropped").increment(1);
+            log::error!(
+                "dropping a commit for {collection:?} that did not fit in a fresh batch: {e}"
+            );
         }
 
         Ok(())
@@ -239,3 +631,77 @@ impl Batcher {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(rkey: &str, rev: &str) -> CommitKey {
+        (
+            Did::new("did:plc:inze6wrmsm7pjl7yta3oig77".to_string()).unwrap(),
+            Nsid::new("a.b.c".to_string()).unwrap(),
+            RecordKey::new(rkey.to_string()).unwrap(),
+            rev.to_string(),
+        )
+    }
+
+    #[test]
+    fn test_overlap_dedupe() {
+        let mut dedupe = OverlapDedupe::default();
+        assert!(dedupe.admit(key("a", "1"), Side::Old));
+        assert!(!dedupe.admit(key("a", "1"), Side::New));
+        assert!(dedupe.admit(key("a", "2"), Side::New));
+        assert!(!dedupe.admit(key("a", "2"), Side::New)); // replayed
+        assert!(!dedupe.admit(key("a", "1"), Side::Old)); // replayed after matching
+        assert!(dedupe.admit(key("b", "1"), Side::Old));
+        assert!(dedupe.admit(key("c", "1"), Side::New));
+        assert_eq!(dedupe.duplicates, 1);
+        assert_eq!(dedupe.unmatched(), (1, 2));
+    }
+
+    #[tokio::test]
+    async fn test_check_gap() {
+        let (_, events) = channel(1);
+        let (batches, _) = channel(1);
+        let minute = 60_000_000;
+        let mut batcher = Batcher::new(events, batches, [0; 16], MAX_BATCHED_COLLECTIONS)
+            .detect_gaps(Some(Cursor::from_raw_u64(100 * minute)));
+
+        batcher.check_gap(Cursor::from_raw_u64(100 * minute + 1));
+        batcher.check_gap(Cursor::from_raw_u64(105 * minute));
+        batcher.check_gap(Cursor::from_raw_u64(104 * minute)); // out of order, from another shard
+        batcher.check_gap(Cursor::from_raw_u64(114 * minute));
+        assert_eq!(batcher.current_batch.batch.cursor_gap, None);
+
+        batcher.check_gap(Cursor::from_raw_u64(125 * minute));
+        assert_eq!(
+            batcher.current_batch.batch.cursor_gap,
+            Some(CursorGap {
+                last_seen: 114 * minute,
+                resumed_at: 125 * minute,
+            })
+        );
+
+        // resuming from a cursor that had fallen out of the replay window
+        let (_, events) = channel(1);
+        let (batches, _) = channel(1);
+        let mut batcher = Batcher::new(events, batches, [0; 16], MAX_BATCHED_COLLECTIONS)
+            .detect_gaps(Some(Cursor::from_raw_u64(minute)));
+        batcher.check_gap(Cursor::from_raw_u64(3_000 * minute));
+        assert_eq!(
+            batcher.current_batch.batch.cursor_gap,
+            Some(CursorGap {
+                last_seen: minute,
+                resumed_at: 3_000 * minute,
+            })
+        );
+
+        // not watching
+        let (_, events) = channel(1);
+        let (batches, _) = channel(1);
+        let mut batcher = Batcher::new(events, batches, [0; 16], MAX_BATCHED_COLLECTIONS);
+        batcher.check_gap(Cursor::from_raw_u64(minute));
+        batcher.check_gap(Cursor::from_raw_u64(3_000 * minute));
+        assert_eq!(batcher.current_batch.batch.cursor_gap, None);
+    }
+}
@@ -1,6 +1,6 @@
 use crate::{Cursor, Did, Nsid, RecordKey};
 use bincode::{
-    config::{standard, Config},
+    config::Config,
     de::Decode as BincodeDecode,
     decode_from_slice,
     enc::Encode as BincodeEncode,
@@ -12,6 +12,7 @@ use std::fmt;
 use std::marker::PhantomData;
 use std::ops::{Bound, Range};
 use thiserror::Error;
+use ufos_core::encoding::{decode_null_terminated, CoreEncodingError};
 
 #[non_exhaustive]
 #[derive(Error, Debug)]
@@ -52,11 +53,20 @@ pub enum EncodingError {
 
 pub type EncodingResult<T> = Result<T, EncodingError>;
 
-fn bincode_conf() -> impl Config {
-    standard()
-        .with_big_endian()
-        .with_fixed_int_encoding()
-        .with_limit::<{ 2_usize.pow(20) }>() // 1MB
+pub(crate) fn bincode_conf() -> impl Config {
+    ufos_core::encoding::bincode_conf()
+}
+
+impl From<CoreEncodingError> for EncodingError {
+    fn from(e: CoreEncodingError) -> Self {
+        match e {
+            CoreEncodingError::BincodeDecodeFailed(e) => Self::BincodeDecodeFailed(e),
+            CoreEncodingError::NotUtf8(e) => Self::NotUtf8(e),
+            CoreEncodingError::UnterminatedString => Self::UnterminatedString,
+            CoreEncodingError::DecodeNotEnoughBytes => Self::DecodeNotEnoughBytes,
+            CoreEncodingError::DecodeTooManyBytes(n) => Self::DecodeTooManyBytes(n),
+        }
+    }
 }
 
 pub trait DbBytes {
@@ -276,14 +286,8 @@ impl DbBytes for String {
         Ok(v)
     }
     fn from_db_bytes(bytes: &[u8]) -> Result<(Self, usize), EncodingError> {
-        for (i, byte) in bytes.iter().enumerate() {
-            if *byte == 0x00 {
-                let (string_bytes, _) = bytes.split_at(i);
-                let s = std::str::from_utf8(string_bytes)?;
-                return Ok((s.to_string(), i + 1)); // +1 for the null byte
-            }
-        }
-        Err(EncodingError::UnterminatedString)
+        let (s, n) = decode_null_terminated(bytes)?;
+        Ok((s.to_string(), n))
     }
 }
 
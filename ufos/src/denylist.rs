@@ -0,0 +1,133 @@
+use crate::db_types::{EncodingError, EncodingResult};
+use crate::{Nsid, NsidPrefix};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// A collection (or group of collections) that should never be stored
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct DenyRule {
+    /// Either an exact collection NSID like `com.example.spam.post`, or a
+    /// group prefix ending with `.*` like `com.example.spam.*` that matches
+    /// every NSID under it.
+    pub pattern: String,
+    /// Keep counting events for matching collections, only skip storing records
+    pub keep_counts: bool,
+}
+
+impl DenyRule {
+    pub fn new(pattern: &str, keep_counts: bool) -> EncodingResult<Self> {
+        if let Some(prefix) = pattern.strip_suffix(".*") {
+            NsidPrefix::new(prefix)?;
+        } else {
+            Nsid::new(pattern.to_string()).map_err(EncodingError::BadAtriumStringType)?;
+        }
+        Ok(Self {
+            pattern: pattern.to_string(),
+            keep_counts,
+        })
+    }
+    fn prefix(&self) -> Option<&str> {
+        // keep the dot so that we only match full segments
+        self.pattern.strip_suffix('*')
+    }
+}
+
+/// In-memory lookup for deny rules
+#[derive(Debug, Default, Clone)]
+pub struct Denylist {
+    exact: HashMap<String, DenyRule>,
+    prefixes: Vec<DenyRule>,
+}
+
+impl Denylist {
+    pub fn new(rules: impl IntoIterator<Item = DenyRule>) -> Self {
+        let mut me = Self::default();
+        for rule in rules {
+            me.insert(rule);
+        }
+        me
+    }
+    pub fn insert(&mut self, rule: DenyRule) {
+        self.remove(&rule.pattern);
+        if rule.prefix().is_some() {
+            self.prefixes.push(rule);
+        } else {
+            self.exact.insert(rule.pattern.clone(), rule);
+        }
+    }
+    pub fn remove(&mut self, pattern: &str) -> bool {
+        let had_exact = self.exact.remove(pattern).is_some();
+        let n = self.prefixes.len();
+        self.prefixes.retain(|r| r.pattern != pattern);
+        had_exact || self.prefixes.len() < n
+    }
+    /// Find the rule denying a collection, if any
+    pub fn check(&self, collection: &Nsid) -> Option<&DenyRule> {
+        if let Some(rule) = self.exact.get(collection.as_str()) {
+            return Some(rule);
+        }
+        self.prefixes.iter().find(|rule| {
+            rule.prefix()
+                .map(|p| collection.as_str().starts_with(p))
+                .unwrap_or(false)
+        })
+    }
+    pub fn rules(&self) -> Vec<DenyRule> {
+        let mut rules: Vec<DenyRule> = self
+            .exact
+            .values()
+            .chain(self.prefixes.iter())
+            .cloned()
+            .collect();
+        rules.sort_by(|a, b| a.pattern.cmp(&b.pattern));
+        rules
+    }
+    pub fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.prefixes.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nsid(s: &str) -> Nsid {
+        Nsid::new(s.to_string()).unwrap()
+    }
+
+    #[test]
+    fn test_rule_validation() {
+        assert!(DenyRule::new("a.b.c", false).is_ok());
+        assert!(DenyRule::new("a.b.*", false).is_ok());
+        assert!(DenyRule::new("a.*", false).is_err());
+        assert!(DenyRule::new("a.b.", false).is_err());
+        assert!(DenyRule::new("*", false).is_err());
+    }
+
+    #[test]
+    fn test_exact_and_prefix_matches() {
+        let list = Denylist::new([
+            DenyRule::new("a.b.c", false).unwrap(),
+            DenyRule::new("x.y.*", true).unwrap(),
+        ]);
+        assert!(list.check(&nsid("a.b.c")).is_some());
+        assert!(list.check(&nsid("a.b.cd")).is_none());
+        assert!(list.check(&nsid("a.b.c.d")).is_none());
+        assert!(list.check(&nsid("x.y.z")).unwrap().keep_counts);
+        assert!(list.check(&nsid("x.y.z.w")).is_some());
+        assert!(list.check(&nsid("x.yz.w")).is_none());
+    }
+
+    #[test]
+    fn test_insert_replaces_and_remove() {
+        let mut list = Denylist::default();
+        list.insert(DenyRule::new("a.b.c", false).unwrap());
+        list.insert(DenyRule::new("a.b.c", true).unwrap());
+        assert_eq!(list.rules().len(), 1);
+        assert!(list.check(&nsid("a.b.c")).unwrap().keep_counts);
+        assert!(list.remove("a.b.c"));
+        assert!(!list.remove("a.b.c"));
+        assert!(list.is_empty());
+    }
+}
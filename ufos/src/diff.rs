@@ -0,0 +1,75 @@
+use serde_json::value::RawValue;
+use serde_json::{Map, Value};
+
+/// Diff two versions of a record as a JSON merge patch (RFC 7386)
+///
+/// Applying the patch to `old` gives `new`. Removed keys are `null` in the
+/// patch, and arrays are replaced whole. Returns `None` if nothing changed.
+///
+/// Merge patches can't tell a key set to `null` from a removed key, which is
+/// fine for atproto records: lexicons don't use explicit nulls.
+pub fn merge_diff(old: &Value, new: &Value) -> Option<Value> {
+    if old == new {
+        return None;
+    }
+    let (Value::Object(old), Value::Object(new)) = (old, new) else {
+        return Some(new.clone());
+    };
+    let mut patch = Map::new();
+    for key in old.keys() {
+        if !new.contains_key(key) {
+            patch.insert(key.clone(), Value::Null);
+        }
+    }
+    for (key, new_val) in new {
+        let changed = match old.get(key) {
+            Some(old_val) => merge_diff(old_val, new_val),
+            None => Some(new_val.clone()),
+        };
+        if let Some(change) = changed {
+            patch.insert(key.clone(), change);
+        }
+    }
+    Some(Value::Object(patch))
+}
+
+/// Diff two raw records, for storing alongside the newer one
+pub fn diff_records(old: &RawValue, new: &RawValue) -> serde_json::Result<Option<String>> {
+    let old: Value = serde_json::from_str(old.get())?;
+    let new: Value = serde_json::from_str(new.get())?;
+    merge_diff(&old, &new)
+        .map(|patch| serde_json::to_string(&patch))
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_merge_diff() {
+        let old = json!({"displayName": "a", "description": "hi", "avatar": {"size": 1}});
+        let new = json!({"displayName": "b", "avatar": {"size": 2}, "labels": [1]});
+        assert_eq!(
+            merge_diff(&old, &new),
+            Some(json!({
+                "displayName": "b",
+                "description": null,
+                "avatar": {"size": 2},
+                "labels": [1],
+            }))
+        );
+        assert_eq!(merge_diff(&old, &old), None);
+        assert_eq!(merge_diff(&json!([1, 2]), &json!([2])), Some(json!([2])));
+    }
+
+    #[test]
+    fn test_diff_records() -> serde_json::Result<()> {
+        let old = RawValue::from_string(r#"{"a":1,"b":{"c":2,"d":3}}"#.into())?;
+        let new = RawValue::from_string(r#"{"a":1,"b":{"c":2,"d":4}}"#.into())?;
+        assert_eq!(diff_records(&old, &new)?, Some(r#"{"b":{"d":4}}"#.into()));
+        assert_eq!(diff_records(&old, &old)?, None);
+        Ok(())
+    }
+}
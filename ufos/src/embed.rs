@@ -0,0 +1,451 @@
+//! Run UFOs inside another application
+//!
+//! This is the ingestion and query engine without the HTTP server: configure
+//! it with [`Ufos::builder`], query storage directly through [`Ufos::reader`],
+//! and drive ingestion with [`Ufos::run`].
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! use ufos::embed::Ufos;
+//! use ufos::storage::{ScanBudget, StoreReader};
+//! use ufos::store_types::HourWindow;
+//!
+//! let ufos = Ufos::builder("./ufos-data")
+//!     .jetstream("us-east-1")
+//!     .deny("app.bsky.feed.like", false)?
+//!     .build()
+//!     .await?;
+//!
+//! let reader = ufos.reader();
+//! tokio::spawn(ufos.run());
+//!
+//! let (collections, _) = reader
+//!     .get_collections(
+//!         10,
+//!         Default::default(),
+//!         HourWindow::all_time(),
+//!         ScanBudget::new(100_000),
+//!     )
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::chase;
+use crate::consumer::{self, MAX_BATCHED_COLLECTIONS};
+use crate::db_types::EncodingResult;
+use crate::denylist::DenyRule;
+use crate::error::StorageError;
+use crate::file_consumer;
+use crate::nsid_limits::NsidLimits;
+use crate::plugin::{IngestPlugin, IngestPlugins};
+use crate::publish::EventBus;
+use crate::redaction::Redactor;
+use crate::spill::{self, MAX_SPILLED_BATCHES};
+use crate::storage::{StorageWhatever, StoreAdmin, StoreBackground, StoreWriter};
+use crate::storage_fjall::{FjallConfig, FjallReader, FjallStorage, FjallWriter};
+use crate::store_types::SketchSecretPrefix;
+use crate::transform::Transformer;
+use crate::watchlist::Watchlists;
+use crate::webhook::Webhooks;
+use crate::{BackgroundIntervals, Cursor, Nsid};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinSet;
+
+/// Where events come from
+#[derive(Debug, Clone)]
+enum Source {
+    Jetstream {
+        endpoint: String,
+        force_endpoint: bool,
+        no_zstd: bool,
+    },
+    Fixture(PathBuf),
+    /// Another UFOs instance's change feed
+    Chase {
+        upstream: String,
+        api_key: Option<String>,
+    },
+}
+
+/// Which storage backend to use
+#[derive(Debug, Clone)]
+pub enum StorageChoice {
+    /// fjall, persisted at this path
+    Fjall(PathBuf),
+    /// fjall in a fresh temporary directory, deleted when the instance is dropped
+    ///
+    /// For CI and demos. Up to `cache_bytes` of data is kept in memory, and
+    /// the rest overflows to disk, so every query works like it does on a
+    /// persisted db.
+    Ephemeral { cache_bytes: Option<u64> },
+}
+
+#[derive(Debug)]
+pub struct UfosBuilder {
+    storage: StorageChoice,
+    source: Option<Source>,
+    deny: Vec<DenyRule>,
+    redaction: Option<Redactor>,
+    transforms: Option<Transformer>,
+    watchlists: Option<Watchlists>,
+    webhooks: Option<Webhooks>,
+    bus: Option<EventBus>,
+    record_diffs: bool,
+    keep_versions: HashMap<Nsid, usize>,
+    change_feed: Option<Duration>,
+    max_collections: usize,
+    max_spilled: usize,
+    backfill: bool,
+    background: Option<BackgroundIntervals>,
+    popular_trim_multiplier: Option<usize>,
+    seed: Option<u64>,
+    nsid_limits: NsidLimits,
+    plugins: IngestPlugins,
+    reroll: bool,
+}
+
+impl UfosBuilder {
+    fn new(storage: StorageChoice) -> Self {
+        Self {
+            storage,
+            source: None,
+            deny: vec![],
+            redaction: None,
+            transforms: None,
+            watchlists: None,
+            webhooks: None,
+            bus: None,
+            record_diffs: false,
+            keep_versions: HashMap::new(),
+            change_feed: None,
+            max_collections: MAX_BATCHED_COLLECTIONS,
+            max_spilled: MAX_SPILLED_BATCHES,
+            backfill: false,
+            background: None,
+            popular_trim_multiplier: None,
+            seed: None,
+            nsid_limits: Default::default(),
+            plugins: Default::default(),
+            reroll: false,
+        }
+    }
+    /// Consume from a jetstream server: a wss:// URL or a shorthand like 'us-east-1'
+    pub fn jetstream(mut self, endpoint: impl Into<String>) -> Self {
+        self.source = Some(Source::Jetstream {
+            endpoint: endpoint.into(),
+            force_endpoint: false,
+            no_zstd: false,
+        });
+        self
+    }
+    /// Allow switching to a different jetstream server than the data was collected from
+    pub fn force_endpoint(mut self) -> Self {
+        if let Some(Source::Jetstream { force_endpoint, .. }) = &mut self.source {
+            *force_endpoint = true;
+        }
+        self
+    }
+    /// Don't request zstd-compressed jetstream events
+    pub fn no_zstd(mut self) -> Self {
+        if let Some(Source::Jetstream { no_zstd, .. }) = &mut self.source {
+            *no_zstd = true;
+        }
+        self
+    }
+    /// Replay events from a jsonl file of jetstream events instead
+    pub fn fixture(mut self, path: impl Into<PathBuf>) -> Self {
+        self.source = Some(Source::Fixture(path.into()));
+        self
+    }
+    /// Follow another UFOs instance's change feed (its base URL) instead of jetstream
+    pub fn chase(mut self, upstream: impl Into<String>) -> Self {
+        self.source = Some(Source::Chase {
+            upstream: upstream.into(),
+            api_key: None,
+        });
+        self
+    }
+    /// Api key for chasing an upstream with an auth config
+    pub fn chase_key(mut self, key: impl Into<String>) -> Self {
+        if let Some(Source::Chase { api_key, .. }) = &mut self.source {
+            *api_key = Some(key.into());
+        }
+        self
+    }
+    /// Never store records from matching collections
+    ///
+    /// Takes an exact NSID or a group like `com.example.*`. Deny rules are
+    /// persisted, the same as rules added through the admin API.
+    pub fn deny(mut self, pattern: &str, keep_counts: bool) -> EncodingResult<Self> {
+        self.deny.push(DenyRule::new(pattern, keep_counts)?);
+        Ok(self)
+    }
+    /// Strip fields from records before storing them
+    pub fn redaction(mut self, redactor: Redactor) -> Self {
+        self.redaction = Some(redactor);
+        self
+    }
+    /// Run per-collection transforms on records before storing them, after redaction
+    pub fn transforms(mut self, transformer: Transformer) -> Self {
+        self.transforms = Some(transformer);
+        self
+    }
+    /// Tag records matching these watchlists as they're stored
+    pub fn watchlists(mut self, watchlists: Watchlists) -> Self {
+        self.watchlists = Some(watchlists);
+        self
+    }
+    /// POST stored records to webhooks, delivered while [`Ufos::run`] is going
+    pub fn webhooks(mut self, webhooks: Webhooks) -> Self {
+        self.webhooks = Some(webhooks);
+        self
+    }
+    /// Publish stored records and count deltas to NATS or kafka while [`Ufos::run`] is going
+    pub fn publish(mut self, bus: EventBus) -> Self {
+        self.bus = Some(bus);
+        self
+    }
+    /// Store a json diff from the previous version with updated records
+    pub fn record_diffs(mut self, record_diffs: bool) -> Self {
+        self.record_diffs = record_diffs;
+        self
+    }
+    /// Keep this many previous versions of each record in a collection
+    pub fn keep_versions(mut self, collection: Nsid, versions: usize) -> Self {
+        self.keep_versions.insert(collection, versions);
+        self
+    }
+    /// Keep this many times more sample records for collections that are read often
+    pub fn popular_trim_multiplier(mut self, multiplier: usize) -> Self {
+        self.popular_trim_multiplier = Some(multiplier);
+        self
+    }
+    /// Derive everything random from a seed, for reproducible demos and tests
+    ///
+    /// Don't use it for a db whose sketches are shared with other services.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+    /// Guard against pathologically deep or long collection NSIDs differently from the defaults
+    pub fn nsid_limits(mut self, limits: NsidLimits) -> Self {
+        self.nsid_limits = limits;
+        self
+    }
+    /// Run a plugin on every batch before it's stored, after any added before it
+    pub fn plugin(mut self, plugin: impl IngestPlugin + 'static) -> Self {
+        self.plugins.add(plugin);
+        self
+    }
+    /// Publish inserted batches for other instances to chase, keeping them this long
+    ///
+    /// Serving them at `/changes` is up to the application.
+    pub fn change_feed(mut self, retention: Duration) -> Self {
+        self.change_feed = Some(retention);
+        self
+    }
+    /// Maximum number of distinct collections in one batch of events
+    pub fn max_batched_collections(mut self, max: usize) -> Self {
+        self.max_collections = max;
+        self
+    }
+    /// Maximum number of batches to spill to disk while the writer is behind (0 to disable)
+    ///
+    /// Batches left on disk by a previous run are replayed before reconnecting.
+    pub fn max_spilled_batches(mut self, max: usize) -> Self {
+        self.max_spilled = max;
+        self
+    }
+    /// Tune background tasks for catching up on old events
+    pub fn backfill(mut self, backfill: bool) -> Self {
+        self.backfill = backfill;
+        self
+    }
+    /// Set background task intervals, instead of the defaults picked by [`backfill`](Self::backfill)
+    pub fn background_intervals(mut self, intervals: BackgroundIntervals) -> Self {
+        self.background = Some(intervals);
+        self
+    }
+    /// Reset the rollup cursor to re-process live counts
+    pub fn reroll(mut self, reroll: bool) -> Self {
+        self.reroll = reroll;
+        self
+    }
+    /// Open storage and apply filters. Nothing is consumed until [`Ufos::run`].
+    pub async fn build(self) -> Result<Ufos, StorageError> {
+        let source = self.source.ok_or(StorageError::InitError(
+            "no event source: set jetstream, fixture, or chase".to_string(),
+        ))?;
+        let (endpoint, force_endpoint) = match &source {
+            Source::Jetstream {
+                endpoint,
+                force_endpoint,
+                ..
+            } => (endpoint.clone(), *force_endpoint),
+            Source::Fixture(path) => (path.to_string_lossy().to_string(), false),
+            Source::Chase { upstream, .. } => (upstream.clone(), false),
+        };
+        if let Some(intervals) = &self.background {
+            intervals.validate().map_err(StorageError::InitError)?;
+        }
+        let (path, ephemeral, cache_bytes) = match self.storage {
+            StorageChoice::Fjall(path) => (path, false, None),
+            StorageChoice::Ephemeral { cache_bytes } => (ephemeral_dir(), true, cache_bytes),
+        };
+        #[allow(clippy::needless_update)] // `temp` exists in test builds
+        let config = FjallConfig {
+            redaction: self.redaction,
+            transforms: self.transforms,
+            watchlists: self.watchlists,
+            webhooks: self.webhooks.as_ref().map(Webhooks::tap),
+            bus: self.bus.as_ref().map(EventBus::tap),
+            record_diffs: self.record_diffs,
+            keep_versions: self.keep_versions,
+            change_feed: self.change_feed,
+            background: self.background,
+            popular_trim_multiplier: self.popular_trim_multiplier,
+            ephemeral,
+            cache_bytes,
+            seed: self.seed,
+            nsid_limits: self.nsid_limits,
+            plugins: self.plugins,
+            ..Default::default()
+        };
+        let (reader, writer, cursor, sketch_secret) =
+            FjallStorage::init(path, endpoint, force_endpoint, config)?;
+        for rule in self.deny {
+            writer.deny_collections(rule).await?;
+        }
+        Ok(Ufos {
+            reader,
+            writer,
+            cursor,
+            sketch_secret,
+            source,
+            max_collections: self.max_collections,
+            max_spilled: self.max_spilled,
+            backfill: self.backfill,
+            reroll: self.reroll,
+            webhooks: self.webhooks,
+            bus: self.bus,
+        })
+    }
+}
+
+/// A new directory under the system temp dir for an ephemeral db
+fn ephemeral_dir() -> PathBuf {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    std::env::temp_dir().join(format!("ufos-ephemeral-{}-{nanos}", std::process::id()))
+}
+
+/// An embedded UFOs instance
+pub struct Ufos {
+    reader: FjallReader,
+    writer: FjallWriter,
+    cursor: Option<Cursor>,
+    sketch_secret: SketchSecretPrefix,
+    source: Source,
+    max_collections: usize,
+    max_spilled: usize,
+    backfill: bool,
+    reroll: bool,
+    webhooks: Option<Webhooks>,
+    bus: Option<EventBus>,
+}
+
+impl Ufos {
+    /// Configure an instance with fjall storage at `data`
+    pub fn builder(data: impl Into<PathBuf>) -> UfosBuilder {
+        UfosBuilder::new(StorageChoice::Fjall(data.into()))
+    }
+    /// Configure an instance with a specific storage backend
+    pub fn builder_with_storage(storage: StorageChoice) -> UfosBuilder {
+        UfosBuilder::new(storage)
+    }
+    /// A query handle. Cheap to clone, and usable while [`Ufos::run`] is going.
+    pub fn reader(&self) -> FjallReader {
+        self.reader.clone()
+    }
+    /// Maintenance operations like purging or denying collections
+    pub fn admin(&self) -> impl StoreAdmin {
+        self.writer.clone()
+    }
+    /// Consume events and run background rollups until the consumer ends
+    pub async fn run(mut self) -> anyhow::Result<()> {
+        let batches = if let Source::Fixture(path) = self.source {
+            file_consumer::consume(path, self.sketch_secret, self.cursor, self.max_collections)
+                .await?
+        } else {
+            let spill = self.writer.spill_queue()?;
+            let cursor = if self.max_spilled > 0 {
+                spill::resume_cursor(&spill, self.cursor)?
+            } else {
+                self.cursor
+            };
+            let batches = match self.source {
+                Source::Jetstream {
+                    endpoint, no_zstd, ..
+                } => {
+                    consumer::consume(
+                        &endpoint,
+                        cursor,
+                        no_zstd,
+                        self.sketch_secret,
+                        self.max_collections,
+                    )
+                    .await?
+                }
+                Source::Chase { upstream, api_key } => {
+                    chase::consume(&upstream, api_key.as_deref(), cursor).await?
+                }
+                Source::Fixture(_) => unreachable!(),
+            };
+            spill::buffer(batches, spill, self.max_spilled, consumer::BATCH_QUEUE_SIZE)
+        };
+
+        let mut tasks: JoinSet<anyhow::Result<()>> = JoinSet::new();
+
+        let rolling = self
+            .writer
+            .background_tasks(self.reroll)?
+            .run(self.backfill);
+        tasks.spawn(async move {
+            rolling
+                .await
+                .inspect_err(|e| log::warn!("rollup ended: {e}"))?;
+            Ok(())
+        });
+
+        if let Some(webhooks) = self.webhooks {
+            tasks.spawn(async move {
+                webhooks
+                    .deliver()
+                    .await
+                    .inspect_err(|e| log::warn!("webhook delivery ended: {e}"))
+            });
+        }
+
+        if let Some(bus) = self.bus {
+            tasks.spawn(async move {
+                bus.publish()
+                    .await
+                    .inspect_err(|e| log::warn!("event bus publishing ended: {e}"))
+            });
+        }
+
+        let receiving = self.writer.receive_batches(batches);
+        let consumed = receiving
+            .await
+            .inspect_err(|e| log::warn!("consumer ended: {e}"));
+
+        tasks.shutdown().await;
+        consumed?;
+        Ok(())
+    }
+}
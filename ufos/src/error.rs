@@ -12,16 +12,51 @@ pub enum FirehoseEventError {
     CommitEventMissingCommit,
 }
 
+#[derive(Debug, Error)]
+pub enum RelayFrameError {
+    #[error("Failed to decode DAG-CBOR: {0}")]
+    Cbor(String),
+    #[error("Bad CAR file: {0}")]
+    Car(&'static str),
+    #[error("Invalid {0} in frame: {1}")]
+    Invalid(&'static str, String),
+    #[error("Relay sent an error: {error} ({message:?})")]
+    Relay {
+        error: String,
+        message: Option<String>,
+    },
+}
+
 #[derive(Debug, Error)]
 pub enum BatchInsertError {
     #[error("Batch is full and no creates are left to be truncated")]
     BatchFull(UFOsCommit),
+    #[error("Batch already has the maximum number of collections")]
+    TooManyCollections(UFOsCommit),
     #[error("Bug: tried to index beyond batch limit: {0}")]
     BatchOverflow(usize),
     #[error("Bug: non-terminating head advancement??")]
     BatchForever,
 }
 
+#[derive(Debug, Error)]
+pub enum WindowError {
+    #[error("Window bound isn't on an hour: {0}")]
+    Misaligned(#[from] EncodingError),
+    #[error("Window ends ({until}) before it starts ({since})")]
+    Backwards { since: u64, until: u64 },
+}
+
+#[derive(Debug, Error)]
+pub enum SearchError {
+    #[error("Invalid search query: {0}")]
+    BadQuery(String),
+    #[error("Search index error: {0}")]
+    Index(String),
+    #[error("Searching records needs ufos built with the \"search\" feature")]
+    NotBuilt,
+}
+
 #[derive(Debug, Error)]
 pub enum StorageError {
     #[error("Failed to initialize: {0}")]
@@ -42,4 +77,174 @@ pub enum StorageError {
     BackgroundAlreadyStarted,
     #[error("Batch sender exited")]
     BatchSenderExited,
+    #[error("RocksDB error: {0}")]
+    RocksError(String),
+    #[error("Not supported by this storage backend: {0}")]
+    Unsupported(&'static str),
+    #[error(transparent)]
+    Search(#[from] SearchError),
+}
+#[cfg(feature = "rocks")]
+impl From<rocksdb::Error> for StorageError {
+    fn from(e: rocksdb::Error) -> Self {
+        Self::RocksError(e.into_string())
+    }
+}
+
+/// Why a read query failed: the request itself, or the storage behind it
+///
+/// Everything but [`QueryError::Storage`] is caused by the query's input, and
+/// would fail again if retried as-is. So would asking a storage backend for
+/// something it doesn't support.
+#[derive(Debug, Error)]
+pub enum QueryError {
+    #[error("Invalid cursor: {0}")]
+    BadCursor(EncodingError),
+    #[error("Snapshot {0} was not found (it may have expired)")]
+    SnapshotNotFound(u64),
+    #[error("Too many pinned snapshots (max: {0})")]
+    TooManySnapshots(usize),
+    #[error("Invalid search query: {0}")]
+    BadSearchQuery(String),
+    #[error(
+        "Query needs to scan more than one request may: try a shorter window or a lower limit"
+    )]
+    OverScanBudget,
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+}
+impl QueryError {
+    /// The client asked for something that can't be served, not a server failure
+    pub fn is_client_error(&self) -> bool {
+        matches!(
+            self,
+            Self::BadCursor(_)
+                | Self::SnapshotNotFound(_)
+                | Self::BadSearchQuery(_)
+                | Self::OverScanBudget
+                | Self::Storage(StorageError::Unsupported(_))
+        )
+    }
+}
+impl From<SearchError> for QueryError {
+    fn from(e: SearchError) -> Self {
+        match e {
+            SearchError::BadQuery(q) => Self::BadSearchQuery(q),
+            e => Self::Storage(e.into()),
+        }
+    }
+}
+impl From<EncodingError> for QueryError {
+    fn from(e: EncodingError) -> Self {
+        Self::Storage(e.into())
+    }
+}
+impl From<fjall::Error> for QueryError {
+    fn from(e: fjall::Error) -> Self {
+        Self::Storage(e.into())
+    }
+}
+impl From<fjall::LsmError> for QueryError {
+    fn from(e: fjall::LsmError) -> Self {
+        Self::Storage(e.into())
+    }
+}
+#[cfg(feature = "rocks")]
+impl From<rocksdb::Error> for QueryError {
+    fn from(e: rocksdb::Error) -> Self {
+        Self::Storage(e.into())
+    }
+}
+impl From<tokio::task::JoinError> for QueryError {
+    fn from(e: tokio::task::JoinError) -> Self {
+        Self::Storage(e.into())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum AuthConfigError {
+    #[error("Failed to read auth config: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to parse auth config: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Duplicate api key (for {0:?})")]
+    DuplicateKey(String),
+    #[error("Rate limit for {0:?} must be above zero")]
+    ZeroRateLimit(String),
+    #[error("Unknown endpoint in endpoint_scopes: {0:?}")]
+    UnknownEndpoint(String),
+}
+
+#[derive(Debug, Error)]
+pub enum RedactionConfigError {
+    #[error("Failed to read redaction config: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to parse redaction config: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Invalid redaction collection {0:?}: {1}")]
+    BadCollection(String, &'static str),
+    #[error("Invalid redaction collection group: {0}")]
+    BadCollectionGroup(#[from] EncodingError),
+    #[error("Invalid redaction path: {0:?}")]
+    BadPath(String),
+}
+
+#[derive(Debug, Error)]
+pub enum TransformConfigError {
+    #[error("Failed to read transform config: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to parse transform config: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Invalid transform collection {0:?}: {1}")]
+    BadCollection(String, &'static str),
+    #[error("Invalid transform collection group: {0}")]
+    BadCollectionGroup(#[from] EncodingError),
+    #[error("Invalid path to redact: {0:?}")]
+    BadPath(String),
+    #[error("cap_size max_bytes must be above zero")]
+    ZeroCapSize,
+}
+
+#[derive(Debug, Error)]
+pub enum WatchlistConfigError {
+    #[error("Failed to read watchlist config: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to parse watchlist config: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Invalid watchlist name {0:?}: use up to 64 lowercase letters, digits, - and _")]
+    BadName(String),
+    #[error("Watchlist name {0:?} is used more than once")]
+    DuplicateName(String),
+    #[error("Invalid watchlist collection {0:?}: {1}")]
+    BadCollection(String, &'static str),
+    #[error("Invalid watchlist collection group: {0}")]
+    BadCollectionGroup(#[from] EncodingError),
+    #[error("Watchlist {0:?} has an empty keyword")]
+    EmptyKeyword(String),
+    #[error("Watchlist {0:?} has no keywords or regexes")]
+    NothingToMatch(String),
+    #[error("Invalid watchlist regex: {0}")]
+    BadRegex(#[from] regex::Error),
+    #[error("retention_days must be above zero")]
+    ZeroRetention,
+}
+
+#[derive(Debug, Error)]
+pub enum WebhookConfigError {
+    #[error("Failed to read webhook config: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to parse webhook config: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Webhook name cannot be empty")]
+    EmptyName,
+    #[error("Webhook name {0:?} is used more than once")]
+    DuplicateName(String),
+    #[error("Invalid webhook collection {0:?}: {1}")]
+    BadCollection(String, EncodingError),
+    #[error("Invalid webhook url {0:?}: {1}")]
+    BadUrl(String, String),
+    #[error("Webhook url {0:?} must be http or https")]
+    BadScheme(String),
+    #[error("Webhook {0:?} max_batch must be above zero")]
+    ZeroBatch(String),
 }
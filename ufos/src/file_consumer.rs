@@ -61,11 +61,12 @@ pub async fn consume(
     p: PathBuf,
     sketch_secret: SketchSecretPrefix,
     cursor: Option<Cursor>,
+    max_collections: usize,
 ) -> Result<Receiver<LimitedBatch>> {
     let f = File::open(p).await?;
     let (jsonl_sender, jsonl_receiver) = channel::<JetstreamEvent>(16);
     let (batch_sender, batch_reciever) = channel::<LimitedBatch>(BATCH_QUEUE_SIZE);
-    let mut batcher = Batcher::new(jsonl_receiver, batch_sender, sketch_secret);
+    let mut batcher = Batcher::new(jsonl_receiver, batch_sender, sketch_secret, max_collections);
     tokio::task::spawn(async move {
         let r = read_jsonl(f, jsonl_sender, cursor).await;
         log::warn!("read_jsonl finished: {r:?}");
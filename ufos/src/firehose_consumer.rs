@@ -0,0 +1,887 @@
+//! Consume a relay's firehose directly instead of jetstream
+//!
+//! `com.atproto.sync.subscribeRepos` frames are DAG-CBOR, with each commit's
+//! records carried in a CAR file of blocks. Every op in a commit is unpacked
+//! into its own [`JetstreamEvent`] with a JSON record, so batching and storage
+//! can't tell where events came from.
+//!
+//! Relay cursors are sequence numbers, not times. Event cursors come from the
+//! commit `time` instead (forced to always increase, and never far past our
+//! own clock), and a small checkpoint
+//! file of recent `(seq, cursor)` pairs in the data dir maps the stored cursor
+//! back to a relay seq to resume from after a restart.
+
+use crate::consumer::{Batcher, LimitedBatch, BATCH_QUEUE_SIZE};
+use crate::error::RelayFrameError;
+use crate::store_types::SketchSecretPrefix;
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine as _};
+use futures_util::StreamExt;
+use ipld_core::{cid::Cid, ipld::Ipld};
+use jetstream::events::{
+    AccountEvent, CommitEvent, CommitOp, Cursor, EventKind, IdentityEvent, JetstreamEvent,
+};
+use jetstream::exports::{Did, Handle, Nsid, RecordKey};
+use metrics::{counter, describe_counter, Unit};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio_tungstenite::tungstenite::Message;
+
+const SUBSCRIBE_REPOS: &str = "com.atproto.sync.subscribeRepos";
+const MIN_RETRY_WAIT: Duration = Duration::from_secs(1);
+const MAX_RETRY_WAIT: Duration = Duration::from_secs(30);
+const CHECKPOINT_EVERY: Duration = Duration::from_secs(10);
+/// Event times further ahead of our clock than this are pulled back to it
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(60);
+/// about 12 hours of checkpoints
+const MAX_CHECKPOINTS: usize = 4_320;
+/// Checkpoint file name, in the data dir
+pub const CHECKPOINTS_FILE: &str = "firehose-checkpoints.json";
+
+/// Start consuming a relay's firehose, eg. `wss://bsky.network`
+///
+/// With a `cursor`, resumes from the latest checkpoint at or before it and
+/// drops events the store already has. Without one (or without checkpoints),
+/// starts live.
+pub async fn consume(
+    relay: &str,
+    cursor: Option<Cursor>,
+    checkpoints_path: PathBuf,
+    sketch_secret: SketchSecretPrefix,
+    max_collections: usize,
+) -> anyhow::Result<Receiver<LimitedBatch>> {
+    describe_counter!(
+        "firehose_frames",
+        Unit::Count,
+        "frames received from the relay firehose"
+    );
+    describe_counter!(
+        "firehose_frame_errors",
+        Unit::Count,
+        "relay firehose frames that failed to decode"
+    );
+    describe_counter!(
+        "firehose_ops_missing_record",
+        Unit::Count,
+        "creates and updates dropped because their record wasn't in the commit's blocks"
+    );
+    describe_counter!(
+        "firehose_invalid_ops",
+        Unit::Count,
+        "commit ops dropped for an invalid path, collection, rkey or cid (the rest of the commit is kept)"
+    );
+    describe_counter!(
+        "firehose_future_times",
+        Unit::Count,
+        "events with a time ahead of our clock, stamped with our clock instead"
+    );
+    describe_counter!(
+        "firehose_reconnects",
+        Unit::Count,
+        "times the relay firehose connection was retried"
+    );
+    // rustls needs a crypto provider: fine if something else already installed one
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    let url = subscribe_url(relay);
+    let checkpoints = Checkpoints::load(&checkpoints_path).await?;
+    let resume = match cursor {
+        Some(cursor) => {
+            let resume = checkpoints.resume_from(cursor);
+            match resume {
+                Some(c) if c.cursor > cursor.to_raw_u64() => log::warn!(
+                    "no firehose checkpoint at or before {cursor:?}, resuming from the oldest (seq {}): events in between are missing",
+                    c.seq
+                ),
+                Some(c) => log::info!("resuming firehose from seq {} for {cursor:?}", c.seq),
+                None => log::warn!("no firehose checkpoints, starting live from {cursor:?}"),
+            }
+            resume
+        }
+        None => None,
+    };
+    let (event_sender, event_receiver) = channel::<JetstreamEvent>(16);
+    let (batch_sender, batch_receiver) = channel::<LimitedBatch>(BATCH_QUEUE_SIZE);
+    let mut batcher = Batcher::new(event_receiver, batch_sender, sketch_secret, max_collections);
+    tokio::task::spawn(async move {
+        let r = subscribe(
+            url,
+            resume,
+            cursor,
+            checkpoints,
+            checkpoints_path,
+            event_sender,
+        )
+        .await;
+        log::warn!("firehose subscription ended: {r:?}");
+    });
+    tokio::task::spawn(async move {
+        let r = batcher.run().await;
+        log::warn!("batcher finished: {r:?}");
+    });
+    Ok(batch_receiver)
+}
+
+fn subscribe_url(relay: &str) -> String {
+    let relay = relay.trim_end_matches('/');
+    if relay.ends_with(SUBSCRIBE_REPOS) {
+        relay.to_string()
+    } else {
+        format!("{relay}/xrpc/{SUBSCRIBE_REPOS}")
+    }
+}
+
+async fn subscribe(
+    url: String,
+    resume: Option<Checkpoint>,
+    skip_through: Option<Cursor>,
+    mut checkpoints: Checkpoints,
+    checkpoints_path: PathBuf,
+    sender: Sender<JetstreamEvent>,
+) -> anyhow::Result<()> {
+    let mut clock = Clock {
+        last: resume.map(|c| c.cursor).unwrap_or(0),
+    };
+    let mut seq = resume.map(|c| c.seq);
+    let mut retry_wait = MIN_RETRY_WAIT;
+    let mut last_checkpoint = Instant::now();
+    loop {
+        let from = match seq {
+            Some(seq) => format!("{url}?cursor={seq}"),
+            None => url.clone(),
+        };
+        let mut ws = match tokio_tungstenite::connect_async(&from).await {
+            Ok((ws, _)) => ws,
+            Err(e) => {
+                counter!("firehose_reconnects").increment(1);
+                log::warn!("failed to connect to {url}, retrying in {retry_wait:?}: {e}");
+                tokio::time::sleep(retry_wait).await;
+                retry_wait = (retry_wait * 2).min(MAX_RETRY_WAIT);
+                continue;
+            }
+        };
+        log::info!("connected to {url} from seq {seq:?}");
+        while let Some(message) = ws.next().await {
+            let frame = match message {
+                Ok(Message::Binary(frame)) => frame,
+                Ok(Message::Close(close)) => {
+                    log::warn!("relay closed the firehose: {close:?}");
+                    break;
+                }
+                Ok(_) => continue,
+                Err(e) => {
+                    log::warn!("firehose connection failed: {e}");
+                    break;
+                }
+            };
+            retry_wait = MIN_RETRY_WAIT;
+            counter!("firehose_frames").increment(1);
+            let (frame_seq, events) = match decode_frame(&frame, &mut clock) {
+                Ok(Frame::Events { seq, events }) => (seq, events),
+                Ok(Frame::Info { name, message }) => {
+                    log::warn!("relay info: {name} ({message:?})");
+                    continue;
+                }
+                Ok(Frame::Other) => continue,
+                Err(RelayFrameError::Relay { error, message }) => {
+                    log::warn!("relay sent an error: {error} ({message:?})");
+                    if error == "FutureCursor" {
+                        log::warn!("relay doesn't have seq {seq:?} yet, starting live");
+                        seq = None;
+                    }
+                    break;
+                }
+                Err(e) => {
+                    counter!("firehose_frame_errors").increment(1);
+                    log::warn!("failed to decode firehose frame: {e}");
+                    continue;
+                }
+            };
+            seq = Some(frame_seq);
+            for event in events {
+                if skip_through.is_some_and(|c| event.cursor <= c) {
+                    continue;
+                }
+                sender
+                    .send(event)
+                    .await
+                    .map_err(|_| anyhow::anyhow!("event receiver closed"))?;
+            }
+            if last_checkpoint.elapsed() >= CHECKPOINT_EVERY {
+                checkpoints.push(Checkpoint {
+                    seq: frame_seq,
+                    cursor: clock.last,
+                });
+                if let Err(e) = checkpoints.save(&checkpoints_path).await {
+                    log::warn!("failed to save firehose checkpoints: {e}");
+                }
+                last_checkpoint = Instant::now();
+            }
+        }
+        counter!("firehose_reconnects").increment(1);
+        tokio::time::sleep(retry_wait).await;
+        retry_wait = (retry_wait * 2).min(MAX_RETRY_WAIT);
+    }
+}
+
+/// A relay seq and the cursor of the last event from it or anything before it
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct Checkpoint {
+    seq: u64,
+    cursor: u64,
+}
+
+/// Recent checkpoints, oldest first
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Checkpoints(VecDeque<Checkpoint>);
+
+impl Checkpoints {
+    async fn load(path: &Path) -> anyhow::Result<Self> {
+        match tokio::fs::read(path).await {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+    async fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let tmp = path.with_extension("json.tmp");
+        tokio::fs::write(&tmp, serde_json::to_vec(self)?).await?;
+        tokio::fs::rename(&tmp, path).await?;
+        Ok(())
+    }
+    fn push(&mut self, checkpoint: Checkpoint) {
+        self.0.push_back(checkpoint);
+        while self.0.len() > MAX_CHECKPOINTS {
+            self.0.pop_front();
+        }
+    }
+    /// The latest checkpoint at or before a cursor, or else the oldest one
+    fn resume_from(&self, cursor: Cursor) -> Option<Checkpoint> {
+        let cursor = cursor.to_raw_u64();
+        self.0
+            .iter()
+            .rev()
+            .find(|c| c.cursor <= cursor)
+            .or(self.0.front())
+            .copied()
+    }
+}
+
+/// Hands out strictly increasing cursors from event times
+struct Clock {
+    last: u64,
+}
+
+impl Clock {
+    fn stamp(&mut self, time_us: u64) -> Cursor {
+        let now_us = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        self.stamp_at(time_us, now_us)
+    }
+    /// Event times come from the PDS, so one far in the future would drag
+    /// every later cursor along with it: don't trust them past `now_us`
+    fn stamp_at(&mut self, time_us: u64, now_us: u64) -> Cursor {
+        let limit = now_us + MAX_CLOCK_SKEW.as_micros() as u64;
+        if time_us > limit {
+            counter!("firehose_future_times").increment(1);
+        }
+        self.last = time_us.min(limit).max(self.last + 1);
+        Cursor::from_raw_u64(self.last)
+    }
+}
+
+#[derive(Debug)]
+enum Frame {
+    Events {
+        seq: u64,
+        events: Vec<JetstreamEvent>,
+    },
+    Info {
+        name: String,
+        message: Option<String>,
+    },
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+struct FrameHeader {
+    op: i64,
+    t: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorBody {
+    error: String,
+    message: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct InfoBody {
+    name: String,
+    message: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CommitBody {
+    seq: u64,
+    repo: String,
+    rev: String,
+    #[serde(with = "serde_bytes")]
+    blocks: Vec<u8>,
+    ops: Vec<RepoOp>,
+    time: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RepoOp {
+    action: String,
+    path: String,
+    cid: Option<Cid>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IdentityBody {
+    seq: u64,
+    did: String,
+    time: String,
+    handle: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AccountBody {
+    seq: u64,
+    did: String,
+    time: String,
+    active: bool,
+    status: Option<String>,
+}
+
+fn cbor_err(e: impl std::fmt::Display) -> RelayFrameError {
+    RelayFrameError::Cbor(e.to_string())
+}
+
+fn decode_frame(frame: &[u8], clock: &mut Clock) -> Result<Frame, RelayFrameError> {
+    let mut reader = io::Cursor::new(frame);
+    let header: FrameHeader =
+        serde_ipld_dagcbor::de::from_reader_once(&mut reader).map_err(cbor_err)?;
+    let body = &frame[reader.position() as usize..];
+    if header.op == -1 {
+        let ErrorBody { error, message } =
+            serde_ipld_dagcbor::from_slice(body).map_err(cbor_err)?;
+        return Err(RelayFrameError::Relay { error, message });
+    }
+    match header.t.as_deref() {
+        Some("#commit") => {
+            let commit: CommitBody = serde_ipld_dagcbor::from_slice(body).map_err(cbor_err)?;
+            let seq = commit.seq;
+            let events = commit_events(commit, clock)?;
+            Ok(Frame::Events { seq, events })
+        }
+        Some("#identity") => {
+            let identity: IdentityBody = serde_ipld_dagcbor::from_slice(body).map_err(cbor_err)?;
+            let did = parse_did(identity.did)?;
+            let time = parse_time(&identity.time);
+            let handle = identity
+                .handle
+                .map(Handle::new)
+                .transpose()
+                .map_err(|e| RelayFrameError::Invalid("handle", e.to_string()))?;
+            let event = JetstreamEvent {
+                cursor: clock.stamp(time.timestamp_micros() as u64),
+                did: did.clone(),
+                kind: EventKind::Identity,
+                commit: None,
+                identity: Some(IdentityEvent {
+                    did,
+                    handle,
+                    seq: identity.seq,
+                    time,
+                }),
+                account: None,
+            };
+            Ok(Frame::Events {
+                seq: identity.seq,
+                events: vec![event],
+            })
+        }
+        Some("#account") => {
+            let account: AccountBody = serde_ipld_dagcbor::from_slice(body).map_err(cbor_err)?;
+            let did = parse_did(account.did)?;
+            let time = parse_time(&account.time);
+            let event = JetstreamEvent {
+                cursor: clock.stamp(time.timestamp_micros() as u64),
+                did: did.clone(),
+                kind: EventKind::Account,
+                commit: None,
+                identity: None,
+                account: Some(AccountEvent {
+                    active: account.active,
+                    did,
+                    seq: account.seq,
+                    time,
+                    status: account.status,
+                }),
+            };
+            Ok(Frame::Events {
+                seq: account.seq,
+                events: vec![event],
+            })
+        }
+        Some("#info") => {
+            let InfoBody { name, message } =
+                serde_ipld_dagcbor::from_slice(body).map_err(cbor_err)?;
+            Ok(Frame::Info { name, message })
+        }
+        _ => Ok(Frame::Other), // #sync, and anything newer
+    }
+}
+
+fn commit_events(
+    commit: CommitBody,
+    clock: &mut Clock,
+) -> Result<Vec<JetstreamEvent>, RelayFrameError> {
+    let did = parse_did(commit.repo)?;
+    let time_us = parse_time(&commit.time).timestamp_micros() as u64;
+    let blocks = read_car(&commit.blocks)?;
+    let mut events = Vec::with_capacity(commit.ops.len());
+    for op in &commit.ops {
+        // one bad op shouldn't cost the rest of the commit
+        let commit_event = match op_event(op, &commit.rev, &blocks) {
+            Ok(Some(commit_event)) => commit_event,
+            Ok(None) => continue,
+            Err(e) => {
+                counter!("firehose_invalid_ops").increment(1);
+                log::debug!("skipping invalid op {:?} from {did:?}: {e}", op.path);
+                continue;
+            }
+        };
+        events.push(JetstreamEvent {
+            cursor: clock.stamp(time_us),
+            did: did.clone(),
+            kind: EventKind::Commit,
+            commit: Some(commit_event),
+            identity: None,
+            account: None,
+        });
+    }
+    Ok(events)
+}
+
+/// Unpack one op, or `None` for ops that are skipped on purpose
+fn op_event(
+    op: &RepoOp,
+    rev: &str,
+    blocks: &HashMap<Cid, &[u8]>,
+) -> Result<Option<CommitEvent>, RelayFrameError> {
+    let (collection, rkey) = op
+        .path
+        .split_once('/')
+        .ok_or_else(|| RelayFrameError::Invalid("op path", op.path.clone()))?;
+    let collection = Nsid::new(collection.to_string())
+        .map_err(|e| RelayFrameError::Invalid("collection", e.to_string()))?;
+    let rkey = RecordKey::new(rkey.to_string())
+        .map_err(|e| RelayFrameError::Invalid("rkey", e.to_string()))?;
+    let operation = match op.action.as_str() {
+        "create" => CommitOp::Create,
+        "update" => CommitOp::Update,
+        "delete" => CommitOp::Delete,
+        _ => return Ok(None),
+    };
+    let record = match (&operation, &op.cid) {
+        (CommitOp::Delete, _) => None,
+        (_, Some(cid)) => match blocks.get(cid) {
+            Some(block) => Some(record_json(block)?),
+            None => {
+                // tooBig commits leave blocks out
+                counter!("firehose_ops_missing_record").increment(1);
+                return Ok(None);
+            }
+        },
+        (_, None) => return Err(RelayFrameError::Invalid("op cid", op.path.clone())),
+    };
+    let cid = op
+        .cid
+        .map(|cid| cid.to_string().parse())
+        .transpose()
+        .map_err(|e| RelayFrameError::Invalid("cid", format!("{e:?}")))?;
+    Ok(Some(CommitEvent {
+        collection,
+        rkey,
+        rev: rev.to_string(),
+        operation,
+        record,
+        cid,
+    }))
+}
+
+fn parse_did(did: String) -> Result<Did, RelayFrameError> {
+    Did::new(did).map_err(|e| RelayFrameError::Invalid("did", e.to_string()))
+}
+
+/// A bad timestamp shouldn't lose the event, so it falls back to now
+fn parse_time(time: &str) -> chrono::DateTime<chrono::Utc> {
+    chrono::DateTime::parse_from_rfc3339(time)
+        .map(|t| t.to_utc())
+        .unwrap_or_else(|e| {
+            log::debug!("bad firehose event time {time:?} ({e}), using now");
+            SystemTime::now().into()
+        })
+}
+
+/// Read an unsigned LEB128 varint, as used for CAR section lengths
+fn read_varint(reader: &mut impl Read) -> Result<Option<u64>, RelayFrameError> {
+    let mut value = 0u64;
+    for i in 0..10 {
+        let mut byte = [0u8];
+        if reader
+            .read(&mut byte)
+            .map_err(|_| RelayFrameError::Car("unreadable varint"))?
+            == 0
+        {
+            return if i == 0 {
+                Ok(None)
+            } else {
+                Err(RelayFrameError::Car("truncated varint"))
+            };
+        }
+        value |= u64::from(byte[0] & 0x7f) << (7 * i);
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(value));
+        }
+    }
+    Err(RelayFrameError::Car("varint too long"))
+}
+
+/// Read a CARv1 file's blocks by CID
+///
+/// The header (version and roots) is skipped: commit ops point at their
+/// record blocks directly.
+fn read_car(car: &[u8]) -> Result<HashMap<Cid, &[u8]>, RelayFrameError> {
+    let mut reader = io::Cursor::new(car);
+    let mut blocks = HashMap::new();
+    let mut header = true;
+    while let Some(len) = read_varint(&mut reader)? {
+        let start = reader.position() as usize;
+        let end = start
+            .checked_add(len as usize)
+            .filter(|end| *end <= car.len())
+            .ok_or(RelayFrameError::Car("section overflows the file"))?;
+        if std::mem::take(&mut header) {
+            reader.set_position(end as u64);
+            continue;
+        }
+        let mut section = io::Cursor::new(&car[start..end]);
+        let cid =
+            Cid::read_bytes(&mut section).map_err(|_| RelayFrameError::Car("bad block cid"))?;
+        let data_start = start + section.position() as usize;
+        blocks.insert(cid, &car[data_start..end]);
+        reader.set_position(end as u64);
+    }
+    if header {
+        return Err(RelayFrameError::Car("missing header"));
+    }
+    Ok(blocks)
+}
+
+/// Convert a DAG-CBOR record block to atproto JSON
+fn record_json(block: &[u8]) -> Result<Box<serde_json::value::RawValue>, RelayFrameError> {
+    let ipld: Ipld = serde_ipld_dagcbor::from_slice(block).map_err(cbor_err)?;
+    serde_json::value::to_raw_value(&ipld_to_json(ipld))
+        .map_err(|e| RelayFrameError::Invalid("record", e.to_string()))
+}
+
+/// Links become `{"$link": cid}` and bytes `{"$bytes": base64}`, like the atproto JSON data model
+fn ipld_to_json(ipld: Ipld) -> Value {
+    match ipld {
+        Ipld::Null => Value::Null,
+        Ipld::Bool(b) => Value::Bool(b),
+        Ipld::Integer(i) => i64::try_from(i)
+            .map(Value::from)
+            .or_else(|_| u64::try_from(i).map(Value::from))
+            .unwrap_or(Value::Null),
+        Ipld::Float(f) => serde_json::Number::from_f64(f)
+            .map(Value::Number)
+            .unwrap_or(Value::Null),
+        Ipld::String(s) => Value::String(s),
+        Ipld::Bytes(b) => json!({ "$bytes": STANDARD_NO_PAD.encode(b) }),
+        Ipld::List(items) => Value::Array(items.into_iter().map(ipld_to_json).collect()),
+        Ipld::Map(entries) => Value::Object(
+            entries
+                .into_iter()
+                .map(|(k, v)| (k, ipld_to_json(v)))
+                .collect::<Map<_, _>>(),
+        ),
+        Ipld::Link(cid) => json!({ "$link": cid.to_string() }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ipld_core::cid::multihash::Multihash;
+    use std::collections::BTreeMap;
+
+    fn varint(mut n: u64, out: &mut Vec<u8>) {
+        loop {
+            let byte = (n & 0x7f) as u8;
+            n >>= 7;
+            if n == 0 {
+                out.push(byte);
+                return;
+            }
+            out.push(byte | 0x80);
+        }
+    }
+
+    fn cid(seed: u8) -> Cid {
+        Cid::new_v1(0x71, Multihash::wrap(0x12, &[seed; 32]).unwrap())
+    }
+
+    fn map<const N: usize>(entries: [(&str, Ipld); N]) -> Ipld {
+        Ipld::Map(BTreeMap::from(entries.map(|(k, v)| (k.to_string(), v))))
+    }
+
+    fn car(blocks: &[(Cid, Vec<u8>)]) -> Vec<u8> {
+        let header = serde_ipld_dagcbor::to_vec(&map([
+            ("version", Ipld::Integer(1)),
+            ("roots", Ipld::List(vec![Ipld::Link(cid(0))])),
+        ]))
+        .unwrap();
+        let mut out = vec![];
+        varint(header.len() as u64, &mut out);
+        out.extend(header);
+        for (cid, data) in blocks {
+            let cid = cid.to_bytes();
+            varint((cid.len() + data.len()) as u64, &mut out);
+            out.extend(cid);
+            out.extend(data);
+        }
+        out
+    }
+
+    fn frame(t: &str, body: Ipld) -> Vec<u8> {
+        let mut out = serde_ipld_dagcbor::to_vec(&map([
+            ("op", Ipld::Integer(1)),
+            ("t", Ipld::String(t.to_string())),
+        ]))
+        .unwrap();
+        out.extend(serde_ipld_dagcbor::to_vec(&body).unwrap());
+        out
+    }
+
+    #[test]
+    fn test_read_varint() {
+        for n in [0, 1, 127, 128, 300, 16_384, u32::MAX as u64] {
+            let mut bytes = vec![];
+            varint(n, &mut bytes);
+            assert_eq!(read_varint(&mut bytes.as_slice()).unwrap(), Some(n));
+        }
+        let mut empty: &[u8] = &[];
+        assert_eq!(read_varint(&mut empty).unwrap(), None);
+        let mut truncated: &[u8] = &[0x80];
+        assert!(read_varint(&mut truncated).is_err());
+    }
+
+    #[test]
+    fn test_read_car() {
+        let bytes = car(&[(cid(1), vec![1, 2, 3]), (cid(2), vec![])]);
+        let blocks = read_car(&bytes).unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[&cid(1)], &[1, 2, 3]);
+        assert_eq!(blocks[&cid(2)], &[] as &[u8]);
+
+        assert!(read_car(&bytes[..bytes.len() - 1]).is_err());
+        assert!(read_car(&[]).is_err());
+    }
+
+    #[test]
+    fn test_ipld_to_json() {
+        let ipld = map([
+            ("$type", Ipld::String("app.bsky.feed.like".into())),
+            ("n", Ipld::Integer(-3)),
+            ("ok", Ipld::Bool(true)),
+            ("sig", Ipld::Bytes(vec![0xff, 0x00])),
+            ("ref", Ipld::Link(cid(1))),
+            ("list", Ipld::List(vec![Ipld::Null])),
+        ]);
+        assert_eq!(
+            ipld_to_json(ipld),
+            json!({
+                "$type": "app.bsky.feed.like",
+                "n": -3,
+                "ok": true,
+                "sig": { "$bytes": "/wA" },
+                "ref": { "$link": cid(1).to_string() },
+                "list": [null],
+            })
+        );
+    }
+
+    #[test]
+    fn test_decode_commit_frame() {
+        let record = serde_ipld_dagcbor::to_vec(&map([
+            ("$type", Ipld::String("app.bsky.feed.like".into())),
+            ("subject", map([("cid", Ipld::Link(cid(9)))])),
+        ]))
+        .unwrap();
+        let body = map([
+            ("seq", Ipld::Integer(42)),
+            ("repo", Ipld::String("did:plc:abc".into())),
+            ("rev", Ipld::String("3lrev".into())),
+            ("time", Ipld::String("2025-01-01T00:00:00.000Z".into())),
+            ("blocks", Ipld::Bytes(car(&[(cid(1), record)]))),
+            (
+                "ops",
+                Ipld::List(vec![
+                    map([
+                        ("action", Ipld::String("create".into())),
+                        ("path", Ipld::String("app.bsky.feed.like/a".into())),
+                        ("cid", Ipld::Link(cid(1))),
+                    ]),
+                    map([
+                        ("action", Ipld::String("delete".into())),
+                        ("path", Ipld::String("app.bsky.feed.like/b".into())),
+                        ("cid", Ipld::Null),
+                    ]),
+                    map([
+                        // invalid rkey: skipped alone
+                        ("action", Ipld::String("create".into())),
+                        ("path", Ipld::String("app.bsky.feed.like/no spaces".into())),
+                        ("cid", Ipld::Link(cid(1))),
+                    ]),
+                    map([
+                        // not in the blocks (tooBig)
+                        ("action", Ipld::String("update".into())),
+                        ("path", Ipld::String("app.bsky.feed.like/c".into())),
+                        ("cid", Ipld::Link(cid(2))),
+                    ]),
+                ]),
+            ),
+        ]);
+        let mut clock = Clock { last: 0 };
+        let Frame::Events { seq, events } =
+            decode_frame(&frame("#commit", body), &mut clock).unwrap()
+        else {
+            panic!("expected events");
+        };
+        assert_eq!(seq, 42);
+        assert_eq!(events.len(), 2);
+
+        let t = 1_735_689_600_000_000;
+        assert_eq!(events[0].cursor, Cursor::from_raw_u64(t));
+        assert_eq!(events[1].cursor, Cursor::from_raw_u64(t + 1));
+        assert_eq!(events[0].did.as_str(), "did:plc:abc");
+
+        let create = events[0].commit.as_ref().unwrap();
+        assert_eq!(create.collection.as_str(), "app.bsky.feed.like");
+        assert_eq!(create.rkey.as_str(), "a");
+        assert_eq!(create.operation, CommitOp::Create);
+        assert_eq!(
+            serde_json::from_str::<Value>(create.record.as_ref().unwrap().get()).unwrap(),
+            json!({
+                "$type": "app.bsky.feed.like",
+                "subject": { "cid": { "$link": cid(9).to_string() } },
+            })
+        );
+
+        let delete = events[1].commit.as_ref().unwrap();
+        assert_eq!(delete.rkey.as_str(), "b");
+        assert_eq!(delete.operation, CommitOp::Delete);
+        assert!(delete.record.is_none());
+    }
+
+    #[test]
+    fn test_decode_account_and_error_frames() {
+        let body = map([
+            ("seq", Ipld::Integer(7)),
+            ("did", Ipld::String("did:plc:abc".into())),
+            ("time", Ipld::String("2025-01-01T00:00:00Z".into())),
+            ("active", Ipld::Bool(false)),
+            ("status", Ipld::String("deleted".into())),
+        ]);
+        // cursors keep increasing even when times don't
+        let mut clock = Clock {
+            last: 1_735_689_600_000_000,
+        };
+        let Frame::Events { seq, events } =
+            decode_frame(&frame("#account", body), &mut clock).unwrap()
+        else {
+            panic!("expected events");
+        };
+        assert_eq!(seq, 7);
+        assert_eq!(events[0].kind, EventKind::Account);
+        assert_eq!(
+            events[0].cursor,
+            Cursor::from_raw_u64(1_735_689_600_000_001)
+        );
+        assert!(!events[0].account.as_ref().unwrap().active);
+
+        let mut error = serde_ipld_dagcbor::to_vec(&map([("op", Ipld::Integer(-1))])).unwrap();
+        error.extend(
+            serde_ipld_dagcbor::to_vec(&map([("error", Ipld::String("FutureCursor".into()))]))
+                .unwrap(),
+        );
+        assert!(matches!(
+            decode_frame(&error, &mut clock),
+            Err(RelayFrameError::Relay { error, .. }) if error == "FutureCursor"
+        ));
+
+        let sync = frame("#sync", map([("seq", Ipld::Integer(8))]));
+        assert!(matches!(decode_frame(&sync, &mut clock), Ok(Frame::Other)));
+    }
+
+    #[test]
+    fn test_clock_clamps_future_times() {
+        let now = 1_735_689_600_000_000;
+        let skew = MAX_CLOCK_SKEW.as_micros() as u64;
+        let mut clock = Clock { last: 0 };
+        assert_eq!(clock.stamp_at(now - 5, now), Cursor::from_raw_u64(now - 5));
+        // a year ahead
+        let future = now + 365 * 86_400 * 1_000_000;
+        assert_eq!(
+            clock.stamp_at(future, now),
+            Cursor::from_raw_u64(now + skew)
+        );
+        // later events aren't dragged along
+        assert_eq!(
+            clock.stamp_at(now + 2_000_000 + skew, now + 2_000_000),
+            Cursor::from_raw_u64(now + 2_000_000 + skew)
+        );
+    }
+
+    #[test]
+    fn test_checkpoint_resume() {
+        let mut checkpoints = Checkpoints::default();
+        assert_eq!(checkpoints.resume_from(Cursor::from_raw_u64(5)), None);
+        for (seq, cursor) in [(10, 100), (20, 200), (30, 300)] {
+            checkpoints.push(Checkpoint { seq, cursor });
+        }
+        let resume = |c| {
+            checkpoints
+                .resume_from(Cursor::from_raw_u64(c))
+                .unwrap()
+                .seq
+        };
+        assert_eq!(resume(250), 20);
+        assert_eq!(resume(300), 30);
+        assert_eq!(resume(999), 30);
+        assert_eq!(resume(50), 10); // nothing earlier: oldest
+    }
+
+    #[test]
+    fn test_subscribe_url() {
+        assert_eq!(
+            subscribe_url("wss://bsky.network/"),
+            "wss://bsky.network/xrpc/com.atproto.sync.subscribeRepos"
+        );
+        assert_eq!(
+            subscribe_url("wss://relay.example/xrpc/com.atproto.sync.subscribeRepos"),
+            "wss://relay.example/xrpc/com.atproto.sync.subscribeRepos"
+        );
+    }
+}
@@ -0,0 +1,258 @@
+//! Approximate per-account record volume, for finding accounts that write far
+//! more than anyone else in a collection
+
+use crate::plugin::{CollectionBatch, IngestPlugin};
+use crate::store_types::{HourTruncatedCursor, HOUR_IN_MICROS};
+use crate::{Did, Nsid};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+/// How many of each collection's busiest DIDs a batch keeps counts for
+pub const BATCH_TOP_DIDS: usize = 32;
+/// How many of each collection's busiest DIDs are kept per hour
+pub const HOURLY_TOP_DIDS: usize = 128;
+/// How many hours of per-DID volume are kept in memory
+pub const MAX_NOISY_HOURS: usize = 24;
+
+/// Approximate counts of the most frequent keys, in bounded memory
+///
+/// Keeps up to `2 * CAPACITY` counters. When it's full, all but the largest
+/// `CAPACITY` are dropped, and keys counted after that start from the largest
+/// dropped count. So a key's true count is somewhere in `count - error ..= count`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound(deserialize = "K: Deserialize<'de> + Hash + Eq"))]
+pub struct HeavyHitters<K, const CAPACITY: usize> {
+    /// key => (count, error)
+    counts: HashMap<K, (u64, u64)>,
+    /// the most a key that isn't counted could have been seen
+    floor: u64,
+}
+
+impl<K, const CAPACITY: usize> Default for HeavyHitters<K, CAPACITY> {
+    fn default() -> Self {
+        Self {
+            counts: HashMap::new(),
+            floor: 0,
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, const CAPACITY: usize> HeavyHitters<K, CAPACITY> {
+    pub fn add(&mut self, key: K, n: u64) {
+        let floor = self.floor;
+        let (count, _) = self.counts.entry(key).or_insert((floor, floor));
+        *count += n;
+        self.prune_if_full();
+    }
+
+    /// Fold in counts kept separately, eg. for another batch
+    pub fn merge<const OTHER: usize>(&mut self, other: &HeavyHitters<K, OTHER>) {
+        for (key, (count, error)) in self.counts.iter_mut() {
+            if !other.counts.contains_key(key) {
+                // it could have been just under the other's cutoff
+                *count += other.floor;
+                *error += other.floor;
+            }
+        }
+        for (key, (count, error)) in &other.counts {
+            let (c, e) = self
+                .counts
+                .entry(key.clone())
+                .or_insert((self.floor, self.floor));
+            *c += count;
+            *e += error;
+        }
+        self.floor += other.floor;
+        self.prune_if_full();
+    }
+
+    /// The most counted keys, biggest first, with their (count, error)
+    pub fn top(&self, limit: usize) -> Vec<(K, u64, u64)> {
+        let mut top: Vec<_> = self
+            .counts
+            .iter()
+            .map(|(k, (count, error))| (k.clone(), *count, *error))
+            .collect();
+        top.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+        top.truncate(limit);
+        top
+    }
+
+    fn prune_if_full(&mut self) {
+        if self.counts.len() <= CAPACITY * 2 {
+            return;
+        }
+        let mut counts: Vec<u64> = self.counts.values().map(|(c, _)| *c).collect();
+        counts.sort_unstable_by(|a, b| b.cmp(a));
+        let cutoff = counts[CAPACITY];
+        let mut kept = 0;
+        self.counts.retain(|_, (count, _)| {
+            // ties at the cutoff go too, so this always makes room
+            let keep = *count > cutoff && kept < CAPACITY;
+            kept += keep as usize;
+            keep
+        });
+        self.floor = self.floor.max(cutoff);
+    }
+}
+
+/// One account's share of a collection's records
+#[derive(Debug, PartialEq, Serialize, JsonSchema)]
+pub struct NoisyDid {
+    pub collection: String,
+    pub did: String,
+    /// Records (creates, updates, and deletes) from this DID in the period, at most
+    pub records: u64,
+    /// How much `records` might be overcounted by
+    pub error: u64,
+    /// All records in the collection in the period
+    pub collection_records: u64,
+    /// This DID's fraction of the collection's records, counting `records - error`
+    pub share: f64,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct NoisyDidsReport {
+    /// The start of the earliest hour counted, in microseconds since the epoch
+    pub since: Option<u64>,
+    /// How many hours with any events were counted
+    pub hours: usize,
+    /// Biggest share first
+    pub dids: Vec<NoisyDid>,
+}
+
+#[derive(Debug, Default)]
+struct CollectionVolume {
+    total: u64,
+    dids: HeavyHitters<Did, HOURLY_TOP_DIDS>,
+}
+
+/// Hourly per-DID record volume for every collection, in memory
+///
+/// A built-in [`IngestPlugin`], shared by the writer, which adds each batch,
+/// and the admin API. Lost on restart.
+#[derive(Debug, Clone, Default)]
+pub struct NoisyDids(Arc<Mutex<VecDeque<(HourTruncatedCursor, HashMap<Nsid, CollectionVolume>)>>>);
+
+impl IngestPlugin for NoisyDids {
+    fn name(&self) -> &'static str {
+        "noisy_dids"
+    }
+    fn collection(&self, hour: HourTruncatedCursor, collection: &Nsid, batch: CollectionBatch) {
+        let mut hours = self.0.lock().unwrap();
+        if hours.back().is_none_or(|(h, _)| *h < hour) {
+            hours.push_back((hour, HashMap::new()));
+            while hours.len() > MAX_NOISY_HOURS {
+                hours.pop_front();
+            }
+        }
+        // a batch from slightly behind the latest hour (eg. a lagging shard)
+        // still goes in with the latest one
+        let (_, collections) = hours.back_mut().unwrap();
+        let volume = collections.entry(collection.clone()).or_default();
+        volume.total += (batch.creates + batch.updates + batch.deletes) as u64;
+        volume.dids.merge(batch.top_dids);
+    }
+}
+
+impl NoisyDids {
+    /// DIDs with at least `min_records` and `min_share` of a collection's
+    /// records over the latest `hours` hours
+    pub fn report(
+        &self,
+        hours: usize,
+        min_records: u64,
+        min_share: f64,
+        limit: usize,
+    ) -> NoisyDidsReport {
+        let tracked = self.0.lock().unwrap();
+        let mut merged: HashMap<&Nsid, CollectionVolume> = HashMap::new();
+        let mut since = None;
+        let Some((latest, _)) = tracked.back() else {
+            return NoisyDidsReport {
+                since: None,
+                hours: 0,
+                dids: vec![],
+            };
+        };
+        let earliest = latest
+            .to_raw_u64()
+            .saturating_sub((hours as u64 - 1) * HOUR_IN_MICROS);
+        let mut counted = 0;
+        for (hour, collections) in tracked.iter().filter(|(h, _)| h.to_raw_u64() >= earliest) {
+            since = since.or(Some(hour.to_raw_u64()));
+            counted += 1;
+            for (nsid, volume) in collections {
+                let m = merged.entry(nsid).or_default();
+                m.total += volume.total;
+                m.dids.merge(&volume.dids);
+            }
+        }
+        let mut dids = Vec::new();
+        for (nsid, volume) in merged {
+            for (did, records, error) in volume.dids.top(limit) {
+                let share = (records - error) as f64 / volume.total.max(1) as f64;
+                if records < min_records || share < min_share {
+                    continue;
+                }
+                dids.push(NoisyDid {
+                    collection: nsid.to_string(),
+                    did: did.to_string(),
+                    records,
+                    error,
+                    collection_records: volume.total,
+                    share,
+                });
+            }
+        }
+        dids.sort_unstable_by(|a, b| b.share.total_cmp(&a.share));
+        dids.truncate(limit);
+        NoisyDidsReport {
+            since,
+            hours: counted,
+            dids,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heavy_hitters_keeps_the_heaviest() {
+        let mut hh = HeavyHitters::<u32, 4>::default();
+        for i in 0..1000u32 {
+            hh.add(i % 100, 1); // background noise: 10 each
+            if i % 2 == 0 {
+                hh.add(1000, 1); // 500
+            }
+            if i % 5 == 0 {
+                hh.add(2000, 1); // 200
+            }
+        }
+        let top = hh.top(2);
+        assert_eq!(top[0].0, 1000);
+        assert_eq!(top[1].0, 2000);
+        for (_, count, error) in [top[0], top[1]] {
+            assert!(count >= error);
+        }
+        assert!(top[0].1 >= 500 && top[0].1 - top[0].2 <= 500);
+        assert!(top[1].1 >= 200 && top[1].1 - top[1].2 <= 200);
+    }
+
+    #[test]
+    fn test_heavy_hitters_merge() {
+        let mut a = HeavyHitters::<&str, 2>::default();
+        a.add("x", 10);
+        a.add("y", 3);
+        let mut b = HeavyHitters::<&str, 2>::default();
+        b.add("x", 5);
+        b.add("z", 7);
+        a.merge(&b);
+        assert_eq!(a.top(3), vec![("x", 15, 0), ("z", 7, 0), ("y", 3, 0)]);
+    }
+}
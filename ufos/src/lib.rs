@@ -1,29 +1,60 @@
+pub mod cache;
+pub mod chase;
 pub mod consumer;
 pub mod db_types;
+pub mod denylist;
+pub mod diff;
+pub mod embed;
 pub mod error;
 pub mod file_consumer;
+#[cfg(feature = "firehose")]
+pub mod firehose_consumer;
+pub mod heavy_hitters;
 pub mod index_html;
+pub mod nsid_limits;
+pub mod plugin;
+pub mod publish;
+pub mod redaction;
+pub mod search;
 pub mod server;
+pub mod simhash;
+pub mod spill;
 pub mod storage;
+#[cfg(test)]
+mod storage_chaos;
 pub mod storage_fjall;
+#[cfg(feature = "rocks")]
+pub mod storage_rocks;
 pub mod store_types;
+pub mod transform;
+pub mod watchlist;
+pub mod webhook;
 
 use crate::db_types::{EncodingError, EncodingResult};
 use crate::error::BatchInsertError;
+use crate::heavy_hitters::{HeavyHitters, BATCH_TOP_DIDS};
 use crate::store_types::{CountsValue, SketchSecretPrefix};
-use cardinality_estimator_safe::{Element, Sketch};
+use cardinality_estimator_safe::Element;
 use error::FirehoseEventError;
 use jetstream::events::{CommitEvent, CommitOp, Cursor};
 use jetstream::exports::{Did, Nsid, RecordKey};
+use microcosm_estimates::DidsSketch;
 use schemars::JsonSchema;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use serde_json::value::RawValue;
-use sha2::Sha256;
 use std::collections::HashMap;
 use std::time::Duration;
 
 fn did_element(sketch_secret: &SketchSecretPrefix, did: &Did) -> Element<14> {
-    Element::from_digest_with_prefix::<Sha256>(sketch_secret, did.as_bytes())
+    microcosm_estimates::did_element(sketch_secret, did)
+}
+
+/// A stable pseudo-random u64 for the nth draw from a seed (splitmix64)
+pub fn splitmix64(seed: u64, n: u64) -> u64 {
+    let mut z = seed ^ n.wrapping_mul(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
 }
 
 pub fn nice_duration(dt: Duration) -> String {
@@ -54,12 +85,25 @@ pub struct CollectionCommits<const LIMIT: usize> {
     pub creates: usize,
     pub updates: usize,
     pub deletes: usize,
-    pub dids_estimate: Sketch<14>,
+    pub dids_estimate: DidsSketch,
+    /// the busiest accounts, counting every commit (even displaced ones)
+    pub top_dids: HeavyHitters<Did, BATCH_TOP_DIDS>,
     pub commits: Vec<UFOsCommit>,
     head: usize,
 }
 
 impl<const LIMIT: usize> CollectionCommits<LIMIT> {
+    /// take in another collection's counts, but not its commits
+    ///
+    /// the commits belong to their own collection's records: moving them would
+    /// mix up records from different collections that share a did and rkey.
+    pub fn absorb_counts(&mut self, other: &Self) {
+        self.creates += other.creates;
+        self.updates += other.updates;
+        self.deletes += other.deletes;
+        self.dids_estimate.merge(&other.dids_estimate);
+        self.top_dids.merge(&other.top_dids);
+    }
     fn advance_head(&mut self) {
         self.head += 1;
         if self.head > LIMIT {
@@ -91,6 +135,7 @@ impl<const LIMIT: usize> CollectionCommits<LIMIT> {
         // every kind of commit counts as "user activity"
         self.dids_estimate
             .insert(did_element(sketch_secret, &commit.did));
+        self.top_dids.add(commit.did.clone(), 1);
 
         match commit.action {
             CommitAction::Put(PutAction {
@@ -152,6 +197,9 @@ impl CommitAction {
             CommitAction::Cut => false,
         }
     }
+    pub fn is_cut(&self) -> bool {
+        matches!(self, CommitAction::Cut)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -179,6 +227,12 @@ pub struct UFOsRecord {
     // TODO: cid?
     pub record: Box<RawValue>,
     pub is_update: bool,
+    /// version of the redaction config applied before storing, if any
+    pub redaction_version: Option<u32>,
+    /// json merge patch from the previous version, if this update stored one
+    pub diff: Option<Box<RawValue>>,
+    /// names of the transforms that ran before storing, in order, if any
+    pub transforms: Option<Vec<String>>,
 }
 
 impl UFOsCommit {
@@ -209,6 +263,39 @@ impl UFOsCommit {
 pub struct EventBatch<const LIMIT: usize> {
     pub commits_by_nsid: HashMap<Nsid, CollectionCommits<LIMIT>>,
     pub account_removes: Vec<DeleteAccount>,
+    /// set when the batch was cut short because a commit for a new collection didn't fit
+    pub overflowed_collections: usize,
+    /// every event the batch saw, including ones that aren't stored
+    pub event_kinds: EventKindCounts,
+    /// set when events were missed right before this batch
+    pub cursor_gap: Option<CursorGap>,
+}
+
+/// A stretch of events the consumer missed, by the cursors on either side
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct CursorGap {
+    /// The last event received before the gap (or the cursor we resumed from)
+    pub last_seen: u64,
+    /// The first event received after it
+    pub resumed_at: u64,
+}
+
+/// How many of each kind of jetstream event there were
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct EventKindCounts {
+    pub commits: u64,
+    pub identities: u64,
+    pub accounts: u64,
+}
+impl EventKindCounts {
+    pub fn is_empty(&self) -> bool {
+        self.commits == 0 && self.identities == 0 && self.accounts == 0
+    }
+    pub fn merge(&mut self, other: &Self) {
+        self.commits += other.commits;
+        self.identities += other.identities;
+        self.accounts += other.accounts;
+    }
 }
 
 impl<const LIMIT: usize> EventBatch<LIMIT> {
@@ -221,7 +308,7 @@ impl<const LIMIT: usize> EventBatch<LIMIT> {
     ) -> Result<(), BatchInsertError> {
         let map = &mut self.commits_by_nsid;
         if !map.contains_key(collection) && map.len() >= max_collections {
-            return Err(BatchInsertError::BatchFull(commit));
+            return Err(BatchInsertError::TooManyCollections(commit));
         }
         map.entry(collection.clone())
             .or_default()
@@ -235,11 +322,11 @@ impl<const LIMIT: usize> EventBatch<LIMIT> {
         self.account_removes.len()
     }
     pub fn estimate_dids(&self) -> usize {
-        let mut estimator = Sketch::<14>::default();
+        let mut estimator = DidsSketch::default();
         for commits in self.commits_by_nsid.values() {
             estimator.merge(&commits.dids_estimate);
         }
-        estimator.estimate()
+        microcosm_estimates::estimate(&estimator) as usize
     }
     pub fn latest_cursor(&self) -> Option<Cursor> {
         let mut oldest = Cursor::from_start();
@@ -274,26 +361,38 @@ pub enum ConsumerInfo {
         started_at: u64,
         latest_cursor: Option<u64>,
         rollup_cursor: Option<u64>,
+        /// How many batches in the last 24h had to be sent early because
+        /// more collections were active at once than a batch can hold
+        overflowed_collections_24h: u64,
+        /// The latest stretch of events the consumer missed, if it's ever
+        /// skipped ahead: usually from resuming with a cursor older than
+        /// jetstream's replay window. Records and counts from inside it are
+        /// missing until something backfills them.
+        gap_detected: Option<CursorGap>,
     },
 }
 
-#[derive(Debug, PartialEq, Serialize, JsonSchema)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct NsidCount {
     nsid: String,
     creates: u64,
     updates: u64,
     deletes: u64,
     dids_estimate: u64,
+    /// Counts only cover events since this time (microseconds): the later of
+    /// when UFOs started and when this collection was first seen
+    tracked_since: u64,
 }
 impl NsidCount {
-    pub fn new(nsid: &Nsid, counts: &CountsValue) -> Self {
+    pub fn new(nsid: &Nsid, counts: &CountsValue, tracked_since: Cursor) -> Self {
         let crud = counts.counts();
         Self {
             nsid: nsid.to_string(),
             creates: crud.creates,
             updates: crud.updates,
             deletes: crud.deletes,
-            dids_estimate: counts.dids().estimate() as u64,
+            dids_estimate: microcosm_estimates::estimate(counts.dids()),
+            tracked_since: tracked_since.to_raw_u64(),
         }
     }
 }
@@ -314,7 +413,7 @@ impl PrefixCount {
             creates: crud.creates,
             updates: crud.updates,
             deletes: crud.deletes,
-            dids_estimate: counts.dids().estimate() as u64,
+            dids_estimate: microcosm_estimates::estimate(counts.dids()),
         }
     }
 }
@@ -325,6 +424,28 @@ pub enum PrefixChild {
     Collection(NsidCount),
     Prefix(PrefixCount),
 }
+impl PrefixChild {
+    /// The child prefix, if this isn't a collection
+    pub fn as_prefix(&self) -> Option<&str> {
+        match self {
+            PrefixChild::Collection(_) => None,
+            PrefixChild::Prefix(p) => Some(&p.prefix),
+        }
+    }
+    /// (creates, updates, deletes)
+    pub fn crud(&self) -> (u64, u64, u64) {
+        match self {
+            PrefixChild::Collection(c) => (c.creates, c.updates, c.deletes),
+            PrefixChild::Prefix(p) => (p.creates, p.updates, p.deletes),
+        }
+    }
+    pub fn dids_estimate(&self) -> u64 {
+        match self {
+            PrefixChild::Collection(c) => c.dids_estimate,
+            PrefixChild::Prefix(p) => p.dids_estimate,
+        }
+    }
+}
 
 #[derive(Debug, Serialize, JsonSchema)]
 pub struct NsidPrefix(String);
@@ -356,7 +477,7 @@ impl NsidPrefix {
     }
 }
 
-#[derive(Debug, Serialize, JsonSchema)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct JustCount {
     creates: u64,
     updates: u64,
@@ -364,11 +485,316 @@ pub struct JustCount {
     dids_estimate: u64,
 }
 
+/// Distinct records created in a window: each (did, rkey) in a collection counts once
+///
+/// Unlike raw creates, replayed events and records re-created after a delete
+/// aren't counted again. Only kept with unique record counting on.
+///
+/// Creates dropped from a full batch never have their did and rkey stored, so
+/// they can't be checked: the count is only exact when `unchecked` is zero.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct UniqueRecords {
+    /// Distinct records created, plus every unchecked create
+    ///
+    /// Unchecked creates can be repeats (eg. replayed after a reconnect), so
+    /// this can be over by up to `unchecked`.
+    pub approximate_records: u64,
+    /// How many of `approximate_records` are creates dropped from a full batch
+    /// before they could be checked, so were counted as unique without checking
+    pub unchecked: u64,
+}
+
+/// Counts over a time window that doesn't line up with the hourly rollups
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct WindowCounts {
+    #[serde(flatten)]
+    pub counts: JustCount,
+    /// Some hours were only partly inside the window, so their counts were
+    /// scaled down by the overlap (estimated dids for those hours are not)
+    pub prorated: bool,
+    /// Some counts came from events that haven't been rolled up yet
+    pub live: bool,
+}
+
+/// How often the storage background loop runs its tasks
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct BackgroundIntervals {
+    /// Between rollup steps while there's more to roll up, in microseconds
+    pub rollup_us: u64,
+    /// Pause after a rollup step that found nothing to roll up, in milliseconds
+    pub rollup_idle_ms: u64,
+    /// Between trim cycles, in milliseconds
+    pub trim_ms: u64,
+}
+impl BackgroundIntervals {
+    /// The defaults for keeping up with live events, or for a backfill
+    pub fn defaults(backfill: bool) -> Self {
+        // backfill condition here is iffy -- longer is good when doing the main ingest and then collection trims
+        // shorter once those are done helps things catch up
+        // the best setting for non-backfill is non-obvious.. it can be pretty slow and still be fine
+        Self {
+            rollup_us: if backfill { 100 } else { 32_000 },
+            rollup_idle_ms: 1_200,
+            // backfill condition again iffy. collection trims should probably happen in their own phase.
+            trim_ms: if backfill { 18_000 } else { 9_000 },
+        }
+    }
+    pub fn validate(&self) -> Result<(), String> {
+        if self.rollup_us == 0 || self.rollup_idle_ms == 0 || self.trim_ms == 0 {
+            return Err(format!("background intervals must be non-zero: {self:?}"));
+        }
+        Ok(())
+    }
+}
+impl Default for BackgroundIntervals {
+    fn default() -> Self {
+        Self::defaults(false)
+    }
+}
+
+/// Collections consumed over their own jetstream connection
+///
+/// Parsed from a comma-separated list of NSIDs. Jetstream takes up to 100
+/// wanted collections per connection, and no wildcards here: every shard
+/// lists exactly the collections it covers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JetstreamShard(Vec<Nsid>);
+impl JetstreamShard {
+    /// Most collections jetstream accepts for one connection
+    pub const MAX_COLLECTIONS: usize = 100;
+
+    pub fn new(mut collections: Vec<Nsid>) -> Result<Self, String> {
+        collections.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        collections.dedup();
+        if collections.is_empty() {
+            return Err("a jetstream shard needs at least one collection".to_string());
+        }
+        if collections.len() > Self::MAX_COLLECTIONS {
+            return Err(format!(
+                "a jetstream shard can have at most {} collections, found {}",
+                Self::MAX_COLLECTIONS,
+                collections.len()
+            ));
+        }
+        Ok(Self(collections))
+    }
+    pub fn collections(&self) -> &[Nsid] {
+        &self.0
+    }
+    /// Stable name for the shard's stored cursor: its sorted collections
+    pub fn id(&self) -> String {
+        self.0
+            .iter()
+            .map(|c| c.as_str())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+    /// Make sure no collection is consumed by two shards
+    pub fn check_disjoint(shards: &[Self]) -> Result<(), String> {
+        let mut seen = HashMap::new();
+        for (i, shard) in shards.iter().enumerate() {
+            for collection in shard.collections() {
+                if let Some(other) = seen.insert(collection.clone(), i) {
+                    return Err(format!(
+                        "collection {} is in jetstream shards {other} and {i}",
+                        collection.as_str()
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+impl std::str::FromStr for JetstreamShard {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let collections = s
+            .split(',')
+            .map(|c| Nsid::new(c.trim().to_string()).map_err(|e| format!("bad NSID {c:?}: {e}")))
+            .collect::<Result<_, _>>()?;
+        Self::new(collections)
+    }
+}
+
+/// Where the storage background loop is in its schedule
+#[derive(Debug, Default, Clone, Serialize, JsonSchema)]
+pub struct BackgroundStatus {
+    /// The background loop is running
+    pub running: bool,
+    /// What the background loop is running with
+    pub intervals: BackgroundIntervals,
+    /// Roughly how long until the next rollup step, in milliseconds
+    pub next_rollup_ms: Option<u64>,
+    /// Roughly how long until the next trim cycle, in milliseconds
+    pub next_trim_ms: Option<u64>,
+    /// Collections with new rollups, waiting to be trimmed
+    pub dirty_nsids: usize,
+    /// Live counts rolled up by the last rollup step
+    pub last_rollup_items: usize,
+    /// How long the last trim cycle took, in milliseconds
+    pub last_trim_ms: Option<u64>,
+}
+
+/// A background task that can be run on demand
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackgroundTask {
+    Rollup,
+    Trim,
+}
+
+/// Account deletes waiting for, or partway through, the rollup
+#[derive(Debug, Default, Serialize, JsonSchema)]
+pub struct AccountDeletesStatus {
+    /// Account deletes queued and not yet started
+    pub queued: usize,
+    /// Account deletes interrupted partway, which will resume where they left off
+    pub in_progress: Vec<AccountDeleteProgress>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct AccountDeleteProgress {
+    pub did: String,
+    /// Records removed so far
+    pub records_deleted: u64,
+}
+
+#[derive(Debug, Default, Serialize, JsonSchema)]
+pub struct PurgeReport {
+    /// Nothing was actually removed: the counts are what a real purge would remove
+    pub dry_run: bool,
+    pub feeds_removed: u64,
+    pub records_removed: u64,
+    pub rollups_removed: u64,
+}
+
+#[derive(Debug, Default, Serialize, JsonSchema)]
+pub struct RebuildFeedsReport {
+    /// Picked up from an interrupted rebuild instead of starting over
+    pub resumed: bool,
+    /// Feed entries removed before rebuilding (zero when resumed)
+    pub feeds_cleared: u64,
+    /// Current records scanned in this run, each getting one feed entry
+    pub records_rebuilt: u64,
+    /// Older record versions passed over, since only current versions are in feeds
+    pub versions_skipped: u64,
+}
+
+/// Estimated disk usage attributable to one collection
+///
+/// Sizes are uncompressed key and value bytes, so actual disk use is lower.
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct CollectionKeySpace {
+    pub collection: String,
+    /// Number of feed entries, counted exactly
+    pub feed_entries: u64,
+    /// Bytes of feed keys and values, counted exactly
+    pub feed_bytes: u64,
+    /// How many records were actually looked up for the estimate
+    pub records_sampled: u64,
+    /// Estimated bytes of records (and their kept versions)
+    pub record_bytes_estimate: u64,
+    pub total_bytes_estimate: u64,
+}
+
+#[derive(Debug, Default, Serialize, JsonSchema)]
+pub struct KeySpaceReport {
+    /// One record was looked up for every this many feed entries
+    pub sample_every: u64,
+    /// Total over every collection, not just the ones listed
+    pub total_bytes_estimate: u64,
+    /// Biggest collections first
+    pub collections: Vec<CollectionKeySpace>,
+}
+
+/// One admin action, as recorded in the audit log
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct AuditEntry {
+    /// When the action happened, in microseconds since the epoch
+    ///
+    /// Unique: also the entry's position in the log.
+    pub at: u64,
+    /// The name of the api key that made the request (the remote address without auth)
+    pub actor: String,
+    /// The admin operation, eg. `purge_collection`
+    pub action: String,
+    pub params: serde_json::Value,
+    /// "ok", or what went wrong
+    pub outcome: String,
+}
+
+/// A record that matched a watchlist as it was stored
+#[derive(Debug, Clone)]
+pub struct WatchlistHit {
+    pub cursor: Cursor,
+    pub did: Did,
+    pub collection: Nsid,
+    pub rkey: RecordKey,
+    /// The keywords and regexes that matched, as configured
+    pub matched: Vec<String>,
+    /// The record as it matched, if that version is still stored
+    pub record: Option<UFOsRecord>,
+}
+
+/// Headline numbers across every collection
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Summary {
+    /// Collections with any rolled-up counts
+    pub collections: u64,
+    /// Records created across every collection, all-time
+    pub records_created: u64,
+    /// Estimated distinct DIDs seen in any collection, all-time
+    pub dids_estimate: u64,
+    /// Estimated distinct DIDs seen in any collection over the last 24 rolled-up hours
+    pub dids_estimate_24h: u64,
+    /// Creates, updates, and deletes per second, over the latest complete hour
+    pub events_per_second: f64,
+    /// How far the latest consumed event is behind now, in milliseconds
+    pub consumer_lag_ms: Option<u64>,
+    /// How far the rollup is behind the latest consumed event, in milliseconds
+    pub rollup_lag_ms: Option<u64>,
+}
+
+/// Rollup buckets to compare for growth rankings
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum GrowthPeriod {
+    /// The latest complete hour against the hour before it
+    Hour,
+    /// The latest complete week against the week before it
+    #[default]
+    Week,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct CollectionGrowth {
+    pub nsid: String,
+    /// Records created in the earlier bucket
+    pub previous: u64,
+    /// Records created in the latest complete bucket
+    pub current: u64,
+    /// How much creates grew: `0.5` is half again as many, `-1` is none at all
+    pub growth: f64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema)]
+pub struct GrowthRanking {
+    /// Start of the earlier bucket (microseconds)
+    pub previous_start: u64,
+    /// Start of the latest complete bucket (microseconds)
+    pub current_start: u64,
+    /// Fastest growing first
+    pub collections: Vec<CollectionGrowth>,
+}
+
+/// Collection ordering, each with its own continuation cursor
+///
+/// Cursors are opaque and only valid for the ordering that produced them.
 #[derive(Debug)]
 pub enum OrderCollectionsBy {
     Lexi { cursor: Option<Vec<u8>> },
-    RecordsCreated,
-    DidsEstimate,
+    RecordsCreated { cursor: Option<Vec<u8>> },
+    DidsEstimate { cursor: Option<Vec<u8>> },
 }
 impl Default for OrderCollectionsBy {
     fn default() -> Self {
@@ -614,4 +1040,19 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_jetstream_shards() {
+        let shard: JetstreamShard = "b.b.b, a.a.a,b.b.b".parse().unwrap();
+        assert_eq!(shard.id(), "a.a.a,b.b.b");
+        assert!("".parse::<JetstreamShard>().is_err());
+        assert!("a.a.a,nope".parse::<JetstreamShard>().is_err());
+        let too_many = (0..=100).map(|i| format!("a.a.c{i}")).collect::<Vec<_>>();
+        assert!(too_many.join(",").parse::<JetstreamShard>().is_err());
+
+        let other: JetstreamShard = "c.c.c".parse().unwrap();
+        assert!(JetstreamShard::check_disjoint(&[shard.clone(), other]).is_ok());
+        let overlapping: JetstreamShard = "c.c.c,a.a.a".parse().unwrap();
+        assert!(JetstreamShard::check_disjoint(&[shard, overlapping]).is_err());
+    }
 }
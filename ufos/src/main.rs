@@ -1,17 +1,36 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use jetstream::events::Cursor;
+use jetstream::exports::Nsid;
 use metrics::{describe_gauge, gauge, Unit};
-use metrics_exporter_prometheus::PrometheusBuilder;
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 use tokio::task::JoinSet;
+use ufos::cache::{DirtyReceiver, DirtyTap, RedisCache};
+use ufos::chase;
 use ufos::consumer;
 use ufos::file_consumer;
+#[cfg(feature = "firehose")]
+use ufos::firehose_consumer;
+use ufos::nsid_limits::{self, NsidLimits};
+use ufos::publish::{valid_prefix, BusTarget, EventBus};
+use ufos::redaction::{RedactionConfig, Redactor};
+use ufos::search::SearchIndex;
 use ufos::server;
-use ufos::storage::{StorageWhatever, StoreBackground, StoreReader, StoreWriter};
-use ufos::storage_fjall::FjallStorage;
+use ufos::server::auth::{Auth, AuthConfig};
+use ufos::server::static_json::StaticJson;
+use ufos::spill;
+use ufos::storage::{StorageWhatever, StoreAdmin, StoreBackground, StoreReader, StoreWriter};
+use ufos::storage_fjall::{FjallConfig, FjallStorage};
+#[cfg(feature = "rocks")]
+use ufos::storage_rocks::{RocksConfig, RocksStorage};
 use ufos::store_types::SketchSecretPrefix;
-use ufos::{nice_duration, ConsumerInfo};
+use ufos::transform::{TransformConfig, Transformer};
+use ufos::watchlist::{WatchlistConfig, Watchlists};
+use ufos::webhook::{WebhookConfig, Webhooks};
+use ufos::{nice_duration, AuditEntry, BackgroundIntervals, ConsumerInfo, JetstreamShard};
 
 #[cfg(not(target_env = "msvc"))]
 use tikv_jemallocator::Jemalloc;
@@ -24,27 +43,102 @@ static GLOBAL: Jemalloc = Jemalloc;
 #[derive(Parser, Debug, Clone)]
 #[command(version, about, long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
     /// Jetstream server to connect to (exclusive with --fixture). Provide either a wss:// URL, or a shorhand value:
     /// 'us-east-1', 'us-east-2', 'us-west-1', or 'us-west-2'
+    #[arg(long, required_unless_present_any = ["chase", "firehose"])]
+    jetstream: Option<String>,
+    /// Consume another UFOs instance's change feed instead of jetstream, eg. https://ufos-api.microcosm.blue
+    ///
+    /// The upstream must run with --change-feed-minutes. Run as a read replica, eg. in another region.
+    #[arg(long, conflicts_with_all = ["jetstream", "jetstream_fixture"])]
+    chase: Option<String>,
+    /// Api key for chasing an upstream with an auth config (needs the trusted scope)
+    #[arg(long, requires = "chase")]
+    chase_key: Option<String>,
+    /// Consume a relay's firehose directly instead of jetstream, eg. wss://bsky.network
+    ///
+    /// Commits are decoded from CBOR and CAR blocks into the same events
+    /// jetstream sends. Recent relay sequence numbers are checkpointed in
+    /// --data to resume after restarts. Needs the `firehose` feature.
+    #[arg(long, conflicts_with_all = ["jetstream", "chase", "jetstream_fixture", "jetstream_shard", "jetstream_migrate_from"])]
+    firehose: Option<String>,
+    /// Publish inserted batches at /changes for replicas to chase, keeping this many minutes of them
+    ///
+    /// Replicas that fall further behind than this have to start over.
     #[arg(long)]
-    jetstream: String,
+    change_feed_minutes: Option<u64>,
     /// allow changing jetstream endpoints
     #[arg(long, action)]
     jetstream_force: bool,
+    /// Switch to --jetstream from this endpoint, overlapping both for a while
+    ///
+    /// Cursors from different endpoints don't line up exactly, so both are
+    /// consumed for --jetstream-migrate-overlap-secs from the stored cursor,
+    /// with commits delivered by both only stored once. The switch is recorded
+    /// in the admin audit log. Implies --jetstream-force.
+    #[arg(long, conflicts_with_all = ["chase", "jetstream_fixture", "jetstream_shard"])]
+    jetstream_migrate_from: Option<String>,
+    /// How many seconds of events to take from both endpoints while migrating
+    #[arg(long, default_value_t = 60, requires = "jetstream_migrate_from")]
+    jetstream_migrate_overlap_secs: u64,
+    /// Consume these collections (comma-separated NSIDs) over their own jetstream connection
+    ///
+    /// Repeat for more connections, for firehose volumes one connection can't
+    /// keep up with. Each shard keeps its own cursor. Shards can't overlap,
+    /// and collections not in any shard are not consumed at all. New batches
+    /// aren't spilled to disk while sharded, but any left over from an
+    /// unsharded run are replayed before the shards start.
+    #[arg(long, conflicts_with_all = ["chase", "jetstream_fixture"])]
+    jetstream_shard: Vec<JetstreamShard>,
     /// don't request zstd-compressed jetstream events
     ///
-    /// reduces CPU at the expense of more ingress bandwidth
+    /// reduces CPU at the expense of more ingress bandwidth. always on for
+    /// builds without the `zstd` or `ruzstd` feature.
     #[arg(long, action)]
     jetstream_no_zstd: bool,
     /// Location to store persist data to disk
     #[arg(long)]
     data: PathBuf,
+    /// Storage engine for --data
+    ///
+    /// `rocks` (needs the `rocks` feature) is for comparing compaction and
+    /// disk usage with fjall. It doesn't support redaction, transforms,
+    /// watchlists, --search, webhooks, --publish, record diffs or versions,
+    /// unique record counts, the change feed, or --ephemeral. Its API also
+    /// answers "unsupported" for purging collections, key space estimates,
+    /// rebuilding feeds, ordered prefix listings and week-stepped timeseries.
+    /// A db only ever works with the backend that made it.
+    #[arg(long, value_enum, default_value_t = Backend::Fjall)]
+    backend: Backend,
+    /// Delete --data when the process exits
+    ///
+    /// For CI and demos. The db works the same, it just doesn't outlive the run.
+    /// --data must not exist yet (or be empty), so a real db can't be deleted.
+    #[arg(long, action)]
+    ephemeral: bool,
+    /// Memory for the storage block cache, in MiB (the storage default if omitted)
+    #[arg(long)]
+    cache_mb: Option<u64>,
     /// DEBUG: don't start the jetstream consumer or its write loop
     #[arg(long, action)]
     pause_writer: bool,
     /// Adjust runtime settings like background task intervals for efficient backfill
     #[arg(long, action)]
     backfill: bool,
+    /// Microseconds between rollup steps while there's more to roll up
+    ///
+    /// Defaults depend on --backfill. Background intervals can also be changed
+    /// at runtime from the admin API.
+    #[arg(long)]
+    rollup_interval_us: Option<u64>,
+    /// Milliseconds to pause after a rollup step finds nothing to roll up
+    #[arg(long)]
+    rollup_idle_ms: Option<u64>,
+    /// Milliseconds between collection trim cycles
+    #[arg(long)]
+    trim_interval_ms: Option<u64>,
     /// DEBUG: force the rw loop to fall behind  by pausing it
     /// todo: restore this
     #[arg(long, action)]
@@ -55,36 +149,442 @@ struct Args {
     /// DEBUG: interpret jetstream as a file fixture
     #[arg(long, action)]
     jetstream_fixture: bool,
+    /// Serve the admin API at this address, eg. 127.0.0.1:9998
+    ///
+    /// Without --auth-config the admin API is unauthenticated, so keep it somewhere private.
+    /// Disabled if omitted.
+    #[arg(long)]
+    admin_listen: Option<SocketAddr>,
+    /// Maximum number of distinct collections in one batch of events
+    ///
+    /// Batches are sent early when a new collection doesn't fit. Watch the
+    /// `overflowed_collections_24h` meta info to see if this is too low.
+    #[arg(long, default_value_t = consumer::MAX_BATCHED_COLLECTIONS)]
+    max_batched_collections: usize,
+    /// Path to a json auth config: api keys, their scopes, and rate limits
+    ///
+    /// Raw records and per-DID endpoints need a trusted key, and the admin API
+    /// an admin key. See `ufos::server::auth::AuthConfig` for the format.
+    /// Everything is public if omitted.
+    #[arg(long)]
+    auth_config: Option<PathBuf>,
+    /// Path to a json redaction config: record fields to strip before storing
+    ///
+    /// See `ufos::redaction::RedactionConfig` for the format
+    #[arg(long)]
+    redaction_config: Option<PathBuf>,
+    /// Path to a json transform config: per-collection transforms to run on
+    /// records before storing them, after redaction
+    ///
+    /// See `ufos::transform::TransformConfig` for the format
+    #[arg(long)]
+    transform_config: Option<PathBuf>,
+    /// Path to a json watchlist config: keywords and regexes to tag matching
+    /// records with as they're stored, for `/watchlist/hits`
+    ///
+    /// See `ufos::watchlist::WatchlistConfig` for the format
+    #[arg(long)]
+    watchlist_config: Option<PathBuf>,
+    /// Keep a full-text index of stored record text in --data, for `/records/search`
+    ///
+    /// Needs the `search` feature. Only records stored while it's on are indexed.
+    #[arg(long, action)]
+    search: bool,
+    /// Count distinct records created per collection, ignoring replayed and re-created ones
+    ///
+    /// Keeps a key for every record created while it's on. Counts show up in
+    /// `/collections/stats` for hour- or week-aligned windows.
+    #[arg(long, action)]
+    unique_record_counts: bool,
+    /// Path to a json webhook config: URLs to POST stored records to, per collection
+    ///
+    /// See `ufos::webhook::WebhookConfig` for the format
+    #[arg(long)]
+    webhook_config: Option<PathBuf>,
+    /// Publish stored records and count deltas to an event bus
+    ///
+    /// `nats://host:port` (needs the `nats` feature) or
+    /// `kafka://broker:port,broker:port` (needs the `kafka` feature)
+    #[arg(long)]
+    publish: Option<String>,
+    /// Start of the subjects (nats) or topics (kafka) to publish to
+    #[arg(long, default_value = "ufos")]
+    publish_prefix: String,
+    /// Cache top-collections lists and collection stats in redis, eg. redis://127.0.0.1:6379
+    ///
+    /// Entries are dropped when their collections get new counts, across every
+    /// instance sharing the same redis and --redis-prefix.
+    #[arg(long)]
+    redis_url: Option<String>,
+    /// Start of all redis keys and the invalidation channel
+    #[arg(long, default_value = "ufos")]
+    redis_prefix: String,
+    /// Longest time to serve a cached response for, in seconds
+    #[arg(long, default_value_t = 60)]
+    redis_ttl_secs: u64,
+    /// Secret (32 hex chars) for the distinct-dids sketches of a fresh db
+    ///
+    /// Share it with other services (eg. constellation's --sketch-secret) to
+    /// get did estimates that can be merged across them. Random if omitted.
+    #[arg(long, value_parser = microcosm_estimates::parse_secret)]
+    sketch_secret: Option<SketchSecretPrefix>,
+    /// Derive everything random from this seed, so demo datasets and test
+    /// snapshots come out the same on every run
+    ///
+    /// Covers the sketch secret of a fresh db (unless --sketch-secret is set)
+    /// and background sampling. Don't use it for a db whose sketches are
+    /// shared: anyone who knows the seed knows the secret.
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Store a json diff from the previous version with every updated record
+    ///
+    /// Served from `/records?include_diff=true`. Costs a record read per update.
+    #[arg(long, action)]
+    record_diffs: bool,
+    /// Keep previous versions of records in a collection, eg. app.bsky.actor.profile=5
+    ///
+    /// Repeat for more collections. Versions are served from `/record/versions`.
+    #[arg(long, value_parser = parse_keep_versions)]
+    keep_versions: Vec<(Nsid, usize)>,
+    /// Keep this many times more sample records for collections that are read often
+    ///
+    /// Collections served from `/records` a few times in the last hour or so
+    /// are trimmed less, so they keep deeper history than ones nobody reads.
+    #[arg(long, default_value_t = 1)]
+    popular_trim_multiplier: usize,
+    /// Most dot-separated segments a collection NSID can have before it's guarded
+    ///
+    /// Guarded collections are counted under `invalid.nsid` instead of their
+    /// own NSID (their records aren't stored), or dropped with
+    /// --reject-invalid-nsids.
+    #[arg(long, default_value_t = nsid_limits::DEFAULT_MAX_NSID_SEGMENTS)]
+    max_nsid_segments: usize,
+    /// Most characters a collection NSID can have before it's guarded
+    #[arg(long, default_value_t = nsid_limits::DEFAULT_MAX_NSID_LEN)]
+    max_nsid_len: usize,
+    /// Drop commits for collections over the NSID limits instead of counting
+    /// them under `invalid.nsid`
+    #[arg(long, action)]
+    reject_invalid_nsids: bool,
+    /// Write the summary and top-collections responses as json files to this directory
+    ///
+    /// Refreshed every --static-json-interval-secs, for serving heavy anonymous
+    /// traffic from a CDN or object store instead of the live store.
+    #[arg(long)]
+    static_json_dir: Option<PathBuf>,
+    /// Seconds between rewrites of the --static-json-dir files
+    #[arg(long, default_value_t = 60, requires = "static_json_dir")]
+    static_json_interval_secs: u64,
+    /// Maximum number of batches to spill to disk while the writer is behind
+    ///
+    /// Absorbs firehose spikes without holding up the consumer. Queued batches
+    /// survive restarts and are replayed before reconnecting. 0 to disable.
+    #[arg(long, default_value_t = spill::MAX_SPILLED_BATCHES)]
+    max_spilled_batches: usize,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+enum Backend {
+    Fjall,
+    Rocks,
+}
+
+/// Flags that only the fjall backend does anything with
+fn fjall_only_flags(args: &Args) -> Vec<&'static str> {
+    [
+        (args.redaction_config.is_some(), "--redaction-config"),
+        (args.transform_config.is_some(), "--transform-config"),
+        (args.watchlist_config.is_some(), "--watchlist-config"),
+        (args.search, "--search"),
+        (args.unique_record_counts, "--unique-record-counts"),
+        (args.webhook_config.is_some(), "--webhook-config"),
+        (args.publish.is_some(), "--publish"),
+        (args.record_diffs, "--record-diffs"),
+        (!args.keep_versions.is_empty(), "--keep-versions"),
+        (args.change_feed_minutes.is_some(), "--change-feed-minutes"),
+        (args.ephemeral, "--ephemeral"),
+    ]
+    .into_iter()
+    .filter_map(|(set, flag)| set.then_some(flag))
+    .collect()
+}
+
+/// Offline maintenance: run instead of ingesting and serving, then exit
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Rebuild the feeds partition from the stored records
+    ///
+    /// For recovering from corrupt feeds. Stop any running instance on the
+    /// same --data first. Resumes if a previous rebuild was interrupted.
+    RebuildFeeds,
+}
+
+/// Parse a collection and how many previous versions to keep, as `NSID=N`
+fn parse_keep_versions(s: &str) -> Result<(Nsid, usize), String> {
+    let (nsid, n) = s.split_once('=').ok_or("expected NSID=N")?;
+    let nsid = Nsid::new(nsid.to_string()).map_err(|e| format!("invalid NSID: {e:?}"))?;
+    let n = n
+        .parse()
+        .map_err(|e| format!("invalid number of versions: {e}"))?;
+    if n == 0 {
+        return Err("keep at least one version, or leave the collection out".to_string());
+    }
+    Ok((nsid, n))
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    env_logger::init();
+    env_logger::Builder::from_default_env()
+        .format(|buf, record| {
+            use std::io::Write;
+            write!(
+                buf,
+                "[{} {:<5} {}",
+                buf.timestamp(),
+                record.level(),
+                record.target()
+            )?;
+            // tag lines logged while handling an api request
+            if let Some(id) = server::request_id() {
+                write!(buf, " req={id}")?;
+            }
+            writeln!(buf, "] {}", record.args())
+        })
+        .init();
 
     let args = Args::parse();
-    let jetstream = args.jetstream.clone();
-    let (read_store, write_store, cursor, sketch_secret) = FjallStorage::init(
-        args.data.clone(),
-        jetstream,
-        args.jetstream_force,
-        Default::default(),
-    )?;
-    go(args, read_store, write_store, cursor, sketch_secret).await?;
+    if args.firehose.is_some() && !cfg!(feature = "firehose") {
+        anyhow::bail!("--firehose needs ufos built with the \"firehose\" feature");
+    }
+    if args.backend != Backend::Fjall {
+        let flags = fjall_only_flags(&args);
+        if !flags.is_empty() {
+            anyhow::bail!(
+                "--backend {:?} doesn't support {}",
+                args.backend,
+                flags.join(", ")
+            );
+        }
+    }
+    // the chased instance or relay takes jetstream's place as the endpoint guarding the cursor
+    let endpoint = args
+        .chase
+        .clone()
+        .or(args.firehose.clone())
+        .or(args.jetstream.clone())
+        .expect("clap requires one of them");
+    let redaction = args
+        .redaction_config
+        .as_ref()
+        .map(|path| {
+            let config = RedactionConfig::load(path)?;
+            log::info!(
+                "loaded redaction config version {} with {} rules",
+                config.version,
+                config.rules.len()
+            );
+            Redactor::new(config)
+        })
+        .transpose()?;
+    let transforms = args
+        .transform_config
+        .as_ref()
+        .map(|path| {
+            let config = TransformConfig::load(path)?;
+            log::info!("loaded transform config with {} rules", config.rules.len());
+            Transformer::new(config)
+        })
+        .transpose()?;
+    let watchlists = args
+        .watchlist_config
+        .as_ref()
+        .map(|path| {
+            let config = WatchlistConfig::load(path)?;
+            log::info!("loaded {} watchlists", config.watchlists.len());
+            Watchlists::new(config)
+        })
+        .transpose()?;
+    let search = args
+        .search
+        .then(|| SearchIndex::open(args.data.join("search")))
+        .transpose()?;
+    let webhooks = args
+        .webhook_config
+        .as_ref()
+        .map(|path| {
+            let config = WebhookConfig::load(path)?;
+            log::info!("loaded {} webhooks", config.hooks.len());
+            Webhooks::new(config)
+        })
+        .transpose()?;
+    if !valid_prefix(&args.publish_prefix) {
+        anyhow::bail!("invalid --publish-prefix: {:?}", args.publish_prefix);
+    }
+    let bus = args
+        .publish
+        .as_deref()
+        .map(|url| {
+            let target = BusTarget::parse(url).map_err(anyhow::Error::msg)?;
+            anyhow::Ok(EventBus::new(target, &args.publish_prefix))
+        })
+        .transpose()?;
+    let cache = match &args.redis_url {
+        Some(url) => {
+            let ttl = Duration::from_secs(args.redis_ttl_secs);
+            let cache = RedisCache::connect(url, &args.redis_prefix, ttl).await?;
+            log::info!("connected to redis cache");
+            Some((cache, DirtyTap::new()))
+        }
+        None => None,
+    };
+    let background = match (
+        args.rollup_interval_us,
+        args.rollup_idle_ms,
+        args.trim_interval_ms,
+    ) {
+        (None, None, None) => None,
+        (rollup_us, rollup_idle_ms, trim_ms) => {
+            let defaults = BackgroundIntervals::defaults(args.backfill);
+            let intervals = BackgroundIntervals {
+                rollup_us: rollup_us.unwrap_or(defaults.rollup_us),
+                rollup_idle_ms: rollup_idle_ms.unwrap_or(defaults.rollup_idle_ms),
+                trim_ms: trim_ms.unwrap_or(defaults.trim_ms),
+            };
+            intervals.validate().map_err(anyhow::Error::msg)?;
+            Some(intervals)
+        }
+    };
+    JetstreamShard::check_disjoint(&args.jetstream_shard).map_err(anyhow::Error::msg)?;
+    let force_endpoint = args.jetstream_force || args.jetstream_migrate_from.is_some();
+    let dirty = cache.as_ref().map(|(_, (tap, _))| tap.clone());
+    let cache = cache.map(|(cache, (_, dirty))| (cache, dirty));
+    let nsid_limits = NsidLimits {
+        max_segments: args.max_nsid_segments,
+        max_len: args.max_nsid_len,
+        reject: args.reject_invalid_nsids,
+    };
+    match args.backend {
+        Backend::Fjall => {
+            let (read_store, write_store, cursor, sketch_secret) = FjallStorage::init(
+                args.data.clone(),
+                endpoint,
+                force_endpoint,
+                FjallConfig {
+                    redaction,
+                    transforms,
+                    watchlists,
+                    search,
+                    webhooks: webhooks.as_ref().map(Webhooks::tap),
+                    bus: bus.as_ref().map(EventBus::tap),
+                    dirty,
+                    sketch_secret: args.sketch_secret,
+                    record_diffs: args.record_diffs,
+                    keep_versions: args.keep_versions.iter().cloned().collect(),
+                    change_feed: args
+                        .change_feed_minutes
+                        .map(|mins| Duration::from_secs(mins * 60)),
+                    background,
+                    popular_trim_multiplier: Some(args.popular_trim_multiplier),
+                    ephemeral: args.ephemeral,
+                    cache_bytes: args.cache_mb.map(|mb| mb * 1024 * 1024),
+                    seed: args.seed,
+                    jetstream_shards: args.jetstream_shard.clone(),
+                    nsid_limits,
+                    plugins: Default::default(),
+                    unique_counts: args.unique_record_counts,
+                },
+            )?;
+            go(
+                args,
+                read_store,
+                write_store,
+                cursor,
+                sketch_secret,
+                webhooks,
+                bus,
+                cache,
+            )
+            .await?;
+        }
+        #[cfg(feature = "rocks")]
+        Backend::Rocks => {
+            let (read_store, write_store, cursor, sketch_secret) = RocksStorage::init(
+                args.data.clone(),
+                endpoint,
+                force_endpoint,
+                RocksConfig {
+                    dirty,
+                    sketch_secret: args.sketch_secret,
+                    background,
+                    popular_trim_multiplier: Some(args.popular_trim_multiplier),
+                    cache_bytes: args.cache_mb.map(|mb| mb * 1024 * 1024),
+                    seed: args.seed,
+                    jetstream_shards: args.jetstream_shard.clone(),
+                    nsid_limits,
+                    plugins: Default::default(),
+                },
+            )?;
+            go(
+                args,
+                read_store,
+                write_store,
+                cursor,
+                sketch_secret,
+                webhooks,
+                bus,
+                cache,
+            )
+            .await?;
+        }
+        #[allow(unreachable_patterns)]
+        other => anyhow::bail!(
+            "--backend {other:?} needs ufos built with the {:?} feature",
+            match other {
+                Backend::Rocks => "rocks",
+                Backend::Fjall => unreachable!(),
+            }
+        ),
+    }
     Ok(())
 }
 
 async fn go<B: StoreBackground + 'static>(
     args: Args,
     read_store: impl StoreReader + 'static + Clone,
-    mut write_store: impl StoreWriter<B> + 'static,
+    mut write_store: impl StoreWriter<B> + StoreAdmin + 'static,
     cursor: Option<Cursor>,
     sketch_secret: SketchSecretPrefix,
+    webhooks: Option<Webhooks>,
+    bus: Option<EventBus>,
+    cache: Option<(RedisCache, DirtyReceiver)>,
 ) -> anyhow::Result<()> {
+    if let Some(Command::RebuildFeeds) = args.command {
+        println!("rebuilding feeds from records...");
+        let report = write_store.rebuild_feeds().await?;
+        println!("done: {report:?}");
+        return Ok(());
+    }
+
+    let metrics = install_metrics_server()?;
+
     let mut whatever_tasks: JoinSet<anyhow::Result<()>> = JoinSet::new();
     let mut consumer_tasks: JoinSet<anyhow::Result<()>> = JoinSet::new();
 
     println!("starting server with storage...");
-    let serving = server::serve(read_store.clone());
+    let auth = match &args.auth_config {
+        Some(path) => {
+            let config = AuthConfig::load(path)?;
+            log::info!("loaded auth config with {} api keys", config.keys.len());
+            Arc::new(Auth::new(config)?)
+        }
+        None => Arc::new(Auth::open()),
+    };
+    let serving = server::serve(
+        read_store.clone(),
+        auth.clone(),
+        cache.as_ref().map(|(cache, _)| cache.clone()),
+        Some(metrics),
+    );
     whatever_tasks.spawn(async move {
         serving.await.map_err(|e| {
             log::warn!("server ended: {e}");
@@ -92,6 +592,39 @@ async fn go<B: StoreBackground + 'static>(
         })
     });
 
+    if let Some(bind) = args.admin_listen {
+        println!("starting admin server at {bind}...");
+        let admin_serving = server::admin::serve(write_store.clone(), bind, auth);
+        whatever_tasks.spawn(async move {
+            admin_serving.await.map_err(|e| {
+                log::warn!("admin server ended: {e}");
+                anyhow::anyhow!(e)
+            })
+        });
+    }
+
+    if let Some(dir) = args.static_json_dir.clone() {
+        println!("writing static json to {dir:?}...");
+        let writing = StaticJson::new(dir, Duration::from_secs(args.static_json_interval_secs))
+            .run(read_store.clone());
+        whatever_tasks.spawn(async move {
+            writing
+                .await
+                .inspect_err(|e| log::warn!("static json writer ended: {e}"))
+        });
+    }
+
+    if let Some((cache, dirty)) = cache {
+        // also runs without the writer, to drop entries other instances report
+        let dirty = (!args.pause_writer).then_some(dirty);
+        whatever_tasks.spawn(async move {
+            cache
+                .run_invalidation(dirty)
+                .await
+                .inspect_err(|e| log::warn!("redis cache invalidation ended: {e}"))
+        });
+    }
+
     if args.pause_writer {
         log::info!("not starting jetstream or the write loop.");
         for t in whatever_tasks.join_all().await {
@@ -103,14 +636,126 @@ async fn go<B: StoreBackground + 'static>(
     }
 
     let batches = if args.jetstream_fixture {
-        log::info!("starting with jestream file fixture: {:?}", args.jetstream);
-        file_consumer::consume(args.jetstream.into(), sketch_secret, cursor).await?
+        let fixture = args.jetstream.expect("fixture mode requires --jetstream");
+        log::info!("starting with jestream file fixture: {fixture:?}");
+        file_consumer::consume(
+            fixture.into(),
+            sketch_secret,
+            cursor,
+            args.max_batched_collections,
+        )
+        .await?
     } else {
+        let spill = write_store.spill_queue()?;
+        // new batches could be ahead of a lagging shard's cursor, so sharded
+        // consumers don't spill. leftovers are still replayed either way.
+        let max_spilled = if args.jetstream_shard.is_empty() {
+            args.max_spilled_batches
+        } else {
+            0
+        };
+        // reconnect after any queued batches, since they'll be replayed first
+        let resumed = spill::resume_cursor(&spill, cursor)?;
+        let replaying = resumed != cursor;
+        let cursor = resumed;
         log::info!(
             "starting consumer with cursor: {cursor:?} from {:?} ago",
             cursor.map(|c| c.elapsed())
         );
-        consumer::consume(&args.jetstream, cursor, false, sketch_secret).await?
+        let batches = match (&args.chase, &args.jetstream) {
+            (Some(upstream), _) => {
+                chase::consume(upstream, args.chase_key.as_deref(), cursor).await?
+            }
+            (None, Some(jetstream)) if !args.jetstream_shard.is_empty() => {
+                let mut shards = Vec::with_capacity(args.jetstream_shard.len());
+                for shard in &args.jetstream_shard {
+                    // a new shard starts where everything else left off. the
+                    // replayed queue is from an unsharded run, which had every
+                    // shard's commits up to its end, so no shard goes back
+                    // before that.
+                    let stored = write_store.jetstream_shard_cursor(shard)?;
+                    let shard_cursor = match (stored, cursor) {
+                        (Some(stored), Some(resumed)) if replaying && resumed > stored => {
+                            Some(resumed)
+                        }
+                        (stored, cursor) => stored.or(cursor),
+                    };
+                    shards.push((shard.clone(), shard_cursor));
+                }
+                consumer::consume_sharded(
+                    jetstream,
+                    shards,
+                    false,
+                    sketch_secret,
+                    args.max_batched_collections,
+                )
+                .await?
+            }
+            (None, Some(jetstream)) if args.jetstream_migrate_from.is_some() => {
+                let from = args
+                    .jetstream_migrate_from
+                    .as_deref()
+                    .expect("just checked");
+                let (batches, report) = consumer::consume_migrating(
+                    from,
+                    jetstream,
+                    cursor,
+                    Duration::from_secs(args.jetstream_migrate_overlap_secs),
+                    false,
+                    sketch_secret,
+                    args.max_batched_collections,
+                )
+                .await?;
+                let admin = write_store.clone();
+                whatever_tasks.spawn(async move {
+                    let Ok(report) = report.await else {
+                        log::warn!("jetstream migration ended without a report");
+                        return Ok(());
+                    };
+                    let outcome = if report.complete {
+                        "ok".to_string()
+                    } else {
+                        "overlap incomplete: counts stop at the timeout, and commits only the old endpoint had may be missing".to_string()
+                    };
+                    admin
+                        .record_audit(AuditEntry {
+                            at: Cursor::at(SystemTime::now()).to_raw_u64(),
+                            actor: "consumer".to_string(),
+                            action: "jetstream_migration".to_string(),
+                            params: serde_json::to_value(&report)?,
+                            outcome,
+                        })
+                        .await?;
+                    Ok(())
+                });
+                batches
+            }
+            (None, Some(jetstream)) => {
+                consumer::consume(
+                    jetstream,
+                    cursor,
+                    false,
+                    sketch_secret,
+                    args.max_batched_collections,
+                )
+                .await?
+            }
+            #[cfg(feature = "firehose")]
+            (None, None) => {
+                let relay = args.firehose.as_deref().expect("clap requires a source");
+                firehose_consumer::consume(
+                    relay,
+                    cursor,
+                    args.data.join(firehose_consumer::CHECKPOINTS_FILE),
+                    sketch_secret,
+                    args.max_batched_collections,
+                )
+                .await?
+            }
+            #[cfg(not(feature = "firehose"))]
+            (None, None) => unreachable!("--firehose without the feature is refused at startup"),
+        };
+        spill::buffer(batches, spill, max_spilled, consumer::BATCH_QUEUE_SIZE)
     };
 
     let rolling = write_store
@@ -123,6 +768,23 @@ async fn go<B: StoreBackground + 'static>(
         Ok(())
     });
 
+    if let Some(webhooks) = webhooks {
+        whatever_tasks.spawn(async move {
+            webhooks
+                .deliver()
+                .await
+                .inspect_err(|e| log::warn!("webhook delivery ended: {e}"))
+        });
+    }
+
+    if let Some(bus) = bus {
+        whatever_tasks.spawn(async move {
+            bus.publish()
+                .await
+                .inspect_err(|e| log::warn!("event bus publishing ended: {e}"))
+        });
+    }
+
     consumer_tasks.spawn(async move {
         write_store
             .receive_batches(batches)
@@ -137,8 +799,6 @@ async fn go<B: StoreBackground + 'static>(
         Ok(())
     });
 
-    install_metrics_server()?;
-
     for (i, t) in consumer_tasks.join_all().await.iter().enumerate() {
         log::warn!("task {i} done: {t:?}");
     }
@@ -151,17 +811,24 @@ async fn go<B: StoreBackground + 'static>(
     Ok(())
 }
 
-fn install_metrics_server() -> anyhow::Result<()> {
+/// Install the global recorder and its standalone listener
+///
+/// The returned handle renders the same metrics for the api's `/metrics`.
+fn install_metrics_server() -> anyhow::Result<PrometheusHandle> {
     log::info!("installing metrics server...");
     let host = [0, 0, 0, 0];
     let port = 8765;
-    PrometheusBuilder::new()
+    let (recorder, exporter) = PrometheusBuilder::new()
         .set_quantiles(&[0.5, 0.9, 0.99, 1.0])?
         .set_bucket_duration(Duration::from_secs(60))?
         .set_bucket_count(std::num::NonZero::new(10).unwrap()) // count * duration = 10 mins. stuff doesn't happen that fast here.
         .set_enable_unit_suffix(false) // this seemed buggy for constellation (sometimes wouldn't engage)
         .with_http_listener((host, port))
-        .install()?;
+        .build()?;
+    let handle = recorder.handle();
+    metrics::set_global_recorder(recorder)
+        .map_err(|_| anyhow::anyhow!("a metrics recorder was already installed"))?;
+    tokio::spawn(exporter);
     log::info!(
         "metrics server installed! listening on http://{}.{}.{}.{}:{port}",
         host[0],
@@ -169,7 +836,7 @@ fn install_metrics_server() -> anyhow::Result<()> {
         host[2],
         host[3]
     );
-    Ok(())
+    Ok(handle)
 }
 
 async fn do_update_stuff(read_store: impl StoreReader) {
@@ -0,0 +1,158 @@
+//! Guards against pathological NSIDs reaching storage keys and the NSID tree
+//!
+//! The lexicon spec allows NSIDs far deeper and longer than anything real, and
+//! every distinct one gets its own keys, rollups, and NSID tree nodes.
+
+use crate::{EventBatch, Nsid};
+use metrics::counter;
+
+/// Collection that offending NSIDs are counted under when they're bucketed
+pub const INVALID_NSID: &str = "invalid.nsid";
+
+pub const DEFAULT_MAX_NSID_SEGMENTS: usize = 10;
+pub const DEFAULT_MAX_NSID_LEN: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NsidLimits {
+    /// most dot-separated segments, including the name
+    pub max_segments: usize,
+    /// most characters in the whole NSID
+    pub max_len: usize,
+    /// drop offenders instead of counting them under [`INVALID_NSID`]
+    pub reject: bool,
+}
+
+impl Default for NsidLimits {
+    fn default() -> Self {
+        Self {
+            max_segments: DEFAULT_MAX_NSID_SEGMENTS,
+            max_len: DEFAULT_MAX_NSID_LEN,
+            reject: false,
+        }
+    }
+}
+
+impl NsidLimits {
+    /// Why the NSID is over the limits, if it is
+    pub fn check(&self, nsid: &Nsid) -> Option<&'static str> {
+        let s = nsid.as_str();
+        if s.len() > self.max_len {
+            Some("length")
+        } else if s.split('.').count() > self.max_segments {
+            Some("segments")
+        } else {
+            None
+        }
+    }
+
+    /// Drop or bucket every collection in the batch that's over the limits
+    ///
+    /// Bucketing only keeps the counts: an offender's records are never stored.
+    pub fn apply<const LIMIT: usize>(&self, batch: &mut EventBatch<LIMIT>) {
+        let offenders: Vec<(Nsid, &'static str)> = batch
+            .commits_by_nsid
+            .keys()
+            .filter_map(|nsid| self.check(nsid).map(|reason| (nsid.clone(), reason)))
+            .collect();
+        if offenders.is_empty() {
+            return;
+        }
+        let sentinel = Nsid::new(INVALID_NSID.to_string()).expect("sentinel is a valid nsid");
+        let action = if self.reject { "rejected" } else { "bucketed" };
+        for (nsid, reason) in offenders {
+            let commits = batch.commits_by_nsid.remove(&nsid).expect("just found it");
+            log::debug!("nsid limits: {action} {nsid:?} ({reason})");
+            counter!("storage_nsid_guarded", "reason" => reason, "action" => action)
+                .increment(commits.commits.len() as u64);
+            if !self.reject {
+                batch
+                    .commits_by_nsid
+                    .entry(sentinel.clone())
+                    .or_default()
+                    .absorb_counts(&commits);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CommitAction, Cursor, Did, RecordKey, UFOsCommit};
+
+    fn batch(collections: &[&str]) -> EventBatch<8> {
+        batch_with_rkeys(collections, |i| format!("rkey-{i}"))
+    }
+
+    fn batch_with_rkeys(collections: &[&str], rkey: impl Fn(usize) -> String) -> EventBatch<8> {
+        let mut batch = EventBatch::default();
+        for (i, collection) in collections.iter().enumerate() {
+            let commit = UFOsCommit {
+                cursor: Cursor::from_raw_u64(100 + i as u64),
+                did: Did::new("did:plc:inze6wrmsm7pjl7yta3oig77".to_string()).unwrap(),
+                rkey: RecordKey::new(rkey(i)).unwrap(),
+                rev: "rev".to_string(),
+                action: CommitAction::Cut,
+            };
+            batch
+                .insert_commit_by_nsid(
+                    &Nsid::new(collection.to_string()).unwrap(),
+                    commit,
+                    8,
+                    &[0u8; 16],
+                )
+                .unwrap();
+        }
+        batch
+    }
+
+    #[test]
+    fn test_nsid_limits() {
+        let limits = NsidLimits {
+            max_segments: 4,
+            max_len: 20,
+            reject: false,
+        };
+        let nsid = |s: &str| Nsid::new(s.to_string()).unwrap();
+        assert_eq!(limits.check(&nsid("app.bsky.feed.post")), None);
+        assert_eq!(limits.check(&nsid("a.b.c.d.e")), Some("segments"));
+        assert_eq!(
+            limits.check(&nsid("com.example.aaaaaaaaaaaaa")),
+            Some("length")
+        );
+
+        let mut bucketed = batch(&["app.bsky.feed.post", "a.b.c.d.e", "a.b.c.d.f"]);
+        limits.apply(&mut bucketed);
+        assert_eq!(bucketed.commits_by_nsid.len(), 2);
+        let invalid = &bucketed.commits_by_nsid[&nsid(INVALID_NSID)];
+        assert_eq!(invalid.deletes, 2);
+        assert!(invalid.commits.is_empty());
+
+        let mut rejected = batch(&["app.bsky.feed.post", "a.b.c.d.e"]);
+        NsidLimits {
+            reject: true,
+            ..limits
+        }
+        .apply(&mut rejected);
+        assert_eq!(
+            rejected.commits_by_nsid.keys().collect::<Vec<_>>(),
+            vec![&nsid("app.bsky.feed.post")]
+        );
+    }
+
+    #[test]
+    fn test_bucketing_keeps_collections_apart() {
+        let limits = NsidLimits {
+            max_segments: 4,
+            ..Default::default()
+        };
+        // same did and rkey in two different over-deep collections
+        let mut bucketed = batch_with_rkeys(&["a.b.c.d.e", "a.b.c.d.f"], |_| "same".to_string());
+        limits.apply(&mut bucketed);
+        let invalid = &bucketed.commits_by_nsid[&Nsid::new(INVALID_NSID.to_string()).unwrap()];
+        assert_eq!(invalid.deletes, 2);
+        // no records are moved under the sentinel, where they'd share a location
+        assert!(invalid.commits.is_empty());
+        assert_eq!(bucketed.commits_by_nsid.len(), 1);
+    }
+}
@@ -0,0 +1,191 @@
+//! Hooks for embedders to see every batch on its way into storage
+//!
+//! Plugins run in the writer, after NSID limits are applied and before the
+//! denylist, so they see the same commits whether they came from a live
+//! consumer, a chased primary, or a spilled batch being replayed. Custom
+//! counters and side outputs can live here instead of in a fork.
+//!
+//! Plugins can also ask for what each batch actually stored, once it's
+//! committed: that's how webhooks, the event bus and the search index get
+//! their records. Features that write to the db in the same batch as the
+//! records (watchlist hits, unique counts, versions and diffs) stay in the
+//! storage backend.
+
+use crate::heavy_hitters::{HeavyHitters, BATCH_TOP_DIDS};
+use crate::publish::CountsDelta;
+use crate::store_types::HourTruncatedCursor;
+use crate::{Cursor, Did, EventBatch, Nsid, RecordKey, UFOsCommit, UFOsRecord};
+use metrics::histogram;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// One collection's share of a batch
+#[derive(Debug, Clone, Copy)]
+pub struct CollectionBatch<'a> {
+    pub creates: usize,
+    pub updates: usize,
+    pub deletes: usize,
+    /// the busiest accounts, counting every commit (even ones the batch didn't keep)
+    pub top_dids: &'a HeavyHitters<Did, BATCH_TOP_DIDS>,
+}
+
+/// What a batch stored, once it's committed
+#[derive(Debug, Default)]
+pub struct StoredBatch {
+    /// creates and updates as they were stored: after redaction and transforms
+    pub records: Vec<UFOsRecord>,
+    /// records deleted: (did, collection, rkey)
+    pub removed: Vec<(Did, Nsid, RecordKey)>,
+    /// each collection's counts, including denylisted ones that keep counts
+    pub counts: Vec<CountsDelta>,
+}
+
+/// Custom processing for commits before they're stored
+///
+/// Both hooks are called from the writer while it holds up the batch, so keep
+/// them cheap: hand anything slow off to another task. A batch keeps a
+/// limited number of commits per collection, so counts that must cover
+/// everything belong in [`IngestPlugin::collection`].
+pub trait IngestPlugin: Send + Sync {
+    /// Shown in logs and metrics
+    fn name(&self) -> &'static str;
+    /// Called with each commit kept in a batch, and its collection
+    fn commit(&self, _commit: &UFOsCommit, _collection: &Nsid) {}
+    /// Called once per collection in a batch, after its commits
+    ///
+    /// `hour` is the hour of the batch's latest event.
+    fn collection(
+        &self,
+        _hour: HourTruncatedCursor,
+        _collection: &Nsid,
+        _batch: CollectionBatch<'_>,
+    ) {
+    }
+    /// Whether [`IngestPlugin::stored`] should be called
+    ///
+    /// Off by default: collecting a [`StoredBatch`] clones every stored record.
+    fn wants_stored(&self) -> bool {
+        false
+    }
+    /// Called with what a batch stored, after it's committed
+    fn stored(&self, _batch: &StoredBatch) {}
+}
+
+/// The plugins a writer runs, in the order they were added
+#[derive(Clone, Default)]
+pub struct IngestPlugins(Vec<Arc<dyn IngestPlugin>>);
+
+impl IngestPlugins {
+    pub fn add(&mut self, plugin: impl IngestPlugin + 'static) {
+        self.0.push(Arc::new(plugin));
+    }
+    /// The built-in plugins first, then these
+    pub(crate) fn after_builtins(self, builtins: Vec<Arc<dyn IngestPlugin>>) -> Self {
+        Self(builtins.into_iter().chain(self.0).collect())
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    /// If any plugin wants a [`StoredBatch`] collected for it
+    pub(crate) fn want_stored(&self) -> bool {
+        self.0.iter().any(|plugin| plugin.wants_stored())
+    }
+    pub(crate) fn run_stored(&self, batch: &StoredBatch) {
+        for plugin in self.0.iter().filter(|plugin| plugin.wants_stored()) {
+            let t0 = Instant::now();
+            plugin.stored(batch);
+            histogram!("storage_ingest_plugin_stored_time", "plugin" => plugin.name())
+                .record(t0.elapsed().as_micros() as f64);
+        }
+    }
+    pub(crate) fn run<const LIMIT: usize>(&self, latest: Cursor, batch: &EventBatch<LIMIT>) {
+        let hour: HourTruncatedCursor = latest.into();
+        for plugin in &self.0 {
+            let t0 = Instant::now();
+            for (nsid, commits) in &batch.commits_by_nsid {
+                for commit in &commits.commits {
+                    plugin.commit(commit, nsid);
+                }
+                plugin.collection(
+                    hour,
+                    nsid,
+                    CollectionBatch {
+                        creates: commits.creates,
+                        updates: commits.updates,
+                        deletes: commits.deletes,
+                        top_dids: &commits.top_dids,
+                    },
+                );
+            }
+            histogram!("storage_ingest_plugin_time", "plugin" => plugin.name())
+                .record(t0.elapsed().as_micros() as f64);
+        }
+    }
+}
+
+impl fmt::Debug for IngestPlugins {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|plugin| plugin.name()))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CommitAction, RecordKey};
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    #[derive(Default, Clone)]
+    struct Tally(Arc<Mutex<HashMap<String, (usize, usize)>>>);
+
+    impl IngestPlugin for Tally {
+        fn name(&self) -> &'static str {
+            "tally"
+        }
+        fn commit(&self, _commit: &UFOsCommit, collection: &Nsid) {
+            let mut tally = self.0.lock().unwrap();
+            tally.entry(collection.to_string()).or_default().0 += 1;
+        }
+        fn collection(&self, _: HourTruncatedCursor, collection: &Nsid, batch: CollectionBatch) {
+            let mut tally = self.0.lock().unwrap();
+            tally.entry(collection.to_string()).or_default().1 += batch.deletes;
+        }
+    }
+
+    #[test]
+    fn test_plugins_see_every_collection() {
+        let mut batch = EventBatch::<8>::default();
+        for (i, collection) in ["a.b.c", "a.b.c", "d.e.f"].iter().enumerate() {
+            let commit = UFOsCommit {
+                cursor: Cursor::from_raw_u64(100 + i as u64),
+                did: Did::new("did:plc:inze6wrmsm7pjl7yta3oig77".to_string()).unwrap(),
+                rkey: RecordKey::new(format!("rkey-{i}")).unwrap(),
+                rev: "rev".to_string(),
+                action: CommitAction::Cut,
+            };
+            batch
+                .insert_commit_by_nsid(
+                    &Nsid::new(collection.to_string()).unwrap(),
+                    commit,
+                    8,
+                    &[0u8; 16],
+                )
+                .unwrap();
+        }
+
+        let tally = Tally::default();
+        let mut plugins = IngestPlugins::default();
+        plugins.add(tally.clone());
+        let plugins = plugins.after_builtins(vec![Arc::new(Tally::default())]);
+        assert_eq!(format!("{plugins:?}"), r#"["tally", "tally"]"#);
+
+        plugins.run(Cursor::from_raw_u64(102), &batch);
+        let tally = tally.0.lock().unwrap();
+        assert_eq!(tally["a.b.c"], (2, 2));
+        assert_eq!(tally["d.e.f"], (1, 1));
+    }
+}
@@ -0,0 +1,323 @@
+//! Forward processed records and count deltas to an external event bus
+//!
+//! Downstream systems get structured, deduplicated data without parsing
+//! jetstream themselves: records as they are stored (after deny rules,
+//! redaction, and transforms), plus per-collection counts from each batch.
+//!
+//! Backends are behind cargo features: `nats` and `kafka`.
+
+use crate::plugin::{IngestPlugin, StoredBatch};
+use crate::UFOsRecord;
+use async_trait::async_trait;
+use metrics::{counter, describe_counter, Unit};
+use serde::Serialize;
+use tokio::sync::mpsc::{channel, error::TrySendError, Receiver, Sender};
+
+/// Messages waiting to be published, before new ones get dropped
+const QUEUE_SIZE: usize = 50_000;
+
+/// Where to publish
+///
+/// - NATS: subjects `<prefix>.records.<nsid>` and `<prefix>.counts.<nsid>`,
+///   so subscribers can use wildcards like `ufos.records.app.bsky.>`
+/// - Kafka: topics `<prefix>.records` and `<prefix>.counts`, with the NSID
+///   as the message key
+#[derive(Debug, Clone, PartialEq)]
+pub enum BusTarget {
+    /// A NATS server url like `nats://localhost:4222`
+    Nats(String),
+    /// Comma-separated kafka bootstrap servers
+    Kafka(String),
+}
+
+impl BusTarget {
+    /// Parse a `nats://...` or `kafka://host:port,host:port` url
+    pub fn parse(url: &str) -> Result<Self, String> {
+        if url.starts_with("nats://") || url.starts_with("tls://") {
+            Ok(Self::Nats(url.to_string()))
+        } else if let Some(brokers) = url.strip_prefix("kafka://") {
+            if brokers.is_empty() {
+                return Err("no kafka brokers in publish url".to_string());
+            }
+            Ok(Self::Kafka(brokers.to_string()))
+        } else {
+            Err(format!(
+                "publish url must start with nats://, tls://, or kafka:// (got {url:?})"
+            ))
+        }
+    }
+}
+
+/// A collection's counts from one inserted batch
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CountsDelta {
+    pub collection: String,
+    /// The latest event in the batch (jetstream cursor, microseconds)
+    pub cursor: u64,
+    pub creates: u64,
+    pub updates: u64,
+    pub deletes: u64,
+}
+
+#[derive(Debug, Clone)]
+pub enum BusMessage {
+    Record(UFOsRecord),
+    Counts(CountsDelta),
+}
+
+impl BusMessage {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Record(_) => "records",
+            Self::Counts(_) => "counts",
+        }
+    }
+    fn collection(&self) -> &str {
+        match self {
+            Self::Record(r) => r.collection.as_str(),
+            Self::Counts(c) => &c.collection,
+        }
+    }
+    fn payload(&self) -> serde_json::Result<Vec<u8>> {
+        match self {
+            Self::Record(r) => serde_json::to_vec(r),
+            Self::Counts(c) => serde_json::to_vec(c),
+        }
+    }
+}
+
+/// Publishes each committed batch's records, then its count deltas
+impl IngestPlugin for BusTap {
+    fn name(&self) -> &'static str {
+        "bus"
+    }
+    fn wants_stored(&self) -> bool {
+        true
+    }
+    fn stored(&self, batch: &StoredBatch) {
+        for record in &batch.records {
+            self.offer(BusMessage::Record(record.clone()));
+        }
+        for counts in &batch.counts {
+            self.offer(BusMessage::Counts(counts.clone()));
+        }
+    }
+}
+
+/// Hands processed data to the publisher. Cheap to clone.
+#[derive(Debug, Clone)]
+pub struct BusTap(Sender<BusMessage>);
+
+impl BusTap {
+    /// Queue a message for publishing
+    ///
+    /// Never waits: if the publisher is too far behind, the message is dropped.
+    pub fn offer(&self, message: BusMessage) {
+        let kind = message.kind();
+        match self.0.try_send(message) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                counter!("bus_dropped", "kind" => kind, "reason" => "queue_full").increment(1);
+            }
+            Err(TrySendError::Closed(_)) => {
+                counter!("bus_dropped", "kind" => kind, "reason" => "closed").increment(1);
+            }
+        }
+    }
+}
+
+#[async_trait]
+trait Publisher: Send + Sync {
+    async fn send(
+        &self,
+        prefix: &str,
+        message: &BusMessage,
+        payload: Vec<u8>,
+    ) -> anyhow::Result<()>;
+}
+
+#[cfg(feature = "nats")]
+struct NatsPublisher(async_nats::Client);
+
+#[cfg(feature = "nats")]
+#[async_trait]
+impl Publisher for NatsPublisher {
+    async fn send(
+        &self,
+        prefix: &str,
+        message: &BusMessage,
+        payload: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        let subject = format!("{prefix}.{}.{}", message.kind(), message.collection());
+        self.0.publish(subject, payload.into()).await?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "kafka")]
+struct KafkaPublisher(rdkafka::producer::FutureProducer);
+
+#[cfg(feature = "kafka")]
+#[async_trait]
+impl Publisher for KafkaPublisher {
+    async fn send(
+        &self,
+        prefix: &str,
+        message: &BusMessage,
+        payload: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        let topic = format!("{prefix}.{}", message.kind());
+        self.0
+            .send(
+                rdkafka::producer::FutureRecord::to(&topic)
+                    .key(message.collection())
+                    .payload(&payload),
+                std::time::Duration::from_secs(10),
+            )
+            .await
+            .map_err(|(e, _)| e)?;
+        Ok(())
+    }
+}
+
+async fn connect(target: &BusTarget) -> anyhow::Result<Box<dyn Publisher>> {
+    match target {
+        #[cfg(feature = "nats")]
+        BusTarget::Nats(url) => Ok(Box::new(NatsPublisher(
+            async_nats::connect(url.as_str()).await?,
+        ))),
+        #[cfg(feature = "kafka")]
+        BusTarget::Kafka(brokers) => Ok(Box::new(KafkaPublisher(
+            rdkafka::ClientConfig::new()
+                .set("bootstrap.servers", brokers)
+                .set("message.timeout.ms", "30000")
+                .create()?,
+        ))),
+        #[allow(unreachable_patterns)]
+        other => anyhow::bail!(
+            "publishing to {other:?} needs ufos built with the {:?} feature",
+            match other {
+                BusTarget::Nats(_) => "nats",
+                BusTarget::Kafka(_) => "kafka",
+            }
+        ),
+    }
+}
+
+/// A configured publisher: a tap for the writer, and the publishing to run
+#[derive(Debug)]
+pub struct EventBus {
+    target: BusTarget,
+    prefix: String,
+    tap: BusTap,
+    receiver: Receiver<BusMessage>,
+}
+
+impl EventBus {
+    /// Publish to a target, with subjects or topics starting with `prefix`
+    pub fn new(target: BusTarget, prefix: &str) -> Self {
+        let (sender, receiver) = channel(QUEUE_SIZE);
+        Self {
+            target,
+            prefix: prefix.to_string(),
+            tap: BusTap(sender),
+            receiver,
+        }
+    }
+    /// For the writer to offer processed data to
+    pub fn tap(&self) -> BusTap {
+        self.tap.clone()
+    }
+    /// Connect and publish until the taps are all dropped
+    ///
+    /// Failed messages are logged and dropped: the clients already retry
+    /// and reconnect on their own.
+    pub async fn publish(mut self) -> anyhow::Result<()> {
+        describe_counter!(
+            "bus_published",
+            Unit::Count,
+            "records and count deltas published to the event bus"
+        );
+        describe_counter!(
+            "bus_dropped",
+            Unit::Count,
+            "records and count deltas never published, by reason"
+        );
+        let publisher = connect(&self.target).await?;
+        log::info!(
+            "publishing to {:?} with prefix {:?}",
+            self.target,
+            self.prefix
+        );
+        // our own tap would keep the receiver open forever
+        drop(self.tap);
+        while let Some(message) = self.receiver.recv().await {
+            let kind = message.kind();
+            let sent = match message.payload() {
+                Ok(payload) => publisher.send(&self.prefix, &message, payload).await,
+                Err(e) => Err(e.into()),
+            };
+            match sent {
+                Ok(()) => counter!("bus_published", "kind" => kind).increment(1),
+                Err(e) => {
+                    log::warn!("failed to publish {kind} for {}: {e}", message.collection());
+                    counter!("bus_dropped", "kind" => kind, "reason" => "failed").increment(1);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Check that a prefix is usable as the start of NATS subjects and kafka topics
+pub fn valid_prefix(prefix: &str) -> bool {
+    prefix.split('.').all(|part| {
+        !part.is_empty()
+            && part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_target() {
+        assert_eq!(
+            BusTarget::parse("nats://localhost:4222"),
+            Ok(BusTarget::Nats("nats://localhost:4222".to_string()))
+        );
+        assert_eq!(
+            BusTarget::parse("kafka://a:9092,b:9092"),
+            Ok(BusTarget::Kafka("a:9092,b:9092".to_string()))
+        );
+        assert!(BusTarget::parse("kafka://").is_err());
+        assert!(BusTarget::parse("http://localhost").is_err());
+    }
+
+    #[test]
+    fn test_valid_prefix() {
+        assert!(valid_prefix("ufos"));
+        assert!(valid_prefix("prod.ufos-1"));
+        assert!(!valid_prefix(""));
+        assert!(!valid_prefix("ufos."));
+        assert!(!valid_prefix("ufos.>"));
+    }
+
+    #[test]
+    fn test_tap_drops_when_closed() {
+        let bus = EventBus::new(BusTarget::Nats("nats://x".to_string()), "ufos");
+        let tap = bus.tap();
+        drop(bus);
+        // doesn't block or panic
+        tap.offer(BusMessage::Counts(CountsDelta {
+            collection: "a.b.c".to_string(),
+            cursor: 1,
+            creates: 1,
+            updates: 0,
+            deletes: 0,
+        }));
+    }
+}
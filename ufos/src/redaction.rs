@@ -0,0 +1,263 @@
+use crate::error::RedactionConfigError;
+use crate::{Nsid, NsidPrefix};
+use serde::Deserialize;
+use serde_json::value::RawValue;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Redaction config, as loaded from a json file
+///
+/// ```json
+/// {
+///   "version": 2,
+///   "rules": [
+///     { "collection": "com.example.profile", "paths": ["contact.email"] },
+///     { "collection": "com.example.forms.*", "paths": ["answers.*.email"] }
+///   ]
+/// }
+/// ```
+///
+/// Bump `version` whenever the rules change: it's stored with every record
+/// that had redaction applied, so it's possible to tell which rules were in
+/// effect when a record was written.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RedactionConfig {
+    pub version: u32,
+    pub rules: Vec<RedactionRule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RedactionRule {
+    /// Either an exact collection NSID, or a group prefix ending with `.*`
+    pub collection: String,
+    /// Dot-separated paths into the record to remove
+    ///
+    /// A `*` segment matches every item of an array or every value of an object.
+    pub paths: Vec<String>,
+}
+
+impl RedactionConfig {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, RedactionConfigError> {
+        let f = std::fs::File::open(path)?;
+        Ok(serde_json::from_reader(std::io::BufReader::new(f))?)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Key(String),
+    Each,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct JsonPath(Vec<Segment>);
+
+impl JsonPath {
+    pub(crate) fn parse(path: &str) -> Result<Self, RedactionConfigError> {
+        let segments = path
+            .split('.')
+            .map(|s| match s {
+                "" => Err(RedactionConfigError::BadPath(path.to_string())),
+                "*" => Ok(Segment::Each),
+                k => Ok(Segment::Key(k.to_string())),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self(segments))
+    }
+    /// remove everything matching this path. returns true if anything was removed.
+    pub(crate) fn remove_from(&self, value: &mut Value) -> bool {
+        remove_path(&self.0, value)
+    }
+}
+
+fn remove_path(segments: &[Segment], value: &mut Value) -> bool {
+    let Some((segment, rest)) = segments.split_first() else {
+        return false;
+    };
+    match (segment, value) {
+        (Segment::Key(k), Value::Object(o)) if rest.is_empty() => o.remove(k).is_some(),
+        (Segment::Key(k), Value::Object(o)) => {
+            o.get_mut(k).map(|v| remove_path(rest, v)).unwrap_or(false)
+        }
+        (Segment::Each, Value::Array(a)) if rest.is_empty() => {
+            let had_any = !a.is_empty();
+            a.clear();
+            had_any
+        }
+        (Segment::Each, Value::Array(a)) => a
+            .iter_mut()
+            .fold(false, |removed, v| remove_path(rest, v) || removed),
+        (Segment::Each, Value::Object(o)) if rest.is_empty() => {
+            let had_any = !o.is_empty();
+            o.clear();
+            had_any
+        }
+        (Segment::Each, Value::Object(o)) => o
+            .values_mut()
+            .fold(false, |removed, v| remove_path(rest, v) || removed),
+        _ => false,
+    }
+}
+
+/// Compiled redaction rules, applied to records before they are stored
+#[derive(Debug, Default, Clone)]
+pub struct Redactor {
+    version: u32,
+    exact: HashMap<String, Vec<JsonPath>>,
+    prefixes: Vec<(String, Vec<JsonPath>)>,
+}
+
+impl Redactor {
+    pub fn new(config: RedactionConfig) -> Result<Self, RedactionConfigError> {
+        let mut me = Self {
+            version: config.version,
+            ..Default::default()
+        };
+        for rule in config.rules {
+            let paths = rule
+                .paths
+                .iter()
+                .map(|p| JsonPath::parse(p))
+                .collect::<Result<Vec<_>, _>>()?;
+            if let Some(prefix) = rule.collection.strip_suffix(".*") {
+                NsidPrefix::new(prefix)?;
+                // keep the dot so that we only match full segments
+                let prefix = format!("{prefix}.");
+                me.prefixes.push((prefix, paths));
+            } else {
+                Nsid::new(rule.collection.clone())
+                    .map_err(|e| RedactionConfigError::BadCollection(rule.collection.clone(), e))?;
+                me.exact.entry(rule.collection).or_default().extend(paths);
+            }
+        }
+        Ok(me)
+    }
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+    fn paths_for<'a>(&'a self, collection: &'a Nsid) -> impl Iterator<Item = &'a JsonPath> {
+        let exact = self.exact.get(collection.as_str()).into_iter().flatten();
+        let prefixed = self
+            .prefixes
+            .iter()
+            .filter(|(prefix, _)| collection.as_str().starts_with(prefix))
+            .flat_map(|(_, paths)| paths);
+        exact.chain(prefixed)
+    }
+    /// Check if any rules apply to a collection
+    pub fn applies_to(&self, collection: &Nsid) -> bool {
+        self.paths_for(collection).next().is_some()
+    }
+    /// Strip configured paths from a record
+    ///
+    /// Returns `None` if no rules apply to the collection, otherwise the
+    /// (possibly unchanged) record along with the config version.
+    pub fn redact(
+        &self,
+        collection: &Nsid,
+        record: &RawValue,
+    ) -> Result<Option<(Box<RawValue>, u32)>, serde_json::Error> {
+        if !self.applies_to(collection) {
+            return Ok(None);
+        }
+        let mut value: Value = serde_json::from_str(record.get())?;
+        let mut removed = false;
+        for path in self.paths_for(collection) {
+            removed |= path.remove_from(&mut value);
+        }
+        let redacted = if removed {
+            serde_json::value::to_raw_value(&value)?
+        } else {
+            record.to_owned()
+        };
+        Ok(Some((redacted, self.version)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nsid(s: &str) -> Nsid {
+        Nsid::new(s.to_string()).unwrap()
+    }
+
+    fn redactor(rules: &[(&str, &[&str])]) -> Redactor {
+        Redactor::new(RedactionConfig {
+            version: 7,
+            rules: rules
+                .iter()
+                .map(|(collection, paths)| RedactionRule {
+                    collection: collection.to_string(),
+                    paths: paths.iter().map(|p| p.to_string()).collect(),
+                })
+                .collect(),
+        })
+        .unwrap()
+    }
+
+    fn redact(r: &Redactor, collection: &str, record: &str) -> Option<(String, u32)> {
+        let raw = RawValue::from_string(record.to_string()).unwrap();
+        r.redact(&nsid(collection), &raw)
+            .unwrap()
+            .map(|(v, version)| (v.get().to_string(), version))
+    }
+
+    #[test]
+    fn test_bad_config() {
+        let bad = |collection: &str, path: &str| {
+            Redactor::new(RedactionConfig {
+                version: 1,
+                rules: vec![RedactionRule {
+                    collection: collection.to_string(),
+                    paths: vec![path.to_string()],
+                }],
+            })
+            .is_err()
+        };
+        assert!(!bad("a.b.c", "email"));
+        assert!(!bad("a.b.*", "contact.*.email"));
+        assert!(bad("a.*", "email"));
+        assert!(bad("a.b.c", "contact..email"));
+        assert!(bad("a.b.c", ""));
+    }
+
+    #[test]
+    fn test_redact_paths() {
+        let r = redactor(&[("a.b.c", &["email", "contact.phone", "people.*.email"])]);
+        assert_eq!(
+            redact(&r, "a.b.c", r#"{"email":"x@y.z","name":"n"}"#),
+            Some((r#"{"name":"n"}"#.to_string(), 7))
+        );
+        assert_eq!(
+            redact(&r, "a.b.c", r#"{"contact":{"phone":"123","city":"c"}}"#),
+            Some((r#"{"contact":{"city":"c"}}"#.to_string(), 7))
+        );
+        assert_eq!(
+            redact(
+                &r,
+                "a.b.c",
+                r#"{"people":[{"email":"a","n":1},{"n":2},{"email":"b"}]}"#
+            ),
+            Some((r#"{"people":[{"n":1},{"n":2},{}]}"#.to_string(), 7))
+        );
+    }
+
+    #[test]
+    fn test_unmatched_unchanged() {
+        let r = redactor(&[("a.b.c", &["email"]), ("x.y.*", &["secret"])]);
+        // no rules for the collection: nothing to do
+        assert_eq!(redact(&r, "a.b.d", r#"{"email": "x"}"#), None);
+        assert_eq!(redact(&r, "x.yz.w", r#"{"secret": "x"}"#), None);
+        // rules apply but nothing matched: original bytes are kept
+        assert_eq!(
+            redact(&r, "a.b.c", r#"{"name": "x"}"#),
+            Some((r#"{"name": "x"}"#.to_string(), 7))
+        );
+        assert_eq!(
+            redact(&r, "x.y.z", r#"{"secret": "x"}"#),
+            Some(("{}".to_string(), 7))
+        );
+    }
+}
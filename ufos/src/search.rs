@@ -0,0 +1,427 @@
+//! Optional full-text index over stored record bodies
+//!
+//! Every string in a record (after redaction and transforms) is indexed with
+//! [tantivy](https://github.com/quickwit-oss/tantivy) as the record is stored,
+//! so records can be found by what they say without paging through a whole
+//! feed. Deletes, trims, purges and account deletes come out of the index
+//! too, and every hit is looked up in storage before it's returned, so
+//! anything the index missed never shows up.
+//!
+//! The index is best-effort, like the cache: write errors are logged and
+//! counted, and changes are only committed every few seconds, so a crash can
+//! lose the latest ones.
+//!
+//! Needs the `search` feature. Without it, [`SearchIndex`] can't be opened, so
+//! storage never has one.
+
+use crate::error::SearchError;
+use crate::plugin::{IngestPlugin, StoredBatch};
+use crate::{Did, Nsid, RecordKey};
+#[cfg(feature = "search")]
+use metrics::counter;
+use serde_json::value::RawValue;
+#[cfg(feature = "search")]
+use serde_json::Value;
+#[cfg(feature = "search")]
+use std::fmt;
+use std::path::Path;
+#[cfg(feature = "search")]
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "search")]
+use std::time::{Duration, Instant};
+#[cfg(feature = "search")]
+use tantivy::{
+    collector::TopDocs,
+    directory::MmapDirectory,
+    doc,
+    query::{BooleanQuery, Occur, Query, QueryParser, TermQuery},
+    schema::{Field, IndexRecordOption, Schema, Value as _, STORED, STRING, TEXT},
+    Index, IndexReader, IndexWriter, ReloadPolicy, TantivyDocument, Term,
+};
+
+/// Most bytes of text indexed from one record
+#[cfg(feature = "search")]
+const MAX_INDEXED_BYTES: usize = 32 * 1024;
+/// Memory for tantivy's indexing buffers, across its threads
+#[cfg(feature = "search")]
+const WRITER_MEMORY_BYTES: usize = 64 * 1024 * 1024;
+/// Changes wait at least this long to be committed, since every commit writes new segments
+#[cfg(feature = "search")]
+const COMMIT_INTERVAL: Duration = Duration::from_secs(5);
+
+#[cfg(feature = "search")]
+fn schema() -> Schema {
+    let mut schema = Schema::builder();
+    schema.add_text_field("location", STRING);
+    schema.add_text_field("did", STRING | STORED);
+    schema.add_text_field("collection", STRING | STORED);
+    schema.add_text_field("rkey", STORED);
+    schema.add_text_field("text", TEXT);
+    schema.build()
+}
+
+#[cfg(feature = "search")]
+#[derive(Debug, Clone, Copy)]
+struct Fields {
+    /// unique per record, for replacing and removing it
+    location: Field,
+    did: Field,
+    collection: Field,
+    rkey: Field,
+    text: Field,
+}
+
+#[cfg(feature = "search")]
+fn index_error(e: impl ToString) -> SearchError {
+    SearchError::Index(e.to_string())
+}
+
+#[cfg(feature = "search")]
+fn location(did: &Did, collection: &Nsid, rkey: &RecordKey) -> String {
+    format!("{}/{}/{}", did.as_str(), collection.as_str(), rkey.as_str())
+}
+
+/// Every string in a record, one per line, leaving out `$`-keys like `$type`
+/// and CID `$link`s
+#[cfg(feature = "search")]
+fn record_text(value: &Value, text: &mut String) {
+    match value {
+        Value::String(s) => {
+            if !text.is_empty() {
+                text.push('\n');
+            }
+            text.push_str(s);
+        }
+        Value::Array(values) => values.iter().for_each(|v| record_text(v, text)),
+        Value::Object(fields) => fields
+            .iter()
+            .filter(|(k, _)| !k.starts_with('$'))
+            .for_each(|(_, v)| record_text(v, text)),
+        _ => {}
+    }
+}
+
+/// A full-text index of record bodies, shared by the writer and readers
+#[cfg(feature = "search")]
+#[derive(Clone)]
+pub struct SearchIndex {
+    index: Index,
+    fields: Fields,
+    /// the writer, and when it last committed
+    writer: Arc<Mutex<(IndexWriter, Instant)>>,
+    reader: IndexReader,
+}
+
+#[cfg(feature = "search")]
+impl fmt::Debug for SearchIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SearchIndex")
+            .field("fields", &self.fields)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "search")]
+impl SearchIndex {
+    /// Open the index in `dir`, creating it if it's not there yet
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self, SearchError> {
+        std::fs::create_dir_all(&dir).map_err(index_error)?;
+        let directory = MmapDirectory::open(dir).map_err(index_error)?;
+        Self::from_index(Index::open_or_create(directory, schema()).map_err(index_error)?)
+    }
+    #[cfg(test)]
+    pub(crate) fn in_memory() -> Self {
+        Self::from_index(Index::create_in_ram(schema())).unwrap()
+    }
+    fn from_index(index: Index) -> Result<Self, SearchError> {
+        let schema = index.schema();
+        let field = |name| schema.get_field(name).map_err(index_error);
+        let fields = Fields {
+            location: field("location")?,
+            did: field("did")?,
+            collection: field("collection")?,
+            rkey: field("rkey")?,
+            text: field("text")?,
+        };
+        let writer: IndexWriter = index.writer(WRITER_MEMORY_BYTES).map_err(index_error)?;
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::Manual)
+            .try_into()
+            .map_err(index_error)?;
+        Ok(Self {
+            index,
+            fields,
+            writer: Arc::new(Mutex::new((writer, Instant::now()))),
+            reader,
+        })
+    }
+
+    /// Index a record's text, replacing anything indexed for an earlier version
+    pub fn add(&self, did: &Did, collection: &Nsid, rkey: &RecordKey, record: &RawValue) {
+        let location = location(did, collection, rkey);
+        let mut text = String::new();
+        match serde_json::from_str(record.get()) {
+            Ok(value) => record_text(&value, &mut text),
+            Err(e) => return write_failed("add", e),
+        }
+        if text.len() > MAX_INDEXED_BYTES {
+            let mut end = MAX_INDEXED_BYTES;
+            while !text.is_char_boundary(end) {
+                end -= 1;
+            }
+            text.truncate(end);
+        }
+        let writer = self.writer.lock().unwrap();
+        writer
+            .0
+            .delete_term(Term::from_field_text(self.fields.location, &location));
+        if text.is_empty() {
+            return;
+        }
+        if let Err(e) = writer.0.add_document(doc!(
+            self.fields.location => location,
+            self.fields.did => did.to_string(),
+            self.fields.collection => collection.to_string(),
+            self.fields.rkey => rkey.to_string(),
+            self.fields.text => text,
+        )) {
+            return write_failed("add", e);
+        }
+        counter!("search_records_indexed").increment(1);
+    }
+    pub fn remove(&self, did: &Did, collection: &Nsid, rkey: &RecordKey) {
+        self.delete(self.fields.location, &location(did, collection, rkey));
+    }
+    pub fn remove_account(&self, did: &Did) {
+        self.delete(self.fields.did, did.as_str());
+    }
+    pub fn remove_collection(&self, collection: &Nsid) {
+        self.delete(self.fields.collection, collection.as_str());
+    }
+    fn delete(&self, field: Field, value: &str) {
+        let writer = self.writer.lock().unwrap();
+        writer.0.delete_term(Term::from_field_text(field, value));
+    }
+
+    /// Make changes searchable, unless the last commit was very recent
+    pub fn commit_if_due(&self) {
+        let mut writer = self.writer.lock().unwrap();
+        if writer.1.elapsed() < COMMIT_INTERVAL {
+            return;
+        }
+        self.commit_locked(&mut writer);
+    }
+    /// Make changes searchable now
+    pub fn commit(&self) {
+        self.commit_locked(&mut self.writer.lock().unwrap());
+    }
+    fn commit_locked(&self, (writer, committed_at): &mut (IndexWriter, Instant)) {
+        *committed_at = Instant::now();
+        if let Err(e) = writer.commit() {
+            return write_failed("commit", e);
+        }
+        if let Err(e) = self.reader.reload() {
+            write_failed("reload", e);
+        }
+    }
+
+    /// Where the records best matching a query are, best first
+    ///
+    /// Queries use tantivy's syntax: every word has to match unless they're
+    /// joined with `OR`, `"quoted phrases"` match in order, and `-word`
+    /// excludes. Matching ignores case.
+    pub fn search(
+        &self,
+        query: &str,
+        collection: Option<&Nsid>,
+        limit: usize,
+    ) -> Result<Vec<(Did, Nsid, RecordKey)>, SearchError> {
+        let mut parser = QueryParser::for_index(&self.index, vec![self.fields.text]);
+        parser.set_conjunction_by_default();
+        let mut query = parser
+            .parse_query(query)
+            .map_err(|e| SearchError::BadQuery(e.to_string()))?;
+        if let Some(collection) = collection {
+            let only_collection: Box<dyn Query> = Box::new(TermQuery::new(
+                Term::from_field_text(self.fields.collection, collection.as_str()),
+                IndexRecordOption::Basic,
+            ));
+            query = Box::new(BooleanQuery::new(vec![
+                (Occur::Must, query),
+                (Occur::Must, only_collection),
+            ]));
+        }
+        let searcher = self.reader.searcher();
+        let top = searcher
+            .search(&query, &TopDocs::with_limit(limit.max(1)))
+            .map_err(index_error)?;
+        let mut found = Vec::with_capacity(top.len());
+        for (_score, address) in top {
+            let doc: TantivyDocument = searcher.doc(address).map_err(index_error)?;
+            let stored = |field, name| {
+                doc.get_first(field)
+                    .and_then(|v| v.as_str())
+                    .map(str::to_string)
+                    .ok_or_else(|| SearchError::Index(format!("indexed record has no {name}")))
+            };
+            found.push((
+                Did::new(stored(self.fields.did, "did")?).map_err(index_error)?,
+                Nsid::new(stored(self.fields.collection, "collection")?).map_err(index_error)?,
+                RecordKey::new(stored(self.fields.rkey, "rkey")?).map_err(index_error)?,
+            ));
+        }
+        Ok(found)
+    }
+}
+
+#[cfg(feature = "search")]
+fn write_failed(op: &'static str, e: impl fmt::Display) {
+    log::warn!("search index {op} failed: {e}");
+    counter!("search_index_errors", "op" => op).increment(1);
+}
+
+/// Stand-in for builds without the `search` feature: it can't be opened, so
+/// there is never one to use
+#[cfg(not(feature = "search"))]
+#[derive(Debug, Clone)]
+pub enum SearchIndex {}
+
+#[cfg(not(feature = "search"))]
+impl SearchIndex {
+    pub fn open(_dir: impl AsRef<Path>) -> Result<Self, SearchError> {
+        Err(SearchError::NotBuilt)
+    }
+    pub fn add(&self, _did: &Did, _collection: &Nsid, _rkey: &RecordKey, _record: &RawValue) {
+        match *self {}
+    }
+    pub fn remove(&self, _did: &Did, _collection: &Nsid, _rkey: &RecordKey) {
+        match *self {}
+    }
+    pub fn remove_account(&self, _did: &Did) {
+        match *self {}
+    }
+    pub fn remove_collection(&self, _collection: &Nsid) {
+        match *self {}
+    }
+    pub fn commit_if_due(&self) {
+        match *self {}
+    }
+    pub fn commit(&self) {
+        match *self {}
+    }
+    pub fn search(
+        &self,
+        _query: &str,
+        _collection: Option<&Nsid>,
+        _limit: usize,
+    ) -> Result<Vec<(Did, Nsid, RecordKey)>, SearchError> {
+        match *self {}
+    }
+}
+
+/// Indexes records as they're stored
+impl IngestPlugin for SearchIndex {
+    fn name(&self) -> &'static str {
+        "search"
+    }
+    fn wants_stored(&self) -> bool {
+        true
+    }
+    fn stored(&self, batch: &StoredBatch) {
+        for (did, collection, rkey) in &batch.removed {
+            self.remove(did, collection, rkey);
+        }
+        for r in &batch.records {
+            self.add(&r.did, &r.collection, &r.rkey, &r.record);
+        }
+        self.commit_if_due();
+    }
+}
+
+#[cfg(all(test, feature = "search"))]
+mod tests {
+    use super::*;
+
+    fn nsid(s: &str) -> Nsid {
+        Nsid::new(s.to_string()).unwrap()
+    }
+
+    fn add(index: &SearchIndex, collection: &str, rkey: &str, record: &str) {
+        index.add(
+            &Did::new("did:plc:inze6wrmsm7pjl7yta3oig77".to_string()).unwrap(),
+            &nsid(collection),
+            &RecordKey::new(rkey.to_string()).unwrap(),
+            &RawValue::from_string(record.to_string()).unwrap(),
+        );
+    }
+
+    fn rkeys(found: Vec<(Did, Nsid, RecordKey)>) -> Vec<String> {
+        let mut rkeys: Vec<_> = found.into_iter().map(|(_, _, r)| r.to_string()).collect();
+        rkeys.sort();
+        rkeys
+    }
+
+    #[test]
+    fn test_record_text() {
+        let mut text = String::new();
+        record_text(
+            &serde_json::json!({
+                "$type": "a.b.c",
+                "text": "hello",
+                "tags": ["one", 2, {"name": "two"}],
+                "embed": {"ref": {"$link": "bafyrei"}},
+            }),
+            &mut text,
+        );
+        let mut lines: Vec<_> = text.lines().collect();
+        lines.sort();
+        assert_eq!(lines, vec!["hello", "one", "two"]);
+    }
+
+    #[test]
+    fn test_search_index() {
+        let index = SearchIndex::in_memory();
+        add(&index, "a.b.c", "1", r#"{"text": "the quick brown fox"}"#);
+        add(&index, "a.b.c", "2", r#"{"text": "a lazy dog"}"#);
+        add(&index, "d.e.f", "3", r#"{"name": "Quick Dog"}"#);
+        add(&index, "d.e.f", "4", r#"{"count": 3}"#);
+        index.commit();
+
+        assert_eq!(
+            rkeys(index.search("quick", None, 10).unwrap()),
+            vec!["1", "3"]
+        );
+        assert_eq!(
+            rkeys(index.search("quick dog", None, 10).unwrap()),
+            vec!["3"]
+        );
+        assert_eq!(
+            rkeys(index.search("quick OR lazy", None, 10).unwrap()),
+            vec!["1", "2", "3"]
+        );
+        assert_eq!(
+            rkeys(index.search("quick", Some(&nsid("d.e.f")), 10).unwrap()),
+            vec!["3"]
+        );
+        assert!(matches!(
+            index.search("text:(", None, 10),
+            Err(SearchError::BadQuery(_))
+        ));
+
+        // a new version replaces the old one
+        add(&index, "a.b.c", "1", r#"{"text": "a slow fox"}"#);
+        index.remove(
+            &Did::new("did:plc:inze6wrmsm7pjl7yta3oig77".to_string()).unwrap(),
+            &nsid("d.e.f"),
+            &RecordKey::new("3".to_string()).unwrap(),
+        );
+        index.commit();
+        assert!(index.search("quick", None, 10).unwrap().is_empty());
+        assert_eq!(rkeys(index.search("fox", None, 10).unwrap()), vec!["1"]);
+
+        index.remove_collection(&nsid("a.b.c"));
+        index.commit();
+        assert!(index.search("fox OR dog", None, 10).unwrap().is_empty());
+    }
+}
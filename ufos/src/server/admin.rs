@@ -0,0 +1,571 @@
+use super::auth::{Auth, Scope};
+use super::time_params::QueryPeriod;
+use super::{instrument_handler, WithAuth};
+use crate::denylist::DenyRule;
+use crate::heavy_hitters::{NoisyDidsReport, MAX_NOISY_HOURS};
+use crate::storage::StoreAdmin;
+use crate::{
+    AccountDeletesStatus, AuditEntry, BackgroundIntervals, BackgroundStatus, BackgroundTask,
+    Cursor, KeySpaceReport, Nsid, PurgeReport,
+};
+use dropshot::endpoint;
+use dropshot::ApiDescription;
+use dropshot::ConfigDropshot;
+use dropshot::ConfigLogging;
+use dropshot::ConfigLoggingLevel;
+use dropshot::HttpError;
+use dropshot::HttpResponseOk;
+use dropshot::Query;
+use dropshot::RequestContext;
+use dropshot::ServerBuilder;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+struct AdminContext {
+    admin: Box<dyn StoreAdmin>,
+    auth: Auth,
+}
+
+impl WithAuth for AdminContext {
+    fn auth(&self) -> &Auth {
+        &self.auth
+    }
+}
+
+/// Record an admin action and how it went in the audit log
+async fn audit<T>(
+    ctx: &RequestContext<AdminContext>,
+    params: serde_json::Value,
+    result: &Result<T, HttpError>,
+) {
+    let AdminContext { admin, auth } = ctx.context();
+    let entry = AuditEntry {
+        at: Cursor::at(SystemTime::now()).to_raw_u64(),
+        actor: auth.caller(&ctx.request),
+        action: ctx.endpoint.operation_id.clone(),
+        params,
+        outcome: match result {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.internal_message.clone(),
+        },
+    };
+    if let Err(e) = admin.record_audit(entry.clone()).await {
+        log::error!("admin: failed to record {entry:?} in the audit log: {e:?}");
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct PurgeCollectionQuery {
+    /// The collection NSID to remove all records and stats for
+    collection: String,
+    /// Only count what would be removed, without removing anything
+    dry_run: Option<bool>,
+}
+/// Purge a collection
+///
+/// Removes every stored record, feed entry, and rollup (all time buckets)
+/// for one collection NSID. This can take a while for big collections: watch
+/// the `storage_purge_running` and `storage_purge_removed` metrics for
+/// progress. Rollups pause while the collection's rollups are removed.
+///
+/// Events for the collection that arrive later will still be stored.
+///
+/// Set `dry_run=true` to preview the impact: the same scan runs and the
+/// report has the counts, but nothing is committed.
+#[endpoint {
+    method = POST,
+    path = "/collections/purge"
+}]
+async fn purge_collection(
+    ctx: RequestContext<AdminContext>,
+    query: Query<PurgeCollectionQuery>,
+) -> Result<HttpResponseOk<PurgeReport>, HttpError> {
+    let AdminContext { admin, .. } = ctx.context();
+    let q = query.into_inner();
+    instrument_handler(&ctx, Scope::Admin, async {
+        let collection = Nsid::new(q.collection).map_err(|e| {
+            HttpError::for_bad_request(None, format!("collection was not a valid NSID: {e:?}"))
+        })?;
+        let dry_run = q.dry_run.unwrap_or(false);
+        if dry_run {
+            log::info!("admin: dry run purge of {:?}", collection.to_string());
+        } else {
+            log::warn!("admin: purging collection {:?}", collection.to_string());
+        }
+        let report = admin
+            .purge_collection(&collection, dry_run)
+            .await
+            .map_err(|e| HttpError::for_internal_error(format!("purge failed: {e:?}")));
+        audit(
+            &ctx,
+            json!({ "collection": collection.to_string(), "dry_run": dry_run }),
+            &report,
+        )
+        .await;
+        Ok(HttpResponseOk(report?))
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct KeySpaceQuery {
+    /// Read one record for every this many feed entries
+    ///
+    /// Lower is more accurate and slower. default: 100
+    #[schemars(range(min = 1))]
+    sample_every: Option<u64>,
+    /// How many of the biggest collections to list
+    ///
+    /// default: 100
+    #[schemars(range(min = 1, max = 10000))]
+    limit: Option<usize>,
+}
+/// Estimate disk usage per collection
+///
+/// Attributes feed entries and record payloads to each collection, biggest
+/// first, to help decide what to trim or deny when disk fills up. Feeds are
+/// scanned in full and records are sampled, so this can take a while.
+#[endpoint {
+    method = GET,
+    path = "/collections/usage"
+}]
+async fn get_key_space(
+    ctx: RequestContext<AdminContext>,
+    query: Query<KeySpaceQuery>,
+) -> Result<HttpResponseOk<KeySpaceReport>, HttpError> {
+    let AdminContext { admin, .. } = ctx.context();
+    let q = query.into_inner();
+    instrument_handler(&ctx, Scope::Admin, async {
+        let sample_every = q.sample_every.unwrap_or(100);
+        if sample_every == 0 {
+            return Err(HttpError::for_bad_request(
+                None,
+                "sample_every must be at least 1".to_string(),
+            ));
+        }
+        let limit = q.limit.unwrap_or(100);
+        if !(1..=10_000).contains(&limit) {
+            let msg = format!("limit not in 1..=10000: {limit}");
+            return Err(HttpError::for_bad_request(None, msg));
+        }
+        let report = admin
+            .estimate_key_space(sample_every, limit)
+            .await
+            .map_err(|e| {
+                HttpError::for_internal_error(format!("failed to estimate key space: {e:?}"))
+            })?;
+        Ok(HttpResponseOk(report))
+    })
+    .await
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+struct DenylistResponse {
+    rules: Vec<DenyRule>,
+}
+/// List deny rules
+///
+/// Records from collections matching these rules are never stored.
+#[endpoint {
+    method = GET,
+    path = "/denylist"
+}]
+async fn get_denylist(
+    ctx: RequestContext<AdminContext>,
+) -> Result<HttpResponseOk<DenylistResponse>, HttpError> {
+    let AdminContext { admin, .. } = ctx.context();
+    instrument_handler(&ctx, Scope::Admin, async {
+        let rules = admin
+            .get_denylist()
+            .await
+            .map_err(|e| HttpError::for_internal_error(format!("failed to get denylist: {e:?}")))?;
+        Ok(HttpResponseOk(DenylistResponse { rules }))
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct DenyQuery {
+    /// An exact collection NSID, or an NSID group ending in `.*` to match everything under it
+    pattern: String,
+    /// Keep counting events for matching collections, and only skip storing their records
+    ///
+    /// default: false
+    keep_counts: Option<bool>,
+}
+/// Add a deny rule
+///
+/// Takes effect for the next batch of events. Already-stored records are not
+/// removed: use the purge endpoint for that.
+#[endpoint {
+    method = POST,
+    path = "/denylist/add"
+}]
+async fn deny_collections(
+    ctx: RequestContext<AdminContext>,
+    query: Query<DenyQuery>,
+) -> Result<HttpResponseOk<DenylistResponse>, HttpError> {
+    let AdminContext { admin, .. } = ctx.context();
+    let q = query.into_inner();
+    instrument_handler(&ctx, Scope::Admin, async {
+        let rule = DenyRule::new(&q.pattern, q.keep_counts.unwrap_or(false)).map_err(|e| {
+            HttpError::for_bad_request(None, format!("invalid deny pattern: {e:?}"))
+        })?;
+        log::warn!("admin: adding deny rule {rule:?}");
+        let params = json!({ "pattern": rule.pattern, "keep_counts": rule.keep_counts });
+        let denied = admin
+            .deny_collections(rule)
+            .await
+            .map_err(|e| HttpError::for_internal_error(format!("failed to deny: {e:?}")));
+        audit(&ctx, params, &denied).await;
+        denied?;
+        let rules = admin
+            .get_denylist()
+            .await
+            .map_err(|e| HttpError::for_internal_error(format!("failed to get denylist: {e:?}")))?;
+        Ok(HttpResponseOk(DenylistResponse { rules }))
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct AllowQuery {
+    /// The exact pattern of an existing deny rule
+    pattern: String,
+}
+/// Remove a deny rule
+#[endpoint {
+    method = POST,
+    path = "/denylist/remove"
+}]
+async fn allow_collections(
+    ctx: RequestContext<AdminContext>,
+    query: Query<AllowQuery>,
+) -> Result<HttpResponseOk<DenylistResponse>, HttpError> {
+    let AdminContext { admin, .. } = ctx.context();
+    let q = query.into_inner();
+    instrument_handler(&ctx, Scope::Admin, async {
+        log::warn!("admin: removing deny rule {:?}", q.pattern);
+        let removed = admin
+            .allow_collections(&q.pattern)
+            .await
+            .map_err(|e| HttpError::for_internal_error(format!("failed to allow: {e:?}")))
+            .and_then(|removed| {
+                if removed {
+                    Ok(())
+                } else {
+                    Err(HttpError::for_not_found(
+                        None,
+                        format!("no deny rule found for {:?}", q.pattern),
+                    ))
+                }
+            });
+        audit(&ctx, json!({ "pattern": q.pattern }), &removed).await;
+        removed?;
+        let rules = admin
+            .get_denylist()
+            .await
+            .map_err(|e| HttpError::for_internal_error(format!("failed to get denylist: {e:?}")))?;
+        Ok(HttpResponseOk(DenylistResponse { rules }))
+    })
+    .await
+}
+
+/// Background schedule
+///
+/// When the background rollup and trim will next run, and how much trimming is waiting.
+#[endpoint {
+    method = GET,
+    path = "/background"
+}]
+async fn get_background_status(
+    ctx: RequestContext<AdminContext>,
+) -> Result<HttpResponseOk<BackgroundStatus>, HttpError> {
+    let AdminContext { admin, .. } = ctx.context();
+    instrument_handler(&ctx, Scope::Admin, async {
+        let status = admin.background_status().await.map_err(|e| {
+            HttpError::for_internal_error(format!("failed to get background status: {e:?}"))
+        })?;
+        Ok(HttpResponseOk(status))
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct TriggerBackgroundQuery {
+    /// `rollup` or `trim`
+    task: BackgroundTask,
+}
+/// Run a background task now
+///
+/// For debugging: the task runs on the next turn of the background loop
+/// instead of waiting for its tick. Returns the schedule as it was when the
+/// trigger was sent.
+#[endpoint {
+    method = POST,
+    path = "/background/trigger"
+}]
+async fn trigger_background(
+    ctx: RequestContext<AdminContext>,
+    query: Query<TriggerBackgroundQuery>,
+) -> Result<HttpResponseOk<BackgroundStatus>, HttpError> {
+    let AdminContext { admin, .. } = ctx.context();
+    let q = query.into_inner();
+    instrument_handler(&ctx, Scope::Admin, async {
+        log::info!("admin: triggering background {:?}", q.task);
+        let triggered = admin
+            .trigger_background(q.task)
+            .await
+            .map_err(|e| HttpError::for_internal_error(format!("failed to trigger: {e:?}")))
+            .and_then(|running| {
+                if running {
+                    Ok(())
+                } else {
+                    Err(HttpError::for_unavail(
+                        None,
+                        "the background loop isn't running".to_string(),
+                    ))
+                }
+            });
+        audit(&ctx, json!({ "task": format!("{:?}", q.task) }), &triggered).await;
+        triggered?;
+        let status = admin.background_status().await.map_err(|e| {
+            HttpError::for_internal_error(format!("failed to get background status: {e:?}"))
+        })?;
+        Ok(HttpResponseOk(status))
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct BackgroundIntervalsQuery {
+    /// Between rollup steps while there's more to roll up, in microseconds
+    rollup_us: Option<u64>,
+    /// Pause after a rollup step that found nothing to roll up, in milliseconds
+    rollup_idle_ms: Option<u64>,
+    /// Between trim cycles, in milliseconds
+    trim_ms: Option<u64>,
+}
+/// Change background intervals
+///
+/// Omitted intervals stay as they are. The new intervals apply from the next
+/// tick, and last until the next restart.
+#[endpoint {
+    method = POST,
+    path = "/background/intervals"
+}]
+async fn set_background_intervals(
+    ctx: RequestContext<AdminContext>,
+    query: Query<BackgroundIntervalsQuery>,
+) -> Result<HttpResponseOk<BackgroundStatus>, HttpError> {
+    let AdminContext { admin, .. } = ctx.context();
+    let q = query.into_inner();
+    instrument_handler(&ctx, Scope::Admin, async {
+        let current = admin.background_status().await.map_err(|e| {
+            HttpError::for_internal_error(format!("failed to get background status: {e:?}"))
+        })?;
+        let intervals = BackgroundIntervals {
+            rollup_us: q.rollup_us.unwrap_or(current.intervals.rollup_us),
+            rollup_idle_ms: q.rollup_idle_ms.unwrap_or(current.intervals.rollup_idle_ms),
+            trim_ms: q.trim_ms.unwrap_or(current.intervals.trim_ms),
+        };
+        intervals
+            .validate()
+            .map_err(|e| HttpError::for_bad_request(None, e))?;
+        log::warn!("admin: setting background intervals to {intervals:?}");
+        let set = admin
+            .set_background_intervals(intervals)
+            .await
+            .map_err(|e| HttpError::for_internal_error(format!("failed to set intervals: {e:?}")));
+        audit(&ctx, json!(intervals), &set).await;
+        set?;
+        let status = admin.background_status().await.map_err(|e| {
+            HttpError::for_internal_error(format!("failed to get background status: {e:?}"))
+        })?;
+        Ok(HttpResponseOk(status))
+    })
+    .await
+}
+
+/// Account deletes
+///
+/// Deleted accounts are queued and their records removed by the rollup, in
+/// order. Big accounts save their progress, so a delete interrupted by a
+/// restart resumes where it left off.
+#[endpoint {
+    method = GET,
+    path = "/account-deletes"
+}]
+async fn get_account_deletes(
+    ctx: RequestContext<AdminContext>,
+) -> Result<HttpResponseOk<AccountDeletesStatus>, HttpError> {
+    let AdminContext { admin, .. } = ctx.context();
+    instrument_handler(&ctx, Scope::Admin, async {
+        let status = admin.account_deletes_status().await.map_err(|e| {
+            HttpError::for_internal_error(format!("failed to get account deletes: {e:?}"))
+        })?;
+        Ok(HttpResponseOk(status))
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct AuditLogQuery {
+    /// Get entries before this position: the `next` from a previous page
+    before: Option<u64>,
+    /// default: 100, max: 500
+    #[schemars(range(min = 1, max = 500))]
+    limit: Option<usize>,
+}
+#[derive(Debug, Serialize, JsonSchema)]
+struct AuditLogResponse {
+    /// Newest first
+    entries: Vec<AuditEntry>,
+    /// Pass as `before` for the next page, if there might be more
+    next: Option<u64>,
+}
+/// Audit log
+///
+/// Every admin action with who made it, when, its parameters, and its outcome.
+#[endpoint {
+    method = GET,
+    path = "/audit"
+}]
+async fn get_audit_log(
+    ctx: RequestContext<AdminContext>,
+    query: Query<AuditLogQuery>,
+) -> Result<HttpResponseOk<AuditLogResponse>, HttpError> {
+    let AdminContext { admin, .. } = ctx.context();
+    let q = query.into_inner();
+    instrument_handler(&ctx, Scope::Admin, async {
+        let limit = q.limit.unwrap_or(100);
+        if !(1..=500).contains(&limit) {
+            let msg = format!("limit not in 1..=500: {limit}");
+            return Err(HttpError::for_bad_request(None, msg));
+        }
+        let entries = admin.get_audit_log(q.before, limit).await.map_err(|e| {
+            HttpError::for_internal_error(format!("failed to get audit log: {e:?}"))
+        })?;
+        let next = if entries.len() == limit {
+            entries.last().map(|e| e.at)
+        } else {
+            None
+        };
+        Ok(HttpResponseOk(AuditLogResponse { entries, next }))
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct NoisyDidsQuery {
+    /// How far back to count, like `1h` or `6h`, rounded up to whole hours
+    ///
+    /// default: `1h`, max: `24h`
+    period: Option<QueryPeriod>,
+    /// Leave out DIDs with fewer records than this in the period
+    ///
+    /// default: 100
+    min_records: Option<u64>,
+    /// Leave out DIDs with less than this fraction of their collection's records
+    ///
+    /// default: 0.1
+    #[schemars(range(min = 0, max = 1))]
+    min_share: Option<f64>,
+    /// default: 100
+    #[schemars(range(min = 1, max = 1000))]
+    limit: Option<usize>,
+}
+/// Noisy DIDs
+///
+/// Accounts writing an outsized share of a collection's records, biggest share first, for
+/// deciding what to deny or exclude. Counts are approximate and kept in memory by hour, so
+/// they only go back to when this instance started.
+#[endpoint {
+    method = GET,
+    path = "/noisy-dids"
+}]
+async fn get_noisy_dids(
+    ctx: RequestContext<AdminContext>,
+    query: Query<NoisyDidsQuery>,
+) -> Result<HttpResponseOk<NoisyDidsReport>, HttpError> {
+    let AdminContext { admin, .. } = ctx.context();
+    let q = query.into_inner();
+    instrument_handler(&ctx, Scope::Admin, async {
+        let hours = q.period.map(|p| p.hours_ceil()).unwrap_or(1);
+        if hours > MAX_NOISY_HOURS as u64 {
+            let msg = format!("period is longer than {MAX_NOISY_HOURS}h");
+            return Err(HttpError::for_bad_request(None, msg));
+        }
+        let min_share = q.min_share.unwrap_or(0.1);
+        if !(0.0..=1.0).contains(&min_share) {
+            let msg = format!("min_share not in 0..=1: {min_share}");
+            return Err(HttpError::for_bad_request(None, msg));
+        }
+        let limit = q.limit.unwrap_or(100);
+        if !(1..=1000).contains(&limit) {
+            let msg = format!("limit not in 1..=1000: {limit}");
+            return Err(HttpError::for_bad_request(None, msg));
+        }
+        let report = admin
+            .noisy_dids(
+                hours as usize,
+                q.min_records.unwrap_or(100),
+                min_share,
+                limit,
+            )
+            .await
+            .map_err(|e| {
+                HttpError::for_internal_error(format!("failed to get noisy dids: {e:?}"))
+            })?;
+        Ok(HttpResponseOk(report))
+    })
+    .await
+}
+
+/// Serve the admin API
+///
+/// Every endpoint needs an admin key if there's an auth config. Without one
+/// there is no auth here: bind it somewhere private!
+pub async fn serve(
+    admin: impl StoreAdmin + 'static,
+    bind: SocketAddr,
+    auth: Arc<Auth>,
+) -> Result<(), String> {
+    let log = ConfigLogging::StderrTerminal {
+        level: ConfigLoggingLevel::Warn,
+    }
+    .to_logger("admin")
+    .map_err(|e| e.to_string())?;
+
+    let mut api = ApiDescription::new();
+
+    api.register(purge_collection).unwrap();
+    api.register(get_key_space).unwrap();
+    api.register(get_denylist).unwrap();
+    api.register(deny_collections).unwrap();
+    api.register(allow_collections).unwrap();
+    api.register(get_audit_log).unwrap();
+    api.register(get_noisy_dids).unwrap();
+    api.register(get_background_status).unwrap();
+    api.register(trigger_background).unwrap();
+    api.register(set_background_intervals).unwrap();
+    api.register(get_account_deletes).unwrap();
+
+    let context = AdminContext {
+        admin: Box::new(admin),
+        auth: auth.for_admin(),
+    };
+
+    ServerBuilder::new(api, context, log)
+        .config(ConfigDropshot {
+            bind_address: bind,
+            ..Default::default()
+        })
+        .start()
+        .map_err(|error| format!("failed to start admin server: {error}"))?
+        .await
+}
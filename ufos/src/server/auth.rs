@@ -0,0 +1,324 @@
+//! API keys with access scopes, and per-scope rate limits
+//!
+//! Without an auth config every endpoint is public and unlimited. With one,
+//! each endpoint requires the scope its handler declares: counts and other
+//! aggregates are public, while raw records and per-DID queries need a
+//! trusted key. The admin API needs an admin key.
+//!
+//! Keys are sent as `Authorization: Bearer <key>`.
+
+use crate::error::AuthConfigError;
+use dropshot::{ClientErrorStatusCode, HttpError, RequestInfo};
+use http::header::AUTHORIZATION;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Stop tracking idle clients' rate limits past this many
+const MAX_TRACKED_CLIENTS: usize = 100_000;
+
+/// Access levels, each including the ones below it
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    #[default]
+    Public,
+    Trusted,
+    Admin,
+}
+
+/// Auth config, as loaded from a json file
+///
+/// ```json
+/// {
+///   "keys": [
+///     { "name": "partner-app", "key": "long-random-string", "scope": "trusted" },
+///     { "name": "ops", "key": "another-long-random-string", "scope": "admin" }
+///   ],
+///   "rate_limits": { "public": 10, "trusted": 100 },
+///   "endpoint_scopes": { "get_records_by_collections": "public" }
+/// }
+/// ```
+///
+/// Rate limits are requests per second for each client: per key for keyed
+/// requests, per remote address for public ones. Scopes without a limit are
+/// unlimited. `endpoint_scopes` overrides the scope an endpoint (by operation
+/// id) requires on the main API, and must only name endpoints that exist.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuthConfig {
+    #[serde(default)]
+    pub keys: Vec<ApiKey>,
+    #[serde(default)]
+    pub rate_limits: HashMap<Scope, u32>,
+    #[serde(default)]
+    pub endpoint_scopes: HashMap<String, Scope>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiKey {
+    /// Shows up in logs and metrics instead of the key
+    pub name: String,
+    pub key: String,
+    pub scope: Scope,
+}
+
+impl AuthConfig {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, AuthConfigError> {
+        let f = std::fs::File::open(path)?;
+        Ok(serde_json::from_reader(std::io::BufReader::new(f))?)
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Limiter {
+    limits: HashMap<Scope, u32>,
+    buckets: Mutex<HashMap<(Scope, String), Bucket>>,
+}
+
+impl Limiter {
+    /// Take a token from the client's bucket, if the scope is limited
+    fn allow(&self, scope: Scope, client: &str) -> bool {
+        let Some(&limit) = self.limits.get(&scope) else {
+            return true;
+        };
+        let rate = limit as f64;
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            // forget clients whose buckets have refilled anyway
+            buckets.retain(|(scope, _), b| {
+                let rate = self.limits.get(scope).copied().unwrap_or(1) as f64;
+                b.tokens + now.duration_since(b.updated).as_secs_f64() * rate < rate
+            });
+        }
+        let bucket = buckets
+            .entry((scope, client.to_string()))
+            .or_insert(Bucket {
+                tokens: rate,
+                updated: now,
+            });
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * rate;
+        bucket.tokens = (bucket.tokens + refill).min(rate);
+        bucket.updated = now;
+        if bucket.tokens < 1. {
+            return false;
+        }
+        bucket.tokens -= 1.;
+        true
+    }
+}
+
+/// Checks requests against the auth config
+#[derive(Debug, Default)]
+pub struct Auth {
+    /// off without a config: everything is allowed
+    enabled: bool,
+    keys: HashMap<String, (String, Scope)>,
+    /// overrides from the config
+    endpoint_scopes: HashMap<String, Scope>,
+    /// no endpoint needs less than this
+    min_scope: Scope,
+    limiter: Limiter,
+}
+
+impl Auth {
+    /// Allow everything (no auth config)
+    pub fn open() -> Self {
+        Self::default()
+    }
+
+    pub fn new(config: AuthConfig) -> Result<Self, AuthConfigError> {
+        let mut keys = HashMap::new();
+        for ApiKey { name, key, scope } in config.keys {
+            if keys.contains_key(&key) {
+                return Err(AuthConfigError::DuplicateKey(name));
+            }
+            keys.insert(key, (name, scope));
+        }
+        if let Some((scope, _)) = config.rate_limits.iter().find(|(_, &limit)| limit == 0) {
+            return Err(AuthConfigError::ZeroRateLimit(format!("{scope:?}")));
+        }
+        if let Some(endpoint) = config
+            .endpoint_scopes
+            .keys()
+            .find(|e| !super::ENDPOINTS.contains(&e.as_str()))
+        {
+            return Err(AuthConfigError::UnknownEndpoint(endpoint.clone()));
+        }
+        Ok(Self {
+            enabled: true,
+            keys,
+            endpoint_scopes: config.endpoint_scopes,
+            min_scope: Scope::Public,
+            limiter: Limiter {
+                limits: config.rate_limits,
+                ..Default::default()
+            },
+        })
+    }
+
+    /// The same keys and limits, requiring an admin key for every endpoint
+    pub fn for_admin(&self) -> Self {
+        Self {
+            enabled: self.enabled,
+            keys: self.keys.clone(),
+            endpoint_scopes: HashMap::new(),
+            min_scope: Scope::Admin,
+            limiter: Limiter {
+                limits: self.limiter.limits.clone(),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Who's making a request: the name of its api key, or its remote address
+    pub fn caller(&self, request: &RequestInfo) -> String {
+        request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .and_then(|key| self.keys.get(key.trim()))
+            .map(|(name, _)| name.clone())
+            .unwrap_or_else(|| request.remote_addr().ip().to_string())
+    }
+
+    /// The scope an endpoint needs, given the one its handler declares
+    fn required(&self, endpoint: &str, declared: Scope) -> Scope {
+        self.endpoint_scopes
+            .get(endpoint)
+            .copied()
+            .unwrap_or(declared)
+            .max(self.min_scope)
+    }
+
+    /// Check that a request may call an endpoint, and isn't over its rate limit
+    pub fn check(
+        &self,
+        request: &RequestInfo,
+        endpoint: &str,
+        declared: Scope,
+    ) -> Result<(), HttpError> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let required = self.required(endpoint, declared);
+
+        let (client, scope) = match request.headers().get(AUTHORIZATION) {
+            Some(header) => {
+                let key = header
+                    .to_str()
+                    .ok()
+                    .and_then(|h| h.strip_prefix("Bearer "))
+                    .ok_or_else(|| unauthorized("expected `Authorization: Bearer <key>`"))?;
+                let (name, scope) = self
+                    .keys
+                    .get(key.trim())
+                    .ok_or_else(|| unauthorized("unknown api key"))?;
+                (name.clone(), *scope)
+            }
+            None => (request.remote_addr().ip().to_string(), Scope::Public),
+        };
+
+        if scope < required {
+            return Err(HttpError::for_client_error(
+                None,
+                ClientErrorStatusCode::FORBIDDEN,
+                format!("this endpoint needs a {required:?} api key"),
+            ));
+        }
+        if !self.limiter.allow(scope, &client) {
+            return Err(HttpError::for_client_error(
+                None,
+                ClientErrorStatusCode::TOO_MANY_REQUESTS,
+                format!("rate limit exceeded for {scope:?} requests, slow down"),
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn unauthorized(msg: &str) -> HttpError {
+    HttpError::for_client_error(None, ClientErrorStatusCode::UNAUTHORIZED, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(json: &str) -> AuthConfig {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_scope_order() {
+        assert!(Scope::Public < Scope::Trusted);
+        assert!(Scope::Trusted < Scope::Admin);
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let limiter = Limiter {
+            limits: [(Scope::Public, 2)].into(),
+            ..Default::default()
+        };
+        assert!(limiter.allow(Scope::Public, "a"));
+        assert!(limiter.allow(Scope::Public, "a"));
+        assert!(!limiter.allow(Scope::Public, "a"));
+        // separate bucket per client
+        assert!(limiter.allow(Scope::Public, "b"));
+        // unlimited scope
+        for _ in 0..10 {
+            assert!(limiter.allow(Scope::Trusted, "a"));
+        }
+    }
+
+    #[test]
+    fn test_config() {
+        let auth = Auth::new(config(
+            r#"{
+                "keys": [{"name": "app", "key": "k1", "scope": "trusted"}],
+                "endpoint_scopes": {"get_changes": "admin"}
+            }"#,
+        ))
+        .unwrap();
+        assert_eq!(auth.keys["k1"], ("app".to_string(), Scope::Trusted));
+        assert_eq!(
+            auth.required("get_records_by_collections", Scope::Trusted),
+            Scope::Trusted
+        );
+        assert_eq!(auth.required("get_changes", Scope::Trusted), Scope::Admin);
+        let admin = auth.for_admin();
+        assert_eq!(admin.required("get_changes", Scope::Public), Scope::Admin);
+        assert_eq!(
+            admin.required("purge_collection", Scope::Admin),
+            Scope::Admin
+        );
+
+        let duplicate = Auth::new(config(
+            r#"{"keys": [
+                {"name": "a", "key": "k", "scope": "trusted"},
+                {"name": "b", "key": "k", "scope": "admin"}
+            ]}"#,
+        ));
+        assert!(matches!(duplicate, Err(AuthConfigError::DuplicateKey(n)) if n == "b"));
+
+        let zero = Auth::new(config(r#"{"rate_limits": {"public": 0}}"#));
+        assert!(matches!(zero, Err(AuthConfigError::ZeroRateLimit(_))));
+
+        let typo = Auth::new(config(
+            r#"{"endpoint_scopes": {"get_record_version": "public"}}"#,
+        ));
+        assert!(
+            matches!(typo, Err(AuthConfigError::UnknownEndpoint(e)) if e == "get_record_version")
+        );
+    }
+}
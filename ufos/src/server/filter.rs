@@ -0,0 +1,438 @@
+use crate::UFOsRecord;
+use dropshot::HttpError;
+use serde_json::{json, Value};
+
+/// Don't let one request ask for an unreasonably complicated filter
+const MAX_FILTER_LEN: usize = 1024;
+/// Deepest nesting of parentheses and `NOT`s
+const MAX_FILTER_DEPTH: usize = 32;
+
+/// A record filter, from a `filter=record.langs contains "en" AND record.reply exists` query param
+///
+/// Paths start from an object with the record's `did`, `collection`, `rkey`,
+/// `time_us`, and `record` (the record itself). Path steps are `.field` or
+/// `[index]`, like `record.facets[0].features`.
+///
+/// Tests:
+///
+/// - `path exists`: the path leads anywhere, even to `null`
+/// - `path contains value`: an array containing the value, or a string
+///   containing a substring
+/// - `path startswith "prefix"`: a string starting with the prefix
+/// - `path == value`, `!=`, `<`, `<=`, `>`, `>=`: comparisons between two
+///   numbers, or two strings (so RFC3339 datetimes compare by time)
+///
+/// Values are json strings, numbers, `true`, `false`, or `null`. Tests combine
+/// with `AND`, `OR`, `NOT`, and parentheses. `AND` binds tighter than `OR`.
+/// Keywords aren't case sensitive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter(Expr);
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Test(Vec<Step>, Test),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Step {
+    Field(String),
+    Index(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Test {
+    Exists,
+    Contains(Value),
+    StartsWith(String),
+    Eq(Value),
+    Ne(Value),
+    Lt(Value),
+    Le(Value),
+    Gt(Value),
+    Ge(Value),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Literal(Value),
+    Op(&'static str),
+    Open,
+    Close,
+}
+
+impl Filter {
+    pub fn parse(expr: &str) -> Result<Self, HttpError> {
+        Self::parse_str(expr)
+            .map_err(|e| HttpError::for_bad_request(None, format!("invalid filter: {e}")))
+    }
+
+    fn parse_str(expr: &str) -> Result<Self, String> {
+        if expr.len() > MAX_FILTER_LEN {
+            return Err(format!("longer than {MAX_FILTER_LEN} bytes"));
+        }
+        let tokens = tokenize(expr)?;
+        if tokens.is_empty() {
+            return Err("empty".to_string());
+        }
+        let mut parser = Parser { tokens, at: 0 };
+        let parsed = parser.or(0)?;
+        if let Some(extra) = parser.tokens.get(parser.at) {
+            return Err(format!("unexpected {extra:?}"));
+        }
+        Ok(Self(parsed))
+    }
+
+    /// Check a stored record, failing only if its json can't be parsed
+    pub fn matches_record(&self, record: &UFOsRecord) -> serde_json::Result<bool> {
+        let context = json!({
+            "did": record.did.as_str(),
+            "collection": record.collection.as_str(),
+            "rkey": record.rkey.as_str(),
+            "time_us": record.cursor.to_raw_u64(),
+            "record": serde_json::from_str::<Value>(record.record.get())?,
+        });
+        Ok(self.matches(&context))
+    }
+
+    /// Check a record, given as the object that paths start from
+    fn matches(&self, context: &Value) -> bool {
+        self.0.eval(context)
+    }
+}
+
+impl Expr {
+    fn eval(&self, context: &Value) -> bool {
+        match self {
+            Self::And(a, b) => a.eval(context) && b.eval(context),
+            Self::Or(a, b) => a.eval(context) || b.eval(context),
+            Self::Not(e) => !e.eval(context),
+            Self::Test(path, test) => {
+                let found = path.iter().try_fold(context, |v, step| match step {
+                    Step::Field(name) => v.get(name),
+                    Step::Index(i) => v.get(i),
+                });
+                match (test, found) {
+                    (Test::Ne(expected), found) => !found.is_some_and(|v| json_eq(v, expected)),
+                    (_, None) => false,
+                    (Test::Exists, Some(_)) => true,
+                    (Test::Contains(expected), Some(Value::Array(items))) => {
+                        items.iter().any(|v| json_eq(v, expected))
+                    }
+                    (Test::Contains(Value::String(sub)), Some(Value::String(s))) => {
+                        s.contains(sub.as_str())
+                    }
+                    (Test::Contains(_), _) => false,
+                    (Test::StartsWith(prefix), Some(Value::String(s))) => {
+                        s.starts_with(prefix.as_str())
+                    }
+                    (Test::StartsWith(_), _) => false,
+                    (Test::Eq(expected), Some(v)) => json_eq(v, expected),
+                    (Test::Lt(expected), Some(v)) => {
+                        json_cmp(v, expected).is_some_and(|o| o.is_lt())
+                    }
+                    (Test::Le(expected), Some(v)) => {
+                        json_cmp(v, expected).is_some_and(|o| o.is_le())
+                    }
+                    (Test::Gt(expected), Some(v)) => {
+                        json_cmp(v, expected).is_some_and(|o| o.is_gt())
+                    }
+                    (Test::Ge(expected), Some(v)) => {
+                        json_cmp(v, expected).is_some_and(|o| o.is_ge())
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Json equality, except that numbers are equal if they have the same value (`1 == 1.0`)
+fn json_eq(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64() == b.as_f64(),
+        _ => a == b,
+    }
+}
+
+fn json_cmp(a: &Value, b: &Value) -> Option<std::cmp::Ordering> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+fn tokenize(expr: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = expr.char_indices().peekable();
+    while let Some(&(i, c)) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::Open);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::Close);
+            }
+            '"' => {
+                chars.next();
+                let mut escaped = false;
+                let end = loop {
+                    match chars.next() {
+                        None => return Err(format!("unterminated string at {i}")),
+                        Some((_, '\\')) if !escaped => escaped = true,
+                        Some((j, '"')) if !escaped => break j,
+                        Some(_) => escaped = false,
+                    }
+                };
+                let s: String = serde_json::from_str(&expr[i..=end])
+                    .map_err(|e| format!("bad string at {i}: {e}"))?;
+                tokens.push(Token::Literal(Value::String(s)));
+            }
+            '=' | '!' | '<' | '>' => {
+                chars.next();
+                let with_eq = chars.next_if(|&(_, c)| c == '=').is_some();
+                let op = match (c, with_eq) {
+                    ('=', true) => "==",
+                    ('!', true) => "!=",
+                    ('<', true) => "<=",
+                    ('>', true) => ">=",
+                    ('<', false) => "<",
+                    ('>', false) => ">",
+                    _ => return Err(format!("unknown operator at {i} (try == or !=)")),
+                };
+                tokens.push(Token::Op(op));
+            }
+            c if c == '-' || c.is_ascii_digit() => {
+                let mut end = i;
+                while let Some((j, c)) =
+                    chars.next_if(|&(_, c)| c.is_ascii_alphanumeric() || "-+.".contains(c))
+                {
+                    end = j + c.len_utf8();
+                }
+                let n: serde_json::Number = expr[i..end]
+                    .parse()
+                    .map_err(|_| format!("bad number at {i}: {:?}", &expr[i..end]))?;
+                tokens.push(Token::Literal(Value::Number(n)));
+            }
+            c if c.is_alphanumeric() || "_$.[]".contains(c) => {
+                let mut end = i;
+                while let Some((j, c)) =
+                    chars.next_if(|&(_, c)| c.is_alphanumeric() || "_$.[]".contains(c))
+                {
+                    end = j + c.len_utf8();
+                }
+                let word = &expr[i..end];
+                tokens.push(match word {
+                    "true" => Token::Literal(Value::Bool(true)),
+                    "false" => Token::Literal(Value::Bool(false)),
+                    "null" => Token::Literal(Value::Null),
+                    _ => Token::Word(word.to_string()),
+                });
+            }
+            c => return Err(format!("unexpected {c:?} at {i}")),
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    at: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.at).cloned();
+        self.at += 1;
+        token
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        match self.tokens.get(self.at) {
+            Some(Token::Word(w)) if w.eq_ignore_ascii_case(keyword) => {
+                self.at += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn or(&mut self, depth: usize) -> Result<Expr, String> {
+        let mut expr = self.and(depth)?;
+        while self.eat_keyword("or") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and(depth)?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self, depth: usize) -> Result<Expr, String> {
+        let mut expr = self.not(depth)?;
+        while self.eat_keyword("and") {
+            expr = Expr::And(Box::new(expr), Box::new(self.not(depth)?));
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self, depth: usize) -> Result<Expr, String> {
+        if depth > MAX_FILTER_DEPTH {
+            return Err(format!("nested deeper than {MAX_FILTER_DEPTH}"));
+        }
+        if self.eat_keyword("not") {
+            return Ok(Expr::Not(Box::new(self.not(depth + 1)?)));
+        }
+        match self.next() {
+            Some(Token::Open) => {
+                let expr = self.or(depth + 1)?;
+                match self.next() {
+                    Some(Token::Close) => Ok(expr),
+                    other => Err(format!("expected ')', found {other:?}")),
+                }
+            }
+            Some(Token::Word(path)) => {
+                let path = parse_path(&path)?;
+                let test = self.test()?;
+                Ok(Expr::Test(path, test))
+            }
+            other => Err(format!("expected a path, found {other:?}")),
+        }
+    }
+
+    fn test(&mut self) -> Result<Test, String> {
+        let op = match self.next() {
+            Some(Token::Word(w)) if w.eq_ignore_ascii_case("exists") => return Ok(Test::Exists),
+            Some(Token::Word(w)) if w.eq_ignore_ascii_case("contains") => "contains",
+            Some(Token::Word(w)) if w.eq_ignore_ascii_case("startswith") => "startswith",
+            Some(Token::Op(op)) => op,
+            other => return Err(format!("expected a test, found {other:?}")),
+        };
+        let value = match self.next() {
+            Some(Token::Literal(v)) => v,
+            other => return Err(format!("expected a value after {op}, found {other:?}")),
+        };
+        Ok(match op {
+            "contains" => Test::Contains(value),
+            "startswith" => match value {
+                Value::String(s) => Test::StartsWith(s),
+                other => return Err(format!("startswith needs a string, found {other}")),
+            },
+            "==" => Test::Eq(value),
+            "!=" => Test::Ne(value),
+            "<" => Test::Lt(value),
+            "<=" => Test::Le(value),
+            ">" => Test::Gt(value),
+            ">=" => Test::Ge(value),
+            _ => unreachable!(),
+        })
+    }
+}
+
+fn parse_path(path: &str) -> Result<Vec<Step>, String> {
+    let mut steps = Vec::new();
+    for part in path.split('.') {
+        let (field, mut rest) = part.split_at(part.find('[').unwrap_or(part.len()));
+        if field.is_empty() && steps.is_empty() {
+            return Err(format!("path must start with a field: {path:?}"));
+        }
+        if !field.is_empty() {
+            steps.push(Step::Field(field.to_string()));
+        } else if rest.is_empty() {
+            return Err(format!("empty path step in {path:?}"));
+        }
+        while !rest.is_empty() {
+            let (index, after) = rest
+                .strip_prefix('[')
+                .and_then(|r| r.split_once(']'))
+                .ok_or_else(|| format!("bad index in {path:?}"))?;
+            let index = index
+                .parse()
+                .map_err(|_| format!("bad index {index:?} in {path:?}"))?;
+            steps.push(Step::Index(index));
+            rest = after;
+        }
+    }
+    Ok(steps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(filter: &str, context: &Value) -> bool {
+        Filter::parse_str(filter).unwrap().matches(context)
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!(Filter::parse_str("record.text exists").is_ok());
+        assert!(Filter::parse_str("record.langs contains \"en\" AND record.reply exists").is_ok());
+        assert!(Filter::parse_str("NOT (did == \"a\" or rkey == \"b\")").is_ok());
+        assert!(Filter::parse_str("record.facets[0].features[1] exists").is_ok());
+        assert!(Filter::parse_str("").is_err());
+        assert!(Filter::parse_str("record.text").is_err());
+        assert!(Filter::parse_str("record.text == ").is_err());
+        assert!(Filter::parse_str("record.text = \"a\"").is_err());
+        assert!(Filter::parse_str("record.text == \"a").is_err());
+        assert!(Filter::parse_str("(record.text exists").is_err());
+        assert!(Filter::parse_str("record.text exists record.x exists").is_err());
+        assert!(Filter::parse_str("record..text exists").is_err());
+        assert!(Filter::parse_str("record.x[a] exists").is_err());
+        assert!(Filter::parse_str("record.x startswith 1").is_err());
+        assert!(Filter::parse_str(&"(".repeat(40)).is_err());
+        assert!(Filter::parse_str(&"x exists OR ".repeat(100)).is_err());
+    }
+
+    #[test]
+    fn test_matches() {
+        let post = json!({
+            "did": "did:plc:abc",
+            "collection": "app.bsky.feed.post",
+            "rkey": "3k",
+            "time_us": 1_700_000_000_000_000u64,
+            "record": {
+                "text": "hello \"world\"",
+                "langs": ["en", "fr"],
+                "reply": null,
+                "likes": 3,
+                "createdAt": "2025-01-02T03:04:05Z",
+                "facets": [{"features": [{"uri": "https://example.com"}]}],
+            },
+        });
+        assert!(check("record.langs contains \"en\"", &post));
+        assert!(!check("record.langs contains \"de\"", &post));
+        assert!(check("record.text contains \"\\\"world\\\"\"", &post));
+        assert!(check("record.reply exists", &post));
+        assert!(!check("record.embed exists", &post));
+        assert!(check("record.reply == null", &post));
+        assert!(check("record.likes == 3.0", &post));
+        assert!(check("record.likes >= 3 and record.likes < 4", &post));
+        assert!(!check("record.likes > 3", &post));
+        assert!(check("record.likes != 4", &post));
+        assert!(check("record.missing != 4", &post));
+        assert!(!check("record.missing == 4", &post));
+        assert!(check("record.createdAt > \"2025-01-01\"", &post));
+        assert!(!check("record.createdAt > 5", &post));
+        assert!(check("did startswith \"did:plc:\"", &post));
+        assert!(check(
+            "record.facets[0].features[0].uri startswith \"https://\"",
+            &post
+        ));
+        assert!(!check("record.facets[1] exists", &post));
+        assert!(check(
+            "record.embed exists OR record.langs contains \"fr\" AND NOT record.likes > 10",
+            &post
+        ));
+        assert!(!check(
+            "(record.embed exists OR record.langs contains \"fr\") AND record.likes > 10",
+            &post
+        ));
+        assert!(check("time_us > 0", &post));
+    }
+}
@@ -1,13 +1,28 @@
+pub mod admin;
+pub mod auth;
 mod collections_query;
 mod cors;
+mod filter;
+mod prefix_tree;
+mod projection;
+pub mod static_json;
+mod time_params;
 
+use crate::cache::{CacheKey, RedisCache};
+use crate::chase::{
+    ChangeBatch, ChangesResponse, CursorInfo, CHANGE_FEED_FORMAT, MAX_CHANGES_LIMIT,
+};
+use crate::error::{QueryError, StorageError};
 use crate::index_html::INDEX_HTML;
-use crate::storage::StoreReader;
-use crate::store_types::{HourTruncatedCursor, WeekTruncatedCursor};
+use crate::simhash;
+use crate::storage::{CountPrefix, ScanBudget, StoreReader};
+use crate::store_types::{HourTruncatedCursor, HourWindow, WeekTruncatedCursor};
 use crate::{
-    ConsumerInfo, Cursor, JustCount, Nsid, NsidCount, NsidPrefix, OrderCollectionsBy, PrefixChild,
-    UFOsRecord,
+    ConsumerInfo, Cursor, Did, EventKindCounts, GrowthPeriod, GrowthRanking, JustCount, Nsid,
+    NsidCount, NsidPrefix, OrderCollectionsBy, PrefixChild, RecordKey, Summary, UFOsRecord,
+    UniqueRecords, WindowCounts,
 };
+use auth::{Auth, Scope};
 use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
 use chrono::{DateTime, Utc};
 use collections_query::MultiCollectionQuery;
@@ -20,22 +35,30 @@ use dropshot::ConfigLogging;
 use dropshot::ConfigLoggingLevel;
 use dropshot::HttpError;
 use dropshot::HttpResponse;
+use dropshot::Path;
 use dropshot::Query;
 use dropshot::RequestContext;
 use dropshot::ServerBuilder;
 use dropshot::ServerContext;
+use filter::Filter;
 use http::{
-    header::{ORIGIN, USER_AGENT},
+    header::{CONTENT_TYPE, ETAG, IF_NONE_MATCH, ORIGIN, USER_AGENT},
     Response, StatusCode,
 };
 use metrics::{counter, describe_counter, describe_histogram, histogram, Unit};
+use metrics_exporter_prometheus::PrometheusHandle;
+use prefix_tree::{OtherChildren, PrefixTreeNode, TreeWalk, MAX_TREE_CHILDREN, MAX_TREE_DEPTH};
+use projection::Projection;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::future::Future;
+use std::net::SocketAddr;
+use std::ops::Bound;
 use std::sync::Arc;
 use std::time::Instant;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime};
+use time_params::{check_window, cursor_to_dt, hour_window, with_period, QueryPeriod, QueryTime};
 
 fn describe_metrics() {
     describe_counter!(
@@ -48,16 +71,69 @@ fn describe_metrics() {
         Unit::Microseconds,
         "time to respond to a request in microseconds, excluding dropshot overhead"
     );
+    describe_counter!(
+        "server_record_not_modified",
+        Unit::Count,
+        "single-record requests answered with 304 because the client's rev was current"
+    );
+}
+
+/// Requests taking longer than this get logged with their request ID
+const SLOW_REQUEST: Duration = Duration::from_secs(2);
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// The ID of the API request currently being handled, if any
+///
+/// Dropshot generates one for every request and returns it to the client in
+/// the `x-request-id` response header (and in error bodies), so it can be
+/// matched up with log lines.
+pub fn request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// `tokio::task::spawn_blocking`, but keeping the current request ID
+///
+/// task-locals don't follow work onto the blocking pool, so storage calls made
+/// for a request would otherwise log without its ID.
+pub fn spawn_blocking<F, R>(f: F) -> tokio::task::JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    match request_id() {
+        Some(id) => tokio::task::spawn_blocking(move || REQUEST_ID.sync_scope(id, f)),
+        None => tokio::task::spawn_blocking(f),
+    }
+}
+
+/// Server contexts that check requests against the auth config
+trait WithAuth: ServerContext {
+    fn auth(&self) -> &Auth;
 }
 
-async fn instrument_handler<T, H, R>(ctx: &RequestContext<T>, handler: H) -> Result<R, HttpError>
+/// Check auth, then run a handler with request logging and metrics
+///
+/// `scope` is what the endpoint needs by default: an auth config's
+/// `endpoint_scopes` can override it.
+async fn instrument_handler<T, H, R>(
+    ctx: &RequestContext<T>,
+    scope: Scope,
+    handler: H,
+) -> Result<R, HttpError>
 where
     R: HttpResponse,
     H: Future<Output = Result<R, HttpError>>,
-    T: ServerContext,
+    T: WithAuth,
 {
+    let endpoint = ctx.endpoint.operation_id.clone();
     let start = Instant::now();
-    let result = handler.await;
+    let result = match ctx.context().auth().check(&ctx.request, &endpoint, scope) {
+        Ok(()) => REQUEST_ID.scope(ctx.request_id.clone(), handler).await,
+        Err(denied) => Err(denied),
+    };
     let latency = start.elapsed();
     let status_code = match &result {
         Ok(response) => response.status_code(),
@@ -65,7 +141,22 @@ where
     }
     .as_str() // just the number (.to_string()'s Display does eg `200 OK`)
     .to_string();
-    let endpoint = ctx.endpoint.operation_id.clone();
+    if let Err(e) = &result {
+        if e.status_code.as_status().is_server_error() {
+            log::error!(
+                "request {} to {endpoint} failed: {}",
+                ctx.request_id,
+                e.internal_message
+            );
+        }
+    }
+    if latency > SLOW_REQUEST {
+        log::warn!(
+            "slow request {} to {endpoint}: {latency:?} ({})",
+            ctx.request_id,
+            ctx.request.uri()
+        );
+    }
     let headers = ctx.request.headers();
     let origin = headers
         .get(ORIGIN)
@@ -98,24 +189,52 @@ where
 struct Context {
     pub spec: Arc<serde_json::Value>,
     storage: Box<dyn StoreReader>,
+    auth: Arc<Auth>,
+    cache: Option<RedisCache>,
+    metrics: Option<PrometheusHandle>,
 }
 
-fn dt_to_cursor(dt: DateTime<Utc>) -> Result<HourTruncatedCursor, HttpError> {
-    let t = dt.timestamp_micros();
-    if t < 0 {
-        Err(HttpError::for_bad_request(None, "timestamp too old".into()))
-    } else {
-        let t = t as u64;
-        let t_now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_micros() as u64;
-        const ONE_HOUR: u64 = 60 * 60 * 1_000_000;
-        if t > t_now && (t - t_now > 2 * ONE_HOUR) {
-            Err(HttpError::for_bad_request(None, "future timestamp".into()))
-        } else {
-            Ok(HourTruncatedCursor::truncate_raw_u64(t))
-        }
+impl WithAuth for Context {
+    fn auth(&self) -> &Auth {
+        &self.auth
+    }
+}
+
+/// An optional time bound, for cache keys
+fn cache_bound(bound: Option<HourTruncatedCursor>) -> String {
+    bound
+        .map(|c| c.to_raw_u64().to_string())
+        .unwrap_or_default()
+}
+
+fn decode_snapshot_token(token: &str) -> Result<u64, HttpError> {
+    let bytes = URL_SAFE_NO_PAD
+        .decode(token)
+        .map_err(|e| HttpError::for_bad_request(None, format!("invalid snapshot: {e:?}")))?;
+    let bytes: [u8; 8] = bytes
+        .try_into()
+        .map_err(|_| HttpError::for_bad_request(None, "invalid snapshot".to_string()))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+/// Get a reader pinned to the requested snapshot, if one was requested
+fn pinned_storage(
+    storage: &dyn StoreReader,
+    snapshot: Option<&str>,
+) -> Result<Option<Box<dyn StoreReader>>, HttpError> {
+    let Some(token) = snapshot else {
+        return Ok(None);
+    };
+    let token = decode_snapshot_token(token)?;
+    storage.at_snapshot(token).map(Some).map_err(query_error)
+}
+
+/// Bad input is a 400, anything from storage itself is on us
+fn query_error(e: QueryError) -> HttpError {
+    match e {
+        QueryError::TooManySnapshots(_) => HttpError::for_unavail(None, e.to_string()),
+        e if e.is_client_error() => HttpError::for_bad_request(None, e.to_string()),
+        e => HttpError::for_internal_error(format!("query failed: {e:?}")),
     }
 }
 
@@ -129,7 +248,7 @@ fn dt_to_cursor(dt: DateTime<Utc>) -> Result<HourTruncatedCursor, HttpError> {
     unpublished = true,
 }]
 async fn index(ctx: RequestContext<Context>) -> Result<Response<Body>, HttpError> {
-    instrument_handler(&ctx, async {
+    instrument_handler(&ctx, Scope::Public, async {
         Ok(Response::builder()
             .status(StatusCode::OK)
             .header(http::header::CONTENT_TYPE, "text/html")
@@ -148,13 +267,38 @@ async fn index(ctx: RequestContext<Context>) -> Result<Response<Body>, HttpError
     unpublished = true,
 }]
 async fn get_openapi(ctx: RequestContext<Context>) -> OkCorsResponse<serde_json::Value> {
-    instrument_handler(&ctx, async {
+    instrument_handler(&ctx, Scope::Public, async {
         let spec = (*ctx.context().spec).clone();
         OkCors(spec).into()
     })
     .await
 }
 
+/// Meta: prometheus metrics for the consumer, writer, rollups, and this api
+#[endpoint {
+    method = GET,
+    path = "/metrics",
+    /*
+     * not useful to have this in openapi
+     */
+    unpublished = true,
+}]
+async fn get_metrics(ctx: RequestContext<Context>) -> Result<Response<Body>, HttpError> {
+    instrument_handler(&ctx, Scope::Admin, async {
+        let Some(handle) = &ctx.context().metrics else {
+            return Err(HttpError::for_not_found(
+                None,
+                "no metrics recorder is installed".to_string(),
+            ));
+        };
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(handle.render().into())?)
+    })
+    .await
+}
+
 #[derive(Debug, Serialize, JsonSchema)]
 struct MetaInfo {
     storage_name: String,
@@ -171,7 +315,7 @@ async fn get_meta_info(ctx: RequestContext<Context>) -> OkCorsResponse<MetaInfo>
     let failed_to_get =
         |what| move |e| HttpError::for_internal_error(format!("failed to get {what}: {e:?}"));
 
-    instrument_handler(&ctx, async {
+    instrument_handler(&ctx, Scope::Public, async {
         let storage_info = storage
             .get_storage_stats()
             .await
@@ -192,6 +336,165 @@ async fn get_meta_info(ctx: RequestContext<Context>) -> OkCorsResponse<MetaInfo>
     .await
 }
 
+/// Summary
+///
+/// Headline numbers across every collection in one call: collections tracked,
+/// records created, distinct DIDs (all-time and the last 24 hours), the recent
+/// event rate, and how far behind the consumer and rollup are.
+///
+/// Counts come from the rollups, so like the other stats they can be a little
+/// behind. DID numbers are estimates.
+#[endpoint {
+    method = GET,
+    path = "/summary"
+}]
+async fn get_summary(ctx: RequestContext<Context>) -> OkCorsResponse<Summary> {
+    let Context { storage, cache, .. } = ctx.context();
+    instrument_handler(&ctx, Scope::Public, async {
+        let key = CacheKey::TopCollections("summary".to_string());
+        if let Some(cache) = cache {
+            if let Some(summary) = cache.get::<Summary>(&key).await {
+                return OkCors(summary).into();
+            }
+        }
+        let summary = storage.get_summary().await.map_err(query_error)?;
+        if let Some(cache) = cache {
+            cache.put(&key, &summary).await;
+        }
+        OkCors(summary).into()
+    })
+    .await
+}
+
+/// Consumer identity and cursor
+///
+/// What this instance consumes and how far it has gotten, for replicas that
+/// want to chase it.
+#[endpoint {
+    method = GET,
+    path = "/cursor"
+}]
+async fn get_cursor(ctx: RequestContext<Context>) -> OkCorsResponse<CursorInfo> {
+    let Context { storage, .. } = ctx.context();
+    instrument_handler(&ctx, Scope::Public, async {
+        let ConsumerInfo::Jetstream {
+            endpoint,
+            started_at,
+            latest_cursor,
+            ..
+        } = storage.get_consumer_info().await.map_err(|e| {
+            HttpError::for_internal_error(format!("failed to get consumer info: {e:?}"))
+        })?;
+        OkCors(CursorInfo {
+            endpoint,
+            started_at,
+            latest_cursor,
+        })
+        .into()
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct ChangesQuery {
+    /// Get batches inserted after this cursor: the `cursor` of the last batch already received
+    after: Option<u64>,
+    /// default: 20, max: 100
+    #[schemars(range(min = 1, max = 100))]
+    limit: Option<usize>,
+}
+/// Change feed
+///
+/// Batches of events as this instance inserted them, oldest first, for other
+/// UFOs instances to chase as read replicas. Batches are only published when
+/// this instance runs with a change feed, otherwise this is always empty.
+#[endpoint {
+    method = GET,
+    path = "/changes"
+}]
+async fn get_changes(
+    ctx: RequestContext<Context>,
+    query: Query<ChangesQuery>,
+) -> OkCorsResponse<ChangesResponse> {
+    let Context { storage, .. } = ctx.context();
+    let q = query.into_inner();
+    instrument_handler(&ctx, Scope::Trusted, async {
+        let limit = q.limit.unwrap_or(20);
+        if !(1..=MAX_CHANGES_LIMIT).contains(&limit) {
+            let msg = format!("limit not in 1..={MAX_CHANGES_LIMIT}: {limit}");
+            return Err(HttpError::for_bad_request(None, msg));
+        }
+        let (changes, trimmed) = storage
+            .get_changes(q.after.map(Cursor::from_raw_u64), limit)
+            .await
+            .map_err(query_error)?;
+        OkCors(ChangesResponse {
+            format: CHANGE_FEED_FORMAT,
+            batches: changes
+                .into_iter()
+                .map(|(cursor, batch)| ChangeBatch {
+                    cursor: cursor.to_raw_u64(),
+                    batch: URL_SAFE_NO_PAD.encode(batch),
+                })
+                .collect(),
+            trimmed_through: trimmed.map(|c| c.to_raw_u64()),
+        })
+        .into()
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct SnapshotQuery {
+    /// How long to keep the snapshot around, in seconds
+    ///
+    /// default: 60, max: 300
+    #[schemars(range(min = 1, max = 300))]
+    ttl: Option<u64>,
+}
+#[derive(Debug, Serialize, JsonSchema)]
+struct SnapshotResponse {
+    /// Include as the `snapshot` parameter in follow-up requests to read from this same instant
+    snapshot: String,
+    /// The snapshot cannot be used after this time
+    expires_at: DateTime<Utc>,
+}
+/// Pin a read snapshot
+///
+/// Get a token for making several related requests (counts, records, timeseries...)
+/// that all see the data exactly as it was at one instant, so that numbers from
+/// different endpoints agree with each other.
+///
+/// Snapshots are short-lived: they expire after their `ttl`.
+#[endpoint {
+    method = POST,
+    path = "/snapshot"
+}]
+async fn pin_snapshot(
+    ctx: RequestContext<Context>,
+    query: Query<SnapshotQuery>,
+) -> OkCorsResponse<SnapshotResponse> {
+    let Context { storage, .. } = ctx.context();
+    let q = query.into_inner();
+    instrument_handler(&ctx, Scope::Public, async {
+        let ttl = q.ttl.unwrap_or(60);
+        if !(1..=300).contains(&ttl) {
+            let msg = format!("ttl not in 1..=300: {ttl}");
+            return Err(HttpError::for_bad_request(None, msg));
+        }
+        let (token, expires_at) = storage
+            .pin_snapshot(Duration::from_secs(ttl))
+            .await
+            .map_err(query_error)?;
+        OkCors(SnapshotResponse {
+            snapshot: URL_SAFE_NO_PAD.encode(token.to_be_bytes()),
+            expires_at: expires_at.into(),
+        })
+        .into()
+    })
+    .await
+}
+
 // TODO: replace with normal (🙃) multi-qs value somehow
 fn to_multiple_nsids(s: &str) -> Result<HashSet<Nsid>, String> {
     let mut out = HashSet::new();
@@ -207,89 +510,1085 @@ fn to_multiple_nsids(s: &str) -> Result<HashSet<Nsid>, String> {
 #[derive(Debug, Deserialize, JsonSchema)]
 struct RecordsCollectionsQuery {
     collection: Option<String>, // JsonSchema not implemented for Nsid :(
+    /// Read from a pinned snapshot (see `POST /snapshot`)
+    snapshot: Option<String>,
+    /// Include the JSON merge patch from the previous version for updates, if
+    /// one was stored
+    #[serde(default)]
+    include_diff: bool,
+    /// Only include these top-level fields of each record, comma-separated,
+    /// like `text,createdAt`. `$type` is always included.
+    fields: Option<String>,
+    /// Only include records matching this expression, like
+    /// `record.langs contains "en" AND NOT record.reply exists`
+    ///
+    /// Paths start with `did`, `collection`, `rkey`, `time_us`, or `record`,
+    /// with `.field` and `[index]` steps. Tests are `exists`, `contains`,
+    /// `startswith`, `==`, `!=`, `<`, `<=`, `>`, and `>=`, against json
+    /// values. Combine them with `AND`, `OR`, `NOT`, and parentheses.
+    ///
+    /// Only the most recent records are checked (see `filter_scanned` on
+    /// `/records/detailed`), so a rare match may not turn up.
+    filter: Option<String>,
+    /// Also count every match among the most recent records, not just the
+    /// ones returned (see `count` on `/records/detailed`)
+    #[serde(default)]
+    include_count: bool,
+}
+#[derive(Debug, Serialize, JsonSchema)]
+struct ApiRecord {
+    did: String,
+    collection: String,
+    rkey: String,
+    /// The repo revision the record was last written at
+    rev: String,
+    record: Box<serde_json::value::RawValue>,
+    time_us: u64,
+    /// Version of the redaction config applied to this record before it was
+    /// stored. Absent if the record was not redacted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    redaction_version: Option<u32>,
+    /// JSON merge patch (RFC 7386) that turns the previous version of this
+    /// record into this one. Only present for updates with `include_diff=true`,
+    /// when the server stores diffs and had the previous version.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    diff: Option<Box<serde_json::value::RawValue>>,
+    /// Names of the configured transforms that ran on this record before it
+    /// was stored, in order. Absent if no transforms apply to its collection.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transforms: Option<Vec<String>>,
+}
+impl From<UFOsRecord> for ApiRecord {
+    fn from(ufo: UFOsRecord) -> Self {
+        Self {
+            did: ufo.did.to_string(),
+            collection: ufo.collection.to_string(),
+            rkey: ufo.rkey.to_string(),
+            rev: ufo.rev,
+            record: ufo.record,
+            time_us: ufo.cursor.to_raw_u64(),
+            redaction_version: ufo.redaction_version,
+            diff: ufo.diff,
+            transforms: ufo.transforms,
+        }
+    }
+}
+/// Convert a record for the api, keeping only projected fields if there's a projection
+fn project(record: UFOsRecord, projection: Option<&Projection>) -> Result<ApiRecord, HttpError> {
+    let mut api_record: ApiRecord = record.into();
+    if let Some(projection) = projection {
+        api_record.record = projection
+            .apply(api_record.record)
+            .map_err(|e| HttpError::for_internal_error(format!("failed to project record: {e}")))?;
+    }
+    Ok(api_record)
+}
+#[derive(Debug, Serialize, JsonSchema)]
+struct RecordsResponse {
+    records: Vec<ApiRecord>,
+    /// Stored entries left out because they could not be decoded
+    ///
+    /// Usually zero. Anything else points at storage corruption on the server.
+    skipped_corrupt: usize,
+    /// With a `filter`: how many recent records were checked against it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filter_scanned: Option<usize>,
+    /// With `include_count=true`: how many records matched, including ones
+    /// past the limit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    count: Option<RecordsCount>,
+    /// Roughly how many records are stored for the requested collections
+    ///
+    /// A cheap storage estimate for "~N results" hints, not a count. Absent
+    /// without a `collection`, or when the server has nothing to go on yet.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    approximate_total: Option<u64>,
+    /// The scan hit the server's per-request limit, so collections may have
+    /// fewer records than `limit` even though more are available
+    truncated: bool,
+}
+#[derive(Debug, Serialize, JsonSchema)]
+struct RecordsCount {
+    /// Matching records, counted from the same read as `records`
+    matched: usize,
+    /// The scan stopped before the oldest record of some collection, so there
+    /// may be more matches than `matched`
+    is_lower_bound: bool,
+}
+/// How many recent records per collection a `filter` or count gets checked against
+const MAX_FILTER_SCAN: usize = 1000;
+/// Most keys one request can touch: longer scans stop early, to be continued
+const SCAN_BUDGET: usize = 100_000;
+/// Record samples
+///
+/// Get most recent records seen in the firehose, by collection NSID
+///
+/// Multiple collections are supported. They will be delivered in one big array with no
+/// specified order.
+///
+/// Only the records: `/records/detailed` takes the same query and also says
+/// how many were skipped, counted, or cut short.
+#[endpoint {
+    method = GET,
+    path = "/records",
+}]
+async fn get_records_by_collections(
+    ctx: RequestContext<Context>,
+    collection_query: Query<RecordsCollectionsQuery>,
+) -> OkCorsResponse<Vec<ApiRecord>> {
+    instrument_handler(&ctx, Scope::Trusted, async {
+        let found = find_records(&ctx, collection_query.into_inner()).await?;
+        OkCors(found.records).into()
+    })
+    .await
+}
+/// Record samples, with details
+///
+/// The same records as `/records`, in an object with what else the server
+/// knows about them: corrupt entries skipped, filter and count results, a
+/// rough total, and whether the scan was cut short.
+#[endpoint {
+    method = GET,
+    path = "/records/detailed",
+}]
+async fn get_records_detailed(
+    ctx: RequestContext<Context>,
+    collection_query: Query<RecordsCollectionsQuery>,
+) -> OkCorsResponse<RecordsResponse> {
+    instrument_handler(&ctx, Scope::Trusted, async {
+        let found = find_records(&ctx, collection_query.into_inner()).await?;
+        OkCors(found).into()
+    })
+    .await
+}
+async fn find_records(
+    ctx: &RequestContext<Context>,
+    query: RecordsCollectionsQuery,
+) -> Result<RecordsResponse, HttpError> {
+    let Context { storage, .. } = ctx.context();
+    let mut limit = 42;
+    let projection = query.fields.as_deref().map(Projection::parse).transpose()?;
+    let filter = query.filter.as_deref().map(Filter::parse).transpose()?;
+    let pinned = pinned_storage(storage.as_ref(), query.snapshot.as_deref())?;
+    let storage = pinned.as_deref().unwrap_or(storage.as_ref());
+    let explicit = query.collection.is_some();
+    let collections = if let Some(provided_collection) = query.collection {
+        to_multiple_nsids(&provided_collection)
+            .map_err(|reason| HttpError::for_bad_request(None, reason))?
+    } else {
+        limit = 12;
+        let min_time_ago = SystemTime::now() - Duration::from_secs(86_400 * 3); // we want at least 3 days of data
+        let since: WeekTruncatedCursor = Cursor::at(min_time_ago).into();
+        let (collections, _) = storage
+            .get_collections(
+                1000,
+                Default::default(),
+                HourWindow::starting(since.try_as().unwrap()),
+                ScanBudget::new(SCAN_BUDGET),
+            )
+            .await
+            .map_err(query_error)?;
+        collections
+            .into_iter()
+            .map(|c| Nsid::new(c.nsid).unwrap())
+            .collect()
+    };
+
+    let mut approximate_total = None;
+    if explicit {
+        for collection in &collections {
+            if let Some(n) = storage
+                .approximate_count(CountPrefix::Collection(collection.clone()))
+                .await
+                .map_err(query_error)?
+            {
+                *approximate_total.get_or_insert(0) += n;
+            }
+        }
+    }
+
+    let scan_limit = if filter.is_some() || query.include_count {
+        MAX_FILTER_SCAN
+    } else {
+        limit
+    };
+    let (mut records, skipped_corrupt, truncated) = storage
+        .get_records_by_collections(collections, scan_limit, true, ScanBudget::new(SCAN_BUDGET))
+        .await
+        .map_err(query_error)?;
+    let filter_scanned = filter.as_ref().map(|_| records.len());
+    let mut count = None;
+    if filter.is_some() || query.include_count {
+        // (scanned, matched) by collection
+        let mut per_collection: HashMap<Nsid, (usize, usize)> = HashMap::new();
+        let mut kept = Vec::new();
+        for record in records {
+            let (scanned, matched) = per_collection.entry(record.collection.clone()).or_default();
+            *scanned += 1;
+            if !query.include_count && *matched >= limit {
+                continue;
+            }
+            let is_match = match filter {
+                Some(ref filter) => filter.matches_record(&record).map_err(|e| {
+                    HttpError::for_internal_error(format!("failed to parse record: {e}"))
+                })?,
+                None => true,
+            };
+            if is_match {
+                if *matched < limit {
+                    kept.push(record);
+                }
+                *matched += 1;
+            }
+        }
+        if query.include_count {
+            count = Some(RecordsCount {
+                matched: per_collection.values().map(|(_, m)| m).sum(),
+                is_lower_bound: truncated
+                    || per_collection
+                        .values()
+                        .any(|(scanned, _)| *scanned >= scan_limit),
+            });
+        }
+        records = kept;
+    }
+    let records = records
+        .into_iter()
+        .map(|mut r| {
+            if !query.include_diff {
+                r.diff = None;
+            }
+            project(r, projection.as_ref())
+        })
+        .collect::<Result<_, _>>()?;
+
+    Ok(RecordsResponse {
+        records,
+        skipped_corrupt,
+        filter_scanned,
+        count,
+        approximate_total,
+        truncated,
+    })
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct SampleQuery {
+    collection: String,
+    /// How many records to draw
+    ///
+    /// default: 100, max: 500
+    #[schemars(range(min = 1, max = 500))]
+    n: Option<usize>,
+    /// Draw the same sample again: the `seed` from a previous response
+    ///
+    /// default: a new random seed
+    seed: Option<u64>,
+    /// Read from a pinned snapshot (see `POST /snapshot`)
+    snapshot: Option<String>,
+    /// Only include these top-level fields of each record, comma-separated,
+    /// like `text,createdAt`. `$type` is always included.
+    fields: Option<String>,
+}
+#[derive(Debug, Serialize, JsonSchema)]
+struct SampleResponse {
+    /// Pass back as `seed` to repeat this sample
+    seed: u64,
+    /// Newest first. Can be fewer than `n` for collections with few records.
+    records: Vec<ApiRecord>,
+}
+/// Random record sample
+///
+/// Get records drawn at random from everything retained for a collection, instead of
+/// only the most recent ones, for unbiased qualitative analysis.
+///
+/// Each draw picks a random time between the collection's oldest and newest retained
+/// records and takes the next record after it, so records following a quiet stretch are
+/// slightly favoured over ones in a burst.
+#[endpoint {
+    method = GET,
+    path = "/records/sample",
+}]
+async fn sample_records(
+    ctx: RequestContext<Context>,
+    query: Query<SampleQuery>,
+) -> OkCorsResponse<SampleResponse> {
+    let Context { storage, .. } = ctx.context();
+    let q = query.into_inner();
+    instrument_handler(&ctx, Scope::Trusted, async {
+        let collection = Nsid::new(q.collection).map_err(|e| {
+            HttpError::for_bad_request(None, format!("collection was not a valid NSID: {e:?}"))
+        })?;
+        let n = q.n.unwrap_or(100);
+        if !(1..=500).contains(&n) {
+            let msg = format!("n not in 1..=500: {n}");
+            return Err(HttpError::for_bad_request(None, msg));
+        }
+        let projection = q.fields.as_deref().map(Projection::parse).transpose()?;
+        let seed = match q.seed {
+            Some(seed) => seed,
+            // 53 bits, to survive a round-trip through javascript numbers
+            None => {
+                getrandom::u64().map_err(|e| {
+                    HttpError::for_internal_error(format!("failed to pick a seed: {e}"))
+                })? >> 11
+            }
+        };
+        let pinned = pinned_storage(storage.as_ref(), q.snapshot.as_deref())?;
+        let storage = pinned.as_deref().unwrap_or(storage.as_ref());
+        let records = storage
+            .sample_records(&collection, n, seed, ScanBudget::new(SCAN_BUDGET))
+            .await
+            .map_err(query_error)?
+            .into_iter()
+            .map(|mut r| {
+                r.diff = None;
+                project(r, projection.as_ref())
+            })
+            .collect::<Result<_, _>>()?;
+        OkCors(SampleResponse { seed, records }).into()
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct RecordSearchQuery {
+    /// Words to find in record text
+    ///
+    /// Every word has to match unless they're joined with `OR`. `"quoted phrases"`
+    /// match in order, and `-word` leaves out records with the word.
+    q: String,
+    /// Only search this collection's records
+    collection: Option<String>,
+    /// default: 20, max: 100
+    #[schemars(range(min = 1, max = 100))]
+    limit: Option<usize>,
+    /// Only include these top-level fields of each record, comma-separated,
+    /// like `text,createdAt`. `$type` is always included.
+    fields: Option<String>,
+}
+#[derive(Debug, Serialize, JsonSchema)]
+struct RecordSearchResponse {
+    /// Best match first
+    records: Vec<ApiRecord>,
+}
+/// Search record text
+///
+/// Find retained records by the text in them: every string value in a record is
+/// indexed, whatever its lexicon. Useful for exploring an unfamiliar lexicon without
+/// paging through its whole feed.
+///
+/// Only available when the server keeps a search index. Records stored in the last
+/// few seconds might not be found yet.
+#[endpoint {
+    method = GET,
+    path = "/records/search",
+}]
+async fn search_records(
+    ctx: RequestContext<Context>,
+    query: Query<RecordSearchQuery>,
+) -> OkCorsResponse<RecordSearchResponse> {
+    let Context { storage, .. } = ctx.context();
+    let q = query.into_inner();
+    instrument_handler(&ctx, Scope::Trusted, async {
+        if q.q.trim().is_empty() {
+            return Err(HttpError::for_bad_request(None, "q is empty".to_string()));
+        }
+        let collection = q
+            .collection
+            .map(|c| {
+                Nsid::new(c).map_err(|e| {
+                    HttpError::for_bad_request(
+                        None,
+                        format!("collection was not a valid NSID: {e:?}"),
+                    )
+                })
+            })
+            .transpose()?;
+        let limit = q.limit.unwrap_or(20);
+        if !(1..=100).contains(&limit) {
+            let msg = format!("limit not in 1..=100: {limit}");
+            return Err(HttpError::for_bad_request(None, msg));
+        }
+        let projection = q.fields.as_deref().map(Projection::parse).transpose()?;
+        let records = storage
+            .search_records(&q.q, collection.as_ref(), limit)
+            .await
+            .map_err(query_error)?
+            .into_iter()
+            .map(|mut r| {
+                r.diff = None;
+                project(r, projection.as_ref())
+            })
+            .collect::<Result<_, _>>()?;
+        OkCors(RecordSearchResponse { records }).into()
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct RecordQuery {
+    did: String,
+    collection: String,
+    rkey: String,
+    /// The rev the client already has. If it's still current, the response is
+    /// an empty `304 Not Modified`. The `If-None-Match` header works the same,
+    /// with the `ETag` from a previous response.
+    rev: Option<String>,
+    /// Read from a pinned snapshot (see `POST /snapshot`)
+    snapshot: Option<String>,
+    /// Only include these top-level fields of the record, comma-separated,
+    /// like `text,createdAt`. `$type` is always included.
+    fields: Option<String>,
+}
+/// Whether an `If-None-Match` header value names this rev
+fn etag_matches(if_none_match: &str, rev: &str) -> bool {
+    if_none_match.split(',').any(|tag| {
+        let tag = tag.trim();
+        let tag = tag.strip_prefix("W/").unwrap_or(tag);
+        tag == "*" || tag.trim_matches('"') == rev
+    })
+}
+/// Record
+///
+/// Get the current version of one sampled record.
+///
+/// Responses carry the record's rev as their `ETag`, so clients polling a record can send it
+/// back (as `rev` or `If-None-Match`) and get an empty `304` until the record changes.
+#[endpoint {
+    method = GET,
+    path = "/record",
+}]
+async fn get_record(
+    ctx: RequestContext<Context>,
+    record_query: Query<RecordQuery>,
+) -> Result<Response<Body>, HttpError> {
+    let Context { storage, .. } = ctx.context();
+    instrument_handler(&ctx, Scope::Trusted, async {
+        let q = record_query.into_inner();
+        let did = Did::new(q.did).map_err(|e| {
+            HttpError::for_bad_request(None, format!("did was not a valid DID: {e:?}"))
+        })?;
+        let collection = Nsid::new(q.collection).map_err(|e| {
+            HttpError::for_bad_request(None, format!("collection was not a valid NSID: {e:?}"))
+        })?;
+        let rkey = RecordKey::new(q.rkey).map_err(|e| {
+            HttpError::for_bad_request(None, format!("rkey was not a valid record key: {e:?}"))
+        })?;
+
+        let projection = q.fields.as_deref().map(Projection::parse).transpose()?;
+        let pinned = pinned_storage(storage.as_ref(), q.snapshot.as_deref())?;
+        let storage = pinned.as_deref().unwrap_or(storage.as_ref());
+
+        // the current version sorts first
+        let Some(current) = storage
+            .get_record_versions(&did, &collection, &rkey)
+            .await
+            .map_err(query_error)?
+            .into_iter()
+            .next()
+        else {
+            return Err(HttpError::for_not_found(
+                None,
+                "record not found (it may not have been sampled)".to_string(),
+            ));
+        };
+
+        let if_none_match = ctx
+            .request
+            .headers()
+            .get(IF_NONE_MATCH)
+            .and_then(|v| v.to_str().ok());
+        let unchanged = q.rev.as_deref() == Some(current.rev.as_str())
+            || if_none_match.is_some_and(|tags| etag_matches(tags, &current.rev));
+        let response = Response::builder()
+            .header(ETAG, format!("\"{}\"", current.rev))
+            .header("access-control-allow-origin", "*")
+            .header("access-control-expose-headers", "etag");
+        if unchanged {
+            counter!("server_record_not_modified").increment(1);
+            return Ok(response
+                .status(StatusCode::NOT_MODIFIED)
+                .body(Body::empty())?);
+        }
+        let body = serde_json::to_vec(&project(current, projection.as_ref())?).map_err(|e| {
+            HttpError::for_internal_error(format!("failed to serialize record: {e}"))
+        })?;
+        Ok(response
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.into())?)
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct RecordVersionsQuery {
+    did: String,
+    collection: String,
+    rkey: String,
+    /// Read from a pinned snapshot (see `POST /snapshot`)
+    snapshot: Option<String>,
+    /// Include the JSON merge patch from the version before each one, if one
+    /// was stored
+    #[serde(default)]
+    include_diff: bool,
+    /// Only include these top-level fields of each record, comma-separated,
+    /// like `text,createdAt`. `$type` is always included.
+    fields: Option<String>,
+}
+/// Record versions
+///
+/// Get the current version of a record followed by its older versions, newest first.
+///
+/// Older versions are only kept for collections the server is configured to keep them for,
+/// and only since it was configured. Other records have just the one version, if they were
+/// sampled at all.
+#[endpoint {
+    method = GET,
+    path = "/record/versions",
+}]
+async fn get_record_versions(
+    ctx: RequestContext<Context>,
+    versions_query: Query<RecordVersionsQuery>,
+) -> OkCorsResponse<Vec<ApiRecord>> {
+    let Context { storage, .. } = ctx.context();
+    instrument_handler(&ctx, Scope::Trusted, async {
+        let q = versions_query.into_inner();
+        let did = Did::new(q.did).map_err(|e| {
+            HttpError::for_bad_request(None, format!("did was not a valid DID: {e:?}"))
+        })?;
+        let collection = Nsid::new(q.collection).map_err(|e| {
+            HttpError::for_bad_request(None, format!("collection was not a valid NSID: {e:?}"))
+        })?;
+        let rkey = RecordKey::new(q.rkey).map_err(|e| {
+            HttpError::for_bad_request(None, format!("rkey was not a valid record key: {e:?}"))
+        })?;
+
+        let projection = q.fields.as_deref().map(Projection::parse).transpose()?;
+        let pinned = pinned_storage(storage.as_ref(), q.snapshot.as_deref())?;
+        let storage = pinned.as_deref().unwrap_or(storage.as_ref());
+
+        let versions = storage
+            .get_record_versions(&did, &collection, &rkey)
+            .await
+            .map_err(query_error)?
+            .into_iter()
+            .map(|mut r| {
+                if !q.include_diff {
+                    r.diff = None;
+                }
+                project(r, projection.as_ref())
+            })
+            .collect::<Result<_, _>>()?;
+
+        OkCors(versions).into()
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct AccountRecordsQuery {
+    did: String,
+    collection: String,
+    /// Only include records with an rkey at or after this one
+    rkey_start: Option<String>,
+    /// Only include records with an rkey before this one
+    rkey_end: Option<String>,
+    /// List from the highest rkey down. For TID rkeys, that's newest first.
+    #[serde(default)]
+    reverse: bool,
+    /// The maximum number of records to return in one request.
+    ///
+    /// Default: `100`
+    #[schemars(range(min = 1, max = 500))]
+    limit: Option<usize>,
+    /// Get the next page of records. Omit for the first request.
+    cursor: Option<String>,
+    /// Read from a pinned snapshot (see `POST /snapshot`)
+    snapshot: Option<String>,
+    /// Only include these top-level fields of each record, comma-separated,
+    /// like `text,createdAt`. `$type` is always included.
+    fields: Option<String>,
+}
+#[derive(Debug, Serialize, JsonSchema)]
+struct AccountRecordsResponse {
+    records: Vec<ApiRecord>,
+    /// Include in a follow-up request to get the next page of results, if more are available
+    cursor: Option<String>,
+    /// Roughly how many records the account has stored in the collection,
+    /// ignoring the rkey range. A cheap storage estimate for "~N results" hints.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    approximate_total: Option<u64>,
+}
+/// Records by account
+///
+/// List a DID's records in a collection, ordered by rkey.
+///
+/// TID rkeys sort chronologically, so this gives a per-account timeline for most collections.
+/// Only records that were sampled (and not yet trimmed) are available.
+#[endpoint {
+    method = GET,
+    path = "/records/by-did",
+}]
+async fn get_account_records(
+    ctx: RequestContext<Context>,
+    query: Query<AccountRecordsQuery>,
+) -> OkCorsResponse<AccountRecordsResponse> {
+    let Context { storage, .. } = ctx.context();
+    let q = query.into_inner();
+
+    instrument_handler(&ctx, Scope::Trusted, async {
+        let did = Did::new(q.did).map_err(|e| {
+            HttpError::for_bad_request(None, format!("did was not a valid DID: {e:?}"))
+        })?;
+        let collection = Nsid::new(q.collection).map_err(|e| {
+            HttpError::for_bad_request(None, format!("collection was not a valid NSID: {e:?}"))
+        })?;
+        let limit = q.limit.unwrap_or(100);
+        if !(1..=500).contains(&limit) {
+            let msg = format!("limit not in 1..=500: {limit}");
+            return Err(HttpError::for_bad_request(None, msg));
+        }
+        let projection = q.fields.as_deref().map(Projection::parse).transpose()?;
+
+        // the cursor is the last rkey returned: continue past it
+        let mut start = q.rkey_start.map_or(Bound::Unbounded, Bound::Included);
+        let mut end = q.rkey_end.map_or(Bound::Unbounded, Bound::Excluded);
+        if let Some(cursor) = q.cursor {
+            if q.reverse {
+                end = Bound::Excluded(cursor);
+            } else {
+                start = Bound::Excluded(cursor);
+            }
+        }
+
+        let pinned = pinned_storage(storage.as_ref(), q.snapshot.as_deref())?;
+        let storage = pinned.as_deref().unwrap_or(storage.as_ref());
+
+        let (records, more) = storage
+            .get_account_records(&did, &collection, (start, end), limit, q.reverse)
+            .await
+            .map_err(query_error)?;
+
+        let approximate_total = storage
+            .approximate_count(CountPrefix::AccountCollection(did, collection))
+            .await
+            .map_err(query_error)?;
+
+        let cursor = if more {
+            records.last().map(|r| r.rkey.to_string())
+        } else {
+            None
+        };
+        let records = records
+            .into_iter()
+            .map(|r| project(r, projection.as_ref()))
+            .collect::<Result<_, _>>()?;
+
+        OkCors(AccountRecordsResponse {
+            records,
+            cursor,
+            approximate_total,
+        })
+        .into()
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct DidRecordsPath {
+    did: String,
+}
+#[derive(Debug, Deserialize, JsonSchema)]
+struct DidRecordsQuery {
+    /// The maximum number of records to return in one request.
+    ///
+    /// Default: `100`
+    #[schemars(range(min = 1, max = 500))]
+    limit: Option<usize>,
+    /// Get the next page of records. Omit for the first request.
+    cursor: Option<String>,
+    /// Read from a pinned snapshot (see `POST /snapshot`)
+    snapshot: Option<String>,
+    /// Only include these top-level fields of each record, comma-separated,
+    /// like `text,createdAt`. `$type` is always included.
+    fields: Option<String>,
+}
+#[derive(Debug, Serialize, JsonSchema)]
+struct DidRecordsResponse {
+    records: Vec<ApiRecord>,
+    /// Include in a follow-up request to get the next page of results, if more are available
+    cursor: Option<String>,
+    /// The scan hit the server's per-request limit before filling the page, so
+    /// there may be fewer records than `limit` even though more are available
+    truncated: bool,
+    /// Roughly how many records the account has stored, across collections.
+    /// A cheap storage estimate for "~N results" hints.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    approximate_total: Option<u64>,
+}
+/// Records by account, all collections
+///
+/// List a DID's records across every tracked collection, grouped by collection.
+///
+/// Only records that were sampled (and not yet trimmed) are available. To list one collection
+/// in rkey order, use `/records/by-did` instead.
+#[endpoint {
+    method = GET,
+    path = "/records/by-did/{did}",
+}]
+async fn get_did_records(
+    ctx: RequestContext<Context>,
+    path: Path<DidRecordsPath>,
+    query: Query<DidRecordsQuery>,
+) -> OkCorsResponse<DidRecordsResponse> {
+    let Context { storage, .. } = ctx.context();
+    let did = path.into_inner().did;
+    let q = query.into_inner();
+
+    instrument_handler(&ctx, Scope::Trusted, async {
+        let did = Did::new(did).map_err(|e| {
+            HttpError::for_bad_request(None, format!("did was not a valid DID: {e:?}"))
+        })?;
+        let limit = q.limit.unwrap_or(100);
+        if !(1..=500).contains(&limit) {
+            let msg = format!("limit not in 1..=500: {limit}");
+            return Err(HttpError::for_bad_request(None, msg));
+        }
+        let projection = q.fields.as_deref().map(Projection::parse).transpose()?;
+        let cursor = q
+            .cursor
+            .map(|c| URL_SAFE_NO_PAD.decode(&c))
+            .transpose()
+            .map_err(|e| HttpError::for_bad_request(None, format!("invalid cursor: {e:?}")))?;
+
+        let pinned = pinned_storage(storage.as_ref(), q.snapshot.as_deref())?;
+        let storage = pinned.as_deref().unwrap_or(storage.as_ref());
+
+        let (records, cursor, truncated) = storage
+            .get_records_by_did(&did, cursor, limit, ScanBudget::new(SCAN_BUDGET))
+            .await
+            .map_err(query_error)?;
+
+        let approximate_total = storage
+            .approximate_count(CountPrefix::Account(did))
+            .await
+            .map_err(query_error)?;
+
+        let cursor = cursor.map(|c| URL_SAFE_NO_PAD.encode(c));
+        let records = records
+            .into_iter()
+            .map(|r| project(r, projection.as_ref()))
+            .collect::<Result<_, _>>()?;
+
+        OkCors(DidRecordsResponse {
+            records,
+            cursor,
+            truncated,
+            approximate_total,
+        })
+        .into()
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct DuplicatesQuery {
+    collection: String,
+    /// How many of the collection's most recent sampled records to look at
+    ///
+    /// Default: `500`
+    #[schemars(range(min = 1, max = 2000))]
+    sample: Option<usize>,
+    /// Records whose simhashes differ by at most this many bits (of 64) are near-duplicates
+    ///
+    /// Default: `6`
+    #[schemars(range(min = 0, max = 32))]
+    max_distance: Option<u32>,
+    /// Leave out clusters with fewer records than this
+    ///
+    /// Default: `3`
+    #[schemars(range(min = 2))]
+    min_size: Option<usize>,
+}
+#[derive(Debug, Serialize, JsonSchema)]
+struct DuplicateCluster {
+    /// Simhash of the newest record in the cluster, as hex
+    simhash: String,
+    /// How many of the sampled records are in the cluster
+    size: usize,
+    /// How many different accounts created them
+    dids: usize,
+    /// The newest few records in the cluster
+    examples: Vec<ApiRecord>,
+}
+#[derive(Debug, Serialize, JsonSchema)]
+struct DuplicatesResponse {
+    /// How many records with text were compared
+    sampled: usize,
+    /// Biggest first
+    clusters: Vec<DuplicateCluster>,
+}
+/// Near-duplicate records
+///
+/// Cluster a collection's recently sampled records by the simhash of their text, to spot
+/// templated floods: many records saying nearly the same thing. Records without any text
+/// are skipped.
+#[endpoint {
+    method = GET,
+    path = "/records/duplicates",
+}]
+async fn get_duplicate_records(
+    ctx: RequestContext<Context>,
+    query: Query<DuplicatesQuery>,
+) -> OkCorsResponse<DuplicatesResponse> {
+    let Context { storage, .. } = ctx.context();
+    let q = query.into_inner();
+    instrument_handler(&ctx, Scope::Trusted, async {
+        let collection = Nsid::new(q.collection).map_err(|e| {
+            HttpError::for_bad_request(None, format!("collection was not a valid NSID: {e:?}"))
+        })?;
+        let sample = q.sample.unwrap_or(500);
+        if !(1..=2000).contains(&sample) {
+            let msg = format!("sample not in 1..=2000: {sample}");
+            return Err(HttpError::for_bad_request(None, msg));
+        }
+        let max_distance = q.max_distance.unwrap_or(6);
+        if max_distance > 32 {
+            let msg = format!("max_distance not in 0..=32: {max_distance}");
+            return Err(HttpError::for_bad_request(None, msg));
+        }
+        let min_size = q.min_size.unwrap_or(3);
+        if min_size < 2 {
+            let msg = format!("min_size must be at least 2: {min_size}");
+            return Err(HttpError::for_bad_request(None, msg));
+        }
+
+        let mut records = vec![];
+        let mut hashes = vec![];
+        let (sampled, _, _) = storage
+            .get_records_by_collections(
+                [collection].into(),
+                sample,
+                false,
+                ScanBudget::new(SCAN_BUDGET),
+            )
+            .await
+            .map_err(query_error)?;
+        for record in sampled {
+            let hash = simhash::simhash(&record.record).map_err(|e| {
+                HttpError::for_internal_error(format!("failed to hash record: {e}"))
+            })?;
+            if hash == 0 {
+                continue; // no text
+            }
+            records.push(record);
+            hashes.push(hash);
+        }
+
+        let clusters = simhash::cluster(&hashes, max_distance, min_size)
+            .into_iter()
+            .map(|members| {
+                let dids: HashSet<_> = members.iter().map(|&i| &records[i].did).collect();
+                DuplicateCluster {
+                    simhash: format!("{:016x}", hashes[members[0]]),
+                    size: members.len(),
+                    dids: dids.len(),
+                    examples: members
+                        .iter()
+                        .take(3)
+                        .map(|&i| {
+                            let mut example = records[i].clone();
+                            example.diff = None;
+                            example.into()
+                        })
+                        .collect(),
+                }
+            })
+            .collect();
+
+        OkCors(DuplicatesResponse {
+            sampled: hashes.len(),
+            clusters,
+        })
+        .into()
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct WatchlistHitsQuery {
+    /// The watchlist's name, from the server's watchlist config
+    name: String,
+    /// Get hits before this cursor (microseconds): the `next` from a previous page
+    before: Option<u64>,
+    /// default: 100, max: 500
+    #[schemars(range(min = 1, max = 500))]
+    limit: Option<usize>,
+    /// Hourly counts since this time: a UTC datetime, or relative like `-24h`
+    ///
+    /// default: 1 week ago
+    since: Option<QueryTime>,
+    /// Hourly counts until this time (a UTC datetime, or relative)
+    ///
+    /// default: now
+    until: Option<QueryTime>,
+    /// A window up to the latest event instead of `since` and `until`, like `1h`, `24h`, or `7d`
+    period: Option<QueryPeriod>,
 }
 #[derive(Debug, Serialize, JsonSchema)]
-struct ApiRecord {
+struct WatchlistHit {
     did: String,
     collection: String,
     rkey: String,
-    record: Box<serde_json::value::RawValue>,
     time_us: u64,
+    /// The configured keywords and regexes that matched
+    matched: Vec<String>,
+    /// The record as it matched. Absent if it's since been updated, deleted, or trimmed.
+    record: Option<ApiRecord>,
 }
-impl From<UFOsRecord> for ApiRecord {
-    fn from(ufo: UFOsRecord) -> Self {
-        Self {
-            did: ufo.did.to_string(),
-            collection: ufo.collection.to_string(),
-            rkey: ufo.rkey.to_string(),
-            record: ufo.record,
-            time_us: ufo.cursor.to_raw_u64(),
-        }
-    }
+#[derive(Debug, Serialize, JsonSchema)]
+struct WatchlistHourlyCount {
+    hour: DateTime<Utc>,
+    hits: u64,
 }
-/// Record samples
+#[derive(Debug, Serialize, JsonSchema)]
+struct WatchlistHitsResponse {
+    /// Newest first
+    hits: Vec<WatchlistHit>,
+    /// Pass as `before` for the next page, if there might be more
+    next: Option<u64>,
+    /// Oldest first. Hours without hits are left out.
+    hourly: Vec<WatchlistHourlyCount>,
+}
+/// Watchlist hits
 ///
-/// Get most recent records seen in the firehose, by collection NSID
+/// Records that matched one of the server's configured keyword or regex watchlists as they
+/// were stored, newest first, with hit counts rolled up per hour. Hits are kept for the
+/// watchlist config's retention (a week by default); hourly counts are kept forever.
 ///
-/// Multiple collections are supported. They will be delivered in one big array with no
-/// specified order.
+/// An unknown watchlist name just has no hits.
 #[endpoint {
     method = GET,
-    path = "/records",
+    path = "/watchlist/hits",
 }]
-async fn get_records_by_collections(
+async fn get_watchlist_hits(
     ctx: RequestContext<Context>,
-    collection_query: Query<RecordsCollectionsQuery>,
-) -> OkCorsResponse<Vec<ApiRecord>> {
+    query: Query<WatchlistHitsQuery>,
+) -> OkCorsResponse<WatchlistHitsResponse> {
     let Context { storage, .. } = ctx.context();
-    instrument_handler(&ctx, async {
-        let mut limit = 42;
-        let query = collection_query.into_inner();
-        let collections = if let Some(provided_collection) = query.collection {
-            to_multiple_nsids(&provided_collection)
-                .map_err(|reason| HttpError::for_bad_request(None, reason))?
+    let q = query.into_inner();
+    instrument_handler(&ctx, Scope::Trusted, async {
+        let limit = q.limit.unwrap_or(100);
+        if !(1..=500).contains(&limit) {
+            let msg = format!("limit not in 1..=500: {limit}");
+            return Err(HttpError::for_bad_request(None, msg));
+        }
+        let (since, until) = with_period(storage.as_ref(), q.since, q.until, q.period).await?;
+        let since = since
+            .map(QueryTime::hour_cursor)
+            .transpose()?
+            .unwrap_or_else(|| {
+                let week_ago_secs = 7 * 86_400;
+                let week_ago = SystemTime::now() - Duration::from_secs(week_ago_secs);
+                Cursor::at(week_ago).into()
+            });
+        let until = until.map(QueryTime::hour_cursor).transpose()?;
+        check_window(storage.as_ref(), Some(since.into()), until.map(Into::into)).await?;
+
+        let hits = storage
+            .get_watchlist_hits(&q.name, q.before.map(Cursor::from_raw_u64), limit)
+            .await
+            .map_err(query_error)?;
+        let next = if hits.len() == limit {
+            hits.last().map(|h| h.cursor.to_raw_u64())
         } else {
-            limit = 12;
-            let min_time_ago = SystemTime::now() - Duration::from_secs(86_400 * 3); // we want at least 3 days of data
-            let since: WeekTruncatedCursor = Cursor::at(min_time_ago).into();
-            let (collections, _) = storage
-                .get_collections(
-                    1000,
-                    Default::default(),
-                    Some(since.try_as().unwrap()),
-                    None,
-                )
-                .await
-                .map_err(|e| HttpError::for_internal_error(e.to_string()))?;
-            collections
-                .into_iter()
-                .map(|c| Nsid::new(c.nsid).unwrap())
-                .collect()
+            None
         };
+        let hits = hits
+            .into_iter()
+            .map(|hit| WatchlistHit {
+                did: hit.did.to_string(),
+                collection: hit.collection.to_string(),
+                rkey: hit.rkey.to_string(),
+                time_us: hit.cursor.to_raw_u64(),
+                matched: hit.matched,
+                record: hit.record.map(|mut record| {
+                    record.diff = None;
+                    record.into()
+                }),
+            })
+            .collect();
 
-        let records = storage
-            .get_records_by_collections(collections, limit, true)
+        let hourly = storage
+            .get_watchlist_counts(&q.name, since, until)
             .await
-            .map_err(|e| HttpError::for_internal_error(e.to_string()))?
+            .map_err(query_error)?
             .into_iter()
-            .map(|r| r.into())
+            .map(|(hour, hits)| WatchlistHourlyCount {
+                hour: cursor_to_dt(hour),
+                hits,
+            })
             .collect();
 
-        OkCors(records).into()
+        OkCors(WatchlistHitsResponse { hits, next, hourly }).into()
     })
     .await
 }
 
+#[derive(Debug, Default, Clone, Copy, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+enum WindowAlign {
+    /// Round `since` and `until` down to the hour
+    #[default]
+    Hour,
+    /// Round `since` and `until` down to the week (weeks start on thursdays, at the unix epoch)
+    Week,
+    /// Use `since` and `until` exactly, with best-effort counts for partial hours
+    None,
+}
 #[derive(Debug, Deserialize, JsonSchema)]
 struct CollectionsStatsQuery {
-    /// Limit stats to those seen after this UTC datetime
+    /// Limit stats to those seen after this time: a UTC datetime, or relative like `-24h`
     ///
     /// default: 1 week ago
-    since: Option<DateTime<Utc>>,
-    /// Limit stats to those seen before this UTC datetime
+    since: Option<QueryTime>,
+    /// Limit stats to those seen before this time (a UTC datetime, or relative)
     ///
     /// default: now
-    until: Option<DateTime<Utc>>,
+    until: Option<QueryTime>,
+    /// A window up to the latest event instead of `since` and `until`, like `1h`, `24h`, or `7d`
+    period: Option<QueryPeriod>,
+    /// How to line `since` and `until` up with the rollup buckets
+    ///
+    /// default: `hour`
+    align: Option<WindowAlign>,
+    /// Read from a pinned snapshot (see `POST /snapshot`)
+    snapshot: Option<String>,
+}
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+struct CollectionStats {
+    #[serde(flatten)]
+    counts: JustCount,
+    /// Counts only cover events since this time (microseconds): the later of
+    /// when UFOs started and when this collection was first seen
+    tracked_since: u64,
+    /// With `align=none`: some hours were only partly inside the window, so
+    /// their counts were scaled down by the overlap
+    #[serde(default)]
+    prorated: bool,
+    /// With `align=none`: some counts came from events that haven't been rolled up yet
+    #[serde(default)]
+    live: bool,
+    /// Distinct records created in the window, not counting replayed or
+    /// re-created records
+    ///
+    /// Approximate when any creates were dropped from full batches: see
+    /// `unchecked`. Only with `align=hour` or `align=week`, when ufos is
+    /// counting them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    unique_records: Option<UniqueRecords>,
 }
 /// Collection stats
 ///
@@ -299,6 +1598,11 @@ struct CollectionsStatsQuery {
 /// so the data here can be as stale as that background task is behind. See the
 /// meta info endpoint to find out how up-to-date the rollup currently is. (In
 /// general it sholud be pretty close to live)
+///
+/// With `align=none`, events that haven't been rolled up yet are counted too,
+/// and windows can start and end anywhere (like "the last 90 minutes"). Hours
+/// that the window only partly covers are scaled by the overlap, so those
+/// counts are estimates: check the `prorated` and `live` flags.
 #[endpoint {
     method = GET,
     path = "/collections/stats"
@@ -307,30 +1611,105 @@ async fn get_collection_stats(
     ctx: RequestContext<Context>,
     collections_query: MultiCollectionQuery,
     query: Query<CollectionsStatsQuery>,
-) -> OkCorsResponse<HashMap<String, JustCount>> {
-    let Context { storage, .. } = ctx.context();
+) -> OkCorsResponse<HashMap<String, CollectionStats>> {
+    let Context { storage, cache, .. } = ctx.context();
 
-    instrument_handler(&ctx, async {
+    instrument_handler(&ctx, Scope::Public, async {
         let q = query.into_inner();
         let collections: HashSet<Nsid> = collections_query.try_into()?;
+        let pinned = pinned_storage(storage.as_ref(), q.snapshot.as_deref())?;
+        // snapshots don't change, so there's nothing to gain from caching them
+        let cache = cache.as_ref().filter(|_| pinned.is_none());
+        let storage = pinned.as_deref().unwrap_or(storage.as_ref());
+
+        let align = q.align.unwrap_or_default();
+        // unaligned windows move with every request (and can include live
+        // counts), so a cached copy would never be hit, or would be stale
+        let cache = cache.filter(|_| !matches!(align, WindowAlign::None));
+        let aligned = |c: Cursor| match align {
+            WindowAlign::Hour => HourTruncatedCursor::from(c).into(),
+            WindowAlign::Week => WeekTruncatedCursor::from(c).into(),
+            WindowAlign::None => c,
+        };
 
-        let since = q.since.map(dt_to_cursor).transpose()?.unwrap_or_else(|| {
-            let week_ago_secs = 7 * 86_400;
-            let week_ago = SystemTime::now() - Duration::from_secs(week_ago_secs);
-            Cursor::at(week_ago).into()
-        });
+        let (since, until) = with_period(storage, q.since, q.until, q.period).await?;
+        let since = since
+            .map(QueryTime::cursor)
+            .transpose()?
+            .unwrap_or_else(|| {
+                let week_ago_secs = 7 * 86_400;
+                Cursor::at(SystemTime::now() - Duration::from_secs(week_ago_secs))
+            });
+        let since = aligned(since);
 
-        let until = q.until.map(dt_to_cursor).transpose()?;
+        let until = until.map(QueryTime::cursor).transpose()?.map(aligned);
+        check_window(storage, Some(since), until).await?;
 
         let mut seen_by_collection = HashMap::with_capacity(collections.len());
 
         for collection in &collections {
-            let counts = storage
-                .get_collection_counts(collection, since, until)
+            let cache_key = CacheKey::Collection(
+                collection.clone(),
+                format!(
+                    "stats:{align:?}:{}:{}",
+                    since.to_raw_u64(),
+                    until
+                        .map(|c| c.to_raw_u64().to_string())
+                        .unwrap_or_default()
+                ),
+            );
+            if let Some(cache) = cache {
+                if let Some(stats) = cache.get::<CollectionStats>(&cache_key).await {
+                    seen_by_collection.insert(collection.to_string(), stats);
+                    continue;
+                }
+            }
+
+            let (counts, prorated, live, unique_records) = match align {
+                WindowAlign::Hour | WindowAlign::Week => {
+                    let counts = storage
+                        .get_collection_counts(collection, since.into(), until.map(Into::into))
+                        .await
+                        .map_err(query_error)?;
+                    let unique_records = match storage
+                        .get_unique_records(collection, since.into(), until.map(Into::into))
+                        .await
+                    {
+                        // stats still work on backends without unique counts
+                        Err(QueryError::Storage(StorageError::Unsupported(_))) => None,
+                        r => r.map_err(query_error)?,
+                    };
+                    (counts, false, false, unique_records)
+                }
+                WindowAlign::None => {
+                    let WindowCounts {
+                        counts,
+                        prorated,
+                        live,
+                    } = storage
+                        .get_collection_window_counts(collection, since, until)
+                        .await
+                        .map_err(query_error)?;
+                    (counts, prorated, live, None)
+                }
+            };
+            let tracked_since = storage
+                .get_tracked_since(collection)
                 .await
-                .map_err(|e| HttpError::for_internal_error(format!("boooo: {e:?}")))?;
+                .map_err(query_error)?
+                .to_raw_u64();
 
-            seen_by_collection.insert(collection.to_string(), counts);
+            let stats = CollectionStats {
+                counts,
+                tracked_since,
+                prorated,
+                live,
+                unique_records,
+            };
+            if let Some(cache) = cache {
+                cache.put(&cache_key, &stats).await;
+            }
+            seen_by_collection.insert(collection.to_string(), stats);
         }
 
         OkCors(seen_by_collection).into()
@@ -338,13 +1717,16 @@ async fn get_collection_stats(
     .await
 }
 
-#[derive(Debug, Serialize, JsonSchema)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 struct CollectionsResponse {
     /// Each known collection and its associated statistics
     ///
-    /// The order is unspecified.
+    /// Sorted by NSID, unless an `order` was requested.
     collections: Vec<NsidCount>,
     /// Include in a follow-up request to get the next page of results, if more are available
+    ///
+    /// A page can be short if it hit the server's per-request scan limit: keep
+    /// paging until there's no cursor.
     cursor: Option<String>,
 }
 #[derive(Debug, Deserialize, JsonSchema)]
@@ -353,11 +1735,11 @@ pub enum CollectionsQueryOrder {
     RecordsCreated,
     DidsEstimate,
 }
-impl From<&CollectionsQueryOrder> for OrderCollectionsBy {
-    fn from(q: &CollectionsQueryOrder) -> Self {
-        match q {
-            CollectionsQueryOrder::RecordsCreated => OrderCollectionsBy::RecordsCreated,
-            CollectionsQueryOrder::DidsEstimate => OrderCollectionsBy::DidsEstimate,
+impl CollectionsQueryOrder {
+    fn with_cursor(&self, cursor: Option<Vec<u8>>) -> OrderCollectionsBy {
+        match self {
+            CollectionsQueryOrder::RecordsCreated => OrderCollectionsBy::RecordsCreated { cursor },
+            CollectionsQueryOrder::DidsEstimate => OrderCollectionsBy::DidsEstimate { cursor },
         }
     }
 }
@@ -372,16 +1754,20 @@ struct CollectionsQuery {
     ///
     /// Always omit the cursor for the first request. If more collections than the limit are available, the response will contain a non-null `cursor` to include with the next request.
     ///
-    /// `cursor` is mutually exclusive with `order`.
+    /// Cursors are tied to the `order` they were returned for: keep `order` (and `since`/`until`) the same while paging.
     cursor: Option<String>,
-    /// Limit collections and statistics to those seen after this UTC datetime
-    since: Option<DateTime<Utc>>,
-    /// Limit collections and statistics to those seen before this UTC datetime
-    until: Option<DateTime<Utc>>,
-    /// Get a limited, sorted list
+    /// Limit collections and statistics to those seen after this time: a UTC datetime, or relative like `-24h`
+    since: Option<QueryTime>,
+    /// Limit collections and statistics to those seen before this time (a UTC datetime, or relative)
+    until: Option<QueryTime>,
+    /// A window up to the latest event instead of `since` and `until`, like `1h`, `24h`, or `7d`
+    period: Option<QueryPeriod>,
+    /// Get a sorted list, highest first
     ///
-    /// Mutually exclusive with `cursor` -- sorted results cannot be paged.
+    /// Collections with equal counts are ordered by NSID, descending, so the order is stable between requests.
     order: Option<CollectionsQueryOrder>,
+    /// Read from a pinned snapshot (see `POST /snapshot`)
+    snapshot: Option<String>,
 }
 
 /// List collections
@@ -404,7 +1790,7 @@ struct CollectionsQuery {
 ///
 /// ## To fetch the top collection NSIDs:
 ///
-/// Specify the `order` parameter (must be either `records-created` or `did-estimate`). Note that ordered results cannot be paged.
+/// Specify the `order` parameter (must be either `records-created` or `did-estimate`). Ordered results can be paged with `cursor` too, though rankings over a `since`/`until` range are approximate and can shift between pages as new events arrive.
 ///
 /// All statistics are bucketed hourly, so the most granular effecitve time boundary for `since` and `until` is one hour.
 #[endpoint {
@@ -415,26 +1801,25 @@ async fn get_collections(
     ctx: RequestContext<Context>,
     query: Query<CollectionsQuery>,
 ) -> OkCorsResponse<CollectionsResponse> {
-    let Context { storage, .. } = ctx.context();
+    let Context { storage, cache, .. } = ctx.context();
     let q = query.into_inner();
 
-    instrument_handler(&ctx, async {
-        if q.cursor.is_some() && q.order.is_some() {
-            let msg =
-                "`cursor` is mutually exclusive with `order`. ordered results cannot be paged.";
-            return Err(HttpError::for_bad_request(None, msg.to_string()));
-        }
+    instrument_handler(&ctx, Scope::Public, async {
+        let cursor = q
+            .cursor
+            .and_then(|c| if c.is_empty() { None } else { Some(c) })
+            .map(|c| URL_SAFE_NO_PAD.decode(&c))
+            .transpose()
+            .map_err(|e| HttpError::for_bad_request(None, format!("invalid cursor: {e:?}")))?;
 
-        let order = if let Some(ref o) = q.order {
-            o.into()
-        } else {
-            let cursor = q
-                .cursor
-                .and_then(|c| if c.is_empty() { None } else { Some(c) })
-                .map(|c| URL_SAFE_NO_PAD.decode(&c))
-                .transpose()
-                .map_err(|e| HttpError::for_bad_request(None, format!("invalid cursor: {e:?}")))?;
-            OrderCollectionsBy::Lexi { cursor }
+        // only the first page of a sorted list is cached: later pages are rarely repeated
+        let top_order = match (&q.order, &cursor) {
+            (Some(o), None) => Some(format!("{o:?}")),
+            _ => None,
+        };
+        let order = match q.order {
+            Some(ref o) => o.with_cursor(cursor),
+            None => OrderCollectionsBy::Lexi { cursor },
         };
 
         let limit = match (q.limit, q.order) {
@@ -448,21 +1833,51 @@ async fn get_collections(
             return Err(HttpError::for_bad_request(None, msg));
         }
 
-        let since = q.since.map(dt_to_cursor).transpose()?;
-        let until = q.until.map(dt_to_cursor).transpose()?;
+        let (since, until) = with_period(storage.as_ref(), q.since, q.until, q.period).await?;
+        let window = hour_window(since, until)?;
+        check_window(
+            storage.as_ref(),
+            window.since().map(Into::into),
+            window.until().map(Into::into),
+        )
+        .await?;
+
+        let pinned = pinned_storage(storage.as_ref(), q.snapshot.as_deref())?;
+
+        let cached = match (cache, top_order, &pinned) {
+            (Some(cache), Some(o), None) => Some((
+                cache,
+                CacheKey::TopCollections(format!(
+                    "{o}:{limit}:{}:{}",
+                    cache_bound(window.since()),
+                    cache_bound(window.until())
+                )),
+            )),
+            _ => None,
+        };
+        if let Some((cache, key)) = &cached {
+            if let Some(response) = cache.get::<CollectionsResponse>(key).await {
+                return OkCors(response).into();
+            }
+        }
+
+        let storage = pinned.as_deref().unwrap_or(storage.as_ref());
 
         let (collections, next_cursor) = storage
-            .get_collections(limit, order, since, until)
+            .get_collections(limit, order, window, ScanBudget::new(SCAN_BUDGET))
             .await
-            .map_err(|e| HttpError::for_internal_error(format!("oh shoot: {e:?}")))?;
+            .map_err(query_error)?;
 
         let next_cursor = next_cursor.map(|c| URL_SAFE_NO_PAD.encode(c));
 
-        OkCors(CollectionsResponse {
+        let response = CollectionsResponse {
             collections,
             cursor: next_cursor,
-        })
-        .into()
+        };
+        if let Some((cache, key)) = &cached {
+            cache.put(key, &response).await;
+        }
+        OkCors(response).into()
     })
     .await
 }
@@ -473,6 +1888,9 @@ struct PrefixResponse {
     total: JustCount,
     children: Vec<PrefixChild>,
     /// Include in a follow-up request to get the next page of results, if more are available
+    ///
+    /// A page can be short if it hit the server's per-request scan limit: keep
+    /// paging until there's no cursor.
     cursor: Option<String>,
 }
 #[derive(Debug, Deserialize, JsonSchema)]
@@ -497,18 +1915,22 @@ struct PrefixQuery {
     ///
     /// `cursor` is mutually exclusive with `order`.
     cursor: Option<String>,
-    /// Limit collections and statistics to those seen after this UTC datetime
+    /// Limit collections and statistics to those seen after this time: a UTC datetime, or relative like `-24h`
     ///
     /// Default: all-time
-    since: Option<DateTime<Utc>>,
-    /// Limit collections and statistics to those seen before this UTC datetime
+    since: Option<QueryTime>,
+    /// Limit collections and statistics to those seen before this time (a UTC datetime, or relative)
     ///
     /// Default: now
-    until: Option<DateTime<Utc>>,
+    until: Option<QueryTime>,
+    /// A window up to the latest event instead of `since` and `until`, like `1h`, `24h`, or `7d`
+    period: Option<QueryPeriod>,
     /// Get a limited, sorted list
     ///
     /// Mutually exclusive with `cursor` -- sorted results cannot be paged.
     order: Option<CollectionsQueryOrder>,
+    /// Read from a pinned snapshot (see `POST /snapshot`)
+    snapshot: Option<String>,
 }
 /// Prefix-filter collections list
 ///
@@ -530,7 +1952,7 @@ struct PrefixQuery {
 ///
 /// ## To fetch the top collection NSIDs:
 ///
-/// Specify the `order` parameter (must be either `records-created` or `did-estimate`). Note that ordered results cannot be paged.
+/// Specify the `order` parameter (must be either `records-created` or `did-estimate`). Ordered results cannot be paged. Child prefixes are ranked by the combined counts of everything under them, over the `since`/`until` range if given.
 ///
 /// All statistics are bucketed hourly, so the most granular effecitve time boundary for `since` and `until` is one hour.
 #[endpoint {
@@ -544,7 +1966,7 @@ async fn get_prefix(
     let Context { storage, .. } = ctx.context();
     let q = query.into_inner();
 
-    instrument_handler(&ctx, async {
+    instrument_handler(&ctx, Scope::Public, async {
         let prefix = NsidPrefix::new(&q.prefix).map_err(|e| {
             HttpError::for_bad_request(
                 None,
@@ -559,7 +1981,7 @@ async fn get_prefix(
         }
 
         let order = if let Some(ref o) = q.order {
-            o.into()
+            o.with_cursor(None)
         } else {
             let cursor = q
                 .cursor
@@ -581,13 +2003,22 @@ async fn get_prefix(
             return Err(HttpError::for_bad_request(None, msg));
         }
 
-        let since = q.since.map(dt_to_cursor).transpose()?;
-        let until = q.until.map(dt_to_cursor).transpose()?;
+        let (since, until) = with_period(storage.as_ref(), q.since, q.until, q.period).await?;
+        let window = hour_window(since, until)?;
+        check_window(
+            storage.as_ref(),
+            window.since().map(Into::into),
+            window.until().map(Into::into),
+        )
+        .await?;
+
+        let pinned = pinned_storage(storage.as_ref(), q.snapshot.as_deref())?;
+        let storage = pinned.as_deref().unwrap_or(storage.as_ref());
 
         let (total, children, next_cursor) = storage
-            .get_prefix(prefix, limit, order, since, until)
+            .get_prefix(prefix, limit, order, window, ScanBudget::new(SCAN_BUDGET))
             .await
-            .map_err(|e| HttpError::for_internal_error(format!("oh shoot: {e:?}")))?;
+            .map_err(query_error)?;
 
         let next_cursor = next_cursor.map(|c| URL_SAFE_NO_PAD.encode(c));
 
@@ -601,17 +2032,154 @@ async fn get_prefix(
     .await
 }
 
+#[derive(Debug, Serialize, JsonSchema)]
+struct PrefixTreeResponse {
+    prefix: String,
+    total: JustCount,
+    /// The biggest children by records created, each with its own children down to `depth`
+    children: Vec<PrefixTreeNode>,
+    /// Sums for the children of `prefix` that were left out
+    other: Option<OtherChildren>,
+    /// Some prefixes above `depth` were not expanded, because the request hit its expansion limit
+    truncated: bool,
+}
+#[derive(Debug, Deserialize, JsonSchema)]
+struct PrefixTreeQuery {
+    /// The NSID prefix to start from, eg. `app.bsky`
+    prefix: String,
+    /// How many levels of the tree to return under `prefix`
+    ///
+    /// Default: `2` for `/prefix/tree`, `1` for `/collections/tree`
+    #[schemars(range(min = 1, max = 4))]
+    depth: Option<usize>,
+    /// The most children to list for each prefix. The rest are summed into `other`.
+    ///
+    /// Default: `10` for `/prefix/tree`, `50` for `/collections/tree`
+    #[schemars(range(min = 1, max = 50))]
+    children: Option<usize>,
+    /// Limit collections and statistics to those seen after this time: a UTC datetime, or relative like `-24h`
+    ///
+    /// Default: all-time
+    since: Option<QueryTime>,
+    /// Limit collections and statistics to those seen before this time (a UTC datetime, or relative)
+    ///
+    /// Default: now
+    until: Option<QueryTime>,
+    /// A window up to the latest event instead of `since` and `until`, like `1h`, `24h`, or `7d`
+    period: Option<QueryPeriod>,
+    /// Read from a pinned snapshot (see `POST /snapshot`)
+    snapshot: Option<String>,
+}
+/// Validate a tree query and walk the tree for it
+async fn walk_prefix_tree(
+    storage: &dyn StoreReader,
+    q: PrefixTreeQuery,
+    default_depth: usize,
+    default_children: usize,
+) -> Result<PrefixTreeResponse, HttpError> {
+    let prefix = NsidPrefix::new(&q.prefix).map_err(|e| {
+        HttpError::for_bad_request(
+            None,
+            format!("{:?} was not a valid NSID prefix: {e:?}", q.prefix),
+        )
+    })?;
+
+    let depth = q.depth.unwrap_or(default_depth);
+    if !(1..=MAX_TREE_DEPTH).contains(&depth) {
+        let msg = format!("depth not in 1..={MAX_TREE_DEPTH}: {depth}");
+        return Err(HttpError::for_bad_request(None, msg));
+    }
+    let children = q.children.unwrap_or(default_children);
+    if !(1..=MAX_TREE_CHILDREN).contains(&children) {
+        let msg = format!("children not in 1..={MAX_TREE_CHILDREN}: {children}");
+        return Err(HttpError::for_bad_request(None, msg));
+    }
+
+    let (since, until) = with_period(storage, q.since, q.until, q.period).await?;
+    let window = hour_window(since, until)?;
+    check_window(
+        storage,
+        window.since().map(Into::into),
+        window.until().map(Into::into),
+    )
+    .await?;
+
+    let pinned = pinned_storage(storage, q.snapshot.as_deref())?;
+    let storage = pinned.as_deref().unwrap_or(storage);
+
+    let mut walk = TreeWalk::new(storage, depth, children);
+    walk.window = window;
+    let (total, children, other) = walk.walk(prefix).await?;
+
+    Ok(PrefixTreeResponse {
+        prefix: q.prefix,
+        total,
+        children,
+        other,
+        truncated: walk.truncated,
+    })
+}
+/// NSID tree under a prefix
+///
+/// Like `/prefix`, but walks down into child prefixes, ranked by records created. Depth, breadth, and the
+/// total number of prefixes expanded are all capped, and children left out at any level are summed into
+/// `other` so that totals still add up.
+#[endpoint {
+    method = GET,
+    path = "/prefix/tree"
+}]
+async fn get_prefix_tree(
+    ctx: RequestContext<Context>,
+    query: Query<PrefixTreeQuery>,
+) -> OkCorsResponse<PrefixTreeResponse> {
+    let Context { storage, .. } = ctx.context();
+    let q = query.into_inner();
+
+    instrument_handler(&ctx, Scope::Public, async {
+        let tree = walk_prefix_tree(storage.as_ref(), q, 2, 10).await?;
+        OkCors(tree).into()
+    })
+    .await
+}
+/// Expandable NSID tree nodes
+///
+/// For explorer UIs that open the NSID hierarchy one level at a time. By default this returns just the
+/// immediate children of `prefix`, with aggregate counts for each. Children with `"type": "prefix"` can
+/// be expanded by requesting this endpoint again with their `prefix`; `collection` children are leaves.
+///
+/// Takes the same parameters as `/prefix/tree`, but `depth` defaults to `1` and `children` to the most
+/// allowed.
+#[endpoint {
+    method = GET,
+    path = "/collections/tree"
+}]
+async fn get_collections_tree(
+    ctx: RequestContext<Context>,
+    query: Query<PrefixTreeQuery>,
+) -> OkCorsResponse<PrefixTreeResponse> {
+    let Context { storage, .. } = ctx.context();
+    let q = query.into_inner();
+
+    instrument_handler(&ctx, Scope::Public, async {
+        let tree = walk_prefix_tree(storage.as_ref(), q, 1, MAX_TREE_CHILDREN).await?;
+        OkCors(tree).into()
+    })
+    .await
+}
+
 #[derive(Debug, Deserialize, JsonSchema)]
 struct CollectionTimeseriesQuery {
     collection: String, // JsonSchema not implemented for Nsid :(
-    /// Limit collections and statistics to those seen after this UTC datetime
+    /// Limit collections and statistics to those seen after this time: a UTC datetime, or relative like `-24h`
     ///
     /// default: 1 week ago
-    since: Option<DateTime<Utc>>,
-    /// Limit collections and statistics to those seen before this UTC datetime
+    since: Option<QueryTime>,
+    /// Limit collections and statistics to those seen before this time (a UTC datetime, or relative)
     ///
     /// default: now
-    until: Option<DateTime<Utc>>,
+    until: Option<QueryTime>,
+    /// A window up to the latest event instead of `since` and `until`, like `1h`, `24h`, or `7d`
+    period: Option<QueryPeriod>,
     /// time steps between data, in seconds
     ///
     /// the step will be rounded down to the nearest hour
@@ -619,6 +2187,15 @@ struct CollectionTimeseriesQuery {
     /// default: 86400 (24hrs)
     #[schemars(range(min = 3600))]
     step: Option<u64>,
+    /// Line steps up with this time zone's clock, in whole hours from UTC
+    ///
+    /// Steps are counted from the unix epoch, shifted by this offset: with the default daily step, each step runs from midnight to midnight in the time zone. `since` is rounded down to the start of its step.
+    ///
+    /// default: 0 (UTC)
+    #[schemars(range(min = -12, max = 14))]
+    utc_offset_hours: Option<i8>,
+    /// Read from a pinned snapshot (see `POST /snapshot`)
+    snapshot: Option<String>,
     // todo: rolling averages
 }
 #[derive(Debug, Serialize, JsonSchema)]
@@ -627,6 +2204,8 @@ struct CollectionTimeseriesResponse {
     series: HashMap<String, Vec<JustCount>>,
 }
 /// Collection timeseries stats
+///
+/// Counts are rolled up hourly, so steps (and time zone offsets) are whole hours.
 #[endpoint {
     method = GET,
     path = "/timeseries"
@@ -638,15 +2217,7 @@ async fn get_timeseries(
     let Context { storage, .. } = ctx.context();
     let q = query.into_inner();
 
-    instrument_handler(&ctx, async {
-        let since = q.since.map(dt_to_cursor).transpose()?.unwrap_or_else(|| {
-            let week_ago_secs = 7 * 86_400;
-            let week_ago = SystemTime::now() - Duration::from_secs(week_ago_secs);
-            Cursor::at(week_ago).into()
-        });
-
-        let until = q.until.map(dt_to_cursor).transpose()?;
-
+    instrument_handler(&ctx, Scope::Public, async {
         let step = if let Some(secs) = q.step {
             if secs < 3600 {
                 let msg = format!("step is too small: {secs}");
@@ -657,19 +2228,43 @@ async fn get_timeseries(
             86_400
         };
 
+        let utc_offset_hours = q.utc_offset_hours.unwrap_or(0);
+        if !(-12..=14).contains(&utc_offset_hours) {
+            let msg = format!("utc_offset_hours not in -12..=14: {utc_offset_hours}");
+            return Err(HttpError::for_bad_request(None, msg));
+        }
+
+        let (since, until) = with_period(storage.as_ref(), q.since, q.until, q.period).await?;
+        let since: HourTruncatedCursor = since
+            .map(QueryTime::hour_cursor)
+            .transpose()?
+            .unwrap_or_else(|| {
+                let week_ago_secs = 7 * 86_400;
+                let week_ago = SystemTime::now() - Duration::from_secs(week_ago_secs);
+                Cursor::at(week_ago).into()
+            });
+        // local midnight at UTC+n is n hours *before* midnight UTC
+        let offset = -(utc_offset_hours as i64) * 3_600_000_000;
+        let since = since
+            .bucket_start(step * 1_000_000, offset)
+            .map_err(|e| HttpError::for_bad_request(None, format!("bad step alignment: {e}")))?;
+
+        let until = until.map(QueryTime::hour_cursor).transpose()?;
+        check_window(storage.as_ref(), Some(since.into()), until.map(Into::into)).await?;
+
         let nsid = Nsid::new(q.collection).map_err(|e| {
             HttpError::for_bad_request(None, format!("collection was not a valid NSID: {e:?}"))
         })?;
 
+        let pinned = pinned_storage(storage.as_ref(), q.snapshot.as_deref())?;
+        let storage = pinned.as_deref().unwrap_or(storage.as_ref());
+
         let (range_cursors, series) = storage
             .get_timeseries(vec![nsid], since, until, step)
             .await
-            .map_err(|e| HttpError::for_internal_error(format!("oh shoot: {e:?}")))?;
+            .map_err(query_error)?;
 
-        let range = range_cursors
-            .into_iter()
-            .map(|c| DateTime::<Utc>::from_timestamp_micros(c.to_raw_u64() as i64).unwrap())
-            .collect();
+        let range = range_cursors.into_iter().map(cursor_to_dt).collect();
 
         let series = series
             .into_iter()
@@ -681,6 +2276,251 @@ async fn get_timeseries(
     .await
 }
 
+#[derive(Debug, Deserialize, JsonSchema)]
+struct EventKindsQuery {
+    /// Start of the window: a UTC datetime, or relative like `-24h`
+    ///
+    /// default: 24 hours ago
+    since: Option<QueryTime>,
+    /// End of the window (a UTC datetime, or relative)
+    ///
+    /// default: now
+    until: Option<QueryTime>,
+    /// A window up to the latest event instead of `since` and `until`, like `1h`, `24h`, or `7d`
+    period: Option<QueryPeriod>,
+    /// time steps between data, in seconds
+    ///
+    /// the step will be rounded down to the nearest hour
+    ///
+    /// default: 3600 (1hr)
+    #[schemars(range(min = 3600))]
+    step: Option<u64>,
+    /// Read from a pinned snapshot (see `POST /snapshot`)
+    snapshot: Option<String>,
+}
+#[derive(Debug, Serialize, JsonSchema)]
+struct EventKindsResponse {
+    range: Vec<DateTime<Utc>>,
+    counts: Vec<EventKindCounts>,
+}
+/// Firehose composition
+///
+/// How many commit, identity, and account events were consumed in each step, including events
+/// that aren't stored (like identity changes). Counted hourly since this feature was deployed.
+#[endpoint {
+    method = GET,
+    path = "/event-kinds"
+}]
+async fn get_event_kinds(
+    ctx: RequestContext<Context>,
+    query: Query<EventKindsQuery>,
+) -> OkCorsResponse<EventKindsResponse> {
+    let Context { storage, .. } = ctx.context();
+    let q = query.into_inner();
+
+    instrument_handler(&ctx, Scope::Public, async {
+        let step = if let Some(secs) = q.step {
+            if secs < 3600 {
+                let msg = format!("step is too small: {secs}");
+                Err(HttpError::for_bad_request(None, msg))?;
+            }
+            (secs / 3600) * 3600 // trucate to hour
+        } else {
+            3600
+        };
+
+        let (since, until) = with_period(storage.as_ref(), q.since, q.until, q.period).await?;
+        let since: HourTruncatedCursor = since
+            .map(QueryTime::hour_cursor)
+            .transpose()?
+            .unwrap_or_else(|| {
+                let day_ago = SystemTime::now() - Duration::from_secs(86_400);
+                Cursor::at(day_ago).into()
+            });
+        let until = until.map(QueryTime::hour_cursor).transpose()?;
+        check_window(storage.as_ref(), Some(since.into()), until.map(Into::into)).await?;
+
+        let pinned = pinned_storage(storage.as_ref(), q.snapshot.as_deref())?;
+        let storage = pinned.as_deref().unwrap_or(storage.as_ref());
+
+        let (range_cursors, counts) = storage
+            .get_event_kinds(since, until, step)
+            .await
+            .map_err(query_error)?;
+        let range = range_cursors.into_iter().map(cursor_to_dt).collect();
+
+        OkCors(EventKindsResponse { range, counts }).into()
+    })
+    .await
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+enum AccountsStep {
+    Hour,
+    #[default]
+    Day,
+}
+#[derive(Debug, Deserialize, JsonSchema)]
+struct AccountsQuery {
+    /// Up to 10 collection NSIDs, comma-separated
+    collection: String, // JsonSchema not implemented for Nsid :(
+    /// Count accounts per hour or per day
+    ///
+    /// default: `day`
+    step: Option<AccountsStep>,
+    /// How many complete steps to go back
+    ///
+    /// default: 24 hours or 30 days
+    #[schemars(range(min = 1, max = 168))]
+    periods: Option<u64>,
+    /// Read from a pinned snapshot (see `POST /snapshot`)
+    snapshot: Option<String>,
+}
+#[derive(Debug, Serialize, JsonSchema)]
+struct AccountsResponse {
+    /// The start of each step, oldest first
+    range: Vec<DateTime<Utc>>,
+    /// Estimated distinct accounts that created, updated, or deleted records
+    /// in each step, by collection
+    series: HashMap<String, Vec<u64>>,
+}
+/// Active accounts per collection
+///
+/// A ready-to-plot series of the estimated number of distinct accounts
+/// writing to each collection, for each of the last few complete hours or
+/// days (UTC). The current, partial step is left out so the series doesn't
+/// end on a dip.
+///
+/// Estimates for each step are merged from the hourly rollups, so one account
+/// active in several hours of a day counts once for the day.
+#[endpoint {
+    method = GET,
+    path = "/accounts"
+}]
+async fn get_accounts(
+    ctx: RequestContext<Context>,
+    query: Query<AccountsQuery>,
+) -> OkCorsResponse<AccountsResponse> {
+    let Context { storage, .. } = ctx.context();
+    let q = query.into_inner();
+    instrument_handler(&ctx, Scope::Public, async {
+        let collections = to_multiple_nsids(&q.collection)
+            .map_err(|reason| HttpError::for_bad_request(None, reason))?;
+        if collections.len() > 10 {
+            let msg = format!("too many collections: {} (max 10)", collections.len());
+            return Err(HttpError::for_bad_request(None, msg));
+        }
+        let step = q.step.unwrap_or_default();
+        let (step_secs, default_periods, max_periods) = match step {
+            AccountsStep::Hour => (3_600, 24, 168),
+            AccountsStep::Day => (86_400, 30, 90),
+        };
+        let periods = q.periods.unwrap_or(default_periods);
+        if !(1..=max_periods).contains(&periods) {
+            let msg = format!("periods not in 1..={max_periods} for {step:?}: {periods}");
+            return Err(HttpError::for_bad_request(None, msg));
+        }
+
+        let step_us = step_secs * 1_000_000;
+        let current: HourTruncatedCursor = Cursor::at(SystemTime::now()).into();
+        let until = current
+            .bucket_start(step_us, 0)
+            .map_err(|e| HttpError::for_internal_error(format!("bad step alignment: {e}")))?;
+        let since = HourTruncatedCursor::try_from_raw_u64(
+            until.to_raw_u64().saturating_sub(periods * step_us),
+        )
+        .map_err(|e| HttpError::for_internal_error(format!("bad step alignment: {e}")))?;
+
+        let pinned = pinned_storage(storage.as_ref(), q.snapshot.as_deref())?;
+        let storage = pinned.as_deref().unwrap_or(storage.as_ref());
+
+        let (range_cursors, series) = storage
+            .get_timeseries(
+                collections.into_iter().collect(),
+                since,
+                Some(until),
+                step_secs,
+            )
+            .await
+            .map_err(query_error)?;
+
+        let range = range_cursors.into_iter().map(cursor_to_dt).collect();
+        let series = series
+            .into_iter()
+            .map(|(nsid, counts)| {
+                let accounts = counts
+                    .iter()
+                    .map(|c| microcosm_estimates::estimate(c.dids()))
+                    .collect();
+                (nsid.to_string(), accounts)
+            })
+            .collect();
+
+        OkCors(AccountsResponse { range, series }).into()
+    })
+    .await
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct GrowthQuery {
+    /// Compare the latest complete hour or week with the one before it
+    ///
+    /// default: `week`
+    period: Option<GrowthPeriod>,
+    /// default: 32
+    #[schemars(range(min = 1, max = 200))]
+    limit: Option<usize>,
+    /// Leave out collections with fewer records created than this in the earlier bucket
+    ///
+    /// default: 10
+    min_previous: Option<u64>,
+}
+/// Fastest growing collections
+///
+/// Collections ranked by how much their records created grew from one rollup
+/// bucket to the next: the latest complete hour or week, against the one
+/// before it. Collections that are new in the latest bucket aren't ranked,
+/// since they have nothing to grow from.
+///
+/// Collections with equal growth are ordered by NSID, descending.
+#[endpoint {
+    method = GET,
+    path = "/collections/growth"
+}]
+async fn get_growing_collections(
+    ctx: RequestContext<Context>,
+    query: Query<GrowthQuery>,
+) -> OkCorsResponse<GrowthRanking> {
+    let Context { storage, cache, .. } = ctx.context();
+    let q = query.into_inner();
+    instrument_handler(&ctx, Scope::Public, async {
+        let period = q.period.unwrap_or_default();
+        let limit = q.limit.unwrap_or(32);
+        if !(1..=200).contains(&limit) {
+            let msg = format!("limit not in 1..=200: {limit}");
+            return Err(HttpError::for_bad_request(None, msg));
+        }
+        let min_previous = q.min_previous.unwrap_or(10);
+
+        let key = CacheKey::TopCollections(format!("growth:{period:?}:{limit}:{min_previous}"));
+        if let Some(cache) = cache {
+            if let Some(ranking) = cache.get::<GrowthRanking>(&key).await {
+                return OkCors(ranking).into();
+            }
+        }
+        let ranking = storage
+            .get_growing_collections(period, limit, min_previous)
+            .await
+            .map_err(query_error)?;
+        if let Some(cache) = cache {
+            cache.put(&key, &ranking).await;
+        }
+        OkCors(ranking).into()
+    })
+    .await
+}
+
 #[derive(Debug, Deserialize, JsonSchema)]
 struct SearchQuery {
     /// Query
@@ -691,6 +2531,9 @@ struct SearchQuery {
 #[derive(Debug, Serialize, JsonSchema)]
 struct SearchResponse {
     matches: Vec<NsidCount>,
+    /// The search hit the server's per-request scan limit before looking at
+    /// every collection, so there may be more matches
+    truncated: bool,
 }
 /// Search lexicons
 #[endpoint {
@@ -703,20 +2546,95 @@ async fn search_collections(
 ) -> OkCorsResponse<SearchResponse> {
     let Context { storage, .. } = ctx.context();
     let q = query.into_inner();
-    instrument_handler(&ctx, async {
+    instrument_handler(&ctx, Scope::Public, async {
         // TODO: query validation
         // TODO: also handle multi-space stuff (ufos-app tries to on client)
         let terms: Vec<String> = q.q.split(' ').map(Into::into).collect();
-        let matches = storage
-            .search_collections(terms)
+        let (matches, truncated) = storage
+            .search_collections(terms, ScanBudget::new(SCAN_BUDGET))
             .await
-            .map_err(|e| HttpError::for_internal_error(format!("oh ugh: {e:?}")))?;
-        OkCors(SearchResponse { matches }).into()
+            .map_err(query_error)?;
+        OkCors(SearchResponse { matches, truncated }).into()
     })
     .await
 }
 
-pub async fn serve(storage: impl StoreReader + 'static) -> Result<(), String> {
+/// List the main API's endpoints once, for registering them and for checking
+/// auth configs against their operation ids (the handler fn names)
+macro_rules! endpoints {
+    ($($endpoint:ident),* $(,)?) => {
+        /// Operation ids of the main API's endpoints
+        pub const ENDPOINTS: &[&str] = &[$(stringify!($endpoint)),*];
+
+        fn register_endpoints(api: &mut ApiDescription<Context>) {
+            $(api.register($endpoint).unwrap();)*
+        }
+    };
+}
+
+endpoints!(
+    index,
+    get_openapi,
+    get_metrics,
+    get_meta_info,
+    get_summary,
+    get_cursor,
+    get_changes,
+    pin_snapshot,
+    get_records_by_collections,
+    get_records_detailed,
+    sample_records,
+    search_records,
+    get_record,
+    get_record_versions,
+    get_account_records,
+    get_did_records,
+    get_duplicate_records,
+    get_watchlist_hits,
+    get_collection_stats,
+    get_collections,
+    get_prefix,
+    get_prefix_tree,
+    get_collections_tree,
+    get_growing_collections,
+    get_timeseries,
+    get_event_kinds,
+    get_accounts,
+    search_collections,
+);
+
+/// Serve the main API
+///
+/// Use [`Auth::open`] to leave every endpoint public.
+///
+/// With a `cache`, top-collections lists and collection stats are served from
+/// redis when they can be.
+///
+/// With `metrics`, the installed prometheus recorder is rendered at `/metrics`.
+pub async fn serve(
+    storage: impl StoreReader + 'static,
+    auth: Arc<Auth>,
+    cache: Option<RedisCache>,
+    metrics: Option<PrometheusHandle>,
+) -> Result<(), String> {
+    serve_at(
+        storage,
+        auth,
+        cache,
+        metrics,
+        "0.0.0.0:9999".parse().unwrap(),
+    )
+    .await
+}
+
+/// [`serve`], listening somewhere other than the usual port
+pub async fn serve_at(
+    storage: impl StoreReader + 'static,
+    auth: Arc<Auth>,
+    cache: Option<RedisCache>,
+    metrics: Option<PrometheusHandle>,
+    bind_address: SocketAddr,
+) -> Result<(), String> {
     describe_metrics();
     let log = ConfigLogging::StderrTerminal {
         level: ConfigLoggingLevel::Warn,
@@ -725,16 +2643,7 @@ pub async fn serve(storage: impl StoreReader + 'static) -> Result<(), String> {
     .map_err(|e| e.to_string())?;
 
     let mut api = ApiDescription::new();
-
-    api.register(index).unwrap();
-    api.register(get_openapi).unwrap();
-    api.register(get_meta_info).unwrap();
-    api.register(get_records_by_collections).unwrap();
-    api.register(get_collection_stats).unwrap();
-    api.register(get_collections).unwrap();
-    api.register(get_prefix).unwrap();
-    api.register(get_timeseries).unwrap();
-    api.register(search_collections).unwrap();
+    register_endpoints(&mut api);
 
     let context = Context {
         spec: Arc::new(
@@ -754,11 +2663,14 @@ pub async fn serve(storage: impl StoreReader + 'static) -> Result<(), String> {
             .map_err(|e| e.to_string())?,
         ),
         storage: Box::new(storage),
+        auth,
+        cache,
+        metrics,
     };
 
     ServerBuilder::new(api, context, log)
         .config(ConfigDropshot {
-            bind_address: "0.0.0.0:9999".parse().unwrap(),
+            bind_address,
             ..Default::default()
         })
         .start()
@@ -0,0 +1,192 @@
+//! Bounded walks down the NSID tree from a prefix
+//!
+//! Every level is a `get_prefix` scan, so depth, breadth, and the number of
+//! prefixes expanded per request are all capped. Children past the breadth
+//! limit are summed into an `other` bucket instead of being dropped silently.
+
+use super::{query_error, SCAN_BUDGET};
+use crate::storage::{ScanBudget, StoreReader};
+use crate::store_types::HourWindow;
+use crate::{JustCount, NsidPrefix, OrderCollectionsBy, PrefixChild};
+use dropshot::HttpError;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::future::Future;
+use std::pin::Pin;
+
+pub const MAX_TREE_DEPTH: usize = 4;
+pub const MAX_TREE_CHILDREN: usize = 50;
+/// Children looked at for each prefix: the biggest are listed, the rest go in `other`
+const SCAN_CHILDREN: usize = 200;
+/// Prefixes expanded per request, over all levels
+const MAX_TREE_EXPANSIONS: usize = 64;
+/// Keys each expansion can scan, so that a whole walk stays in one request's budget
+const EXPANSION_BUDGET: usize = SCAN_BUDGET / MAX_TREE_EXPANSIONS;
+
+/// Children left out of a node, summed
+#[derive(Debug, Default, PartialEq, Serialize, JsonSchema)]
+pub struct OtherChildren {
+    /// How many children were left out
+    pub count: usize,
+    pub creates: u64,
+    pub updates: u64,
+    pub deletes: u64,
+    /// There were more children than could be looked at, so everything above
+    /// is a lower bound
+    pub more: bool,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct PrefixTreeNode {
+    #[serde(flatten)]
+    pub node: PrefixChild,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<PrefixTreeNode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub other: Option<OtherChildren>,
+}
+
+/// Keep the `keep` children with the most creates, and sum up the rest
+fn keep_biggest(
+    mut children: Vec<PrefixChild>,
+    keep: usize,
+    more: bool,
+) -> (Vec<PrefixChild>, Option<OtherChildren>) {
+    if children.len() <= keep && !more {
+        return (children, None);
+    }
+    children.sort_by_key(|c| std::cmp::Reverse(c.crud().0));
+    let mut other = OtherChildren {
+        more,
+        ..Default::default()
+    };
+    for child in children.drain(keep.min(children.len())..) {
+        let (creates, updates, deletes) = child.crud();
+        other.count += 1;
+        other.creates += creates;
+        other.updates += updates;
+        other.deletes += deletes;
+    }
+    (children, Some(other))
+}
+
+pub struct TreeWalk<'a> {
+    pub storage: &'a dyn StoreReader,
+    pub depth: usize,
+    pub children: usize,
+    pub window: HourWindow,
+    expansions: usize,
+    /// set when some prefixes weren't expanded because the request ran out of expansions
+    pub truncated: bool,
+}
+
+type Level = (JustCount, Vec<PrefixTreeNode>, Option<OtherChildren>);
+
+impl<'a> TreeWalk<'a> {
+    pub fn new(storage: &'a dyn StoreReader, depth: usize, children: usize) -> Self {
+        Self {
+            storage,
+            depth,
+            children,
+            window: HourWindow::all_time(),
+            expansions: 0,
+            truncated: false,
+        }
+    }
+
+    /// The tree under a prefix, `depth` levels deep
+    pub async fn walk(&mut self, prefix: NsidPrefix) -> Result<Level, HttpError> {
+        self.level(prefix, 1).await
+    }
+
+    fn level(
+        &mut self,
+        prefix: NsidPrefix,
+        depth: usize,
+    ) -> Pin<Box<dyn Future<Output = Result<Level, HttpError>> + Send + '_>> {
+        Box::pin(async move {
+            self.expansions += 1;
+            let (total, children, cursor) = self
+                .storage
+                .get_prefix(
+                    prefix,
+                    SCAN_CHILDREN,
+                    OrderCollectionsBy::Lexi { cursor: None },
+                    self.window,
+                    ScanBudget::new(EXPANSION_BUDGET),
+                )
+                .await
+                .map_err(query_error)?;
+            let (children, other) = keep_biggest(children, self.children, cursor.is_some());
+            let mut nodes = Vec::with_capacity(children.len());
+            for child in children {
+                let sub_prefix = match child.as_prefix() {
+                    Some(p) if depth < self.depth => Some(p.to_string()),
+                    _ => None,
+                };
+                let mut node = PrefixTreeNode {
+                    node: child,
+                    children: vec![],
+                    other: None,
+                };
+                if let Some(p) = sub_prefix {
+                    if self.expansions >= MAX_TREE_EXPANSIONS {
+                        self.truncated = true;
+                    } else {
+                        let prefix = NsidPrefix::new(&p).map_err(|e| {
+                            HttpError::for_internal_error(format!("bad stored prefix {p:?}: {e:?}"))
+                        })?;
+                        let (_, children, other) = self.level(prefix, depth + 1).await?;
+                        node.children = children;
+                        node.other = other;
+                    }
+                }
+                nodes.push(node);
+            }
+            Ok((total, nodes, other))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store_types::{CommitCounts, CountsValue};
+    use crate::PrefixCount;
+
+    fn child(prefix: &str, creates: u64) -> PrefixChild {
+        let counts = CountsValue::new(
+            CommitCounts {
+                creates,
+                updates: 1,
+                deletes: 0,
+            },
+            Default::default(),
+        );
+        PrefixChild::Prefix(PrefixCount::new(prefix, &counts))
+    }
+
+    #[test]
+    fn test_keep_biggest() {
+        let children = vec![child("a.a", 1), child("a.b", 30), child("a.c", 20)];
+        let (kept, other) = keep_biggest(children, 2, false);
+        assert_eq!(kept, vec![child("a.b", 30), child("a.c", 20)]);
+        assert_eq!(
+            other,
+            Some(OtherChildren {
+                count: 1,
+                creates: 1,
+                updates: 1,
+                deletes: 0,
+                more: false,
+            })
+        );
+
+        let (kept, other) = keep_biggest(vec![child("a.a", 1)], 2, false);
+        assert_eq!(kept.len(), 1);
+        assert_eq!(other, None);
+
+        let (_, other) = keep_biggest(vec![child("a.a", 1)], 2, true);
+        assert_eq!(other.map(|o| (o.count, o.more)), Some((0, true)));
+    }
+}
@@ -0,0 +1,108 @@
+use dropshot::HttpError;
+use serde_json::value::RawValue;
+use std::collections::HashMap;
+
+/// Don't let one request ask for an unreasonable number of fields
+const MAX_PROJECTED_FIELDS: usize = 64;
+
+/// Top-level record fields to keep, from a `fields=text,createdAt` query param
+///
+/// `$type` is always kept. Field values are copied through as raw json without
+/// being parsed, in the order the fields were asked for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Projection(Vec<String>);
+
+impl Projection {
+    pub fn parse(fields: &str) -> Result<Self, HttpError> {
+        let mut out: Vec<String> = vec!["$type".to_string()];
+        for field in fields.split(',').map(str::trim) {
+            if field.is_empty() {
+                return Err(HttpError::for_bad_request(
+                    None,
+                    format!("empty field name in fields={fields:?}"),
+                ));
+            }
+            if !out.iter().any(|f| f == field) {
+                out.push(field.to_string());
+            }
+        }
+        if out.len() > MAX_PROJECTED_FIELDS + 1 {
+            return Err(HttpError::for_bad_request(
+                None,
+                format!("too many fields (max {MAX_PROJECTED_FIELDS})"),
+            ));
+        }
+        Ok(Self(out))
+    }
+
+    /// Keep only the projected fields of a record
+    ///
+    /// Records that aren't json objects are returned unchanged.
+    pub fn apply(&self, record: Box<RawValue>) -> Result<Box<RawValue>, serde_json::Error> {
+        let Ok(fields) = serde_json::from_str::<HashMap<String, &RawValue>>(record.get()) else {
+            return Ok(record);
+        };
+        let mut out = String::from("{");
+        for name in &self.0 {
+            let Some(value) = fields.get(name) else {
+                continue;
+            };
+            if out.len() > 1 {
+                out.push(',');
+            }
+            out.push_str(&serde_json::to_string(name)?);
+            out.push(':');
+            out.push_str(value.get());
+        }
+        out.push('}');
+        RawValue::from_string(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn project(fields: &str, record: &str) -> String {
+        let raw = RawValue::from_string(record.to_string()).unwrap();
+        Projection::parse(fields)
+            .unwrap()
+            .apply(raw)
+            .unwrap()
+            .get()
+            .to_string()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!(Projection::parse("text").is_ok());
+        assert!(Projection::parse("text,,createdAt").is_err());
+        assert!(Projection::parse("").is_err());
+        let too_many: Vec<_> = (0..=MAX_PROJECTED_FIELDS)
+            .map(|i| format!("f{i}"))
+            .collect();
+        assert!(Projection::parse(&too_many.join(",")).is_err());
+        assert_eq!(
+            Projection::parse("a, b,a").unwrap(),
+            Projection(vec!["$type".to_string(), "a".to_string(), "b".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_apply() {
+        assert_eq!(
+            project(
+                "createdAt,text",
+                r#"{"$type":"a.b.c","text":"hi","embed":{"big":[1,2,3]},"createdAt":"2025"}"#
+            ),
+            r#"{"$type":"a.b.c","createdAt":"2025","text":"hi"}"#
+        );
+        // values are passed through untouched
+        assert_eq!(
+            project("nested", r#"{"nested": { "x" : [1, 2] }}"#),
+            r#"{"nested":{ "x" : [1, 2] }}"#
+        );
+        assert_eq!(project("missing", r#"{"text":"hi"}"#), "{}");
+        assert_eq!(project("text", "[1,2]"), "[1,2]");
+    }
+}
@@ -0,0 +1,131 @@
+use super::CollectionsResponse;
+use crate::storage::{ScanBudget, StoreReader};
+use crate::store_types::{HourTruncatedCursor, HourWindow};
+use crate::{Cursor, OrderCollectionsBy};
+use metrics::{counter, describe_counter, describe_histogram, histogram, Unit};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How many collections go in each top-collections file
+const TOP_COLLECTIONS: usize = 100;
+
+fn describe_metrics() {
+    describe_counter!(
+        "static_json_rounds",
+        Unit::Count,
+        "rounds of static json files written"
+    );
+    describe_counter!(
+        "static_json_failed_rounds",
+        Unit::Count,
+        "rounds of static json files that failed partway, retried on the next tick"
+    );
+    describe_histogram!(
+        "static_json_round_duration",
+        Unit::Seconds,
+        "time to query and write every static json file"
+    );
+}
+
+/// Periodically write the heaviest public responses to json files
+///
+/// The files have the same shape as the API responses they mirror, so a CDN
+/// or any static file server can take anonymous read traffic off the live
+/// store. Sync the directory to an object store to serve from there.
+///
+/// Files are replaced atomically, so readers never see a partial write:
+///
+/// - `summary.json`: like `/summary`
+/// - `collections/top-records-created.json`: like `/collections?order=records-created&limit=100`
+/// - `collections/top-dids-estimate.json`: like `/collections?order=dids-estimate&limit=100`
+/// - `collections/top-records-created-24h.json` and
+///   `collections/top-dids-estimate-24h.json`: the same, for the last day
+pub struct StaticJson {
+    dir: PathBuf,
+    interval: Duration,
+}
+
+impl StaticJson {
+    pub fn new(dir: PathBuf, interval: Duration) -> Self {
+        Self { dir, interval }
+    }
+
+    /// Write every file, then again after each interval
+    ///
+    /// A failed round is logged and retried on the next tick. Only ends if the
+    /// directory can't be created.
+    pub async fn run(self, storage: impl StoreReader) -> anyhow::Result<()> {
+        describe_metrics();
+        tokio::fs::create_dir_all(self.dir.join("collections")).await?;
+        let mut interval = tokio::time::interval(self.interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let t0 = Instant::now();
+            match self.write_all(&storage).await {
+                Ok(()) => {
+                    counter!("static_json_rounds").increment(1);
+                    histogram!("static_json_round_duration").record(t0.elapsed().as_secs_f64());
+                }
+                Err(e) => {
+                    counter!("static_json_failed_rounds").increment(1);
+                    log::warn!("failed to write static json: {e}");
+                }
+            }
+        }
+    }
+
+    async fn write_all(&self, storage: &impl StoreReader) -> anyhow::Result<()> {
+        let summary = storage.get_summary().await?;
+        self.write("summary.json", &summary).await?;
+
+        let day_ago: HourTruncatedCursor =
+            Cursor::at(SystemTime::now() - Duration::from_secs(86_400)).into();
+        for (name, window) in [
+            ("", HourWindow::all_time()),
+            ("-24h", HourWindow::starting(day_ago)),
+        ] {
+            for (order_name, order) in [
+                (
+                    "records-created",
+                    OrderCollectionsBy::RecordsCreated { cursor: None },
+                ),
+                (
+                    "dids-estimate",
+                    OrderCollectionsBy::DidsEstimate { cursor: None },
+                ),
+            ] {
+                // written in the background, not for a request: no scan limit
+                let (collections, _) = storage
+                    .get_collections(TOP_COLLECTIONS, order, window, ScanBudget::new(usize::MAX))
+                    .await?;
+                let response = CollectionsResponse {
+                    collections,
+                    // a static file is the whole list: there's nothing to page to
+                    cursor: None,
+                };
+                let path = format!("collections/top-{order_name}{name}.json");
+                self.write(&path, &response).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn write(&self, name: &str, value: &impl Serialize) -> anyhow::Result<()> {
+        let path = self.dir.join(name);
+        let tmp = tmp_path(&path);
+        tokio::fs::write(&tmp, serde_json::to_vec(value)?).await?;
+        tokio::fs::rename(&tmp, &path).await?;
+        Ok(())
+    }
+}
+
+/// A hidden sibling to write to before renaming over the real file
+fn tmp_path(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{name}.tmp"))
+}
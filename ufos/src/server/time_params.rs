@@ -0,0 +1,298 @@
+use crate::error::WindowError;
+use crate::storage::StoreReader;
+use crate::store_types::{HourTruncatedCursor, HourWindow};
+use crate::{ConsumerInfo, Cursor};
+use chrono::{DateTime, TimeDelta, Utc};
+use dropshot::HttpError;
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer};
+
+/// How far past now a timestamp can be, for clients with fast clocks
+const MAX_FUTURE_SKEW: TimeDelta = TimeDelta::hours(2);
+
+/// A `since` or `until` query param
+///
+/// Either an RFC3339 datetime (`2025-01-02T03:04:05Z`), or a time before now
+/// like `-90m`, `-24h`, or `-7d` (units are `s`, `m`, `h`, `d`, and `w`), or
+/// just `now`. Relative times are resolved when the request is parsed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QueryTime(DateTime<Utc>);
+
+impl QueryTime {
+    pub fn parse(s: &str, now: DateTime<Utc>) -> Result<Self, String> {
+        let s = s.trim();
+        if s == "now" {
+            return Ok(Self(now));
+        }
+        let Some(relative) = s.strip_prefix('-') else {
+            return s
+                .parse()
+                .map(Self)
+                .map_err(|e| format!("not an RFC3339 datetime or a relative time: {s:?} ({e})"));
+        };
+        let ago = parse_span(relative)?;
+        now.checked_sub_signed(ago)
+            .map(Self)
+            .ok_or_else(|| format!("relative time is too large: {s:?}"))
+    }
+
+    /// The exact cursor for this time, refusing times before the epoch or in the future
+    pub fn cursor(self) -> Result<Cursor, HttpError> {
+        to_cursor(self.0, Utc::now())
+    }
+
+    /// The cursor for the start of the hour containing this time
+    pub fn hour_cursor(self) -> Result<HourTruncatedCursor, HttpError> {
+        Ok(self.cursor()?.into())
+    }
+}
+
+/// The hours holding `since` and `until`, refusing windows that end before they start
+pub fn hour_window(
+    since: Option<QueryTime>,
+    until: Option<QueryTime>,
+) -> Result<HourWindow, HttpError> {
+    let since = since.map(QueryTime::cursor).transpose()?;
+    let until = until.map(QueryTime::cursor).transpose()?;
+    HourWindow::covering(since, until).map_err(|e| {
+        let msg = match e {
+            WindowError::Backwards { since, until } => format!(
+                "since ({}) is after until ({})",
+                cursor_to_dt(Cursor::from_raw_u64(since)),
+                cursor_to_dt(Cursor::from_raw_u64(until))
+            ),
+            e => e.to_string(),
+        };
+        HttpError::for_bad_request(None, msg)
+    })
+}
+
+impl<'de> Deserialize<'de> for QueryTime {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Self::parse(&s, Utc::now()).map_err(serde::de::Error::custom)
+    }
+}
+
+impl JsonSchema for QueryTime {
+    fn schema_name() -> String {
+        "QueryTime".to_string()
+    }
+    fn is_referenceable() -> bool {
+        false
+    }
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        String::json_schema(gen)
+    }
+}
+
+/// A length of time like `90m`, `24h`, or `7d`
+fn parse_span(s: &str) -> Result<TimeDelta, String> {
+    let unit_at = s
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| format!("time span is missing a unit (s, m, h, d, w): {s:?}"))?;
+    let (n, unit) = s.split_at(unit_at);
+    let n: i64 = n
+        .parse()
+        .map_err(|_| format!("time span needs a whole number: {s:?}"))?;
+    match unit {
+        "s" => TimeDelta::try_seconds(n),
+        "m" => TimeDelta::try_minutes(n),
+        "h" => TimeDelta::try_hours(n),
+        "d" => TimeDelta::try_days(n),
+        "w" => TimeDelta::try_weeks(n),
+        _ => return Err(format!("unknown time span unit {unit:?} (s, m, h, d, w)")),
+    }
+    .ok_or_else(|| format!("time span is too large: {s:?}"))
+}
+
+/// A `period` query param: the window up to the latest event, like `1h`, `24h`, or `7d`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QueryPeriod(TimeDelta);
+
+impl QueryPeriod {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let span = parse_span(s.trim())?;
+        if span.is_zero() {
+            return Err("period can't be zero".to_string());
+        }
+        Ok(Self(span))
+    }
+
+    /// How many whole hours it takes to cover the period
+    pub fn hours_ceil(&self) -> u64 {
+        let secs = self.0.num_seconds().max(1) as u64;
+        secs.div_ceil(3600)
+    }
+}
+
+impl<'de> Deserialize<'de> for QueryPeriod {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Self::parse(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+impl JsonSchema for QueryPeriod {
+    fn schema_name() -> String {
+        "QueryPeriod".to_string()
+    }
+    fn is_referenceable() -> bool {
+        false
+    }
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        String::json_schema(gen)
+    }
+}
+
+/// Turn a `period` into `since`, counting back from the latest event consumed
+///
+/// Only one of `period` or `since`/`until` can be used. When the consumer is
+/// behind, the window still covers `period` worth of events instead of ending
+/// early. `until` is left open, so the latest (partial) hour is included.
+pub async fn with_period(
+    storage: &dyn StoreReader,
+    since: Option<QueryTime>,
+    until: Option<QueryTime>,
+    period: Option<QueryPeriod>,
+) -> Result<(Option<QueryTime>, Option<QueryTime>), HttpError> {
+    let Some(QueryPeriod(span)) = period else {
+        return Ok((since, until));
+    };
+    if since.is_some() || until.is_some() {
+        return Err(HttpError::for_bad_request(
+            None,
+            "period can't be combined with since or until".to_string(),
+        ));
+    }
+    let ConsumerInfo::Jetstream { latest_cursor, .. } =
+        storage.get_consumer_info().await.map_err(|e| {
+            HttpError::for_internal_error(format!("failed to get latest cursor: {e:?}"))
+        })?;
+    let latest = latest_cursor
+        .map(|c| cursor_to_dt(Cursor::from_raw_u64(c)))
+        .unwrap_or_else(Utc::now);
+    let since = latest
+        .checked_sub_signed(span)
+        .ok_or_else(|| HttpError::for_bad_request(None, "period is too long".to_string()))?;
+    Ok((Some(QueryTime(since)), None))
+}
+
+fn to_cursor(dt: DateTime<Utc>, now: DateTime<Utc>) -> Result<Cursor, HttpError> {
+    let t = dt.timestamp_micros();
+    if t < 0 {
+        return Err(HttpError::for_bad_request(None, "timestamp too old".into()));
+    }
+    if dt - now > MAX_FUTURE_SKEW {
+        return Err(HttpError::for_bad_request(
+            None,
+            format!("future timestamp: {dt}"),
+        ));
+    }
+    Ok(Cursor::from_raw_u64(t as u64))
+}
+
+/// The time of a cursor, for responses
+pub fn cursor_to_dt(cursor: impl Into<Cursor>) -> DateTime<Utc> {
+    let cursor: Cursor = cursor.into();
+    DateTime::<Utc>::from_timestamp_micros(cursor.to_raw_u64() as i64).unwrap()
+}
+
+/// Reject windows that end before they start, or before UFOs started tracking
+///
+/// A `since` before takeoff is fine: counts just start from takeoff (see
+/// `tracked_since`), but a window that's over by then can't have anything in it.
+pub async fn check_window(
+    storage: &dyn StoreReader,
+    since: Option<Cursor>,
+    until: Option<Cursor>,
+) -> Result<(), HttpError> {
+    let Some(until) = until else {
+        return Ok(());
+    };
+    if let Some(since) = since {
+        if since > until {
+            return Err(HttpError::for_bad_request(
+                None,
+                format!(
+                    "since ({}) is after until ({})",
+                    cursor_to_dt(since),
+                    cursor_to_dt(until)
+                ),
+            ));
+        }
+    }
+    let ConsumerInfo::Jetstream { started_at, .. } = storage
+        .get_consumer_info()
+        .await
+        .map_err(|e| HttpError::for_internal_error(format!("failed to get takeoff: {e:?}")))?;
+    let takeoff = Cursor::from_raw_u64(started_at);
+    if until < takeoff {
+        return Err(HttpError::for_bad_request(
+            None,
+            format!(
+                "until ({}) is before tracking started ({})",
+                cursor_to_dt(until),
+                cursor_to_dt(takeoff)
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn now() -> DateTime<Utc> {
+        "2025-06-01T12:00:00Z".parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_query_time() {
+        let t = |s| QueryTime::parse(s, now()).map(|QueryTime(dt)| dt.to_rfc3339());
+        assert_eq!(t("now"), Ok("2025-06-01T12:00:00+00:00".into()));
+        assert_eq!(
+            t("2025-05-01T00:00:00Z"),
+            Ok("2025-05-01T00:00:00+00:00".into())
+        );
+        assert_eq!(
+            t("2025-05-01T02:00:00+02:00"),
+            Ok("2025-05-01T00:00:00+00:00".into())
+        );
+        assert_eq!(t("-90m"), Ok("2025-06-01T10:30:00+00:00".into()));
+        assert_eq!(t("-24h"), Ok("2025-05-31T12:00:00+00:00".into()));
+        assert_eq!(t("-7d"), Ok("2025-05-25T12:00:00+00:00".into()));
+        assert_eq!(t("-1w"), Ok("2025-05-25T12:00:00+00:00".into()));
+        assert!(t("-24").is_err());
+        assert!(t("-h").is_err());
+        assert!(t("-3y").is_err());
+        assert!(t("24h").is_err());
+        assert!(t("-99999999999999w").is_err());
+        assert!(t("yesterday").is_err());
+    }
+
+    #[test]
+    fn test_parse_query_period() {
+        let p = |s| QueryPeriod::parse(s).map(|QueryPeriod(span)| span.num_seconds());
+        assert_eq!(p("1h"), Ok(3_600));
+        assert_eq!(p("24h"), Ok(86_400));
+        assert_eq!(p("7d"), Ok(7 * 86_400));
+        assert!(p("0h").is_err());
+        assert!(p("-1h").is_err());
+        assert!(p("1").is_err());
+    }
+
+    #[test]
+    fn test_cursor_bounds() {
+        assert!(to_cursor(now(), now()).is_ok());
+        assert!(to_cursor(now() + TimeDelta::hours(1), now()).is_ok());
+        assert!(to_cursor(now() + TimeDelta::hours(3), now()).is_err());
+        let before_epoch = "1969-12-31T00:00:00Z".parse().unwrap();
+        assert!(to_cursor(before_epoch, now()).is_err());
+        let c = to_cursor(now(), now()).unwrap();
+        assert_eq!(cursor_to_dt(c), now());
+    }
+}
@@ -0,0 +1,168 @@
+//! Near-duplicate detection for sampled records
+//!
+//! Records get a 64-bit [simhash](https://en.wikipedia.org/wiki/SimHash) of
+//! their text content: records that say nearly the same thing have hashes
+//! that differ in only a few bits, so templated floods (the same post with a
+//! different link or mention) land in one cluster even when no two records
+//! are byte-identical.
+
+use serde_json::value::RawValue;
+use serde_json::Value;
+
+/// Words per shingle, hashed along with single words
+///
+/// Records are short, so this is kept small: longer shingles mean a one-word
+/// edit changes more of the features, and so more of the hash.
+const SHINGLE_WORDS: usize = 2;
+
+/// FNV-1a, so that hashes are stable across runs and builds
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |h, b| {
+        (h ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Every string in a record (not its keys), lowercased and split into words
+///
+/// `$type` is skipped: it's the same for every record in a collection.
+fn words(value: &Value, out: &mut Vec<String>) {
+    match value {
+        Value::String(s) => out.extend(
+            s.split(|c: char| !c.is_alphanumeric())
+                .filter(|w| !w.is_empty())
+                .map(str::to_lowercase),
+        ),
+        Value::Array(a) => a.iter().for_each(|v| words(v, out)),
+        Value::Object(o) => o
+            .iter()
+            .filter(|(k, _)| *k != "$type")
+            .for_each(|(_, v)| words(v, out)),
+        _ => {}
+    }
+}
+
+/// The simhash of a record's text content
+///
+/// Records with no text at all hash to 0.
+pub fn simhash(record: &RawValue) -> Result<u64, serde_json::Error> {
+    let value: Value = serde_json::from_str(record.get())?;
+    let mut all_words = vec![];
+    words(&value, &mut all_words);
+    if all_words.is_empty() {
+        return Ok(0);
+    }
+    let shingles = all_words
+        .windows(SHINGLE_WORDS.min(all_words.len()))
+        .map(|shingle| shingle.join(" "));
+    let mut weights = [0i32; 64];
+    for feature in all_words.iter().cloned().chain(shingles) {
+        let h = fnv1a(feature.as_bytes());
+        for (bit, weight) in weights.iter_mut().enumerate() {
+            if h & (1 << bit) != 0 {
+                *weight += 1;
+            } else {
+                *weight -= 1;
+            }
+        }
+    }
+    Ok(weights
+        .iter()
+        .enumerate()
+        .filter(|(_, w)| **w > 0)
+        .fold(0, |h, (bit, _)| h | (1 << bit)))
+}
+
+/// How many bits differ between two simhashes
+pub fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Group hashes that are within `max_distance` bits of each other
+///
+/// Clustering is transitive (single-linkage): a chain of small edits ends up
+/// in one cluster. Returns clusters of item indexes with at least `min_size`
+/// items, biggest first.
+pub fn cluster(hashes: &[u64], max_distance: u32, min_size: usize) -> Vec<Vec<usize>> {
+    // union-find over every close pair. fine for the few thousand records a
+    // collection keeps sampled.
+    let mut parent: Vec<usize> = (0..hashes.len()).collect();
+    fn root(parent: &mut [usize], mut i: usize) -> usize {
+        while parent[i] != i {
+            parent[i] = parent[parent[i]];
+            i = parent[i];
+        }
+        i
+    }
+    for i in 0..hashes.len() {
+        for j in (i + 1)..hashes.len() {
+            if distance(hashes[i], hashes[j]) <= max_distance {
+                let (ri, rj) = (root(&mut parent, i), root(&mut parent, j));
+                if ri != rj {
+                    parent[rj] = ri;
+                }
+            }
+        }
+    }
+    let mut groups: Vec<Vec<usize>> = vec![vec![]; hashes.len()];
+    for i in 0..hashes.len() {
+        let r = root(&mut parent, i);
+        groups[r].push(i);
+    }
+    let mut clusters: Vec<_> = groups
+        .into_iter()
+        .filter(|g| !g.is_empty() && g.len() >= min_size)
+        .collect();
+    clusters.sort_by(|a, b| b.len().cmp(&a.len()).then(a[0].cmp(&b[0])));
+    clusters
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(record: &str) -> u64 {
+        simhash(&RawValue::from_string(record.to_string()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_simhash() {
+        let spam = |link: &str| {
+            hash(&format!(
+                r#"{{"$type": "a.b.c", "text": "huge giveaway today only, claim your free tokens now before they run out at {link}"}}"#
+            ))
+        };
+        let (a, b) = (spam("scam.example"), spam("other-scam.example"));
+        assert!(distance(a, b) <= 8, "{}", distance(a, b));
+
+        let unrelated = hash(
+            r#"{"$type": "a.b.c", "text": "my cat knocked a glass of water off the table this morning"}"#,
+        );
+        assert!(distance(a, unrelated) > 16, "{}", distance(a, unrelated));
+
+        // keys, $type, and non-string values don't count
+        assert_eq!(
+            hash(r#"{"$type": "a.b.c", "text": "Hello there, world"}"#),
+            hash(r#"{"$type": "x.y.z", "other": "hello THERE world", "n": 1}"#),
+        );
+        assert_eq!(hash(r#"{"$type": "a.b.c", "likes": 4}"#), 0);
+    }
+
+    #[test]
+    fn test_cluster() {
+        let hashes = [
+            0b0000_0000,
+            0b1111_0000,
+            0b0000_0001,
+            0b1111_0001,
+            0b0000_0011,
+            u64::MAX,
+        ];
+        assert_eq!(
+            cluster(&hashes, 1, 2),
+            vec![vec![0, 2, 4], vec![1, 3]],
+            "chained edits cluster together, singletons are dropped"
+        );
+        assert_eq!(cluster(&hashes, 0, 2), Vec::<Vec<usize>>::new());
+        assert_eq!(cluster(&hashes, 64, 1), vec![vec![0, 1, 2, 3, 4, 5]]);
+    }
+}
@@ -0,0 +1,566 @@
+//! Disk-backed queue between the consumer and the writer
+//!
+//! Every batch from the consumer is written to a queue partition before it's
+//! handed to the writer, and the writer drops it from the queue in the same
+//! db batch that inserts it. So batches that were consumed but never inserted
+//! survive a crash or restart: they're replayed at startup, and the consumer
+//! reconnects after the last of them.
+//!
+//! The queue also absorbs firehose spikes. When the writer's channel is full,
+//! new batches wait on disk instead of holding up the consumer (which would
+//! eventually force it to cut batches short), and are fed to the writer in
+//! order once it catches up.
+
+use crate::db_types::{bincode_conf, EncodingResult};
+use crate::error::StorageError;
+use crate::heavy_hitters::{HeavyHitters, BATCH_TOP_DIDS};
+use crate::storage::StorageResult;
+use crate::{
+    CollectionCommits, CommitAction, Cursor, CursorGap, DeleteAccount, Did, EncodingError,
+    EventBatch, EventKindCounts, Nsid, PutAction, RecordKey, UFOsCommit,
+};
+use metrics::{counter, describe_counter, describe_gauge, gauge, Unit};
+use microcosm_estimates::DidsSketch;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::value::RawValue;
+use std::collections::VecDeque;
+use tokio::sync::mpsc::{channel, error::TrySendError, Receiver, Sender};
+
+/// Default limit for batches waiting on disk before the consumer is held up
+pub const MAX_SPILLED_BATCHES: usize = 1024;
+
+/// A FIFO queue of encoded batches, kept until the writer inserts them
+pub trait SpillQueue: Clone + Send + Sync + 'static {
+    /// Add an encoded batch to the back of the queue, returning its position
+    fn push(&self, latest: Option<Cursor>, batch: Vec<u8>) -> StorageResult<u64>;
+    /// Get a queued batch, if it hasn't been inserted yet
+    fn get(&self, position: u64) -> StorageResult<Option<Vec<u8>>>;
+    /// Positions of every batch still in the queue, oldest first
+    fn queued(&self) -> StorageResult<Vec<u64>>;
+    /// The latest cursor of any batch still in the queue
+    fn latest_cursor(&self) -> StorageResult<Option<Cursor>>;
+}
+
+/// Where the consumer should reconnect: after any batches waiting to be replayed
+pub fn resume_cursor(
+    spill: &impl SpillQueue,
+    stored: Option<Cursor>,
+) -> StorageResult<Option<Cursor>> {
+    match (stored, spill.latest_cursor()?) {
+        (Some(stored), Some(queued)) if queued > stored => {
+            log::info!("resuming the consumer after queued batches, at {queued:?}");
+            Ok(Some(queued))
+        }
+        (None, Some(queued)) => Ok(Some(queued)),
+        (stored, _) => Ok(stored),
+    }
+}
+
+/// Queue batches on the way to the writer, replaying any left from before
+///
+/// With `max_spilled` at zero, nothing new is queued: batches left from before
+/// are still replayed first, so the writer doesn't drop them as covered by a
+/// later insert, then the rest are passed straight through.
+pub fn buffer<const LIMIT: usize>(
+    batches: Receiver<EventBatch<LIMIT>>,
+    spill: impl SpillQueue,
+    max_spilled: usize,
+    queue_size: usize,
+) -> Receiver<EventBatch<LIMIT>> {
+    describe_counter!(
+        "spill_batches_spilled",
+        Unit::Count,
+        "batches that waited on disk because the writer's queue was full"
+    );
+    describe_gauge!(
+        "spill_queue_batches",
+        Unit::Count,
+        "batches waiting on disk to be sent to the writer"
+    );
+    let (sender, receiver) = channel(queue_size);
+    tokio::task::spawn(async move {
+        let r = run_buffer(batches, sender, spill, max_spilled).await;
+        log::warn!("spill buffer ended: {r:?}");
+    });
+    receiver
+}
+
+async fn run_buffer<const LIMIT: usize>(
+    mut batches: Receiver<EventBatch<LIMIT>>,
+    sender: Sender<EventBatch<LIMIT>>,
+    spill: impl SpillQueue,
+    max_spilled: usize,
+) -> StorageResult<()> {
+    // positions on disk that still need to be sent, starting with leftovers
+    let mut pending: VecDeque<u64> = {
+        let spill = spill.clone();
+        tokio::task::spawn_blocking(move || spill.queued()).await??
+    }
+    .into();
+    if !pending.is_empty() {
+        log::info!("replaying {} queued batches", pending.len());
+    }
+    let mut upstream_open = true;
+    loop {
+        gauge!("spill_queue_batches").set(pending.len() as f64);
+        if pending.is_empty() {
+            if !upstream_open {
+                return Ok(());
+            }
+            let Some(batch) = batches.recv().await else {
+                return Ok(());
+            };
+            if max_spilled == 0 {
+                sender
+                    .send(batch)
+                    .await
+                    .map_err(|_| StorageError::BatchSenderExited)?;
+                continue;
+            }
+            let position = push(&spill, &batch).await?;
+            match sender.try_send(batch) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    counter!("spill_batches_spilled").increment(1);
+                    pending.push_back(position);
+                }
+                Err(TrySendError::Closed(_)) => return Err(StorageError::BatchSenderExited),
+            }
+            continue;
+        }
+
+        // queued batches go out first to keep the order. new ones keep
+        // queueing behind them until the limit, then the consumer waits.
+        tokio::select! {
+            permit = sender.reserve() => {
+                let permit = permit.map_err(|_| StorageError::BatchSenderExited)?;
+                let position = pending.pop_front().expect("pending is not empty");
+                // batches can be missing if a later insert already covered them
+                if let Some(batch) = get(&spill, position).await? {
+                    permit.send(batch);
+                }
+            }
+            batch = batches.recv(), if upstream_open && pending.len() < max_spilled => {
+                match batch {
+                    Some(batch) => {
+                        let position = push(&spill, &batch).await?;
+                        counter!("spill_batches_spilled").increment(1);
+                        pending.push_back(position);
+                    }
+                    None => upstream_open = false,
+                }
+            }
+        }
+    }
+}
+
+async fn push<const LIMIT: usize>(
+    spill: &impl SpillQueue,
+    batch: &EventBatch<LIMIT>,
+) -> StorageResult<u64> {
+    let latest = batch.latest_cursor();
+    let bytes = encode(batch)?;
+    let spill = spill.clone();
+    tokio::task::spawn_blocking(move || spill.push(latest, bytes)).await?
+}
+
+async fn get<const LIMIT: usize>(
+    spill: &impl SpillQueue,
+    position: u64,
+) -> StorageResult<Option<EventBatch<LIMIT>>> {
+    let spill = spill.clone();
+    let Some(bytes) = tokio::task::spawn_blocking(move || spill.get(position)).await?? else {
+        return Ok(None);
+    };
+    Ok(Some(decode(&bytes)?))
+}
+
+#[derive(Serialize, Deserialize)]
+struct SpilledCommit {
+    cursor: u64,
+    did: String,
+    rkey: String,
+    rev: String,
+    /// `None` for deletes
+    put: Option<(String, bool)>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SpilledCollection {
+    nsid: String,
+    creates: usize,
+    updates: usize,
+    deletes: usize,
+    dids_estimate: DidsSketch,
+    commits: Vec<SpilledCommit>,
+    head: usize,
+}
+
+/// The fields every encoded batch has
+///
+/// Fields added later are encoded after these, one at a time, so batches
+/// spilled or published by an older version just end early. Add new ones
+/// at the end, never in here.
+#[derive(Serialize, Deserialize)]
+struct SpilledBatch {
+    collections: Vec<SpilledCollection>,
+    account_removes: Vec<(String, u64)>,
+    overflowed_collections: usize,
+}
+
+/// Decode the next field added after [`SpilledBatch`], or `None` if the batch ended before it
+fn trailing<T: DeserializeOwned>(bytes: &mut &[u8]) -> EncodingResult<Option<T>> {
+    if bytes.is_empty() {
+        return Ok(None);
+    }
+    let (field, read) = bincode::serde::decode_from_slice(bytes, bincode_conf())?;
+    *bytes = &bytes[read..];
+    Ok(Some(field))
+}
+
+/// Encode a batch for storage (also used for the change feed that replicas chase)
+pub fn encode<const LIMIT: usize>(batch: &EventBatch<LIMIT>) -> EncodingResult<Vec<u8>> {
+    let by_nsid: Vec<_> = batch.commits_by_nsid.iter().collect();
+    let spilled = SpilledBatch {
+        collections: by_nsid
+            .iter()
+            .map(|(nsid, commits)| SpilledCollection {
+                nsid: nsid.to_string(),
+                creates: commits.creates,
+                updates: commits.updates,
+                deletes: commits.deletes,
+                dids_estimate: commits.dids_estimate.clone(),
+                commits: commits
+                    .commits
+                    .iter()
+                    .map(|c| SpilledCommit {
+                        cursor: c.cursor.to_raw_u64(),
+                        did: c.did.to_string(),
+                        rkey: c.rkey.to_string(),
+                        rev: c.rev.clone(),
+                        put: match &c.action {
+                            CommitAction::Put(put) => {
+                                Some((put.record.get().to_string(), put.is_update))
+                            }
+                            CommitAction::Cut => None,
+                        },
+                    })
+                    .collect(),
+                head: commits.head,
+            })
+            .collect(),
+        account_removes: batch
+            .account_removes
+            .iter()
+            .map(|r| (r.did.to_string(), r.cursor.to_raw_u64()))
+            .collect(),
+        overflowed_collections: batch.overflowed_collections,
+    };
+    // same order as `collections`
+    let top_dids: Vec<_> = by_nsid.iter().map(|(_, c)| &c.top_dids).collect();
+    Ok(bincode::serde::encode_to_vec(
+        (spilled, batch.event_kinds, top_dids, batch.cursor_gap),
+        bincode_conf(),
+    )?)
+}
+
+/// Decode a batch from [`encode`]
+pub fn decode<const LIMIT: usize>(bytes: &[u8]) -> EncodingResult<EventBatch<LIMIT>> {
+    let (spilled, read): (SpilledBatch, _) =
+        bincode::serde::decode_from_slice(bytes, bincode_conf())?;
+    let mut rest = &bytes[read..];
+    let event_kinds: Option<EventKindCounts> = trailing(&mut rest)?;
+    let mut top_dids =
+        trailing::<Vec<HeavyHitters<Did, BATCH_TOP_DIDS>>>(&mut rest)?.map(Vec::into_iter);
+    let cursor_gap: Option<CursorGap> = trailing(&mut rest)?.flatten();
+
+    let did = |s: String| Did::new(s).map_err(EncodingError::BadAtriumStringType);
+    let mut batch = EventBatch::<LIMIT> {
+        overflowed_collections: spilled.overflowed_collections,
+        event_kinds: event_kinds.unwrap_or_default(),
+        cursor_gap,
+        ..Default::default()
+    };
+    if event_kinds.is_none() {
+        // spilled by a version without event kinds: count what the batch still has
+        batch.event_kinds.accounts = spilled.account_removes.len() as u64;
+        batch.event_kinds.commits = spilled
+            .collections
+            .iter()
+            .map(|c| (c.creates + c.updates + c.deletes) as u64)
+            .sum();
+    }
+    for c in spilled.collections {
+        let nsid = Nsid::new(c.nsid).map_err(EncodingError::BadAtriumStringType)?;
+        let mut commits = Vec::with_capacity(c.commits.len());
+        for commit in c.commits {
+            let action = match commit.put {
+                Some((record, is_update)) => CommitAction::Put(PutAction {
+                    record: RawValue::from_string(record)?,
+                    is_update,
+                }),
+                None => CommitAction::Cut,
+            };
+            commits.push(UFOsCommit {
+                cursor: Cursor::from_raw_u64(commit.cursor),
+                did: did(commit.did)?,
+                rkey: RecordKey::new(commit.rkey).map_err(EncodingError::BadAtriumStringType)?,
+                rev: commit.rev,
+                action,
+            });
+        }
+        let top_dids = match top_dids.as_mut().and_then(Iterator::next) {
+            Some(top_dids) => top_dids,
+            None => {
+                // spilled by a version without per-DID counts: the kept commits are the best guess
+                let mut top_dids = HeavyHitters::default();
+                for commit in &commits {
+                    top_dids.add(commit.did.clone(), 1);
+                }
+                top_dids
+            }
+        };
+        let collection = CollectionCommits {
+            creates: c.creates,
+            updates: c.updates,
+            deletes: c.deletes,
+            dids_estimate: c.dids_estimate,
+            top_dids,
+            commits,
+            head: c.head,
+        };
+        batch.commits_by_nsid.insert(nsid, collection);
+    }
+    for (d, cursor) in spilled.account_removes {
+        batch.account_removes.push(DeleteAccount {
+            did: did(d)?,
+            cursor: Cursor::from_raw_u64(cursor),
+        });
+    }
+    Ok(batch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, Clone, Default)]
+    struct MemSpill(Arc<Mutex<BTreeMap<u64, (Option<Cursor>, Vec<u8>)>>>);
+    impl MemSpill {
+        fn inserted_through(&self, cursor: Cursor) {
+            self.0
+                .lock()
+                .unwrap()
+                .retain(|_, (latest, _)| latest.is_some_and(|c| c > cursor));
+        }
+    }
+    impl SpillQueue for MemSpill {
+        fn push(&self, latest: Option<Cursor>, batch: Vec<u8>) -> StorageResult<u64> {
+            let mut queue = self.0.lock().unwrap();
+            let position = queue.last_key_value().map(|(p, _)| p + 1).unwrap_or(0);
+            queue.insert(position, (latest, batch));
+            Ok(position)
+        }
+        fn get(&self, position: u64) -> StorageResult<Option<Vec<u8>>> {
+            Ok(self
+                .0
+                .lock()
+                .unwrap()
+                .get(&position)
+                .map(|(_, b)| b.clone()))
+        }
+        fn queued(&self) -> StorageResult<Vec<u64>> {
+            Ok(self.0.lock().unwrap().keys().copied().collect())
+        }
+        fn latest_cursor(&self) -> StorageResult<Option<Cursor>> {
+            Ok(self
+                .0
+                .lock()
+                .unwrap()
+                .values()
+                .filter_map(|(c, _)| *c)
+                .last())
+        }
+    }
+
+    fn batch(cursor: u64) -> EventBatch<4> {
+        let mut batch = EventBatch::<4>::default();
+        let commit = UFOsCommit {
+            cursor: Cursor::from_raw_u64(cursor),
+            did: Did::new("did:plc:inze6wrmsm7pjl7yta3oig77".to_string()).unwrap(),
+            rkey: RecordKey::new("asdf".to_string()).unwrap(),
+            rev: "rev".to_string(),
+            action: CommitAction::Put(PutAction {
+                record: RawValue::from_string(r#"{"a": 1}"#.to_string()).unwrap(),
+                is_update: true,
+            }),
+        };
+        batch
+            .insert_commit_by_nsid(
+                &Nsid::new("a.b.c".to_string()).unwrap(),
+                commit,
+                8,
+                &[0u8; 16],
+            )
+            .unwrap();
+        batch.account_removes.push(DeleteAccount {
+            did: Did::new("did:plc:asdf".to_string()).unwrap(),
+            cursor: Cursor::from_raw_u64(cursor + 1),
+        });
+        batch
+    }
+
+    #[test]
+    fn test_spill_roundtrip() -> EncodingResult<()> {
+        let original = batch(100);
+        let restored: EventBatch<4> = decode(&encode(&original)?)?;
+        assert_eq!(restored.latest_cursor(), Some(Cursor::from_raw_u64(101)));
+        assert_eq!(restored.estimate_dids(), original.estimate_dids());
+        let commits = &restored.commits_by_nsid[&Nsid::new("a.b.c".to_string()).unwrap()];
+        assert_eq!(commits.updates, 1);
+        let CommitAction::Put(put) = &commits.commits[0].action else {
+            panic!("expected a put");
+        };
+        assert_eq!(put.record.get(), r#"{"a": 1}"#);
+        assert!(put.is_update);
+        Ok(())
+    }
+
+    #[test]
+    fn test_spill_keeps_top_dids() -> EncodingResult<()> {
+        let mut original = batch(100);
+        let nsid = Nsid::new("a.b.c".to_string()).unwrap();
+        let busy = Did::new("did:plc:busy".to_string()).unwrap();
+        // counted, but displaced from the batch
+        original
+            .commits_by_nsid
+            .get_mut(&nsid)
+            .unwrap()
+            .top_dids
+            .add(busy.clone(), 50);
+        let restored: EventBatch<4> = decode(&encode(&original)?)?;
+        let top = restored.commits_by_nsid[&nsid].top_dids.top(1);
+        assert_eq!(top[0].0, busy);
+        assert_eq!(top[0].1, 50);
+        Ok(())
+    }
+
+    #[test]
+    fn test_spill_keeps_cursor_gap() -> EncodingResult<()> {
+        let mut original = batch(100);
+        let restored: EventBatch<4> = decode(&encode(&original)?)?;
+        assert_eq!(restored.cursor_gap, None);
+
+        let gap = CursorGap {
+            last_seen: 10,
+            resumed_at: 90,
+        };
+        original.cursor_gap = Some(gap);
+        let restored: EventBatch<4> = decode(&encode(&original)?)?;
+        assert_eq!(restored.cursor_gap, Some(gap));
+        Ok(())
+    }
+
+    #[test]
+    fn test_spill_keeps_event_kinds() -> EncodingResult<()> {
+        let mut original = batch(100);
+        original.event_kinds = EventKindCounts {
+            commits: 7,
+            identities: 3,
+            accounts: 2,
+        };
+        let encoded = encode(&original)?;
+        let restored: EventBatch<4> = decode(&encoded)?;
+        assert_eq!(restored.event_kinds, original.event_kinds);
+
+        // spilled by a version without event kinds: estimated from what's left
+        let (_, original_len) =
+            bincode::serde::decode_from_slice::<SpilledBatch, _>(&encoded, bincode_conf())?;
+        let restored: EventBatch<4> = decode(&encoded[..original_len])?;
+        assert_eq!(
+            restored.event_kinds,
+            EventKindCounts {
+                commits: 1,
+                identities: 0,
+                accounts: 1,
+            }
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_buffer_spills_in_order() {
+        let (sender, receiver) = channel(1);
+        let spill = MemSpill::default();
+        let mut buffered = buffer(receiver, spill.clone(), 16, 1);
+
+        // nothing is reading from the buffer yet, so all but one wait on disk
+        for i in 0..5 {
+            sender.send(batch(i * 10)).await.unwrap();
+        }
+        drop(sender);
+        for i in 0..5 {
+            let batch = buffered.recv().await.unwrap();
+            let latest = batch.latest_cursor().unwrap();
+            assert_eq!(latest, Cursor::from_raw_u64(i * 10 + 1));
+            spill.inserted_through(latest);
+        }
+        assert!(buffered.recv().await.is_none());
+        assert!(spill.0.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_buffer_replays_queued() -> StorageResult<()> {
+        // left over from a previous run: consumed, never inserted
+        let spill = MemSpill::default();
+        for i in 0..3 {
+            let leftover = batch(i * 10);
+            spill.push(leftover.latest_cursor(), encode(&leftover)?)?;
+        }
+        let resume = resume_cursor(&spill, Some(Cursor::from_raw_u64(5)))?;
+        assert_eq!(resume, Some(Cursor::from_raw_u64(21)));
+
+        let (sender, receiver) = channel(4);
+        let mut buffered = buffer(receiver, spill.clone(), 16, 4);
+        sender.send(batch(30)).await.unwrap();
+        drop(sender);
+        for i in 0..4 {
+            let batch = buffered.recv().await.unwrap();
+            assert_eq!(
+                batch.latest_cursor(),
+                Some(Cursor::from_raw_u64(i * 10 + 1))
+            );
+        }
+        assert!(buffered.recv().await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_buffer_replays_queued_without_spilling() -> StorageResult<()> {
+        let spill = MemSpill::default();
+        for i in 0..2 {
+            let leftover = batch(i * 10);
+            spill.push(leftover.latest_cursor(), encode(&leftover)?)?;
+        }
+
+        let (sender, receiver) = channel(4);
+        let mut buffered = buffer(receiver, spill.clone(), 0, 1);
+        for i in 2..5 {
+            sender.send(batch(i * 10)).await.unwrap();
+        }
+        drop(sender);
+        for i in 0..5 {
+            let batch = buffered.recv().await.unwrap();
+            assert_eq!(
+                batch.latest_cursor(),
+                Some(Cursor::from_raw_u64(i * 10 + 1))
+            );
+        }
+        assert!(buffered.recv().await.is_none());
+        // only the leftovers were ever on disk
+        assert_eq!(spill.queued()?, vec![0, 1]);
+        Ok(())
+    }
+}
@@ -1,18 +1,106 @@
-use crate::store_types::{CountsValue, HourTruncatedCursor, SketchSecretPrefix};
+use crate::denylist::DenyRule;
+use crate::heavy_hitters::NoisyDidsReport;
+use crate::spill::SpillQueue;
+use crate::store_types::{CountsValue, HourTruncatedCursor, HourWindow, SketchSecretPrefix};
 use crate::{
-    error::StorageError, ConsumerInfo, Cursor, EventBatch, JustCount, NsidCount, NsidPrefix,
-    OrderCollectionsBy, PrefixChild, UFOsRecord,
+    error::{QueryError, StorageError},
+    AccountDeletesStatus, AuditEntry, BackgroundIntervals, BackgroundStatus, BackgroundTask,
+    ConsumerInfo, Cursor, EventBatch, EventKindCounts, JetstreamShard, JustCount, KeySpaceReport,
+    NsidCount, NsidPrefix, OrderCollectionsBy, PrefixChild, PurgeReport, RebuildFeedsReport,
+    UFOsRecord, UniqueRecords, WatchlistHit, WindowCounts,
 };
 use async_trait::async_trait;
-use jetstream::exports::{Did, Nsid};
-use metrics::{describe_histogram, histogram, Unit};
+use jetstream::exports::{Did, Nsid, RecordKey};
+use metrics::{counter, describe_counter, describe_histogram, histogram, Unit};
 use std::collections::{HashMap, HashSet};
+use std::ops::Bound;
 use std::path::Path;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::sync::mpsc::Receiver;
 use tokio_util::sync::CancellationToken;
 
 pub type StorageResult<T> = Result<T, StorageError>;
+pub type QueryResult<T> = Result<T, QueryError>;
+
+/// A cap on how many keys one request can have storage touch
+///
+/// Scans that use it up stop early with what they have so far, and a cursor
+/// to pick up from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScanBudget {
+    left: usize,
+    ran_out: bool,
+}
+impl ScanBudget {
+    pub const fn new(keys: usize) -> Self {
+        Self {
+            left: keys,
+            ran_out: false,
+        }
+    }
+    /// Use up one key, if there's any left
+    pub fn spend(&mut self) -> bool {
+        self.reserve(1)
+    }
+    /// Use up `keys` at once, if there are that many left
+    pub fn reserve(&mut self, keys: usize) -> bool {
+        if self.left < keys {
+            self.ran_out = true;
+            return false;
+        }
+        self.left -= keys;
+        true
+    }
+    /// Whether a scan wanted more keys than were left
+    pub fn ran_out(&self) -> bool {
+        self.ran_out
+    }
+}
+
+/// A slice of the stored records to estimate the size of
+#[derive(Debug, Clone, PartialEq)]
+pub enum CountPrefix {
+    /// A collection's feed: the records `/records` lists
+    Collection(Nsid),
+    /// Every record stored for an account, older versions included
+    Account(Did),
+    /// An account's records in one collection, older versions included
+    AccountCollection(Did, Nsid),
+}
+
+/// How many probes a sample may take per record asked for, before settling for fewer
+const SAMPLE_PROBES_PER_RECORD: usize = 4;
+
+/// Draw up to `n` distinct records by probing random cursors in `oldest..=newest`
+///
+/// `probe` finds the first live record at or after a cursor. Probes that land
+/// on an already-drawn record are wasted, so a collection with few records can
+/// come back short. Newest first, and the same for the same seed and data.
+pub(crate) fn sample_by_probes(
+    (oldest, newest): (Cursor, Cursor),
+    n: usize,
+    seed: u64,
+    mut probe: impl FnMut(Cursor) -> StorageResult<Option<UFOsRecord>>,
+) -> StorageResult<Vec<UFOsRecord>> {
+    let (oldest, newest) = (oldest.to_raw_u64(), newest.to_raw_u64());
+    let span = newest.saturating_sub(oldest) + 1;
+    let mut drawn = HashSet::new();
+    let mut sample = Vec::with_capacity(n);
+    for i in 0..(n * SAMPLE_PROBES_PER_RECORD) as u64 {
+        if sample.len() >= n {
+            break;
+        }
+        let at = oldest + crate::splitmix64(seed, i) % span;
+        let Some(record) = probe(Cursor::from_raw_u64(at))? else {
+            continue;
+        };
+        if drawn.insert(record.cursor.to_raw_u64()) {
+            sample.push(record);
+        }
+    }
+    sample.sort_by_key(|r| std::cmp::Reverse(r.cursor.to_raw_u64()));
+    Ok(sample)
+}
 
 pub trait StorageWhatever<R: StoreReader, W: StoreWriter<B>, B: StoreBackground, C> {
     fn init(
@@ -30,8 +118,16 @@ pub trait StoreWriter<B: StoreBackground>: Clone + Send + Sync
 where
     Self: 'static,
 {
+    type Spill: SpillQueue;
+
+    /// A temporary on-disk queue for batches arriving faster than they're inserted
+    fn spill_queue(&self) -> StorageResult<Self::Spill>;
+
     fn background_tasks(&mut self, reroll: bool) -> StorageResult<B>;
 
+    /// Where a sharded jetstream connection left off, if it ever inserted anything
+    fn jetstream_shard_cursor(&self, shard: &JetstreamShard) -> StorageResult<Option<Cursor>>;
+
     async fn receive_batches<const LIMIT: usize>(
         self,
         mut batches: Receiver<EventBatch<LIMIT>>,
@@ -46,6 +142,11 @@ where
             Unit::Microseconds,
             "total time to insert one commit batch"
         );
+        describe_counter!(
+            "storage_batches_inserted",
+            Unit::Count,
+            "event batches written to storage"
+        );
         while let Some(event_batch) = batches.recv().await {
             let token = CancellationToken::new();
             let cancelled = token.clone();
@@ -81,6 +182,7 @@ where
                 }
             })
             .await??;
+            counter!("storage_batches_inserted").increment(1);
         }
 
         Err(StorageError::BatchSenderExited)
@@ -103,6 +205,79 @@ where
     fn delete_account(&mut self, did: &Did) -> StorageResult<usize>;
 }
 
+/// Operator maintenance operations for the admin API
+///
+/// Unlike `StoreWriter`, this is object-safe so the admin server can hold it
+#[async_trait]
+pub trait StoreAdmin: Send + Sync {
+    /// Remove everything stored for a collection, or with `dry_run` only count it
+    async fn purge_collection(
+        &self,
+        collection: &Nsid,
+        dry_run: bool,
+    ) -> StorageResult<PurgeReport>;
+
+    /// Estimate the bytes each collection takes up, biggest first
+    ///
+    /// Feeds are scanned in full, but only one in `sample_every` records is read.
+    async fn estimate_key_space(
+        &self,
+        sample_every: u64,
+        limit: usize,
+    ) -> StorageResult<KeySpaceReport>;
+
+    /// Replace the feeds with entries rebuilt from the stored records
+    ///
+    /// Records are authoritative, so this recovers from corrupt or stale feeds.
+    /// Progress is saved as it goes: calling this again after an interruption
+    /// resumes where it left off. Nothing else should be writing meanwhile.
+    async fn rebuild_feeds(&self) -> StorageResult<RebuildFeedsReport>;
+
+    /// Where the background loop is in its schedule
+    async fn background_status(&self) -> StorageResult<BackgroundStatus>;
+
+    /// Run a background task right away instead of waiting for its next tick
+    ///
+    /// Returns false if the background loop isn't running.
+    async fn trigger_background(&self, task: BackgroundTask) -> StorageResult<bool>;
+
+    /// Change how often the background tasks run, starting with their next tick
+    async fn set_background_intervals(&self, intervals: BackgroundIntervals) -> StorageResult<()>;
+
+    /// Queued account deletes, and any that were interrupted partway
+    async fn account_deletes_status(&self) -> StorageResult<AccountDeletesStatus>;
+
+    async fn get_denylist(&self) -> StorageResult<Vec<DenyRule>>;
+
+    /// Stop storing records for matching collections (takes effect for the next batch)
+    async fn deny_collections(&self, rule: DenyRule) -> StorageResult<()>;
+
+    /// Remove a deny rule. Returns false if there was no rule for the pattern.
+    async fn allow_collections(&self, pattern: &str) -> StorageResult<bool>;
+
+    /// Append to the audit log. Entries are never modified or removed.
+    ///
+    /// `at` is bumped if needed to stay after the latest entry.
+    async fn record_audit(&self, entry: AuditEntry) -> StorageResult<()>;
+
+    /// Audit log entries before a position (`at`), newest first
+    async fn get_audit_log(
+        &self,
+        before: Option<u64>,
+        limit: usize,
+    ) -> StorageResult<Vec<AuditEntry>>;
+
+    /// Accounts writing an outsized share of a collection's records in the
+    /// latest `hours` hours (counted in memory since startup)
+    async fn noisy_dids(
+        &self,
+        hours: usize,
+        min_records: u64,
+        min_share: f64,
+        limit: usize,
+    ) -> StorageResult<NoisyDidsReport>;
+}
+
 #[async_trait]
 pub trait StoreBackground: Send + Sync {
     async fn run(mut self, backfill: bool) -> StorageResult<()>;
@@ -114,26 +289,55 @@ pub trait StoreReader: Send + Sync {
 
     fn update_metrics(&self) {}
 
-    async fn get_storage_stats(&self) -> StorageResult<serde_json::Value>;
+    async fn get_storage_stats(&self) -> QueryResult<serde_json::Value>;
+
+    async fn get_consumer_info(&self) -> QueryResult<ConsumerInfo>;
+
+    /// Published batches after a cursor, oldest first, for replicas chasing this instance
+    ///
+    /// Each batch comes with its latest cursor. Also returns the latest cursor
+    /// trimmed from the change feed: a replica that's behind it has missed batches.
+    /// Empty unless the writer publishes a change feed.
+    async fn get_changes(
+        &self,
+        after: Option<Cursor>,
+        limit: usize,
+    ) -> QueryResult<(Vec<(Cursor, Vec<u8>)>, Option<Cursor>)>;
+
+    /// Pin the current state of the store for a while
+    ///
+    /// Returns a token and its expiry time. Use the token with `at_snapshot`
+    /// to make several reads against the same instant.
+    async fn pin_snapshot(&self, ttl: Duration) -> QueryResult<(u64, SystemTime)>;
 
-    async fn get_consumer_info(&self) -> StorageResult<ConsumerInfo>;
+    /// Get a reader that only sees data from a previously-pinned snapshot
+    fn at_snapshot(&self, token: u64) -> QueryResult<Box<dyn StoreReader>>;
 
+    /// Collections with their counts over a window
+    ///
+    /// In lexi order, running out of `budget` makes for a short page, with a
+    /// cursor to carry on from. Rankings can't be cut short, so they fail with
+    /// [`QueryError::OverScanBudget`](crate::error::QueryError::OverScanBudget)
+    /// instead.
     async fn get_collections(
         &self,
         limit: usize,
         order: OrderCollectionsBy,
-        since: Option<HourTruncatedCursor>,
-        until: Option<HourTruncatedCursor>,
-    ) -> StorageResult<(Vec<NsidCount>, Option<Vec<u8>>)>;
+        window: HourWindow,
+        budget: ScanBudget,
+    ) -> QueryResult<(Vec<NsidCount>, Option<Vec<u8>>)>;
 
+    /// A prefix's total and its children's counts over a window
+    ///
+    /// Spends `budget` like [`StoreReader::get_collections`].
     async fn get_prefix(
         &self,
         prefix: NsidPrefix,
         limit: usize,
         order: OrderCollectionsBy,
-        since: Option<HourTruncatedCursor>,
-        until: Option<HourTruncatedCursor>,
-    ) -> StorageResult<(JustCount, Vec<PrefixChild>, Option<Vec<u8>>)>;
+        window: HourWindow,
+        budget: ScanBudget,
+    ) -> QueryResult<(JustCount, Vec<PrefixChild>, Option<Vec<u8>>)>;
 
     async fn get_timeseries(
         &self,
@@ -141,21 +345,168 @@ pub trait StoreReader: Send + Sync {
         since: HourTruncatedCursor,
         until: Option<HourTruncatedCursor>,
         step: u64,
-    ) -> StorageResult<(Vec<HourTruncatedCursor>, HashMap<Nsid, Vec<CountsValue>>)>;
+    ) -> QueryResult<(Vec<HourTruncatedCursor>, HashMap<Nsid, Vec<CountsValue>>)>;
+
+    /// How many of each kind of jetstream event were consumed, per step
+    async fn get_event_kinds(
+        &self,
+        since: HourTruncatedCursor,
+        until: Option<HourTruncatedCursor>,
+        step: u64,
+    ) -> QueryResult<(Vec<HourTruncatedCursor>, Vec<EventKindCounts>)>;
 
     async fn get_collection_counts(
         &self,
         collection: &Nsid,
         since: HourTruncatedCursor,
         until: Option<HourTruncatedCursor>,
-    ) -> StorageResult<JustCount>;
+    ) -> QueryResult<JustCount>;
+
+    /// Counts for a collection over a window that isn't aligned to hours
+    async fn get_collection_window_counts(
+        &self,
+        collection: &Nsid,
+        since: Cursor,
+        until: Option<Cursor>,
+    ) -> QueryResult<WindowCounts>;
+
+    /// Distinct records created in a collection over whole hours, like
+    /// [`StoreReader::get_collection_counts`]
+    ///
+    /// `None` unless unique record counting is on. Backends that can't count
+    /// them return [`StorageError::Unsupported`](crate::error::StorageError::Unsupported).
+    async fn get_unique_records(
+        &self,
+        collection: &Nsid,
+        since: HourTruncatedCursor,
+        until: Option<HourTruncatedCursor>,
+    ) -> QueryResult<Option<UniqueRecords>>;
+
+    /// When counting started for a collection: the later of takeoff and first-seen
+    async fn get_tracked_since(&self, collection: &Nsid) -> QueryResult<Cursor>;
 
+    /// The latest records of some collections, newest first
+    ///
+    /// Also returns how many corrupt entries were skipped, and whether the scan
+    /// ran out of `budget`: then collections can have fewer records than they
+    /// could have had.
     async fn get_records_by_collections(
         &self,
         collections: HashSet<Nsid>,
         limit: usize,
         expand_each_collection: bool,
-    ) -> StorageResult<Vec<UFOsRecord>>;
+        budget: ScanBudget,
+    ) -> QueryResult<(Vec<UFOsRecord>, usize, bool)>;
 
-    async fn search_collections(&self, terms: Vec<String>) -> StorageResult<Vec<NsidCount>>;
+    /// The current version of a record followed by any older kept versions, newest first
+    async fn get_record_versions(
+        &self,
+        did: &Did,
+        collection: &Nsid,
+        rkey: &RecordKey,
+    ) -> QueryResult<Vec<UFOsRecord>>;
+
+    /// A DID's sampled records in a collection, in rkey order
+    ///
+    /// Only current versions are listed. Returns whether there were more
+    /// records in the range than the limit.
+    async fn get_account_records(
+        &self,
+        did: &Did,
+        collection: &Nsid,
+        range: (Bound<String>, Bound<String>),
+        limit: usize,
+        reverse: bool,
+    ) -> QueryResult<(Vec<UFOsRecord>, bool)>;
+
+    /// A DID's sampled records across every collection, grouped by collection
+    ///
+    /// Only current versions are listed. Pass the returned cursor back to
+    /// continue; it's `None` once there are no more. Older versions count
+    /// against the budget too: if it runs out first, the page is short and
+    /// marked truncated.
+    async fn get_records_by_did(
+        &self,
+        did: &Did,
+        cursor: Option<Vec<u8>>,
+        limit: usize,
+        budget: ScanBudget,
+    ) -> QueryResult<(Vec<UFOsRecord>, Option<Vec<u8>>, bool)>;
+
+    /// Roughly how many keys are stored under a prefix, without scanning them
+    ///
+    /// From the storage engine's own estimates, so it's only good for "~N"
+    /// hints: it can be well off for small prefixes, and can count deleted keys
+    /// that haven't been compacted away. `None` if there's nothing to go on yet.
+    async fn approximate_count(&self, prefix: CountPrefix) -> QueryResult<Option<u64>>;
+
+    /// A random sample of a collection's current records, newest first
+    ///
+    /// Each draw picks a time uniformly between the collection's oldest and
+    /// newest retained records and takes the first record at or after it, so
+    /// records that follow a quiet stretch are a little more likely to be drawn
+    /// than ones in a burst. The same `seed` gives the same sample while the
+    /// stored records don't change. Can return fewer than `n`, including when
+    /// the draws run out of `budget`.
+    async fn sample_records(
+        &self,
+        collection: &Nsid,
+        n: usize,
+        seed: u64,
+        budget: ScanBudget,
+    ) -> QueryResult<Vec<UFOsRecord>>;
+
+    /// Collections with a name containing any of the terms
+    ///
+    /// Also returns whether the scan ran out of `budget` before it was done, so
+    /// there may be more matches.
+    async fn search_collections(
+        &self,
+        terms: Vec<String>,
+        budget: ScanBudget,
+    ) -> QueryResult<(Vec<NsidCount>, bool)>;
+
+    /// Current records whose text matches a full-text query, best match first
+    ///
+    /// Needs a search index (fjall only). Records indexed in the last few
+    /// seconds might not be found yet.
+    async fn search_records(
+        &self,
+        query: &str,
+        collection: Option<&Nsid>,
+        limit: usize,
+    ) -> QueryResult<Vec<UFOsRecord>>;
+
+    /// Headline numbers across every collection, from the rollups
+    async fn get_summary(&self) -> QueryResult<Summary>;
+
+    /// Collections ranked by how much their record creates grew between the
+    /// two latest complete rollup buckets
+    ///
+    /// Collections with fewer than `min_previous` creates in the earlier bucket
+    /// are left out, since small counts make for wild growth rates.
+    async fn get_growing_collections(
+        &self,
+        period: GrowthPeriod,
+        limit: usize,
+        min_previous: u64,
+    ) -> QueryResult<GrowthRanking>;
+
+    /// A watchlist's hits before a cursor, newest first
+    ///
+    /// Hits are only kept for the watchlist config's retention.
+    async fn get_watchlist_hits(
+        &self,
+        name: &str,
+        before: Option<Cursor>,
+        limit: usize,
+    ) -> QueryResult<Vec<WatchlistHit>>;
+
+    /// A watchlist's hit counts per hour, oldest first, skipping hours without hits
+    async fn get_watchlist_counts(
+        &self,
+        name: &str,
+        since: HourTruncatedCursor,
+        until: Option<HourTruncatedCursor>,
+    ) -> QueryResult<Vec<(HourTruncatedCursor, u64)>>;
 }
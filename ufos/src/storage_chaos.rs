@@ -0,0 +1,365 @@
+//! Storage failure injection for tests
+//!
+//! Wraps a writer (and its spill queue) so that a configurable fraction of
+//! operations stall or fail. Which operations misbehave is decided by a seed,
+//! so a failing run can be reproduced.
+
+use crate::error::StorageError;
+use crate::spill::SpillQueue;
+use crate::storage::{StorageResult, StoreBackground, StoreWriter};
+use crate::{Cursor, EventBatch, JetstreamShard};
+use jetstream::exports::{Did, Nsid};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often and how badly storage should misbehave
+#[derive(Debug, Clone, Default)]
+pub struct ChaosConfig {
+    /// Fraction of operations that fail, from 0 to 1
+    pub error_rate: f64,
+    /// Fraction of operations that stall before running, from 0 to 1
+    pub latency_rate: f64,
+    /// How long a stalled operation waits
+    pub latency: Duration,
+    /// Operations to let through before misbehaving
+    pub grace: u64,
+    pub seed: u64,
+}
+
+#[derive(Debug, Default)]
+struct Chaos {
+    config: ChaosConfig,
+    calls: AtomicU64,
+    injected: AtomicU64,
+}
+
+impl Chaos {
+    fn new(config: ChaosConfig) -> Arc<Self> {
+        Arc::new(Self {
+            config,
+            ..Default::default()
+        })
+    }
+
+    /// A stable pseudo-random number in [0, 1) for the nth roll (splitmix64)
+    fn roll(&self, n: u64) -> f64 {
+        (crate::splitmix64(self.config.seed, n) >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Maybe stall and maybe fail before an operation. Blocks the thread.
+    fn disrupt(&self, op: &str) -> StorageResult<()> {
+        let n = self.calls.fetch_add(1, Ordering::SeqCst);
+        if n < self.config.grace {
+            return Ok(());
+        }
+        if self.roll(n * 2) < self.config.latency_rate {
+            self.injected.fetch_add(1, Ordering::SeqCst);
+            std::thread::sleep(self.config.latency);
+        }
+        if self.roll(n * 2 + 1) < self.config.error_rate {
+            self.injected.fetch_add(1, Ordering::SeqCst);
+            return Err(StorageError::BadStateError(format!(
+                "chaos: injected failure for {op} (call {n})"
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// A writer that misbehaves on purpose
+#[derive(Debug, Clone)]
+pub struct ChaosWriter<W> {
+    inner: W,
+    chaos: Arc<Chaos>,
+    spill_chaos: Arc<Chaos>,
+}
+
+impl<W> ChaosWriter<W> {
+    pub fn new(inner: W, config: ChaosConfig) -> Self {
+        Self {
+            inner,
+            chaos: Chaos::new(config),
+            spill_chaos: Chaos::new(ChaosConfig::default()),
+        }
+    }
+    /// Also misbehave in the spill queue (it behaves by default)
+    pub fn with_spill_chaos(mut self, config: ChaosConfig) -> Self {
+        self.spill_chaos = Chaos::new(config);
+        self
+    }
+    /// How many stalls and failures have been injected into writer operations
+    pub fn injected(&self) -> u64 {
+        self.chaos.injected.load(Ordering::SeqCst)
+    }
+}
+
+impl<W: StoreWriter<B>, B: StoreBackground> StoreWriter<B> for ChaosWriter<W> {
+    type Spill = ChaosSpill<W::Spill>;
+
+    fn spill_queue(&self) -> StorageResult<Self::Spill> {
+        Ok(ChaosSpill {
+            inner: self.inner.spill_queue()?,
+            chaos: self.spill_chaos.clone(),
+        })
+    }
+
+    fn background_tasks(&mut self, reroll: bool) -> StorageResult<B> {
+        self.inner.background_tasks(reroll)
+    }
+
+    fn jetstream_shard_cursor(&self, shard: &JetstreamShard) -> StorageResult<Option<Cursor>> {
+        self.inner.jetstream_shard_cursor(shard)
+    }
+
+    fn insert_batch<const LIMIT: usize>(
+        &mut self,
+        event_batch: EventBatch<LIMIT>,
+    ) -> StorageResult<()> {
+        self.chaos.disrupt("insert_batch")?;
+        self.inner.insert_batch(event_batch)
+    }
+
+    fn step_rollup(&mut self) -> StorageResult<(usize, HashSet<Nsid>)> {
+        self.chaos.disrupt("step_rollup")?;
+        self.inner.step_rollup()
+    }
+
+    fn trim_collection(
+        &mut self,
+        collection: &Nsid,
+        limit: usize,
+        full_scan: bool,
+    ) -> StorageResult<(usize, usize, bool)> {
+        self.chaos.disrupt("trim_collection")?;
+        self.inner.trim_collection(collection, limit, full_scan)
+    }
+
+    fn delete_account(&mut self, did: &Did) -> StorageResult<usize> {
+        self.chaos.disrupt("delete_account")?;
+        self.inner.delete_account(did)
+    }
+}
+
+/// A spill queue that misbehaves on purpose
+#[derive(Debug, Clone)]
+pub struct ChaosSpill<S> {
+    inner: S,
+    chaos: Arc<Chaos>,
+}
+
+impl<S: SpillQueue> SpillQueue for ChaosSpill<S> {
+    fn push(&self, latest: Option<Cursor>, batch: Vec<u8>) -> StorageResult<u64> {
+        self.chaos.disrupt("spill push")?;
+        self.inner.push(latest, batch)
+    }
+    fn get(&self, position: u64) -> StorageResult<Option<Vec<u8>>> {
+        self.chaos.disrupt("spill get")?;
+        self.inner.get(position)
+    }
+    fn queued(&self) -> StorageResult<Vec<u64>> {
+        self.chaos.disrupt("spill queued")?;
+        self.inner.queued()
+    }
+    fn latest_cursor(&self) -> StorageResult<Option<Cursor>> {
+        self.chaos.disrupt("spill latest_cursor")?;
+        self.inner.latest_cursor()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spill;
+    use crate::storage::{ScanBudget, StorageWhatever};
+    use crate::storage_fjall::{FjallConfig, FjallReader, FjallStorage, FjallWriter};
+    use crate::{CommitAction, ConsumerInfo, PutAction, UFOsCommit};
+    use jetstream::exports::RecordKey;
+    use serde_json::value::RawValue;
+    use tokio::sync::mpsc::channel;
+    use tokio::time::timeout;
+
+    const LIMIT: usize = 4;
+    const BUDGET: ScanBudget = ScanBudget::new(1_000_000);
+
+    fn fjall_db() -> (FjallReader, FjallWriter) {
+        let (read, write, _, _) = FjallStorage::init(
+            tempfile::tempdir().unwrap(),
+            "offline test (no real jetstream endpoint)".to_string(),
+            false,
+            FjallConfig {
+                temp: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        (read, write)
+    }
+
+    /// One new record per batch, with a distinct rkey
+    fn batch(cursor: u64) -> EventBatch<LIMIT> {
+        let mut batch = EventBatch::default();
+        let commit = UFOsCommit {
+            cursor: Cursor::from_raw_u64(cursor),
+            did: Did::new("did:plc:inze6wrmsm7pjl7yta3oig77".to_string()).unwrap(),
+            rkey: RecordKey::new(format!("r{cursor}")).unwrap(),
+            rev: format!("rev{cursor}"),
+            action: CommitAction::Put(PutAction {
+                record: RawValue::from_string(r#"{"a": 1}"#.to_string()).unwrap(),
+                is_update: false,
+            }),
+        };
+        batch
+            .insert_commit_by_nsid(&collection(), commit, 8, &[0u8; 16])
+            .unwrap();
+        batch
+    }
+
+    fn collection() -> Nsid {
+        Nsid::new("a.b.c".to_string()).unwrap()
+    }
+
+    fn stored_records(read: &FjallReader) -> usize {
+        read.get_records_by_collections([collection()].into(), 100, false, BUDGET)
+            .unwrap()
+            .0
+            .len()
+    }
+
+    fn latest_cursor(read: &FjallReader) -> Option<u64> {
+        let ConsumerInfo::Jetstream { latest_cursor, .. } = read.get_consumer_info().unwrap();
+        latest_cursor
+    }
+
+    async fn wait_for_queued(spill: &impl SpillQueue, n: usize) {
+        timeout(Duration::from_secs(5), async {
+            while spill.queued().unwrap().len() < n {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("batches should be queued");
+    }
+
+    #[test]
+    fn test_chaos_is_reproducible() {
+        let config = ChaosConfig {
+            error_rate: 0.3,
+            seed: 7,
+            ..Default::default()
+        };
+        let outcomes = || {
+            let chaos = Chaos::new(config.clone());
+            (0..100)
+                .map(|_| chaos.disrupt("test").is_ok())
+                .collect::<Vec<_>>()
+        };
+        let first = outcomes();
+        assert_eq!(first, outcomes());
+        let failures = first.iter().filter(|ok| !**ok).count();
+        assert!((10..50).contains(&failures), "{failures} failures");
+    }
+
+    #[tokio::test]
+    async fn test_insert_failure_stops_write_loop_and_replays() -> anyhow::Result<()> {
+        let (read, write) = fjall_db();
+        let flaky = ChaosWriter::new(
+            write.clone(),
+            ChaosConfig {
+                error_rate: 1.0,
+                grace: 2,
+                ..Default::default()
+            },
+        );
+        let spill = flaky.spill_queue()?;
+
+        let (sender, receiver) = channel(8);
+        for i in 1..=5 {
+            sender.send(batch(i * 10)).await?;
+        }
+        drop(sender);
+        let buffered = spill::buffer(receiver, spill.clone(), 16, 1);
+        wait_for_queued(&spill, 5).await;
+
+        // two batches make it in, then the write loop gives up instead of retrying
+        let r = timeout(
+            Duration::from_secs(5),
+            flaky.clone().receive_batches(buffered),
+        )
+        .await?;
+        assert!(matches!(r, Err(StorageError::BadStateError(_))));
+        assert_eq!(flaky.injected(), 1);
+        assert_eq!(stored_records(&read), 2);
+        assert_eq!(latest_cursor(&read), Some(20));
+
+        // everything after the failure is still queued, so a restart picks it up
+        assert_eq!(spill.queued()?.len(), 3);
+        let resume = spill::resume_cursor(&spill, Some(Cursor::from_raw_u64(20)))?;
+        assert_eq!(resume, Some(Cursor::from_raw_u64(50)));
+
+        let (sender, receiver) = channel::<EventBatch<LIMIT>>(8);
+        drop(sender);
+        let buffered = spill::buffer(receiver, spill.clone(), 16, 1);
+        let r = timeout(Duration::from_secs(5), write.receive_batches(buffered)).await?;
+        assert!(matches!(r, Err(StorageError::BatchSenderExited)));
+        assert_eq!(stored_records(&read), 5);
+        assert_eq!(latest_cursor(&read), Some(50));
+        assert!(spill.queued()?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_spill_failure_shuts_down() -> anyhow::Result<()> {
+        let (read, write) = fjall_db();
+        let flaky = ChaosWriter::new(write, ChaosConfig::default()).with_spill_chaos(ChaosConfig {
+            error_rate: 1.0,
+            grace: 1, // the startup replay check
+            ..Default::default()
+        });
+
+        let (sender, receiver) = channel(8);
+        let buffered = spill::buffer(receiver, flaky.spill_queue()?, 16, 1);
+        sender.send(batch(10)).await?;
+
+        // the buffer can't journal the batch, so the write loop ends instead of hanging
+        let r = timeout(Duration::from_secs(5), flaky.receive_batches(buffered)).await?;
+        assert!(matches!(r, Err(StorageError::BatchSenderExited)));
+        assert_eq!(stored_records(&read), 0);
+
+        // and the consumer finds out when it next tries to send
+        assert!(sender.send(batch(20)).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_slow_inserts_keep_order() -> anyhow::Result<()> {
+        let (read, write) = fjall_db();
+        let slow = ChaosWriter::new(
+            write,
+            ChaosConfig {
+                latency_rate: 0.5,
+                latency: Duration::from_millis(10),
+                seed: 3,
+                ..Default::default()
+            },
+        );
+        let spill = slow.spill_queue()?;
+
+        let (sender, receiver) = channel(1);
+        let buffered = spill::buffer(receiver, spill.clone(), 16, 1);
+        let writing = tokio::spawn(slow.clone().receive_batches(buffered));
+        for i in 1..=10 {
+            sender.send(batch(i * 10)).await?;
+        }
+        drop(sender);
+
+        let r = timeout(Duration::from_secs(10), writing).await??;
+        assert!(matches!(r, Err(StorageError::BatchSenderExited)));
+        assert!(slow.injected() > 0);
+        assert_eq!(stored_records(&read), 10);
+        assert_eq!(latest_cursor(&read), Some(100));
+        assert!(spill.queued()?.is_empty());
+        Ok(())
+    }
+}
@@ -1,22 +1,49 @@
+use crate::cache::DirtyTap;
 use crate::db_types::{
-    db_complete, DbBytes, DbStaticStr, EncodingResult, StaticStr, SubPrefixBytes,
+    db_complete, DbBytes, DbConcat, DbStaticStr, EncodingResult, StaticStr, SubPrefixBytes,
+};
+use crate::denylist::{DenyRule, Denylist};
+use crate::diff::diff_records;
+use crate::error::{QueryError, StorageError};
+use crate::heavy_hitters::{NoisyDids, NoisyDidsReport};
+use crate::nsid_limits::NsidLimits;
+use crate::plugin::{IngestPlugin, IngestPlugins, StoredBatch};
+use crate::publish::{BusTap, CountsDelta};
+use crate::redaction::Redactor;
+use crate::search::SearchIndex;
+use crate::server;
+use crate::spill::{self, SpillQueue};
+use crate::storage::{
+    sample_by_probes, CountPrefix, QueryResult, ScanBudget, StorageResult, StorageWhatever,
+    StoreAdmin, StoreBackground, StoreReader, StoreWriter,
 };
-use crate::error::StorageError;
-use crate::storage::{StorageResult, StorageWhatever, StoreBackground, StoreReader, StoreWriter};
 use crate::store_types::{
-    AllTimeDidsKey, AllTimeRecordsKey, AllTimeRollupKey, CommitCounts, CountsValue, CursorBucket,
-    DeleteAccountQueueKey, DeleteAccountQueueVal, HourTruncatedCursor, HourlyDidsKey,
-    HourlyRecordsKey, HourlyRollupKey, HourlyRollupStaticPrefix, JetstreamCursorKey,
-    JetstreamCursorValue, JetstreamEndpointKey, JetstreamEndpointValue, LiveCountsKey,
+    AllTimeDidsKey, AllTimeRecordsKey, AllTimeRollupKey, ChangesTrimmedKey, ChangesTrimmedValue,
+    CollectionFirstSeenKey, CollectionFirstSeenVal, CommitCounts, CountsValue, CursorBucket,
+    CursorGapKey, CursorGapValue, DeleteAccountProgressKey, DeleteAccountProgressVal,
+    DeleteAccountQueueKey, DeleteAccountQueueVal, DenylistKey, DenylistVal, EventKindsKey,
+    EventKindsVal, HourTruncatedCursor, HourWindow, HourlyDidsKey, HourlyRecordsKey,
+    HourlyRollupKey, HourlyRollupStaticPrefix, JetstreamCursorKey, JetstreamCursorValue,
+    JetstreamEndpointKey, JetstreamEndpointValue, JetstreamShardCursorKey, LiveCountsKey,
     NewRollupCursorKey, NewRollupCursorValue, NsidRecordFeedKey, NsidRecordFeedVal,
-    RecordLocationKey, RecordLocationMeta, RecordLocationVal, RecordRawValue, SketchSecretKey,
-    SketchSecretPrefix, TakeoffKey, TakeoffValue, TrimCollectionCursorKey, WeekTruncatedCursor,
-    WeeklyDidsKey, WeeklyRecordsKey, WeeklyRollupKey, WithCollection, WithRank, HOUR_IN_MICROS,
-    WEEK_IN_MICROS,
+    OverflowedCollectionsKey, OverflowedCollectionsVal, QuarantineKey, RankCursor, RebuildFeedsKey,
+    RebuildFeedsValue, RecordLocationKey, RecordLocationMeta, RecordLocationVal, RecordRawValue,
+    RecordTombstoneKey, RecordTombstoneVal, RecordVersionKey, SketchSecretKey, SketchSecretPrefix,
+    TakeoffKey, TakeoffValue, TombstoneExpiryKey, TrimCollectionCursorKey, UniqueHourlyKey,
+    UniqueHourlyVal, UniqueSeenKey, WatchHitKey, WatchHitVal, WatchHourlyKey, WatchHourlyVal,
+    WeekTruncatedCursor, WeeklyDidsKey, WeeklyRecordsKey, WeeklyRollupKey, WithCollection,
+    WithRank, HOUR_IN_MICROS, WEEK_IN_MICROS,
 };
+use crate::transform::Transformer;
+use crate::watchlist::Watchlists;
+use crate::webhook::WebhookTap;
 use crate::{
-    nice_duration, CommitAction, ConsumerInfo, Did, EncodingError, EventBatch, JustCount, Nsid,
-    NsidCount, NsidPrefix, OrderCollectionsBy, PrefixChild, PrefixCount, UFOsRecord,
+    nice_duration, AccountDeleteProgress, AccountDeletesStatus, AuditEntry, BackgroundIntervals,
+    BackgroundStatus, BackgroundTask, CollectionCommits, CollectionGrowth, CollectionKeySpace,
+    CommitAction, ConsumerInfo, Did, EncodingError, EventBatch, EventKindCounts, GrowthPeriod,
+    GrowthRanking, JetstreamShard, JustCount, KeySpaceReport, Nsid, NsidCount, NsidPrefix,
+    OrderCollectionsBy, PrefixChild, PrefixCount, PurgeReport, PutAction, RebuildFeedsReport,
+    RecordKey, Summary, UFOsCommit, UFOsRecord, UniqueRecords, WatchlistHit, WindowCounts,
 };
 use async_trait::async_trait;
 use fjall::{
@@ -27,18 +54,44 @@ use lsm_tree::AbstractTree;
 use metrics::{
     counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram, Unit,
 };
-use std::collections::{HashMap, HashSet};
+use serde_json::value::RawValue;
+use sha2::{Digest, Sha256};
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::iter::Peekable;
-use std::ops::Bound;
+use std::ops::{Bound, RangeBounds};
 use std::path::Path;
+use std::rc::Rc;
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc, Mutex, RwLock,
 };
 use std::time::{Duration, Instant, SystemTime};
 
-const MAX_BATCHED_ACCOUNT_DELETE_RECORDS: usize = 1024;
-const MAX_BATCHED_ROLLUP_COUNTS: usize = 256;
+pub(crate) const MAX_BATCHED_ACCOUNT_DELETE_RECORDS: usize = 1024;
+/// Most queued account deletes handled in one rollup step
+pub(crate) const MAX_BATCHED_ACCOUNT_DELETES: usize = 64;
+/// Stop taking more account deletes in a rollup step after removing this many records
+pub(crate) const MAX_ROLLUP_DELETE_RECORDS: usize = 8192;
+pub(crate) const MAX_BATCHED_ROLLUP_COUNTS: usize = 256;
+const MAX_BATCHED_PURGE_ITEMS: usize = 1024;
+const MAX_BATCHED_REBUILD_ITEMS: usize = 4096;
+pub(crate) const MAX_BATCHED_TRIM_ITEMS: usize = 4096;
+/// Most feed entries one trim call will remove, so a huge backlog can't stall the background task
+pub(crate) const MAX_TRIM_RANGE_ITEMS: usize = 1_000_000;
+/// How long a deleted record's tombstone is kept: well past how far back
+/// jetstream can replay, so a replayed create can't bring the record back
+pub(crate) const MAX_TOMBSTONE_AGE: Duration = Duration::from_secs(7 * 86_400);
+/// How many of the most recent records per collection survive a trim
+pub(crate) const TRIM_KEEP_RECORDS: usize = 512;
+/// How quickly a collection's read popularity fades after it stops being read
+const POPULARITY_HALF_LIFE: Duration = Duration::from_secs(3_600);
+/// A collection with at least this many (decayed) reads is trimmed less
+const POPULAR_READS: f64 = 3.0;
+/// Don't let reads of made-up collections grow the popularity map forever
+const MAX_POPULARITY_TRACKED: usize = 16_384;
+pub(crate) const MAX_PINNED_SNAPSHOTS: usize = 64;
+pub(crate) const MAX_PINNED_SNAPSHOT_TTL: Duration = Duration::from_secs(5 * 60);
 
 ///
 /// new data format, roughly:
@@ -69,6 +122,14 @@ const MAX_BATCHED_ROLLUP_COUNTS: usize = 256;
 ///      - key: "trim_cursor" || nullstr (nsid)
 ///      - val: u64 (earliest previously-removed feed entry jetstream cursor)
 ///
+///  - Change feed trim cursor (bg work: drop published batches past retention)
+///      - key: "changes_trimmed" (literal)
+///      - val: u64 (latest js_cursor of the last removed batch)
+///
+///  - Feeds rebuild progress (only while a rebuild is unfinished)
+///      - key: "rebuild_feeds" (literal)
+///      - val: bytes (records key of the last rebuilt record, empty once feeds are cleared)
+///
 /// Partition: 'feed'
 ///
 ///  - Per-collection list of record references ordered by jetstream cursor
@@ -80,7 +141,11 @@ const MAX_BATCHED_ROLLUP_COUNTS: usize = 256;
 ///
 ///  - Actual records by their atproto location
 ///      - key: nullstr || nullstr || nullstr (did, collection, rkey)
-///      - val: u64 || bool || nullstr || rawval (js_cursor, is_update, rev, actual record)
+///      - val: u64 || bool || nullstr || option<u32> || option<str> || rawval (js_cursor, is_update, rev, redaction version, diff from previous version, actual record)
+///
+///  - Older versions of records, for collections configured to keep them
+///      - key: nullstr || nullstr || nullstr || u64 (did, collection, rkey, js_cursor of the version)
+///      - val: same as the record val, as it was stored
 ///
 ///
 /// Partition: 'rollups'
@@ -128,6 +193,20 @@ const MAX_BATCHED_ROLLUP_COUNTS: usize = 256;
 ///      - key: "ever_rank_dids" || u64 || nullstr (dids estimate, nsid)
 ///      - val: [empty]
 ///
+/// (ranked listings scan the rank keys in reverse: ties in count come out by nsid, descending)
+///
+/// - Batches cut short by too many active collections, per hour
+///      - key: "overflowed_collections" || u64 (hour)
+///      - val: u64 (number of batches)
+///
+/// - Jetstream events by kind, per hour
+///      - key: "event_kinds" || u64 (hour)
+///      - val: u64 || u64 || u64 (commits, identities, accounts)
+///
+/// - Collection first-seen (set when the all-time rollup is first created)
+///      - key: "first_seen" || nullstr (nsid)
+///      - val: u64 (js_cursor of the earliest rolled-up live counts)
+///
 ///
 /// Partition: 'queues'
 ///
@@ -136,6 +215,68 @@ const MAX_BATCHED_ROLLUP_COUNTS: usize = 256;
 ///      - val: nullstr (did)
 ///
 ///
+///
+/// Partition: 'spill'
+///
+///  - Queue of consumed batches that haven't been inserted yet. Entries are removed in the
+///    same db batch that inserts them, and anything left is replayed at startup.
+///      - key: u64 (queue position)
+///      - val: js_cursor (latest in the batch) || encoded batch
+///
+///
+/// Partition: 'audit'
+///
+///  - Append-only log of admin actions
+///      - key: u64 (micros timestamp, bumped to be unique)
+///      - val: json (actor, action, params, outcome)
+///
+///
+/// Partition: 'changes'
+///
+///  - Inserted batches published for replicas chasing this instance (only with a change feed)
+///      - key: u64 (js_cursor, latest in the batch)
+///      - val: encoded batch (redacted, without denied collections' records)
+///
+///
+/// Partition: 'watch'
+///
+///  - Records that matched a watchlist (only with a watchlist config), kept for its retention
+///      - key: "watch_hit" || nullstr || u64 || nullstr || nullstr || nullstr (watchlist, js_cursor, did, collection, rkey)
+///      - val: bincode (the keywords and regexes that matched)
+///
+///  - Watchlist hit counts per hour
+///      - key: "watch_hourly" || nullstr || u64 (watchlist, hour)
+///      - val: u64 (number of hits)
+///
+///
+/// Partition: 'unique'
+///
+///  - Every record ever created in a collection (only with unique record counting)
+///      - key: "unique_seen" || nullstr || nullstr || nullstr (collection, did, rkey)
+///      - val: [empty]
+///
+///  - Unique record creates per hour
+///      - key: "unique_hourly" || nullstr || u64 (collection, hour)
+///      - val: u64 || u64 (first-time creates, unchecked creates)
+///
+///
+/// Partition: 'tombstones'
+///
+///  - When a record was last deleted, kept for a while so older replayed puts stay deleted
+///      - key: "deleted_at" || nullstr || nullstr || nullstr (did, collection, rkey)
+///      - val: u64 (js_cursor of the delete)
+///
+///  - The same tombstones by age, for dropping them
+///      - key: "deleted_expiry" || u64 || nullstr || nullstr || nullstr (js_cursor, did, collection, rkey)
+///      - val: [empty]
+///
+///
+/// Partition: 'quarantine'
+///
+///  - Undecodable entries moved out of other partitions by the background scrub
+///      - key: nullstr || bytes (partition name, original key)
+///      - val: bytes (original value)
+///
 /// TODO: moderation actions
 /// TODO: account privacy preferences. Might wait for the protocol-level (PDS-level?) stuff to land. Will probably do lazy fetching + caching on read.
 #[derive(Debug)]
@@ -148,6 +289,84 @@ pub struct FjallConfig {
     /// this is only meant for tests
     #[cfg(test)]
     pub temp: bool,
+    /// strip configured paths from records before storing them
+    pub redaction: Option<Redactor>,
+    /// per-collection transforms to run on records (after redaction) before storing them
+    pub transforms: Option<Transformer>,
+    /// tag records matching these watchlists as they're stored
+    pub watchlists: Option<Watchlists>,
+    /// index the text of stored records, for searching them
+    pub search: Option<SearchIndex>,
+    /// count each record created in a collection only once, however often
+    /// it's replayed or re-created
+    ///
+    /// keeps a key for every record ever created in a stored collection.
+    pub unique_counts: bool,
+    /// hand stored records to webhooks once their batch is committed
+    pub webhooks: Option<WebhookTap>,
+    /// publish stored records and count deltas once their batch is committed
+    pub bus: Option<BusTap>,
+    /// report collections with new rolled-up counts, to drop their cache entries
+    pub dirty: Option<DirtyTap>,
+    /// use this secret for cardinality sketches instead of a random one
+    ///
+    /// other services estimating dids with the same secret produce sketches
+    /// that can be merged with ours. must match the stored secret of an
+    /// existing db.
+    pub sketch_secret: Option<SketchSecretPrefix>,
+    /// store a json merge patch from the previous version with updated records
+    ///
+    /// only possible when the previous version is still in the db
+    pub record_diffs: bool,
+    /// keep this many previous versions of each record in these collections
+    ///
+    /// older versions are dropped along with the record when it's deleted or
+    /// trimmed from the feed
+    pub keep_versions: HashMap<Nsid, usize>,
+    /// publish inserted batches for replicas to chase, keeping them this long
+    pub change_feed: Option<Duration>,
+    /// run background tasks this often instead of the defaults for (non-)backfill
+    ///
+    /// can be changed later from the admin api
+    pub background: Option<BackgroundIntervals>,
+    /// keep this many times more records when trimming collections that are read often
+    ///
+    /// so collections people actually look at keep deeper samples than ones
+    /// nobody reads. 1 (or unset) trims every collection the same.
+    pub popular_trim_multiplier: Option<usize>,
+    /// delete the db directory when the storage is dropped
+    ///
+    /// for throwaway instances like CI and demos. everything works the same:
+    /// whatever doesn't fit in the block cache just stays on disk. the
+    /// directory must be missing or empty, so an existing db can't be lost.
+    pub ephemeral: bool,
+    /// memory for fjall's block cache, in bytes (fjall's default if unset)
+    pub cache_bytes: Option<u64>,
+    /// derive everything random from this seed, for reproducible demos and tests
+    ///
+    /// covers the sketch secret of a fresh db (unless `sketch_secret` is set)
+    /// and the entries sampled by consistency checks. never use it for a db
+    /// that shares sketches with anything: the secret becomes guessable.
+    pub seed: Option<u64>,
+    /// keep a separate cursor for each of these jetstream connections
+    ///
+    /// commits are credited to the shard that lists their collection.
+    pub jetstream_shards: Vec<JetstreamShard>,
+    /// keep pathologically deep or long NSIDs out of the keys and NSID tree
+    pub nsid_limits: NsidLimits,
+    /// run these on every batch before storing it, after the built-in plugins
+    pub plugins: IngestPlugins,
+}
+
+/// Missing or empty: nothing would be lost by deleting it later
+fn is_unused_dir(path: &Path) -> StorageResult<bool> {
+    match std::fs::read_dir(path) {
+        Ok(mut entries) => Ok(entries.next().is_none()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(true),
+        Err(e) => Err(StorageError::InitError(format!(
+            "failed to check the db directory {path:?}: {e}"
+        ))),
+    }
 }
 
 impl StorageWhatever<FjallReader, FjallWriter, FjallBackground, FjallConfig> for FjallStorage {
@@ -155,15 +374,25 @@ impl StorageWhatever<FjallReader, FjallWriter, FjallBackground, FjallConfig> for
         path: impl AsRef<Path>,
         endpoint: String,
         force_endpoint: bool,
-        _config: FjallConfig,
+        config: FjallConfig,
     ) -> StorageResult<(FjallReader, FjallWriter, Option<Cursor>, SketchSecretPrefix)> {
+        let randomness = Randomness::new(config.seed);
+        if config.ephemeral && !is_unused_dir(path.as_ref())? {
+            return Err(StorageError::InitError(format!(
+                "{:?} isn't empty: refusing to use it for an ephemeral db, which is deleted on exit",
+                path.as_ref()
+            )));
+        }
         let keyspace = {
-            let config = Config::new(path);
+            let mut keyspace_config = Config::new(path).temporary(config.ephemeral);
+            if let Some(bytes) = config.cache_bytes {
+                keyspace_config = keyspace_config.cache_size(bytes);
+            }
 
             // #[cfg(not(test))]
-            // let config = config.fsync_ms(Some(4_000));
+            // let keyspace_config = keyspace_config.fsync_ms(Some(4_000));
 
-            config.open()?
+            keyspace_config.open()?
         };
 
         let global = keyspace.open_partition("global", PartitionCreateOptions::default())?;
@@ -171,6 +400,15 @@ impl StorageWhatever<FjallReader, FjallWriter, FjallBackground, FjallConfig> for
         let records = keyspace.open_partition("records", PartitionCreateOptions::default())?;
         let rollups = keyspace.open_partition("rollups", PartitionCreateOptions::default())?;
         let queues = keyspace.open_partition("queues", PartitionCreateOptions::default())?;
+        let changes = keyspace.open_partition("changes", PartitionCreateOptions::default())?;
+        let audit = keyspace.open_partition("audit", PartitionCreateOptions::default())?;
+        let watch = keyspace.open_partition("watch", PartitionCreateOptions::default())?;
+        let unique = keyspace.open_partition("unique", PartitionCreateOptions::default())?;
+        let tombstones =
+            keyspace.open_partition("tombstones", PartitionCreateOptions::default())?;
+        let quarantine =
+            keyspace.open_partition("quarantine", PartitionCreateOptions::default())?;
+        let spill = FjallSpill::open(&keyspace)?;
 
         let js_cursor = get_static_neu::<JetstreamCursorKey, JetstreamCursorValue>(&global)?;
 
@@ -189,6 +427,13 @@ impl StorageWhatever<FjallReader, FjallWriter, FjallBackground, FjallConfig> for
                 ));
             };
 
+            if config.sketch_secret.is_some_and(|s| s != stored_secret) {
+                return Err(StorageError::InitError(
+                    "stored sketch_secret differs from the provided one, refusing to start."
+                        .to_string(),
+                ));
+            }
+
             if stored != endpoint {
                 if force_endpoint {
                     log::warn!("forcing a jetstream switch from {stored:?} to {endpoint:?}");
@@ -209,13 +454,19 @@ impl StorageWhatever<FjallReader, FjallWriter, FjallBackground, FjallConfig> for
                 JetstreamEndpointValue(endpoint.to_string()),
             )?;
 
-            log::info!("generating new secret for cardinality sketches...");
-            let mut sketch_secret: SketchSecretPrefix = [0u8; 16];
-            getrandom::fill(&mut sketch_secret).map_err(|e| {
-                StorageError::InitError(format!(
-                    "failed to get a random secret for cardinality sketches: {e:?}"
-                ))
-            })?;
+            let sketch_secret = match config.sketch_secret {
+                Some(secret) => secret,
+                None => {
+                    log::info!("generating new secret for cardinality sketches...");
+                    let mut sketch_secret: SketchSecretPrefix = [0u8; 16];
+                    randomness.fill(&mut sketch_secret).map_err(|e| {
+                        StorageError::InitError(format!(
+                            "failed to get a random secret for cardinality sketches: {e:?}"
+                        ))
+                    })?;
+                    sketch_secret
+                }
+            };
             init_static_neu::<SketchSecretKey>(&global, sketch_secret)?;
 
             init_static_neu::<TakeoffKey>(&global, Cursor::at(SystemTime::now()))?;
@@ -224,6 +475,8 @@ impl StorageWhatever<FjallReader, FjallWriter, FjallBackground, FjallConfig> for
             sketch_secret
         };
 
+        let popularity = ReadPopularity::default();
+
         let reader = FjallReader {
             keyspace: keyspace.clone(),
             global: global.clone(),
@@ -231,23 +484,188 @@ impl StorageWhatever<FjallReader, FjallWriter, FjallBackground, FjallConfig> for
             records: records.clone(),
             rollups: rollups.clone(),
             queues: queues.clone(),
+            changes: changes.clone(),
+            watch: watch.clone(),
+            unique: unique.clone(),
+            unique_counts: config.unique_counts,
+            pins: Default::default(),
+            pinned: None,
+            popularity: popularity.clone(),
+            search: config.search.clone(),
         };
         reader.describe_metrics();
+        let mut deny_rules = Vec::new();
+        for kv in global.prefix(DenylistKey::from_prefix_to_db_bytes(&Default::default())?) {
+            let (key_bytes, val_bytes) = kv?;
+            let key = db_complete::<DenylistKey>(&key_bytes)?;
+            let val = db_complete::<DenylistVal>(&val_bytes)?;
+            deny_rules.push(DenyRule {
+                pattern: key.pattern().to_string(),
+                keep_counts: val.keep_counts,
+            });
+        }
+        if !deny_rules.is_empty() {
+            log::info!("loaded {} collection deny rules", deny_rules.len());
+        }
+
+        let noisy = NoisyDids::default();
+        let mut builtins: Vec<Arc<dyn IngestPlugin>> = vec![Arc::new(noisy.clone())];
+        if let Some(search) = config.search {
+            builtins.push(Arc::new(search));
+        }
+        if let Some(webhooks) = config.webhooks {
+            builtins.push(Arc::new(webhooks));
+        }
+        if let Some(bus) = config.bus {
+            builtins.push(Arc::new(bus));
+        }
         let writer = FjallWriter {
             bg_taken: Arc::new(AtomicBool::new(false)),
+            schedule: Arc::new(BackgroundSchedule::new(config.background)),
+            denylist: Arc::new(RwLock::new(Denylist::new(deny_rules))),
+            redactor: config.redaction.map(Arc::new),
+            transformer: config.transforms.map(Arc::new),
+            watchlists: config.watchlists.map(Arc::new),
+            search: config.search.clone(),
+            unique_counts: config.unique_counts,
+            dirty: config.dirty,
+            record_diffs: config.record_diffs,
+            keep_versions: Arc::new(config.keep_versions),
+            change_feed: config.change_feed,
+            popularity,
+            popular_trim_multiplier: config.popular_trim_multiplier.unwrap_or(1).max(1),
+            randomness,
+            noisy,
+            plugins: config.plugins.after_builtins(builtins),
+            nsid_limits: config.nsid_limits,
+            shards: Arc::new(
+                config
+                    .jetstream_shards
+                    .iter()
+                    .flat_map(|shard| {
+                        let id = shard.id();
+                        shard
+                            .collections()
+                            .iter()
+                            .map(move |c| (c.clone(), id.clone()))
+                    })
+                    .collect(),
+            ),
+            spill,
             keyspace,
             global,
             feeds,
             records,
             rollups,
             queues,
+            changes,
+            audit,
+            audit_lock: Default::default(),
+            rollup_lock: Default::default(),
+            watch,
+            unique,
+            tombstones,
+            quarantine,
         };
         writer.describe_metrics();
         Ok((reader, writer, js_cursor, sketch_secret))
     }
 }
 
-type FjallRKV = fjall::Result<(fjall::Slice, fjall::Slice)>;
+type FjallRKV = StorageResult<(fjall::Slice, fjall::Slice)>;
+
+/// Partition snapshots all taken at the same keyspace instant
+///
+/// Kept alive (and so protected from compaction) until they expire
+#[derive(Clone)]
+struct PinnedSnapshot {
+    expires_at: Instant,
+    global: Snapshot,
+    feeds: Snapshot,
+    records: Snapshot,
+    rollups: Snapshot,
+}
+
+type PinnedSnapshots = Arc<Mutex<HashMap<u64, PinnedSnapshot>>>;
+
+/// Where random bytes come from: the OS, or a seeded sequence for reproducible runs
+#[derive(Debug, Clone, Default)]
+pub(crate) enum Randomness {
+    #[default]
+    Os,
+    Seeded {
+        seed: u64,
+        draws: Arc<AtomicU64>,
+    },
+}
+
+impl Randomness {
+    pub(crate) fn new(seed: Option<u64>) -> Self {
+        match seed {
+            None => Self::Os,
+            Some(seed) => Self::Seeded {
+                seed,
+                draws: Default::default(),
+            },
+        }
+    }
+
+    pub(crate) fn fill(&self, dest: &mut [u8]) -> Result<(), getrandom::Error> {
+        let Self::Seeded { seed, draws } = self else {
+            return getrandom::fill(dest);
+        };
+        for chunk in dest.chunks_mut(8) {
+            let n = draws.fetch_add(1, Ordering::SeqCst);
+            let bytes = crate::splitmix64(*seed, n).to_be_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+        Ok(())
+    }
+}
+
+/// Reads of a collection's records, decaying by half every `POPULARITY_HALF_LIFE`
+///
+/// Shared by reader and writer clones, so trimming can keep deeper history
+/// for the collections people are actually reading.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ReadPopularity(Arc<Mutex<HashMap<Nsid, (f64, Instant)>>>);
+
+impl ReadPopularity {
+    fn decayed(score: f64, since: Instant, now: Instant) -> f64 {
+        let half_lives =
+            now.saturating_duration_since(since).as_secs_f64() / POPULARITY_HALF_LIFE.as_secs_f64();
+        score * 0.5_f64.powf(half_lives)
+    }
+
+    pub(crate) fn record_read(&self, collection: &Nsid) {
+        self.record_read_at(collection, Instant::now())
+    }
+
+    fn record_read_at(&self, collection: &Nsid, now: Instant) {
+        let mut scores = self.0.lock().unwrap();
+        if let Some((score, at)) = scores.get_mut(collection) {
+            *score = Self::decayed(*score, *at, now) + 1.0;
+            *at = now;
+        } else if scores.len() < MAX_POPULARITY_TRACKED {
+            scores.insert(collection.clone(), (1.0, now));
+        }
+    }
+
+    pub(crate) fn is_popular_at(&self, collection: &Nsid, now: Instant) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .get(collection)
+            .is_some_and(|(score, at)| Self::decayed(*score, *at, now) >= POPULAR_READS)
+    }
+
+    /// Forget collections that haven't been read in a long time
+    pub(crate) fn prune(&self, now: Instant) -> usize {
+        let mut scores = self.0.lock().unwrap();
+        scores.retain(|_, (score, at)| Self::decayed(*score, *at, now) >= 0.01);
+        scores.len()
+    }
+}
 
 #[derive(Clone)]
 pub struct FjallReader {
@@ -257,29 +675,71 @@ pub struct FjallReader {
     records: PartitionHandle,
     rollups: PartitionHandle,
     queues: PartitionHandle,
+    changes: PartitionHandle,
+    watch: PartitionHandle,
+    unique: PartitionHandle,
+    unique_counts: bool,
+    /// all currently-pinned snapshots, shared across reader clones
+    pins: PinnedSnapshots,
+    /// if set, all reads from this reader go through this snapshot
+    pinned: Option<PinnedSnapshot>,
+    /// recent record reads per collection, shared with the writer for trimming
+    popularity: ReadPopularity,
+    search: Option<SearchIndex>,
 }
 
 /// An iterator that knows how to skip over deleted/invalidated records
+///
+/// Entries that fail to decode are skipped too, and counted in `skipped_corrupt`.
 struct RecordIterator {
     db_iter: Box<dyn Iterator<Item = FjallRKV>>,
-    records: PartitionHandle,
+    records: Snapshot,
     limit: usize,
     fetched: usize,
+    skipped_corrupt: Rc<Cell<usize>>,
+    /// shared by every iterator of a request: one key per feed entry
+    budget: Rc<Cell<ScanBudget>>,
 }
 impl RecordIterator {
     pub fn new(
-        feeds: &PartitionHandle,
-        records: PartitionHandle,
+        feeds: &Snapshot,
+        records: Snapshot,
         collection: &Nsid,
         limit: usize,
+        skipped_corrupt: Rc<Cell<usize>>,
+        budget: Rc<Cell<ScanBudget>>,
     ) -> StorageResult<Self> {
         let prefix = NsidRecordFeedKey::from_prefix_to_db_bytes(collection)?;
-        let db_iter = feeds.prefix(prefix).rev();
+        let db_iter = feeds.prefix(prefix).rev().map(|kv| Ok(kv?));
         Ok(Self {
             db_iter: Box::new(db_iter),
             records,
             limit,
             fetched: 0,
+            skipped_corrupt,
+            budget,
+        })
+    }
+    /// Oldest-first from a cursor, for finding the first live record after it
+    pub fn starting_at(
+        feeds: &Snapshot,
+        records: Snapshot,
+        collection: &Nsid,
+        cursor: Cursor,
+        limit: usize,
+        skipped_corrupt: Rc<Cell<usize>>,
+        budget: Rc<Cell<ScanBudget>>,
+    ) -> StorageResult<Self> {
+        let range =
+            NsidRecordFeedKey::from_pair(collection.clone(), cursor).range_to_prefix_end()?;
+        let db_iter = feeds.range(range).map(|kv| Ok(kv?));
+        Ok(Self {
+            db_iter: Box::new(db_iter),
+            records,
+            limit,
+            fetched: 0,
+            skipped_corrupt,
+            budget,
         })
     }
     fn get_record(&self, db_next: FjallRKV) -> StorageResult<Option<UFOsRecord>> {
@@ -310,18 +770,36 @@ impl RecordIterator {
             );
             return Ok(None);
         };
-        let rawval = db_complete::<RecordRawValue>(raw_value_bytes)?;
-        Ok(Some(UFOsRecord {
-            collection: feed_key.collection().clone(),
-            cursor: feed_key.cursor(),
-            did: feed_val.did().clone(),
-            rkey: feed_val.rkey().clone(),
-            rev: meta.rev.to_string(),
-            record: rawval.try_into()?,
-            is_update: meta.is_update,
-        }))
+        Ok(Some(decode_record(&location_key, meta, raw_value_bytes)?))
     }
 }
+
+/// Build a record from its location and stored value (current or an older version)
+pub(crate) fn decode_record(
+    location_key: &RecordLocationKey,
+    meta: RecordLocationMeta,
+    raw_value_bytes: &[u8],
+) -> StorageResult<UFOsRecord> {
+    let rawval = db_complete::<RecordRawValue>(raw_value_bytes)?;
+    let cursor = meta.cursor();
+    let diff = meta
+        .diff
+        .map(RawValue::from_string)
+        .transpose()
+        .map_err(EncodingError::JsonError)?;
+    Ok(UFOsRecord {
+        collection: location_key.collection().clone(),
+        cursor,
+        did: location_key.did().clone(),
+        rkey: location_key.rkey().clone(),
+        rev: meta.rev,
+        record: rawval.try_into()?,
+        is_update: meta.is_update,
+        redaction_version: meta.redaction_version,
+        diff,
+        transforms: meta.transforms,
+    })
+}
 impl Iterator for RecordIterator {
     type Item = StorageResult<Option<UFOsRecord>>;
     fn next(&mut self) -> Option<Self::Item> {
@@ -330,7 +808,20 @@ impl Iterator for RecordIterator {
         }
         let record = loop {
             let db_next = self.db_iter.next()?; // None short-circuits here
+            let mut budget = self.budget.get();
+            let spent = budget.spend();
+            self.budget.set(budget);
+            if !spent {
+                return Some(Ok(None)); // out of budget: done, like at the limit
+            }
             match self.get_record(db_next) {
+                Err(StorageError::EncodingError(e)) => {
+                    // keep serving the rest while the bad entry is looked into
+                    log::warn!("record lookup: skipping an entry that failed to decode: {e}");
+                    counter!("storage_records_skipped_corrupt").increment(1);
+                    self.skipped_corrupt.set(self.skipped_corrupt.get() + 1);
+                    continue;
+                }
                 Err(e) => return Some(Err(e)),
                 Ok(Some(record)) => break record,
                 Ok(None) => continue,
@@ -385,9 +876,125 @@ fn get_lookup_iter<T: WithCollection + WithRank + DbBytes + 'static>(
     )))
 }
 
+fn sum_rollups(
+    rollups: &Snapshot,
+    collection: &Nsid,
+    buckets: Vec<CursorBucket>,
+) -> StorageResult<CountsValue> {
+    let mut total_counts = CountsValue::default();
+    for bucket in buckets {
+        let key = match bucket {
+            CursorBucket::Hour(t) => HourlyRollupKey::new(t, collection).to_db_bytes()?,
+            CursorBucket::Week(t) => WeeklyRollupKey::new(t, collection).to_db_bytes()?,
+            CursorBucket::AllTime => unreachable!(), // TODO: fall back on this if the time span spans the whole dataset?
+        };
+        let count = rollups
+            .get(&key)?
+            .as_deref()
+            .map(db_complete::<CountsValue>)
+            .transpose()?
+            .unwrap_or_default();
+        total_counts.merge(&count);
+    }
+    Ok(total_counts)
+}
+
 type CollectionSerieses = HashMap<Nsid, Vec<CountsValue>>;
 
 impl FjallReader {
+    fn global_snapshot(&self) -> Snapshot {
+        match &self.pinned {
+            Some(pinned) => pinned.global.clone(),
+            None => self.global.snapshot(),
+        }
+    }
+
+    fn feeds_snapshot(&self) -> Snapshot {
+        match &self.pinned {
+            Some(pinned) => pinned.feeds.clone(),
+            None => self.feeds.snapshot(),
+        }
+    }
+
+    fn records_snapshot(&self) -> Snapshot {
+        match &self.pinned {
+            Some(pinned) => pinned.records.clone(),
+            None => self.records.snapshot(),
+        }
+    }
+
+    fn rollups_snapshot(&self) -> Snapshot {
+        match &self.pinned {
+            Some(pinned) => pinned.rollups.clone(),
+            None => self.rollups.snapshot(),
+        }
+    }
+
+    /// Lookup for when counting started for each collection
+    ///
+    /// This is the later of takeoff and the collection's first-seen time.
+    /// Collections rolled up before first-seen was recorded fall back to takeoff.
+    fn tracked_since(
+        &self,
+        rollups: Snapshot,
+    ) -> StorageResult<impl Fn(&Nsid) -> StorageResult<Cursor>> {
+        let takeoff = get_snapshot_static_neu::<TakeoffKey, TakeoffValue>(&self.global_snapshot())?
+            .ok_or(StorageError::BadStateError(
+                "Could not find jetstream takeoff time".to_string(),
+            ))?;
+        Ok(move |nsid: &Nsid| {
+            let first_seen = rollups
+                .get(CollectionFirstSeenKey::new(nsid).to_db_bytes()?)?
+                .as_deref()
+                .map(db_complete::<CollectionFirstSeenVal>)
+                .transpose()?;
+            Ok(match first_seen {
+                Some(seen) if seen.to_raw_u64() > takeoff.to_raw_u64() => seen,
+                _ => takeoff,
+            })
+        })
+    }
+
+    /// Pin the current keyspace instant for consistent reads across calls
+    ///
+    /// Returns the snapshot token and its expiry. The ttl is capped.
+    fn pin_snapshot(&self, ttl: Duration) -> QueryResult<(u64, SystemTime)> {
+        let ttl = ttl.min(MAX_PINNED_SNAPSHOT_TTL);
+        let now = Instant::now();
+        let mut pins = self.pins.lock().unwrap();
+        pins.retain(|_, pinned| pinned.expires_at > now);
+        if pins.len() >= MAX_PINNED_SNAPSHOTS {
+            return Err(QueryError::TooManySnapshots(MAX_PINNED_SNAPSHOTS));
+        }
+        let instant = self.keyspace.instant();
+        let pinned = pins.entry(instant).or_insert_with(|| PinnedSnapshot {
+            expires_at: now,
+            global: self.global.snapshot_at(instant),
+            feeds: self.feeds.snapshot_at(instant),
+            records: self.records.snapshot_at(instant),
+            rollups: self.rollups.snapshot_at(instant),
+        });
+        // a re-used instant gets its expiry extended, never shortened
+        pinned.expires_at = pinned.expires_at.max(now + ttl);
+        let expires_at = SystemTime::now() + pinned.expires_at.duration_since(now);
+        Ok((instant, expires_at))
+    }
+
+    /// Get a reader that only sees data from a previously pinned snapshot
+    fn at_snapshot(&self, token: u64) -> QueryResult<FjallReader> {
+        let now = Instant::now();
+        let mut pins = self.pins.lock().unwrap();
+        pins.retain(|_, pinned| pinned.expires_at > now);
+        let pinned = pins
+            .get(&token)
+            .ok_or(QueryError::SnapshotNotFound(token))?
+            .clone();
+        Ok(FjallReader {
+            pinned: Some(pinned),
+            ..self.clone()
+        })
+    }
+
     fn describe_metrics(&self) {
         describe_gauge!(
             "storage_fjall_l0_run_count",
@@ -409,6 +1016,11 @@ impl FjallReader {
             Unit::Count,
             "fjall keyspace sequence"
         );
+        describe_counter!(
+            "storage_records_skipped_corrupt",
+            Unit::Count,
+            "feed entries or records skipped at query time because they failed to decode"
+        );
     }
 
     fn get_storage_stats(&self) -> StorageResult<serde_json::Value> {
@@ -425,7 +1037,7 @@ impl FjallReader {
     }
 
     fn get_consumer_info(&self) -> StorageResult<ConsumerInfo> {
-        let global = self.global.snapshot();
+        let global = self.global_snapshot();
 
         let endpoint =
             get_snapshot_static_neu::<JetstreamEndpointKey, JetstreamEndpointValue>(&global)?
@@ -448,17 +1060,118 @@ impl FjallReader {
             get_snapshot_static_neu::<NewRollupCursorKey, NewRollupCursorValue>(&global)?
                 .map(|c| c.to_raw_u64());
 
+        let gap_detected =
+            get_snapshot_static_neu::<CursorGapKey, CursorGapValue>(&global)?.map(Into::into);
+
+        let day_ago = Cursor::at(SystemTime::now() - Duration::from_secs(86_400));
+        let mut overflowed_collections_24h = 0;
+        for kv in self
+            .rollups_snapshot()
+            .range(OverflowedCollectionsKey::new(day_ago.into()).range_to_prefix_end()?)
+        {
+            let (_, val_bytes) = kv?;
+            overflowed_collections_24h +=
+                db_complete::<OverflowedCollectionsVal>(&val_bytes)?.batches;
+        }
+
         Ok(ConsumerInfo::Jetstream {
             endpoint,
             started_at,
             latest_cursor,
             rollup_cursor,
+            overflowed_collections_24h,
+            gap_detected,
         })
     }
 
+    fn get_changes(
+        &self,
+        after: Option<Cursor>,
+        limit: usize,
+    ) -> StorageResult<(Vec<(Cursor, Vec<u8>)>, Option<Cursor>)> {
+        // the change feed is for chasing the live edge: it always reads the latest data
+        let trimmed = get_static_neu::<ChangesTrimmedKey, ChangesTrimmedValue>(&self.global)?;
+        let start = match after {
+            Some(after) => Bound::Excluded(after.to_db_bytes()?),
+            None => Bound::Unbounded,
+        };
+        let mut changes = Vec::new();
+        for kv in self
+            .changes
+            .range::<Vec<u8>, _>((start, Bound::Unbounded))
+            .take(limit)
+        {
+            let (key_bytes, val_bytes) = kv?;
+            changes.push((db_complete::<Cursor>(&key_bytes)?, val_bytes.to_vec()));
+        }
+        Ok((changes, trimmed))
+    }
+
+    fn get_watchlist_hits(
+        &self,
+        name: &str,
+        before: Option<Cursor>,
+        limit: usize,
+    ) -> StorageResult<Vec<WatchlistHit>> {
+        // like the change feed, watch hits aren't part of pinned snapshots
+        let start = Bound::Included(WatchHitKey::prefix(name).to_db_bytes()?);
+        let end = match before {
+            Some(before) => Bound::Excluded(WatchHitKey::at(name, before).to_db_bytes()?),
+            None => Bound::Excluded(WatchHitKey::prefix(name).as_prefix_range_end()?),
+        };
+        let records = self.records_snapshot();
+        let mut hits = Vec::new();
+        for kv in self.watch.range((start, end)).rev().take(limit) {
+            let (key_bytes, val_bytes) = kv?;
+            let key = db_complete::<WatchHitKey>(&key_bytes)?;
+            let val = db_complete::<WatchHitVal>(&val_bytes)?;
+            let location = key.location();
+            // the version that matched might be current, kept, or gone
+            let mut record = None;
+            for kv in records.prefix(location.to_db_bytes()?) {
+                let (_, location_val_bytes) = kv?;
+                let (meta, n) = RecordLocationMeta::from_db_bytes(&location_val_bytes)?;
+                if meta.cursor() == key.cursor() {
+                    record = Some(decode_record(location, meta, &location_val_bytes[n..])?);
+                    break;
+                }
+            }
+            hits.push(WatchlistHit {
+                cursor: key.cursor(),
+                did: location.did().clone(),
+                collection: location.collection().clone(),
+                rkey: location.rkey().clone(),
+                matched: val.matched,
+                record,
+            });
+        }
+        Ok(hits)
+    }
+
+    fn get_watchlist_counts(
+        &self,
+        name: &str,
+        since: HourTruncatedCursor,
+        until: Option<HourTruncatedCursor>,
+    ) -> StorageResult<Vec<(HourTruncatedCursor, u64)>> {
+        let start = WatchHourlyKey::new(name, since).to_db_bytes()?;
+        let end = match until {
+            Some(until) => Bound::Included(WatchHourlyKey::new(name, until).to_db_bytes()?),
+            None => Bound::Excluded(WatchHourlyKey::prefix(name).as_prefix_range_end()?),
+        };
+        let mut counts = Vec::new();
+        for kv in self.watch.range((Bound::Included(start), end)) {
+            let (key_bytes, val_bytes) = kv?;
+            let key = db_complete::<WatchHourlyKey>(&key_bytes)?;
+            let val = db_complete::<WatchHourlyVal>(&val_bytes)?;
+            counts.push((key.hour(), val.hits));
+        }
+        Ok(counts)
+    }
+
     fn get_earliest_hour(&self, rollups: Option<&Snapshot>) -> StorageResult<HourTruncatedCursor> {
         let cursor = rollups
-            .unwrap_or(&self.rollups.snapshot())
+            .unwrap_or(&self.rollups_snapshot())
             .prefix(HourlyRollupStaticPrefix::default().to_db_bytes()?)
             .next()
             .transpose()?
@@ -475,8 +1188,14 @@ impl FjallReader {
         limit: usize,
         cursor: Option<Vec<u8>>,
         buckets: Vec<CursorBucket>,
-    ) -> StorageResult<(Vec<NsidCount>, Option<Vec<u8>>)> {
-        let cursor_nsid = cursor.as_deref().map(db_complete::<Nsid>).transpose()?;
+        budget: &mut ScanBudget,
+    ) -> QueryResult<(Vec<NsidCount>, Option<Vec<u8>>)> {
+        let cursor_nsid = cursor
+            .as_deref()
+            .map(db_complete::<Nsid>)
+            .transpose()
+            .map_err(QueryError::BadCursor)?;
+        let tracked_since = self.tracked_since(snapshot.clone())?;
         let mut iters: Vec<Peekable<NsidCounter>> = Vec::with_capacity(buckets.len());
         for bucket in &buckets {
             let it: NsidCounter = match bucket {
@@ -511,6 +1230,11 @@ impl FjallReader {
         let mut out = Vec::new();
         let mut current_nsid = None;
         for _ in 0..limit {
+            // each bucket can have a key for the next nsid. if the budget can't
+            // cover them, the page stops short at the last nsid finished.
+            if !budget.reserve(iters.len()) {
+                break;
+            }
             // double-scan the iters for each element: this could be eliminated but we're starting simple.
             // first scan: find the lowest nsid
             // second scan: take + merge, and advance all iters with lowest nsid
@@ -538,7 +1262,10 @@ impl FjallReader {
                     merged.merge(&counts);
                 }
             }
-            out.push(NsidCount::new(&nsid, &merged));
+            out.push(NsidCount::new(&nsid, &merged, tracked_since(&nsid)?));
+        }
+        if budget.ran_out() && out.is_empty() {
+            return Err(QueryError::OverScanBudget); // no progress to page on from
         }
 
         let next_cursor = current_nsid.map(|s| s.to_db_bytes()).transpose()?;
@@ -551,64 +1278,105 @@ impl FjallReader {
         limit: usize,
         order: OrderCollectionsBy,
         buckets: Vec<CursorBucket>,
-    ) -> StorageResult<Vec<NsidCount>> {
+        budget: &mut ScanBudget,
+    ) -> QueryResult<(Vec<NsidCount>, Option<Vec<u8>>)> {
+        let cursor = match &order {
+            OrderCollectionsBy::RecordsCreated { cursor } => cursor,
+            OrderCollectionsBy::DidsEstimate { cursor } => cursor,
+            OrderCollectionsBy::Lexi { .. } => unreachable!(),
+        };
+        let after = cursor
+            .as_deref()
+            .map(db_complete::<RankCursor>)
+            .transpose()
+            .map_err(QueryError::BadCursor)?;
+
+        // rankings are ordered by count, then nsid, both descending: exactly a
+        // reverse scan of the rank keys. a merged count is never lower than its
+        // count in any one bucket, so anything after the cursor overall is also
+        // after it in every bucket (an equal count means the other buckets added
+        // nothing), and each bucket's scan can pick up right after the cursor's key.
+        //
+        // the scans only find candidates though: with more than one bucket, a
+        // candidate is ranked on its total over all of them, or a page could
+        // rank it on whichever buckets happened to yield it.
+        let total_over = (buckets.len() > 1).then(|| buckets.clone());
+
         let mut iters: Vec<NsidCounter> = Vec::with_capacity(buckets.len());
 
         for bucket in buckets {
             let it: NsidCounter = match (&order, bucket) {
-                (OrderCollectionsBy::RecordsCreated, CursorBucket::Hour(t)) => {
+                (OrderCollectionsBy::RecordsCreated { .. }, CursorBucket::Hour(t)) => {
                     get_lookup_iter::<HourlyRecordsKey>(
                         snapshot.clone(),
                         HourlyRecordsKey::start(t)?,
-                        HourlyRecordsKey::end(t)?,
+                        match &after {
+                            Some(a) => HourlyRecordsKey::end_before(t, a.rank(), a.nsid())?,
+                            None => HourlyRecordsKey::end(t)?,
+                        },
                         Arc::new({
                             move |collection| HourlyRollupKey::new(t, collection).to_db_bytes()
                         }),
                     )?
                 }
-                (OrderCollectionsBy::DidsEstimate, CursorBucket::Hour(t)) => {
+                (OrderCollectionsBy::DidsEstimate { .. }, CursorBucket::Hour(t)) => {
                     get_lookup_iter::<HourlyDidsKey>(
                         snapshot.clone(),
                         HourlyDidsKey::start(t)?,
-                        HourlyDidsKey::end(t)?,
+                        match &after {
+                            Some(a) => HourlyDidsKey::end_before(t, a.rank(), a.nsid())?,
+                            None => HourlyDidsKey::end(t)?,
+                        },
                         Arc::new({
                             move |collection| HourlyRollupKey::new(t, collection).to_db_bytes()
                         }),
                     )?
                 }
-                (OrderCollectionsBy::RecordsCreated, CursorBucket::Week(t)) => {
+                (OrderCollectionsBy::RecordsCreated { .. }, CursorBucket::Week(t)) => {
                     get_lookup_iter::<WeeklyRecordsKey>(
                         snapshot.clone(),
                         WeeklyRecordsKey::start(t)?,
-                        WeeklyRecordsKey::end(t)?,
+                        match &after {
+                            Some(a) => WeeklyRecordsKey::end_before(t, a.rank(), a.nsid())?,
+                            None => WeeklyRecordsKey::end(t)?,
+                        },
                         Arc::new({
                             move |collection| WeeklyRollupKey::new(t, collection).to_db_bytes()
                         }),
                     )?
                 }
-                (OrderCollectionsBy::DidsEstimate, CursorBucket::Week(t)) => {
+                (OrderCollectionsBy::DidsEstimate { .. }, CursorBucket::Week(t)) => {
                     get_lookup_iter::<WeeklyDidsKey>(
                         snapshot.clone(),
                         WeeklyDidsKey::start(t)?,
-                        WeeklyDidsKey::end(t)?,
+                        match &after {
+                            Some(a) => WeeklyDidsKey::end_before(t, a.rank(), a.nsid())?,
+                            None => WeeklyDidsKey::end(t)?,
+                        },
                         Arc::new({
                             move |collection| WeeklyRollupKey::new(t, collection).to_db_bytes()
                         }),
                     )?
                 }
-                (OrderCollectionsBy::RecordsCreated, CursorBucket::AllTime) => {
+                (OrderCollectionsBy::RecordsCreated { .. }, CursorBucket::AllTime) => {
                     get_lookup_iter::<AllTimeRecordsKey>(
                         snapshot.clone(),
                         AllTimeRecordsKey::start()?,
-                        AllTimeRecordsKey::end()?,
+                        match &after {
+                            Some(a) => AllTimeRecordsKey::end_before(a.rank(), a.nsid())?,
+                            None => AllTimeRecordsKey::end()?,
+                        },
                         Arc::new(|collection| AllTimeRollupKey::new(collection).to_db_bytes()),
                     )?
                 }
-                (OrderCollectionsBy::DidsEstimate, CursorBucket::AllTime) => {
+                (OrderCollectionsBy::DidsEstimate { .. }, CursorBucket::AllTime) => {
                     get_lookup_iter::<AllTimeDidsKey>(
                         snapshot.clone(),
                         AllTimeDidsKey::start()?,
-                        AllTimeDidsKey::end()?,
+                        match &after {
+                            Some(a) => AllTimeDidsKey::end_before(a.rank(), a.nsid())?,
+                            None => AllTimeDidsKey::end()?,
+                        },
                         Arc::new(|collection| AllTimeRollupKey::new(collection).to_db_bytes()),
                     )?
                 }
@@ -622,31 +1390,66 @@ impl FjallReader {
         // sort by requested order, take limit, discard all remaining
         //
         // this isn't guaranteed to be correct, but it will hopefully be close most of the time:
-        // - it's possible that some NSIDs might score low during some time-buckets, and miss being merged
+        // - an NSID that scores low in every time-bucket can miss being picked as a candidate,
+        //   even if its total would rank (candidates are always ranked on their full total)
         // - overfetching hopefully helps a bit by catching nsids near the threshold more often, but. yeah.
         //
-        // this thing is heavy, there's probably a better way
+        // this thing is heavy, there's probably a better way. a ranking from
+        // part of the candidates would be wrong, so running out of budget fails.
         let mut ranked: HashMap<Nsid, CountsValue> = HashMap::with_capacity(limit * 2);
         for iter in iters {
             for pair in iter.take((limit as f64 * 1.3).ceil() as usize) {
+                if !budget.spend() {
+                    return Err(QueryError::OverScanBudget);
+                }
                 let (nsid, get_counts) = pair?;
-                let counts = get_counts()?;
-                ranked.entry(nsid).or_default().merge(&counts);
+                if total_over.is_some() {
+                    ranked.entry(nsid).or_default();
+                } else {
+                    ranked.insert(nsid, get_counts()?);
+                }
             }
         }
-        let mut ranked: Vec<(Nsid, CountsValue)> = ranked.into_iter().collect();
-        match order {
-            OrderCollectionsBy::RecordsCreated => ranked.sort_by_key(|(_, c)| c.counts().creates),
-            OrderCollectionsBy::DidsEstimate => ranked.sort_by_key(|(_, c)| c.dids().estimate()),
-            OrderCollectionsBy::Lexi { .. } => unreachable!(),
+        if let Some(buckets) = total_over {
+            for (nsid, counts) in ranked.iter_mut() {
+                if !budget.reserve(buckets.len()) {
+                    return Err(QueryError::OverScanBudget);
+                }
+                *counts = sum_rollups(&snapshot, nsid, buckets.clone())?;
+            }
         }
-        let counts = ranked
+        let score: fn(&CountsValue) -> u64 = match order {
+            OrderCollectionsBy::RecordsCreated { .. } => |c| c.counts().creates,
+            OrderCollectionsBy::DidsEstimate { .. } => |c| microcosm_estimates::estimate(c.dids()),
+            OrderCollectionsBy::Lexi { .. } => unreachable!(),
+        };
+        // same order as the rank keys, so that ties come out the same way on every page
+        let mut ranked: Vec<(u64, Nsid, CountsValue)> = ranked
             .into_iter()
-            .rev()
-            .take(limit)
-            .map(|(nsid, cv)| NsidCount::new(&nsid, &cv))
+            .map(|(nsid, cv)| (score(&cv), nsid, cv))
+            .filter(|(rank, nsid, _)| match &after {
+                Some(a) => (*rank, nsid.as_str()) < (a.rank().into(), a.nsid().as_str()),
+                None => true,
+            })
             .collect();
-        Ok(counts)
+        ranked.sort_by(|(ra, na, _), (rb, nb, _)| (rb, nb.as_str()).cmp(&(ra, na.as_str())));
+        ranked.truncate(limit);
+
+        let next_cursor = if ranked.len() < limit {
+            None
+        } else {
+            ranked
+                .last()
+                .map(|(rank, nsid, _)| RankCursor::new((*rank).into(), nsid).to_db_bytes())
+                .transpose()?
+        };
+
+        let tracked_since = self.tracked_since(snapshot)?;
+        let counts = ranked
+            .into_iter()
+            .map(|(_, nsid, cv)| Ok(NsidCount::new(&nsid, &cv, tracked_since(&nsid)?)))
+            .collect::<StorageResult<_>>()?;
+        Ok((counts, next_cursor))
     }
 
     fn get_collections(
@@ -655,8 +1458,9 @@ impl FjallReader {
         order: OrderCollectionsBy,
         since: Option<HourTruncatedCursor>,
         until: Option<HourTruncatedCursor>,
-    ) -> StorageResult<(Vec<NsidCount>, Option<Vec<u8>>)> {
-        let snapshot = self.rollups.snapshot();
+        mut budget: ScanBudget,
+    ) -> QueryResult<(Vec<NsidCount>, Option<Vec<u8>>)> {
+        let snapshot = self.rollups_snapshot();
         let buckets = if let (None, None) = (since, until) {
             vec![CursorBucket::AllTime]
         } else {
@@ -671,12 +1475,9 @@ impl FjallReader {
         };
         match order {
             OrderCollectionsBy::Lexi { cursor } => {
-                self.get_lexi_collections(snapshot, limit, cursor, buckets)
+                self.get_lexi_collections(snapshot, limit, cursor, buckets, &mut budget)
             }
-            _ => Ok((
-                self.get_ordered_collections(snapshot, limit, order, buckets)?,
-                None,
-            )),
+            _ => self.get_ordered_collections(snapshot, limit, order, buckets, &mut budget),
         }
     }
 
@@ -687,7 +1488,8 @@ impl FjallReader {
         limit: usize,
         cursor: Option<Vec<u8>>,
         buckets: Vec<CursorBucket>,
-    ) -> StorageResult<(JustCount, Vec<PrefixChild>, Option<Vec<u8>>)> {
+        budget: &mut ScanBudget,
+    ) -> QueryResult<(JustCount, Vec<PrefixChild>, Option<Vec<u8>>)> {
         // let prefix_sub_with_null = prefix.as_str().to_string().to_db_bytes()?;
         let prefix_sub = String::sub_prefix(&prefix.terminated())?; // with trailing dot to ensure full segment match
         let cursor_child = cursor
@@ -698,7 +1500,9 @@ impl FjallReader {
                 let as_sub_prefix_with_null = decoded.to_db_bytes()?;
                 Ok::<_, EncodingError>(as_sub_prefix_with_null)
             })
-            .transpose()?;
+            .transpose()
+            .map_err(QueryError::BadCursor)?;
+        let tracked_since = self.tracked_since(snapshot.clone())?;
         let mut iters: Vec<NsidCounter> = Vec::with_capacity(buckets.len());
         for bucket in &buckets {
             let it: NsidCounter = match bucket {
@@ -781,7 +1585,9 @@ impl FjallReader {
             }
         }
         let mut current_child: Option<Child> = None;
-        for _ in 0..limit {
+        // the last child counted in full, where a page cut short by the budget ends
+        let mut finished_child: Option<Child> = None;
+        'children: for _ in 0..limit {
             // double-scan the iters for each element: this could be eliminated but we're starting simple.
             // first scan: find the lowest nsid
             // second scan: take + merge, and advance all iters with lowest nsid
@@ -808,13 +1614,19 @@ impl FjallReader {
                 while let Some(Ok((_, get_counts))) =
                     iter.next_if(|v| v.as_ref().unwrap().0 == child)
                 {
-                    let counts = get_counts()?;
-                    prefix_count.merge(&counts);
-                    merged.merge(&counts);
+                    if !budget.spend() {
+                        break 'children; // leave out the part-counted child
+                    }
+                    merged.merge(&get_counts()?);
                 }
             }
+            prefix_count.merge(&merged);
+            finished_child = Some(child.clone());
             items.push(match child {
-                Child::FullNsid(nsid) => PrefixChild::Collection(NsidCount::new(&nsid, &merged)),
+                Child::FullNsid(nsid) => {
+                    let since = tracked_since(&nsid)?;
+                    PrefixChild::Collection(NsidCount::new(&nsid, &merged, since))
+                }
                 Child::ChildPrefix(prefix) => {
                     PrefixChild::Prefix(PrefixCount::new(&prefix, &merged))
                 }
@@ -824,7 +1636,15 @@ impl FjallReader {
         // TODO: could serialize the prefix count (with sketch) into the cursor so that uniqs can actually count up?
         // ....er the sketch is probably too big
         // TODO: this is probably buggy on child-type boundaries bleh
-        let next_cursor = current_child
+        let next_child = if budget.ran_out() {
+            if finished_child.is_none() {
+                return Err(QueryError::OverScanBudget); // no progress to page on from
+            }
+            finished_child
+        } else {
+            current_child
+        };
+        let next_cursor = next_child
             .map(|s| s.into_inner().to_db_bytes())
             .transpose()?;
 
@@ -838,8 +1658,9 @@ impl FjallReader {
         order: OrderCollectionsBy,
         since: Option<HourTruncatedCursor>,
         until: Option<HourTruncatedCursor>,
-    ) -> StorageResult<(JustCount, Vec<PrefixChild>, Option<Vec<u8>>)> {
-        let snapshot = self.rollups.snapshot();
+        mut budget: ScanBudget,
+    ) -> QueryResult<(JustCount, Vec<PrefixChild>, Option<Vec<u8>>)> {
+        let snapshot = self.rollups_snapshot();
         let buckets = if let (None, None) = (since, until) {
             vec![CursorBucket::AllTime]
         } else {
@@ -854,10 +1675,41 @@ impl FjallReader {
         };
         match order {
             OrderCollectionsBy::Lexi { cursor } => {
-                self.get_lexi_prefix(snapshot, prefix, limit, cursor, buckets)
+                self.get_lexi_prefix(snapshot, prefix, limit, cursor, buckets, &mut budget)
             }
-            _ => todo!(),
+            _ => self.get_ordered_prefix(snapshot, prefix, limit, order, buckets, &mut budget),
+        }
+    }
+
+    /// Rank a prefix's children over the buckets
+    ///
+    /// There are no rank keys by prefix, so this merges every child from a full
+    /// lexi scan before sorting. Fine for a lexicon group, slow for a prefix
+    /// like `app.` with many children, and fails if the scan would go over
+    /// budget. Not pageable: the cursor is always None.
+    fn get_ordered_prefix(
+        &self,
+        snapshot: Snapshot,
+        prefix: NsidPrefix,
+        limit: usize,
+        order: OrderCollectionsBy,
+        buckets: Vec<CursorBucket>,
+        budget: &mut ScanBudget,
+    ) -> QueryResult<(JustCount, Vec<PrefixChild>, Option<Vec<u8>>)> {
+        let (total, mut children, _) =
+            self.get_lexi_prefix(snapshot, prefix, usize::MAX, None, buckets, budget)?;
+        if budget.ran_out() {
+            return Err(QueryError::OverScanBudget); // can't rank part of the children
         }
+        let score: fn(&PrefixChild) -> u64 = match order {
+            OrderCollectionsBy::RecordsCreated { .. } => |c| c.crud().0,
+            OrderCollectionsBy::DidsEstimate { .. } => |c| c.dids_estimate(),
+            OrderCollectionsBy::Lexi { .. } => unreachable!(),
+        };
+        // stable, so ties stay in lexi order
+        children.sort_by_key(|c| std::cmp::Reverse(score(c)));
+        children.truncate(limit);
+        Ok((total, children, None))
     }
 
     /// - step: output series time step, in seconds
@@ -881,7 +1733,7 @@ impl FjallReader {
         };
         let n_hours = (dt.as_micros() as u64) / HOUR_IN_MICROS;
         let mut counts_by_hour = Vec::with_capacity(n_hours as usize);
-        let snapshot = self.rollups.snapshot();
+        let snapshot = self.rollups_snapshot();
         for hour in (0..n_hours).map(|i| since.nth_next(i)) {
             let mut counts = Vec::with_capacity(collections.len());
             for nsid in &collections {
@@ -920,6 +1772,41 @@ impl FjallReader {
         Ok((output_hours, output_series))
     }
 
+    fn get_event_kinds(
+        &self,
+        since: HourTruncatedCursor,
+        until: Option<HourTruncatedCursor>,
+        step: u64,
+    ) -> StorageResult<(Vec<HourTruncatedCursor>, Vec<EventKindCounts>)> {
+        let until = until.unwrap_or_else(|| Cursor::at(SystemTime::now()).into());
+        let Ok(dt) = Cursor::from(until).duration_since(&Cursor::from(since)) else {
+            return Ok((vec![], vec![])); // empty: until < since
+        };
+        let n_hours = (dt.as_micros() as u64) / HOUR_IN_MICROS;
+        let step_hours = (step / (HOUR_IN_MICROS / 1_000_000)).max(1);
+        let n_steps = n_hours.div_ceil(step_hours);
+        let hours = (0..n_steps)
+            .map(|i| since.nth_next(i * step_hours))
+            .collect();
+        let mut kinds = vec![EventKindCounts::default(); n_steps as usize];
+        let end = since.nth_next(n_hours);
+        for kv in self
+            .rollups_snapshot()
+            .range(EventKindsKey::new(since).to_db_bytes()?..EventKindsKey::new(end).to_db_bytes()?)
+        {
+            let (key_bytes, val_bytes) = kv?;
+            let hour = db_complete::<EventKindsKey>(&key_bytes)?.hour();
+            let val = db_complete::<EventKindsVal>(&val_bytes)?;
+            let i = (hour.to_raw_u64() - since.to_raw_u64()) / HOUR_IN_MICROS / step_hours;
+            kinds[i as usize].merge(&EventKindCounts {
+                commits: val.commits,
+                identities: val.identities,
+                accounts: val.accounts,
+            });
+        }
+        Ok((hours, kinds))
+    }
+
     fn get_collection_counts(
         &self,
         collection: &Nsid,
@@ -927,42 +1814,153 @@ impl FjallReader {
         until: Option<HourTruncatedCursor>,
     ) -> StorageResult<JustCount> {
         // grab snapshots in case rollups happen while we're working
-        let rollups = self.rollups.snapshot();
+        let rollups = self.rollups_snapshot();
 
         let until = until.unwrap_or_else(|| Cursor::at(SystemTime::now()).into());
         let buckets = CursorBucket::buckets_spanning(since, until);
-        let mut total_counts = CountsValue::default();
-
-        for bucket in buckets {
-            let key = match bucket {
-                CursorBucket::Hour(t) => HourlyRollupKey::new(t, collection).to_db_bytes()?,
-                CursorBucket::Week(t) => WeeklyRollupKey::new(t, collection).to_db_bytes()?,
-                CursorBucket::AllTime => unreachable!(), // TODO: fall back on this if the time span spans the whole dataset?
-            };
-            let count = rollups
-                .get(&key)?
-                .as_deref()
-                .map(db_complete::<CountsValue>)
-                .transpose()?
-                .unwrap_or_default();
-            total_counts.merge(&count);
-        }
+        let total_counts = sum_rollups(&rollups, collection, buckets)?;
 
         Ok((&total_counts).into())
     }
 
+    fn get_unique_records(
+        &self,
+        collection: &Nsid,
+        since: HourTruncatedCursor,
+        until: Option<HourTruncatedCursor>,
+    ) -> StorageResult<Option<UniqueRecords>> {
+        if !self.unique_counts {
+            return Ok(None);
+        }
+        // the same hours as the rollups: up to but not including `until`
+        let until = until.unwrap_or_else(|| Cursor::at(SystemTime::now()).into());
+        let mut unique = UniqueRecords::default();
+        if until <= since {
+            return Ok(Some(unique));
+        }
+        let start = UniqueHourlyKey::new(collection, since).to_db_bytes()?;
+        let end = UniqueHourlyKey::new(collection, until).to_db_bytes()?;
+        for kv in self.unique.range(start..end) {
+            let (_, val_bytes) = kv?;
+            let hourly = db_complete::<UniqueHourlyVal>(&val_bytes)?;
+            unique.approximate_records += hourly.records;
+            unique.unchecked += hourly.unchecked;
+        }
+        Ok(Some(unique))
+    }
+
+    /// Counts over an arbitrary window, not aligned to hours
+    ///
+    /// Rolled-up hours that the window only partly covers are scaled down by
+    /// the fraction covered (their dids estimate is kept whole), and anything
+    /// after the rollup cursor is added up from the live counts.
+    fn get_collection_window_counts(
+        &self,
+        collection: &Nsid,
+        since: Cursor,
+        until: Option<Cursor>,
+    ) -> StorageResult<WindowCounts> {
+        let rollups = self.rollups_snapshot();
+        let rollup_cursor = get_snapshot_static_neu::<NewRollupCursorKey, NewRollupCursorValue>(
+            &self.global_snapshot(),
+        )?
+        .unwrap_or(Cursor::from_start())
+        .to_raw_u64();
+
+        let since = since.to_raw_u64();
+        let until = until
+            .unwrap_or_else(|| Cursor::at(SystemTime::now()))
+            .to_raw_u64();
+
+        let mut total_counts = CountsValue::default();
+        let mut prorated = false;
+        let mut live = false;
+
+        // rolled up: [since, rolled_until)
+        let rolled_until = until.min(rollup_cursor);
+        if since < rolled_until {
+            let mut part_of_hour = |hour: u64| -> StorageResult<()> {
+                let covered = (hour, (hour + HOUR_IN_MICROS).min(rollup_cursor));
+                let wanted = (since.max(hour), rolled_until.min(hour + HOUR_IN_MICROS));
+                let hour = HourTruncatedCursor::truncate_raw_u64(hour);
+                let counts = sum_rollups(&rollups, collection, vec![CursorBucket::Hour(hour)])?;
+                if wanted == covered {
+                    total_counts.merge(&counts);
+                } else {
+                    prorated = true;
+                    let scaled = counts.scaled(wanted.1 - wanted.0, covered.1 - covered.0);
+                    total_counts.merge(&scaled);
+                }
+                Ok(())
+            };
+            let first = HourTruncatedCursor::truncate(since);
+            let last = HourTruncatedCursor::truncate(rolled_until - 1);
+            part_of_hour(first)?;
+            if last > first {
+                part_of_hour(last)?;
+            }
+            if last > first + HOUR_IN_MICROS {
+                let whole = CursorBucket::buckets_spanning(
+                    HourTruncatedCursor::truncate_raw_u64(first + HOUR_IN_MICROS),
+                    HourTruncatedCursor::truncate_raw_u64(last),
+                );
+                total_counts.merge(&sum_rollups(&rollups, collection, whole)?);
+            }
+        }
+
+        // not rolled up yet: [since.max(rollup_cursor), until)
+        let live_since = Cursor::from_raw_u64(since.max(rollup_cursor));
+        for kv in rollups.range(LiveCountsKey::range_from_cursor(live_since)?) {
+            let (key_bytes, val_bytes) = kv?;
+            let key = db_complete::<LiveCountsKey>(&key_bytes)?;
+            if key.cursor().to_raw_u64() >= until {
+                break;
+            }
+            if key.collection() != collection {
+                continue;
+            }
+            live = true;
+            total_counts.merge(&db_complete::<CountsValue>(&val_bytes)?);
+        }
+
+        Ok(WindowCounts {
+            counts: (&total_counts).into(),
+            prorated,
+            live,
+        })
+    }
+
+    fn get_tracked_since(&self, collection: &Nsid) -> StorageResult<Cursor> {
+        self.tracked_since(self.rollups_snapshot())?(collection)
+    }
+
     fn get_records_by_collections(
         &self,
         collections: HashSet<Nsid>,
         limit: usize,
         expand_each_collection: bool,
-    ) -> StorageResult<Vec<UFOsRecord>> {
+        budget: ScanBudget,
+    ) -> StorageResult<(Vec<UFOsRecord>, usize, bool)> {
         if collections.is_empty() {
-            return Ok(vec![]);
+            return Ok((vec![], 0, false));
+        }
+        for collection in &collections {
+            self.popularity.record_read(collection);
         }
+        let feeds = self.feeds_snapshot();
+        let records = self.records_snapshot();
+        let skipped_corrupt = Rc::new(Cell::new(0));
+        let budget = Rc::new(Cell::new(budget));
         let mut record_iterators = Vec::new();
         for collection in collections {
-            let iter = RecordIterator::new(&self.feeds, self.records.clone(), &collection, limit)?;
+            let iter = RecordIterator::new(
+                &feeds,
+                records.clone(),
+                &collection,
+                limit,
+                skipped_corrupt.clone(),
+                budget.clone(),
+            )?;
             record_iterators.push(iter.peekable());
         }
         let mut merged = Vec::new();
@@ -997,22 +1995,367 @@ impl FjallReader {
             // yeah yeah whateverrrrrrrrrrrrrrrr
             merged.push(record_iterators[idx].next().unwrap().unwrap().unwrap());
         }
-        Ok(merged)
+        Ok((merged, skipped_corrupt.get(), budget.get().ran_out()))
+    }
+
+    fn sample_records(
+        &self,
+        collection: &Nsid,
+        n: usize,
+        seed: u64,
+        budget: ScanBudget,
+    ) -> StorageResult<Vec<UFOsRecord>> {
+        let feeds = self.feeds_snapshot();
+        let records = self.records_snapshot();
+        let prefix = NsidRecordFeedKey::from_prefix_to_db_bytes(collection)?;
+        let mut entries = feeds.prefix(&prefix);
+        let (Some(first), Some(last)) = (entries.next(), entries.next_back()) else {
+            return Ok(vec![]);
+        };
+        let oldest = db_complete::<NsidRecordFeedKey>(&first?.0)?.cursor();
+        let newest = db_complete::<NsidRecordFeedKey>(&last?.0)?.cursor();
+        let skipped_corrupt = Rc::new(Cell::new(0));
+        let budget = Rc::new(Cell::new(budget));
+        sample_by_probes((oldest, newest), n, seed, |at| {
+            RecordIterator::starting_at(
+                &feeds,
+                records.clone(),
+                collection,
+                at,
+                1,
+                skipped_corrupt.clone(),
+                budget.clone(),
+            )?
+            .next()
+            .transpose()
+            .map(Option::flatten)
+        })
+    }
+
+    fn search_records(
+        &self,
+        query: &str,
+        collection: Option<&Nsid>,
+        limit: usize,
+    ) -> QueryResult<Vec<UFOsRecord>> {
+        let Some(search) = &self.search else {
+            return Err(StorageError::Unsupported("record search without --search").into());
+        };
+        let records = self.records_snapshot();
+        let mut found = Vec::new();
+        for (did, collection, rkey) in search.search(query, collection, limit)? {
+            let location_key =
+                RecordLocationKey::from_pair(did, DbConcat::from_pair(collection, rkey));
+            let Some(val_bytes) = records.get(location_key.to_db_bytes()?)? else {
+                // deleted or trimmed since it was indexed
+                continue;
+            };
+            let (meta, n) = RecordLocationMeta::from_db_bytes(&val_bytes)?;
+            found.push(decode_record(&location_key, meta, &val_bytes[n..])?);
+        }
+        Ok(found)
+    }
+
+    fn get_record_versions(
+        &self,
+        did: &Did,
+        collection: &Nsid,
+        rkey: &RecordKey,
+    ) -> StorageResult<Vec<UFOsRecord>> {
+        let location_key = RecordLocationKey::from_pair(
+            did.clone(),
+            DbConcat::from_pair(collection.clone(), rkey.clone()),
+        );
+        let mut versions = Vec::new();
+        for kv in self.records_snapshot().prefix(location_key.to_db_bytes()?) {
+            let (_, val_bytes) = kv?;
+            let (meta, n) = RecordLocationMeta::from_db_bytes(&val_bytes)?;
+            versions.push(decode_record(&location_key, meta, &val_bytes[n..])?);
+        }
+        // the db has the current version first, then older ones oldest-first.
+        // newest first overall puts the current one first either way.
+        versions.sort_by_key(|v| std::cmp::Reverse(v.cursor.to_raw_u64()));
+        Ok(versions)
+    }
+
+    fn get_account_records(
+        &self,
+        did: &Did,
+        collection: &Nsid,
+        range: (Bound<String>, Bound<String>),
+        limit: usize,
+        reverse: bool,
+    ) -> StorageResult<(Vec<UFOsRecord>, bool)> {
+        // rkeys are length-prefixed in the key, so db order isn't rkey order:
+        // collect the matching ones and sort. one account's sampled records in
+        // one collection is never very many.
+        let prefix = DbConcat::from_pair(did.clone(), collection.clone()).to_db_bytes()?;
+        let mut found = Vec::new();
+        for kv in self.records_snapshot().prefix(prefix) {
+            let (key_bytes, val_bytes) = kv?;
+            let (location_key, n) = RecordLocationKey::from_db_bytes(&key_bytes)?;
+            if n < key_bytes.len() {
+                continue; // an older version
+            }
+            let rkey = location_key.rkey().to_string();
+            if range.contains(&rkey) {
+                found.push((rkey, location_key, val_bytes));
+            }
+        }
+        found.sort_by(|(a, _, _), (b, _, _)| a.cmp(b));
+        if reverse {
+            found.reverse();
+        }
+        let more = found.len() > limit;
+        let mut records = Vec::with_capacity(limit.min(found.len()));
+        for (_, location_key, val_bytes) in found.into_iter().take(limit) {
+            let (meta, n) = RecordLocationMeta::from_db_bytes(&val_bytes)?;
+            records.push(decode_record(&location_key, meta, &val_bytes[n..])?);
+        }
+        Ok((records, more))
+    }
+
+    fn get_records_by_did(
+        &self,
+        did: &Did,
+        cursor: Option<Vec<u8>>,
+        limit: usize,
+        mut budget: ScanBudget,
+    ) -> StorageResult<(Vec<UFOsRecord>, Option<Vec<u8>>, bool)> {
+        // cursors are relative to the did's prefix, so they can't escape it
+        let prefix = RecordLocationKey::from_prefix_to_db_bytes(did)?;
+        let start = match &cursor {
+            Some(after) => Bound::Excluded([prefix.as_slice(), after].concat()),
+            None => Bound::Included(prefix.clone()),
+        };
+        let end = RecordLocationKey::prefix_range_end(did)?;
+        let mut records = Vec::with_capacity(limit);
+        let mut after = cursor.unwrap_or_default();
+        for kv in self.records_snapshot().range((start, Bound::Excluded(end))) {
+            if !budget.spend() {
+                return Ok((records, Some(after), true));
+            }
+            let (key_bytes, val_bytes) = kv?;
+            let (location_key, n) = RecordLocationKey::from_db_bytes(&key_bytes)?;
+            if n == key_bytes.len() {
+                if records.len() >= limit {
+                    return Ok((records, Some(after), false));
+                }
+                let (meta, n) = RecordLocationMeta::from_db_bytes(&val_bytes)?;
+                records.push(decode_record(&location_key, meta, &val_bytes[n..])?);
+            } // else it's an older version
+            after = key_bytes[prefix.len()..].to_vec();
+        }
+        Ok((records, None, false))
+    }
+
+    fn approximate_count(&self, prefix: CountPrefix) -> StorageResult<Option<u64>> {
+        let (snapshot, prefix) = match prefix {
+            CountPrefix::Collection(collection) => (
+                self.feeds_snapshot(),
+                NsidRecordFeedKey::from_prefix_to_db_bytes(&collection)?,
+            ),
+            CountPrefix::Account(did) => (
+                self.records_snapshot(),
+                RecordLocationKey::from_prefix_to_db_bytes(&did)?,
+            ),
+            CountPrefix::AccountCollection(did, collection) => (
+                self.records_snapshot(),
+                DbConcat::from_pair(did, collection).to_db_bytes()?,
+            ),
+        };
+        approximate_prefix_len(&snapshot, prefix).map(Some)
+    }
+
+    fn get_summary(&self) -> StorageResult<Summary> {
+        let rollups = self.rollups_snapshot();
+        let global = self.global_snapshot();
+        let latest_cursor =
+            get_snapshot_static_neu::<JetstreamCursorKey, JetstreamCursorValue>(&global)?;
+        let rollup_cursor =
+            get_snapshot_static_neu::<NewRollupCursorKey, NewRollupCursorValue>(&global)?;
+
+        let mut summary = Summary::default();
+
+        let mut all_time = CountsValue::default();
+        for kv in get_lexi_iter::<AllTimeRollupKey>(
+            &rollups,
+            AllTimeRollupKey::start()?,
+            AllTimeRollupKey::end()?,
+        )? {
+            let (_, get_counts) = kv?;
+            all_time.merge(&get_counts()?);
+            summary.collections += 1;
+        }
+        summary.records_created = all_time.counts().creates;
+        summary.dids_estimate = microcosm_estimates::estimate(all_time.dids());
+
+        if let Some(rollup_cursor) = rollup_cursor {
+            // the hour holding the rollup cursor is still filling up
+            let filling = HourTruncatedCursor::from(rollup_cursor);
+            let mut day = CountsValue::default();
+            for hours_ago in 0..24 {
+                if filling.to_raw_u64() < hours_ago * HOUR_IN_MICROS {
+                    break;
+                }
+                let hour = HourTruncatedCursor::from(Cursor::from_raw_u64(
+                    filling.to_raw_u64() - hours_ago * HOUR_IN_MICROS,
+                ));
+                let mut hour_counts = CountsValue::default();
+                for kv in get_lexi_iter::<HourlyRollupKey>(
+                    &rollups,
+                    HourlyRollupKey::start(hour)?,
+                    HourlyRollupKey::end(hour)?,
+                )? {
+                    let (_, get_counts) = kv?;
+                    hour_counts.merge(&get_counts()?);
+                }
+                if hours_ago == 1 {
+                    let CommitCounts {
+                        creates,
+                        updates,
+                        deletes,
+                    } = hour_counts.counts();
+                    summary.events_per_second = (creates + updates + deletes) as f64 / 3600.;
+                }
+                day.merge(&hour_counts);
+            }
+            summary.dids_estimate_24h = microcosm_estimates::estimate(day.dids());
+        }
+
+        if let Some(latest) = latest_cursor {
+            let now = Cursor::at(SystemTime::now()).to_raw_u64();
+            summary.consumer_lag_ms = Some(now.saturating_sub(latest.to_raw_u64()) / 1_000);
+            if let Some(rollup_cursor) = rollup_cursor {
+                summary.rollup_lag_ms = Some(
+                    latest
+                        .to_raw_u64()
+                        .saturating_sub(rollup_cursor.to_raw_u64())
+                        / 1_000,
+                );
+            }
+        }
+
+        Ok(summary)
+    }
+
+    fn get_growing_collections(
+        &self,
+        period: GrowthPeriod,
+        limit: usize,
+        min_previous: u64,
+    ) -> StorageResult<GrowthRanking> {
+        let rollups = self.rollups_snapshot();
+        let rollup_cursor = get_snapshot_static_neu::<NewRollupCursorKey, NewRollupCursorValue>(
+            &self.global_snapshot(),
+        )?
+        .unwrap_or(Cursor::from_start());
+
+        // the bucket holding the rollup cursor is still filling up
+        let (previous, current) = match period {
+            GrowthPeriod::Hour => {
+                let filling = HourTruncatedCursor::from(rollup_cursor);
+                if filling.to_raw_u64() < 2 * HOUR_IN_MICROS {
+                    return Ok(GrowthRanking::default());
+                }
+                let current = filling.prev();
+                (
+                    CursorBucket::Hour(current.prev()),
+                    CursorBucket::Hour(current),
+                )
+            }
+            GrowthPeriod::Week => {
+                let filling = WeekTruncatedCursor::from(rollup_cursor);
+                if filling.to_raw_u64() < 2 * WEEK_IN_MICROS {
+                    return Ok(GrowthRanking::default());
+                }
+                let current = filling.prev();
+                (
+                    CursorBucket::Week(current.prev()),
+                    CursorBucket::Week(current),
+                )
+            }
+        };
+
+        let bucket_creates = |bucket: &CursorBucket| -> StorageResult<HashMap<Nsid, u64>> {
+            let iter = match bucket {
+                CursorBucket::Hour(t) => get_lexi_iter::<HourlyRollupKey>(
+                    &rollups,
+                    HourlyRollupKey::start(*t)?,
+                    HourlyRollupKey::end(*t)?,
+                )?,
+                CursorBucket::Week(t) => get_lexi_iter::<WeeklyRollupKey>(
+                    &rollups,
+                    WeeklyRollupKey::start(*t)?,
+                    WeeklyRollupKey::end(*t)?,
+                )?,
+                CursorBucket::AllTime => unreachable!(),
+            };
+            iter.map(|kv| {
+                let (nsid, get_counts) = kv?;
+                Ok((nsid, get_counts()?.counts().creates))
+            })
+            .collect()
+        };
+        let previous_creates = bucket_creates(&previous)?;
+        let current_creates = bucket_creates(&current)?;
+
+        let min_previous = min_previous.max(1);
+        let mut collections: Vec<CollectionGrowth> = previous_creates
+            .into_iter()
+            .filter(|(_, previous)| *previous >= min_previous)
+            .map(|(nsid, previous)| {
+                let current = current_creates.get(&nsid).copied().unwrap_or(0);
+                CollectionGrowth {
+                    nsid: nsid.to_string(),
+                    previous,
+                    current,
+                    growth: current as f64 / previous as f64 - 1.0,
+                }
+            })
+            .collect();
+        // same tie-break as the other rankings: nsid, descending
+        collections.sort_by(|a, b| {
+            b.growth
+                .total_cmp(&a.growth)
+                .then_with(|| b.nsid.cmp(&a.nsid))
+        });
+        collections.truncate(limit);
+
+        let bucket_start = |bucket: &CursorBucket| match bucket {
+            CursorBucket::Hour(t) => t.to_raw_u64(),
+            CursorBucket::Week(t) => t.to_raw_u64(),
+            CursorBucket::AllTime => unreachable!(),
+        };
+        Ok(GrowthRanking {
+            previous_start: bucket_start(&previous),
+            current_start: bucket_start(&current),
+            collections,
+        })
     }
 
-    fn search_collections(&self, terms: Vec<String>) -> StorageResult<Vec<NsidCount>> {
+    fn search_collections(
+        &self,
+        terms: Vec<String>,
+        mut budget: ScanBudget,
+    ) -> StorageResult<(Vec<NsidCount>, bool)> {
         let start = AllTimeRollupKey::start()?;
         let end = AllTimeRollupKey::end()?;
         let mut matches = Vec::new();
         let limit = 16; // TODO: param
-        for kv in self.rollups.range((start, end)) {
+        let snapshot = self.rollups_snapshot();
+        let tracked_since = self.tracked_since(snapshot.clone())?;
+        for kv in snapshot.range((start, end)) {
+            if !budget.spend() {
+                return Ok((matches, true));
+            }
             let (key_bytes, val_bytes) = kv?;
             let key = db_complete::<AllTimeRollupKey>(&key_bytes)?;
             let nsid = key.collection();
             for term in &terms {
                 if nsid.contains(term) {
                     let counts = db_complete::<CountsValue>(&val_bytes)?;
-                    matches.push(NsidCount::new(nsid, &counts));
+                    matches.push(NsidCount::new(nsid, &counts, tracked_since(nsid)?));
                     break;
                 }
             }
@@ -1020,8 +2363,7 @@ impl FjallReader {
                 break;
             }
         }
-        // TODO: indicate incomplete results
-        Ok(matches)
+        Ok((matches, false))
     }
 }
 
@@ -1045,24 +2387,39 @@ impl StoreReader for FjallReader {
         gauge!("storage_fjall_journal_count").set(self.keyspace.journal_count() as f64);
         gauge!("storage_fjall_keyspace_sequence").set(self.keyspace.instant() as f64);
     }
-    async fn get_storage_stats(&self) -> StorageResult<serde_json::Value> {
+    async fn get_storage_stats(&self) -> QueryResult<serde_json::Value> {
+        let s = self.clone();
+        Ok(server::spawn_blocking(move || FjallReader::get_storage_stats(&s)).await??)
+    }
+    async fn get_consumer_info(&self) -> QueryResult<ConsumerInfo> {
+        let s = self.clone();
+        Ok(server::spawn_blocking(move || FjallReader::get_consumer_info(&s)).await??)
+    }
+    async fn get_changes(
+        &self,
+        after: Option<Cursor>,
+        limit: usize,
+    ) -> QueryResult<(Vec<(Cursor, Vec<u8>)>, Option<Cursor>)> {
         let s = self.clone();
-        tokio::task::spawn_blocking(move || FjallReader::get_storage_stats(&s)).await?
+        Ok(server::spawn_blocking(move || FjallReader::get_changes(&s, after, limit)).await??)
     }
-    async fn get_consumer_info(&self) -> StorageResult<ConsumerInfo> {
+    async fn pin_snapshot(&self, ttl: Duration) -> QueryResult<(u64, SystemTime)> {
         let s = self.clone();
-        tokio::task::spawn_blocking(move || FjallReader::get_consumer_info(&s)).await?
+        server::spawn_blocking(move || FjallReader::pin_snapshot(&s, ttl)).await?
+    }
+    fn at_snapshot(&self, token: u64) -> QueryResult<Box<dyn StoreReader>> {
+        Ok(Box::new(FjallReader::at_snapshot(self, token)?))
     }
     async fn get_collections(
         &self,
         limit: usize,
         order: OrderCollectionsBy,
-        since: Option<HourTruncatedCursor>,
-        until: Option<HourTruncatedCursor>,
-    ) -> StorageResult<(Vec<NsidCount>, Option<Vec<u8>>)> {
+        window: HourWindow,
+        budget: ScanBudget,
+    ) -> QueryResult<(Vec<NsidCount>, Option<Vec<u8>>)> {
         let s = self.clone();
-        tokio::task::spawn_blocking(move || {
-            FjallReader::get_collections(&s, limit, order, since, until)
+        server::spawn_blocking(move || {
+            FjallReader::get_collections(&s, limit, order, window.since(), window.until(), budget)
         })
         .await?
     }
@@ -1071,12 +2428,20 @@ impl StoreReader for FjallReader {
         prefix: NsidPrefix,
         limit: usize,
         order: OrderCollectionsBy,
-        since: Option<HourTruncatedCursor>,
-        until: Option<HourTruncatedCursor>,
-    ) -> StorageResult<(JustCount, Vec<PrefixChild>, Option<Vec<u8>>)> {
+        window: HourWindow,
+        budget: ScanBudget,
+    ) -> QueryResult<(JustCount, Vec<PrefixChild>, Option<Vec<u8>>)> {
         let s = self.clone();
-        tokio::task::spawn_blocking(move || {
-            FjallReader::get_prefix(&s, prefix, limit, order, since, until)
+        server::spawn_blocking(move || {
+            FjallReader::get_prefix(
+                &s,
+                prefix,
+                limit,
+                order,
+                window.since(),
+                window.until(),
+                budget,
+            )
         })
         .await?
     }
@@ -1086,1062 +2451,5816 @@ impl StoreReader for FjallReader {
         since: HourTruncatedCursor,
         until: Option<HourTruncatedCursor>,
         step: u64,
-    ) -> StorageResult<(Vec<HourTruncatedCursor>, CollectionSerieses)> {
+    ) -> QueryResult<(Vec<HourTruncatedCursor>, CollectionSerieses)> {
         let s = self.clone();
-        tokio::task::spawn_blocking(move || {
+        Ok(server::spawn_blocking(move || {
             FjallReader::get_timeseries(&s, collections, since, until, step)
         })
-        .await?
+        .await??)
+    }
+    async fn get_event_kinds(
+        &self,
+        since: HourTruncatedCursor,
+        until: Option<HourTruncatedCursor>,
+        step: u64,
+    ) -> QueryResult<(Vec<HourTruncatedCursor>, Vec<EventKindCounts>)> {
+        let s = self.clone();
+        Ok(
+            server::spawn_blocking(move || FjallReader::get_event_kinds(&s, since, until, step))
+                .await??,
+        )
     }
     async fn get_collection_counts(
         &self,
         collection: &Nsid,
         since: HourTruncatedCursor,
         until: Option<HourTruncatedCursor>,
-    ) -> StorageResult<JustCount> {
+    ) -> QueryResult<JustCount> {
         let s = self.clone();
         let collection = collection.clone();
-        tokio::task::spawn_blocking(move || {
+        Ok(server::spawn_blocking(move || {
             FjallReader::get_collection_counts(&s, &collection, since, until)
         })
-        .await?
+        .await??)
+    }
+    async fn get_collection_window_counts(
+        &self,
+        collection: &Nsid,
+        since: Cursor,
+        until: Option<Cursor>,
+    ) -> QueryResult<WindowCounts> {
+        let s = self.clone();
+        let collection = collection.clone();
+        Ok(server::spawn_blocking(move || {
+            FjallReader::get_collection_window_counts(&s, &collection, since, until)
+        })
+        .await??)
+    }
+    async fn get_unique_records(
+        &self,
+        collection: &Nsid,
+        since: HourTruncatedCursor,
+        until: Option<HourTruncatedCursor>,
+    ) -> QueryResult<Option<UniqueRecords>> {
+        let s = self.clone();
+        let collection = collection.clone();
+        Ok(server::spawn_blocking(move || {
+            FjallReader::get_unique_records(&s, &collection, since, until)
+        })
+        .await??)
+    }
+    async fn get_tracked_since(&self, collection: &Nsid) -> QueryResult<Cursor> {
+        let s = self.clone();
+        let collection = collection.clone();
+        Ok(
+            server::spawn_blocking(move || FjallReader::get_tracked_since(&s, &collection))
+                .await??,
+        )
     }
     async fn get_records_by_collections(
         &self,
         collections: HashSet<Nsid>,
         limit: usize,
         expand_each_collection: bool,
-    ) -> StorageResult<Vec<UFOsRecord>> {
+        budget: ScanBudget,
+    ) -> QueryResult<(Vec<UFOsRecord>, usize, bool)> {
+        let s = self.clone();
+        Ok(server::spawn_blocking(move || {
+            FjallReader::get_records_by_collections(
+                &s,
+                collections,
+                limit,
+                expand_each_collection,
+                budget,
+            )
+        })
+        .await??)
+    }
+    async fn sample_records(
+        &self,
+        collection: &Nsid,
+        n: usize,
+        seed: u64,
+        budget: ScanBudget,
+    ) -> QueryResult<Vec<UFOsRecord>> {
+        let s = self.clone();
+        let collection = collection.clone();
+        Ok(server::spawn_blocking(move || {
+            FjallReader::sample_records(&s, &collection, n, seed, budget)
+        })
+        .await??)
+    }
+    async fn search_records(
+        &self,
+        query: &str,
+        collection: Option<&Nsid>,
+        limit: usize,
+    ) -> QueryResult<Vec<UFOsRecord>> {
         let s = self.clone();
-        tokio::task::spawn_blocking(move || {
-            FjallReader::get_records_by_collections(&s, collections, limit, expand_each_collection)
+        let query = query.to_string();
+        let collection = collection.cloned();
+        server::spawn_blocking(move || {
+            FjallReader::search_records(&s, &query, collection.as_ref(), limit)
         })
         .await?
     }
-    async fn search_collections(&self, terms: Vec<String>) -> StorageResult<Vec<NsidCount>> {
+    async fn get_record_versions(
+        &self,
+        did: &Did,
+        collection: &Nsid,
+        rkey: &RecordKey,
+    ) -> QueryResult<Vec<UFOsRecord>> {
+        let s = self.clone();
+        let did = did.clone();
+        let collection = collection.clone();
+        let rkey = rkey.clone();
+        Ok(server::spawn_blocking(move || {
+            FjallReader::get_record_versions(&s, &did, &collection, &rkey)
+        })
+        .await??)
+    }
+    async fn get_account_records(
+        &self,
+        did: &Did,
+        collection: &Nsid,
+        range: (Bound<String>, Bound<String>),
+        limit: usize,
+        reverse: bool,
+    ) -> QueryResult<(Vec<UFOsRecord>, bool)> {
+        let s = self.clone();
+        let did = did.clone();
+        let collection = collection.clone();
+        Ok(server::spawn_blocking(move || {
+            FjallReader::get_account_records(&s, &did, &collection, range, limit, reverse)
+        })
+        .await??)
+    }
+    async fn get_records_by_did(
+        &self,
+        did: &Did,
+        cursor: Option<Vec<u8>>,
+        limit: usize,
+        budget: ScanBudget,
+    ) -> QueryResult<(Vec<UFOsRecord>, Option<Vec<u8>>, bool)> {
+        let s = self.clone();
+        let did = did.clone();
+        Ok(server::spawn_blocking(move || {
+            FjallReader::get_records_by_did(&s, &did, cursor, limit, budget)
+        })
+        .await??)
+    }
+    async fn approximate_count(&self, prefix: CountPrefix) -> QueryResult<Option<u64>> {
+        let s = self.clone();
+        Ok(server::spawn_blocking(move || FjallReader::approximate_count(&s, prefix)).await??)
+    }
+    async fn get_summary(&self) -> QueryResult<Summary> {
+        let s = self.clone();
+        Ok(server::spawn_blocking(move || FjallReader::get_summary(&s)).await??)
+    }
+    async fn get_growing_collections(
+        &self,
+        period: GrowthPeriod,
+        limit: usize,
+        min_previous: u64,
+    ) -> QueryResult<GrowthRanking> {
+        let s = self.clone();
+        Ok(server::spawn_blocking(move || {
+            FjallReader::get_growing_collections(&s, period, limit, min_previous)
+        })
+        .await??)
+    }
+    async fn search_collections(
+        &self,
+        terms: Vec<String>,
+        budget: ScanBudget,
+    ) -> QueryResult<(Vec<NsidCount>, bool)> {
+        let s = self.clone();
+        Ok(
+            server::spawn_blocking(move || FjallReader::search_collections(&s, terms, budget))
+                .await??,
+        )
+    }
+    async fn get_watchlist_hits(
+        &self,
+        name: &str,
+        before: Option<Cursor>,
+        limit: usize,
+    ) -> QueryResult<Vec<WatchlistHit>> {
+        let s = self.clone();
+        let name = name.to_string();
+        Ok(server::spawn_blocking(move || {
+            FjallReader::get_watchlist_hits(&s, &name, before, limit)
+        })
+        .await??)
+    }
+    async fn get_watchlist_counts(
+        &self,
+        name: &str,
+        since: HourTruncatedCursor,
+        until: Option<HourTruncatedCursor>,
+    ) -> QueryResult<Vec<(HourTruncatedCursor, u64)>> {
         let s = self.clone();
-        tokio::task::spawn_blocking(move || FjallReader::search_collections(&s, terms)).await?
+        let name = name.to_string();
+        Ok(server::spawn_blocking(move || {
+            FjallReader::get_watchlist_counts(&s, &name, since, until)
+        })
+        .await??)
     }
 }
 
 #[derive(Clone)]
 pub struct FjallWriter {
     bg_taken: Arc<AtomicBool>,
+    schedule: Arc<BackgroundSchedule>,
+    denylist: Arc<RwLock<Denylist>>,
+    redactor: Option<Arc<Redactor>>,
+    transformer: Option<Arc<Transformer>>,
+    watchlists: Option<Arc<Watchlists>>,
+    search: Option<SearchIndex>,
+    unique_counts: bool,
+    dirty: Option<DirtyTap>,
+    record_diffs: bool,
+    keep_versions: Arc<HashMap<Nsid, usize>>,
+    change_feed: Option<Duration>,
+    popularity: ReadPopularity,
+    popular_trim_multiplier: usize,
+    randomness: Randomness,
+    noisy: NoisyDids,
+    plugins: IngestPlugins,
+    nsid_limits: NsidLimits,
+    /// the jetstream shard id for each sharded collection
+    shards: Arc<HashMap<Nsid, String>>,
+    spill: FjallSpill,
     keyspace: Keyspace,
     global: PartitionHandle,
     feeds: PartitionHandle,
     records: PartitionHandle,
     rollups: PartitionHandle,
     queues: PartitionHandle,
+    changes: PartitionHandle,
+    audit: PartitionHandle,
+    /// serializes audit appends so positions stay unique
+    audit_lock: Arc<Mutex<()>>,
+    /// held by rollup steps and purges, which both read and rewrite rollup keys
+    rollup_lock: Arc<Mutex<()>>,
+    watch: PartitionHandle,
+    unique: PartitionHandle,
+    tombstones: PartitionHandle,
+    quarantine: PartitionHandle,
 }
 
 impl FjallWriter {
-    fn describe_metrics(&self) {
-        describe_histogram!(
-            "storage_insert_batch_db_batch_items",
-            Unit::Count,
-            "how many items are in the fjall batch for batched inserts"
-        );
-        describe_histogram!(
-            "storage_rollup_counts_db_batch_items",
-            Unit::Count,
-            "how many items are in the fjall batch for a timlies rollup"
-        );
-        describe_counter!(
-            "storage_delete_account_partial_commits",
-            Unit::Count,
-            "fjall checkpoint commits for cleaning up accounts with too many records"
-        );
-        describe_counter!(
-            "storage_delete_account_completions",
-            Unit::Count,
-            "total count of account deletes handled"
-        );
-        describe_counter!(
-            "storage_delete_account_records_deleted",
-            Unit::Count,
-            "total records deleted when handling account deletes"
-        );
-        describe_histogram!(
-            "storage_trim_dirty_nsids",
-            Unit::Count,
-            "number of NSIDs trimmed"
-        );
-        describe_histogram!(
-            "storage_trim_duration",
-            Unit::Microseconds,
-            "how long it took to trim the dirty NSIDs"
-        );
-        describe_counter!(
-            "storage_trim_removed",
-            Unit::Count,
-            "how many records were removed during trim"
-        );
+    /// A copy of a batch for the change feed, with only what this instance would store
+    ///
+    /// Denied collections' records are dropped (their counts stay if they're
+    /// kept), and records are redacted and transformed.
+    fn publishable<const LIMIT: usize>(
+        &self,
+        event_batch: &EventBatch<LIMIT>,
+        denylist: &Denylist,
+    ) -> StorageResult<EventBatch<LIMIT>> {
+        let mut published = event_batch.clone();
+        published
+            .commits_by_nsid
+            .retain(|nsid, _| denylist.check(nsid).is_none_or(|rule| rule.keep_counts));
+        for (nsid, commits) in published.commits_by_nsid.iter_mut() {
+            if denylist.check(nsid).is_some() {
+                commits.commits.clear();
+                continue;
+            }
+            for commit in commits.commits.iter_mut() {
+                if let CommitAction::Put(put) = &mut commit.action {
+                    self.prepare_record(nsid, put)?;
+                }
+            }
+        }
+        Ok(published)
     }
-    fn rollup_delete_account(
-        &mut self,
-        cursor: Cursor,
-        key_bytes: &[u8],
-        val_bytes: &[u8],
-    ) -> StorageResult<usize> {
-        let did = db_complete::<DeleteAccountQueueVal>(val_bytes)?;
-        self.delete_account(&did)?;
+
+    /// Redact, then transform, a record in place, the way it will be stored
+    ///
+    /// Returns the redaction config version and the transforms that ran, if any.
+    fn prepare_record(
+        &self,
+        collection: &Nsid,
+        put: &mut PutAction,
+    ) -> StorageResult<(Option<u32>, Option<Vec<String>>)> {
+        let mut redaction_version = None;
+        if let Some(redactor) = &self.redactor {
+            if let Some((redacted, version)) = redactor
+                .redact(collection, &put.record)
+                .map_err(EncodingError::JsonError)?
+            {
+                put.record = redacted;
+                redaction_version = Some(version);
+            }
+        }
+        let mut transforms = None;
+        if let Some(transformer) = &self.transformer {
+            if let Some((transformed, applied)) = transformer
+                .transform(collection, &put.record)
+                .map_err(EncodingError::JsonError)?
+            {
+                put.record = transformed;
+                transforms = Some(applied);
+            }
+        }
+        Ok((redaction_version, transforms))
+    }
+
+    /// Drop published batches that are past the change feed's retention
+    fn trim_changes(&self) -> StorageResult<usize> {
+        let Some(retention) = self.change_feed else {
+            return Ok(0);
+        };
+        let cutoff = Cursor::at(SystemTime::now() - retention);
         let mut batch = self.keyspace.batch();
-        batch.remove(&self.queues, key_bytes);
-        insert_batch_static_neu::<NewRollupCursorKey>(&mut batch, &self.global, cursor)?;
+        let mut trimmed = None;
+        for kv in self.changes.range(..cutoff.to_db_bytes()?) {
+            let (key_bytes, _) = kv?;
+            trimmed = Some(db_complete::<Cursor>(&key_bytes)?);
+            batch.remove(&self.changes, key_bytes);
+        }
+        let Some(trimmed) = trimmed else {
+            return Ok(0);
+        };
+        let n = batch.len();
+        insert_batch_static_neu::<ChangesTrimmedKey>(&mut batch, &self.global, trimmed)?;
         batch.commit()?;
-        Ok(1)
+        Ok(n)
     }
 
-    fn rollup_live_counts(
-        &mut self,
-        timelies: impl Iterator<Item = Result<(fjall::Slice, fjall::Slice), fjall::Error>>,
-        cursor_exclusive_limit: Option<Cursor>,
-        rollup_limit: usize,
-    ) -> StorageResult<(usize, HashSet<Nsid>)> {
-        // current strategy is to buffer counts in mem before writing the rollups
-        // we *could* read+write every single batch to rollup.. but their merge is associative so
-        // ...so save the db some work up front? is this worth it? who knows...
-
-        let mut dirty_nsids = HashSet::new();
-
-        #[derive(Eq, Hash, PartialEq)]
-        enum Rollup {
-            Hourly(HourTruncatedCursor),
-            Weekly(WeekTruncatedCursor),
-            AllTime,
+    /// Drop watchlist hits that are past the watchlist config's retention
+    ///
+    /// Only configured watchlists are trimmed: hits from a removed watchlist stay.
+    fn trim_watch_hits(&self) -> StorageResult<usize> {
+        let Some(watchlists) = &self.watchlists else {
+            return Ok(0);
+        };
+        let cutoff = Cursor::at(SystemTime::now() - watchlists.retention());
+        let mut batch = self.keyspace.batch();
+        for name in watchlists.names() {
+            let start = WatchHitKey::prefix(name).to_db_bytes()?;
+            let end = WatchHitKey::at(name, cutoff).to_db_bytes()?;
+            for kv in self.watch.range(start..end) {
+                let (key_bytes, _) = kv?;
+                batch.remove(&self.watch, key_bytes);
+            }
         }
+        let n = batch.len();
+        batch.commit()?;
+        Ok(n)
+    }
 
+    /// Drop tombstones of deletes older than [`MAX_TOMBSTONE_AGE`]
+    ///
+    /// A record deleted again since has a newer tombstone, which stays.
+    fn trim_tombstones(&self) -> StorageResult<usize> {
+        let cutoff = Cursor::at(SystemTime::now() - MAX_TOMBSTONE_AGE);
+        let start = TombstoneExpiryKey::at(Cursor::from_start()).to_db_bytes()?;
+        let end = TombstoneExpiryKey::at(cutoff).to_db_bytes()?;
         let mut batch = self.keyspace.batch();
-        let mut cursors_advanced = 0;
-        let mut last_cursor = Cursor::from_start();
-        let mut counts_by_rollup: HashMap<(Nsid, Rollup), CountsValue> = HashMap::new();
-
-        for (i, kv) in timelies.enumerate() {
-            if i >= rollup_limit {
-                break;
+        let mut trimmed = 0;
+        for kv in self.tombstones.range(start..end) {
+            let (key_bytes, _) = kv?;
+            let expiry = db_complete::<TombstoneExpiryKey>(&key_bytes)?;
+            let deleted_at = expiry.cursor();
+            let tombstone_key = RecordTombstoneKey::new(expiry.into_location()).to_db_bytes()?;
+            if let Some(val_bytes) = self.tombstones.get(&tombstone_key)? {
+                let latest = db_complete::<RecordTombstoneVal>(&val_bytes)?;
+                if latest.to_raw_u64() <= deleted_at.to_raw_u64() {
+                    batch.remove(&self.tombstones, tombstone_key);
+                    trimmed += 1;
+                }
             }
-
-            let (key_bytes, val_bytes) = kv?;
-            let key = db_complete::<LiveCountsKey>(&key_bytes)?;
-
-            if cursor_exclusive_limit
-                .map(|limit| key.cursor() > limit)
-                .unwrap_or(false)
-            {
-                break;
+            batch.remove(&self.tombstones, key_bytes);
+            if batch.len() >= MAX_BATCHED_TRIM_ITEMS {
+                batch.commit()?;
+                batch = self.keyspace.batch();
             }
-
-            dirty_nsids.insert(key.collection().clone());
-
-            batch.remove(&self.rollups, key_bytes);
-            let val = db_complete::<CountsValue>(&val_bytes)?;
-            counts_by_rollup
-                .entry((
-                    key.collection().clone(),
-                    Rollup::Hourly(key.cursor().into()),
-                ))
-                .or_default()
-                .merge(&val);
-            counts_by_rollup
-                .entry((
-                    key.collection().clone(),
-                    Rollup::Weekly(key.cursor().into()),
-                ))
-                .or_default()
-                .merge(&val);
-            counts_by_rollup
-                .entry((key.collection().clone(), Rollup::AllTime))
-                .or_default()
-                .merge(&val);
-
-            cursors_advanced += 1;
-            last_cursor = key.cursor();
         }
+        batch.commit()?;
+        Ok(trimmed)
+    }
 
-        // go through each new rollup thing and merge it with whatever might already be in the db
-        for ((nsid, rollup), counts) in counts_by_rollup {
-            let rollup_key_bytes = match rollup {
-                Rollup::Hourly(hourly_cursor) => {
-                    HourlyRollupKey::new(hourly_cursor, &nsid).to_db_bytes()?
-                }
-                Rollup::Weekly(weekly_cursor) => {
-                    WeeklyRollupKey::new(weekly_cursor, &nsid).to_db_bytes()?
-                }
-                Rollup::AllTime => AllTimeRollupKey::new(&nsid).to_db_bytes()?,
+    /// Check some random feed entries and records against each other
+    ///
+    /// A feed entry is dangling when its record is gone or has a newer
+    /// version: a few are normal until trim gets to them. A record is orphaned
+    /// when no feed entry points at its current version, which trim never fixes.
+    fn sample_consistency(&self, samples: usize) -> StorageResult<ConsistencySample> {
+        let mut sample = ConsistencySample::default();
+        for _ in 0..samples {
+            let Some((key_bytes, val_bytes)) = random_entry(&self.feeds, &self.randomness)? else {
+                break;
             };
-            let mut rolled: CountsValue = self
-                .rollups
-                .get(&rollup_key_bytes)?
-                .as_deref()
-                .map(db_complete::<CountsValue>)
+            let feed_key = db_complete::<NsidRecordFeedKey>(&key_bytes)?;
+            let feed_val = db_complete::<NsidRecordFeedVal>(&val_bytes)?;
+            let location_key_bytes =
+                RecordLocationKey::from((&feed_key, &feed_val)).to_db_bytes()?;
+            let current = self
+                .records
+                .get(&location_key_bytes)?
+                .map(|location_val_bytes| RecordLocationMeta::from_db_bytes(&location_val_bytes))
                 .transpose()?
-                .unwrap_or_default();
-
-            // now that we have values, we can know the exising ranks
-            let before_creates_count = rolled.counts().creates;
-            let before_dids_estimate = rolled.dids().estimate() as u64;
-
-            // update the rollup
-            rolled.merge(&counts);
-
-            // new ranks
-            let new_creates_count = rolled.counts().creates;
-            let new_dids_estimate = rolled.dids().estimate() as u64;
-
-            // update create-ranked secondary index if rank changed
-            if new_creates_count != before_creates_count {
-                let (old_k, new_k) = match rollup {
-                    Rollup::Hourly(cursor) => (
-                        HourlyRecordsKey::new(cursor, before_creates_count.into(), &nsid)
-                            .to_db_bytes()?,
-                        HourlyRecordsKey::new(cursor, new_creates_count.into(), &nsid)
-                            .to_db_bytes()?,
-                    ),
-                    Rollup::Weekly(cursor) => (
-                        WeeklyRecordsKey::new(cursor, before_creates_count.into(), &nsid)
-                            .to_db_bytes()?,
-                        WeeklyRecordsKey::new(cursor, new_creates_count.into(), &nsid)
-                            .to_db_bytes()?,
-                    ),
-                    Rollup::AllTime => (
-                        AllTimeRecordsKey::new(before_creates_count.into(), &nsid).to_db_bytes()?,
-                        AllTimeRecordsKey::new(new_creates_count.into(), &nsid).to_db_bytes()?,
-                    ),
-                };
-                // remove_weak is allowed here because the secondary ranking index only ever inserts once at a key
-                batch.remove_weak(&self.rollups, &old_k);
-                batch.insert(&self.rollups, &new_k, "");
+                .map(|(meta, _)| meta.cursor());
+            sample.feeds_checked += 1;
+            if current != Some(feed_key.cursor()) {
+                sample.feeds_dangling += 1;
             }
-
-            // update dids-ranked secondary index if rank changed
-            if new_dids_estimate != before_dids_estimate {
-                let (old_k, new_k) = match rollup {
-                    Rollup::Hourly(cursor) => (
-                        HourlyDidsKey::new(cursor, before_dids_estimate.into(), &nsid)
-                            .to_db_bytes()?,
-                        HourlyDidsKey::new(cursor, new_dids_estimate.into(), &nsid)
-                            .to_db_bytes()?,
-                    ),
-                    Rollup::Weekly(cursor) => (
-                        WeeklyDidsKey::new(cursor, before_dids_estimate.into(), &nsid)
-                            .to_db_bytes()?,
-                        WeeklyDidsKey::new(cursor, new_dids_estimate.into(), &nsid)
-                            .to_db_bytes()?,
-                    ),
-                    Rollup::AllTime => (
-                        AllTimeDidsKey::new(before_dids_estimate.into(), &nsid).to_db_bytes()?,
-                        AllTimeDidsKey::new(new_dids_estimate.into(), &nsid).to_db_bytes()?,
-                    ),
-                };
-                // remove_weak is allowed here because the secondary ranking index only ever inserts once at a key
-                batch.remove_weak(&self.rollups, &old_k);
-                batch.insert(&self.rollups, &new_k, "");
+        }
+        for _ in 0..samples {
+            let Some((key_bytes, val_bytes)) = random_entry(&self.records, &self.randomness)?
+            else {
+                break;
+            };
+            let (location_key, n) = RecordLocationKey::from_db_bytes(&key_bytes)?;
+            if n < key_bytes.len() {
+                continue; // an older version kept as history, not a current record
+            }
+            let (meta, _) = RecordLocationMeta::from_db_bytes(&val_bytes)?;
+            let feed_key_bytes =
+                NsidRecordFeedKey::from_pair(location_key.collection().clone(), meta.cursor())
+                    .to_db_bytes()?;
+            sample.records_checked += 1;
+            if !self.feeds.contains_key(&feed_key_bytes)? {
+                sample.records_orphaned += 1;
             }
-
-            // replace the main counts rollup
-            batch.insert(&self.rollups, &rollup_key_bytes, &rolled.to_db_bytes()?);
         }
-
-        insert_batch_static_neu::<NewRollupCursorKey>(&mut batch, &self.global, last_cursor)?;
-
-        histogram!("storage_rollup_counts_db_batch_items").record(batch.len() as f64);
-        batch.commit()?;
-        Ok((cursors_advanced, dirty_nsids))
+        Ok(sample)
     }
-}
 
-impl StoreWriter<FjallBackground> for FjallWriter {
-    fn background_tasks(&mut self, reroll: bool) -> StorageResult<FjallBackground> {
-        if self.bg_taken.swap(true, Ordering::SeqCst) {
-            return Err(StorageError::BackgroundAlreadyStarted);
-        }
-        if reroll {
-            log::info!("reroll: resetting rollup cursor...");
-            insert_static_neu::<NewRollupCursorKey>(&self.global, Cursor::from_start())?;
-            log::info!("reroll: clearing trim cursors...");
-            let mut batch = self.keyspace.batch();
-            for kv in self
-                .global
-                .prefix(TrimCollectionCursorKey::from_prefix_to_db_bytes(
-                    &Default::default(),
-                )?)
-            {
-                let (k, _) = kv?;
-                batch.remove(&self.global, k);
+    /// Check the next entries of a scrub pass, quarantining any that don't decode
+    ///
+    /// Returns the pass to continue with: a fresh pass over the next partition
+    /// once this one reaches the end.
+    fn scrub_step(&self, mut pass: ScrubPass, limit: usize) -> StorageResult<ScrubPass> {
+        let (name, partition) = match pass.partition {
+            Scrubbed::Feeds => ("feeds", &self.feeds),
+            Scrubbed::Records => ("records", &self.records),
+        };
+        let start = match &pass.after {
+            Some(after) => Bound::Excluded(after.to_vec()),
+            None => Bound::Unbounded,
+        };
+        let mut batch = self.keyspace.batch();
+        let mut seen = 0;
+        for kv in partition
+            .range::<Vec<u8>, _>((start, Bound::Unbounded))
+            .take(limit)
+        {
+            let (key_bytes, val_bytes) = kv?;
+            seen += 1;
+            for bytes in [&key_bytes, &val_bytes] {
+                pass.checksum.update((bytes.len() as u64).to_be_bytes());
+                pass.checksum.update(bytes);
             }
-            let n = batch.len();
-            batch.commit()?;
-            log::info!("reroll: cleared {n} trim cursors.");
+            let checked = match pass.partition {
+                Scrubbed::Feeds => check_feed_entry(&key_bytes, &val_bytes),
+                Scrubbed::Records => check_record_entry(&key_bytes, &val_bytes),
+            };
+            if let Err(e) = checked {
+                log::warn!("scrub: quarantining undecodable {name} entry {key_bytes:?}: {e}");
+                let quarantine_key = QuarantineKey::new(name, key_bytes.to_vec());
+                batch.insert(&self.quarantine, quarantine_key.to_db_bytes()?, val_bytes);
+                batch.remove(partition, key_bytes.clone());
+                pass.quarantined += 1;
+                counter!("storage_scrub_quarantined", "partition" => name).increment(1);
+            }
+            pass.after = Some(key_bytes);
         }
-        Ok(FjallBackground(self.clone()))
+        batch.commit()?;
+        pass.checked += seen as u64;
+        counter!("storage_scrub_checked", "partition" => name).increment(seen as u64);
+
+        if seen < limit {
+            let checksum: String = pass
+                .checksum
+                .finalize()
+                .iter()
+                .take(8)
+                .map(|b| format!("{b:02x}"))
+                .collect();
+            log::info!(
+                "scrub: finished a pass over {name} in {:?}: {} entries, {} quarantined, checksum {checksum}",
+                pass.started.elapsed(),
+                pass.checked,
+                pass.quarantined,
+            );
+            return Ok(ScrubPass::new(pass.partition.next()));
+        }
+        Ok(pass)
     }
 
-    fn insert_batch<const LIMIT: usize>(
-        &mut self,
-        event_batch: EventBatch<LIMIT>,
-    ) -> StorageResult<()> {
-        if event_batch.is_empty() {
-            return Ok(());
+    /// The latest commit cursor in a batch for each jetstream shard it has commits from
+    fn shard_cursors<const LIMIT: usize>(
+        &self,
+        event_batch: &EventBatch<LIMIT>,
+    ) -> HashMap<String, Cursor> {
+        let mut cursors: HashMap<String, Cursor> = HashMap::new();
+        for (nsid, commits) in &event_batch.commits_by_nsid {
+            let Some(shard) = self.shards.get(nsid) else {
+                continue;
+            };
+            for commit in &commits.commits {
+                let latest = cursors.entry(shard.clone()).or_insert(commit.cursor);
+                if commit.cursor > *latest {
+                    *latest = commit.cursor;
+                }
+            }
         }
+        cursors
+    }
 
-        let mut batch = self.keyspace.batch();
-
-        // would be nice not to have to iterate everything at once here
-        let latest = event_batch.latest_cursor().unwrap();
+    /// How many records to keep when trimming a collection
+    fn trim_limit(&self, collection: &Nsid) -> usize {
+        self.trim_limit_at(collection, Instant::now())
+    }
 
-        for (nsid, commits) in event_batch.commits_by_nsid {
-            for commit in commits.commits {
-                let location_key: RecordLocationKey = (&commit, &nsid).into();
+    fn trim_limit_at(&self, collection: &Nsid, now: Instant) -> usize {
+        if self.popular_trim_multiplier > 1 && self.popularity.is_popular_at(collection, now) {
+            counter!("storage_trim_popular").increment(1);
+            TRIM_KEEP_RECORDS * self.popular_trim_multiplier
+        } else {
+            TRIM_KEEP_RECORDS
+        }
+    }
 
-                match commit.action {
+    fn describe_metrics(&self) {
+        describe_counter!(
+            "storage_nsid_guarded",
+            Unit::Count,
+            "commits for collections over the NSID limits, by reason and action"
+        );
+        describe_histogram!(
+            "storage_insert_batch_db_batch_items",
+            Unit::Count,
+            "how many items are in the fjall batch for batched inserts"
+        );
+        describe_counter!(
+            "storage_insert_batch_stale_puts",
+            Unit::Count,
+            "puts skipped because the stored record or its delete is newer"
+        );
+        describe_counter!(
+            "storage_insert_batch_superseded_commits",
+            Unit::Count,
+            "commits skipped because a later commit in the same batch targets the same record"
+        );
+        describe_histogram!(
+            "storage_rollup_counts_db_batch_items",
+            Unit::Count,
+            "how many items are in the fjall batch for a timlies rollup"
+        );
+        describe_counter!(
+            "storage_delete_account_partial_commits",
+            Unit::Count,
+            "fjall checkpoint commits for cleaning up accounts with too many records"
+        );
+        describe_counter!(
+            "storage_delete_account_resumed",
+            Unit::Count,
+            "account deletes picked up from a checkpoint after an interruption"
+        );
+        describe_counter!(
+            "storage_delete_account_completions",
+            Unit::Count,
+            "total count of account deletes handled"
+        );
+        describe_counter!(
+            "storage_delete_account_records_deleted",
+            Unit::Count,
+            "total records deleted when handling account deletes"
+        );
+        describe_histogram!(
+            "storage_rollup_accounts_deleted",
+            Unit::Count,
+            "how many queued account deletes were handled in one rollup step"
+        );
+        describe_histogram!(
+            "storage_trim_dirty_nsids",
+            Unit::Count,
+            "number of NSIDs trimmed"
+        );
+        describe_histogram!(
+            "storage_trim_duration",
+            Unit::Microseconds,
+            "how long it took to trim the dirty NSIDs"
+        );
+        describe_counter!(
+            "storage_trim_removed",
+            Unit::Count,
+            "how many records were removed during trim"
+        );
+        describe_counter!(
+            "storage_trim_popular",
+            Unit::Count,
+            "collection trims that kept extra records because the collection is read often"
+        );
+        describe_gauge!(
+            "storage_read_popularity_tracked",
+            Unit::Count,
+            "collections with recent record reads, for trimming popular ones less"
+        );
+        describe_histogram!(
+            "storage_trim_range_removed",
+            Unit::Count,
+            "feed entries removed below the cutoff by a single collection trim"
+        );
+        describe_counter!(
+            "storage_trim_changes_removed",
+            Unit::Count,
+            "how many published batches were dropped from the change feed past retention"
+        );
+        describe_counter!(
+            "storage_trim_watch_hits_removed",
+            Unit::Count,
+            "how many watchlist hits were dropped past retention"
+        );
+        describe_counter!(
+            "storage_trim_tombstones_removed",
+            Unit::Count,
+            "how many deleted-record tombstones were dropped for being old enough"
+        );
+        describe_counter!(
+            "storage_watchlist_hits",
+            Unit::Count,
+            "records matching a watchlist as they were stored"
+        );
+        describe_counter!(
+            "storage_consistency_sampled",
+            Unit::Count,
+            "feed entries and records checked by the background consistency sampler"
+        );
+        describe_gauge!(
+            "storage_consistency_dangling_rate",
+            Unit::Count,
+            "fraction of recently sampled feed entries (dangling) or records (orphaned) that failed the check"
+        );
+        describe_counter!(
+            "storage_purge_collection_completions",
+            Unit::Count,
+            "total count of collections purged by an admin"
+        );
+        describe_gauge!(
+            "storage_purge_running",
+            Unit::Count,
+            "1 while a collection purge is removing things"
+        );
+        describe_gauge!(
+            "storage_purge_removed",
+            Unit::Count,
+            "feed entries, records and rollups removed so far by the current (or last) purge"
+        );
+        describe_counter!(
+            "storage_scrub_checked",
+            Unit::Count,
+            "entries checked for decodability by the background scrub"
+        );
+        describe_counter!(
+            "storage_scrub_quarantined",
+            Unit::Count,
+            "undecodable entries the background scrub moved to the quarantine partition"
+        );
+        describe_counter!(
+            "storage_rebuild_feeds_completions",
+            Unit::Count,
+            "total count of finished feeds rebuilds"
+        );
+        describe_counter!(
+            "storage_denylist_skipped_commits",
+            Unit::Count,
+            "commits not stored because their collection is denied"
+        );
+        describe_counter!(
+            "storage_record_diffs",
+            Unit::Count,
+            "updated records stored with a diff from their previous version"
+        );
+        describe_counter!(
+            "storage_record_versions_kept",
+            Unit::Count,
+            "previous record versions moved into history on update"
+        );
+        describe_histogram!(
+            "storage_fjall_batch_commit_time",
+            Unit::Microseconds,
+            "time to commit one write batch to fjall, by what the batch was for"
+        );
+        describe_counter!(
+            "storage_rollup_steps",
+            Unit::Count,
+            "rollup steps run by the background task"
+        );
+        describe_counter!(
+            "storage_rollup_items",
+            Unit::Count,
+            "live counts and account deletes rolled up"
+        );
+        describe_histogram!(
+            "storage_ingest_plugin_time",
+            Unit::Microseconds,
+            "time spent in each ingest plugin per batch"
+        );
+        describe_histogram!(
+            "storage_ingest_plugin_stored_time",
+            Unit::Microseconds,
+            "time spent in each ingest plugin per stored batch"
+        );
+    }
+    /// Diff an updated record against the version currently stored, if any
+    fn diff_from_stored(
+        &self,
+        location_key_bytes: &[u8],
+        record: &RawValue,
+    ) -> StorageResult<Option<String>> {
+        let Some(stored) = self.records.get(location_key_bytes)? else {
+            return Ok(None);
+        };
+        let previous: Box<RawValue> = db_complete::<RecordLocationVal>(&stored)?
+            .suffix
+            .try_into()?;
+        let diff = diff_records(&previous, record).map_err(EncodingError::JsonError)?;
+        if diff.is_some() {
+            counter!("storage_record_diffs").increment(1);
+        }
+        Ok(diff)
+    }
+    /// Store a record's watchlist hits, and count them for their hour
+    fn record_watch_hits(
+        &self,
+        batch: &mut FjallBatch,
+        nsid: &Nsid,
+        did: &Did,
+        rkey: &RecordKey,
+        cursor: Cursor,
+        record: &RawValue,
+        watch_hourly: &mut HashMap<(String, HourTruncatedCursor), u64>,
+    ) -> StorageResult<()> {
+        let Some(watchlists) = &self.watchlists else {
+            return Ok(());
+        };
+        for hit in watchlists
+            .check(nsid, record)
+            .map_err(EncodingError::JsonError)?
+        {
+            counter!("storage_watchlist_hits", "watchlist" => hit.name.clone()).increment(1);
+            let location = RecordLocationKey::from_pair(
+                did.clone(),
+                DbConcat::from_pair(nsid.clone(), rkey.clone()),
+            );
+            let hit_key = WatchHitKey::new(&hit.name, cursor, location);
+            let hit_val = WatchHitVal {
+                matched: hit.matched,
+            };
+            batch.insert(&self.watch, hit_key.to_db_bytes()?, hit_val.to_db_bytes()?);
+            *watch_hourly.entry((hit.name, cursor.into())).or_default() += 1;
+        }
+        Ok(())
+    }
+    /// Count one collection's creates of records it hasn't seen before, by hour
+    ///
+    /// Replays and re-creates find their record already in the seen set.
+    /// Creates the batch had to drop can't be checked, so they're counted as
+    /// unchecked in the hour of the batch's latest event.
+    fn count_unique<const LIMIT: usize>(
+        &self,
+        batch: &mut FjallBatch,
+        collection: &Nsid,
+        commits: &CollectionCommits<LIMIT>,
+        latest: Cursor,
+        seen_in_batch: &mut HashSet<Vec<u8>>,
+        hourly: &mut HashMap<(Nsid, HourTruncatedCursor), UniqueHourlyVal>,
+    ) -> StorageResult<()> {
+        let mut kept_creates = 0;
+        for commit in &commits.commits {
+            let CommitAction::Put(PutAction {
+                is_update: false, ..
+            }) = commit.action
+            else {
+                continue;
+            };
+            kept_creates += 1;
+            let key_bytes =
+                UniqueSeenKey::new(collection, &commit.did, &commit.rkey).to_db_bytes()?;
+            if seen_in_batch.contains(&key_bytes) || self.unique.contains_key(&key_bytes)? {
+                counter!("storage_unique_repeat_creates").increment(1);
+                continue;
+            }
+            batch.insert(&self.unique, &key_bytes, "");
+            seen_in_batch.insert(key_bytes);
+            hourly
+                .entry((collection.clone(), commit.cursor.into()))
+                .or_default()
+                .records += 1;
+        }
+        // dropped creates are gone by now, so there's nothing to check them
+        // against: they're counted as new, which makes the count approximate
+        let unchecked = commits.creates.saturating_sub(kept_creates) as u64;
+        if unchecked > 0 {
+            let counts = hourly
+                .entry((collection.clone(), latest.into()))
+                .or_default();
+            counts.records += unchecked;
+            counts.unchecked += unchecked;
+        }
+        Ok(())
+    }
+    /// Keys of the older versions kept for a record, oldest first
+    fn version_keys(&self, location_key_bytes: &[u8]) -> StorageResult<Vec<fjall::Slice>> {
+        let mut keys = Vec::new();
+        for kv in self.records.prefix(location_key_bytes) {
+            let (key_bytes, _) = kv?;
+            if key_bytes.len() > location_key_bytes.len() {
+                keys.push(key_bytes);
+            }
+        }
+        Ok(keys)
+    }
+    /// Whether a put is older than the stored version of its record, or its delete
+    ///
+    /// Replays (eg. after reconnecting from an older cursor) can bring back
+    /// creates and updates that were already overwritten or deleted.
+    fn put_is_stale(&self, commit: &UFOsCommit, nsid: &Nsid) -> StorageResult<bool> {
+        let location_key: RecordLocationKey = (commit, nsid).into();
+        if let Some(stored) = self.records.get(location_key.to_db_bytes()?)? {
+            let (meta, _) = RecordLocationMeta::from_db_bytes(&stored)?;
+            if meta.cursor().to_raw_u64() > commit.cursor.to_raw_u64() {
+                return Ok(true);
+            }
+        }
+        let tombstone_key = RecordTombstoneKey::new(location_key).to_db_bytes()?;
+        let Some(val_bytes) = self.tombstones.get(tombstone_key)? else {
+            return Ok(false);
+        };
+        let deleted_at = db_complete::<RecordTombstoneVal>(&val_bytes)?;
+        Ok(deleted_at.to_raw_u64() > commit.cursor.to_raw_u64())
+    }
+    /// Remember when a record was deleted, so older puts replayed later are skipped
+    ///
+    /// Dropped by [`FjallWriter::trim_tombstones`] after [`MAX_TOMBSTONE_AGE`].
+    fn keep_tombstone(
+        &self,
+        batch: &mut FjallBatch,
+        commit: &UFOsCommit,
+        nsid: &Nsid,
+    ) -> StorageResult<()> {
+        let key_bytes = RecordTombstoneKey::new((commit, nsid).into()).to_db_bytes()?;
+        if let Some(val_bytes) = self.tombstones.get(&key_bytes)? {
+            let deleted_at = db_complete::<RecordTombstoneVal>(&val_bytes)?;
+            if deleted_at.to_raw_u64() >= commit.cursor.to_raw_u64() {
+                return Ok(());
+            }
+        }
+        batch.insert(&self.tombstones, key_bytes, commit.cursor.to_db_bytes()?);
+        let expiry_key = TombstoneExpiryKey::new(commit.cursor, (commit, nsid).into());
+        batch.insert(&self.tombstones, expiry_key.to_db_bytes()?, "");
+        Ok(())
+    }
+    /// Move the stored version of a record into its history before it's overwritten
+    fn retain_version(
+        &self,
+        batch: &mut FjallBatch,
+        location_key: RecordLocationKey,
+        cursor: Cursor,
+        keep: usize,
+    ) -> StorageResult<()> {
+        let location_key_bytes = location_key.to_db_bytes()?;
+        let Some(stored) = self.records.get(&location_key_bytes)? else {
+            return Ok(());
+        };
+        let (meta, _) = RecordLocationMeta::from_db_bytes(&stored)?;
+        if meta.cursor().to_raw_u64() >= cursor.to_raw_u64() {
+            // replayed or out-of-order: the stored version isn't older
+            return Ok(());
+        }
+        let older = self.version_keys(&location_key_bytes)?;
+        let excess = (older.len() + 1).saturating_sub(keep);
+        for key_bytes in older.into_iter().take(excess) {
+            batch.remove(&self.records, key_bytes);
+        }
+        if keep > 0 {
+            let version_key = RecordVersionKey::from_pair(location_key, meta.cursor());
+            batch.insert(&self.records, version_key.to_db_bytes()?, stored);
+            counter!("storage_record_versions_kept").increment(1);
+        }
+        Ok(())
+    }
+    /// Process queued account deletes in order, up to the next live count
+    ///
+    /// Stops after [`MAX_BATCHED_ACCOUNT_DELETES`] accounts, or once the
+    /// records removed reach [`MAX_ROLLUP_DELETE_RECORDS`]. At least one
+    /// account is always processed, however many records it has.
+    fn rollup_delete_accounts(
+        &mut self,
+        rollup_cursor: Cursor,
+        timely_next: Option<Cursor>,
+    ) -> StorageResult<usize> {
+        let delete_accounts_range =
+            DeleteAccountQueueKey::new(rollup_cursor).range_to_prefix_end()?;
+        let mut accounts_deleted = 0;
+        let mut records_deleted = 0;
+        for kv in self.queues.range(delete_accounts_range) {
+            let (key_bytes, val_bytes) = kv?;
+            let cursor = db_complete::<DeleteAccountQueueKey>(&key_bytes)?.suffix;
+            if timely_next.is_some_and(|timely| timely < cursor) {
+                break;
+            }
+            let did = db_complete::<DeleteAccountQueueVal>(&val_bytes)?;
+            records_deleted += self.delete_account(&did)?;
+            let mut batch = self.keyspace.batch();
+            batch.remove(&self.queues, key_bytes);
+            insert_batch_static_neu::<NewRollupCursorKey>(&mut batch, &self.global, cursor)?;
+            batch.commit()?;
+            accounts_deleted += 1;
+            if accounts_deleted >= MAX_BATCHED_ACCOUNT_DELETES
+                || records_deleted >= MAX_ROLLUP_DELETE_RECORDS
+            {
+                break;
+            }
+        }
+        histogram!("storage_rollup_accounts_deleted").record(accounts_deleted as f64);
+        Ok(accounts_deleted)
+    }
+
+    fn rollup_live_counts(
+        &mut self,
+        timelies: impl Iterator<Item = Result<(fjall::Slice, fjall::Slice), fjall::Error>>,
+        cursor_exclusive_limit: Option<Cursor>,
+        rollup_limit: usize,
+    ) -> StorageResult<(usize, HashSet<Nsid>)> {
+        // current strategy is to buffer counts in mem before writing the rollups
+        // we *could* read+write every single batch to rollup.. but their merge is associative so
+        // ...so save the db some work up front? is this worth it? who knows...
+
+        let mut dirty_nsids = HashSet::new();
+
+        #[derive(Eq, Hash, PartialEq)]
+        enum Rollup {
+            Hourly(HourTruncatedCursor),
+            Weekly(WeekTruncatedCursor),
+            AllTime,
+        }
+
+        let mut batch = self.keyspace.batch();
+        let mut cursors_advanced = 0;
+        let mut last_cursor = Cursor::from_start();
+        let mut counts_by_rollup: HashMap<(Nsid, Rollup), CountsValue> = HashMap::new();
+        let mut earliest_cursors: HashMap<Nsid, Cursor> = HashMap::new();
+
+        for (i, kv) in timelies.enumerate() {
+            if i >= rollup_limit {
+                break;
+            }
+
+            let (key_bytes, val_bytes) = kv?;
+            let key = db_complete::<LiveCountsKey>(&key_bytes)?;
+
+            if cursor_exclusive_limit
+                .map(|limit| key.cursor() > limit)
+                .unwrap_or(false)
+            {
+                break;
+            }
+
+            dirty_nsids.insert(key.collection().clone());
+            earliest_cursors
+                .entry(key.collection().clone())
+                .or_insert(key.cursor());
+
+            batch.remove(&self.rollups, key_bytes);
+            let val = db_complete::<CountsValue>(&val_bytes)?;
+            counts_by_rollup
+                .entry((
+                    key.collection().clone(),
+                    Rollup::Hourly(key.cursor().into()),
+                ))
+                .or_default()
+                .merge(&val);
+            counts_by_rollup
+                .entry((
+                    key.collection().clone(),
+                    Rollup::Weekly(key.cursor().into()),
+                ))
+                .or_default()
+                .merge(&val);
+            counts_by_rollup
+                .entry((key.collection().clone(), Rollup::AllTime))
+                .or_default()
+                .merge(&val);
+
+            cursors_advanced += 1;
+            last_cursor = key.cursor();
+        }
+
+        // go through each new rollup thing and merge it with whatever might already be in the db
+        for ((nsid, rollup), counts) in counts_by_rollup {
+            let rollup_key_bytes = match rollup {
+                Rollup::Hourly(hourly_cursor) => {
+                    HourlyRollupKey::new(hourly_cursor, &nsid).to_db_bytes()?
+                }
+                Rollup::Weekly(weekly_cursor) => {
+                    WeeklyRollupKey::new(weekly_cursor, &nsid).to_db_bytes()?
+                }
+                Rollup::AllTime => AllTimeRollupKey::new(&nsid).to_db_bytes()?,
+            };
+            let existing = self.rollups.get(&rollup_key_bytes)?;
+
+            // first time we've rolled up this collection: remember when we started tracking it
+            if existing.is_none() && matches!(rollup, Rollup::AllTime) {
+                if let Some(first_cursor) = earliest_cursors.get(&nsid) {
+                    batch.insert(
+                        &self.rollups,
+                        CollectionFirstSeenKey::new(&nsid).to_db_bytes()?,
+                        first_cursor.to_db_bytes()?,
+                    );
+                }
+            }
+
+            let mut rolled: CountsValue = existing
+                .as_deref()
+                .map(db_complete::<CountsValue>)
+                .transpose()?
+                .unwrap_or_default();
+
+            // now that we have values, we can know the exising ranks
+            let before_creates_count = rolled.counts().creates;
+            let before_dids_estimate = microcosm_estimates::estimate(rolled.dids());
+
+            // update the rollup
+            rolled.merge(&counts);
+
+            // new ranks
+            let new_creates_count = rolled.counts().creates;
+            let new_dids_estimate = microcosm_estimates::estimate(rolled.dids());
+
+            // update create-ranked secondary index if rank changed
+            if new_creates_count != before_creates_count {
+                let (old_k, new_k) = match rollup {
+                    Rollup::Hourly(cursor) => (
+                        HourlyRecordsKey::new(cursor, before_creates_count.into(), &nsid)
+                            .to_db_bytes()?,
+                        HourlyRecordsKey::new(cursor, new_creates_count.into(), &nsid)
+                            .to_db_bytes()?,
+                    ),
+                    Rollup::Weekly(cursor) => (
+                        WeeklyRecordsKey::new(cursor, before_creates_count.into(), &nsid)
+                            .to_db_bytes()?,
+                        WeeklyRecordsKey::new(cursor, new_creates_count.into(), &nsid)
+                            .to_db_bytes()?,
+                    ),
+                    Rollup::AllTime => (
+                        AllTimeRecordsKey::new(before_creates_count.into(), &nsid).to_db_bytes()?,
+                        AllTimeRecordsKey::new(new_creates_count.into(), &nsid).to_db_bytes()?,
+                    ),
+                };
+                // remove_weak is allowed here because the secondary ranking index only ever inserts once at a key
+                batch.remove_weak(&self.rollups, &old_k);
+                batch.insert(&self.rollups, &new_k, "");
+            }
+
+            // update dids-ranked secondary index if rank changed
+            if new_dids_estimate != before_dids_estimate {
+                let (old_k, new_k) = match rollup {
+                    Rollup::Hourly(cursor) => (
+                        HourlyDidsKey::new(cursor, before_dids_estimate.into(), &nsid)
+                            .to_db_bytes()?,
+                        HourlyDidsKey::new(cursor, new_dids_estimate.into(), &nsid)
+                            .to_db_bytes()?,
+                    ),
+                    Rollup::Weekly(cursor) => (
+                        WeeklyDidsKey::new(cursor, before_dids_estimate.into(), &nsid)
+                            .to_db_bytes()?,
+                        WeeklyDidsKey::new(cursor, new_dids_estimate.into(), &nsid)
+                            .to_db_bytes()?,
+                    ),
+                    Rollup::AllTime => (
+                        AllTimeDidsKey::new(before_dids_estimate.into(), &nsid).to_db_bytes()?,
+                        AllTimeDidsKey::new(new_dids_estimate.into(), &nsid).to_db_bytes()?,
+                    ),
+                };
+                // remove_weak is allowed here because the secondary ranking index only ever inserts once at a key
+                batch.remove_weak(&self.rollups, &old_k);
+                batch.insert(&self.rollups, &new_k, "");
+            }
+
+            // replace the main counts rollup
+            batch.insert(&self.rollups, &rollup_key_bytes, &rolled.to_db_bytes()?);
+        }
+
+        insert_batch_static_neu::<NewRollupCursorKey>(&mut batch, &self.global, last_cursor)?;
+
+        histogram!("storage_rollup_counts_db_batch_items").record(batch.len() as f64);
+        let t0 = Instant::now();
+        batch.commit()?;
+        histogram!("storage_fjall_batch_commit_time", "batch" => "rollup")
+            .record(t0.elapsed().as_micros() as f64);
+        Ok((cursors_advanced, dirty_nsids))
+    }
+}
+
+/// Publish a purge's progress so far to metrics
+fn purge_progress(report: &PurgeReport) {
+    gauge!("storage_purge_removed", "kind" => "feeds").set(report.feeds_removed as f64);
+    gauge!("storage_purge_removed", "kind" => "records").set(report.records_removed as f64);
+    gauge!("storage_purge_removed", "kind" => "rollups").set(report.rollups_removed as f64);
+}
+
+/// Sets `storage_purge_running` for as long as a (real) purge is going, however it ends
+struct PurgeRunning(bool);
+impl PurgeRunning {
+    fn new(dry_run: bool) -> Self {
+        if !dry_run {
+            gauge!("storage_purge_running").increment(1);
+        }
+        Self(!dry_run)
+    }
+}
+impl Drop for PurgeRunning {
+    fn drop(&mut self) {
+        if self.0 {
+            gauge!("storage_purge_running").decrement(1);
+        }
+    }
+}
+
+impl FjallWriter {
+    /// Remove every record, feed entry, and rollup for a collection
+    ///
+    /// New events for the collection will still be stored and counted after
+    /// (or while) this runs. Rollup steps wait while its rollups are removed.
+    /// Progress is published to the `storage_purge_*` metrics as it goes.
+    ///
+    /// With `dry_run`, the same scan runs and the report counts what would be
+    /// removed, but no batch is ever committed.
+    fn purge_collection(&self, collection: &Nsid, dry_run: bool) -> StorageResult<PurgeReport> {
+        let t0 = Instant::now();
+        let mut report = PurgeReport {
+            dry_run,
+            ..Default::default()
+        };
+        if !dry_run {
+            purge_progress(&report);
+        }
+        let _running = PurgeRunning::new(dry_run);
+        let mut batch = self.keyspace.batch();
+
+        // feeds, and the records they point to
+        let feed_prefix = NsidRecordFeedKey::from_prefix_to_db_bytes(collection)?;
+        for kv in self.feeds.prefix(feed_prefix) {
+            let (key_bytes, val_bytes) = kv?;
+            let feed_key = db_complete::<NsidRecordFeedKey>(&key_bytes)?;
+            let feed_val = db_complete::<NsidRecordFeedVal>(&val_bytes)?;
+            let location_key_bytes =
+                RecordLocationKey::from((&feed_key, &feed_val)).to_db_bytes()?;
+            if self.records.contains_key(&location_key_bytes)? {
+                for version_key in self.version_keys(&location_key_bytes)? {
+                    batch.remove(&self.records, version_key);
+                }
+                batch.remove(&self.records, location_key_bytes);
+                report.records_removed += 1;
+            }
+            batch.remove(&self.feeds, key_bytes);
+            report.feeds_removed += 1;
+            if batch.len() >= MAX_BATCHED_PURGE_ITEMS {
+                if !dry_run {
+                    batch.commit()?;
+                    purge_progress(&report);
+                }
+                batch = self.keyspace.batch();
+                log::info!(
+                    "purge {:?}: removed {} feed entries and {} records so far ({:?})",
+                    collection.to_string(),
+                    report.feeds_removed,
+                    report.records_removed,
+                    t0.elapsed(),
+                );
+            }
+        }
+        batch.remove(
+            &self.global,
+            TrimCollectionCursorKey::new(collection.clone()).to_db_bytes()?,
+        );
+        if !dry_run {
+            batch.commit()?;
+            if let Some(search) = &self.search {
+                search.remove_collection(collection);
+            }
+        }
+
+        // records seen for unique counts: as many as were ever created, so batched like feeds
+        let mut batch = self.keyspace.batch();
+        for kv in self
+            .unique
+            .prefix(UniqueSeenKey::prefix(collection).to_db_bytes()?)
+        {
+            let (key_bytes, _) = kv?;
+            batch.remove(&self.unique, key_bytes);
+            if batch.len() >= MAX_BATCHED_PURGE_ITEMS {
+                if !dry_run {
+                    batch.commit()?;
+                }
+                batch = self.keyspace.batch();
+            }
+        }
+        if !dry_run {
+            batch.commit()?;
+            purge_progress(&report);
+        }
+        log::info!(
+            "purge {:?}: finished feeds and records ({} feed entries, {} records). starting rollups.",
+            collection.to_string(),
+            report.feeds_removed,
+            report.records_removed,
+        );
+
+        // a rollup step in between could read counts we're removing and write them back
+        let _rollup_guard = self.rollup_lock.lock().unwrap();
+
+        // live counts that haven't been rolled up yet
+        let mut batch = self.keyspace.batch();
+        for kv in self
+            .rollups
+            .range(LiveCountsKey::range_from_cursor(Cursor::from_start())?)
+        {
+            let (key_bytes, _) = kv?;
+            let key = db_complete::<LiveCountsKey>(&key_bytes)?;
+            if key.collection() == collection {
+                batch.remove(&self.rollups, key_bytes);
+                report.rollups_removed += 1;
+                self.commit_full_purge_batch(&mut batch, dry_run)?;
+            }
+        }
+
+        // hourly and weekly rollups, plus their rank secondary indexes. their
+        // keys start with the time bucket, so seek to the collection's key in
+        // each bucket, jumping straight over buckets without any rollups.
+        let earliest_hour = self
+            .rollups
+            .prefix(HourlyRollupStaticPrefix::default().to_db_bytes()?)
+            .next()
+            .transpose()?
+            .map(|(key_bytes, _)| db_complete::<HourlyRollupKey>(&key_bytes))
+            .transpose()?
+            .map(|key| key.cursor());
+        if let Some(earliest_hour) = earliest_hour {
+            let now: HourTruncatedCursor = Cursor::at(SystemTime::now()).into();
+            let end = HourlyRollupKey::end(now)?;
+            let mut hour = earliest_hour;
+            while hour <= now {
+                let start = Bound::Included(HourlyRollupKey::new(hour, collection).to_db_bytes()?);
+                let Some(kv) = self.rollups.range((start, end.clone())).next() else {
+                    break;
+                };
+                let (key_bytes, val_bytes) = kv?;
+                let key = db_complete::<HourlyRollupKey>(&key_bytes)?;
+                if key.cursor() > hour {
+                    hour = key.cursor();
+                    continue;
+                }
+                if key.collection() == collection {
+                    let counts = db_complete::<CountsValue>(&val_bytes)?;
+                    let creates = counts.counts().creates.into();
+                    let dids = microcosm_estimates::estimate(counts.dids()).into();
+                    batch.remove(&self.rollups, key_bytes);
+                    batch.remove(
+                        &self.rollups,
+                        HourlyRecordsKey::new(hour, creates, collection).to_db_bytes()?,
+                    );
+                    batch.remove(
+                        &self.rollups,
+                        HourlyDidsKey::new(hour, dids, collection).to_db_bytes()?,
+                    );
+                    report.rollups_removed += 1;
+                    self.commit_full_purge_batch(&mut batch, dry_run)?;
+                }
+                hour = hour.next();
+            }
+
+            let now: WeekTruncatedCursor = Cursor::at(SystemTime::now()).into();
+            let end = WeeklyRollupKey::end(now)?;
+            let mut week: WeekTruncatedCursor = Cursor::from(earliest_hour).into();
+            while week <= now {
+                let start = Bound::Included(WeeklyRollupKey::new(week, collection).to_db_bytes()?);
+                let Some(kv) = self.rollups.range((start, end.clone())).next() else {
+                    break;
+                };
+                let (key_bytes, val_bytes) = kv?;
+                let key = db_complete::<WeeklyRollupKey>(&key_bytes)?;
+                if key.cursor() > week {
+                    week = key.cursor();
+                    continue;
+                }
+                if key.collection() == collection {
+                    let counts = db_complete::<CountsValue>(&val_bytes)?;
+                    let creates = counts.counts().creates.into();
+                    let dids = microcosm_estimates::estimate(counts.dids()).into();
+                    batch.remove(&self.rollups, key_bytes);
+                    batch.remove(
+                        &self.rollups,
+                        WeeklyRecordsKey::new(week, creates, collection).to_db_bytes()?,
+                    );
+                    batch.remove(
+                        &self.rollups,
+                        WeeklyDidsKey::new(week, dids, collection).to_db_bytes()?,
+                    );
+                    report.rollups_removed += 1;
+                    self.commit_full_purge_batch(&mut batch, dry_run)?;
+                }
+                week = week.next();
+            }
+        }
+
+        // all-time rollup
+        let key_bytes = AllTimeRollupKey::new(collection).to_db_bytes()?;
+        if let Some(val_bytes) = self.rollups.get(&key_bytes)? {
+            let counts = db_complete::<CountsValue>(&val_bytes)?;
+            let creates = counts.counts().creates.into();
+            let dids = microcosm_estimates::estimate(counts.dids()).into();
+            batch.remove(&self.rollups, key_bytes);
+            batch.remove(
+                &self.rollups,
+                AllTimeRecordsKey::new(creates, collection).to_db_bytes()?,
+            );
+            batch.remove(
+                &self.rollups,
+                AllTimeDidsKey::new(dids, collection).to_db_bytes()?,
+            );
+            report.rollups_removed += 1;
+        }
+        batch.remove(
+            &self.rollups,
+            CollectionFirstSeenKey::new(collection).to_db_bytes()?,
+        );
+        for kv in self
+            .unique
+            .prefix(UniqueHourlyKey::prefix(collection).to_db_bytes()?)
+        {
+            let (key_bytes, _) = kv?;
+            batch.remove(&self.unique, key_bytes);
+            report.rollups_removed += 1;
+            self.commit_full_purge_batch(&mut batch, dry_run)?;
+        }
+        if dry_run {
+            log::info!(
+                "purge {:?}: dry run done in {:?}, nothing removed: {report:?}",
+                collection.to_string(),
+                t0.elapsed()
+            );
+            return Ok(report);
+        }
+        batch.commit()?;
+        purge_progress(&report);
+
+        log::info!(
+            "purge {:?}: done in {:?}: {report:?}",
+            collection.to_string(),
+            t0.elapsed()
+        );
+        counter!("storage_purge_collection_completions").increment(1);
+        Ok(report)
+    }
+
+    /// Start a new purge batch once this one is full, committing it unless it's a dry run
+    fn commit_full_purge_batch(&self, batch: &mut FjallBatch, dry_run: bool) -> StorageResult<()> {
+        if batch.len() >= MAX_BATCHED_PURGE_ITEMS {
+            let full = std::mem::replace(batch, self.keyspace.batch());
+            if !dry_run {
+                full.commit()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Estimate how many bytes each collection's feed entries and records take
+    ///
+    /// Walks the feeds partition one collection at a time, counting feed keys
+    /// exactly. Record sizes are extrapolated from every `sample_every`th entry.
+    fn estimate_key_space(&self, sample_every: u64, limit: usize) -> StorageResult<KeySpaceReport> {
+        let t0 = Instant::now();
+        let sample_every = sample_every.max(1);
+        let mut collections = Vec::new();
+        let mut next_collection = self.feeds.first_key_value()?;
+        while let Some((first_key_bytes, _)) = next_collection {
+            let collection = db_complete::<NsidRecordFeedKey>(&first_key_bytes)?
+                .collection()
+                .clone();
+            let mut feed_entries = 0;
+            let mut feed_bytes = 0;
+            let mut records_sampled = 0;
+            let mut sampled_bytes = 0;
+            let feed_prefix = NsidRecordFeedKey::from_prefix_to_db_bytes(&collection)?;
+            for kv in self.feeds.prefix(feed_prefix) {
+                let (key_bytes, val_bytes) = kv?;
+                feed_entries += 1;
+                feed_bytes += (key_bytes.len() + val_bytes.len()) as u64;
+                if (feed_entries - 1) % sample_every != 0 {
+                    continue;
+                }
+                let feed_key = db_complete::<NsidRecordFeedKey>(&key_bytes)?;
+                let feed_val = db_complete::<NsidRecordFeedVal>(&val_bytes)?;
+                let location_key_bytes =
+                    RecordLocationKey::from((&feed_key, &feed_val)).to_db_bytes()?;
+                records_sampled += 1;
+                if let Some(location_val_bytes) = self.records.get(&location_key_bytes)? {
+                    sampled_bytes += (location_key_bytes.len() + location_val_bytes.len()) as u64;
+                }
+                for version_key in self.version_keys(&location_key_bytes)? {
+                    if let Some(version_val_bytes) = self.records.get(&version_key)? {
+                        sampled_bytes += (version_key.len() + version_val_bytes.len()) as u64;
+                    }
+                }
+            }
+            let record_bytes_estimate = if records_sampled == 0 {
+                0
+            } else {
+                sampled_bytes * feed_entries / records_sampled
+            };
+            collections.push(CollectionKeySpace {
+                collection: collection.to_string(),
+                feed_entries,
+                feed_bytes,
+                records_sampled,
+                record_bytes_estimate,
+                total_bytes_estimate: feed_bytes + record_bytes_estimate,
+            });
+            let end = NsidRecordFeedKey::prefix_range_end(&collection)?;
+            next_collection = self.feeds.range(end..).next().transpose()?;
+        }
+        log::info!(
+            "key space: estimated {} collections in {:?}",
+            collections.len(),
+            t0.elapsed()
+        );
+        collections.sort_by(|a, b| b.total_bytes_estimate.cmp(&a.total_bytes_estimate));
+        let total_bytes_estimate = collections.iter().map(|c| c.total_bytes_estimate).sum();
+        collections.truncate(limit);
+        Ok(KeySpaceReport {
+            sample_every,
+            total_bytes_estimate,
+            collections,
+        })
+    }
+
+    /// Clear the feeds and write one entry for every current record
+    ///
+    /// The progress key is set once the feeds are cleared and moved along with
+    /// each committed batch, so an interrupted rebuild resumes after the last
+    /// record it committed instead of clearing again.
+    fn rebuild_feeds(&self) -> StorageResult<RebuildFeedsReport> {
+        let t0 = Instant::now();
+        let mut report = RebuildFeedsReport::default();
+
+        let progress = get_static_neu::<RebuildFeedsKey, RebuildFeedsValue>(&self.global)?;
+        report.resumed = progress.is_some();
+        let start = match progress {
+            Some(last) if !last.is_empty() => Bound::Excluded(last),
+            _ => Bound::Unbounded,
+        };
+        if report.resumed {
+            log::info!("rebuild feeds: resuming an interrupted rebuild");
+        } else {
+            let mut batch = self.keyspace.batch();
+            for kv in self.feeds.iter() {
+                let (key_bytes, _) = kv?;
+                batch.remove(&self.feeds, key_bytes);
+                report.feeds_cleared += 1;
+                if batch.len() >= MAX_BATCHED_REBUILD_ITEMS {
+                    batch.commit()?;
+                    batch = self.keyspace.batch();
+                }
+            }
+            insert_batch_static_neu::<RebuildFeedsKey>(
+                &mut batch,
+                &self.global,
+                RebuildFeedsValue::new(),
+            )?;
+            batch.commit()?;
+            log::info!(
+                "rebuild feeds: cleared {} feed entries ({:?}). rebuilding from records.",
+                report.feeds_cleared,
+                t0.elapsed(),
+            );
+        }
+
+        let mut batch = self.keyspace.batch();
+        for kv in self.records.range::<Vec<u8>, _>((start, Bound::Unbounded)) {
+            let (key_bytes, val_bytes) = kv?;
+            let (location_key, n) = RecordLocationKey::from_db_bytes(&key_bytes)?;
+            if n < key_bytes.len() {
+                report.versions_skipped += 1; // an older version kept as history
+                continue;
+            }
+            let (meta, _) = RecordLocationMeta::from_db_bytes(&val_bytes)?;
+            let feed_key =
+                NsidRecordFeedKey::from_pair(location_key.collection().clone(), meta.cursor());
+            let feed_val: NsidRecordFeedVal =
+                (location_key.did(), location_key.rkey(), meta.rev.as_str()).into();
+            batch.insert(
+                &self.feeds,
+                feed_key.to_db_bytes()?,
+                feed_val.to_db_bytes()?,
+            );
+            report.records_rebuilt += 1;
+            if batch.len() >= MAX_BATCHED_REBUILD_ITEMS {
+                insert_batch_static_neu::<RebuildFeedsKey>(
+                    &mut batch,
+                    &self.global,
+                    key_bytes.to_vec(),
+                )?;
+                batch.commit()?;
+                batch = self.keyspace.batch();
+                log::info!(
+                    "rebuild feeds: rebuilt {} records so far ({:?})",
+                    report.records_rebuilt,
+                    t0.elapsed(),
+                );
+            }
+        }
+        batch.remove(
+            &self.global,
+            DbStaticStr::<RebuildFeedsKey>::default().to_db_bytes()?,
+        );
+        batch.commit()?;
+
+        log::info!("rebuild feeds: done in {:?}: {report:?}", t0.elapsed());
+        counter!("storage_rebuild_feeds_completions").increment(1);
+        Ok(report)
+    }
+
+    fn get_denylist(&self) -> Vec<DenyRule> {
+        self.denylist.read().unwrap().rules()
+    }
+
+    /// Persist a deny rule, replacing any existing rule with the same pattern
+    fn deny_collections(&self, rule: DenyRule) -> StorageResult<()> {
+        let mut denylist = self.denylist.write().unwrap();
+        self.global.insert(
+            DenylistKey::new(&rule.pattern).to_db_bytes()?,
+            DenylistVal {
+                keep_counts: rule.keep_counts,
+            }
+            .to_db_bytes()?,
+        )?;
+        denylist.insert(rule);
+        Ok(())
+    }
+
+    /// Remove a deny rule. Returns false if it didn't exist.
+    fn allow_collections(&self, pattern: &str) -> StorageResult<bool> {
+        let mut denylist = self.denylist.write().unwrap();
+        self.global
+            .remove(DenylistKey::new(pattern).to_db_bytes()?)?;
+        Ok(denylist.remove(pattern))
+    }
+
+    fn record_audit(&self, mut entry: AuditEntry) -> StorageResult<()> {
+        let _guard = self.audit_lock.lock().unwrap();
+        if let Some((key_bytes, _)) = self.audit.last_key_value()? {
+            let last = db_complete::<Cursor>(&key_bytes)?.to_raw_u64();
+            entry.at = entry.at.max(last + 1);
+        }
+        let val = serde_json::to_vec(&entry).map_err(EncodingError::JsonError)?;
+        self.audit
+            .insert(Cursor::from_raw_u64(entry.at).to_db_bytes()?, val)?;
+        Ok(())
+    }
+
+    fn get_audit_log(&self, before: Option<u64>, limit: usize) -> StorageResult<Vec<AuditEntry>> {
+        let end = match before {
+            Some(before) => Bound::Excluded(Cursor::from_raw_u64(before).to_db_bytes()?),
+            None => Bound::Unbounded,
+        };
+        let mut entries = Vec::new();
+        for kv in self
+            .audit
+            .range::<Vec<u8>, _>((Bound::Unbounded, end))
+            .rev()
+            .take(limit)
+        {
+            let (_, val_bytes) = kv?;
+            entries.push(serde_json::from_slice(&val_bytes).map_err(EncodingError::JsonError)?);
+        }
+        Ok(entries)
+    }
+}
+
+#[async_trait]
+impl StoreAdmin for FjallWriter {
+    async fn purge_collection(
+        &self,
+        collection: &Nsid,
+        dry_run: bool,
+    ) -> StorageResult<PurgeReport> {
+        let s = self.clone();
+        let collection = collection.clone();
+        server::spawn_blocking(move || FjallWriter::purge_collection(&s, &collection, dry_run))
+            .await?
+    }
+    async fn estimate_key_space(
+        &self,
+        sample_every: u64,
+        limit: usize,
+    ) -> StorageResult<KeySpaceReport> {
+        let s = self.clone();
+        server::spawn_blocking(move || FjallWriter::estimate_key_space(&s, sample_every, limit))
+            .await?
+    }
+    async fn rebuild_feeds(&self) -> StorageResult<RebuildFeedsReport> {
+        let s = self.clone();
+        server::spawn_blocking(move || FjallWriter::rebuild_feeds(&s)).await?
+    }
+    async fn background_status(&self) -> StorageResult<BackgroundStatus> {
+        Ok(self.schedule.status())
+    }
+    async fn trigger_background(&self, task: BackgroundTask) -> StorageResult<bool> {
+        Ok(self.schedule.trigger(task))
+    }
+    async fn set_background_intervals(&self, intervals: BackgroundIntervals) -> StorageResult<()> {
+        intervals.validate().map_err(StorageError::BadStateError)?;
+        self.schedule.update(|s| s.intervals = Some(intervals));
+        self.schedule.reconfigure.notify_one();
+        Ok(())
+    }
+    async fn account_deletes_status(&self) -> StorageResult<AccountDeletesStatus> {
+        let s = self.clone();
+        server::spawn_blocking(move || FjallWriter::account_deletes_status(&s)).await?
+    }
+    async fn get_denylist(&self) -> StorageResult<Vec<DenyRule>> {
+        Ok(FjallWriter::get_denylist(self))
+    }
+    async fn deny_collections(&self, rule: DenyRule) -> StorageResult<()> {
+        let s = self.clone();
+        server::spawn_blocking(move || FjallWriter::deny_collections(&s, rule)).await?
+    }
+    async fn allow_collections(&self, pattern: &str) -> StorageResult<bool> {
+        let s = self.clone();
+        let pattern = pattern.to_string();
+        server::spawn_blocking(move || FjallWriter::allow_collections(&s, &pattern)).await?
+    }
+    async fn record_audit(&self, entry: AuditEntry) -> StorageResult<()> {
+        let s = self.clone();
+        server::spawn_blocking(move || FjallWriter::record_audit(&s, entry)).await?
+    }
+    async fn get_audit_log(
+        &self,
+        before: Option<u64>,
+        limit: usize,
+    ) -> StorageResult<Vec<AuditEntry>> {
+        let s = self.clone();
+        server::spawn_blocking(move || FjallWriter::get_audit_log(&s, before, limit)).await?
+    }
+    async fn noisy_dids(
+        &self,
+        hours: usize,
+        min_records: u64,
+        min_share: f64,
+        limit: usize,
+    ) -> StorageResult<NoisyDidsReport> {
+        Ok(self.noisy.report(hours, min_records, min_share, limit))
+    }
+}
+
+impl StoreWriter<FjallBackground> for FjallWriter {
+    type Spill = FjallSpill;
+
+    fn spill_queue(&self) -> StorageResult<FjallSpill> {
+        Ok(self.spill.clone())
+    }
+
+    fn jetstream_shard_cursor(&self, shard: &JetstreamShard) -> StorageResult<Option<Cursor>> {
+        self.global
+            .get(JetstreamShardCursorKey::new(shard.id()).to_db_bytes()?)?
+            .map(|bytes| db_complete::<JetstreamCursorValue>(&bytes))
+            .transpose()
+    }
+
+    fn background_tasks(&mut self, reroll: bool) -> StorageResult<FjallBackground> {
+        if self.bg_taken.swap(true, Ordering::SeqCst) {
+            return Err(StorageError::BackgroundAlreadyStarted);
+        }
+        if reroll {
+            log::info!("reroll: resetting rollup cursor...");
+            insert_static_neu::<NewRollupCursorKey>(&self.global, Cursor::from_start())?;
+            log::info!("reroll: clearing trim cursors...");
+            let mut batch = self.keyspace.batch();
+            for kv in self
+                .global
+                .prefix(TrimCollectionCursorKey::from_prefix_to_db_bytes(
+                    &Default::default(),
+                )?)
+            {
+                let (k, _) = kv?;
+                batch.remove(&self.global, k);
+            }
+            let n = batch.len();
+            batch.commit()?;
+            log::info!("reroll: cleared {n} trim cursors.");
+        }
+        Ok(FjallBackground(self.clone()))
+    }
+
+    fn insert_batch<const LIMIT: usize>(
+        &mut self,
+        mut event_batch: EventBatch<LIMIT>,
+    ) -> StorageResult<()> {
+        if event_batch.is_empty() {
+            return Ok(());
+        }
+
+        let mut batch = self.keyspace.batch();
+
+        // would be nice not to have to iterate everything at once here
+        let latest = event_batch.latest_cursor().unwrap();
+        // a batch from a lagging jetstream shard can be older than the last
+        // one. keep the cursor for live counts moving forward, since rollups
+        // never look behind where they've already been.
+        let latest = if self.shards.is_empty() {
+            latest
+        } else {
+            match get_static_neu::<JetstreamCursorKey, JetstreamCursorValue>(&self.global)? {
+                Some(stored) if stored > latest => stored,
+                _ => latest,
+            }
+        };
+        let shard_cursors = self.shard_cursors(&event_batch);
+        // after the cursors, so they still move past anything dropped here
+        self.nsid_limits.apply(&mut event_batch);
+        self.plugins.run(latest, &event_batch);
+
+        let denylist = self.denylist.read().unwrap();
+
+        if self.change_feed.is_some() {
+            let published = self.publishable(&event_batch, &denylist)?;
+            batch.insert(
+                &self.changes,
+                latest.to_db_bytes()?,
+                spill::encode(&published)?,
+            );
+        }
+
+        let mut watch_hourly: HashMap<(String, HourTruncatedCursor), u64> = HashMap::new();
+        let mut unique_seen = HashSet::new();
+        let mut unique_hourly: HashMap<(Nsid, HourTruncatedCursor), UniqueHourlyVal> =
+            HashMap::new();
+        let mut stored_batch = self.plugins.want_stored().then(StoredBatch::default);
+
+        for (nsid, commits) in event_batch.commits_by_nsid {
+            // a denied collection's new records aren't stored, but deletes still
+            // apply, so records stored before it was denied can still go
+            let denied = denylist.check(&nsid);
+            if let Some(rule) = denied {
+                counter!("storage_denylist_skipped_commits", "keep_counts" => rule.keep_counts.to_string())
+                    .increment(commits.commits.iter().filter(|c| !c.action.is_cut()).count() as u64);
+            } else if self.unique_counts {
+                self.count_unique(
+                    &mut batch,
+                    &nsid,
+                    &commits,
+                    latest,
+                    &mut unique_seen,
+                    &mut unique_hourly,
+                )?;
+            }
+            // truncation can displace commits out of cursor order, so only
+            // apply the latest commit for each record: a delete always wins
+            // over an earlier put in the same batch, and vice versa.
+            let mut latest_first = commits.commits;
+            latest_first.sort_by_key(|c| std::cmp::Reverse(c.cursor.to_raw_u64()));
+            let mut seen_locations = HashSet::new();
+
+            for commit in latest_first {
+                if denied.is_some() && !commit.action.is_cut() {
+                    continue;
+                }
+                let location_key: RecordLocationKey = (&commit, &nsid).into();
+                let location_key_bytes = location_key.to_db_bytes()?;
+                if !seen_locations.insert(location_key_bytes.clone()) {
+                    counter!("storage_insert_batch_superseded_commits").increment(1);
+                    continue;
+                }
+                if !commit.action.is_cut() && self.put_is_stale(&commit, &nsid)? {
+                    counter!("storage_insert_batch_stale_puts").increment(1);
+                    continue;
+                }
+
+                match commit.action {
                     CommitAction::Cut => {
-                        batch.remove(&self.records, &location_key.to_db_bytes()?);
+                        if let Some(stored) = self.records.get(&location_key_bytes)? {
+                            let (meta, _) = RecordLocationMeta::from_db_bytes(&stored)?;
+                            if meta.cursor().to_raw_u64() > commit.cursor.to_raw_u64() {
+                                // the stored version is newer than this delete (replay?)
+                                continue;
+                            }
+                            // also drop the stored version's feed entry so it doesn't linger until trim
+                            let feed_key =
+                                NsidRecordFeedKey::from_pair(nsid.clone(), meta.cursor());
+                            batch.remove(&self.feeds, feed_key.to_db_bytes()?);
+                            batch.remove(&self.records, &location_key_bytes);
+                            for version_key in self.version_keys(&location_key_bytes)? {
+                                batch.remove(&self.records, version_key);
+                            }
+                            if let Some(stored_batch) = &mut stored_batch {
+                                stored_batch.removed.push((
+                                    commit.did.clone(),
+                                    nsid.clone(),
+                                    commit.rkey.clone(),
+                                ));
+                            }
+                        }
+                        // even with nothing stored (eg. its create was truncated),
+                        // the create could still be replayed later
+                        self.keep_tombstone(&mut batch, &commit, &nsid)?;
+                    }
+                    CommitAction::Put(mut put_action) => {
+                        let (redaction_version, transforms) =
+                            self.prepare_record(&nsid, &mut put_action)?;
+                        self.record_watch_hits(
+                            &mut batch,
+                            &nsid,
+                            &commit.did,
+                            &commit.rkey,
+                            commit.cursor,
+                            &put_action.record,
+                            &mut watch_hourly,
+                        )?;
+                        if let Some(&keep) = self.keep_versions.get(&nsid) {
+                            self.retain_version(&mut batch, location_key, commit.cursor, keep)?;
+                        }
+                        let diff = if self.record_diffs && put_action.is_update {
+                            self.diff_from_stored(&location_key_bytes, &put_action.record)?
+                        } else {
+                            None
+                        };
+                        let feed_key = NsidRecordFeedKey::from_pair(nsid.clone(), commit.cursor);
+                        let feed_val: NsidRecordFeedVal =
+                            (&commit.did, &commit.rkey, commit.rev.as_str()).into();
+                        batch.insert(
+                            &self.feeds,
+                            feed_key.to_db_bytes()?,
+                            feed_val.to_db_bytes()?,
+                        );
+
+                        if let Some(stored_batch) = &mut stored_batch {
+                            stored_batch.records.push(UFOsRecord {
+                                cursor: commit.cursor,
+                                did: commit.did.clone(),
+                                collection: nsid.clone(),
+                                rkey: commit.rkey.clone(),
+                                rev: commit.rev.clone(),
+                                record: put_action.record.clone(),
+                                is_update: put_action.is_update,
+                                redaction_version,
+                                diff: diff
+                                    .clone()
+                                    .map(RawValue::from_string)
+                                    .transpose()
+                                    .map_err(EncodingError::JsonError)?,
+                                transforms: transforms.clone(),
+                            });
+                        }
+
+                        let location_val: RecordLocationVal = (
+                            commit.cursor,
+                            commit.rev.as_str(),
+                            put_action,
+                            redaction_version,
+                            diff,
+                            transforms,
+                        )
+                            .into();
+                        batch.insert(
+                            &self.records,
+                            &location_key_bytes,
+                            &location_val.to_db_bytes()?,
+                        );
+                    }
+                }
+            }
+            if denied.is_some_and(|rule| !rule.keep_counts) {
+                continue;
+            }
+            let live_counts_key: LiveCountsKey = (latest, &nsid).into();
+            let counts_value = CountsValue::new(
+                CommitCounts {
+                    creates: commits.creates as u64,
+                    updates: commits.updates as u64,
+                    deletes: commits.deletes as u64,
+                },
+                commits.dids_estimate,
+            );
+            batch.insert(
+                &self.rollups,
+                &live_counts_key.to_db_bytes()?,
+                &counts_value.to_db_bytes()?,
+            );
+            if let Some(stored_batch) = &mut stored_batch {
+                stored_batch.counts.push(CountsDelta {
+                    collection: nsid.to_string(),
+                    cursor: latest.to_raw_u64(),
+                    creates: commits.creates as u64,
+                    updates: commits.updates as u64,
+                    deletes: commits.deletes as u64,
+                });
+            }
+        }
+
+        if event_batch.overflowed_collections > 0 {
+            let key_bytes = OverflowedCollectionsKey::new(latest.into()).to_db_bytes()?;
+            let mut overflowed: OverflowedCollectionsVal = self
+                .rollups
+                .get(&key_bytes)?
+                .as_deref()
+                .map(db_complete)
+                .transpose()?
+                .unwrap_or_default();
+            overflowed.batches += event_batch.overflowed_collections as u64;
+            batch.insert(&self.rollups, key_bytes, overflowed.to_db_bytes()?);
+        }
+
+        if let Some(gap) = event_batch.cursor_gap {
+            insert_batch_static_neu::<CursorGapKey>(
+                &mut batch,
+                &self.global,
+                CursorGapValue::from(gap),
+            )?;
+        }
+
+        if !event_batch.event_kinds.is_empty() {
+            let key_bytes = EventKindsKey::new(latest.into()).to_db_bytes()?;
+            let mut kinds: EventKindsVal = self
+                .rollups
+                .get(&key_bytes)?
+                .as_deref()
+                .map(db_complete)
+                .transpose()?
+                .unwrap_or_default();
+            kinds.commits += event_batch.event_kinds.commits;
+            kinds.identities += event_batch.event_kinds.identities;
+            kinds.accounts += event_batch.event_kinds.accounts;
+            batch.insert(&self.rollups, key_bytes, kinds.to_db_bytes()?);
+        }
+
+        for ((nsid, hour), counts) in unique_hourly {
+            let key_bytes = UniqueHourlyKey::new(&nsid, hour).to_db_bytes()?;
+            let mut hourly: UniqueHourlyVal = self
+                .unique
+                .get(&key_bytes)?
+                .as_deref()
+                .map(db_complete)
+                .transpose()?
+                .unwrap_or_default();
+            hourly.records += counts.records;
+            hourly.unchecked += counts.unchecked;
+            batch.insert(&self.unique, key_bytes, hourly.to_db_bytes()?);
+        }
+
+        for ((name, hour), hits) in watch_hourly {
+            let key_bytes = WatchHourlyKey::new(&name, hour).to_db_bytes()?;
+            let mut hourly: WatchHourlyVal = self
+                .watch
+                .get(&key_bytes)?
+                .as_deref()
+                .map(db_complete)
+                .transpose()?
+                .unwrap_or_default();
+            hourly.hits += hits;
+            batch.insert(&self.watch, key_bytes, hourly.to_db_bytes()?);
+        }
+
+        for remove in event_batch.account_removes {
+            let queue_key = DeleteAccountQueueKey::new(remove.cursor);
+            let queue_val: DeleteAccountQueueVal = remove.did;
+            batch.insert(
+                &self.queues,
+                &queue_key.to_db_bytes()?,
+                &queue_val.to_db_bytes()?,
+            );
+        }
+
+        self.spill.inserted_through(&mut batch, latest)?;
+        batch.insert(
+            &self.global,
+            DbStaticStr::<JetstreamCursorKey>::default().to_db_bytes()?,
+            latest.to_db_bytes()?,
+        );
+        for (shard, cursor) in shard_cursors {
+            batch.insert(
+                &self.global,
+                JetstreamShardCursorKey::new(shard).to_db_bytes()?,
+                cursor.to_db_bytes()?,
+            );
+        }
+
+        histogram!("storage_insert_batch_db_batch_items").record(batch.len() as f64);
+        let t0 = Instant::now();
+        batch.commit()?;
+        histogram!("storage_fjall_batch_commit_time", "batch" => "insert")
+            .record(t0.elapsed().as_micros() as f64);
+
+        if let Some(stored_batch) = stored_batch {
+            self.plugins.run_stored(&stored_batch);
+        }
+        Ok(())
+    }
+
+    fn step_rollup(&mut self) -> StorageResult<(usize, HashSet<Nsid>)> {
+        let rollup_lock = self.rollup_lock.clone();
+        let _guard = rollup_lock.lock().unwrap();
+        let mut dirty_nsids = HashSet::new();
+
+        let rollup_cursor =
+            get_static_neu::<NewRollupCursorKey, NewRollupCursorValue>(&self.global)?.ok_or(
+                StorageError::BadStateError("Could not find current rollup cursor".to_string()),
+            )?;
+
+        // timelies
+        let live_counts_range = LiveCountsKey::range_from_cursor(rollup_cursor)?;
+        let mut timely_iter = self.rollups.range(live_counts_range).peekable();
+
+        let timely_next = timely_iter
+            .peek_mut()
+            .map(|kv| -> StorageResult<LiveCountsKey> {
+                match kv {
+                    Err(e) => Err(std::mem::replace(e, fjall::Error::Poisoned))?,
+                    Ok((key_bytes, _)) => {
+                        let key = db_complete::<LiveCountsKey>(key_bytes)?;
+                        Ok(key)
+                    }
+                }
+            })
+            .transpose()?;
+
+        // delete accounts
+        let delete_accounts_range =
+            DeleteAccountQueueKey::new(rollup_cursor).range_to_prefix_end()?;
+
+        let next_delete = self
+            .queues
+            .range(delete_accounts_range)
+            .next()
+            .transpose()?
+            .map(|(key_bytes, _)| {
+                db_complete::<DeleteAccountQueueKey>(&key_bytes).map(|k| k.suffix)
+            })
+            .transpose()?;
+
+        let cursors_stepped = match (timely_next, next_delete) {
+            (Some(timely), Some(delete_cursor)) if timely.cursor() < delete_cursor => {
+                let (n, dirty) = self.rollup_live_counts(
+                    timely_iter,
+                    Some(delete_cursor),
+                    MAX_BATCHED_ROLLUP_COUNTS,
+                )?;
+                dirty_nsids.extend(dirty);
+                n
+            }
+            (Some(_), None) => {
+                let (n, dirty) =
+                    self.rollup_live_counts(timely_iter, None, MAX_BATCHED_ROLLUP_COUNTS)?;
+                dirty_nsids.extend(dirty);
+                n
+            }
+            (timely, Some(_)) => {
+                self.rollup_delete_accounts(rollup_cursor, timely.map(|t| t.cursor()))?
+            }
+            (None, None) => 0,
+        };
+
+        Ok((cursors_stepped, dirty_nsids))
+    }
+
+    fn trim_collection(
+        &mut self,
+        collection: &Nsid,
+        limit: usize,
+        full_scan: bool,
+    ) -> StorageResult<(usize, usize, bool)> {
+        let mut dangling_feed_keys_cleaned = 0;
+        let mut records_deleted = 0;
+
+        let live_range = if full_scan {
+            let start = NsidRecordFeedKey::from_prefix_to_db_bytes(collection)?;
+            let end = NsidRecordFeedKey::prefix_range_end(collection)?;
+            start..end
+        } else {
+            let feed_trim_cursor_key =
+                TrimCollectionCursorKey::new(collection.clone()).to_db_bytes()?;
+            let trim_cursor = self
+                .global
+                .get(&feed_trim_cursor_key)?
+                .map(|value_bytes| db_complete(&value_bytes))
+                .transpose()?
+                .unwrap_or(Cursor::from_start());
+            NsidRecordFeedKey::from_pair(collection.clone(), trim_cursor).range_to_prefix_end()?
+        };
+
+        // newest first: keep the latest `limit` live records, cleaning up
+        // danglers among them, and find the newest feed entry to drop.
+        let mut live_records_found = 0;
+        let mut cutoff = None;
+        let mut current_cursor: Option<Cursor> = None;
+        let mut batch = self.keyspace.batch();
+        for kv in self.feeds.range(live_range.clone()).rev() {
+            let (key_bytes, val_bytes) = kv?;
+            let feed_key = db_complete::<NsidRecordFeedKey>(&key_bytes)?;
+            let feed_val = db_complete::<NsidRecordFeedVal>(&val_bytes)?;
+            let location_key: RecordLocationKey = (&feed_key, &feed_val).into();
+            let location_key_bytes = location_key.to_db_bytes()?;
+
+            let Some(location_val_bytes) = self.records.get(&location_key_bytes)? else {
+                // record was deleted (hopefully)
+                batch.remove(&self.feeds, key_bytes);
+                dangling_feed_keys_cleaned += 1;
+                continue;
+            };
+
+            let (meta, _) = RecordLocationMeta::from_db_bytes(&location_val_bytes)?;
+            current_cursor = Some(meta.cursor());
+
+            if meta.cursor() != feed_key.cursor() {
+                // older/different version
+                batch.remove(&self.feeds, key_bytes);
+                dangling_feed_keys_cleaned += 1;
+                continue;
+            }
+            if meta.rev != feed_val.rev() {
+                // weird...
+                log::warn!("record lookup: cursor match but rev did not...? removing.");
+                batch.remove(&self.records, location_key_bytes);
+                batch.remove(&self.feeds, key_bytes);
+                dangling_feed_keys_cleaned += 1;
+                continue;
+            }
+
+            live_records_found += 1;
+            if live_records_found > limit {
+                cutoff = Some((key_bytes, feed_key.cursor()));
+                break;
+            }
+        }
+        batch.commit()?;
+
+        // then everything at or below the cutoff goes, oldest first. fjall has
+        // no range tombstones, so this is still a tombstone per key (batched,
+        // and capped per call like before). rocks drops its feed entries with
+        // a range tombstone instead.
+        let mut ended_early = false;
+        if let Some((cutoff_key, cutoff_cursor)) = cutoff {
+            let range = (
+                Bound::Included(live_range.start),
+                Bound::Included(cutoff_key.to_vec()),
+            );
+            let mut batch = self.keyspace.batch();
+            let mut removed = 0;
+            for (i, kv) in self.feeds.range(range).enumerate() {
+                if i >= MAX_TRIM_RANGE_ITEMS {
+                    log::info!(
+                        "trim: stopping at {i} for {:?} (was at {}), will resume next time",
+                        collection.to_string(),
+                        current_cursor
+                            .map(|c| c
+                                .elapsed()
+                                .map(nice_duration)
+                                .unwrap_or("[not past]".into()))
+                            .unwrap_or("??".into()),
+                    );
+                    ended_early = true;
+                    break;
+                }
+                let (key_bytes, val_bytes) = kv?;
+                let feed_key = db_complete::<NsidRecordFeedKey>(&key_bytes)?;
+                let feed_val = db_complete::<NsidRecordFeedVal>(&val_bytes)?;
+                let location_key: RecordLocationKey = (&feed_key, &feed_val).into();
+                let location_key_bytes = location_key.to_db_bytes()?;
+                current_cursor = Some(feed_key.cursor());
+
+                let current = self
+                    .records
+                    .get(&location_key_bytes)?
+                    .map(|bytes| RecordLocationMeta::from_db_bytes(&bytes))
+                    .transpose()?
+                    .is_some_and(|(meta, _)| meta.cursor() == feed_key.cursor());
+                if current {
+                    for version_key in self.version_keys(&location_key_bytes)? {
+                        batch.remove(&self.records, version_key);
+                    }
+                    batch.remove(&self.records, location_key_bytes);
+                    if let Some(search) = &self.search {
+                        search.remove(location_key.did(), collection, location_key.rkey());
+                    }
+                    records_deleted += 1;
+                } else {
+                    dangling_feed_keys_cleaned += 1;
+                }
+                batch.remove(&self.feeds, key_bytes);
+                removed += 1;
+
+                if batch.len() >= MAX_BATCHED_TRIM_ITEMS {
+                    batch.commit()?;
+                    batch = self.keyspace.batch();
+                }
+            }
+            batch.commit()?;
+            histogram!("storage_trim_range_removed").record(removed as f64);
+            if let Some(search) = &self.search {
+                search.commit_if_due();
+            }
+
+            if !ended_early {
+                self.global.insert(
+                    &TrimCollectionCursorKey::new(collection.clone()).to_db_bytes()?,
+                    &cutoff_cursor.to_db_bytes()?,
+                )?;
+            }
+        }
+
+        log::trace!("trim_collection ({collection:?}) removed {dangling_feed_keys_cleaned} dangling feed entries and {records_deleted} records (ended early? {ended_early})");
+        Ok((dangling_feed_keys_cleaned, records_deleted, ended_early))
+    }
+
+    /// Remove every record for an account
+    ///
+    /// Big accounts take several db batches. Each one saves a checkpoint, so an
+    /// interrupted delete resumes after the last removed record instead of
+    /// scanning over everything it already removed.
+    fn delete_account(&mut self, did: &Did) -> Result<usize, StorageError> {
+        let progress_key = DeleteAccountProgressKey::new(did.clone()).to_db_bytes()?;
+        let progress = self
+            .global
+            .get(&progress_key)?
+            .map(|bytes| db_complete::<DeleteAccountProgressVal>(&bytes))
+            .transpose()?;
+        let prefix = RecordLocationKey::from_prefix_to_db_bytes(did)?;
+        let end = RecordLocationKey::prefix_range_end(did)?;
+        let (start, mut total_deleted) = match progress {
+            // a checkpoint only ever resumes inside this account's own records
+            Some(DeleteAccountProgressVal {
+                records_deleted,
+                last_key,
+            }) if last_key.starts_with(&prefix) => {
+                counter!("storage_delete_account_resumed").increment(1);
+                log::info!("resuming delete for {did:?} after {records_deleted} records");
+                (Bound::Excluded(last_key), records_deleted)
+            }
+            Some(_) => {
+                log::warn!("ignoring a delete checkpoint outside {did:?}'s records");
+                (Bound::Included(prefix), 0)
+            }
+            None => (Bound::Included(prefix), 0),
+        };
+
+        let mut records_deleted = 0;
+        let mut batch = self.keyspace.batch();
+        for kv in self.records.range((start, Bound::Excluded(end))) {
+            let (key_bytes, _) = kv?;
+            batch.remove(&self.records, key_bytes.clone());
+            records_deleted += 1;
+            total_deleted += 1;
+            if batch.len() >= MAX_BATCHED_ACCOUNT_DELETE_RECORDS {
+                counter!("storage_delete_account_partial_commits").increment(1);
+                let progress = DeleteAccountProgressVal {
+                    records_deleted: total_deleted,
+                    last_key: key_bytes.to_vec(),
+                };
+                batch.insert(&self.global, &progress_key, progress.to_db_bytes()?);
+                batch.commit()?;
+                batch = self.keyspace.batch();
+            }
+        }
+        counter!("storage_delete_account_completions").increment(1);
+        counter!("storage_delete_account_records_deleted").increment(records_deleted as u64);
+        batch.remove(&self.global, progress_key);
+        batch.commit()?;
+        if let Some(search) = &self.search {
+            search.remove_account(did);
+            search.commit_if_due();
+        }
+        Ok(records_deleted)
+    }
+
+    fn account_deletes_status(&self) -> StorageResult<AccountDeletesStatus> {
+        let rollup_cursor =
+            get_static_neu::<NewRollupCursorKey, NewRollupCursorValue>(&self.global)?
+                .unwrap_or(Cursor::from_start());
+        let mut queued = 0;
+        for kv in self
+            .queues
+            .range(DeleteAccountQueueKey::new(rollup_cursor).range_to_prefix_end()?)
+        {
+            kv?;
+            queued += 1;
+        }
+        let mut in_progress = Vec::new();
+        let prefix = DeleteAccountProgressKey::from_prefix_to_db_bytes(&Default::default())?;
+        for kv in self.global.prefix(prefix) {
+            let (key_bytes, val_bytes) = kv?;
+            let key = db_complete::<DeleteAccountProgressKey>(&key_bytes)?;
+            let val = db_complete::<DeleteAccountProgressVal>(&val_bytes)?;
+            in_progress.push(AccountDeleteProgress {
+                did: key.did().to_string(),
+                records_deleted: val.records_deleted,
+            });
+        }
+        // the one in progress is still in the queue
+        queued = queued.saturating_sub(in_progress.len());
+        Ok(AccountDeletesStatus {
+            queued,
+            in_progress,
+        })
+    }
+}
+
+#[cfg(feature = "bench")]
+impl FjallWriter {
+    /// The trim from before it removed the range below the cutoff in batches
+    ///
+    /// Walks newest-first and writes each removal on its own. Only kept for
+    /// `benches/trim.rs` to measure [`StoreWriter::trim_collection`] against.
+    #[doc(hidden)]
+    pub fn trim_collection_per_key(
+        &mut self,
+        collection: &Nsid,
+        limit: usize,
+    ) -> StorageResult<(usize, usize)> {
+        let mut dangling_feed_keys_cleaned = 0;
+        let mut records_deleted = 0;
+
+        let feed_trim_cursor_key =
+            TrimCollectionCursorKey::new(collection.clone()).to_db_bytes()?;
+        let trim_cursor = self
+            .global
+            .get(&feed_trim_cursor_key)?
+            .map(|value_bytes| db_complete(&value_bytes))
+            .transpose()?
+            .unwrap_or(Cursor::from_start());
+        let live_range =
+            NsidRecordFeedKey::from_pair(collection.clone(), trim_cursor).range_to_prefix_end()?;
+
+        let mut live_records_found = 0;
+        let mut candidate_new_feed_lower_cursor = None;
+        for kv in self.feeds.range(live_range).rev() {
+            let (key_bytes, val_bytes) = kv?;
+            let feed_key = db_complete::<NsidRecordFeedKey>(&key_bytes)?;
+            let feed_val = db_complete::<NsidRecordFeedVal>(&val_bytes)?;
+            let location_key: RecordLocationKey = (&feed_key, &feed_val).into();
+            let location_key_bytes = location_key.to_db_bytes()?;
+
+            let Some(location_val_bytes) = self.records.get(&location_key_bytes)? else {
+                self.feeds.remove(&*key_bytes)?;
+                dangling_feed_keys_cleaned += 1;
+                continue;
+            };
+            let (meta, _) = RecordLocationMeta::from_db_bytes(&location_val_bytes)?;
+            if meta.cursor() != feed_key.cursor() || meta.rev != feed_val.rev() {
+                self.feeds.remove(&*key_bytes)?;
+                dangling_feed_keys_cleaned += 1;
+                continue;
+            }
+
+            live_records_found += 1;
+            if live_records_found <= limit {
+                continue;
+            }
+            if candidate_new_feed_lower_cursor.is_none() {
+                candidate_new_feed_lower_cursor = Some(feed_key.cursor());
+            }
+            for version_key in self.version_keys(&location_key_bytes)? {
+                self.records.remove(version_key)?;
+            }
+            self.records.remove(&location_key_bytes)?;
+            self.feeds.remove(key_bytes)?;
+            records_deleted += 1;
+        }
+
+        if let Some(new_cursor) = candidate_new_feed_lower_cursor {
+            self.global
+                .insert(&feed_trim_cursor_key, &new_cursor.to_db_bytes()?)?;
+        }
+        Ok((dangling_feed_keys_cleaned, records_deleted))
+    }
+}
+
+/// Batches consumed but not yet inserted, replayed at startup
+#[derive(Clone)]
+pub struct FjallSpill {
+    partition: PartitionHandle,
+    next: Arc<AtomicU64>,
+}
+
+impl FjallSpill {
+    fn open(keyspace: &Keyspace) -> StorageResult<Self> {
+        let partition = keyspace.open_partition("spill", PartitionCreateOptions::default())?;
+        let next = match partition.last_key_value()? {
+            Some((key, _)) => spill_position(&key)? + 1,
+            None => 0,
+        };
+        if !partition.is_empty()? {
+            log::info!("found queued batches from a previous run, they will be replayed");
+        }
+        Ok(Self {
+            partition,
+            next: Arc::new(AtomicU64::new(next)),
+        })
+    }
+
+    /// Drop queued batches covered by an insert, in the same db batch
+    fn inserted_through(&self, batch: &mut FjallBatch, cursor: Cursor) -> StorageResult<()> {
+        for kv in self.partition.iter() {
+            let (key, val) = kv?;
+            let (queued, _) = Cursor::from_db_bytes(&val)?;
+            if queued > cursor {
+                break;
+            }
+            batch.remove(&self.partition, key);
+        }
+        Ok(())
+    }
+}
+
+fn spill_position(key: &[u8]) -> StorageResult<u64> {
+    let bytes: [u8; 8] = key.try_into().map_err(EncodingError::BadSlice)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+impl SpillQueue for FjallSpill {
+    fn push(&self, latest: Option<Cursor>, batch: Vec<u8>) -> StorageResult<u64> {
+        let position = self.next.fetch_add(1, Ordering::SeqCst);
+        // empty batches have no cursor: they're dropped with the next insert
+        let mut val = latest.unwrap_or(Cursor::from_start()).to_db_bytes()?;
+        val.extend(batch);
+        self.partition.insert(position.to_be_bytes(), val)?;
+        Ok(position)
+    }
+    fn get(&self, position: u64) -> StorageResult<Option<Vec<u8>>> {
+        let Some(val) = self.partition.get(position.to_be_bytes())? else {
+            return Ok(None);
+        };
+        let (_, n) = Cursor::from_db_bytes(&val)?;
+        Ok(Some(val[n..].to_vec()))
+    }
+    fn queued(&self) -> StorageResult<Vec<u64>> {
+        self.partition
+            .keys()
+            .map(|key| spill_position(&key?))
+            .collect()
+    }
+    fn latest_cursor(&self) -> StorageResult<Option<Cursor>> {
+        let Some((_, val)) = self.partition.last_key_value()? else {
+            return Ok(None);
+        };
+        Ok(Some(Cursor::from_db_bytes(&val)?.0))
+    }
+}
+
+/// How many feed entries and records to check each consistency sampling tick
+const CONSISTENCY_SAMPLES: usize = 16;
+/// How many recent checks the rolling dangling rate covers
+const CONSISTENCY_WINDOW: usize = 2_000;
+
+#[derive(Debug, Default, PartialEq)]
+struct ConsistencySample {
+    feeds_checked: usize,
+    feeds_dangling: usize,
+    records_checked: usize,
+    records_orphaned: usize,
+}
+
+/// Pass/fail results of the most recent consistency checks
+#[derive(Default)]
+struct RollingRate(VecDeque<bool>);
+impl RollingRate {
+    fn record(&mut self, checked: usize, failed: usize) {
+        for i in 0..checked {
+            if self.0.len() >= CONSISTENCY_WINDOW {
+                self.0.pop_front();
+            }
+            self.0.push_back(i < failed);
+        }
+    }
+    fn rate(&self) -> f64 {
+        if self.0.is_empty() {
+            return 0.;
+        }
+        self.0.iter().filter(|failed| **failed).count() as f64 / self.0.len() as f64
+    }
+}
+
+/// Keys read from the start of a prefix before the rest are interpolated
+const APPROX_COUNT_SAMPLE: usize = 64;
+
+/// Estimate how many keys share a prefix without walking them all
+///
+/// fjall only estimates the length of whole partitions. Past the first
+/// [`APPROX_COUNT_SAMPLE`] keys, this assumes the rest are spread through the
+/// key space up to the last one as densely as the first ones were, which
+/// holds up for cursor-ordered feeds and TID rkeys.
+fn approximate_prefix_len(snapshot: &Snapshot, prefix: Vec<u8>) -> StorageResult<u64> {
+    let mut keys = snapshot.prefix(prefix);
+    let mut first = None;
+    let mut sampled = None;
+    let mut n = 0;
+    while n < APPROX_COUNT_SAMPLE {
+        let Some(kv) = keys.next() else {
+            return Ok(n as u64); // counted them all
+        };
+        let (key, _) = kv?;
+        first.get_or_insert_with(|| key.clone());
+        sampled = Some(key);
+        n += 1;
+    }
+    let (Some(first), Some(sampled), Some(last)) = (first, sampled, keys.next_back()) else {
+        return Ok(n as u64);
+    };
+    let (last, _) = last?;
+    Ok(interpolate_len(n as u64, &first, &sampled, &last))
+}
+
+/// Scale up `n` keys from `first` through `sampled` to the span through `last`
+fn interpolate_len(n: u64, first: &[u8], sampled: &[u8], last: &[u8]) -> u64 {
+    // like random_entry, interpolate over the eight bytes after the shared prefix
+    let shared = first
+        .iter()
+        .zip(last.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let position = |key: &[u8]| {
+        let mut bytes = [0u8; 8];
+        for (b, k) in bytes.iter_mut().zip(key.iter().skip(shared)) {
+            *b = *k;
+        }
+        u64::from_be_bytes(bytes)
+    };
+    let covered = position(sampled).saturating_sub(position(first));
+    let span = position(last).saturating_sub(position(first));
+    if covered == 0 {
+        return n + 1; // no spread to go on
+    }
+    let estimate = n as f64 * span as f64 / covered as f64;
+    (estimate as u64).max(n + 1)
+}
+
+/// The entry at or after a random point in a partition's key space
+///
+/// Not uniform: keys in sparse parts of the key space get picked more often.
+/// That's fine for catching drift, which isn't clustered by key anyway.
+fn random_entry(
+    partition: &PartitionHandle,
+    randomness: &Randomness,
+) -> StorageResult<Option<(fjall::Slice, fjall::Slice)>> {
+    let (Some((first, _)), Some(last)) =
+        (partition.first_key_value()?, partition.last_key_value()?)
+    else {
+        return Ok(None);
+    };
+    // interpolate over the eight bytes after the prefix every key shares
+    let shared = first
+        .iter()
+        .zip(last.0.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let position = |key: &[u8]| {
+        let mut bytes = [0u8; 8];
+        for (b, k) in bytes.iter_mut().zip(key.iter().skip(shared)) {
+            *b = *k;
+        }
+        u64::from_be_bytes(bytes)
+    };
+    let (lo, hi) = (position(&first), position(&last.0));
+    let mut random = [0u8; 8];
+    randomness.fill(&mut random).map_err(|e| {
+        StorageError::BadStateError(format!("failed to get randomness for sampling: {e:?}"))
+    })?;
+    let at = lo + u64::from_be_bytes(random) % (hi - lo).saturating_add(1);
+    let mut seek = first[..shared].to_vec();
+    seek.extend(at.to_be_bytes());
+    match partition.range(seek..).next() {
+        Some(kv) => Ok(Some(kv?)),
+        None => Ok(Some(last)),
+    }
+}
+
+/// Entries the scrub checks per tick
+const SCRUB_STEP: usize = 2_048;
+
+/// Partitions the scrub checks, in turn
+///
+/// These are the ones read by query-time iterators, where one bad entry
+/// would fail a whole request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Scrubbed {
+    Feeds,
+    Records,
+}
+impl Scrubbed {
+    fn next(self) -> Self {
+        match self {
+            Self::Feeds => Self::Records,
+            Self::Records => Self::Feeds,
+        }
+    }
+}
+
+/// A scrub's progress through one partition
+///
+/// Not persisted: a restart begins a fresh pass over the feeds.
+struct ScrubPass {
+    partition: Scrubbed,
+    /// the last key checked
+    after: Option<fjall::Slice>,
+    /// rolling over every key and value checked so far in the pass
+    checksum: Sha256,
+    checked: u64,
+    quarantined: u64,
+    started: Instant,
+}
+impl ScrubPass {
+    fn new(partition: Scrubbed) -> Self {
+        Self {
+            partition,
+            after: None,
+            checksum: Sha256::new(),
+            checked: 0,
+            quarantined: 0,
+            started: Instant::now(),
+        }
+    }
+}
+
+/// Check that a feed entry decodes
+fn check_feed_entry(key_bytes: &[u8], val_bytes: &[u8]) -> EncodingResult<()> {
+    db_complete::<NsidRecordFeedKey>(key_bytes)?;
+    db_complete::<NsidRecordFeedVal>(val_bytes)?;
+    Ok(())
+}
+
+/// Check that a record or an older version of one decodes, including its json
+fn check_record_entry(key_bytes: &[u8], val_bytes: &[u8]) -> EncodingResult<()> {
+    let (_, n) = RecordLocationKey::from_db_bytes(key_bytes)?;
+    if n < key_bytes.len() {
+        db_complete::<RecordVersionKey>(key_bytes)?;
+    }
+    let location_val = db_complete::<RecordLocationVal>(val_bytes)?;
+    let _: Box<RawValue> = location_val.suffix.try_into()?;
+    Ok(())
+}
+
+/// The background loop's schedule, shared with the admin API
+#[derive(Debug, Default)]
+pub(crate) struct BackgroundSchedule {
+    state: Mutex<ScheduleState>,
+    pub(crate) rollup_now: tokio::sync::Notify,
+    pub(crate) trim_now: tokio::sync::Notify,
+    pub(crate) reconfigure: tokio::sync::Notify,
+}
+#[derive(Debug, Default)]
+pub(crate) struct ScheduleState {
+    running: bool,
+    /// set from config or the admin api, otherwise the loop picks defaults
+    pub(crate) intervals: Option<BackgroundIntervals>,
+    pub(crate) next_rollup: Option<Instant>,
+    pub(crate) next_trim: Option<Instant>,
+    pub(crate) dirty_nsids: usize,
+    pub(crate) last_rollup_items: usize,
+    pub(crate) last_trim: Option<Duration>,
+}
+impl BackgroundSchedule {
+    pub(crate) fn new(intervals: Option<BackgroundIntervals>) -> Self {
+        let schedule = Self::default();
+        schedule.update(|s| s.intervals = intervals);
+        schedule
+    }
+    pub(crate) fn intervals(&self, backfill: bool) -> BackgroundIntervals {
+        let mut state = self.state.lock().unwrap();
+        *state
+            .intervals
+            .get_or_insert_with(|| BackgroundIntervals::defaults(backfill))
+    }
+    pub(crate) fn update(&self, f: impl FnOnce(&mut ScheduleState)) {
+        f(&mut self.state.lock().unwrap())
+    }
+    pub(crate) fn status(&self) -> BackgroundStatus {
+        let state = self.state.lock().unwrap();
+        let until = |t: Option<Instant>| {
+            t.map(|t| t.saturating_duration_since(Instant::now()).as_millis() as u64)
+        };
+        BackgroundStatus {
+            running: state.running,
+            intervals: state.intervals.unwrap_or_default(),
+            next_rollup_ms: until(state.next_rollup),
+            next_trim_ms: until(state.next_trim),
+            dirty_nsids: state.dirty_nsids,
+            last_rollup_items: state.last_rollup_items,
+            last_trim_ms: state.last_trim.map(|dt| dt.as_millis() as u64),
+        }
+    }
+    pub(crate) fn trigger(&self, task: BackgroundTask) -> bool {
+        if !self.state.lock().unwrap().running {
+            return false;
+        }
+        match task {
+            BackgroundTask::Rollup => self.rollup_now.notify_one(),
+            BackgroundTask::Trim => self.trim_now.notify_one(),
+        }
+        true
+    }
+}
+
+/// Marks the schedule as running for as long as the background loop is
+pub(crate) struct ScheduleRunning(pub(crate) Arc<BackgroundSchedule>);
+impl ScheduleRunning {
+    pub(crate) fn new(schedule: Arc<BackgroundSchedule>) -> Self {
+        schedule.update(|s| s.running = true);
+        Self(schedule)
+    }
+}
+impl Drop for ScheduleRunning {
+    fn drop(&mut self) {
+        self.0.update(|s| {
+            s.running = false;
+            s.next_rollup = None;
+            s.next_trim = None;
+        });
+    }
+}
+
+pub(crate) fn rollup_interval(intervals: &BackgroundIntervals) -> tokio::time::Interval {
+    let mut rollup = tokio::time::interval(Duration::from_micros(intervals.rollup_us));
+    rollup.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    rollup
+}
+
+pub(crate) fn trim_interval(intervals: &BackgroundIntervals) -> tokio::time::Interval {
+    let mut trim = tokio::time::interval(Duration::from_millis(intervals.trim_ms));
+    trim.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    trim
+}
+
+pub struct FjallBackground(FjallWriter);
+
+#[async_trait]
+impl StoreBackground for FjallBackground {
+    async fn run(mut self, backfill: bool) -> StorageResult<()> {
+        let mut dirty_nsids = HashSet::new();
+        // rolled up since the last trim tick, for cache invalidation
+        let mut changed_nsids = HashSet::new();
+
+        let mut intervals = self.0.schedule.intervals(backfill);
+        let mut rollup = rollup_interval(&intervals);
+        let mut trim = trim_interval(&intervals);
+
+        // low-rate: this is for noticing index drift, not for fixing it
+        let mut verify = tokio::time::interval(Duration::from_secs(30));
+        verify.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        let (mut feeds_rate, mut records_rate) = (RollingRate::default(), RollingRate::default());
+
+        // slow and steady: a full pass can take days on a big db, and that's fine
+        let mut scrub = tokio::time::interval(Duration::from_secs(5));
+        scrub.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        let mut scrub_pass = Some(ScrubPass::new(Scrubbed::Feeds));
+
+        let schedule = ScheduleRunning::new(self.0.schedule.clone());
+
+        loop {
+            tokio::select! {
+                _ = rollup.tick() => {
+                    let mut db = self.0.clone();
+                    let (n, dirty) = tokio::task::spawn_blocking(move || db.step_rollup()).await??;
+                    counter!("storage_rollup_steps").increment(1);
+                    counter!("storage_rollup_items").increment(n as u64);
+                    let mut next_rollup = rollup.period();
+                    if n == 0 {
+                        next_rollup = Duration::from_millis(intervals.rollup_idle_ms);
+                        rollup.reset_after(next_rollup); // we're caught up, take a break
+                    }
+                    if self.0.dirty.is_some() {
+                        changed_nsids.extend(dirty.iter().cloned());
+                    }
+                    dirty_nsids.extend(dirty);
+                    log::trace!("rolled up {n} items ({} collections now dirty)", dirty_nsids.len());
+                    schedule.0.update(|s| {
+                        s.next_rollup = Some(Instant::now() + next_rollup);
+                        s.last_rollup_items = n;
+                        s.dirty_nsids = dirty_nsids.len();
+                    });
+                },
+                _ = schedule.0.rollup_now.notified() => {
+                    log::info!("rollup triggered from the admin api");
+                    rollup.reset_immediately();
+                },
+                _ = schedule.0.trim_now.notified() => {
+                    log::info!("trim triggered from the admin api");
+                    trim.reset_immediately();
+                },
+                _ = schedule.0.reconfigure.notified() => {
+                    intervals = schedule.0.intervals(backfill);
+                    log::info!("background intervals changed to {intervals:?}");
+                    rollup = rollup_interval(&intervals);
+                    rollup.reset();
+                    trim = trim_interval(&intervals);
+                    trim.reset();
+                },
+                _ = trim.tick() => {
+                    if let Some(tap) = &self.0.dirty {
+                        tap.offer(std::mem::take(&mut changed_nsids));
+                    }
+                    let n = dirty_nsids.len();
+                    log::trace!("trimming {n} nsids: {dirty_nsids:?}");
+                    let t0 = Instant::now();
+                    let (mut total_danglers, mut total_deleted) = (0, 0);
+                    let mut completed = HashSet::new();
+                    for collection in &dirty_nsids {
+                        let mut db = self.0.clone();
+                        let c = collection.clone();
+                        let limit = self.0.trim_limit(&c);
+                        let (danglers, deleted, ended_early) = tokio::task::spawn_blocking(move || db.trim_collection(&c, limit, false)).await??;
+                        total_danglers += danglers;
+                        total_deleted += deleted;
+                        if !ended_early {
+                            completed.insert(collection.clone());
+                        }
+                        if total_deleted > 10_000_000 {
+                            log::info!("trim stopped early, more than 10M records already deleted.");
+                            break;
+                        }
+                    }
+                    let dt = t0.elapsed();
+                    log::trace!("finished trimming {n} nsids in {dt:?}: {total_danglers} dangling and {total_deleted} total removed.");
+                    histogram!("storage_trim_dirty_nsids").record(completed.len() as f64);
+                    histogram!("storage_trim_duration").record(dt.as_micros() as f64);
+                    counter!("storage_trim_removed", "dangling" => "true").increment(total_danglers as u64);
+                    if total_deleted >= total_danglers {
+                        counter!("storage_trim_removed", "dangling" => "false").increment((total_deleted - total_danglers) as u64);
+                    } else {
+                        // TODO: probably think through what's happening here
+                        log::warn!("weird trim case: more danglers than deleted? metric will be missing for dangling=false. deleted={total_deleted} danglers={total_danglers}");
+                    }
+                    for c in completed {
+                        dirty_nsids.remove(&c);
                     }
-                    CommitAction::Put(put_action) => {
-                        let feed_key = NsidRecordFeedKey::from_pair(nsid.clone(), commit.cursor);
-                        let feed_val: NsidRecordFeedVal =
-                            (&commit.did, &commit.rkey, commit.rev.as_str()).into();
-                        batch.insert(
-                            &self.feeds,
-                            feed_key.to_db_bytes()?,
-                            feed_val.to_db_bytes()?,
-                        );
+                    let tracked = self.0.popularity.prune(Instant::now());
+                    gauge!("storage_read_popularity_tracked").set(tracked as f64);
+                    schedule.0.update(|s| {
+                        s.next_trim = Some(Instant::now() + trim.period());
+                        s.last_trim = Some(dt);
+                        s.dirty_nsids = dirty_nsids.len();
+                    });
+
+                    let db = self.0.clone();
+                    let changes_trimmed = tokio::task::spawn_blocking(move || db.trim_changes()).await??;
+                    counter!("storage_trim_changes_removed").increment(changes_trimmed as u64);
+
+                    let db = self.0.clone();
+                    let hits_trimmed = tokio::task::spawn_blocking(move || db.trim_watch_hits()).await??;
+                    counter!("storage_trim_watch_hits_removed").increment(hits_trimmed as u64);
+
+                    let db = self.0.clone();
+                    let tombstones_trimmed = tokio::task::spawn_blocking(move || db.trim_tombstones()).await??;
+                    counter!("storage_trim_tombstones_removed").increment(tombstones_trimmed as u64);
+                },
+                _ = verify.tick() => {
+                    let db = self.0.clone();
+                    let sample = tokio::task::spawn_blocking(move || db.sample_consistency(CONSISTENCY_SAMPLES)).await??;
+                    feeds_rate.record(sample.feeds_checked, sample.feeds_dangling);
+                    records_rate.record(sample.records_checked, sample.records_orphaned);
+                    counter!("storage_consistency_sampled", "sample" => "feeds").increment(sample.feeds_checked as u64);
+                    counter!("storage_consistency_sampled", "sample" => "records").increment(sample.records_checked as u64);
+                    gauge!("storage_consistency_dangling_rate", "sample" => "feeds").set(feeds_rate.rate());
+                    gauge!("storage_consistency_dangling_rate", "sample" => "records").set(records_rate.rate());
+                    if sample.records_orphaned > 0 {
+                        log::warn!("consistency: {} of {} sampled records have no feed entry", sample.records_orphaned, sample.records_checked);
+                    }
+                },
+                _ = scrub.tick() => {
+                    let db = self.0.clone();
+                    let pass = scrub_pass.take().expect("put back after every step");
+                    scrub_pass = Some(tokio::task::spawn_blocking(move || db.scrub_step(pass, SCRUB_STEP)).await??);
+                },
+            };
+        }
+    }
+}
+
+/// Get a value from a fixed key
+fn get_static_neu<K: StaticStr, V: DbBytes>(global: &PartitionHandle) -> StorageResult<Option<V>> {
+    let key_bytes = DbStaticStr::<K>::default().to_db_bytes()?;
+    let value = global
+        .get(&key_bytes)?
+        .map(|value_bytes| db_complete(&value_bytes))
+        .transpose()?;
+    Ok(value)
+}
+
+/// Get a value from a fixed key
+fn get_snapshot_static_neu<K: StaticStr, V: DbBytes>(
+    global: &fjall::Snapshot,
+) -> StorageResult<Option<V>> {
+    let key_bytes = DbStaticStr::<K>::default().to_db_bytes()?;
+    let value = global
+        .get(&key_bytes)?
+        .map(|value_bytes| db_complete(&value_bytes))
+        .transpose()?;
+    Ok(value)
+}
+
+/// Set a value to a fixed key
+fn insert_static_neu<K: StaticStr>(
+    global: &PartitionHandle,
+    value: impl DbBytes,
+) -> StorageResult<()> {
+    let key_bytes = DbStaticStr::<K>::default().to_db_bytes()?;
+    let value_bytes = value.to_db_bytes()?;
+    global.insert(&key_bytes, &value_bytes)?;
+    Ok(())
+}
+
+/// Set a value to a fixed key, erroring if the value already exists
+///
+/// Intended for single-threaded init: not safe under concurrency, since there
+/// is no transaction between checking if the already exists and writing it.
+fn init_static_neu<K: StaticStr>(
+    global: &PartitionHandle,
+    value: impl DbBytes,
+) -> StorageResult<()> {
+    let key_bytes = DbStaticStr::<K>::default().to_db_bytes()?;
+    if global.get(&key_bytes)?.is_some() {
+        return Err(StorageError::InitError(format!(
+            "init failed: value for key {key_bytes:?} already exists"
+        )));
+    }
+    let value_bytes = value.to_db_bytes()?;
+    global.insert(&key_bytes, &value_bytes)?;
+    Ok(())
+}
+
+/// Set a value to a fixed key
+fn insert_batch_static_neu<K: StaticStr>(
+    batch: &mut FjallBatch,
+    global: &PartitionHandle,
+    value: impl DbBytes,
+) -> StorageResult<()> {
+    let key_bytes = DbStaticStr::<K>::default().to_db_bytes()?;
+    let value_bytes = value.to_db_bytes()?;
+    batch.insert(global, &key_bytes, &value_bytes);
+    Ok(())
+}
+
+#[derive(Debug, serde::Serialize, schemars::JsonSchema)]
+pub struct StorageInfo {
+    pub keyspace_disk_space: u64,
+    pub keyspace_journal_count: usize,
+    pub keyspace_sequence: u64,
+    pub global_approximate_len: usize,
+}
+
+////////// temp stuff to remove:
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CursorGap, DeleteAccount, RecordKey, UFOsCommit};
+    use jetstream::events::{CommitEvent, CommitOp};
+    use jetstream::exports::Cid;
+    use serde_json::value::RawValue;
+
+    fn fjall_db() -> (FjallReader, FjallWriter) {
+        let (read, write, _, _) = FjallStorage::init(
+            tempfile::tempdir().unwrap(),
+            "offline test (no real jetstream endpoint)".to_string(),
+            false,
+            FjallConfig {
+                temp: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        (read, write)
+    }
+
+    const TEST_BATCH_LIMIT: usize = 16;
+    /// more than any test scans, for tests that aren't about the budget
+    const BUDGET: ScanBudget = ScanBudget::new(1_000_000);
+    fn beginning() -> HourTruncatedCursor {
+        Cursor::from_start().into()
+    }
+
+    #[derive(Debug, Default)]
+    struct TestBatch {
+        pub batch: EventBatch<TEST_BATCH_LIMIT>,
+    }
+
+    impl TestBatch {
+        #[allow(clippy::too_many_arguments)]
+        pub fn create(
+            &mut self,
+            did: &str,
+            collection: &str,
+            rkey: &str,
+            record: &str,
+            rev: Option<&str>,
+            cid: Option<Cid>,
+            cursor: u64,
+        ) -> Nsid {
+            let did = Did::new(did.to_string()).unwrap();
+            let collection = Nsid::new(collection.to_string()).unwrap();
+            let record = RawValue::from_string(record.to_string()).unwrap();
+            let cid = cid.unwrap_or(
+                "bafyreidofvwoqvd2cnzbun6dkzgfucxh57tirf3ohhde7lsvh4fu3jehgy"
+                    .parse()
+                    .unwrap(),
+            );
+
+            let event = CommitEvent {
+                collection,
+                rkey: RecordKey::new(rkey.to_string()).unwrap(),
+                rev: rev.unwrap_or("asdf").to_string(),
+                operation: CommitOp::Create,
+                record: Some(record),
+                cid: Some(cid),
+            };
+
+            let (commit, collection) =
+                UFOsCommit::from_commit_info(event, did.clone(), Cursor::from_raw_u64(cursor))
+                    .unwrap();
+
+            self.batch
+                .commits_by_nsid
+                .entry(collection.clone())
+                .or_default()
+                .truncating_insert(commit, &[0u8; 16])
+                .unwrap();
+
+            collection
+        }
+        #[allow(clippy::too_many_arguments)]
+        pub fn update(
+            &mut self,
+            did: &str,
+            collection: &str,
+            rkey: &str,
+            record: &str,
+            rev: Option<&str>,
+            cid: Option<Cid>,
+            cursor: u64,
+        ) -> Nsid {
+            let did = Did::new(did.to_string()).unwrap();
+            let collection = Nsid::new(collection.to_string()).unwrap();
+            let record = RawValue::from_string(record.to_string()).unwrap();
+            let cid = cid.unwrap_or(
+                "bafyreidofvwoqvd2cnzbun6dkzgfucxh57tirf3ohhde7lsvh4fu3jehgy"
+                    .parse()
+                    .unwrap(),
+            );
+
+            let event = CommitEvent {
+                collection,
+                rkey: RecordKey::new(rkey.to_string()).unwrap(),
+                rev: rev.unwrap_or("asdf").to_string(),
+                operation: CommitOp::Update,
+                record: Some(record),
+                cid: Some(cid),
+            };
+
+            let (commit, collection) =
+                UFOsCommit::from_commit_info(event, did.clone(), Cursor::from_raw_u64(cursor))
+                    .unwrap();
+
+            self.batch
+                .commits_by_nsid
+                .entry(collection.clone())
+                .or_default()
+                .truncating_insert(commit, &[0u8; 16])
+                .unwrap();
+
+            collection
+        }
+        #[allow(clippy::too_many_arguments)]
+        pub fn delete(
+            &mut self,
+            did: &str,
+            collection: &str,
+            rkey: &str,
+            rev: Option<&str>,
+            cursor: u64,
+        ) -> Nsid {
+            let did = Did::new(did.to_string()).unwrap();
+            let collection = Nsid::new(collection.to_string()).unwrap();
+            let event = CommitEvent {
+                collection,
+                rkey: RecordKey::new(rkey.to_string()).unwrap(),
+                rev: rev.unwrap_or("asdf").to_string(),
+                operation: CommitOp::Delete,
+                record: None,
+                cid: None,
+            };
+
+            let (commit, collection) =
+                UFOsCommit::from_commit_info(event, did, Cursor::from_raw_u64(cursor)).unwrap();
+
+            self.batch
+                .commits_by_nsid
+                .entry(collection.clone())
+                .or_default()
+                .truncating_insert(commit, &[0u8; 16])
+                .unwrap();
+
+            collection
+        }
+        pub fn delete_account(&mut self, did: &str, cursor: u64) -> Did {
+            let did = Did::new(did.to_string()).unwrap();
+            self.batch.account_removes.push(DeleteAccount {
+                did: did.clone(),
+                cursor: Cursor::from_raw_u64(cursor),
+            });
+            did
+        }
+    }
+
+    #[test]
+    fn test_ephemeral_refuses_existing_data() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("precious"), "not a throwaway")?;
+        let init = FjallStorage::init(
+            dir.path(),
+            "offline test (no real jetstream endpoint)".to_string(),
+            false,
+            FjallConfig {
+                ephemeral: true,
+                ..Default::default()
+            },
+        );
+        assert!(matches!(init, Err(StorageError::InitError(_))));
+        assert!(dir.path().join("precious").exists());
+
+        // a directory that doesn't exist yet is fine
+        FjallStorage::init(
+            dir.path().join("fresh"),
+            "offline test (no real jetstream endpoint)".to_string(),
+            false,
+            FjallConfig {
+                ephemeral: true,
+                ..Default::default()
+            },
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_hello() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
+        write.insert_batch::<TEST_BATCH_LIMIT>(EventBatch::default())?;
+        let JustCount {
+            creates,
+            dids_estimate,
+            ..
+        } = read.get_collection_counts(
+            &Nsid::new("a.b.c".to_string()).unwrap(),
+            beginning(),
+            None,
+        )?;
+        assert_eq!(creates, 0);
+        assert_eq!(dids_estimate, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_one() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
+
+        let mut batch = TestBatch::default();
+        let collection = batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "asdf",
+            "{}",
+            Some("rev-z"),
+            None,
+            100,
+        );
+        write.insert_batch(batch.batch)?;
+        write.step_rollup()?;
+
+        let JustCount {
+            creates,
+            dids_estimate,
+            ..
+        } = read.get_collection_counts(&collection, beginning(), None)?;
+        assert_eq!(creates, 1);
+        assert_eq!(dids_estimate, 1);
+        let JustCount {
+            creates,
+            dids_estimate,
+            ..
+        } = read.get_collection_counts(
+            &Nsid::new("d.e.f".to_string()).unwrap(),
+            beginning(),
+            None,
+        )?;
+        assert_eq!(creates, 0);
+        assert_eq!(dids_estimate, 0);
+
+        let (records, _, _) =
+            read.get_records_by_collections([collection].into(), 2, false, BUDGET)?;
+        assert_eq!(records.len(), 1);
+        let rec = &records[0];
+        assert_eq!(rec.record.get(), "{}");
+        assert!(!rec.is_update);
+
+        let (records, _, _) = read.get_records_by_collections(
+            [Nsid::new("d.e.f".to_string()).unwrap()].into(),
+            2,
+            false,
+            BUDGET,
+        )?;
+        assert_eq!(records.len(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_multi_collection() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
+
+        let mut batch = TestBatch::default();
+        batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.a.a",
+            "aaa",
+            r#""earliest""#,
+            Some("rev-a"),
+            None,
+            100,
+        );
+        batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.a.b",
+            "aab",
+            r#""in between""#,
+            Some("rev-ab"),
+            None,
+            101,
+        );
+        batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.a.a",
+            "aaa-2",
+            r#""last""#,
+            Some("rev-a-2"),
+            None,
+            102,
+        );
+        write.insert_batch(batch.batch)?;
+
+        let (records, _, _) = read.get_records_by_collections(
+            HashSet::from([
+                Nsid::new("a.a.a".to_string()).unwrap(),
+                Nsid::new("a.a.b".to_string()).unwrap(),
+                Nsid::new("a.a.c".to_string()).unwrap(),
+            ]),
+            100,
+            false,
+            BUDGET,
+        )?;
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].record.get(), r#""last""#);
+        assert_eq!(
+            records[0].collection,
+            Nsid::new("a.a.a".to_string()).unwrap()
+        );
+        assert_eq!(records[1].record.get(), r#""in between""#);
+        assert_eq!(
+            records[1].collection,
+            Nsid::new("a.a.b".to_string()).unwrap()
+        );
+        assert_eq!(records[2].record.get(), r#""earliest""#);
+        assert_eq!(
+            records[2].collection,
+            Nsid::new("a.a.a".to_string()).unwrap()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_multi_collection_expanded() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
+
+        let mut batch = TestBatch::default();
+        // insert some older ones in aab
+        for i in 1..=3 {
+            batch.create(
+                "did:plc:inze6wrmsm7pjl7yta3oig77",
+                "a.a.b",
+                &format!("aab-{i}"),
+                &format!(r#""b {i}""#),
+                Some(&format!("rev-b-{i}")),
+                None,
+                100 + i,
+            );
+        }
+        // and some newer ones in aaa
+        for i in 1..=3 {
+            batch.create(
+                "did:plc:inze6wrmsm7pjl7yta3oig77",
+                "a.a.a",
+                &format!("aaa-{i}"),
+                &format!(r#""a {i}""#),
+                Some(&format!("rev-a-{i}")),
+                None,
+                200 + i,
+            );
+        }
+        write.insert_batch(batch.batch)?;
+
+        let (records, _, _) = read.get_records_by_collections(
+            HashSet::from([
+                Nsid::new("a.a.a".to_string()).unwrap(),
+                Nsid::new("a.a.b".to_string()).unwrap(),
+                Nsid::new("a.a.c".to_string()).unwrap(),
+            ]),
+            2,
+            true,
+            BUDGET,
+        )?;
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[0].record.get(), r#""a 3""#);
+        assert_eq!(
+            records[0].collection,
+            Nsid::new("a.a.a".to_string()).unwrap()
+        );
+
+        assert_eq!(records[3].record.get(), r#""b 2""#);
+        assert_eq!(
+            records[3].collection,
+            Nsid::new("a.a.b".to_string()).unwrap()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_one() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
+
+        let mut batch = TestBatch::default();
+        let collection = batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-asdf",
+            "{}",
+            Some("rev-a"),
+            None,
+            100,
+        );
+        write.insert_batch(batch.batch)?;
+
+        let mut batch = TestBatch::default();
+        batch.update(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-asdf",
+            r#"{"ch":  "ch-ch-ch-changes"}"#,
+            Some("rev-z"),
+            None,
+            101,
+        );
+        write.insert_batch(batch.batch)?;
+        write.step_rollup()?;
+
+        let JustCount {
+            creates,
+            dids_estimate,
+            ..
+        } = read.get_collection_counts(&collection, beginning(), None)?;
+        assert_eq!(creates, 1);
+        assert_eq!(dids_estimate, 1);
+
+        let (records, _, _) =
+            read.get_records_by_collections([collection].into(), 2, false, BUDGET)?;
+        assert_eq!(records.len(), 1);
+        let rec = &records[0];
+        assert_eq!(rec.record.get(), r#"{"ch":  "ch-ch-ch-changes"}"#);
+        assert!(rec.is_update);
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_one() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
+
+        let mut batch = TestBatch::default();
+        let collection = batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-asdf",
+            "{}",
+            Some("rev-a"),
+            None,
+            100,
+        );
+        write.insert_batch(batch.batch)?;
+
+        let mut batch = TestBatch::default();
+        batch.delete(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-asdf",
+            Some("rev-z"),
+            101,
+        );
+        write.insert_batch(batch.batch)?;
+        write.step_rollup()?;
+
+        let JustCount {
+            creates,
+            dids_estimate,
+            ..
+        } = read.get_collection_counts(&collection, beginning(), None)?;
+        assert_eq!(creates, 1);
+        assert_eq!(dids_estimate, 1);
+
+        let (records, _, _) =
+            read.get_records_by_collections([collection].into(), 2, false, BUDGET)?;
+        assert_eq!(records.len(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_removes_feed_entry() -> anyhow::Result<()> {
+        let (_read, mut write) = fjall_db();
+
+        let mut batch = TestBatch::default();
+        let collection = batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-asdf",
+            "{}",
+            Some("rev-a"),
+            None,
+            100,
+        );
+        write.insert_batch(batch.batch)?;
+        let feed_prefix = NsidRecordFeedKey::from_prefix_to_db_bytes(&collection)?;
+        assert_eq!(write.feeds.prefix(&feed_prefix).count(), 1);
+
+        let mut batch = TestBatch::default();
+        batch.delete(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-asdf",
+            Some("rev-z"),
+            101,
+        );
+        write.insert_batch(batch.batch)?;
+        assert_eq!(write.feeds.prefix(&feed_prefix).count(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stale_delete_does_not_remove_newer_record() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
+
+        let mut batch = TestBatch::default();
+        let collection = batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-asdf",
+            "{}",
+            Some("rev-b"),
+            None,
+            102,
+        );
+        write.insert_batch(batch.batch)?;
+
+        // eg. replayed from an older cursor
+        let mut batch = TestBatch::default();
+        batch.delete(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-asdf",
+            Some("rev-a"),
+            101,
+        );
+        write.insert_batch(batch.batch)?;
+
+        let (records, _, _) =
+            read.get_records_by_collections([collection].into(), 2, false, BUDGET)?;
+        assert_eq!(records.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_out_of_order_batch_delete_wins() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
+
+        // as if truncation displaced commits so the delete lands before its create
+        let mut batch = TestBatch::default();
+        batch.delete(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-asdf",
+            Some("rev-z"),
+            101,
+        );
+        let collection = batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-asdf",
+            "{}",
+            Some("rev-a"),
+            None,
+            100,
+        );
+        write.insert_batch(batch.batch)?;
+
+        let (records, _, _) =
+            read.get_records_by_collections([collection.clone()].into(), 2, false, BUDGET)?;
+        assert_eq!(records.len(), 0);
+        let feed_prefix = NsidRecordFeedKey::from_prefix_to_db_bytes(&collection)?;
+        assert_eq!(write.feeds.prefix(&feed_prefix).count(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_out_of_order_batch_recreate_wins() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
+
+        let mut batch = TestBatch::default();
+        batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-asdf",
+            r#"{"v": 1}"#,
+            Some("rev-a"),
+            None,
+            100,
+        );
+        write.insert_batch(batch.batch)?;
+
+        // a re-create displaced ahead of the delete it follows
+        let mut batch = TestBatch::default();
+        let collection = batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-asdf",
+            r#"{"v": 2}"#,
+            Some("rev-c"),
+            None,
+            102,
+        );
+        batch.delete(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-asdf",
+            Some("rev-b"),
+            101,
+        );
+        write.insert_batch(batch.batch)?;
+
+        let (records, _, _) =
+            read.get_records_by_collections([collection].into(), 2, false, BUDGET)?;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].record.get(), r#"{"v": 2}"#);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_replayed_puts_stay_superseded() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
+        let did = "did:plc:inze6wrmsm7pjl7yta3oig77";
+
+        let mut created = TestBatch::default();
+        let collection = created.create(did, "a.b.c", "rkey-gone", "{}", Some("rev-a"), None, 99);
+        created.create(
+            did,
+            "a.b.c",
+            "rkey-kept",
+            r#"{"v": 1}"#,
+            Some("rev-a"),
+            None,
+            100,
+        );
+        write.insert_batch(created.batch.clone())?;
+
+        let mut batch = TestBatch::default();
+        batch.delete(did, "a.b.c", "rkey-gone", Some("rev-b"), 101);
+        batch.update(
+            did,
+            "a.b.c",
+            "rkey-kept",
+            r#"{"v": 3}"#,
+            Some("rev-c"),
+            None,
+            103,
+        );
+        write.insert_batch(batch.batch)?;
+
+        // replayed, like after reconnecting from an older cursor
+        let mut older_update = TestBatch::default();
+        older_update.update(
+            did,
+            "a.b.c",
+            "rkey-kept",
+            r#"{"v": 2}"#,
+            Some("rev-b"),
+            None,
+            102,
+        );
+        write.insert_batch(created.batch)?;
+        write.insert_batch(older_update.batch)?;
+
+        let (records, _, _) =
+            read.get_records_by_collections([collection.clone()].into(), 5, false, BUDGET)?;
+        assert_eq!(records.len(), 1);
+        assert_eq!(
+            records[0].rkey,
+            RecordKey::new("rkey-kept".to_string()).unwrap()
+        );
+        assert_eq!(records[0].record.get(), r#"{"v": 3}"#);
+        // rkey-kept's create and update: the replays added nothing
+        let feed_prefix = NsidRecordFeedKey::from_prefix_to_db_bytes(&collection)?;
+        assert_eq!(write.feeds.prefix(&feed_prefix).count(), 2);
+
+        // a create after the delete still goes in
+        let mut batch = TestBatch::default();
+        batch.create(did, "a.b.c", "rkey-gone", "{}", Some("rev-d"), None, 104);
+        write.insert_batch(batch.batch)?;
+        let (records, _, _) =
+            read.get_records_by_collections([collection].into(), 5, false, BUDGET)?;
+        assert_eq!(records.len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_before_create_keeps_tombstone() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
+        let did = "did:plc:inze6wrmsm7pjl7yta3oig77";
+
+        // the create was truncated from its batch, so only the delete is stored
+        let mut batch = TestBatch::default();
+        let collection = batch.delete(did, "a.b.c", "rkey-asdf", Some("rev-b"), 101);
+        write.insert_batch(batch.batch)?;
+
+        // then the create turns up after all, replayed
+        let mut batch = TestBatch::default();
+        batch.create(did, "a.b.c", "rkey-asdf", "{}", Some("rev-a"), None, 100);
+        write.insert_batch(batch.batch)?;
+
+        let (records, _, _) =
+            read.get_records_by_collections([collection.clone()].into(), 2, false, BUDGET)?;
+        assert!(records.is_empty());
+
+        // tombstones are dropped once they're old, and these cursors are ancient
+        assert_eq!(write.trim_tombstones()?, 1);
+        assert_eq!(write.tombstones.iter().count(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_trim_tombstones_keeps_newer_delete() -> anyhow::Result<()> {
+        let (_read, mut write) = fjall_db();
+        let did = "did:plc:inze6wrmsm7pjl7yta3oig77";
+        let now = Cursor::at(SystemTime::now()).to_raw_u64();
+
+        let mut batch = TestBatch::default();
+        batch.delete(did, "a.b.c", "rkey-asdf", Some("rev-a"), 100);
+        write.insert_batch(batch.batch)?;
+        let mut batch = TestBatch::default();
+        batch.create(did, "a.b.c", "rkey-asdf", "{}", Some("rev-b"), None, now);
+        write.insert_batch(batch.batch)?;
+        let mut batch = TestBatch::default();
+        batch.delete(did, "a.b.c", "rkey-asdf", Some("rev-c"), now + 1);
+        write.insert_batch(batch.batch)?;
+
+        // only the old delete's expiry entry goes: the tombstone is the newer one's
+        assert_eq!(write.trim_tombstones()?, 0);
+        assert_eq!(write.tombstones.iter().count(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pinned_snapshot_reads() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
+
+        let mut batch = TestBatch::default();
+        let collection = batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-asdf",
+            "{}",
+            Some("rev-a"),
+            None,
+            100,
+        );
+        write.insert_batch(batch.batch)?;
+        write.step_rollup()?;
+
+        let (token, _) = read.pin_snapshot(Duration::from_secs(60))?;
+
+        let mut batch = TestBatch::default();
+        batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-asdg",
+            "{}",
+            Some("rev-b"),
+            None,
+            101,
+        );
+        write.insert_batch(batch.batch)?;
+        write.step_rollup()?;
+
+        let pinned = read.at_snapshot(token)?;
+        let JustCount { creates, .. } =
+            pinned.get_collection_counts(&collection, beginning(), None)?;
+        assert_eq!(creates, 1);
+        let (records, _, _) =
+            pinned.get_records_by_collections([collection.clone()].into(), 2, false, BUDGET)?;
+        assert_eq!(records.len(), 1);
+
+        let JustCount { creates, .. } =
+            read.get_collection_counts(&collection, beginning(), None)?;
+        assert_eq!(creates, 2);
+        let (records, _, _) =
+            read.get_records_by_collections([collection].into(), 2, false, BUDGET)?;
+        assert_eq!(records.len(), 2);
+
+        assert!(matches!(
+            read.at_snapshot(token + 1),
+            Err(QueryError::SnapshotNotFound(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bad_cursor_is_a_client_error() -> anyhow::Result<()> {
+        let (read, _) = fjall_db();
+        let order = OrderCollectionsBy::Lexi {
+            cursor: Some(vec![0xff]),
+        };
+        let r = read.get_collections(10, order, None, None, BUDGET);
+        assert!(matches!(r, Err(QueryError::BadCursor(_))));
+        assert!(r.unwrap_err().is_client_error());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ordered_collections_paging() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
+
+        let mut batch = TestBatch::default();
+        let mut cursor = 100;
+        for (collection, n) in [("a.a.a", 3), ("b.b.b", 2), ("c.c.c", 2), ("d.d.d", 1)] {
+            for i in 0..n {
+                batch.create(
+                    "did:plc:inze6wrmsm7pjl7yta3oig77",
+                    collection,
+                    &format!("rkey-{i}"),
+                    "{}",
+                    None,
+                    None,
+                    cursor,
+                );
+                cursor += 1;
+            }
+        }
+        write.insert_batch(batch.batch)?;
+        write.step_rollup()?;
+
+        let mut seen = vec![];
+        let mut cursor = None;
+        loop {
+            let order = OrderCollectionsBy::RecordsCreated { cursor };
+            let (page, next) = read.get_collections(2, order, None, None, BUDGET)?;
+            seen.extend(page.into_iter().map(|c| (c.nsid, c.creates)));
+            let Some(next) = next else { break };
+            cursor = Some(next);
+        }
+        assert_eq!(
+            seen,
+            vec![
+                ("a.a.a".to_string(), 3),
+                ("c.c.c".to_string(), 2),
+                ("b.b.b".to_string(), 2),
+                ("d.d.d".to_string(), 1),
+            ]
+        );
+
+        let order = OrderCollectionsBy::DidsEstimate {
+            cursor: Some(vec![0xff]),
+        };
+        let r = read.get_collections(2, order, None, None, BUDGET);
+        assert!(matches!(r, Err(QueryError::BadCursor(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_budget_cuts_reads_short() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
+
+        let mut batch = TestBatch::default();
+        let mut cursor = 100;
+        for (collection, n) in [
+            ("a.a.x", 3),
+            ("a.a.y.one", 1),
+            ("a.a.y.two", 1),
+            ("b.b.b", 1),
+        ] {
+            for i in 0..n {
+                batch.create(
+                    "did:plc:inze6wrmsm7pjl7yta3oig77",
+                    collection,
+                    &format!("rkey-{i}"),
+                    "{}",
+                    None,
+                    None,
+                    cursor,
+                );
+                cursor += 1;
+            }
+        }
+        write.insert_batch(batch.batch)?;
+        write.step_rollup()?;
+
+        // lexi pages stop short with a cursor, and paging on gets the rest
+        let mut seen = vec![];
+        let mut cursor = None;
+        loop {
+            let order = OrderCollectionsBy::Lexi { cursor };
+            let (page, next) = read.get_collections(10, order, None, None, ScanBudget::new(3))?;
+            seen.push(page.into_iter().map(|c| c.nsid).collect::<Vec<_>>());
+            let Some(next) = next else { break };
+            cursor = Some(next);
+        }
+        assert_eq!(
+            seen,
+            vec![vec!["a.a.x", "a.a.y.one", "a.a.y.two"], vec!["b.b.b"]]
+        );
+
+        // rankings can't be cut short
+        let order = OrderCollectionsBy::RecordsCreated { cursor: None };
+        let r = read.get_collections(10, order, None, None, ScanBudget::new(2));
+        assert!(matches!(r, Err(QueryError::OverScanBudget)));
+        assert!(r.unwrap_err().is_client_error());
+
+        // a prefix page leaves out a child it couldn't count in full
+        let prefix = NsidPrefix::new("a.a").unwrap();
+        let lexi = |cursor| OrderCollectionsBy::Lexi { cursor };
+        let (total, children, cursor) = read.get_prefix(
+            prefix.clone(),
+            10,
+            lexi(None),
+            None,
+            None,
+            ScanBudget::new(2),
+        )?;
+        assert_eq!(total.creates, 3);
+        assert_eq!(children.len(), 1);
+        assert!(cursor.is_some());
+        let r = read.get_prefix(
+            prefix.clone(),
+            10,
+            lexi(cursor.clone()),
+            None,
+            None,
+            ScanBudget::new(1),
+        );
+        assert!(matches!(r, Err(QueryError::OverScanBudget)));
+        let (total, children, cursor) =
+            read.get_prefix(prefix, 10, lexi(cursor), None, None, ScanBudget::new(2))?;
+        assert_eq!(total.creates, 2);
+        assert!(matches!(&children[..], [PrefixChild::Prefix(p)] if p.prefix == "a.a.y"));
+        assert_eq!(cursor, None);
+
+        let collection = Nsid::new("a.a.x".to_string()).unwrap();
+        let (records, _, truncated) = read.get_records_by_collections(
+            [collection.clone()].into(),
+            10,
+            false,
+            ScanBudget::new(2),
+        )?;
+        assert_eq!(records.len(), 2);
+        assert!(truncated);
+        let (records, _, truncated) =
+            read.get_records_by_collections([collection].into(), 10, false, BUDGET)?;
+        assert_eq!(records.len(), 3);
+        assert!(!truncated);
+
+        let (matches, truncated) =
+            read.search_collections(vec!["b.b".to_string()], ScanBudget::new(2))?;
+        assert!(matches.is_empty());
+        assert!(truncated);
+        let (matches, truncated) = read.search_collections(vec!["b.b".to_string()], BUDGET)?;
+        assert_eq!(matches.len(), 1);
+        assert!(!truncated);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ordered_collections_ties_are_stable() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
+
+        let mut batch = TestBatch::default();
+        for (i, collection) in ["b.b.b", "d.d.d", "z.z.z", "a.a.a", "c.c.c", "z.z.z"]
+            .iter()
+            .enumerate()
+        {
+            batch.create(
+                "did:plc:inze6wrmsm7pjl7yta3oig77",
+                collection,
+                &format!("rkey-{i}"),
+                "{}",
+                None,
+                None,
+                100 + i as u64,
+            );
+        }
+        write.insert_batch(batch.batch)?;
+        write.step_rollup()?;
+
+        let expected = vec!["z.z.z", "d.d.d", "c.c.c", "b.b.b", "a.a.a"];
+
+        for _ in 0..3 {
+            let order = OrderCollectionsBy::RecordsCreated { cursor: None };
+            let (all, next) = read.get_collections(10, order, None, None, BUDGET)?;
+            assert_eq!(
+                all.iter().map(|c| c.nsid.as_str()).collect::<Vec<_>>(),
+                expected
+            );
+            assert_eq!(next, None);
+        }
+
+        // one at a time through the tied run: no repeats, nothing skipped
+        let mut paged = vec![];
+        let mut cursor = None;
+        loop {
+            let order = OrderCollectionsBy::RecordsCreated { cursor };
+            let (page, next) = read.get_collections(1, order, None, None, BUDGET)?;
+            paged.extend(page.into_iter().map(|c| c.nsid));
+            let Some(next) = next else { break };
+            cursor = Some(next);
+        }
+        assert_eq!(paged, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ordered_collections_page_over_hours() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
+
+        // each collection's top hour alone would rank them differently than their totals
+        for (hour, counts) in [
+            (10, [("a.a.a", 3), ("b.b.b", 1), ("c.c.c", 2)]),
+            (11, [("b.b.b", 3), ("c.c.c", 1), ("d.d.d", 2)]),
+        ] {
+            let mut batch = TestBatch::default();
+            let mut i = 0;
+            for (collection, n) in counts {
+                for _ in 0..n {
+                    i += 1;
+                    batch.create(
+                        "did:plc:inze6wrmsm7pjl7yta3oig77",
+                        collection,
+                        &format!("rkey-{hour}-{i}"),
+                        "{}",
+                        None,
+                        None,
+                        hour * HOUR_IN_MICROS + i,
+                    );
+                }
+            }
+            write.insert_batch(batch.batch)?;
+        }
+        while write.step_rollup()?.0 > 0 {}
+
+        let since = Cursor::from_raw_u64(10 * HOUR_IN_MICROS).into();
+        let until = Cursor::from_raw_u64(12 * HOUR_IN_MICROS).into();
+        // totals b=4, then a tie at 3 ordered by nsid, then d=2
+        let expected = vec![("b.b.b", 4), ("c.c.c", 3), ("a.a.a", 3), ("d.d.d", 2)];
+
+        for limit in [1, 2, 10] {
+            let mut paged = vec![];
+            let mut cursor = None;
+            loop {
+                let order = OrderCollectionsBy::RecordsCreated { cursor };
+                let (page, next) =
+                    read.get_collections(limit, order, Some(since), Some(until), BUDGET)?;
+                paged.extend(page.into_iter().map(|c| (c.nsid, c.creates)));
+                let Some(next) = next else { break };
+                cursor = Some(next);
+            }
+            let expected: Vec<_> = expected
+                .iter()
+                .map(|(nsid, n)| (nsid.to_string(), *n))
+                .collect();
+            assert_eq!(paged, expected, "paging {limit} at a time");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ordered_collections_ties_over_hours_are_stable() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
+
+        // every collection totals 2 over three hours, split differently
+        let splits = [
+            ("a.a.a", [2, 0, 0]),
+            ("b.b.b", [0, 0, 2]),
+            ("c.c.c", [1, 1, 0]),
+            ("d.d.d", [0, 1, 1]),
+            ("e.e.e", [1, 0, 1]),
+        ];
+        for hour in 0..3 {
+            let mut batch = TestBatch::default();
+            let mut i = 0;
+            for (collection, split) in splits {
+                for _ in 0..split[hour] {
+                    i += 1;
+                    batch.create(
+                        "did:plc:inze6wrmsm7pjl7yta3oig77",
+                        collection,
+                        &format!("rkey-{hour}-{i}"),
+                        "{}",
+                        None,
+                        None,
+                        (20 + hour as u64) * HOUR_IN_MICROS + i,
+                    );
+                }
+            }
+            write.insert_batch(batch.batch)?;
+        }
+        while write.step_rollup()?.0 > 0 {}
+
+        let since = Cursor::from_raw_u64(20 * HOUR_IN_MICROS).into();
+        let until = Cursor::from_raw_u64(23 * HOUR_IN_MICROS).into();
+        let expected = vec!["e.e.e", "d.d.d", "c.c.c", "b.b.b", "a.a.a"];
+
+        for limit in [1, 2, 3] {
+            for _ in 0..2 {
+                let mut paged = vec![];
+                let mut cursor = None;
+                loop {
+                    let order = OrderCollectionsBy::RecordsCreated { cursor };
+                    let (page, next) =
+                        read.get_collections(limit, order, Some(since), Some(until), BUDGET)?;
+                    assert!(page.iter().all(|c| c.creates == 2));
+                    paged.extend(page.into_iter().map(|c| c.nsid));
+                    let Some(next) = next else { break };
+                    cursor = Some(next);
+                }
+                assert_eq!(paged, expected, "paging {limit} at a time");
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_background_trigger_needs_running_loop() {
+        let schedule = Arc::new(BackgroundSchedule::default());
+        assert!(!schedule.trigger(BackgroundTask::Rollup));
+
+        let running = ScheduleRunning::new(schedule.clone());
+        assert!(schedule.status().running);
+        assert!(schedule.trigger(BackgroundTask::Trim));
+
+        drop(running);
+        assert!(!schedule.status().running);
+        assert!(!schedule.trigger(BackgroundTask::Trim));
+    }
+
+    #[test]
+    fn test_background_intervals_outlive_the_loop() {
+        let schedule = Arc::new(BackgroundSchedule::new(None));
+        assert_eq!(
+            schedule.intervals(true),
+            BackgroundIntervals::defaults(true)
+        );
+
+        let custom = BackgroundIntervals {
+            rollup_us: 500,
+            rollup_idle_ms: 2_000,
+            trim_ms: 60_000,
+        };
+        schedule.update(|s| s.intervals = Some(custom));
+        drop(ScheduleRunning::new(schedule.clone()));
+        assert_eq!(schedule.status().intervals, custom);
+        assert_eq!(schedule.intervals(false), custom);
+    }
+
+    #[test]
+    fn test_purge_collection() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
+
+        let mut batch = TestBatch::default();
+        let purged = batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-asdf",
+            "{}",
+            Some("rev-a"),
+            None,
+            100,
+        );
+        let kept = batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "d.e.f",
+            "rkey-asdg",
+            "{}",
+            Some("rev-b"),
+            None,
+            101,
+        );
+        write.insert_batch(batch.batch)?;
+        write.step_rollup()?;
+
+        let report = write.purge_collection(&purged, false)?;
+        assert_eq!(report.feeds_removed, 1);
+        assert_eq!(report.records_removed, 1);
+        assert!(report.rollups_removed > 0);
+
+        let JustCount { creates, .. } = read.get_collection_counts(&purged, beginning(), None)?;
+        assert_eq!(creates, 0);
+        let (records, _, _) = read.get_records_by_collections([purged].into(), 2, false, BUDGET)?;
+        assert_eq!(records.len(), 0);
+
+        let JustCount { creates, .. } = read.get_collection_counts(&kept, beginning(), None)?;
+        assert_eq!(creates, 1);
+        let (records, _, _) = read.get_records_by_collections([kept].into(), 2, false, BUDGET)?;
+        assert_eq!(records.len(), 1);
+
+        let (collections, _) = read.get_collections(10, Default::default(), None, None, BUDGET)?;
+        assert_eq!(collections.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_purge_collection_rollups_across_gaps() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
+
+        // hours with only the purged collection, only another one, or both,
+        // spread over a few weeks with empty hours between
+        for (hour, collection) in [
+            (2, "a.b.c"),
+            (2, "d.e.f"),
+            (30, "a.b.c"),
+            (300, "d.e.f"),
+            (500, "a.b.c"),
+        ] {
+            let mut batch = TestBatch::default();
+            batch.create(
+                "did:plc:inze6wrmsm7pjl7yta3oig77",
+                collection,
+                &format!("rkey-{hour}"),
+                "{}",
+                None,
+                None,
+                hour * HOUR_IN_MICROS,
+            );
+            write.insert_batch(batch.batch)?;
+        }
+        while write.step_rollup()?.0 > 0 {}
+
+        let purged = Nsid::new("a.b.c".to_string()).unwrap();
+        let kept = Nsid::new("d.e.f".to_string()).unwrap();
+        let report = write.purge_collection(&purged, false)?;
+        // three hours, two weeks, and all-time
+        assert_eq!(report.rollups_removed, 6);
+
+        for kv in write
+            .rollups
+            .prefix(HourlyRollupStaticPrefix::default().to_db_bytes()?)
+        {
+            let (key_bytes, _) = kv?;
+            assert_eq!(
+                db_complete::<HourlyRollupKey>(&key_bytes)?.collection(),
+                &kept
+            );
+        }
+        let JustCount { creates, .. } = read.get_collection_counts(&purged, beginning(), None)?;
+        assert_eq!(creates, 0);
+        let JustCount { creates, .. } = read.get_collection_counts(&kept, beginning(), None)?;
+        assert_eq!(creates, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebuild_feeds() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
+
+        let mut batch = TestBatch::default();
+        let collection = batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-asdf",
+            "{}",
+            Some("rev-a"),
+            None,
+            100,
+        );
+        batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-asdg",
+            "{}",
+            Some("rev-b"),
+            None,
+            101,
+        );
+        write.insert_batch(batch.batch)?;
+
+        // a dangling entry, and a lost one
+        let mut batch = write.keyspace.batch();
+        let dangling = NsidRecordFeedKey::from_pair(collection.clone(), Cursor::from_raw_u64(99));
+        let dangling_val: NsidRecordFeedVal = (
+            &Did::new("did:plc:inze6wrmsm7pjl7yta3oig77".to_string()).unwrap(),
+            &RecordKey::new("rkey-gone".to_string()).unwrap(),
+            "rev-z",
+        )
+            .into();
+        batch.insert(
+            &write.feeds,
+            dangling.to_db_bytes()?,
+            dangling_val.to_db_bytes()?,
+        );
+        let lost = NsidRecordFeedKey::from_pair(collection.clone(), Cursor::from_raw_u64(101));
+        batch.remove(&write.feeds, lost.to_db_bytes()?);
+        batch.commit()?;
+
+        let report = write.rebuild_feeds()?;
+        assert!(!report.resumed);
+        assert_eq!(report.feeds_cleared, 2);
+        assert_eq!(report.records_rebuilt, 2);
+
+        assert!(!write.feeds.contains_key(dangling.to_db_bytes()?)?);
+        assert!(write.feeds.contains_key(lost.to_db_bytes()?)?);
+        let (records, _, _) =
+            read.get_records_by_collections([collection].into(), 3, false, BUDGET)?;
+        assert_eq!(records.len(), 2);
+
+        // a rebuild left unfinished after clearing picks up from its progress key
+        insert_static_neu::<RebuildFeedsKey>(&write.global, RebuildFeedsValue::new())?;
+        let report = write.rebuild_feeds()?;
+        assert!(report.resumed);
+        assert_eq!(report.feeds_cleared, 0);
+        assert_eq!(report.records_rebuilt, 2);
+        assert!(get_static_neu::<RebuildFeedsKey, RebuildFeedsValue>(&write.global)?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_scrub_quarantines_undecodable() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
+
+        let mut batch = TestBatch::default();
+        let collection = batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-asdf",
+            "{}",
+            Some("rev-a"),
+            None,
+            100,
+        );
+        batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-asdg",
+            "{}",
+            Some("rev-b"),
+            None,
+            101,
+        );
+        write.insert_batch(batch.batch)?;
+
+        let corrupt = RecordLocationKey::from_pair(
+            Did::new("did:plc:inze6wrmsm7pjl7yta3oig77".to_string()).unwrap(),
+            DbConcat::from_pair(
+                collection.clone(),
+                RecordKey::new("rkey-asdg".to_string()).unwrap(),
+            ),
+        )
+        .to_db_bytes()?;
+        write.records.insert(corrupt.as_slice(), &b"nope"[..])?;
+        let (records, skipped_corrupt, _) =
+            read.get_records_by_collections([collection.clone()].into(), 2, false, BUDGET)?;
+        assert_eq!(records.len(), 1);
+        assert_eq!(skipped_corrupt, 1);
+
+        // a small step, so the pass takes a few
+        let mut pass = ScrubPass::new(Scrubbed::Feeds);
+        let mut steps = 0;
+        loop {
+            pass = write.scrub_step(pass, 1)?;
+            steps += 1;
+            if pass.partition == Scrubbed::Feeds && pass.after.is_none() {
+                break;
+            }
+        }
+        assert_eq!(steps, 6); // two feeds, two records, and an empty step at each end
+
+        let quarantined = write
+            .quarantine
+            .get(QuarantineKey::new("records", corrupt.clone()).to_db_bytes()?)?;
+        assert_eq!(quarantined.as_deref(), Some(&b"nope"[..]));
+        assert!(!write.records.contains_key(&corrupt)?);
+
+        let (records, skipped_corrupt, _) =
+            read.get_records_by_collections([collection].into(), 2, false, BUDGET)?;
+        assert_eq!(records.len(), 1);
+        assert_eq!(skipped_corrupt, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_purge_collection_dry_run() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
+
+        let mut batch = TestBatch::default();
+        let collection = batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-asdf",
+            "{}",
+            Some("rev-a"),
+            None,
+            100,
+        );
+        write.insert_batch(batch.batch)?;
+        write.step_rollup()?;
+
+        let preview = write.purge_collection(&collection, true)?;
+        assert!(preview.dry_run);
+        assert_eq!(preview.feeds_removed, 1);
+        assert_eq!(preview.records_removed, 1);
+        assert!(preview.rollups_removed > 0);
+
+        // nothing was removed
+        let JustCount { creates, .. } =
+            read.get_collection_counts(&collection, beginning(), None)?;
+        assert_eq!(creates, 1);
+        let (records, _, _) =
+            read.get_records_by_collections([collection.clone()].into(), 2, false, BUDGET)?;
+        assert_eq!(records.len(), 1);
+
+        // and the real purge removes exactly what the preview counted
+        let report = write.purge_collection(&collection, false)?;
+        assert!(!report.dry_run);
+        assert_eq!(report.feeds_removed, preview.feeds_removed);
+        assert_eq!(report.records_removed, preview.records_removed);
+        assert_eq!(report.rollups_removed, preview.rollups_removed);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sample_consistency() -> anyhow::Result<()> {
+        let (_read, mut write) = fjall_db();
+
+        let empty = write.sample_consistency(4)?;
+        assert_eq!(empty, ConsistencySample::default());
+
+        let mut batch = TestBatch::default();
+        batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-asdf",
+            "{}",
+            Some("rev-a"),
+            None,
+            100,
+        );
+        write.insert_batch(batch.batch)?;
 
-                        let location_val: RecordLocationVal =
-                            (commit.cursor, commit.rev.as_str(), put_action).into();
-                        batch.insert(
-                            &self.records,
-                            &location_key.to_db_bytes()?,
-                            &location_val.to_db_bytes()?,
-                        );
-                    }
-                }
+        let sample = write.sample_consistency(4)?;
+        assert_eq!(
+            sample,
+            ConsistencySample {
+                feeds_checked: 4,
+                feeds_dangling: 0,
+                records_checked: 4,
+                records_orphaned: 0,
             }
-            let live_counts_key: LiveCountsKey = (latest, &nsid).into();
-            let counts_value = CountsValue::new(
-                CommitCounts {
-                    creates: commits.creates as u64,
-                    updates: commits.updates as u64,
-                    deletes: commits.deletes as u64,
-                },
-                commits.dids_estimate,
-            );
-            batch.insert(
-                &self.rollups,
-                &live_counts_key.to_db_bytes()?,
-                &counts_value.to_db_bytes()?,
-            );
-        }
+        );
 
-        for remove in event_batch.account_removes {
-            let queue_key = DeleteAccountQueueKey::new(remove.cursor);
-            let queue_val: DeleteAccountQueueVal = remove.did;
-            batch.insert(
-                &self.queues,
-                &queue_key.to_db_bytes()?,
-                &queue_val.to_db_bytes()?,
+        // lose the feed entry: the record is now orphaned
+        let (feed_key, feed_val) = write.feeds.first_key_value()?.unwrap();
+        write.feeds.remove(feed_key.clone())?;
+        let sample = write.sample_consistency(4)?;
+        assert_eq!(sample.feeds_checked, 0);
+        assert_eq!(sample.records_orphaned, 4);
+
+        // put it back and lose the record instead: the feed entry dangles
+        write.feeds.insert(feed_key, feed_val)?;
+        let (record_key, _) = write.records.first_key_value()?.unwrap();
+        write.records.remove(record_key)?;
+        let sample = write.sample_consistency(4)?;
+        assert_eq!(sample.feeds_dangling, 4);
+        assert_eq!(sample.records_checked, 0);
+
+        let mut rate = RollingRate::default();
+        assert_eq!(rate.rate(), 0.);
+        rate.record(4, 1);
+        assert_eq!(rate.rate(), 0.25);
+        rate.record(CONSISTENCY_WINDOW, 0);
+        assert_eq!(rate.rate(), 0.);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_estimate_key_space() -> anyhow::Result<()> {
+        let (_read, mut write) = fjall_db();
+
+        let mut batch = TestBatch::default();
+        for i in 0..10 {
+            batch.create(
+                "did:plc:inze6wrmsm7pjl7yta3oig77",
+                "a.b.c",
+                &format!("rkey-big-{i}"),
+                &format!("{{\"text\": \"{}\"}}", "x".repeat(200)),
+                Some("rev-a"),
+                None,
+                100 + i,
             );
         }
+        batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "d.e.f",
+            "rkey-small",
+            "{}",
+            Some("rev-b"),
+            None,
+            200,
+        );
+        write.insert_batch(batch.batch)?;
 
-        batch.insert(
-            &self.global,
-            DbStaticStr::<JetstreamCursorKey>::default().to_db_bytes()?,
-            latest.to_db_bytes()?,
+        let exact = write.estimate_key_space(1, 10)?;
+        assert_eq!(exact.collections.len(), 2);
+        let big = &exact.collections[0];
+        assert_eq!(big.collection, "a.b.c");
+        assert_eq!(big.feed_entries, 10);
+        assert_eq!(big.records_sampled, 10);
+        assert!(big.record_bytes_estimate > 2_000);
+        let small = &exact.collections[1];
+        assert_eq!(small.collection, "d.e.f");
+        assert_eq!(small.feed_entries, 1);
+        assert_eq!(
+            exact.total_bytes_estimate,
+            big.total_bytes_estimate + small.total_bytes_estimate
         );
 
-        histogram!("storage_insert_batch_db_batch_items").record(batch.len() as f64);
-        batch.commit()?;
+        let sampled = write.estimate_key_space(5, 1)?;
+        assert_eq!(sampled.collections.len(), 1);
+        assert_eq!(sampled.collections[0].records_sampled, 2);
+        assert_eq!(sampled.collections[0].feed_bytes, big.feed_bytes);
+        assert_eq!(sampled.total_bytes_estimate, exact.total_bytes_estimate);
+
         Ok(())
     }
 
-    fn step_rollup(&mut self) -> StorageResult<(usize, HashSet<Nsid>)> {
-        let mut dirty_nsids = HashSet::new();
+    #[test]
+    fn test_tracked_since() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
+        let ConsumerInfo::Jetstream {
+            started_at: takeoff,
+            ..
+        } = read.get_consumer_info()?;
 
-        let rollup_cursor =
-            get_static_neu::<NewRollupCursorKey, NewRollupCursorValue>(&self.global)?.ok_or(
-                StorageError::BadStateError("Could not find current rollup cursor".to_string()),
-            )?;
+        let later = takeoff + 3_600_000_000;
+        let mut batch = TestBatch::default();
+        let old = batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-asdf",
+            "{}",
+            Some("rev-a"),
+            None,
+            100,
+        );
+        write.insert_batch(batch.batch)?;
+        let mut batch = TestBatch::default();
+        let new = batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "d.e.f",
+            "rkey-asdg",
+            "{}",
+            Some("rev-b"),
+            None,
+            later,
+        );
+        write.insert_batch(batch.batch)?;
+        write.step_rollup()?;
 
-        // timelies
-        let live_counts_range = LiveCountsKey::range_from_cursor(rollup_cursor)?;
-        let mut timely_iter = self.rollups.range(live_counts_range).peekable();
+        // seen before takeoff (backfill-ish): clamped to takeoff
+        assert_eq!(read.get_tracked_since(&old)?.to_raw_u64(), takeoff);
+        assert_eq!(read.get_tracked_since(&new)?.to_raw_u64(), later);
 
-        let timely_next = timely_iter
-            .peek_mut()
-            .map(|kv| -> StorageResult<LiveCountsKey> {
-                match kv {
-                    Err(e) => Err(std::mem::replace(e, fjall::Error::Poisoned))?,
-                    Ok((key_bytes, _)) => {
-                        let key = db_complete::<LiveCountsKey>(key_bytes)?;
-                        Ok(key)
-                    }
-                }
-            })
-            .transpose()?;
+        let (collections, _) = read.get_collections(
+            10,
+            OrderCollectionsBy::Lexi { cursor: None },
+            None,
+            None,
+            BUDGET,
+        )?;
+        assert_eq!(collections.len(), 2);
+        assert_eq!(collections[0].tracked_since, takeoff);
+        assert_eq!(collections[1].tracked_since, later);
 
-        // delete accounts
-        let delete_accounts_range =
-            DeleteAccountQueueKey::new(rollup_cursor).range_to_prefix_end()?;
+        // more events later on don't move it
+        let mut batch = TestBatch::default();
+        batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "d.e.f",
+            "rkey-asdh",
+            "{}",
+            Some("rev-c"),
+            None,
+            later + 1,
+        );
+        write.insert_batch(batch.batch)?;
+        write.step_rollup()?;
+        assert_eq!(read.get_tracked_since(&new)?.to_raw_u64(), later);
 
-        let next_delete = self
-            .queues
-            .range(delete_accounts_range)
-            .next()
-            .transpose()?
-            .map(|(key_bytes, val_bytes)| {
-                db_complete::<DeleteAccountQueueKey>(&key_bytes)
-                    .map(|k| (k.suffix, key_bytes, val_bytes))
-            })
-            .transpose()?;
+        Ok(())
+    }
 
-        let cursors_stepped = match (timely_next, next_delete) {
-            (Some(timely), Some((delete_cursor, delete_key_bytes, delete_val_bytes))) => {
-                if timely.cursor() < delete_cursor {
-                    let (n, dirty) = self.rollup_live_counts(
-                        timely_iter,
-                        Some(delete_cursor),
-                        MAX_BATCHED_ROLLUP_COUNTS,
-                    )?;
-                    dirty_nsids.extend(dirty);
-                    n
-                } else {
-                    self.rollup_delete_account(delete_cursor, &delete_key_bytes, &delete_val_bytes)?
-                }
-            }
-            (Some(_), None) => {
-                let (n, dirty) =
-                    self.rollup_live_counts(timely_iter, None, MAX_BATCHED_ROLLUP_COUNTS)?;
-                dirty_nsids.extend(dirty);
-                n
+    #[test]
+    fn test_summary() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
+
+        let summary = read.get_summary()?;
+        assert_eq!(summary.collections, 0);
+        assert_eq!(summary.consumer_lag_ms, None);
+
+        for (hour, did, collection, n) in [
+            (10, "did:plc:person-a", "a.a.a", 3600),
+            (11, "did:plc:person-b", "b.b.b", 2),
+        ] {
+            let mut batch = TestBatch::default();
+            for i in 0..n {
+                batch.create(
+                    did,
+                    collection,
+                    &format!("rkey-{i}"),
+                    "{}",
+                    None,
+                    None,
+                    hour * HOUR_IN_MICROS + i,
+                );
             }
-            (None, Some((delete_cursor, delete_key_bytes, delete_val_bytes))) => {
-                self.rollup_delete_account(delete_cursor, &delete_key_bytes, &delete_val_bytes)?
+            write.insert_batch(batch.batch)?;
+        }
+        while write.step_rollup()?.0 > 0 {}
+
+        let summary = read.get_summary()?;
+        assert_eq!(summary.collections, 2);
+        assert_eq!(summary.records_created, 3602);
+        assert_eq!(summary.dids_estimate, 2);
+        assert_eq!(summary.dids_estimate_24h, 2);
+        // hour 11 is still filling, so the rate is from hour 10
+        assert_eq!(summary.events_per_second, 1.0);
+        assert!(summary.consumer_lag_ms.is_some());
+        assert_eq!(summary.rollup_lag_ms, Some(0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_growing_collections() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
+
+        // live counts are bucketed by their batch's latest cursor: one batch per hour.
+        // c.c.c is new in hour 11, and z.z.z moves the rollup into hour 12.
+        for (hour, creates) in [
+            (10, vec![("a.a.a", 2), ("b.b.b", 4)]),
+            (11, vec![("a.a.a", 6), ("b.b.b", 2), ("c.c.c", 5)]),
+            (12, vec![("z.z.z", 1)]),
+        ] {
+            let mut batch = TestBatch::default();
+            for (collection, n) in creates {
+                for i in 0..n {
+                    batch.create(
+                        "did:plc:inze6wrmsm7pjl7yta3oig77",
+                        collection,
+                        &format!("rkey-{hour}-{i}"),
+                        "{}",
+                        None,
+                        None,
+                        hour * HOUR_IN_MICROS + i,
+                    );
+                }
             }
-            (None, None) => 0,
-        };
+            write.insert_batch(batch.batch)?;
+        }
+        while write.step_rollup()?.0 > 0 {}
 
-        Ok((cursors_stepped, dirty_nsids))
+        let ranking = read.get_growing_collections(GrowthPeriod::Hour, 10, 1)?;
+        assert_eq!(ranking.previous_start, 10 * HOUR_IN_MICROS);
+        assert_eq!(ranking.current_start, 11 * HOUR_IN_MICROS);
+        assert_eq!(
+            ranking.collections,
+            vec![
+                CollectionGrowth {
+                    nsid: "a.a.a".to_string(),
+                    previous: 2,
+                    current: 6,
+                    growth: 2.0,
+                },
+                CollectionGrowth {
+                    nsid: "b.b.b".to_string(),
+                    previous: 4,
+                    current: 2,
+                    growth: -0.5,
+                },
+            ]
+        );
+
+        let ranking = read.get_growing_collections(GrowthPeriod::Hour, 10, 3)?;
+        assert_eq!(ranking.collections.len(), 1);
+        assert_eq!(ranking.collections[0].nsid, "b.b.b");
+
+        // not two whole weeks in yet
+        let ranking = read.get_growing_collections(GrowthPeriod::Week, 10, 1)?;
+        assert!(ranking.collections.is_empty());
+
+        Ok(())
     }
 
-    fn trim_collection(
-        &mut self,
-        collection: &Nsid,
-        limit: usize,
-        full_scan: bool,
-    ) -> StorageResult<(usize, usize, bool)> {
-        let mut dangling_feed_keys_cleaned = 0;
-        let mut records_deleted = 0;
+    #[test]
+    fn test_collection_window_counts() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
+        let base = 10 * HOUR_IN_MICROS;
+        let quarter = HOUR_IN_MICROS / 4;
 
-        let live_range = if full_scan {
-            let start = NsidRecordFeedKey::from_prefix_to_db_bytes(collection)?;
-            let end = NsidRecordFeedKey::prefix_range_end(collection)?;
-            start..end
-        } else {
-            let feed_trim_cursor_key =
-                TrimCollectionCursorKey::new(collection.clone()).to_db_bytes()?;
-            let trim_cursor = self
-                .global
-                .get(&feed_trim_cursor_key)?
-                .map(|value_bytes| db_complete(&value_bytes))
-                .transpose()?
-                .unwrap_or(Cursor::from_start());
-            NsidRecordFeedKey::from_pair(collection.clone(), trim_cursor).range_to_prefix_end()?
-        };
+        let mut batch = TestBatch::default();
+        let mut collection = None;
+        for i in 0..4 {
+            collection = Some(batch.create(
+                "did:plc:inze6wrmsm7pjl7yta3oig77",
+                "a.b.c",
+                &format!("rkey-{i}"),
+                "{}",
+                None,
+                None,
+                base + i * quarter,
+            ));
+        }
+        let collection = collection.unwrap();
+        write.insert_batch(batch.batch)?;
+        write.step_rollup()?;
 
-        let mut live_records_found = 0;
-        let mut candidate_new_feed_lower_cursor = None;
-        let ended_early = false;
-        let mut current_cursor: Option<Cursor> = None;
-        for (i, kv) in self.feeds.range(live_range).rev().enumerate() {
-            if i > 0 && i % 500_000 == 0 {
-                log::info!(
-                    "trim: at {i} for {:?} (now at {})",
-                    collection.to_string(),
-                    current_cursor
-                        .map(|c| c
-                            .elapsed()
-                            .map(nice_duration)
-                            .unwrap_or("[not past]".into()))
-                        .unwrap_or("??".into()),
-                );
-            }
-            let (key_bytes, val_bytes) = kv?;
-            let feed_key = db_complete::<NsidRecordFeedKey>(&key_bytes)?;
-            let feed_val = db_complete::<NsidRecordFeedVal>(&val_bytes)?;
-            let location_key: RecordLocationKey = (&feed_key, &feed_val).into();
-            let location_key_bytes = location_key.to_db_bytes()?;
+        let at = Cursor::from_raw_u64;
 
-            let Some(location_val_bytes) = self.records.get(&location_key_bytes)? else {
-                // record was deleted (hopefully)
-                self.feeds.remove(&*key_bytes)?;
-                dangling_feed_keys_cleaned += 1;
-                continue;
-            };
+        // the whole rolled-up part of the hour
+        let counts = read.get_collection_window_counts(
+            &collection,
+            at(base),
+            Some(at(base + HOUR_IN_MICROS)),
+        )?;
+        assert_eq!(counts.counts.creates, 4);
+        assert!(!counts.prorated);
+        assert!(!counts.live);
+
+        // the second half: a third of the rolled-up span
+        let counts = read.get_collection_window_counts(
+            &collection,
+            at(base + 2 * quarter),
+            Some(at(base + HOUR_IN_MICROS)),
+        )?;
+        assert_eq!(counts.counts.creates, 1);
+        assert!(counts.prorated);
+        assert!(!counts.live);
 
-            let (meta, _) = RecordLocationMeta::from_db_bytes(&location_val_bytes)?;
-            current_cursor = Some(meta.cursor());
+        // not rolled up yet
+        let mut batch = TestBatch::default();
+        batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-later",
+            "{}",
+            None,
+            None,
+            base + 7 * quarter / 2,
+        );
+        write.insert_batch(batch.batch)?;
+
+        let counts = read.get_collection_window_counts(
+            &collection,
+            at(base + 2 * quarter),
+            Some(at(base + HOUR_IN_MICROS)),
+        )?;
+        assert_eq!(counts.counts.creates, 2);
+        assert!(counts.prorated);
+        assert!(counts.live);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_overflowed_collections_rollup() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
+
+        let now = Cursor::at(SystemTime::now()).to_raw_u64();
+        for (i, overflowed) in [1, 0, 1].into_iter().enumerate() {
+            let mut batch = TestBatch::default();
+            batch.create(
+                "did:plc:inze6wrmsm7pjl7yta3oig77",
+                "a.b.c",
+                &format!("rkey-{i}"),
+                "{}",
+                Some("rev-a"),
+                None,
+                now + i as u64,
+            );
+            batch.batch.overflowed_collections = overflowed;
+            write.insert_batch(batch.batch)?;
+        }
+
+        let ConsumerInfo::Jetstream {
+            overflowed_collections_24h,
+            ..
+        } = read.get_consumer_info()?;
+        assert_eq!(overflowed_collections_24h, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cursor_gap_detected() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
+        let did = "did:plc:inze6wrmsm7pjl7yta3oig77";
+
+        let mut batch = TestBatch::default();
+        batch.create(did, "a.b.c", "rkey-a", "{}", None, None, 100);
+        write.insert_batch(batch.batch)?;
+        let ConsumerInfo::Jetstream { gap_detected, .. } = read.get_consumer_info()?;
+        assert_eq!(gap_detected, None);
+
+        let gap = CursorGap {
+            last_seen: 100,
+            resumed_at: 900_000_000,
+        };
+        let mut batch = TestBatch::default();
+        batch.create(did, "a.b.c", "rkey-b", "{}", None, None, 900_000_000);
+        batch.batch.cursor_gap = Some(gap);
+        write.insert_batch(batch.batch)?;
+
+        // sticks around for later batches without one
+        let mut batch = TestBatch::default();
+        batch.create(did, "a.b.c", "rkey-c", "{}", None, None, 900_000_001);
+        write.insert_batch(batch.batch)?;
+        let ConsumerInfo::Jetstream { gap_detected, .. } = read.get_consumer_info()?;
+        assert_eq!(gap_detected, Some(gap));
 
-            if meta.cursor() != feed_key.cursor() {
-                // older/different version
-                self.feeds.remove(&*key_bytes)?;
-                dangling_feed_keys_cleaned += 1;
-                continue;
-            }
-            if meta.rev != feed_val.rev() {
-                // weird...
-                log::warn!("record lookup: cursor match but rev did not...? removing.");
-                self.records.remove(&location_key_bytes)?;
-                self.feeds.remove(&*key_bytes)?;
-                dangling_feed_keys_cleaned += 1;
-                continue;
-            }
+        Ok(())
+    }
 
-            live_records_found += 1;
-            if live_records_found <= limit {
-                continue;
-            }
-            if candidate_new_feed_lower_cursor.is_none() {
-                candidate_new_feed_lower_cursor = Some(feed_key.cursor());
-            }
+    #[test]
+    fn test_event_kinds_rollup() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
 
-            self.records.remove(&location_key_bytes)?;
-            self.feeds.remove(key_bytes)?;
-            records_deleted += 1;
+        let now = Cursor::at(SystemTime::now()).to_raw_u64();
+        for (i, (identities, accounts)) in [(2, 0), (0, 3)].into_iter().enumerate() {
+            let mut batch = TestBatch::default();
+            batch.create(
+                "did:plc:inze6wrmsm7pjl7yta3oig77",
+                "a.b.c",
+                &format!("rkey-{i}"),
+                "{}",
+                Some("rev-a"),
+                None,
+                now + i as u64,
+            );
+            batch.batch.event_kinds = EventKindCounts {
+                commits: 1,
+                identities,
+                accounts,
+            };
+            write.insert_batch(batch.batch)?;
         }
 
-        if !ended_early {
-            if let Some(new_cursor) = candidate_new_feed_lower_cursor {
-                self.global.insert(
-                    &TrimCollectionCursorKey::new(collection.clone()).to_db_bytes()?,
-                    &new_cursor.to_db_bytes()?,
-                )?;
+        let hour: HourTruncatedCursor = Cursor::from_raw_u64(now).into();
+        let (hours, kinds) = read.get_event_kinds(hour.prev(), Some(hour.nth_next(1)), 3600)?;
+        assert_eq!(hours, vec![hour.prev(), hour]);
+        assert_eq!(kinds[0], EventKindCounts::default());
+        assert_eq!(
+            kinds[1],
+            EventKindCounts {
+                commits: 2,
+                identities: 2,
+                accounts: 3,
             }
-        }
+        );
 
-        log::trace!("trim_collection ({collection:?}) removed {dangling_feed_keys_cleaned} dangling feed entries and {records_deleted} records (ended early? {ended_early})");
-        Ok((dangling_feed_keys_cleaned, records_deleted, ended_early))
+        Ok(())
     }
 
-    fn delete_account(&mut self, did: &Did) -> Result<usize, StorageError> {
-        let mut records_deleted = 0;
-        let mut batch = self.keyspace.batch();
-        let prefix = RecordLocationKey::from_prefix_to_db_bytes(did)?;
-        for kv in self.records.prefix(prefix) {
-            let (key_bytes, _) = kv?;
-            batch.remove(&self.records, key_bytes);
-            records_deleted += 1;
-            if batch.len() >= MAX_BATCHED_ACCOUNT_DELETE_RECORDS {
-                counter!("storage_delete_account_partial_commits").increment(1);
-                batch.commit()?;
-                batch = self.keyspace.batch();
-            }
-        }
-        counter!("storage_delete_account_completions").increment(1);
-        counter!("storage_delete_account_records_deleted").increment(records_deleted as u64);
-        batch.commit()?;
-        Ok(records_deleted)
+    #[test]
+    fn test_provided_sketch_secret() -> anyhow::Result<()> {
+        let secret = microcosm_estimates::parse_secret("00112233445566778899aabbccddeeff")?;
+        let (_, _, _, sketch_secret) = FjallStorage::init(
+            tempfile::tempdir()?,
+            "offline test (no real jetstream endpoint)".to_string(),
+            false,
+            FjallConfig {
+                temp: true,
+                sketch_secret: Some(secret),
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(sketch_secret, secret);
+        Ok(())
     }
-}
 
-pub struct FjallBackground(FjallWriter);
+    #[test]
+    fn test_seeded_sketch_secret() -> anyhow::Result<()> {
+        let init = |seed| {
+            FjallStorage::init(
+                tempfile::tempdir().unwrap(),
+                "offline test (no real jetstream endpoint)".to_string(),
+                false,
+                FjallConfig {
+                    temp: true,
+                    seed: Some(seed),
+                    ..Default::default()
+                },
+            )
+            .map(|(_, _, _, secret)| secret)
+        };
+        assert_eq!(init(42)?, init(42)?);
+        assert_ne!(init(42)?, init(43)?);
+        Ok(())
+    }
 
-#[async_trait]
-impl StoreBackground for FjallBackground {
-    async fn run(mut self, backfill: bool) -> StorageResult<()> {
-        let mut dirty_nsids = HashSet::new();
+    #[test]
+    fn test_jetstream_shard_cursors() -> anyhow::Result<()> {
+        let shard_a: JetstreamShard = "a.a.a".parse().unwrap();
+        let shard_b: JetstreamShard = "b.b.b,c.c.c".parse().unwrap();
+        let (_, mut write, _, _) = FjallStorage::init(
+            tempfile::tempdir()?,
+            "offline test (no real jetstream endpoint)".to_string(),
+            false,
+            FjallConfig {
+                temp: true,
+                jetstream_shards: vec![shard_a.clone(), shard_b.clone()],
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(write.jetstream_shard_cursor(&shard_a)?, None);
 
-        // backfill condition here is iffy -- longer is good when doing the main ingest and then collection trims
-        // shorter once those are done helps things catch up
-        // the best setting for non-backfill is non-obvious.. it can be pretty slow and still be fine
-        let mut rollup =
-            tokio::time::interval(Duration::from_micros(if backfill { 100 } else { 32_000 }));
-        rollup.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut batch = TestBatch::default();
+        batch.create("did:plc:a", "a.a.a", "rkey-a", "{}", None, None, 100);
+        batch.create("did:plc:b", "b.b.b", "rkey-b", "{}", None, None, 200);
+        write.insert_batch(batch.batch)?;
+        assert_eq!(
+            write.jetstream_shard_cursor(&shard_a)?,
+            Some(Cursor::from_raw_u64(100))
+        );
+        assert_eq!(
+            write.jetstream_shard_cursor(&shard_b)?,
+            Some(Cursor::from_raw_u64(200))
+        );
 
-        // backfill condition again iffy. collection trims should probably happen in their own phase.
-        let mut trim = tokio::time::interval(Duration::from_secs(if backfill { 18 } else { 9 }));
-        trim.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        // shard a is lagging behind: its cursor moves, the overall one doesn't go back
+        let mut batch = TestBatch::default();
+        batch.create("did:plc:a", "a.a.a", "rkey-a2", "{}", None, None, 150);
+        write.insert_batch(batch.batch)?;
+        assert_eq!(
+            write.jetstream_shard_cursor(&shard_a)?,
+            Some(Cursor::from_raw_u64(150))
+        );
+        assert_eq!(
+            get_static_neu::<JetstreamCursorKey, JetstreamCursorValue>(&write.global)?,
+            Some(Cursor::from_raw_u64(200))
+        );
+        Ok(())
+    }
 
-        loop {
-            tokio::select! {
-                _ = rollup.tick() => {
-                    let mut db = self.0.clone();
-                    let (n, dirty) = tokio::task::spawn_blocking(move || db.step_rollup()).await??;
-                    if n == 0 {
-                        rollup.reset_after(Duration::from_millis(1_200)); // we're caught up, take a break
-                    }
-                    dirty_nsids.extend(dirty);
-                    log::trace!("rolled up {n} items ({} collections now dirty)", dirty_nsids.len());
-                },
-                _ = trim.tick() => {
-                    let n = dirty_nsids.len();
-                    log::trace!("trimming {n} nsids: {dirty_nsids:?}");
-                    let t0 = Instant::now();
-                    let (mut total_danglers, mut total_deleted) = (0, 0);
-                    let mut completed = HashSet::new();
-                    for collection in &dirty_nsids {
-                        let mut db = self.0.clone();
-                        let c = collection.clone();
-                        let (danglers, deleted, ended_early) = tokio::task::spawn_blocking(move || db.trim_collection(&c, 512, false)).await??;
-                        total_danglers += danglers;
-                        total_deleted += deleted;
-                        if !ended_early {
-                            completed.insert(collection.clone());
-                        }
-                        if total_deleted > 10_000_000 {
-                            log::info!("trim stopped early, more than 10M records already deleted.");
-                            break;
-                        }
-                    }
-                    let dt = t0.elapsed();
-                    log::trace!("finished trimming {n} nsids in {dt:?}: {total_danglers} dangling and {total_deleted} total removed.");
-                    histogram!("storage_trim_dirty_nsids").record(completed.len() as f64);
-                    histogram!("storage_trim_duration").record(dt.as_micros() as f64);
-                    counter!("storage_trim_removed", "dangling" => "true").increment(total_danglers as u64);
-                    if total_deleted >= total_danglers {
-                        counter!("storage_trim_removed", "dangling" => "false").increment((total_deleted - total_danglers) as u64);
-                    } else {
-                        // TODO: probably think through what's happening here
-                        log::warn!("weird trim case: more danglers than deleted? metric will be missing for dangling=false. deleted={total_deleted} danglers={total_danglers}");
-                    }
-                    for c in completed {
-                        dirty_nsids.remove(&c);
-                    }
-                },
-            };
-        }
+    #[test]
+    fn test_redaction_on_insert() -> anyhow::Result<()> {
+        let redaction = Redactor::new(serde_json::from_str(
+            r#"{"version": 4, "rules": [{"collection": "a.b.c", "paths": ["email"]}]}"#,
+        )?)?;
+        let (read, mut write, _, _) = FjallStorage::init(
+            tempfile::tempdir()?,
+            "offline test (no real jetstream endpoint)".to_string(),
+            false,
+            FjallConfig {
+                temp: true,
+                redaction: Some(redaction),
+                ..Default::default()
+            },
+        )?;
+
+        let mut batch = TestBatch::default();
+        let redacted = batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-asdf",
+            r#"{"email": "me@example.com", "text": "hi"}"#,
+            Some("rev-a"),
+            None,
+            100,
+        );
+        let untouched = batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "d.e.f",
+            "rkey-asdg",
+            r#"{"email": "me@example.com"}"#,
+            Some("rev-b"),
+            None,
+            101,
+        );
+        write.insert_batch(batch.batch)?;
+
+        let (records, _, _) =
+            read.get_records_by_collections([redacted].into(), 2, false, BUDGET)?;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].record.get(), r#"{"text":"hi"}"#);
+        assert_eq!(records[0].redaction_version, Some(4));
+
+        let (records, _, _) =
+            read.get_records_by_collections([untouched].into(), 2, false, BUDGET)?;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].record.get(), r#"{"email": "me@example.com"}"#);
+        assert_eq!(records[0].redaction_version, None);
+
+        Ok(())
     }
-}
 
-/// Get a value from a fixed key
-fn get_static_neu<K: StaticStr, V: DbBytes>(global: &PartitionHandle) -> StorageResult<Option<V>> {
-    let key_bytes = DbStaticStr::<K>::default().to_db_bytes()?;
-    let value = global
-        .get(&key_bytes)?
-        .map(|value_bytes| db_complete(&value_bytes))
-        .transpose()?;
-    Ok(value)
-}
+    #[test]
+    fn test_transforms_on_insert() -> anyhow::Result<()> {
+        let redaction = Redactor::new(serde_json::from_str(
+            r#"{"version": 4, "rules": [{"collection": "a.b.c", "paths": ["email"]}]}"#,
+        )?)?;
+        let transforms = Transformer::new(serde_json::from_str(
+            r#"{"rules": [{"collection": "a.b.c", "transforms": [
+                {"type": "keep_fields", "fields": ["text"]},
+                {"type": "normalize"}
+            ]}]}"#,
+        )?)?;
+        let (read, mut write, _, _) = FjallStorage::init(
+            tempfile::tempdir()?,
+            "offline test (no real jetstream endpoint)".to_string(),
+            false,
+            FjallConfig {
+                temp: true,
+                redaction: Some(redaction),
+                transforms: Some(transforms),
+                ..Default::default()
+            },
+        )?;
 
-/// Get a value from a fixed key
-fn get_snapshot_static_neu<K: StaticStr, V: DbBytes>(
-    global: &fjall::Snapshot,
-) -> StorageResult<Option<V>> {
-    let key_bytes = DbStaticStr::<K>::default().to_db_bytes()?;
-    let value = global
-        .get(&key_bytes)?
-        .map(|value_bytes| db_complete(&value_bytes))
-        .transpose()?;
-    Ok(value)
-}
+        let mut batch = TestBatch::default();
+        let transformed = batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-asdf",
+            r#"{"email": "me@example.com", "text": "hi", "extra": 1}"#,
+            Some("rev-a"),
+            None,
+            100,
+        );
+        let untouched = batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "d.e.f",
+            "rkey-asdg",
+            r#"{"extra": 1}"#,
+            Some("rev-b"),
+            None,
+            101,
+        );
+        write.insert_batch(batch.batch)?;
 
-/// Set a value to a fixed key
-fn insert_static_neu<K: StaticStr>(
-    global: &PartitionHandle,
-    value: impl DbBytes,
-) -> StorageResult<()> {
-    let key_bytes = DbStaticStr::<K>::default().to_db_bytes()?;
-    let value_bytes = value.to_db_bytes()?;
-    global.insert(&key_bytes, &value_bytes)?;
-    Ok(())
-}
+        let (records, _, _) =
+            read.get_records_by_collections([transformed].into(), 2, false, BUDGET)?;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].record.get(), r#"{"text":"hi"}"#);
+        assert_eq!(records[0].redaction_version, Some(4));
+        assert_eq!(
+            records[0].transforms,
+            Some(vec!["keep_fields".to_string(), "normalize".to_string()])
+        );
 
-/// Set a value to a fixed key, erroring if the value already exists
-///
-/// Intended for single-threaded init: not safe under concurrency, since there
-/// is no transaction between checking if the already exists and writing it.
-fn init_static_neu<K: StaticStr>(
-    global: &PartitionHandle,
-    value: impl DbBytes,
-) -> StorageResult<()> {
-    let key_bytes = DbStaticStr::<K>::default().to_db_bytes()?;
-    if global.get(&key_bytes)?.is_some() {
-        return Err(StorageError::InitError(format!(
-            "init failed: value for key {key_bytes:?} already exists"
-        )));
+        let (records, _, _) =
+            read.get_records_by_collections([untouched].into(), 2, false, BUDGET)?;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].record.get(), r#"{"extra": 1}"#);
+        assert_eq!(records[0].transforms, None);
+
+        Ok(())
     }
-    let value_bytes = value.to_db_bytes()?;
-    global.insert(&key_bytes, &value_bytes)?;
-    Ok(())
-}
 
-/// Set a value to a fixed key
-fn insert_batch_static_neu<K: StaticStr>(
-    batch: &mut FjallBatch,
-    global: &PartitionHandle,
-    value: impl DbBytes,
-) -> StorageResult<()> {
-    let key_bytes = DbStaticStr::<K>::default().to_db_bytes()?;
-    let value_bytes = value.to_db_bytes()?;
-    batch.insert(global, &key_bytes, &value_bytes);
-    Ok(())
-}
+    #[test]
+    fn test_watchlist_hits() -> anyhow::Result<()> {
+        let watchlists = Watchlists::new(serde_json::from_str(
+            r#"{"watchlists": [{"name": "crypto", "collection": "a.b.*", "keywords": ["free crypto"]}]}"#,
+        )?)?;
+        let (read, mut write, _, _) = FjallStorage::init(
+            tempfile::tempdir()?,
+            "offline test (no real jetstream endpoint)".to_string(),
+            false,
+            FjallConfig {
+                temp: true,
+                watchlists: Some(watchlists),
+                ..Default::default()
+            },
+        )?;
+
+        let mut batch = TestBatch::default();
+        batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-asdf",
+            r#"{"text": "free crypto here"}"#,
+            Some("rev-a"),
+            None,
+            100,
+        );
+        batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-asdg",
+            r#"{"text": "nothing to see"}"#,
+            Some("rev-b"),
+            None,
+            101,
+        );
+        batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig78",
+            "a.b.d",
+            "rkey-asdh",
+            r#"{"text": "FREE CRYPTO again"}"#,
+            Some("rev-c"),
+            None,
+            102,
+        );
+        write.insert_batch(batch.batch)?;
+
+        let mut batch = TestBatch::default();
+        batch.update(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-asdf",
+            r#"{"text": "just hi"}"#,
+            Some("rev-d"),
+            None,
+            200,
+        );
+        write.insert_batch(batch.batch)?;
 
-#[derive(Debug, serde::Serialize, schemars::JsonSchema)]
-pub struct StorageInfo {
-    pub keyspace_disk_space: u64,
-    pub keyspace_journal_count: usize,
-    pub keyspace_sequence: u64,
-    pub global_approximate_len: usize,
-}
+        let hits = read.get_watchlist_hits("crypto", None, 10)?;
+        assert_eq!(hits.len(), 2);
+        assert_eq!(hits[0].cursor, Cursor::from_raw_u64(102));
+        assert_eq!(hits[0].rkey.as_str(), "rkey-asdh");
+        assert_eq!(hits[0].matched, vec!["free crypto".to_string()]);
+        assert_eq!(
+            hits[0].record.as_ref().map(|r| r.record.get()),
+            Some(r#"{"text": "FREE CRYPTO again"}"#)
+        );
+        // updated since it matched
+        assert_eq!(hits[1].cursor, Cursor::from_raw_u64(100));
+        assert!(hits[1].record.is_none());
 
-////////// temp stuff to remove:
+        let hits = read.get_watchlist_hits("crypto", Some(Cursor::from_raw_u64(102)), 10)?;
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].cursor, Cursor::from_raw_u64(100));
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::{DeleteAccount, RecordKey, UFOsCommit};
-    use jetstream::events::{CommitEvent, CommitOp};
-    use jetstream::exports::Cid;
-    use serde_json::value::RawValue;
+        assert!(read.get_watchlist_hits("nope", None, 10)?.is_empty());
 
-    fn fjall_db() -> (FjallReader, FjallWriter) {
-        let (read, write, _, _) = FjallStorage::init(
-            tempfile::tempdir().unwrap(),
-            "offline test (no real jetstream endpoint)".to_string(),
-            false,
-            FjallConfig { temp: true },
-        )
-        .unwrap();
-        (read, write)
-    }
+        let counts =
+            read.get_watchlist_counts("crypto", HourTruncatedCursor::truncate_raw_u64(0), None)?;
+        assert_eq!(counts, vec![(HourTruncatedCursor::truncate_raw_u64(0), 2)]);
 
-    const TEST_BATCH_LIMIT: usize = 16;
-    fn beginning() -> HourTruncatedCursor {
-        Cursor::from_start().into()
+        Ok(())
     }
 
-    #[derive(Debug, Default)]
-    struct TestBatch {
-        pub batch: EventBatch<TEST_BATCH_LIMIT>,
-    }
+    #[cfg(feature = "search")]
+    #[test]
+    fn test_search_records() -> anyhow::Result<()> {
+        let search = SearchIndex::in_memory();
+        let (read, mut write, _, _) = FjallStorage::init(
+            tempfile::tempdir()?,
+            "offline test (no real jetstream endpoint)".to_string(),
+            false,
+            FjallConfig {
+                temp: true,
+                search: Some(search.clone()),
+                ..Default::default()
+            },
+        )?;
 
-    impl TestBatch {
-        #[allow(clippy::too_many_arguments)]
-        pub fn create(
-            &mut self,
-            did: &str,
-            collection: &str,
-            rkey: &str,
-            record: &str,
-            rev: Option<&str>,
-            cid: Option<Cid>,
-            cursor: u64,
-        ) -> Nsid {
-            let did = Did::new(did.to_string()).unwrap();
-            let collection = Nsid::new(collection.to_string()).unwrap();
-            let record = RawValue::from_string(record.to_string()).unwrap();
-            let cid = cid.unwrap_or(
-                "bafyreidofvwoqvd2cnzbun6dkzgfucxh57tirf3ohhde7lsvh4fu3jehgy"
-                    .parse()
-                    .unwrap(),
-            );
+        let mut batch = TestBatch::default();
+        batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-asdf",
+            r#"{"text": "hello from the ufo"}"#,
+            Some("rev-a"),
+            None,
+            100,
+        );
+        batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-asdg",
+            r#"{"text": "goodbye"}"#,
+            Some("rev-b"),
+            None,
+            101,
+        );
+        batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig78",
+            "a.b.d",
+            "rkey-asdh",
+            r#"{"title": "Hello, UFO"}"#,
+            Some("rev-c"),
+            None,
+            102,
+        );
+        write.insert_batch(batch.batch)?;
+        search.commit();
 
-            let event = CommitEvent {
-                collection,
-                rkey: RecordKey::new(rkey.to_string()).unwrap(),
-                rev: rev.unwrap_or("asdf").to_string(),
-                operation: CommitOp::Create,
-                record: Some(record),
-                cid: Some(cid),
-            };
+        let found = read.search_records("hello ufo", None, 10)?;
+        let mut rkeys: Vec<_> = found.iter().map(|r| r.rkey.as_str()).collect();
+        rkeys.sort();
+        assert_eq!(rkeys, vec!["rkey-asdf", "rkey-asdh"]);
 
-            let (commit, collection) =
-                UFOsCommit::from_commit_info(event, did.clone(), Cursor::from_raw_u64(cursor))
-                    .unwrap();
+        let found =
+            read.search_records("hello", Some(&Nsid::new("a.b.d".to_string()).unwrap()), 10)?;
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].record.get(), r#"{"title": "Hello, UFO"}"#);
 
-            self.batch
-                .commits_by_nsid
-                .entry(collection.clone())
-                .or_default()
-                .truncating_insert(commit, &[0u8; 16])
-                .unwrap();
+        let mut batch = TestBatch::default();
+        batch.delete(
+            "did:plc:inze6wrmsm7pjl7yta3oig78",
+            "a.b.d",
+            "rkey-asdh",
+            Some("rev-d"),
+            200,
+        );
+        write.insert_batch(batch.batch)?;
+        search.commit();
 
-            collection
-        }
-        #[allow(clippy::too_many_arguments)]
-        pub fn update(
-            &mut self,
-            did: &str,
-            collection: &str,
-            rkey: &str,
-            record: &str,
-            rev: Option<&str>,
-            cid: Option<Cid>,
-            cursor: u64,
-        ) -> Nsid {
-            let did = Did::new(did.to_string()).unwrap();
-            let collection = Nsid::new(collection.to_string()).unwrap();
-            let record = RawValue::from_string(record.to_string()).unwrap();
-            let cid = cid.unwrap_or(
-                "bafyreidofvwoqvd2cnzbun6dkzgfucxh57tirf3ohhde7lsvh4fu3jehgy"
-                    .parse()
-                    .unwrap(),
-            );
+        let found = read.search_records("hello", None, 10)?;
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].rkey.as_str(), "rkey-asdf");
 
-            let event = CommitEvent {
-                collection,
-                rkey: RecordKey::new(rkey.to_string()).unwrap(),
-                rev: rev.unwrap_or("asdf").to_string(),
-                operation: CommitOp::Update,
-                record: Some(record),
-                cid: Some(cid),
-            };
+        assert!(matches!(
+            read.search_records("text:(", None, 10),
+            Err(QueryError::BadSearchQuery(_))
+        ));
 
-            let (commit, collection) =
-                UFOsCommit::from_commit_info(event, did.clone(), Cursor::from_raw_u64(cursor))
-                    .unwrap();
+        Ok(())
+    }
 
-            self.batch
-                .commits_by_nsid
-                .entry(collection.clone())
-                .or_default()
-                .truncating_insert(commit, &[0u8; 16])
-                .unwrap();
+    #[test]
+    fn test_search_records_without_index() -> anyhow::Result<()> {
+        let (read, _) = fjall_db();
+        assert!(matches!(
+            read.search_records("hello", None, 10),
+            Err(QueryError::Storage(StorageError::Unsupported(_)))
+        ));
+        Ok(())
+    }
 
-            collection
-        }
-        #[allow(clippy::too_many_arguments)]
-        pub fn delete(
-            &mut self,
-            did: &str,
-            collection: &str,
-            rkey: &str,
-            rev: Option<&str>,
-            cursor: u64,
-        ) -> Nsid {
-            let did = Did::new(did.to_string()).unwrap();
-            let collection = Nsid::new(collection.to_string()).unwrap();
-            let event = CommitEvent {
-                collection,
-                rkey: RecordKey::new(rkey.to_string()).unwrap(),
-                rev: rev.unwrap_or("asdf").to_string(),
-                operation: CommitOp::Delete,
-                record: None,
-                cid: None,
-            };
+    #[test]
+    fn test_unique_record_counts() -> anyhow::Result<()> {
+        let (read, mut write, _, _) = FjallStorage::init(
+            tempfile::tempdir()?,
+            "offline test (no real jetstream endpoint)".to_string(),
+            false,
+            FjallConfig {
+                temp: true,
+                unique_counts: true,
+                ..Default::default()
+            },
+        )?;
+        let hour = 3_600_000_000;
+        let window = |since: u64, until: u64| {
+            read.get_unique_records(
+                &Nsid::new("a.b.c".to_string()).unwrap(),
+                HourTruncatedCursor::truncate_raw_u64(since),
+                Some(HourTruncatedCursor::truncate_raw_u64(until)),
+            )
+        };
 
-            let (commit, collection) =
-                UFOsCommit::from_commit_info(event, did, Cursor::from_raw_u64(cursor)).unwrap();
+        let mut batch = TestBatch::default();
+        let collection = batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-asdf",
+            "{}",
+            Some("rev-a"),
+            None,
+            100,
+        );
+        write.insert_batch(batch.batch.clone())?;
+        // the same batch again, like after a reconnect with an old cursor
+        write.insert_batch(batch.batch)?;
 
-            self.batch
-                .commits_by_nsid
-                .entry(collection.clone())
-                .or_default()
-                .truncating_insert(commit, &[0u8; 16])
-                .unwrap();
+        // deleted and created again
+        let mut batch = TestBatch::default();
+        batch.delete(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-asdf",
+            Some("rev-b"),
+            101,
+        );
+        write.insert_batch(batch.batch)?;
+        let mut batch = TestBatch::default();
+        batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-asdf",
+            "{}",
+            Some("rev-c"),
+            None,
+            102,
+        );
+        // a different record, an hour later
+        batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-asdg",
+            "{}",
+            Some("rev-d"),
+            None,
+            hour + 103,
+        );
+        write.insert_batch(batch.batch)?;
 
-            collection
-        }
-        pub fn delete_account(&mut self, did: &str, cursor: u64) -> Did {
-            let did = Did::new(did.to_string()).unwrap();
-            self.batch.account_removes.push(DeleteAccount {
-                did: did.clone(),
-                cursor: Cursor::from_raw_u64(cursor),
-            });
-            did
+        assert_eq!(
+            window(0, 2 * hour)?,
+            Some(UniqueRecords {
+                approximate_records: 2,
+                unchecked: 0,
+            })
+        );
+        assert_eq!(window(hour, 2 * hour)?.unwrap().approximate_records, 1);
+        assert_eq!(window(hour, hour)?.unwrap().approximate_records, 0);
+
+        // more creates than fit in a batch: the dropped ones can't be checked
+        let mut batch = TestBatch::default();
+        for i in 0..(TEST_BATCH_LIMIT + 4) {
+            batch.create(
+                "did:plc:inze6wrmsm7pjl7yta3oig77",
+                "a.b.c",
+                &format!("rkey-full-{i}"),
+                "{}",
+                Some(&format!("rev-full-{i}")),
+                None,
+                3 * hour + i as u64,
+            );
         }
+        write.insert_batch(batch.batch.clone())?;
+        let full = window(3 * hour, 4 * hour)?.unwrap();
+        assert_eq!(full.approximate_records, TEST_BATCH_LIMIT as u64 + 4);
+        assert_eq!(full.unchecked, 4);
+        // replayed: the kept creates are caught as repeats, the dropped ones aren't
+        write.insert_batch(batch.batch)?;
+        let replayed = window(3 * hour, 4 * hour)?.unwrap();
+        assert_eq!(
+            replayed.approximate_records,
+            full.approximate_records + full.unchecked
+        );
+        assert_eq!(replayed.unchecked, 2 * full.unchecked);
+
+        write.purge_collection(&collection, false)?;
+        assert_eq!(window(0, 2 * hour)?, Some(UniqueRecords::default()));
+
+        // not counting
+        let (read, _) = fjall_db();
+        assert_eq!(
+            read.get_unique_records(&collection, beginning(), None)?,
+            None
+        );
+        Ok(())
     }
 
     #[test]
-    fn test_hello() -> anyhow::Result<()> {
-        let (read, mut write) = fjall_db();
-        write.insert_batch::<TEST_BATCH_LIMIT>(EventBatch::default())?;
-        let JustCount {
-            creates,
-            dids_estimate,
-            ..
-        } = read.get_collection_counts(
-            &Nsid::new("a.b.c".to_string()).unwrap(),
-            beginning(),
-            None,
-        )?;
-        assert_eq!(creates, 0);
-        assert_eq!(dids_estimate, 0);
+    fn test_audit_log() -> anyhow::Result<()> {
+        let (_, write) = fjall_db();
+        let entry = |at, action: &str| AuditEntry {
+            at,
+            actor: "ops".to_string(),
+            action: action.to_string(),
+            params: serde_json::json!({"collection": "a.b.c"}),
+            outcome: "ok".to_string(),
+        };
+        write.record_audit(entry(100, "purge_collection"))?;
+        // same instant: bumped after the previous entry
+        write.record_audit(entry(100, "deny_collections"))?;
+        write.record_audit(entry(50, "allow_collections"))?;
+
+        let log = write.get_audit_log(None, 10)?;
+        let positions: Vec<_> = log.iter().map(|e| (e.at, e.action.as_str())).collect();
+        assert_eq!(
+            positions,
+            vec![
+                (102, "allow_collections"),
+                (101, "deny_collections"),
+                (100, "purge_collection"),
+            ]
+        );
+
+        let page = write.get_audit_log(Some(101), 10)?;
+        assert_eq!(page, vec![entry(100, "purge_collection")]);
+        assert_eq!(write.get_audit_log(None, 1)?.len(), 1);
         Ok(())
     }
 
     #[test]
-    fn test_insert_one() -> anyhow::Result<()> {
-        let (read, mut write) = fjall_db();
+    fn test_change_feed() -> anyhow::Result<()> {
+        let redaction = Redactor::new(serde_json::from_str(
+            r#"{"version": 1, "rules": [{"collection": "a.b.c", "paths": ["email"]}]}"#,
+        )?)?;
+        let (read, mut write, _, _) = FjallStorage::init(
+            tempfile::tempdir()?,
+            "offline test (no real jetstream endpoint)".to_string(),
+            false,
+            FjallConfig {
+                temp: true,
+                redaction: Some(redaction),
+                // test cursors are ancient, so everything is past retention
+                change_feed: Some(Duration::ZERO),
+                ..Default::default()
+            },
+        )?;
 
         let mut batch = TestBatch::default();
         let collection = batch.create(
             "did:plc:inze6wrmsm7pjl7yta3oig77",
             "a.b.c",
-            "asdf",
-            "{}",
-            Some("rev-z"),
+            "rkey-asdf",
+            r#"{"email": "me@example.com", "text": "hi"}"#,
+            Some("rev-a"),
             None,
             100,
         );
         write.insert_batch(batch.batch)?;
-        write.step_rollup()?;
+        let mut batch = TestBatch::default();
+        batch.delete(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-asdf",
+            Some("rev-b"),
+            101,
+        );
+        write.insert_batch(batch.batch)?;
 
-        let JustCount {
-            creates,
-            dids_estimate,
-            ..
-        } = read.get_collection_counts(&collection, beginning(), None)?;
-        assert_eq!(creates, 1);
-        assert_eq!(dids_estimate, 1);
-        let JustCount {
-            creates,
-            dids_estimate,
-            ..
-        } = read.get_collection_counts(
-            &Nsid::new("d.e.f".to_string()).unwrap(),
-            beginning(),
-            None,
-        )?;
-        assert_eq!(creates, 0);
-        assert_eq!(dids_estimate, 0);
+        let (changes, trimmed) = read.get_changes(None, 10)?;
+        assert_eq!(trimmed, None);
+        let cursors: Vec<_> = changes.iter().map(|(c, _)| c.to_raw_u64()).collect();
+        assert_eq!(cursors, vec![100, 101]);
 
-        let records = read.get_records_by_collections([collection].into(), 2, false)?;
-        assert_eq!(records.len(), 1);
-        let rec = &records[0];
-        assert_eq!(rec.record.get(), "{}");
-        assert!(!rec.is_update);
+        // published records are redacted like stored ones
+        let published: EventBatch<TEST_BATCH_LIMIT> = spill::decode(&changes[0].1)?;
+        let commits = &published.commits_by_nsid[&collection].commits;
+        let CommitAction::Put(put) = &commits[0].action else {
+            panic!("expected a put");
+        };
+        assert_eq!(put.record.get(), r#"{"text":"hi"}"#);
 
-        let records = read.get_records_by_collections(
-            [Nsid::new("d.e.f".to_string()).unwrap()].into(),
-            2,
-            false,
-        )?;
-        assert_eq!(records.len(), 0);
+        let (changes, _) = read.get_changes(Some(Cursor::from_raw_u64(100)), 10)?;
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].0, Cursor::from_raw_u64(101));
 
+        assert_eq!(write.trim_changes()?, 2);
+        let (changes, trimmed) = read.get_changes(None, 10)?;
+        assert!(changes.is_empty());
+        assert_eq!(trimmed, Some(Cursor::from_raw_u64(101)));
         Ok(())
     }
 
     #[test]
-    fn test_get_multi_collection() -> anyhow::Result<()> {
-        let (read, mut write) = fjall_db();
+    fn test_record_diffs_on_update() -> anyhow::Result<()> {
+        let (read, mut write, _, _) = FjallStorage::init(
+            tempfile::tempdir()?,
+            "offline test (no real jetstream endpoint)".to_string(),
+            false,
+            FjallConfig {
+                temp: true,
+                record_diffs: true,
+                ..Default::default()
+            },
+        )?;
 
         let mut batch = TestBatch::default();
-        batch.create(
+        let collection = batch.create(
             "did:plc:inze6wrmsm7pjl7yta3oig77",
-            "a.a.a",
-            "aaa",
-            r#""earliest""#,
+            "a.b.c",
+            "self",
+            r#"{"displayName": "a", "description": "hi"}"#,
             Some("rev-a"),
             None,
             100,
         );
-        batch.create(
+        write.insert_batch(batch.batch)?;
+
+        let (records, _, _) =
+            read.get_records_by_collections([collection.clone()].into(), 2, false, BUDGET)?;
+        assert_eq!(records.len(), 1);
+        assert!(records[0].diff.is_none());
+
+        let mut batch = TestBatch::default();
+        batch.update(
             "did:plc:inze6wrmsm7pjl7yta3oig77",
-            "a.a.b",
-            "aab",
-            r#""in between""#,
-            Some("rev-ab"),
+            "a.b.c",
+            "self",
+            r#"{"displayName": "b"}"#,
+            Some("rev-b"),
             None,
             101,
         );
-        batch.create(
+        write.insert_batch(batch.batch)?;
+
+        let (records, _, _) =
+            read.get_records_by_collections([collection.clone()].into(), 2, false, BUDGET)?;
+        assert_eq!(records.len(), 1);
+        assert!(records[0].is_update);
+        assert_eq!(
+            records[0].diff.as_ref().map(|d| d.get()),
+            Some(r#"{"description":null,"displayName":"b"}"#)
+        );
+
+        // no previous version to diff against
+        let mut batch = TestBatch::default();
+        batch.update(
             "did:plc:inze6wrmsm7pjl7yta3oig77",
-            "a.a.a",
-            "aaa-2",
-            r#""last""#,
-            Some("rev-a-2"),
+            "a.b.c",
+            "other",
+            r#"{"displayName": "c"}"#,
+            Some("rev-c"),
             None,
             102,
         );
         write.insert_batch(batch.batch)?;
 
-        let records = read.get_records_by_collections(
-            HashSet::from([
-                Nsid::new("a.a.a".to_string()).unwrap(),
-                Nsid::new("a.a.b".to_string()).unwrap(),
-                Nsid::new("a.a.c".to_string()).unwrap(),
-            ]),
-            100,
+        let (records, _, _) =
+            read.get_records_by_collections([collection].into(), 1, false, BUDGET)?;
+        assert_eq!(records[0].record.get(), r#"{"displayName": "c"}"#);
+        assert!(records[0].diff.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_keep_record_versions() -> anyhow::Result<()> {
+        let kept = Nsid::new("a.b.c".to_string()).unwrap();
+        let (read, mut write, _, _) = FjallStorage::init(
+            tempfile::tempdir()?,
+            "offline test (no real jetstream endpoint)".to_string(),
             false,
+            FjallConfig {
+                temp: true,
+                keep_versions: [(kept.clone(), 2)].into(),
+                ..Default::default()
+            },
         )?;
-        assert_eq!(records.len(), 3);
-        assert_eq!(records[0].record.get(), r#""last""#);
-        assert_eq!(
-            records[0].collection,
-            Nsid::new("a.a.a".to_string()).unwrap()
-        );
-        assert_eq!(records[1].record.get(), r#""in between""#);
-        assert_eq!(
-            records[1].collection,
-            Nsid::new("a.a.b".to_string()).unwrap()
+        let did_str = "did:plc:inze6wrmsm7pjl7yta3oig77";
+        let did = Did::new(did_str.to_string()).unwrap();
+        let rkey = RecordKey::new("self".to_string()).unwrap();
+
+        let mut batch = TestBatch::default();
+        batch.create(
+            did_str,
+            "a.b.c",
+            "self",
+            r#"{"v": 0}"#,
+            Some("rev-0"),
+            None,
+            100,
         );
-        assert_eq!(records[2].record.get(), r#""earliest""#);
-        assert_eq!(
-            records[2].collection,
-            Nsid::new("a.a.a".to_string()).unwrap()
+        batch.create(
+            did_str,
+            "d.e.f",
+            "self",
+            r#"{"v": 0}"#,
+            Some("rev-0"),
+            None,
+            100,
         );
+        write.insert_batch(batch.batch)?;
+        for v in 1..=3 {
+            let mut batch = TestBatch::default();
+            let record = format!(r#"{{"v": {v}}}"#);
+            let rev = format!("rev-{v}");
+            batch.update(did_str, "a.b.c", "self", &record, Some(&rev), None, 100 + v);
+            batch.update(did_str, "d.e.f", "self", &record, Some(&rev), None, 100 + v);
+            write.insert_batch(batch.batch)?;
+        }
+
+        // the current version plus the two before it, newest first
+        let versions = read.get_record_versions(&did, &kept, &rkey)?;
+        let records: Vec<_> = versions.iter().map(|v| v.record.get()).collect();
+        assert_eq!(records, vec![r#"{"v": 3}"#, r#"{"v": 2}"#, r#"{"v": 1}"#]);
+        assert_eq!(versions[2].rev, "rev-1");
+        let cursors: Vec<_> = versions.iter().map(|v| v.cursor.to_raw_u64()).collect();
+        assert_eq!(cursors, vec![103, 102, 101]);
+
+        // sampled records still only see the current version
+        let (records, _, _) =
+            read.get_records_by_collections([kept.clone()].into(), 5, false, BUDGET)?;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].record.get(), r#"{"v": 3}"#);
+
+        // other collections only have the current version
+        let other = Nsid::new("d.e.f".to_string()).unwrap();
+        let versions = read.get_record_versions(&did, &other, &rkey)?;
+        assert_eq!(versions.len(), 1);
+
+        // deleting the record drops its history too
+        let mut batch = TestBatch::default();
+        batch.delete(did_str, "a.b.c", "self", Some("rev-4"), 110);
+        write.insert_batch(batch.batch)?;
+        assert!(read.get_record_versions(&did, &kept, &rkey)?.is_empty());
 
         Ok(())
     }
 
     #[test]
-    fn test_get_multi_collection_expanded() -> anyhow::Result<()> {
+    fn test_account_records_in_rkey_order() -> anyhow::Result<()> {
         let (read, mut write) = fjall_db();
+        let did_str = "did:plc:inze6wrmsm7pjl7yta3oig77";
+        let did = Did::new(did_str.to_string()).unwrap();
 
         let mut batch = TestBatch::default();
-        // insert some older ones in aab
-        for i in 1..=3 {
-            batch.create(
-                "did:plc:inze6wrmsm7pjl7yta3oig77",
-                "a.a.b",
-                &format!("aab-{i}"),
-                &format!(r#""b {i}""#),
-                Some(&format!("rev-b-{i}")),
-                None,
-                100 + i,
-            );
+        // out of cursor order, and with different lengths
+        for (rkey, cursor) in [("3l2", 103), ("3l1", 102), ("3l10", 101), ("3l3", 100)] {
+            batch.create(did_str, "a.b.c", rkey, "{}", None, None, cursor);
         }
-        // and some newer ones in aaa
-        for i in 1..=3 {
-            batch.create(
-                "did:plc:inze6wrmsm7pjl7yta3oig77",
-                "a.a.a",
-                &format!("aaa-{i}"),
-                &format!(r#""a {i}""#),
-                Some(&format!("rev-a-{i}")),
-                None,
-                200 + i,
-            );
+        batch.create(
+            "did:plc:someone-else",
+            "a.b.c",
+            "3l0",
+            "{}",
+            None,
+            None,
+            104,
+        );
+        let collection = batch.create(did_str, "d.e.f", "3l0", "{}", None, None, 105);
+        write.insert_batch(batch.batch)?;
+        let collection_abc = Nsid::new("a.b.c".to_string()).unwrap();
+
+        let rkeys = |records: Vec<UFOsRecord>| -> Vec<String> {
+            records.iter().map(|r| r.rkey.to_string()).collect()
+        };
+
+        let all = || (Bound::Unbounded, Bound::Unbounded);
+        let (records, more) = read.get_account_records(&did, &collection_abc, all(), 10, false)?;
+        assert_eq!(rkeys(records), vec!["3l1", "3l10", "3l2", "3l3"]);
+        assert!(!more);
+
+        let (records, more) = read.get_account_records(&did, &collection_abc, all(), 2, true)?;
+        assert_eq!(rkeys(records), vec!["3l3", "3l2"]);
+        assert!(more);
+
+        let range = (
+            Bound::Excluded("3l1".to_string()),
+            Bound::Excluded("3l3".to_string()),
+        );
+        let (records, _) = read.get_account_records(&did, &collection_abc, range, 10, false)?;
+        assert_eq!(rkeys(records), vec!["3l10", "3l2"]);
+
+        let (records, _) = read.get_account_records(&did, &collection, all(), 10, false)?;
+        assert_eq!(rkeys(records), vec!["3l0"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_approximate_count() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
+        let did = "did:plc:inze6wrmsm7pjl7yta3oig77";
+
+        let mut cursor = 100;
+        for b in 0..25 {
+            let mut batch = TestBatch::default();
+            for i in 0..10 {
+                let rkey = format!("rkey-{b}-{i}");
+                batch.create(did, "a.b.c", &rkey, "{}", None, None, cursor);
+                cursor += 1_000;
+            }
+            write.insert_batch(batch.batch)?;
         }
+        let mut batch = TestBatch::default();
+        batch.create(did, "d.e.f", "3l0", "{}", None, None, cursor);
+        batch.create(
+            "did:plc:someone-else",
+            "d.e.f",
+            "3l0",
+            "{}",
+            None,
+            None,
+            cursor + 1,
+        );
         write.insert_batch(batch.batch)?;
 
-        let records = read.get_records_by_collections(
-            HashSet::from([
-                Nsid::new("a.a.a".to_string()).unwrap(),
-                Nsid::new("a.a.b".to_string()).unwrap(),
-                Nsid::new("a.a.c".to_string()).unwrap(),
-            ]),
-            2,
-            true,
-        )?;
-        assert_eq!(records.len(), 4);
-        assert_eq!(records[0].record.get(), r#""a 3""#);
+        let count = |prefix| read.approximate_count(prefix).unwrap().unwrap();
+        let a_b_c = Nsid::new("a.b.c".to_string()).unwrap();
+        let d_e_f = Nsid::new("d.e.f".to_string()).unwrap();
+        let did = Did::new(did.to_string()).unwrap();
+
+        // past the sample, evenly spaced cursors interpolate well
+        let feed = count(CountPrefix::Collection(a_b_c.clone()));
+        assert!((240..=260).contains(&feed), "estimated {feed}");
+        // small prefixes are counted exactly
+        assert_eq!(count(CountPrefix::Collection(d_e_f.clone())), 2);
+        assert_eq!(count(CountPrefix::AccountCollection(did.clone(), d_e_f)), 1);
+        // the sample is all one collection, so there's no spread to go on
+        assert!(count(CountPrefix::Account(did)) > 64);
+        assert_eq!(
+            count(CountPrefix::Collection(
+                Nsid::new("x.y.z".to_string()).unwrap()
+            )),
+            0
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_sample_records() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
+        let did = "did:plc:inze6wrmsm7pjl7yta3oig77";
+        let collection = Nsid::new("a.b.c".to_string()).unwrap();
+
+        let mut cursor = 100;
+        for b in 0..5 {
+            let mut batch = TestBatch::default();
+            for i in 0..10 {
+                let rkey = format!("rkey-{b}-{i}");
+                batch.create(did, "a.b.c", &rkey, "{}", None, None, cursor);
+                cursor += 1_000;
+            }
+            write.insert_batch(batch.batch)?;
+        }
+        let mut batch = TestBatch::default();
+        batch.delete(did, "a.b.c", "rkey-2-3", None, cursor);
+        write.insert_batch(batch.batch)?;
+
+        let sample = read.sample_records(&collection, 10, 1)?;
+        assert_eq!(sample.len(), 10);
+        let cursors: Vec<u64> = sample.iter().map(|r| r.cursor.to_raw_u64()).collect();
+        assert!(cursors.windows(2).all(|w| w[0] > w[1]), "newest first");
+        assert!(sample.iter().all(|r| r.rkey.as_str() != "rkey-2-3"));
+
+        let again = read.sample_records(&collection, 10, 1)?;
+        let again: Vec<u64> = again.iter().map(|r| r.cursor.to_raw_u64()).collect();
+        assert_eq!(cursors, again);
+        let other = read.sample_records(&collection, 10, 2)?;
+        let other: Vec<u64> = other.iter().map(|r| r.cursor.to_raw_u64()).collect();
+        assert_ne!(cursors, other);
+
+        // asking for more than there are comes back short, without repeats
+        let all = read.sample_records(&collection, 100, 1)?;
+        let distinct: HashSet<u64> = all.iter().map(|r| r.cursor.to_raw_u64()).collect();
+        assert!(all.len() <= 49);
+        assert_eq!(distinct.len(), all.len());
+
+        let empty = read.sample_records(&Nsid::new("d.e.f".to_string()).unwrap(), 10, 1)?;
+        assert!(empty.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_interpolate_len() {
+        let key = |n: u64| [b"pre\0".as_slice(), &n.to_be_bytes()].concat();
+        assert_eq!(interpolate_len(10, &key(0), &key(9), &key(99)), 110);
+        // nothing to interpolate over
+        assert_eq!(interpolate_len(10, &key(5), &key(5), &key(5)), 11);
+    }
+
+    #[test]
+    fn test_records_by_did_across_collections() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
+        let did_str = "did:plc:inze6wrmsm7pjl7yta3oig77";
+        let did = Did::new(did_str.to_string()).unwrap();
+
+        let mut batch = TestBatch::default();
+        batch.create(did_str, "d.e.f", "3l0", "{}", None, None, 100);
+        batch.create(did_str, "a.b.c", "3l2", "{}", None, None, 101);
+        batch.create(did_str, "a.b.c", "3l1", "{}", None, None, 102);
+        batch.create(
+            "did:plc:someone-else",
+            "a.b.c",
+            "3l0",
+            "{}",
+            None,
+            None,
+            103,
+        );
+        write.insert_batch(batch.batch)?;
+
+        let locations = |records: Vec<UFOsRecord>| -> Vec<(String, String)> {
+            records
+                .iter()
+                .map(|r| (r.collection.to_string(), r.rkey.to_string()))
+                .collect()
+        };
+
+        let budget = ScanBudget::new(100);
+        let (records, cursor, truncated) = read.get_records_by_did(&did, None, 3, budget)?;
         assert_eq!(
-            records[0].collection,
-            Nsid::new("a.a.a".to_string()).unwrap()
+            locations(records),
+            vec![
+                ("a.b.c".to_string(), "3l1".to_string()),
+                ("a.b.c".to_string(), "3l2".to_string()),
+                ("d.e.f".to_string(), "3l0".to_string()),
+            ]
         );
+        assert_eq!(cursor, None);
+        assert!(!truncated);
 
-        assert_eq!(records[3].record.get(), r#""b 2""#);
+        let (records, cursor, truncated) = read.get_records_by_did(&did, None, 2, budget)?;
+        assert_eq!(records.len(), 2);
+        assert!(cursor.is_some());
+        assert!(!truncated);
+        let (records, cursor, _) = read.get_records_by_did(&did, cursor, 2, budget)?;
         assert_eq!(
-            records[3].collection,
-            Nsid::new("a.a.b".to_string()).unwrap()
+            locations(records),
+            vec![("d.e.f".to_string(), "3l0".to_string())]
         );
+        assert_eq!(cursor, None);
 
+        // running out of budget cuts the page short, and the cursor carries on
+        let (records, cursor, truncated) =
+            read.get_records_by_did(&did, None, 10, ScanBudget::new(1))?;
+        assert_eq!(
+            locations(records),
+            vec![("a.b.c".to_string(), "3l1".to_string())]
+        );
+        assert!(truncated);
+        let (records, _, truncated) = read.get_records_by_did(&did, cursor, 10, budget)?;
+        assert_eq!(records.len(), 2);
+        assert!(!truncated);
+
+        let nobody = Did::new("did:plc:nobody".to_string()).unwrap();
+        let (records, cursor, _) = read.get_records_by_did(&nobody, None, 10, budget)?;
+        assert!(records.is_empty());
+        assert_eq!(cursor, None);
         Ok(())
     }
 
     #[test]
-    fn test_update_one() -> anyhow::Result<()> {
+    fn test_spill_queue_until_inserted() -> anyhow::Result<()> {
+        let (_, mut write) = fjall_db();
+        let spill = write.spill_queue()?;
+        assert_eq!(spill.queued()?, vec![]);
+        assert_eq!(spill.latest_cursor()?, None);
+
+        let a = spill.push(Some(Cursor::from_raw_u64(100)), b"a".to_vec())?;
+        let b = spill.push(Some(Cursor::from_raw_u64(102)), b"b".to_vec())?;
+        assert_eq!(spill.queued()?, vec![a, b]);
+        assert_eq!(spill.get(a)?, Some(b"a".to_vec()));
+        assert_eq!(spill.latest_cursor()?, Some(Cursor::from_raw_u64(102)));
+
+        // inserting through the first batch's cursor drops it from the queue
+        let mut batch = TestBatch::default();
+        batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "asdf",
+            "{}",
+            Some("rev-z"),
+            None,
+            101,
+        );
+        write.insert_batch(batch.batch)?;
+        assert_eq!(spill.queued()?, vec![b]);
+        assert_eq!(spill.get(a)?, None);
+        assert_eq!(spill.get(b)?, Some(b"b".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_record_diffs_by_default() -> anyhow::Result<()> {
         let (read, mut write) = fjall_db();
 
         let mut batch = TestBatch::default();
         let collection = batch.create(
             "did:plc:inze6wrmsm7pjl7yta3oig77",
             "a.b.c",
-            "rkey-asdf",
-            "{}",
+            "self",
+            r#"{"displayName": "a"}"#,
             Some("rev-a"),
             None,
             100,
@@ -2150,35 +8269,87 @@ mod tests {
 
         let mut batch = TestBatch::default();
         batch.update(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "self",
+            r#"{"displayName": "b"}"#,
+            Some("rev-b"),
+            None,
+            101,
+        );
+        write.insert_batch(batch.batch)?;
+
+        let (records, _, _) =
+            read.get_records_by_collections([collection].into(), 2, false, BUDGET)?;
+        assert_eq!(records.len(), 1);
+        assert!(records[0].diff.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_denylist_skips_storage() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
+
+        write.deny_collections(DenyRule::new("a.b.*", false)?)?;
+        write.deny_collections(DenyRule::new("d.e.f", true)?)?;
+        assert_eq!(write.get_denylist().len(), 2);
+
+        let mut batch = TestBatch::default();
+        let denied = batch.create(
             "did:plc:inze6wrmsm7pjl7yta3oig77",
             "a.b.c",
             "rkey-asdf",
-            r#"{"ch":  "ch-ch-ch-changes"}"#,
-            Some("rev-z"),
+            "{}",
+            Some("rev-a"),
+            None,
+            100,
+        );
+        let counted = batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "d.e.f",
+            "rkey-asdg",
+            "{}",
+            Some("rev-b"),
             None,
             101,
         );
         write.insert_batch(batch.batch)?;
         write.step_rollup()?;
 
-        let JustCount {
-            creates,
-            dids_estimate,
-            ..
-        } = read.get_collection_counts(&collection, beginning(), None)?;
+        let JustCount { creates, .. } = read.get_collection_counts(&denied, beginning(), None)?;
+        assert_eq!(creates, 0);
+        let (records, _, _) =
+            read.get_records_by_collections([denied.clone()].into(), 2, false, BUDGET)?;
+        assert_eq!(records.len(), 0);
+
+        let JustCount { creates, .. } = read.get_collection_counts(&counted, beginning(), None)?;
         assert_eq!(creates, 1);
-        assert_eq!(dids_estimate, 1);
+        let (records, _, _) =
+            read.get_records_by_collections([counted].into(), 2, false, BUDGET)?;
+        assert_eq!(records.len(), 0);
+
+        assert!(write.allow_collections("a.b.*")?);
+        assert!(!write.allow_collections("a.b.*")?);
 
-        let records = read.get_records_by_collections([collection].into(), 2, false)?;
+        let mut batch = TestBatch::default();
+        batch.create(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.b.c",
+            "rkey-asdh",
+            "{}",
+            Some("rev-c"),
+            None,
+            102,
+        );
+        write.insert_batch(batch.batch)?;
+        let (records, _, _) = read.get_records_by_collections([denied].into(), 2, false, BUDGET)?;
         assert_eq!(records.len(), 1);
-        let rec = &records[0];
-        assert_eq!(rec.record.get(), r#"{"ch":  "ch-ch-ch-changes"}"#);
-        assert!(rec.is_update);
+
         Ok(())
     }
 
     #[test]
-    fn test_delete_one() -> anyhow::Result<()> {
+    fn test_denylist_still_applies_deletes() -> anyhow::Result<()> {
         let (read, mut write) = fjall_db();
 
         let mut batch = TestBatch::default();
@@ -2193,28 +8364,24 @@ mod tests {
         );
         write.insert_batch(batch.batch)?;
 
+        write.deny_collections(DenyRule::new("a.b.c", false)?)?;
+
         let mut batch = TestBatch::default();
         batch.delete(
             "did:plc:inze6wrmsm7pjl7yta3oig77",
             "a.b.c",
             "rkey-asdf",
-            Some("rev-z"),
+            Some("rev-b"),
             101,
         );
         write.insert_batch(batch.batch)?;
-        write.step_rollup()?;
-
-        let JustCount {
-            creates,
-            dids_estimate,
-            ..
-        } = read.get_collection_counts(&collection, beginning(), None)?;
-        assert_eq!(creates, 1);
-        assert_eq!(dids_estimate, 1);
 
-        let records = read.get_records_by_collections([collection].into(), 2, false)?;
+        assert!(write.allow_collections("a.b.c")?);
+        let (records, _, _) =
+            read.get_records_by_collections([collection.clone()].into(), 2, false, BUDGET)?;
         assert_eq!(records.len(), 0);
-
+        let feed_prefix = NsidRecordFeedKey::from_prefix_to_db_bytes(&collection)?;
+        assert!(write.feeds.prefix(feed_prefix).next().is_none());
         Ok(())
     }
 
@@ -2257,28 +8424,32 @@ mod tests {
 
         write.insert_batch(batch.batch)?;
 
-        let records = read.get_records_by_collections(
+        let (records, _, _) = read.get_records_by_collections(
             HashSet::from([Nsid::new("a.a.a".to_string()).unwrap()]),
             100,
             false,
+            BUDGET,
         )?;
         assert_eq!(records.len(), 1);
-        let records = read.get_records_by_collections(
+        let (records, _, _) = read.get_records_by_collections(
             HashSet::from([Nsid::new("a.a.b".to_string()).unwrap()]),
             100,
             false,
+            BUDGET,
         )?;
         assert_eq!(records.len(), 10);
-        let records = read.get_records_by_collections(
+        let (records, _, _) = read.get_records_by_collections(
             HashSet::from([Nsid::new("a.a.c".to_string()).unwrap()]),
             100,
             false,
+            BUDGET,
         )?;
         assert_eq!(records.len(), 1);
-        let records = read.get_records_by_collections(
+        let (records, _, _) = read.get_records_by_collections(
             HashSet::from([Nsid::new("a.a.d".to_string()).unwrap()]),
             100,
             false,
+            BUDGET,
         )?;
         assert_eq!(records.len(), 0);
 
@@ -2287,34 +8458,124 @@ mod tests {
         write.trim_collection(&Nsid::new("a.a.c".to_string()).unwrap(), 6, false)?;
         write.trim_collection(&Nsid::new("a.a.d".to_string()).unwrap(), 6, false)?;
 
-        let records = read.get_records_by_collections(
+        let (records, _, _) = read.get_records_by_collections(
             HashSet::from([Nsid::new("a.a.a".to_string()).unwrap()]),
             100,
             false,
+            BUDGET,
         )?;
         assert_eq!(records.len(), 1);
-        let records = read.get_records_by_collections(
+        let (records, _, _) = read.get_records_by_collections(
             HashSet::from([Nsid::new("a.a.b".to_string()).unwrap()]),
             100,
             false,
+            BUDGET,
         )?;
         assert_eq!(records.len(), 6);
-        let records = read.get_records_by_collections(
+        let (records, _, _) = read.get_records_by_collections(
             HashSet::from([Nsid::new("a.a.c".to_string()).unwrap()]),
             100,
             false,
+            BUDGET,
         )?;
         assert_eq!(records.len(), 1);
-        let records = read.get_records_by_collections(
+        let (records, _, _) = read.get_records_by_collections(
             HashSet::from([Nsid::new("a.a.d".to_string()).unwrap()]),
             100,
             false,
+            BUDGET,
         )?;
         assert_eq!(records.len(), 0);
 
         Ok(())
     }
 
+    #[test]
+    fn test_collection_trim_below_cutoff() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
+
+        let mut batch = TestBatch::default();
+        let mut collection = None;
+        for i in 1..=10 {
+            collection = Some(batch.create(
+                "did:plc:inze6wrmsm7pjl7yta3oig77",
+                "a.a.b",
+                &format!("rkey-bbb-{i}"),
+                &format!(r#"{{"n": {i}}}"#),
+                Some(&format!("rev-bbb-{i}")),
+                None,
+                11_000 + i,
+            ));
+        }
+        write.insert_batch(batch.batch)?;
+        let collection = collection.unwrap();
+
+        // leaves the original feed entry for rkey-bbb-2 stale
+        let mut batch = TestBatch::default();
+        batch.update(
+            "did:plc:inze6wrmsm7pjl7yta3oig77",
+            "a.a.b",
+            "rkey-bbb-2",
+            r#"{"n": 20}"#,
+            Some("rev-bbb-20"),
+            None,
+            11_020,
+        );
+        write.insert_batch(batch.batch)?;
+
+        // keeps 11_020, 11_010, 11_009: everything from 11_008 down goes
+        let (danglers, deleted, ended_early) = write.trim_collection(&collection, 3, false)?;
+        assert_eq!((danglers, deleted, ended_early), (1, 7, false));
+
+        let (records, _, _) =
+            read.get_records_by_collections([collection.clone()].into(), 100, false, BUDGET)?;
+        assert_eq!(records.len(), 3);
+
+        // nothing left to do
+        let (danglers, deleted, ended_early) = write.trim_collection(&collection, 3, false)?;
+        assert_eq!((danglers, deleted, ended_early), (0, 0, false));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_popularity_decays() {
+        let popularity = ReadPopularity::default();
+        let nsid = Nsid::new("a.a.a".to_string()).unwrap();
+        let t0 = Instant::now();
+        for _ in 0..3 {
+            popularity.record_read_at(&nsid, t0);
+        }
+        assert!(popularity.is_popular_at(&nsid, t0));
+        assert!(!popularity.is_popular_at(&nsid, t0 + POPULARITY_HALF_LIFE));
+        assert!(!popularity.is_popular_at(&Nsid::new("a.a.b".to_string()).unwrap(), t0));
+
+        assert_eq!(popularity.prune(t0 + POPULARITY_HALF_LIFE), 1);
+        assert_eq!(popularity.prune(t0 + POPULARITY_HALF_LIFE * 10), 0);
+    }
+
+    #[test]
+    fn test_popular_collections_trimmed_less() -> anyhow::Result<()> {
+        let (read, write) = fjall_db();
+        let popular = Nsid::new("a.a.a".to_string()).unwrap();
+        let ignored = Nsid::new("a.a.b".to_string()).unwrap();
+        for _ in 0..3 {
+            read.get_records_by_collections([popular.clone()].into(), 1, false, BUDGET)?;
+        }
+        let now = Instant::now();
+
+        // off by default
+        assert_eq!(write.trim_limit_at(&popular, now), TRIM_KEEP_RECORDS);
+
+        let write = FjallWriter {
+            popular_trim_multiplier: 4,
+            ..write
+        };
+        assert_eq!(write.trim_limit_at(&popular, now), TRIM_KEEP_RECORDS * 4);
+        assert_eq!(write.trim_limit_at(&ignored, now), TRIM_KEEP_RECORDS);
+        Ok(())
+    }
+
     #[test]
     fn test_delete_account() -> anyhow::Result<()> {
         let (read, mut write) = fjall_db();
@@ -2342,10 +8603,11 @@ mod tests {
         }
         write.insert_batch(batch.batch)?;
 
-        let records = read.get_records_by_collections(
+        let (records, _, _) = read.get_records_by_collections(
             HashSet::from([Nsid::new("a.a.a".to_string()).unwrap()]),
             100,
             false,
+            BUDGET,
         )?;
         assert_eq!(records.len(), 3);
 
@@ -2353,16 +8615,116 @@ mod tests {
             write.delete_account(&Did::new("did:plc:person-b".to_string()).unwrap())?;
         assert_eq!(records_deleted, 2);
 
-        let records = read.get_records_by_collections(
+        let (records, _, _) = read.get_records_by_collections(
             HashSet::from([Nsid::new("a.a.a".to_string()).unwrap()]),
             100,
             false,
+            BUDGET,
         )?;
         assert_eq!(records.len(), 1);
 
         Ok(())
     }
 
+    #[test]
+    fn test_delete_account_resumes_from_checkpoint() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
+
+        let mut batch = TestBatch::default();
+        for i in 1..=3 {
+            batch.create(
+                "did:plc:person-b",
+                "a.a.a",
+                &format!("rkey-bbb-{i}"),
+                "{}",
+                Some(&format!("rev-bbb-{i}")),
+                None,
+                11_000 + i,
+            );
+        }
+        write.insert_batch(batch.batch)?;
+
+        // an earlier run removed the first record and checkpointed it in the
+        // same batch, then got interrupted
+        let did = Did::new("did:plc:person-b".to_string()).unwrap();
+        let prefix = RecordLocationKey::from_prefix_to_db_bytes(&did)?;
+        let (first_key, _) = write.records.prefix(&prefix).next().unwrap()?;
+        let mut batch = write.keyspace.batch();
+        batch.remove(&write.records, first_key.clone());
+        batch.insert(
+            &write.global,
+            DeleteAccountProgressKey::new(did.clone()).to_db_bytes()?,
+            DeleteAccountProgressVal {
+                records_deleted: 1,
+                last_key: first_key.to_vec(),
+            }
+            .to_db_bytes()?,
+        );
+        batch.commit()?;
+        let status = write.account_deletes_status()?;
+        assert_eq!(status.in_progress.len(), 1);
+        assert_eq!(status.in_progress[0].did, "did:plc:person-b");
+        assert_eq!(status.in_progress[0].records_deleted, 1);
+
+        // the resumed delete only had the rest left to remove
+        assert_eq!(write.delete_account(&did)?, 2);
+        let (records, _, _) = read.get_records_by_collections(
+            HashSet::from([Nsid::new("a.a.a".to_string()).unwrap()]),
+            100,
+            false,
+            BUDGET,
+        )?;
+        assert!(records.is_empty());
+        assert!(write.records.prefix(&prefix).next().is_none());
+        assert!(write.account_deletes_status()?.in_progress.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_account_ignores_foreign_checkpoint() -> anyhow::Result<()> {
+        let (_read, mut write) = fjall_db();
+
+        let mut batch = TestBatch::default();
+        for did in ["did:plc:person-b", "did:plc:person-c"] {
+            batch.create(
+                did,
+                "a.a.a",
+                "rkey-aaa",
+                "{}",
+                Some("rev-aaa"),
+                None,
+                11_000,
+            );
+        }
+        write.insert_batch(batch.batch)?;
+
+        // a checkpoint for person-b pointing into person-c's records, which
+        // would skip all of person-b's
+        let other = Did::new("did:plc:person-c".to_string()).unwrap();
+        let (other_key, _) = write
+            .records
+            .prefix(RecordLocationKey::from_prefix_to_db_bytes(&other)?)
+            .next()
+            .unwrap()?;
+        let did = Did::new("did:plc:person-b".to_string()).unwrap();
+        write.global.insert(
+            DeleteAccountProgressKey::new(did.clone()).to_db_bytes()?,
+            DeleteAccountProgressVal {
+                records_deleted: 5,
+                last_key: other_key.to_vec(),
+            }
+            .to_db_bytes()?,
+        )?;
+
+        assert_eq!(write.delete_account(&did)?, 1);
+        let prefix = RecordLocationKey::from_prefix_to_db_bytes(&did)?;
+        assert!(write.records.prefix(&prefix).next().is_none());
+        assert!(write.records.contains_key(&other_key)?);
+
+        Ok(())
+    }
+
     #[test]
     fn rollup_delete_account_removes_record() -> anyhow::Result<()> {
         let (read, mut write) = fjall_db();
@@ -2385,10 +8747,64 @@ mod tests {
 
         write.step_rollup()?;
 
-        let records = read.get_records_by_collections(
+        let (records, _, _) = read.get_records_by_collections(
             [Nsid::new("a.a.a".to_string()).unwrap()].into(),
             1,
             false,
+            BUDGET,
+        )?;
+        assert_eq!(records.len(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn rollup_delete_accounts_batched_up_to_next_live_count() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
+
+        let mut batch = TestBatch::default();
+        for (i, did) in ["did:plc:person-a", "did:plc:person-b", "did:plc:person-c"]
+            .into_iter()
+            .enumerate()
+        {
+            batch.create(
+                did,
+                "a.a.a",
+                "rkey-aaa",
+                "{}",
+                Some("rev-aaa"),
+                None,
+                10_000 + i as u64,
+            );
+        }
+        write.insert_batch(batch.batch)?;
+
+        let mut batch = TestBatch::default();
+        batch.delete_account("did:plc:person-a", 9_997);
+        batch.delete_account("did:plc:person-b", 9_998);
+        batch.delete_account("did:plc:person-c", 10_100); // after the live counts
+        write.insert_batch(batch.batch)?;
+
+        // both deletes queued before the first live count go in one step
+        let (n, _) = write.step_rollup()?;
+        assert_eq!(n, 2);
+        let (records, _, _) = read.get_records_by_collections(
+            [Nsid::new("a.a.a".to_string()).unwrap()].into(),
+            3,
+            false,
+            BUDGET,
+        )?;
+        assert_eq!(records.len(), 1);
+
+        let (n, _) = write.step_rollup()?; // live counts
+        assert_eq!(n, 1);
+        let (n, _) = write.step_rollup()?;
+        assert_eq!(n, 1);
+        let (records, _, _) = read.get_records_by_collections(
+            [Nsid::new("a.a.a".to_string()).unwrap()].into(),
+            3,
+            false,
+            BUDGET,
         )?;
         assert_eq!(records.len(), 0);
 
@@ -2418,20 +8834,22 @@ mod tests {
         batch.delete_account("did:plc:person-a", 10_001);
         write.insert_batch(batch.batch)?;
 
-        let records = read.get_records_by_collections(
+        let (records, _, _) = read.get_records_by_collections(
             [Nsid::new("a.a.a".to_string()).unwrap()].into(),
             1,
             false,
+            BUDGET,
         )?;
         assert_eq!(records.len(), 1);
 
         let (n, _) = write.step_rollup()?;
         assert_eq!(n, 1);
 
-        let records = read.get_records_by_collections(
+        let (records, _, _) = read.get_records_by_collections(
             [Nsid::new("a.a.a".to_string()).unwrap()].into(),
             1,
             false,
+            BUDGET,
         )?;
         assert_eq!(records.len(), 0);
 
@@ -2609,6 +9027,7 @@ mod tests {
                 OrderCollectionsBy::Lexi { cursor: None },
                 None,
                 None,
+                BUDGET,
             )
             .unwrap();
 
@@ -2649,6 +9068,7 @@ mod tests {
             OrderCollectionsBy::Lexi { cursor: None },
             None,
             None,
+            BUDGET,
         )?;
         assert_eq!(creates, 0);
         assert_eq!(dids_estimate, 0);
@@ -2688,6 +9108,7 @@ mod tests {
             OrderCollectionsBy::Lexi { cursor: None },
             None,
             None,
+            BUDGET,
         )?;
         assert_eq!(creates, 0);
         assert_eq!(dids_estimate, 0);
@@ -2712,6 +9133,10 @@ mod tests {
         );
         write.insert_batch(batch.batch)?;
         write.step_rollup()?;
+        let ConsumerInfo::Jetstream {
+            started_at: takeoff,
+            ..
+        } = read.get_consumer_info()?;
 
         let (
             JustCount {
@@ -2727,6 +9152,7 @@ mod tests {
             OrderCollectionsBy::Lexi { cursor: None },
             None,
             None,
+            BUDGET,
         )?;
         assert_eq!(creates, 1);
         assert_eq!(dids_estimate, 1);
@@ -2737,7 +9163,8 @@ mod tests {
                 creates: 1,
                 updates: 0,
                 deletes: 0,
-                dids_estimate: 1
+                dids_estimate: 1,
+                tracked_since: takeoff,
             }),]
         );
         assert_eq!(cursor, None);
@@ -2775,6 +9202,7 @@ mod tests {
             OrderCollectionsBy::Lexi { cursor: None },
             None,
             None,
+            BUDGET,
         )?;
         assert_eq!(creates, 1);
         assert_eq!(dids_estimate, 1);
@@ -2832,6 +9260,7 @@ mod tests {
             OrderCollectionsBy::Lexi { cursor: None },
             None,
             None,
+            BUDGET,
         )?;
         assert_eq!(creates, 2);
         assert_eq!(dids_estimate, 1);
@@ -2886,6 +9315,10 @@ mod tests {
         );
         write.insert_batch(batch.batch)?;
         write.step_rollup()?;
+        let ConsumerInfo::Jetstream {
+            started_at: takeoff,
+            ..
+        } = read.get_consumer_info()?;
 
         let (
             JustCount {
@@ -2901,6 +9334,7 @@ mod tests {
             OrderCollectionsBy::Lexi { cursor: None },
             None,
             None,
+            BUDGET,
         )?;
         assert_eq!(creates, 2);
         assert_eq!(dids_estimate, 1);
@@ -2912,7 +9346,8 @@ mod tests {
                     creates: 1,
                     updates: 0,
                     deletes: 0,
-                    dids_estimate: 1
+                    dids_estimate: 1,
+                    tracked_since: takeoff,
                 }),
                 PrefixChild::Prefix(PrefixCount {
                     prefix: "a.a.a.a".to_string(),
@@ -2926,4 +9361,90 @@ mod tests {
         assert_eq!(cursor, None);
         Ok(())
     }
+
+    #[test]
+    fn get_prefix_ordered_over_hours() -> anyhow::Result<()> {
+        let (read, mut write) = fjall_db();
+
+        // one batch per hour, and z.z.z moves the rollup into hour 12
+        for (hour, creates) in [
+            (10, vec![("a.a.x", 4), ("a.a.b.c", 1)]),
+            (11, vec![("a.a.b.c", 2), ("a.a.y", 1)]),
+            (12, vec![("z.z.z", 1)]),
+        ] {
+            let mut batch = TestBatch::default();
+            for (collection, n) in creates {
+                for i in 0..n {
+                    batch.create(
+                        &format!("did:plc:person-{i}"),
+                        collection,
+                        &format!("rkey-{hour}-{i}"),
+                        "{}",
+                        None,
+                        None,
+                        hour * HOUR_IN_MICROS + i,
+                    );
+                }
+            }
+            write.insert_batch(batch.batch)?;
+        }
+        while write.step_rollup()?.0 > 0 {}
+
+        let names = |children: &[PrefixChild]| -> Vec<(String, u64)> {
+            children
+                .iter()
+                .map(|c| match c {
+                    PrefixChild::Collection(c) => (c.nsid.clone(), c.creates),
+                    PrefixChild::Prefix(p) => (p.prefix.clone(), p.creates),
+                })
+                .collect()
+        };
+
+        let (total, children, cursor) = read.get_prefix(
+            NsidPrefix::new("a.a").unwrap(),
+            2,
+            OrderCollectionsBy::RecordsCreated { cursor: None },
+            None,
+            None,
+            BUDGET,
+        )?;
+        assert_eq!(total.creates, 8);
+        assert_eq!(
+            names(&children),
+            vec![("a.a.x".to_string(), 4), ("a.a.b".to_string(), 3)]
+        );
+        assert_eq!(cursor, None);
+
+        let (total, children, cursor) = read.get_prefix(
+            NsidPrefix::new("a.a").unwrap(),
+            10,
+            OrderCollectionsBy::RecordsCreated { cursor: None },
+            Some(HourTruncatedCursor::truncate_raw_u64(11 * HOUR_IN_MICROS)),
+            Some(HourTruncatedCursor::truncate_raw_u64(12 * HOUR_IN_MICROS)),
+            BUDGET,
+        )?;
+        assert_eq!(total.creates, 3);
+        assert_eq!(
+            names(&children),
+            vec![("a.a.b".to_string(), 2), ("a.a.y".to_string(), 1)]
+        );
+        assert_eq!(cursor, None);
+
+        let (_, children, _) = read.get_prefix(
+            NsidPrefix::new("a.a").unwrap(),
+            10,
+            OrderCollectionsBy::DidsEstimate { cursor: None },
+            Some(HourTruncatedCursor::truncate_raw_u64(11 * HOUR_IN_MICROS)),
+            Some(HourTruncatedCursor::truncate_raw_u64(12 * HOUR_IN_MICROS)),
+            BUDGET,
+        )?;
+        assert_eq!(
+            children
+                .iter()
+                .map(|c| c.dids_estimate())
+                .collect::<Vec<_>>(),
+            vec![2, 1]
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,3284 @@
+//! RocksDB storage, for comparing compaction and disk usage against fjall
+//!
+//! Column families mirror fjall's partitions (global, feeds, records, rollups,
+//! queues, audit, spill) with exactly the same keys and values, so see the
+//! layout notes at the top of `storage_fjall`.
+//!
+//! This backend covers ingest, rollups, trimming, account deletes, and the
+//! read api. The optional extras that live in the fjall writer (redaction,
+//! transforms, watchlists, webhooks, the event bus, record diffs and versions,
+//! unique record counts, and the change feed) aren't implemented here, and
+//! neither are the purge, key space, and feed rebuild admin operations. Reads
+//! that depend on them return [`StorageError::Unsupported`], never an empty
+//! result.
+
+use crate::cache::DirtyTap;
+use crate::db_types::{
+    db_complete, DbBytes, DbConcat, DbStaticStr, EncodingResult, StaticStr, SubPrefixBytes,
+};
+use crate::denylist::{DenyRule, Denylist};
+use crate::error::{QueryError, StorageError};
+use crate::heavy_hitters::{NoisyDids, NoisyDidsReport};
+use crate::nsid_limits::NsidLimits;
+use crate::plugin::{IngestPlugins, StoredBatch};
+use crate::publish::CountsDelta;
+use crate::server;
+use crate::spill::SpillQueue;
+use crate::storage::{
+    sample_by_probes, CountPrefix, QueryResult, ScanBudget, StorageResult, StorageWhatever,
+    StoreAdmin, StoreBackground, StoreReader, StoreWriter,
+};
+use crate::storage_fjall::{
+    decode_record, rollup_interval, trim_interval, BackgroundSchedule, Randomness, ReadPopularity,
+    ScheduleRunning, MAX_BATCHED_ACCOUNT_DELETES, MAX_BATCHED_ACCOUNT_DELETE_RECORDS,
+    MAX_BATCHED_ROLLUP_COUNTS, MAX_BATCHED_TRIM_ITEMS, MAX_PINNED_SNAPSHOTS,
+    MAX_PINNED_SNAPSHOT_TTL, MAX_ROLLUP_DELETE_RECORDS, MAX_TRIM_RANGE_ITEMS, TRIM_KEEP_RECORDS,
+};
+use crate::store_types::{
+    AllTimeDidsKey, AllTimeRecordsKey, AllTimeRollupKey, CollectionFirstSeenKey,
+    CollectionFirstSeenVal, CommitCounts, CountsValue, CursorBucket, CursorGapKey, CursorGapValue,
+    DeleteAccountProgressKey, DeleteAccountProgressVal, DeleteAccountQueueKey,
+    DeleteAccountQueueVal, DenylistKey, DenylistVal, EventKindsKey, EventKindsVal,
+    HourTruncatedCursor, HourWindow, HourlyDidsKey, HourlyRecordsKey, HourlyRollupKey,
+    HourlyRollupStaticPrefix, JetstreamCursorKey, JetstreamCursorValue, JetstreamEndpointKey,
+    JetstreamEndpointValue, JetstreamShardCursorKey, LiveCountsKey, NewRollupCursorKey,
+    NewRollupCursorValue, NsidRecordFeedKey, NsidRecordFeedVal, OverflowedCollectionsKey,
+    OverflowedCollectionsVal, RankCursor, RecordLocationKey, RecordLocationMeta, RecordLocationVal,
+    SketchSecretKey, SketchSecretPrefix, TakeoffKey, TakeoffValue, TrimCollectionCursorKey,
+    WeekTruncatedCursor, WeeklyDidsKey, WeeklyRecordsKey, WeeklyRollupKey, WithCollection,
+    WithRank, HOUR_IN_MICROS, WEEK_IN_MICROS,
+};
+use crate::{
+    nice_duration, AccountDeleteProgress, AccountDeletesStatus, AuditEntry, BackgroundIntervals,
+    BackgroundStatus, BackgroundTask, CollectionGrowth, CommitAction, ConsumerInfo, Did,
+    EncodingError, EventBatch, EventKindCounts, GrowthPeriod, GrowthRanking, JetstreamShard,
+    JustCount, KeySpaceReport, Nsid, NsidCount, NsidPrefix, OrderCollectionsBy, PrefixChild,
+    PrefixCount, PurgeReport, RebuildFeedsReport, RecordKey, Summary, UFOsRecord, UniqueRecords,
+    WatchlistHit, WindowCounts,
+};
+use async_trait::async_trait;
+use jetstream::events::Cursor;
+use metrics::{counter, describe_gauge, gauge, histogram, Unit};
+use rocksdb::{
+    BlockBasedOptions, BoundColumnFamily, Cache, ColumnFamilyDescriptor, DBCompressionType,
+    DBWithThreadMode, IteratorMode, MultiThreaded, Options, PrefixRange, Range, ReadOptions,
+    SnapshotWithThreadMode, WriteBatch,
+};
+use std::collections::{HashMap, HashSet};
+use std::iter::Peekable;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc, Mutex, RwLock,
+};
+use std::time::{Duration, Instant, SystemTime};
+
+const GLOBAL: &str = "global";
+const FEEDS: &str = "feeds";
+const RECORDS: &str = "records";
+const ROLLUPS: &str = "rollups";
+const QUEUES: &str = "queues";
+const AUDIT: &str = "audit";
+const SPILL: &str = "spill";
+const COLUMN_FAMILIES: [&str; 7] = [GLOBAL, FEEDS, RECORDS, ROLLUPS, QUEUES, AUDIT, SPILL];
+
+type RocksDb = DBWithThreadMode<MultiThreaded>;
+type RocksKV = StorageResult<(Box<[u8]>, Box<[u8]>)>;
+
+#[derive(Debug)]
+pub struct RocksStorage {}
+
+#[derive(Debug, Default)]
+pub struct RocksConfig {
+    /// report collections with new rolled-up counts, to drop their cache entries
+    pub dirty: Option<DirtyTap>,
+    /// use this secret for cardinality sketches instead of a random one
+    ///
+    /// must match the stored secret of an existing db.
+    pub sketch_secret: Option<SketchSecretPrefix>,
+    /// run background tasks this often instead of the defaults for (non-)backfill
+    pub background: Option<BackgroundIntervals>,
+    /// keep this many times more records when trimming collections that are read often
+    pub popular_trim_multiplier: Option<usize>,
+    /// memory for rocksdb's block cache, in bytes (rocksdb's default if unset)
+    pub cache_bytes: Option<u64>,
+    /// derive the sketch secret of a fresh db from this seed
+    pub seed: Option<u64>,
+    /// keep a separate cursor for each of these jetstream connections
+    pub jetstream_shards: Vec<JetstreamShard>,
+    /// keep pathologically deep or long NSIDs out of the keys and NSID tree
+    pub nsid_limits: NsidLimits,
+    /// run these on every batch before storing it, after the built-in plugins
+    pub plugins: IngestPlugins,
+}
+
+// lz4 like fjall, so that disk usage compares like for like
+fn rocks_opts(cache: Option<&Cache>) -> Options {
+    let mut opts = Options::default();
+    opts.create_if_missing(true);
+    opts.create_missing_column_families(true);
+    opts.set_level_compaction_dynamic_level_bytes(true);
+    opts.set_compression_type(DBCompressionType::Lz4);
+    if let Some(cache) = cache {
+        let mut block_opts = BlockBasedOptions::default();
+        block_opts.set_block_cache(cache);
+        opts.set_block_based_table_factory(&block_opts);
+    }
+    opts
+}
+
+impl StorageWhatever<RocksReader, RocksWriter, RocksBackground, RocksConfig> for RocksStorage {
+    fn init(
+        path: impl AsRef<Path>,
+        endpoint: String,
+        force_endpoint: bool,
+        config: RocksConfig,
+    ) -> StorageResult<(RocksReader, RocksWriter, Option<Cursor>, SketchSecretPrefix)> {
+        let cache = config
+            .cache_bytes
+            .map(|bytes| Cache::new_lru_cache(bytes as usize));
+        let cfs = COLUMN_FAMILIES
+            .iter()
+            .map(|name| ColumnFamilyDescriptor::new(*name, rocks_opts(cache.as_ref())));
+        let db = RocksDb::open_cf_descriptors(&rocks_opts(cache.as_ref()), path, cfs)?;
+        let rocks = Rocks {
+            db: Arc::new(db),
+            pinned: None,
+        };
+
+        let js_cursor = rocks.get_static::<JetstreamCursorKey, JetstreamCursorValue>()?;
+
+        let sketch_secret = if js_cursor.is_some() {
+            let JetstreamEndpointValue(stored) = rocks
+                .get_static::<JetstreamEndpointKey, JetstreamEndpointValue>()?
+                .ok_or(StorageError::InitError(
+                    "found cursor but missing js_endpoint, refusing to start.".to_string(),
+                ))?;
+
+            let Some(stored_secret) = rocks.get_static::<SketchSecretKey, SketchSecretPrefix>()?
+            else {
+                return Err(StorageError::InitError(
+                    "found cursor but missing sketch_secret, refusing to start.".to_string(),
+                ));
+            };
+
+            if config.sketch_secret.is_some_and(|s| s != stored_secret) {
+                return Err(StorageError::InitError(
+                    "stored sketch_secret differs from the provided one, refusing to start."
+                        .to_string(),
+                ));
+            }
+
+            if stored != endpoint {
+                if force_endpoint {
+                    log::warn!("forcing a jetstream switch from {stored:?} to {endpoint:?}");
+                    rocks.put_static::<JetstreamEndpointKey>(JetstreamEndpointValue(
+                        endpoint.to_string(),
+                    ))?;
+                } else {
+                    return Err(StorageError::InitError(format!(
+                        "stored js_endpoint {stored:?} differs from provided {endpoint:?}, refusing to start without --jetstream-force.")));
+                }
+            }
+            stored_secret
+        } else {
+            log::info!("initializing a fresh rocksdb db!");
+            rocks.init_static::<JetstreamEndpointKey>(JetstreamEndpointValue(
+                endpoint.to_string(),
+            ))?;
+
+            let sketch_secret = match config.sketch_secret {
+                Some(secret) => secret,
+                None => {
+                    log::info!("generating new secret for cardinality sketches...");
+                    let mut sketch_secret: SketchSecretPrefix = [0u8; 16];
+                    Randomness::new(config.seed)
+                        .fill(&mut sketch_secret)
+                        .map_err(|e| {
+                            StorageError::InitError(format!(
+                                "failed to get a random secret for cardinality sketches: {e:?}"
+                            ))
+                        })?;
+                    sketch_secret
+                }
+            };
+            rocks.init_static::<SketchSecretKey>(sketch_secret)?;
+
+            rocks.init_static::<TakeoffKey>(Cursor::at(SystemTime::now()))?;
+            rocks.init_static::<NewRollupCursorKey>(Cursor::from_start())?;
+
+            sketch_secret
+        };
+
+        let popularity = ReadPopularity::default();
+
+        let reader = RocksReader {
+            rocks: rocks.clone(),
+            pins: Default::default(),
+            popularity: popularity.clone(),
+        };
+        reader.describe_metrics();
+
+        let mut deny_rules = Vec::new();
+        for kv in rocks.prefix(
+            GLOBAL,
+            DenylistKey::from_prefix_to_db_bytes(&Default::default())?,
+        ) {
+            let (key_bytes, val_bytes) = kv?;
+            let key = db_complete::<DenylistKey>(&key_bytes)?;
+            let val = db_complete::<DenylistVal>(&val_bytes)?;
+            deny_rules.push(DenyRule {
+                pattern: key.pattern().to_string(),
+                keep_counts: val.keep_counts,
+            });
+        }
+        if !deny_rules.is_empty() {
+            log::info!("loaded {} collection deny rules", deny_rules.len());
+        }
+
+        let noisy = NoisyDids::default();
+        let writer = RocksWriter {
+            bg_taken: Arc::new(AtomicBool::new(false)),
+            schedule: Arc::new(BackgroundSchedule::new(config.background)),
+            denylist: Arc::new(RwLock::new(Denylist::new(deny_rules))),
+            dirty: config.dirty,
+            popularity,
+            popular_trim_multiplier: config.popular_trim_multiplier.unwrap_or(1).max(1),
+            noisy: noisy.clone(),
+            plugins: config.plugins.after_builtins(vec![Arc::new(noisy)]),
+            nsid_limits: config.nsid_limits,
+            shards: Arc::new(
+                config
+                    .jetstream_shards
+                    .iter()
+                    .flat_map(|shard| {
+                        let id = shard.id();
+                        shard
+                            .collections()
+                            .iter()
+                            .map(move |c| (c.clone(), id.clone()))
+                    })
+                    .collect(),
+            ),
+            spill: RocksSpill::open(rocks.clone())?,
+            audit_lock: Default::default(),
+            rocks,
+        };
+        Ok((reader, writer, js_cursor, sketch_secret))
+    }
+}
+
+/// A rocksdb snapshot that isn't tied to the lifetime of a borrow of the db
+///
+/// It holds its own Arc of the db, and fields drop in declaration order, so
+/// the snapshot is always released before the db could be closed.
+struct PinnedSnapshot {
+    snapshot: SnapshotWithThreadMode<'static, RocksDb>,
+    _db: Arc<RocksDb>,
+}
+impl PinnedSnapshot {
+    fn new(db: &Arc<RocksDb>) -> Self {
+        let db = db.clone();
+        let snapshot = db.snapshot();
+        // SAFETY: the db is heap-allocated behind the Arc we keep alongside, which outlives the snapshot
+        let snapshot = unsafe {
+            std::mem::transmute::<
+                SnapshotWithThreadMode<'_, RocksDb>,
+                SnapshotWithThreadMode<'static, RocksDb>,
+            >(snapshot)
+        };
+        Self { snapshot, _db: db }
+    }
+}
+
+type PinnedSnapshots = Arc<Mutex<HashMap<u64, (Instant, Arc<PinnedSnapshot>)>>>;
+
+/// The db, and the snapshot to read from if one is pinned
+#[derive(Clone)]
+struct Rocks {
+    db: Arc<RocksDb>,
+    pinned: Option<Arc<PinnedSnapshot>>,
+}
+
+/// The smallest key after `key`
+fn next_key(key: &[u8]) -> Vec<u8> {
+    let mut next = key.to_vec();
+    next.push(0);
+    next
+}
+
+impl Rocks {
+    fn cf(&self, name: &str) -> Arc<BoundColumnFamily<'_>> {
+        self.db
+            .cf_handle(name)
+            .expect("every column family is opened at init")
+    }
+
+    fn read_opts(&self) -> ReadOptions {
+        let mut read_opts = ReadOptions::default();
+        if let Some(pinned) = &self.pinned {
+            read_opts.set_snapshot(&pinned.snapshot);
+        }
+        read_opts
+    }
+
+    fn get(&self, cf: &str, key: impl AsRef<[u8]>) -> StorageResult<Option<Vec<u8>>> {
+        Ok(self.db.get_cf_opt(&self.cf(cf), key, &self.read_opts())?)
+    }
+
+    fn scan(
+        &self,
+        cf: &str,
+        range: impl RangeBounds<Vec<u8>>,
+        mode: IteratorMode,
+    ) -> Box<dyn Iterator<Item = RocksKV> + '_> {
+        let mut read_opts = self.read_opts();
+        match range.start_bound() {
+            Bound::Included(start) => read_opts.set_iterate_lower_bound(start.clone()),
+            Bound::Excluded(start) => read_opts.set_iterate_lower_bound(next_key(start)),
+            Bound::Unbounded => {}
+        }
+        match range.end_bound() {
+            Bound::Included(end) => read_opts.set_iterate_upper_bound(next_key(end)),
+            Bound::Excluded(end) => read_opts.set_iterate_upper_bound(end.clone()),
+            Bound::Unbounded => {}
+        }
+        Box::new(
+            self.db
+                .iterator_cf_opt(&self.cf(cf), read_opts, mode)
+                .map(|kv| kv.map_err(StorageError::from)),
+        )
+    }
+
+    fn range(
+        &self,
+        cf: &str,
+        range: impl RangeBounds<Vec<u8>>,
+    ) -> Box<dyn Iterator<Item = RocksKV> + '_> {
+        self.scan(cf, range, IteratorMode::Start)
+    }
+
+    fn range_rev(
+        &self,
+        cf: &str,
+        range: impl RangeBounds<Vec<u8>>,
+    ) -> Box<dyn Iterator<Item = RocksKV> + '_> {
+        self.scan(cf, range, IteratorMode::End)
+    }
+
+    fn prefix(&self, cf: &str, prefix: Vec<u8>) -> Box<dyn Iterator<Item = RocksKV> + '_> {
+        let mut read_opts = self.read_opts();
+        read_opts.set_iterate_range(PrefixRange(prefix));
+        Box::new(
+            self.db
+                .iterator_cf_opt(&self.cf(cf), read_opts, IteratorMode::Start)
+                .map(|kv| kv.map_err(StorageError::from)),
+        )
+    }
+
+    /// Get a value from a fixed key
+    fn get_static<K: StaticStr, V: DbBytes>(&self) -> StorageResult<Option<V>> {
+        let key_bytes = DbStaticStr::<K>::default().to_db_bytes()?;
+        let value = self
+            .get(GLOBAL, key_bytes)?
+            .map(|value_bytes| db_complete(&value_bytes))
+            .transpose()?;
+        Ok(value)
+    }
+
+    /// Set a value to a fixed key
+    fn put_static<K: StaticStr>(&self, value: impl DbBytes) -> StorageResult<()> {
+        let key_bytes = DbStaticStr::<K>::default().to_db_bytes()?;
+        self.db
+            .put_cf(&self.cf(GLOBAL), key_bytes, value.to_db_bytes()?)?;
+        Ok(())
+    }
+
+    /// Set a value to a fixed key, erroring if the value already exists
+    ///
+    /// Only for single-threaded init, like fjall's.
+    fn init_static<K: StaticStr>(&self, value: impl DbBytes) -> StorageResult<()> {
+        let key_bytes = DbStaticStr::<K>::default().to_db_bytes()?;
+        if self.get(GLOBAL, &key_bytes)?.is_some() {
+            return Err(StorageError::InitError(format!(
+                "init failed: value for key {key_bytes:?} already exists"
+            )));
+        }
+        self.put_static::<K>(value)
+    }
+
+    /// Set a value to a fixed key in a write batch
+    fn batch_static<K: StaticStr>(
+        &self,
+        batch: &mut WriteBatch,
+        value: impl DbBytes,
+    ) -> StorageResult<()> {
+        let key_bytes = DbStaticStr::<K>::default().to_db_bytes()?;
+        batch.put_cf(&self.cf(GLOBAL), key_bytes, value.to_db_bytes()?);
+        Ok(())
+    }
+
+    fn property(&self, cf: &str, name: &str) -> Option<u64> {
+        self.db
+            .property_int_value_cf(&self.cf(cf), name)
+            .ok()
+            .flatten()
+    }
+
+    /// Estimate the keys in a range from its share of the column family's files
+    ///
+    /// Only flushed keys are counted: `None` until something is on disk.
+    fn approximate_range_len(&self, cf: &str, start: &[u8], end: &[u8]) -> Option<u64> {
+        let keys = self.property(cf, "rocksdb.estimate-num-keys")?;
+        let bytes = self.property(cf, "rocksdb.total-sst-files-size")?;
+        if bytes == 0 {
+            return None;
+        }
+        let range_bytes = self
+            .db
+            .get_approximate_sizes_cf(&self.cf(cf), &[Range::new(start, end)])
+            .first()
+            .copied()
+            .unwrap_or(0);
+        Some((keys as u128 * range_bytes as u128 / bytes as u128) as u64)
+    }
+}
+
+#[derive(Clone)]
+pub struct RocksReader {
+    rocks: Rocks,
+    /// all currently-pinned snapshots, shared across reader clones
+    pins: PinnedSnapshots,
+    /// recent record reads per collection, shared with the writer for trimming
+    popularity: ReadPopularity,
+}
+
+/// Like fjall's, an iterator that knows how to skip over deleted/invalidated records
+///
+/// Entries that fail to decode are skipped too, and counted in `skipped_corrupt`.
+struct RecordIterator<'a> {
+    rocks: &'a Rocks,
+    db_iter: Box<dyn Iterator<Item = RocksKV> + 'a>,
+    limit: usize,
+    fetched: usize,
+    skipped_corrupt: Rc<Cell<usize>>,
+    /// shared by every iterator of a request: one key per feed entry
+    budget: Rc<Cell<ScanBudget>>,
+}
+impl<'a> RecordIterator<'a> {
+    fn new(
+        rocks: &'a Rocks,
+        collection: &Nsid,
+        limit: usize,
+        skipped_corrupt: Rc<Cell<usize>>,
+        budget: Rc<Cell<ScanBudget>>,
+    ) -> StorageResult<Self> {
+        let start = NsidRecordFeedKey::from_prefix_to_db_bytes(collection)?;
+        let end = NsidRecordFeedKey::prefix_range_end(collection)?;
+        Ok(Self {
+            rocks,
+            db_iter: rocks.range_rev(FEEDS, start..end),
+            limit,
+            fetched: 0,
+            skipped_corrupt,
+            budget,
+        })
+    }
+    /// Oldest-first from a cursor, for finding the first live record after it
+    fn starting_at(
+        rocks: &'a Rocks,
+        collection: &Nsid,
+        cursor: Cursor,
+        limit: usize,
+        skipped_corrupt: Rc<Cell<usize>>,
+        budget: Rc<Cell<ScanBudget>>,
+    ) -> StorageResult<Self> {
+        let range =
+            NsidRecordFeedKey::from_pair(collection.clone(), cursor).range_to_prefix_end()?;
+        Ok(Self {
+            rocks,
+            db_iter: rocks.range(FEEDS, range),
+            limit,
+            fetched: 0,
+            skipped_corrupt,
+            budget,
+        })
+    }
+    fn get_record(&self, db_next: RocksKV) -> StorageResult<Option<UFOsRecord>> {
+        let (key_bytes, val_bytes) = db_next?;
+        let feed_key = db_complete::<NsidRecordFeedKey>(&key_bytes)?;
+        let feed_val = db_complete::<NsidRecordFeedVal>(&val_bytes)?;
+        let location_key: RecordLocationKey = (&feed_key, &feed_val).into();
+
+        let Some(location_val_bytes) = self.rocks.get(RECORDS, location_key.to_db_bytes()?)? else {
+            // record was deleted (hopefully)
+            return Ok(None);
+        };
+
+        let (meta, n) = RecordLocationMeta::from_db_bytes(&location_val_bytes)?;
+
+        if meta.cursor() != feed_key.cursor() {
+            // older/different version
+            return Ok(None);
+        }
+        if meta.rev != feed_val.rev() {
+            log::warn!("record lookup: cursor match but rev did not...? excluding.");
+            return Ok(None);
+        }
+        Ok(Some(decode_record(
+            &location_key,
+            meta,
+            &location_val_bytes[n..],
+        )?))
+    }
+}
+impl Iterator for RecordIterator<'_> {
+    type Item = StorageResult<Option<UFOsRecord>>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.fetched == self.limit {
+            return Some(Ok(None));
+        }
+        let record = loop {
+            let db_next = self.db_iter.next()?; // None short-circuits here
+            let mut budget = self.budget.get();
+            let spent = budget.spend();
+            self.budget.set(budget);
+            if !spent {
+                return Some(Ok(None)); // out of budget: done, like at the limit
+            }
+            match self.get_record(db_next) {
+                Err(StorageError::EncodingError(e)) => {
+                    log::warn!("record lookup: skipping an entry that failed to decode: {e}");
+                    counter!("storage_records_skipped_corrupt").increment(1);
+                    self.skipped_corrupt.set(self.skipped_corrupt.get() + 1);
+                    continue;
+                }
+                Err(e) => return Some(Err(e)),
+                Ok(Some(record)) => break record,
+                Ok(None) => continue,
+            }
+        };
+        self.fetched += 1;
+        Some(Ok(Some(record)))
+    }
+}
+
+type GetCounts<'a> = Box<dyn FnOnce() -> StorageResult<CountsValue> + 'a>;
+type GetByterCounts<'a> = StorageResult<(Nsid, GetCounts<'a>)>;
+type NsidCounter<'a> = Box<dyn Iterator<Item = GetByterCounts<'a>> + 'a>;
+type GetRollupKey = Arc<dyn Fn(&Nsid) -> EncodingResult<Vec<u8>>>;
+type CollectionSerieses = HashMap<Nsid, Vec<CountsValue>>;
+
+impl RocksReader {
+    fn describe_metrics(&self) {
+        describe_gauge!(
+            "storage_rocks_sst_bytes",
+            Unit::Bytes,
+            "total size of a column family's sst files"
+        );
+        describe_gauge!(
+            "storage_rocks_pending_compaction_bytes",
+            Unit::Bytes,
+            "rocksdb's estimate of the bytes compaction still has to rewrite, per column family"
+        );
+    }
+
+    fn lexi_iter<'a, T: WithCollection + DbBytes + 'static>(
+        &'a self,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+    ) -> NsidCounter<'a> {
+        Box::new(
+            self.rocks
+                .range(ROLLUPS, (start, end))
+                .map(|kv| -> GetByterCounts<'a> {
+                    let (k_bytes, v_bytes) = kv?;
+                    let key = db_complete::<T>(&k_bytes)?;
+                    let nsid = key.collection().clone();
+                    let get_counts: GetCounts<'a> =
+                        Box::new(move || Ok(db_complete::<CountsValue>(&v_bytes)?));
+                    Ok((nsid, get_counts))
+                }),
+        )
+    }
+
+    fn lookup_iter<'a, T: WithCollection + WithRank + DbBytes + 'static>(
+        &'a self,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        get_rollup_key: GetRollupKey,
+    ) -> NsidCounter<'a> {
+        let rocks = &self.rocks;
+        Box::new(
+            rocks
+                .range_rev(ROLLUPS, (start, end))
+                .map(move |kv| -> GetByterCounts<'a> {
+                    let (k_bytes, _) = kv?;
+                    let key = db_complete::<T>(&k_bytes)?;
+                    let nsid = key.collection().clone();
+                    let get_counts: GetCounts<'a> = Box::new({
+                        let nsid = nsid.clone();
+                        let get_rollup_key = get_rollup_key.clone();
+                        move || {
+                            let db_count_bytes =
+                                rocks.get(ROLLUPS, get_rollup_key(&nsid)?)?.expect(
+                                    "integrity: rank rollup must have corresponding count rollup",
+                                );
+                            Ok(db_complete::<CountsValue>(&db_count_bytes)?)
+                        }
+                    });
+                    Ok((nsid, get_counts))
+                }),
+        )
+    }
+
+    fn sum_rollups(
+        &self,
+        collection: &Nsid,
+        buckets: Vec<CursorBucket>,
+    ) -> StorageResult<CountsValue> {
+        let mut total_counts = CountsValue::default();
+        for bucket in buckets {
+            let key = match bucket {
+                CursorBucket::Hour(t) => HourlyRollupKey::new(t, collection).to_db_bytes()?,
+                CursorBucket::Week(t) => WeeklyRollupKey::new(t, collection).to_db_bytes()?,
+                CursorBucket::AllTime => unreachable!(),
+            };
+            let count = self
+                .rocks
+                .get(ROLLUPS, key)?
+                .as_deref()
+                .map(db_complete::<CountsValue>)
+                .transpose()?
+                .unwrap_or_default();
+            total_counts.merge(&count);
+        }
+        Ok(total_counts)
+    }
+
+    /// Lookup for when counting started for each collection
+    ///
+    /// The later of takeoff and the collection's first-seen time.
+    fn tracked_since(&self) -> StorageResult<impl Fn(&Nsid) -> StorageResult<Cursor> + '_> {
+        let takeoff = self.rocks.get_static::<TakeoffKey, TakeoffValue>()?.ok_or(
+            StorageError::BadStateError("Could not find jetstream takeoff time".to_string()),
+        )?;
+        Ok(move |nsid: &Nsid| {
+            let first_seen = self
+                .rocks
+                .get(ROLLUPS, CollectionFirstSeenKey::new(nsid).to_db_bytes()?)?
+                .as_deref()
+                .map(db_complete::<CollectionFirstSeenVal>)
+                .transpose()?;
+            Ok(match first_seen {
+                Some(seen) if seen.to_raw_u64() > takeoff.to_raw_u64() => seen,
+                _ => takeoff,
+            })
+        })
+    }
+
+    /// Pin the db's current sequence number for consistent reads across calls
+    fn pin_snapshot(&self, ttl: Duration) -> QueryResult<(u64, SystemTime)> {
+        let ttl = ttl.min(MAX_PINNED_SNAPSHOT_TTL);
+        let now = Instant::now();
+        let mut pins = self.pins.lock().unwrap();
+        pins.retain(|_, (expires_at, _)| *expires_at > now);
+        if pins.len() >= MAX_PINNED_SNAPSHOTS {
+            return Err(QueryError::TooManySnapshots(MAX_PINNED_SNAPSHOTS));
+        }
+        let sequence = self.rocks.db.latest_sequence_number();
+        let (expires_at, _) = pins
+            .entry(sequence)
+            .or_insert_with(|| (now, Arc::new(PinnedSnapshot::new(&self.rocks.db))));
+        // a re-used sequence number gets its expiry extended, never shortened
+        *expires_at = (*expires_at).max(now + ttl);
+        let expires_at = SystemTime::now() + expires_at.duration_since(now);
+        Ok((sequence, expires_at))
+    }
+
+    fn at_snapshot(&self, token: u64) -> QueryResult<RocksReader> {
+        let now = Instant::now();
+        let mut pins = self.pins.lock().unwrap();
+        pins.retain(|_, (expires_at, _)| *expires_at > now);
+        let (_, pinned) = pins
+            .get(&token)
+            .ok_or(QueryError::SnapshotNotFound(token))?;
+        Ok(RocksReader {
+            rocks: Rocks {
+                db: self.rocks.db.clone(),
+                pinned: Some(pinned.clone()),
+            },
+            ..self.clone()
+        })
+    }
+
+    fn get_storage_stats(&self) -> StorageResult<serde_json::Value> {
+        let rollup_cursor = self
+            .rocks
+            .get_static::<NewRollupCursorKey, NewRollupCursorValue>()?
+            .map(|c| c.to_raw_u64());
+        let mut column_families = serde_json::Map::new();
+        for cf in COLUMN_FAMILIES {
+            column_families.insert(
+                cf.to_string(),
+                serde_json::json!({
+                    "sst_bytes": self.rocks.property(cf, "rocksdb.total-sst-files-size"),
+                    "live_data_bytes": self.rocks.property(cf, "rocksdb.estimate-live-data-size"),
+                    "estimated_keys": self.rocks.property(cf, "rocksdb.estimate-num-keys"),
+                    "pending_compaction_bytes": self.rocks.property(cf, "rocksdb.estimate-pending-compaction-bytes"),
+                }),
+            );
+        }
+        Ok(serde_json::json!({
+            "rocksdb_sequence": self.rocks.db.latest_sequence_number(),
+            "column_families": column_families,
+            "rollup_cursor": rollup_cursor,
+        }))
+    }
+
+    fn get_consumer_info(&self) -> StorageResult<ConsumerInfo> {
+        let endpoint = self
+            .rocks
+            .get_static::<JetstreamEndpointKey, JetstreamEndpointValue>()?
+            .ok_or(StorageError::BadStateError(
+                "Could not find jetstream endpoint".to_string(),
+            ))?
+            .0;
+        let started_at = self
+            .rocks
+            .get_static::<TakeoffKey, TakeoffValue>()?
+            .ok_or(StorageError::BadStateError(
+                "Could not find jetstream takeoff time".to_string(),
+            ))?
+            .to_raw_u64();
+        let latest_cursor = self
+            .rocks
+            .get_static::<JetstreamCursorKey, JetstreamCursorValue>()?
+            .map(|c| c.to_raw_u64());
+        let rollup_cursor = self
+            .rocks
+            .get_static::<NewRollupCursorKey, NewRollupCursorValue>()?
+            .map(|c| c.to_raw_u64());
+
+        let gap_detected = self
+            .rocks
+            .get_static::<CursorGapKey, CursorGapValue>()?
+            .map(Into::into);
+
+        let day_ago = Cursor::at(SystemTime::now() - Duration::from_secs(86_400));
+        let mut overflowed_collections_24h = 0;
+        for kv in self.rocks.range(
+            ROLLUPS,
+            OverflowedCollectionsKey::new(day_ago.into()).range_to_prefix_end()?,
+        ) {
+            let (_, val_bytes) = kv?;
+            overflowed_collections_24h +=
+                db_complete::<OverflowedCollectionsVal>(&val_bytes)?.batches;
+        }
+
+        Ok(ConsumerInfo::Jetstream {
+            endpoint,
+            started_at,
+            latest_cursor,
+            rollup_cursor,
+            overflowed_collections_24h,
+            gap_detected,
+        })
+    }
+
+    fn get_earliest_hour(&self) -> StorageResult<HourTruncatedCursor> {
+        let cursor = self
+            .rocks
+            .prefix(ROLLUPS, HourlyRollupStaticPrefix::default().to_db_bytes()?)
+            .next()
+            .transpose()?
+            .map(|(key_bytes, _)| db_complete::<HourlyRollupKey>(&key_bytes))
+            .transpose()?
+            .map(|key| key.cursor())
+            .unwrap_or_else(|| Cursor::from_start().into());
+        Ok(cursor)
+    }
+
+    fn buckets(
+        &self,
+        since: Option<HourTruncatedCursor>,
+        until: Option<HourTruncatedCursor>,
+    ) -> StorageResult<Vec<CursorBucket>> {
+        if let (None, None) = (since, until) {
+            return Ok(vec![CursorBucket::AllTime]);
+        }
+        let mut lower = self.get_earliest_hour()?;
+        if let Some(specified) = since {
+            if specified > lower {
+                lower = specified;
+            }
+        }
+        let upper = until.unwrap_or_else(|| Cursor::at(SystemTime::now()).into());
+        Ok(CursorBucket::buckets_spanning(lower, upper))
+    }
+
+    fn get_lexi_collections(
+        &self,
+        limit: usize,
+        cursor: Option<Vec<u8>>,
+        buckets: Vec<CursorBucket>,
+        budget: &mut ScanBudget,
+    ) -> QueryResult<(Vec<NsidCount>, Option<Vec<u8>>)> {
+        let cursor_nsid = cursor
+            .as_deref()
+            .map(db_complete::<Nsid>)
+            .transpose()
+            .map_err(QueryError::BadCursor)?;
+        let tracked_since = self.tracked_since()?;
+        let mut iters: Vec<Peekable<NsidCounter>> = Vec::with_capacity(buckets.len());
+        for bucket in &buckets {
+            let it: NsidCounter = match bucket {
+                CursorBucket::Hour(t) => {
+                    let start = cursor_nsid
+                        .as_ref()
+                        .map(|nsid| HourlyRollupKey::after_nsid(*t, nsid))
+                        .unwrap_or_else(|| HourlyRollupKey::start(*t))?;
+                    self.lexi_iter::<HourlyRollupKey>(start, HourlyRollupKey::end(*t)?)
+                }
+                CursorBucket::Week(t) => {
+                    let start = cursor_nsid
+                        .as_ref()
+                        .map(|nsid| WeeklyRollupKey::after_nsid(*t, nsid))
+                        .unwrap_or_else(|| WeeklyRollupKey::start(*t))?;
+                    self.lexi_iter::<WeeklyRollupKey>(start, WeeklyRollupKey::end(*t)?)
+                }
+                CursorBucket::AllTime => {
+                    let start = cursor_nsid
+                        .as_ref()
+                        .map(AllTimeRollupKey::after_nsid)
+                        .unwrap_or_else(AllTimeRollupKey::start)?;
+                    self.lexi_iter::<AllTimeRollupKey>(start, AllTimeRollupKey::end()?)
+                }
+            };
+            iters.push(it.peekable());
+        }
+
+        let mut out = Vec::new();
+        let mut current_nsid = None;
+        for _ in 0..limit {
+            // see fjall's: a page the budget can't cover stops short
+            if !budget.reserve(iters.len()) {
+                break;
+            }
+            // first scan: find the lowest nsid. second: take + merge it from every iter
+            let mut lowest: Option<Nsid> = None;
+            for iter in &mut iters {
+                if let Some(bla) = iter.peek_mut() {
+                    let (nsid, _) = match bla {
+                        Ok(v) => v,
+                        Err(e) => Err(std::mem::replace(e, StorageError::Stolen))?,
+                    };
+                    lowest = match lowest {
+                        Some(ref current) if nsid.as_str() > current.as_str() => lowest,
+                        _ => Some(nsid.clone()),
+                    };
+                }
+            }
+            current_nsid = lowest.clone();
+            let Some(nsid) = lowest else { break };
+
+            let mut merged = CountsValue::default();
+            for iter in &mut iters {
+                // unwrap: errors were already bailed over when peeking in the first scan
+                if let Some(Ok((_, get_counts))) = iter.next_if(|v| v.as_ref().unwrap().0 == nsid) {
+                    merged.merge(&get_counts()?);
+                }
+            }
+            out.push(NsidCount::new(&nsid, &merged, tracked_since(&nsid)?));
+        }
+        if budget.ran_out() && out.is_empty() {
+            return Err(QueryError::OverScanBudget);
+        }
+
+        let next_cursor = current_nsid.map(|s| s.to_db_bytes()).transpose()?;
+        Ok((out, next_cursor))
+    }
+
+    fn get_ordered_collections(
+        &self,
+        limit: usize,
+        order: OrderCollectionsBy,
+        buckets: Vec<CursorBucket>,
+        budget: &mut ScanBudget,
+    ) -> QueryResult<(Vec<NsidCount>, Option<Vec<u8>>)> {
+        let cursor = match &order {
+            OrderCollectionsBy::RecordsCreated { cursor } => cursor,
+            OrderCollectionsBy::DidsEstimate { cursor } => cursor,
+            OrderCollectionsBy::Lexi { .. } => unreachable!(),
+        };
+        let after = cursor
+            .as_deref()
+            .map(db_complete::<RankCursor>)
+            .transpose()
+            .map_err(QueryError::BadCursor)?;
+
+        // same reverse scan of the rank keys as fjall's, see there for why
+        // each bucket can pick up right after the cursor's key, and why
+        // candidates are ranked on their total over every bucket
+        let total_over = (buckets.len() > 1).then(|| buckets.clone());
+        let mut iters: Vec<NsidCounter> = Vec::with_capacity(buckets.len());
+        for bucket in buckets {
+            let it: NsidCounter = match (&order, bucket) {
+                (OrderCollectionsBy::RecordsCreated { .. }, CursorBucket::Hour(t)) => {
+                    self.lookup_iter::<HourlyRecordsKey>(
+                        HourlyRecordsKey::start(t)?,
+                        match &after {
+                            Some(a) => HourlyRecordsKey::end_before(t, a.rank(), a.nsid())?,
+                            None => HourlyRecordsKey::end(t)?,
+                        },
+                        Arc::new(move |collection| {
+                            HourlyRollupKey::new(t, collection).to_db_bytes()
+                        }),
+                    )
+                }
+                (OrderCollectionsBy::DidsEstimate { .. }, CursorBucket::Hour(t)) => self
+                    .lookup_iter::<HourlyDidsKey>(
+                    HourlyDidsKey::start(t)?,
+                    match &after {
+                        Some(a) => HourlyDidsKey::end_before(t, a.rank(), a.nsid())?,
+                        None => HourlyDidsKey::end(t)?,
+                    },
+                    Arc::new(move |collection| HourlyRollupKey::new(t, collection).to_db_bytes()),
+                ),
+                (OrderCollectionsBy::RecordsCreated { .. }, CursorBucket::Week(t)) => {
+                    self.lookup_iter::<WeeklyRecordsKey>(
+                        WeeklyRecordsKey::start(t)?,
+                        match &after {
+                            Some(a) => WeeklyRecordsKey::end_before(t, a.rank(), a.nsid())?,
+                            None => WeeklyRecordsKey::end(t)?,
+                        },
+                        Arc::new(move |collection| {
+                            WeeklyRollupKey::new(t, collection).to_db_bytes()
+                        }),
+                    )
+                }
+                (OrderCollectionsBy::DidsEstimate { .. }, CursorBucket::Week(t)) => self
+                    .lookup_iter::<WeeklyDidsKey>(
+                    WeeklyDidsKey::start(t)?,
+                    match &after {
+                        Some(a) => WeeklyDidsKey::end_before(t, a.rank(), a.nsid())?,
+                        None => WeeklyDidsKey::end(t)?,
+                    },
+                    Arc::new(move |collection| WeeklyRollupKey::new(t, collection).to_db_bytes()),
+                ),
+                (OrderCollectionsBy::RecordsCreated { .. }, CursorBucket::AllTime) => {
+                    self.lookup_iter::<AllTimeRecordsKey>(
+                        AllTimeRecordsKey::start()?,
+                        match &after {
+                            Some(a) => AllTimeRecordsKey::end_before(a.rank(), a.nsid())?,
+                            None => AllTimeRecordsKey::end()?,
+                        },
+                        Arc::new(|collection| AllTimeRollupKey::new(collection).to_db_bytes()),
+                    )
+                }
+                (OrderCollectionsBy::DidsEstimate { .. }, CursorBucket::AllTime) => self
+                    .lookup_iter::<AllTimeDidsKey>(
+                    AllTimeDidsKey::start()?,
+                    match &after {
+                        Some(a) => AllTimeDidsKey::end_before(a.rank(), a.nsid())?,
+                        None => AllTimeDidsKey::end()?,
+                    },
+                    Arc::new(|collection| AllTimeRollupKey::new(collection).to_db_bytes()),
+                ),
+                (OrderCollectionsBy::Lexi { .. }, _) => unreachable!(),
+            };
+            iters.push(it);
+        }
+
+        // overfetch a bit, merge by collection, then rank and take the limit.
+        // running out of budget fails, like fjall's.
+        let mut ranked: HashMap<Nsid, CountsValue> = HashMap::with_capacity(limit * 2);
+        for iter in iters {
+            for pair in iter.take((limit as f64 * 1.3).ceil() as usize) {
+                if !budget.spend() {
+                    return Err(QueryError::OverScanBudget);
+                }
+                let (nsid, get_counts) = pair?;
+                if total_over.is_some() {
+                    ranked.entry(nsid).or_default();
+                } else {
+                    ranked.insert(nsid, get_counts()?);
+                }
+            }
+        }
+        if let Some(buckets) = total_over {
+            for (nsid, counts) in ranked.iter_mut() {
+                if !budget.reserve(buckets.len()) {
+                    return Err(QueryError::OverScanBudget);
+                }
+                *counts = self.sum_rollups(nsid, buckets.clone())?;
+            }
+        }
+        let score: fn(&CountsValue) -> u64 = match order {
+            OrderCollectionsBy::RecordsCreated { .. } => |c| c.counts().creates,
+            OrderCollectionsBy::DidsEstimate { .. } => |c| microcosm_estimates::estimate(c.dids()),
+            OrderCollectionsBy::Lexi { .. } => unreachable!(),
+        };
+        let mut ranked: Vec<(u64, Nsid, CountsValue)> = ranked
+            .into_iter()
+            .map(|(nsid, cv)| (score(&cv), nsid, cv))
+            .filter(|(rank, nsid, _)| match &after {
+                Some(a) => (*rank, nsid.as_str()) < (a.rank().into(), a.nsid().as_str()),
+                None => true,
+            })
+            .collect();
+        ranked.sort_by(|(ra, na, _), (rb, nb, _)| (rb, nb.as_str()).cmp(&(ra, na.as_str())));
+        ranked.truncate(limit);
+
+        let next_cursor = if ranked.len() < limit {
+            None
+        } else {
+            ranked
+                .last()
+                .map(|(rank, nsid, _)| RankCursor::new((*rank).into(), nsid).to_db_bytes())
+                .transpose()?
+        };
+
+        let tracked_since = self.tracked_since()?;
+        let counts = ranked
+            .into_iter()
+            .map(|(_, nsid, cv)| Ok(NsidCount::new(&nsid, &cv, tracked_since(&nsid)?)))
+            .collect::<StorageResult<_>>()?;
+        Ok((counts, next_cursor))
+    }
+
+    fn get_collections(
+        &self,
+        limit: usize,
+        order: OrderCollectionsBy,
+        since: Option<HourTruncatedCursor>,
+        until: Option<HourTruncatedCursor>,
+        mut budget: ScanBudget,
+    ) -> QueryResult<(Vec<NsidCount>, Option<Vec<u8>>)> {
+        let buckets = self.buckets(since, until)?;
+        match order {
+            OrderCollectionsBy::Lexi { cursor } => {
+                self.get_lexi_collections(limit, cursor, buckets, &mut budget)
+            }
+            _ => self.get_ordered_collections(limit, order, buckets, &mut budget),
+        }
+    }
+
+    fn get_prefix(
+        &self,
+        prefix: NsidPrefix,
+        limit: usize,
+        order: OrderCollectionsBy,
+        since: Option<HourTruncatedCursor>,
+        until: Option<HourTruncatedCursor>,
+        mut budget: ScanBudget,
+    ) -> QueryResult<(JustCount, Vec<PrefixChild>, Option<Vec<u8>>)> {
+        let OrderCollectionsBy::Lexi { cursor } = order else {
+            return Err(StorageError::Unsupported("ordered prefix listings").into());
+        };
+        let buckets = self.buckets(since, until)?;
+
+        let prefix_sub = String::sub_prefix(&prefix.terminated())?; // with trailing dot to ensure full segment match
+        let cursor_child = cursor
+            .as_deref()
+            .map(|encoded_bytes| {
+                let decoded: String = db_complete(encoded_bytes)?;
+                decoded.to_db_bytes()
+            })
+            .transpose()
+            .map_err(QueryError::BadCursor)?;
+        let tracked_since = self.tracked_since()?;
+        let mut iters: Vec<NsidCounter> = Vec::with_capacity(buckets.len());
+        for bucket in &buckets {
+            let it: NsidCounter = match bucket {
+                CursorBucket::Hour(t) => {
+                    let start = HourlyRollupKey::after_nsid_prefix(
+                        *t,
+                        cursor_child.as_ref().unwrap_or(&prefix_sub),
+                    )?;
+                    let end = HourlyRollupKey::nsid_prefix_end(*t, &prefix_sub)?;
+                    self.lexi_iter::<HourlyRollupKey>(start, end)
+                }
+                CursorBucket::Week(t) => {
+                    let start = WeeklyRollupKey::after_nsid_prefix(
+                        *t,
+                        cursor_child.as_ref().unwrap_or(&prefix_sub),
+                    )?;
+                    let end = WeeklyRollupKey::nsid_prefix_end(*t, &prefix_sub)?;
+                    self.lexi_iter::<WeeklyRollupKey>(start, end)
+                }
+                CursorBucket::AllTime => {
+                    let start = AllTimeRollupKey::after_nsid_prefix(
+                        cursor_child.as_ref().unwrap_or(&prefix_sub),
+                    )?;
+                    let end = AllTimeRollupKey::nsid_prefix_end(&prefix_sub)?;
+                    self.lexi_iter::<AllTimeRollupKey>(start, end)
+                }
+            };
+            iters.push(it);
+        }
+
+        #[derive(Debug, Clone, PartialEq)]
+        enum Child {
+            FullNsid(Nsid),
+            ChildPrefix(String),
+        }
+        impl Child {
+            fn from_prefix(nsid: &Nsid, prefix: &NsidPrefix) -> Option<Self> {
+                if prefix.is_group_of(nsid) {
+                    return Some(Child::FullNsid(nsid.clone()));
+                }
+                let suffix = nsid.as_str().strip_prefix(&format!("{}.", prefix.0))?;
+                let (segment, _) = suffix.split_once('.').unwrap();
+                Some(Child::ChildPrefix(format!("{}.{segment}", prefix.0)))
+            }
+            fn is_before(&self, other: &Child) -> bool {
+                match (self, other) {
+                    (Child::FullNsid(s), Child::ChildPrefix(o)) if s.as_str() == o => true,
+                    (Child::ChildPrefix(s), Child::FullNsid(o)) if s == o.as_str() => false,
+                    (Child::FullNsid(s), Child::FullNsid(o)) => s.as_str() < o.as_str(),
+                    (Child::ChildPrefix(s), Child::ChildPrefix(o)) => s < o,
+                    (Child::FullNsid(s), Child::ChildPrefix(o)) => s.to_string() < *o,
+                    (Child::ChildPrefix(s), Child::FullNsid(o)) => *s < o.to_string(),
+                }
+            }
+            fn into_inner(self) -> String {
+                match self {
+                    Child::FullNsid(s) => s.to_string(),
+                    Child::ChildPrefix(s) => s,
+                }
+            }
+        }
+
+        let mut iters: Vec<_> = iters
+            .into_iter()
+            .map(|it| {
+                it.map(|bla| {
+                    bla.map(|(nsid, v)| {
+                        let Some(child) = Child::from_prefix(&nsid, &prefix) else {
+                            panic!("failed from_prefix: {nsid:?} {prefix:?} (bad iter bounds?)");
+                        };
+                        (child, v)
+                    })
+                })
+                .peekable()
+            })
+            .collect();
+
+        let mut items = Vec::new();
+        let mut prefix_count = CountsValue::default();
+        let mut current_child: Option<Child> = None;
+        // the last child counted in full, where a page cut short by the budget ends
+        let mut finished_child: Option<Child> = None;
+        'children: for _ in 0..limit {
+            let mut lowest: Option<Child> = None;
+            for iter in &mut iters {
+                if let Some(bla) = iter.peek_mut() {
+                    let (child, _) = match bla {
+                        Ok(v) => v,
+                        Err(e) => Err(std::mem::replace(e, StorageError::Stolen))?,
+                    };
+                    lowest = match lowest {
+                        Some(ref current) if current.is_before(child) => lowest,
+                        _ => Some(child.clone()),
+                    };
+                }
+            }
+            current_child = lowest.clone();
+            let Some(child) = lowest else { break };
+
+            let mut merged = CountsValue::default();
+            for iter in &mut iters {
+                while let Some(Ok((_, get_counts))) =
+                    iter.next_if(|v| v.as_ref().unwrap().0 == child)
+                {
+                    if !budget.spend() {
+                        break 'children; // leave out the part-counted child
+                    }
+                    merged.merge(&get_counts()?);
+                }
+            }
+            prefix_count.merge(&merged);
+            finished_child = Some(child.clone());
+            items.push(match child {
+                Child::FullNsid(nsid) => {
+                    let since = tracked_since(&nsid)?;
+                    PrefixChild::Collection(NsidCount::new(&nsid, &merged, since))
+                }
+                Child::ChildPrefix(prefix) => {
+                    PrefixChild::Prefix(PrefixCount::new(&prefix, &merged))
+                }
+            });
+        }
+
+        let next_child = if budget.ran_out() {
+            if finished_child.is_none() {
+                return Err(QueryError::OverScanBudget);
+            }
+            finished_child
+        } else {
+            current_child
+        };
+        let next_cursor = next_child
+            .map(|s| s.into_inner().to_db_bytes())
+            .transpose()?;
+        Ok(((&prefix_count).into(), items, next_cursor))
+    }
+
+    fn get_timeseries(
+        &self,
+        collections: Vec<Nsid>,
+        since: HourTruncatedCursor,
+        until: Option<HourTruncatedCursor>,
+        step: u64,
+    ) -> StorageResult<(Vec<HourTruncatedCursor>, CollectionSerieses)> {
+        if step > WEEK_IN_MICROS {
+            return Err(StorageError::Unsupported("week-stepped timeseries"));
+        }
+        let until = until.unwrap_or_else(|| Cursor::at(SystemTime::now()).into());
+        let Ok(dt) = Cursor::from(until).duration_since(&Cursor::from(since)) else {
+            return Ok((
+                // empty: until < since
+                vec![],
+                collections.into_iter().map(|c| (c, vec![])).collect(),
+            ));
+        };
+        let n_hours = (dt.as_micros() as u64) / HOUR_IN_MICROS;
+        let mut counts_by_hour = Vec::with_capacity(n_hours as usize);
+        for hour in (0..n_hours).map(|i| since.nth_next(i)) {
+            let mut counts = Vec::with_capacity(collections.len());
+            for nsid in &collections {
+                counts.push(self.sum_rollups(nsid, vec![CursorBucket::Hour(hour)])?);
+            }
+            counts_by_hour.push((hour, counts));
+        }
+
+        let step_hours = step / (HOUR_IN_MICROS / 1_000_000);
+        let mut output_hours = Vec::with_capacity(step_hours as usize);
+        let mut output_series: CollectionSerieses = collections
+            .iter()
+            .map(|c| (c.clone(), Vec::with_capacity(step_hours as usize)))
+            .collect();
+        for chunk in counts_by_hour.chunks(step_hours as usize) {
+            output_hours.push(chunk[0].0);
+            for (i, collection) in collections.iter().enumerate() {
+                let mut c = CountsValue::default();
+                for (_, counts) in chunk {
+                    c.merge(&counts[i]);
+                }
+                output_series
+                    .get_mut(collection)
+                    .expect("output series is initialized with all collections")
+                    .push(c);
+            }
+        }
+        Ok((output_hours, output_series))
+    }
+
+    fn get_event_kinds(
+        &self,
+        since: HourTruncatedCursor,
+        until: Option<HourTruncatedCursor>,
+        step: u64,
+    ) -> StorageResult<(Vec<HourTruncatedCursor>, Vec<EventKindCounts>)> {
+        let until = until.unwrap_or_else(|| Cursor::at(SystemTime::now()).into());
+        let Ok(dt) = Cursor::from(until).duration_since(&Cursor::from(since)) else {
+            return Ok((vec![], vec![])); // empty: until < since
+        };
+        let n_hours = (dt.as_micros() as u64) / HOUR_IN_MICROS;
+        let step_hours = (step / (HOUR_IN_MICROS / 1_000_000)).max(1);
+        let n_steps = n_hours.div_ceil(step_hours);
+        let hours = (0..n_steps)
+            .map(|i| since.nth_next(i * step_hours))
+            .collect();
+        let mut kinds = vec![EventKindCounts::default(); n_steps as usize];
+        let end = since.nth_next(n_hours);
+        for kv in self.rocks.range(
+            ROLLUPS,
+            EventKindsKey::new(since).to_db_bytes()?..EventKindsKey::new(end).to_db_bytes()?,
+        ) {
+            let (key_bytes, val_bytes) = kv?;
+            let hour = db_complete::<EventKindsKey>(&key_bytes)?.hour();
+            let val = db_complete::<EventKindsVal>(&val_bytes)?;
+            let i = (hour.to_raw_u64() - since.to_raw_u64()) / HOUR_IN_MICROS / step_hours;
+            kinds[i as usize].merge(&EventKindCounts {
+                commits: val.commits,
+                identities: val.identities,
+                accounts: val.accounts,
+            });
+        }
+        Ok((hours, kinds))
+    }
+
+    fn get_collection_counts(
+        &self,
+        collection: &Nsid,
+        since: HourTruncatedCursor,
+        until: Option<HourTruncatedCursor>,
+    ) -> StorageResult<JustCount> {
+        let until = until.unwrap_or_else(|| Cursor::at(SystemTime::now()).into());
+        let buckets = CursorBucket::buckets_spanning(since, until);
+        Ok((&self.sum_rollups(collection, buckets)?).into())
+    }
+
+    /// Counts over an arbitrary window, prorating partly-covered hours like fjall's
+    fn get_collection_window_counts(
+        &self,
+        collection: &Nsid,
+        since: Cursor,
+        until: Option<Cursor>,
+    ) -> StorageResult<WindowCounts> {
+        let rollup_cursor = self
+            .rocks
+            .get_static::<NewRollupCursorKey, NewRollupCursorValue>()?
+            .unwrap_or(Cursor::from_start())
+            .to_raw_u64();
+
+        let since = since.to_raw_u64();
+        let until = until
+            .unwrap_or_else(|| Cursor::at(SystemTime::now()))
+            .to_raw_u64();
+
+        let mut total_counts = CountsValue::default();
+        let mut prorated = false;
+        let mut live = false;
+
+        // rolled up: [since, rolled_until)
+        let rolled_until = until.min(rollup_cursor);
+        if since < rolled_until {
+            let mut part_of_hour = |hour: u64| -> StorageResult<()> {
+                let covered = (hour, (hour + HOUR_IN_MICROS).min(rollup_cursor));
+                let wanted = (since.max(hour), rolled_until.min(hour + HOUR_IN_MICROS));
+                let hour = HourTruncatedCursor::truncate_raw_u64(hour);
+                let counts = self.sum_rollups(collection, vec![CursorBucket::Hour(hour)])?;
+                if wanted == covered {
+                    total_counts.merge(&counts);
+                } else {
+                    prorated = true;
+                    total_counts.merge(&counts.scaled(wanted.1 - wanted.0, covered.1 - covered.0));
+                }
+                Ok(())
+            };
+            let first = HourTruncatedCursor::truncate(since);
+            let last = HourTruncatedCursor::truncate(rolled_until - 1);
+            part_of_hour(first)?;
+            if last > first {
+                part_of_hour(last)?;
+            }
+            if last > first + HOUR_IN_MICROS {
+                let whole = CursorBucket::buckets_spanning(
+                    HourTruncatedCursor::truncate_raw_u64(first + HOUR_IN_MICROS),
+                    HourTruncatedCursor::truncate_raw_u64(last),
+                );
+                total_counts.merge(&self.sum_rollups(collection, whole)?);
+            }
+        }
+
+        // not rolled up yet: [since.max(rollup_cursor), until)
+        let live_since = Cursor::from_raw_u64(since.max(rollup_cursor));
+        for kv in self
+            .rocks
+            .range(ROLLUPS, LiveCountsKey::range_from_cursor(live_since)?)
+        {
+            let (key_bytes, val_bytes) = kv?;
+            let key = db_complete::<LiveCountsKey>(&key_bytes)?;
+            if key.cursor().to_raw_u64() >= until {
+                break;
+            }
+            if key.collection() != collection {
+                continue;
+            }
+            live = true;
+            total_counts.merge(&db_complete::<CountsValue>(&val_bytes)?);
+        }
+
+        Ok(WindowCounts {
+            counts: (&total_counts).into(),
+            prorated,
+            live,
+        })
+    }
+
+    fn get_records_by_collections(
+        &self,
+        collections: HashSet<Nsid>,
+        limit: usize,
+        expand_each_collection: bool,
+        budget: ScanBudget,
+    ) -> StorageResult<(Vec<UFOsRecord>, usize, bool)> {
+        if collections.is_empty() {
+            return Ok((vec![], 0, false));
+        }
+        for collection in &collections {
+            self.popularity.record_read(collection);
+        }
+        let skipped_corrupt = Rc::new(Cell::new(0));
+        let budget = Rc::new(Cell::new(budget));
+        let mut record_iterators = Vec::new();
+        for collection in collections {
+            let iter = RecordIterator::new(
+                &self.rocks,
+                &collection,
+                limit,
+                skipped_corrupt.clone(),
+                budget.clone(),
+            )?;
+            record_iterators.push(iter.peekable());
+        }
+        let mut merged = Vec::new();
+        loop {
+            let mut latest: Option<(Cursor, usize)> = None;
+            for (i, iter) in record_iterators.iter_mut().enumerate() {
+                let Some(it) = iter.peek_mut() else {
+                    continue;
+                };
+                let it = match it {
+                    Ok(v) => v,
+                    Err(e) => Err(std::mem::replace(e, StorageError::Stolen))?,
+                };
+                let Some(rec) = it else {
+                    if expand_each_collection {
+                        continue;
+                    } else {
+                        break;
+                    }
+                };
+                match latest {
+                    Some((cursor, _)) if rec.cursor <= cursor => {}
+                    _ => latest = Some((rec.cursor, i)),
+                }
+            }
+            let Some((_, idx)) = latest else {
+                break;
+            };
+            merged.push(record_iterators[idx].next().unwrap().unwrap().unwrap());
+        }
+        Ok((merged, skipped_corrupt.get(), budget.get().ran_out()))
+    }
+
+    fn sample_records(
+        &self,
+        collection: &Nsid,
+        n: usize,
+        seed: u64,
+        budget: ScanBudget,
+    ) -> StorageResult<Vec<UFOsRecord>> {
+        let start = NsidRecordFeedKey::from_prefix_to_db_bytes(collection)?;
+        let end = NsidRecordFeedKey::prefix_range_end(collection)?;
+        let first = self.rocks.range(FEEDS, start.clone()..end.clone()).next();
+        let last = self.rocks.range_rev(FEEDS, start..end).next();
+        let (Some(first), Some(last)) = (first, last) else {
+            return Ok(vec![]);
+        };
+        let oldest = db_complete::<NsidRecordFeedKey>(&first?.0)?.cursor();
+        let newest = db_complete::<NsidRecordFeedKey>(&last?.0)?.cursor();
+        let skipped_corrupt = Rc::new(Cell::new(0));
+        let budget = Rc::new(Cell::new(budget));
+        sample_by_probes((oldest, newest), n, seed, |at| {
+            RecordIterator::starting_at(
+                &self.rocks,
+                collection,
+                at,
+                1,
+                skipped_corrupt.clone(),
+                budget.clone(),
+            )?
+            .next()
+            .transpose()
+            .map(Option::flatten)
+        })
+    }
+
+    fn get_record_versions(
+        &self,
+        did: &Did,
+        collection: &Nsid,
+        rkey: &RecordKey,
+    ) -> StorageResult<Vec<UFOsRecord>> {
+        let location_key = RecordLocationKey::from_pair(
+            did.clone(),
+            DbConcat::from_pair(collection.clone(), rkey.clone()),
+        );
+        // older versions are never kept by this backend, but match fjall's reads anyway
+        let mut versions = Vec::new();
+        for kv in self.rocks.prefix(RECORDS, location_key.to_db_bytes()?) {
+            let (_, val_bytes) = kv?;
+            let (meta, n) = RecordLocationMeta::from_db_bytes(&val_bytes)?;
+            versions.push(decode_record(&location_key, meta, &val_bytes[n..])?);
+        }
+        versions.sort_by_key(|v| std::cmp::Reverse(v.cursor.to_raw_u64()));
+        Ok(versions)
+    }
+
+    fn get_account_records(
+        &self,
+        did: &Did,
+        collection: &Nsid,
+        range: (Bound<String>, Bound<String>),
+        limit: usize,
+        reverse: bool,
+    ) -> StorageResult<(Vec<UFOsRecord>, bool)> {
+        // rkeys are length-prefixed in the key, so db order isn't rkey order
+        let prefix = DbConcat::from_pair(did.clone(), collection.clone()).to_db_bytes()?;
+        let mut found = Vec::new();
+        for kv in self.rocks.prefix(RECORDS, prefix) {
+            let (key_bytes, val_bytes) = kv?;
+            let (location_key, n) = RecordLocationKey::from_db_bytes(&key_bytes)?;
+            if n < key_bytes.len() {
+                continue; // an older version
+            }
+            let rkey = location_key.rkey().to_string();
+            if range.contains(&rkey) {
+                found.push((rkey, location_key, val_bytes));
+            }
+        }
+        found.sort_by(|(a, _, _), (b, _, _)| a.cmp(b));
+        if reverse {
+            found.reverse();
+        }
+        let more = found.len() > limit;
+        let mut records = Vec::with_capacity(limit.min(found.len()));
+        for (_, location_key, val_bytes) in found.into_iter().take(limit) {
+            let (meta, n) = RecordLocationMeta::from_db_bytes(&val_bytes)?;
+            records.push(decode_record(&location_key, meta, &val_bytes[n..])?);
+        }
+        Ok((records, more))
+    }
+
+    fn get_records_by_did(
+        &self,
+        did: &Did,
+        cursor: Option<Vec<u8>>,
+        limit: usize,
+        mut budget: ScanBudget,
+    ) -> StorageResult<(Vec<UFOsRecord>, Option<Vec<u8>>, bool)> {
+        // cursors are relative to the did's prefix, so they can't escape it
+        let prefix = RecordLocationKey::from_prefix_to_db_bytes(did)?;
+        let start = match &cursor {
+            Some(after) => Bound::Excluded([prefix.as_slice(), after].concat()),
+            None => Bound::Included(prefix.clone()),
+        };
+        let end = RecordLocationKey::prefix_range_end(did)?;
+        let mut records = Vec::with_capacity(limit);
+        let mut after = cursor.unwrap_or_default();
+        for kv in self.rocks.range(RECORDS, (start, Bound::Excluded(end))) {
+            if !budget.spend() {
+                return Ok((records, Some(after), true));
+            }
+            let (key_bytes, val_bytes) = kv?;
+            let (location_key, n) = RecordLocationKey::from_db_bytes(&key_bytes)?;
+            if n == key_bytes.len() {
+                if records.len() >= limit {
+                    return Ok((records, Some(after), false));
+                }
+                let (meta, n) = RecordLocationMeta::from_db_bytes(&val_bytes)?;
+                records.push(decode_record(&location_key, meta, &val_bytes[n..])?);
+            } // else it's an older version
+            after = key_bytes[prefix.len()..].to_vec();
+        }
+        Ok((records, None, false))
+    }
+
+    fn approximate_count(&self, prefix: CountPrefix) -> StorageResult<Option<u64>> {
+        let (cf, start, end) = match prefix {
+            CountPrefix::Collection(collection) => (
+                FEEDS,
+                NsidRecordFeedKey::from_prefix_to_db_bytes(&collection)?,
+                NsidRecordFeedKey::prefix_range_end(&collection)?,
+            ),
+            CountPrefix::Account(did) => (
+                RECORDS,
+                RecordLocationKey::from_prefix_to_db_bytes(&did)?,
+                RecordLocationKey::prefix_range_end(&did)?,
+            ),
+            CountPrefix::AccountCollection(did, collection) => {
+                let prefix = DbConcat::from_pair(did, collection);
+                (
+                    RECORDS,
+                    prefix.to_db_bytes()?,
+                    prefix.as_prefix_range_end()?,
+                )
+            }
+        };
+        Ok(self.rocks.approximate_range_len(cf, &start, &end))
+    }
+
+    fn hour_counts(&self, hour: HourTruncatedCursor) -> StorageResult<CountsValue> {
+        let mut counts = CountsValue::default();
+        for kv in self.lexi_iter::<HourlyRollupKey>(
+            HourlyRollupKey::start(hour)?,
+            HourlyRollupKey::end(hour)?,
+        ) {
+            let (_, get_counts) = kv?;
+            counts.merge(&get_counts()?);
+        }
+        Ok(counts)
+    }
+
+    fn get_summary(&self) -> StorageResult<Summary> {
+        let latest_cursor = self
+            .rocks
+            .get_static::<JetstreamCursorKey, JetstreamCursorValue>()?;
+        let rollup_cursor = self
+            .rocks
+            .get_static::<NewRollupCursorKey, NewRollupCursorValue>()?;
+
+        let mut summary = Summary::default();
+
+        let mut all_time = CountsValue::default();
+        for kv in
+            self.lexi_iter::<AllTimeRollupKey>(AllTimeRollupKey::start()?, AllTimeRollupKey::end()?)
+        {
+            let (_, get_counts) = kv?;
+            all_time.merge(&get_counts()?);
+            summary.collections += 1;
+        }
+        summary.records_created = all_time.counts().creates;
+        summary.dids_estimate = microcosm_estimates::estimate(all_time.dids());
+
+        if let Some(rollup_cursor) = rollup_cursor {
+            // the hour holding the rollup cursor is still filling up
+            let filling = HourTruncatedCursor::from(rollup_cursor);
+            let mut day = CountsValue::default();
+            for hours_ago in 0..24 {
+                if filling.to_raw_u64() < hours_ago * HOUR_IN_MICROS {
+                    break;
+                }
+                let hour = HourTruncatedCursor::from(Cursor::from_raw_u64(
+                    filling.to_raw_u64() - hours_ago * HOUR_IN_MICROS,
+                ));
+                let hour_counts = self.hour_counts(hour)?;
+                if hours_ago == 1 {
+                    let CommitCounts {
+                        creates,
+                        updates,
+                        deletes,
+                    } = hour_counts.counts();
+                    summary.events_per_second = (creates + updates + deletes) as f64 / 3600.;
+                }
+                day.merge(&hour_counts);
+            }
+            summary.dids_estimate_24h = microcosm_estimates::estimate(day.dids());
+        }
+
+        if let Some(latest) = latest_cursor {
+            let now = Cursor::at(SystemTime::now()).to_raw_u64();
+            summary.consumer_lag_ms = Some(now.saturating_sub(latest.to_raw_u64()) / 1_000);
+            if let Some(rollup_cursor) = rollup_cursor {
+                summary.rollup_lag_ms = Some(
+                    latest
+                        .to_raw_u64()
+                        .saturating_sub(rollup_cursor.to_raw_u64())
+                        / 1_000,
+                );
+            }
+        }
+
+        Ok(summary)
+    }
+
+    fn get_growing_collections(
+        &self,
+        period: GrowthPeriod,
+        limit: usize,
+        min_previous: u64,
+    ) -> StorageResult<GrowthRanking> {
+        let rollup_cursor = self
+            .rocks
+            .get_static::<NewRollupCursorKey, NewRollupCursorValue>()?
+            .unwrap_or(Cursor::from_start());
+
+        // the bucket holding the rollup cursor is still filling up
+        let (previous, current) = match period {
+            GrowthPeriod::Hour => {
+                let filling = HourTruncatedCursor::from(rollup_cursor);
+                if filling.to_raw_u64() < 2 * HOUR_IN_MICROS {
+                    return Ok(GrowthRanking::default());
+                }
+                let current = filling.prev();
+                (
+                    CursorBucket::Hour(current.prev()),
+                    CursorBucket::Hour(current),
+                )
+            }
+            GrowthPeriod::Week => {
+                let filling = WeekTruncatedCursor::from(rollup_cursor);
+                if filling.to_raw_u64() < 2 * WEEK_IN_MICROS {
+                    return Ok(GrowthRanking::default());
+                }
+                let current = filling.prev();
+                (
+                    CursorBucket::Week(current.prev()),
+                    CursorBucket::Week(current),
+                )
+            }
+        };
+
+        let bucket_creates = |bucket: &CursorBucket| -> StorageResult<HashMap<Nsid, u64>> {
+            let iter = match bucket {
+                CursorBucket::Hour(t) => self.lexi_iter::<HourlyRollupKey>(
+                    HourlyRollupKey::start(*t)?,
+                    HourlyRollupKey::end(*t)?,
+                ),
+                CursorBucket::Week(t) => self.lexi_iter::<WeeklyRollupKey>(
+                    WeeklyRollupKey::start(*t)?,
+                    WeeklyRollupKey::end(*t)?,
+                ),
+                CursorBucket::AllTime => unreachable!(),
+            };
+            iter.map(|kv| {
+                let (nsid, get_counts) = kv?;
+                Ok((nsid, get_counts()?.counts().creates))
+            })
+            .collect()
+        };
+        let previous_creates = bucket_creates(&previous)?;
+        let current_creates = bucket_creates(&current)?;
+
+        let min_previous = min_previous.max(1);
+        let mut collections: Vec<CollectionGrowth> = previous_creates
+            .into_iter()
+            .filter(|(_, previous)| *previous >= min_previous)
+            .map(|(nsid, previous)| {
+                let current = current_creates.get(&nsid).copied().unwrap_or(0);
+                CollectionGrowth {
+                    nsid: nsid.to_string(),
+                    previous,
+                    current,
+                    growth: current as f64 / previous as f64 - 1.0,
+                }
+            })
+            .collect();
+        collections.sort_by(|a, b| {
+            b.growth
+                .total_cmp(&a.growth)
+                .then_with(|| b.nsid.cmp(&a.nsid))
+        });
+        collections.truncate(limit);
+
+        let bucket_start = |bucket: &CursorBucket| match bucket {
+            CursorBucket::Hour(t) => t.to_raw_u64(),
+            CursorBucket::Week(t) => t.to_raw_u64(),
+            CursorBucket::AllTime => unreachable!(),
+        };
+        Ok(GrowthRanking {
+            previous_start: bucket_start(&previous),
+            current_start: bucket_start(&current),
+            collections,
+        })
+    }
+
+    fn search_collections(
+        &self,
+        terms: Vec<String>,
+        mut budget: ScanBudget,
+    ) -> StorageResult<(Vec<NsidCount>, bool)> {
+        let limit = 16; // same as fjall's
+        let tracked_since = self.tracked_since()?;
+        let mut matches = Vec::new();
+        for kv in self.rocks.range(
+            ROLLUPS,
+            (AllTimeRollupKey::start()?, AllTimeRollupKey::end()?),
+        ) {
+            if !budget.spend() {
+                return Ok((matches, true));
+            }
+            let (key_bytes, val_bytes) = kv?;
+            let key = db_complete::<AllTimeRollupKey>(&key_bytes)?;
+            let nsid = key.collection();
+            if terms.iter().any(|term| nsid.contains(term)) {
+                let counts = db_complete::<CountsValue>(&val_bytes)?;
+                matches.push(NsidCount::new(nsid, &counts, tracked_since(nsid)?));
+            }
+            if matches.len() >= limit {
+                break;
+            }
+        }
+        Ok((matches, false))
+    }
+}
+
+#[async_trait]
+impl StoreReader for RocksReader {
+    fn name(&self) -> String {
+        "rocksdb storage".into()
+    }
+    fn update_metrics(&self) {
+        for cf in COLUMN_FAMILIES {
+            if let Some(bytes) = self.rocks.property(cf, "rocksdb.total-sst-files-size") {
+                gauge!("storage_rocks_sst_bytes", "cf" => cf).set(bytes as f64);
+            }
+            if let Some(bytes) = self
+                .rocks
+                .property(cf, "rocksdb.estimate-pending-compaction-bytes")
+            {
+                gauge!("storage_rocks_pending_compaction_bytes", "cf" => cf).set(bytes as f64);
+            }
+        }
+    }
+    async fn get_storage_stats(&self) -> QueryResult<serde_json::Value> {
+        let s = self.clone();
+        Ok(server::spawn_blocking(move || RocksReader::get_storage_stats(&s)).await??)
+    }
+    async fn get_consumer_info(&self) -> QueryResult<ConsumerInfo> {
+        let s = self.clone();
+        Ok(server::spawn_blocking(move || RocksReader::get_consumer_info(&s)).await??)
+    }
+    async fn get_changes(
+        &self,
+        _after: Option<Cursor>,
+        _limit: usize,
+    ) -> QueryResult<(Vec<(Cursor, Vec<u8>)>, Option<Cursor>)> {
+        // this backend never publishes a change feed
+        Ok((vec![], None))
+    }
+    async fn pin_snapshot(&self, ttl: Duration) -> QueryResult<(u64, SystemTime)> {
+        let s = self.clone();
+        server::spawn_blocking(move || RocksReader::pin_snapshot(&s, ttl)).await?
+    }
+    fn at_snapshot(&self, token: u64) -> QueryResult<Box<dyn StoreReader>> {
+        Ok(Box::new(RocksReader::at_snapshot(self, token)?))
+    }
+    async fn get_collections(
+        &self,
+        limit: usize,
+        order: OrderCollectionsBy,
+        window: HourWindow,
+        budget: ScanBudget,
+    ) -> QueryResult<(Vec<NsidCount>, Option<Vec<u8>>)> {
+        let s = self.clone();
+        server::spawn_blocking(move || {
+            RocksReader::get_collections(&s, limit, order, window.since(), window.until(), budget)
+        })
+        .await?
+    }
+    async fn get_prefix(
+        &self,
+        prefix: NsidPrefix,
+        limit: usize,
+        order: OrderCollectionsBy,
+        window: HourWindow,
+        budget: ScanBudget,
+    ) -> QueryResult<(JustCount, Vec<PrefixChild>, Option<Vec<u8>>)> {
+        let s = self.clone();
+        server::spawn_blocking(move || {
+            RocksReader::get_prefix(
+                &s,
+                prefix,
+                limit,
+                order,
+                window.since(),
+                window.until(),
+                budget,
+            )
+        })
+        .await?
+    }
+    async fn get_timeseries(
+        &self,
+        collections: Vec<Nsid>,
+        since: HourTruncatedCursor,
+        until: Option<HourTruncatedCursor>,
+        step: u64,
+    ) -> QueryResult<(Vec<HourTruncatedCursor>, CollectionSerieses)> {
+        let s = self.clone();
+        Ok(server::spawn_blocking(move || {
+            RocksReader::get_timeseries(&s, collections, since, until, step)
+        })
+        .await??)
+    }
+    async fn get_event_kinds(
+        &self,
+        since: HourTruncatedCursor,
+        until: Option<HourTruncatedCursor>,
+        step: u64,
+    ) -> QueryResult<(Vec<HourTruncatedCursor>, Vec<EventKindCounts>)> {
+        let s = self.clone();
+        Ok(
+            server::spawn_blocking(move || RocksReader::get_event_kinds(&s, since, until, step))
+                .await??,
+        )
+    }
+    async fn get_collection_counts(
+        &self,
+        collection: &Nsid,
+        since: HourTruncatedCursor,
+        until: Option<HourTruncatedCursor>,
+    ) -> QueryResult<JustCount> {
+        let s = self.clone();
+        let collection = collection.clone();
+        Ok(server::spawn_blocking(move || {
+            RocksReader::get_collection_counts(&s, &collection, since, until)
+        })
+        .await??)
+    }
+    async fn get_collection_window_counts(
+        &self,
+        collection: &Nsid,
+        since: Cursor,
+        until: Option<Cursor>,
+    ) -> QueryResult<WindowCounts> {
+        let s = self.clone();
+        let collection = collection.clone();
+        Ok(server::spawn_blocking(move || {
+            RocksReader::get_collection_window_counts(&s, &collection, since, until)
+        })
+        .await??)
+    }
+    async fn get_unique_records(
+        &self,
+        _collection: &Nsid,
+        _since: HourTruncatedCursor,
+        _until: Option<HourTruncatedCursor>,
+    ) -> QueryResult<Option<UniqueRecords>> {
+        Err(StorageError::Unsupported("unique record counts").into())
+    }
+    async fn get_tracked_since(&self, collection: &Nsid) -> QueryResult<Cursor> {
+        let s = self.clone();
+        let collection = collection.clone();
+        Ok(server::spawn_blocking(move || s.tracked_since()?(&collection)).await??)
+    }
+    async fn get_records_by_collections(
+        &self,
+        collections: HashSet<Nsid>,
+        limit: usize,
+        expand_each_collection: bool,
+        budget: ScanBudget,
+    ) -> QueryResult<(Vec<UFOsRecord>, usize, bool)> {
+        let s = self.clone();
+        Ok(server::spawn_blocking(move || {
+            RocksReader::get_records_by_collections(
+                &s,
+                collections,
+                limit,
+                expand_each_collection,
+                budget,
+            )
+        })
+        .await??)
+    }
+    async fn sample_records(
+        &self,
+        collection: &Nsid,
+        n: usize,
+        seed: u64,
+        budget: ScanBudget,
+    ) -> QueryResult<Vec<UFOsRecord>> {
+        let s = self.clone();
+        let collection = collection.clone();
+        Ok(server::spawn_blocking(move || {
+            RocksReader::sample_records(&s, &collection, n, seed, budget)
+        })
+        .await??)
+    }
+    async fn get_record_versions(
+        &self,
+        did: &Did,
+        collection: &Nsid,
+        rkey: &RecordKey,
+    ) -> QueryResult<Vec<UFOsRecord>> {
+        let s = self.clone();
+        let did = did.clone();
+        let collection = collection.clone();
+        let rkey = rkey.clone();
+        Ok(server::spawn_blocking(move || {
+            RocksReader::get_record_versions(&s, &did, &collection, &rkey)
+        })
+        .await??)
+    }
+    async fn get_account_records(
+        &self,
+        did: &Did,
+        collection: &Nsid,
+        range: (Bound<String>, Bound<String>),
+        limit: usize,
+        reverse: bool,
+    ) -> QueryResult<(Vec<UFOsRecord>, bool)> {
+        let s = self.clone();
+        let did = did.clone();
+        let collection = collection.clone();
+        Ok(server::spawn_blocking(move || {
+            RocksReader::get_account_records(&s, &did, &collection, range, limit, reverse)
+        })
+        .await??)
+    }
+    async fn get_records_by_did(
+        &self,
+        did: &Did,
+        cursor: Option<Vec<u8>>,
+        limit: usize,
+        budget: ScanBudget,
+    ) -> QueryResult<(Vec<UFOsRecord>, Option<Vec<u8>>, bool)> {
+        let s = self.clone();
+        let did = did.clone();
+        Ok(server::spawn_blocking(move || {
+            RocksReader::get_records_by_did(&s, &did, cursor, limit, budget)
+        })
+        .await??)
+    }
+    async fn approximate_count(&self, prefix: CountPrefix) -> QueryResult<Option<u64>> {
+        let s = self.clone();
+        Ok(server::spawn_blocking(move || RocksReader::approximate_count(&s, prefix)).await??)
+    }
+    async fn get_summary(&self) -> QueryResult<Summary> {
+        let s = self.clone();
+        Ok(server::spawn_blocking(move || RocksReader::get_summary(&s)).await??)
+    }
+    async fn get_growing_collections(
+        &self,
+        period: GrowthPeriod,
+        limit: usize,
+        min_previous: u64,
+    ) -> QueryResult<GrowthRanking> {
+        let s = self.clone();
+        Ok(server::spawn_blocking(move || {
+            RocksReader::get_growing_collections(&s, period, limit, min_previous)
+        })
+        .await??)
+    }
+    async fn search_collections(
+        &self,
+        terms: Vec<String>,
+        budget: ScanBudget,
+    ) -> QueryResult<(Vec<NsidCount>, bool)> {
+        let s = self.clone();
+        Ok(
+            server::spawn_blocking(move || RocksReader::search_collections(&s, terms, budget))
+                .await??,
+        )
+    }
+    async fn get_watchlist_hits(
+        &self,
+        _name: &str,
+        _before: Option<Cursor>,
+        _limit: usize,
+    ) -> QueryResult<Vec<WatchlistHit>> {
+        Err(StorageError::Unsupported("watchlists").into())
+    }
+    async fn search_records(
+        &self,
+        _query: &str,
+        _collection: Option<&Nsid>,
+        _limit: usize,
+    ) -> QueryResult<Vec<UFOsRecord>> {
+        Err(StorageError::Unsupported("record search").into())
+    }
+    async fn get_watchlist_counts(
+        &self,
+        _name: &str,
+        _since: HourTruncatedCursor,
+        _until: Option<HourTruncatedCursor>,
+    ) -> QueryResult<Vec<(HourTruncatedCursor, u64)>> {
+        Err(StorageError::Unsupported("watchlists").into())
+    }
+}
+
+#[derive(Clone)]
+pub struct RocksWriter {
+    bg_taken: Arc<AtomicBool>,
+    schedule: Arc<BackgroundSchedule>,
+    denylist: Arc<RwLock<Denylist>>,
+    dirty: Option<DirtyTap>,
+    popularity: ReadPopularity,
+    popular_trim_multiplier: usize,
+    noisy: NoisyDids,
+    plugins: IngestPlugins,
+    nsid_limits: NsidLimits,
+    /// the jetstream shard id for each sharded collection
+    shards: Arc<HashMap<Nsid, String>>,
+    spill: RocksSpill,
+    /// serializes audit appends so positions stay unique
+    audit_lock: Arc<Mutex<()>>,
+    rocks: Rocks,
+}
+
+impl RocksWriter {
+    fn shard_cursors<const LIMIT: usize>(
+        &self,
+        event_batch: &EventBatch<LIMIT>,
+    ) -> HashMap<String, Cursor> {
+        let mut cursors: HashMap<String, Cursor> = HashMap::new();
+        for (nsid, commits) in &event_batch.commits_by_nsid {
+            let Some(shard) = self.shards.get(nsid) else {
+                continue;
+            };
+            for commit in &commits.commits {
+                let latest = cursors.entry(shard.clone()).or_insert(commit.cursor);
+                if commit.cursor > *latest {
+                    *latest = commit.cursor;
+                }
+            }
+        }
+        cursors
+    }
+
+    fn trim_limit(&self, collection: &Nsid) -> usize {
+        if self.popular_trim_multiplier > 1
+            && self.popularity.is_popular_at(collection, Instant::now())
+        {
+            counter!("storage_trim_popular").increment(1);
+            TRIM_KEEP_RECORDS * self.popular_trim_multiplier
+        } else {
+            TRIM_KEEP_RECORDS
+        }
+    }
+
+    /// Process queued account deletes in order, up to the next live count
+    ///
+    /// Same limits as fjall's: at least one account, then stop at
+    /// [`MAX_BATCHED_ACCOUNT_DELETES`] accounts or [`MAX_ROLLUP_DELETE_RECORDS`] records.
+    fn rollup_delete_accounts(
+        &self,
+        rollup_cursor: Cursor,
+        timely_next: Option<Cursor>,
+    ) -> StorageResult<usize> {
+        let delete_accounts_range =
+            DeleteAccountQueueKey::new(rollup_cursor).range_to_prefix_end()?;
+        let mut accounts_deleted = 0;
+        let mut records_deleted = 0;
+        for kv in self.rocks.range(QUEUES, delete_accounts_range) {
+            let (key_bytes, val_bytes) = kv?;
+            let cursor = db_complete::<DeleteAccountQueueKey>(&key_bytes)?.suffix;
+            if timely_next.is_some_and(|timely| timely < cursor) {
+                break;
+            }
+            let did = db_complete::<DeleteAccountQueueVal>(&val_bytes)?;
+            records_deleted += self.delete_account_records(&did)?;
+            let mut batch = WriteBatch::default();
+            batch.delete_cf(&self.rocks.cf(QUEUES), key_bytes);
+            self.rocks
+                .batch_static::<NewRollupCursorKey>(&mut batch, cursor)?;
+            self.rocks.db.write(batch)?;
+            accounts_deleted += 1;
+            if accounts_deleted >= MAX_BATCHED_ACCOUNT_DELETES
+                || records_deleted >= MAX_ROLLUP_DELETE_RECORDS
+            {
+                break;
+            }
+        }
+        histogram!("storage_rollup_accounts_deleted").record(accounts_deleted as f64);
+        Ok(accounts_deleted)
+    }
+
+    fn rollup_live_counts(
+        &self,
+        timelies: impl Iterator<Item = RocksKV>,
+        cursor_exclusive_limit: Option<Cursor>,
+        rollup_limit: usize,
+    ) -> StorageResult<(usize, HashSet<Nsid>)> {
+        let mut dirty_nsids = HashSet::new();
+
+        #[derive(Eq, Hash, PartialEq)]
+        enum Rollup {
+            Hourly(HourTruncatedCursor),
+            Weekly(WeekTruncatedCursor),
+            AllTime,
+        }
+
+        let rollups = self.rocks.cf(ROLLUPS);
+        let mut batch = WriteBatch::default();
+        let mut cursors_advanced = 0;
+        let mut last_cursor = Cursor::from_start();
+        let mut counts_by_rollup: HashMap<(Nsid, Rollup), CountsValue> = HashMap::new();
+        let mut earliest_cursors: HashMap<Nsid, Cursor> = HashMap::new();
+
+        for (i, kv) in timelies.enumerate() {
+            if i >= rollup_limit {
+                break;
+            }
+
+            let (key_bytes, val_bytes) = kv?;
+            let key = db_complete::<LiveCountsKey>(&key_bytes)?;
+
+            if cursor_exclusive_limit.is_some_and(|limit| key.cursor() > limit) {
+                break;
+            }
+
+            dirty_nsids.insert(key.collection().clone());
+            earliest_cursors
+                .entry(key.collection().clone())
+                .or_insert(key.cursor());
+
+            batch.delete_cf(&rollups, key_bytes);
+            let val = db_complete::<CountsValue>(&val_bytes)?;
+            for rollup in [
+                Rollup::Hourly(key.cursor().into()),
+                Rollup::Weekly(key.cursor().into()),
+                Rollup::AllTime,
+            ] {
+                counts_by_rollup
+                    .entry((key.collection().clone(), rollup))
+                    .or_default()
+                    .merge(&val);
+            }
+
+            cursors_advanced += 1;
+            last_cursor = key.cursor();
+        }
+
+        for ((nsid, rollup), counts) in counts_by_rollup {
+            let rollup_key_bytes = match rollup {
+                Rollup::Hourly(hourly_cursor) => {
+                    HourlyRollupKey::new(hourly_cursor, &nsid).to_db_bytes()?
+                }
+                Rollup::Weekly(weekly_cursor) => {
+                    WeeklyRollupKey::new(weekly_cursor, &nsid).to_db_bytes()?
+                }
+                Rollup::AllTime => AllTimeRollupKey::new(&nsid).to_db_bytes()?,
+            };
+            let existing = self.rocks.get(ROLLUPS, &rollup_key_bytes)?;
+
+            // first time we've rolled up this collection: remember when we started tracking it
+            if existing.is_none() && matches!(rollup, Rollup::AllTime) {
+                if let Some(first_cursor) = earliest_cursors.get(&nsid) {
+                    batch.put_cf(
+                        &rollups,
+                        CollectionFirstSeenKey::new(&nsid).to_db_bytes()?,
+                        first_cursor.to_db_bytes()?,
+                    );
+                }
+            }
+
+            let mut rolled: CountsValue = existing
+                .as_deref()
+                .map(db_complete::<CountsValue>)
+                .transpose()?
+                .unwrap_or_default();
+
+            let before_creates_count = rolled.counts().creates;
+            let before_dids_estimate = microcosm_estimates::estimate(rolled.dids());
+
+            rolled.merge(&counts);
+
+            let new_creates_count = rolled.counts().creates;
+            let new_dids_estimate = microcosm_estimates::estimate(rolled.dids());
+
+            // swap the rank index entries whose rank changed
+            if new_creates_count != before_creates_count {
+                let (old_k, new_k) = match rollup {
+                    Rollup::Hourly(cursor) => (
+                        HourlyRecordsKey::new(cursor, before_creates_count.into(), &nsid)
+                            .to_db_bytes()?,
+                        HourlyRecordsKey::new(cursor, new_creates_count.into(), &nsid)
+                            .to_db_bytes()?,
+                    ),
+                    Rollup::Weekly(cursor) => (
+                        WeeklyRecordsKey::new(cursor, before_creates_count.into(), &nsid)
+                            .to_db_bytes()?,
+                        WeeklyRecordsKey::new(cursor, new_creates_count.into(), &nsid)
+                            .to_db_bytes()?,
+                    ),
+                    Rollup::AllTime => (
+                        AllTimeRecordsKey::new(before_creates_count.into(), &nsid).to_db_bytes()?,
+                        AllTimeRecordsKey::new(new_creates_count.into(), &nsid).to_db_bytes()?,
+                    ),
+                };
+                batch.delete_cf(&rollups, old_k);
+                batch.put_cf(&rollups, new_k, "");
+            }
+            if new_dids_estimate != before_dids_estimate {
+                let (old_k, new_k) = match rollup {
+                    Rollup::Hourly(cursor) => (
+                        HourlyDidsKey::new(cursor, before_dids_estimate.into(), &nsid)
+                            .to_db_bytes()?,
+                        HourlyDidsKey::new(cursor, new_dids_estimate.into(), &nsid)
+                            .to_db_bytes()?,
+                    ),
+                    Rollup::Weekly(cursor) => (
+                        WeeklyDidsKey::new(cursor, before_dids_estimate.into(), &nsid)
+                            .to_db_bytes()?,
+                        WeeklyDidsKey::new(cursor, new_dids_estimate.into(), &nsid)
+                            .to_db_bytes()?,
+                    ),
+                    Rollup::AllTime => (
+                        AllTimeDidsKey::new(before_dids_estimate.into(), &nsid).to_db_bytes()?,
+                        AllTimeDidsKey::new(new_dids_estimate.into(), &nsid).to_db_bytes()?,
+                    ),
+                };
+                batch.delete_cf(&rollups, old_k);
+                batch.put_cf(&rollups, new_k, "");
+            }
+
+            batch.put_cf(&rollups, &rollup_key_bytes, rolled.to_db_bytes()?);
+        }
+
+        self.rocks
+            .batch_static::<NewRollupCursorKey>(&mut batch, last_cursor)?;
+
+        histogram!("storage_rollup_counts_db_batch_items").record(batch.len() as f64);
+        self.rocks.db.write(batch)?;
+        Ok((cursors_advanced, dirty_nsids))
+    }
+
+    /// Remove every record for an account, checkpointing like fjall's
+    fn delete_account_records(&self, did: &Did) -> StorageResult<usize> {
+        let progress_key = DeleteAccountProgressKey::new(did.clone()).to_db_bytes()?;
+        let progress = self
+            .rocks
+            .get(GLOBAL, &progress_key)?
+            .map(|bytes| db_complete::<DeleteAccountProgressVal>(&bytes))
+            .transpose()?;
+        let prefix = RecordLocationKey::from_prefix_to_db_bytes(did)?;
+        let end = RecordLocationKey::prefix_range_end(did)?;
+        let (start, mut total_deleted) = match progress {
+            // a checkpoint only ever resumes inside this account's own records
+            Some(DeleteAccountProgressVal {
+                records_deleted,
+                last_key,
+            }) if last_key.starts_with(&prefix) => {
+                counter!("storage_delete_account_resumed").increment(1);
+                log::info!("resuming delete for {did:?} after {records_deleted} records");
+                (Bound::Excluded(last_key), records_deleted)
+            }
+            Some(_) => {
+                log::warn!("ignoring a delete checkpoint outside {did:?}'s records");
+                (Bound::Included(prefix), 0)
+            }
+            None => (Bound::Included(prefix), 0),
+        };
+
+        let records = self.rocks.cf(RECORDS);
+        let global = self.rocks.cf(GLOBAL);
+        let mut records_deleted = 0;
+        let mut batch = WriteBatch::default();
+        for kv in self.rocks.range(RECORDS, (start, Bound::Excluded(end))) {
+            let (key_bytes, _) = kv?;
+            batch.delete_cf(&records, &key_bytes);
+            records_deleted += 1;
+            total_deleted += 1;
+            if batch.len() >= MAX_BATCHED_ACCOUNT_DELETE_RECORDS {
+                counter!("storage_delete_account_partial_commits").increment(1);
+                let progress = DeleteAccountProgressVal {
+                    records_deleted: total_deleted,
+                    last_key: key_bytes.to_vec(),
+                };
+                batch.put_cf(&global, &progress_key, progress.to_db_bytes()?);
+                self.rocks.db.write(std::mem::take(&mut batch))?;
+            }
+        }
+        counter!("storage_delete_account_completions").increment(1);
+        counter!("storage_delete_account_records_deleted").increment(records_deleted as u64);
+        batch.delete_cf(&global, progress_key);
+        self.rocks.db.write(batch)?;
+        Ok(records_deleted)
+    }
+
+    fn account_deletes_status(&self) -> StorageResult<AccountDeletesStatus> {
+        let rollup_cursor = self
+            .rocks
+            .get_static::<NewRollupCursorKey, NewRollupCursorValue>()?
+            .unwrap_or(Cursor::from_start());
+        let mut queued = 0;
+        for kv in self.rocks.range(
+            QUEUES,
+            DeleteAccountQueueKey::new(rollup_cursor).range_to_prefix_end()?,
+        ) {
+            kv?;
+            queued += 1;
+        }
+        let mut in_progress = Vec::new();
+        let prefix = DeleteAccountProgressKey::from_prefix_to_db_bytes(&Default::default())?;
+        for kv in self.rocks.prefix(GLOBAL, prefix) {
+            let (key_bytes, val_bytes) = kv?;
+            let key = db_complete::<DeleteAccountProgressKey>(&key_bytes)?;
+            let val = db_complete::<DeleteAccountProgressVal>(&val_bytes)?;
+            in_progress.push(AccountDeleteProgress {
+                did: key.did().to_string(),
+                records_deleted: val.records_deleted,
+            });
+        }
+        // the one in progress is still in the queue
+        queued = queued.saturating_sub(in_progress.len());
+        Ok(AccountDeletesStatus {
+            queued,
+            in_progress,
+        })
+    }
+
+    fn get_denylist(&self) -> Vec<DenyRule> {
+        self.denylist.read().unwrap().rules()
+    }
+
+    fn deny_collections(&self, rule: DenyRule) -> StorageResult<()> {
+        let mut denylist = self.denylist.write().unwrap();
+        self.rocks.db.put_cf(
+            &self.rocks.cf(GLOBAL),
+            DenylistKey::new(&rule.pattern).to_db_bytes()?,
+            DenylistVal {
+                keep_counts: rule.keep_counts,
+            }
+            .to_db_bytes()?,
+        )?;
+        denylist.insert(rule);
+        Ok(())
+    }
+
+    fn allow_collections(&self, pattern: &str) -> StorageResult<bool> {
+        let mut denylist = self.denylist.write().unwrap();
+        self.rocks.db.delete_cf(
+            &self.rocks.cf(GLOBAL),
+            DenylistKey::new(pattern).to_db_bytes()?,
+        )?;
+        Ok(denylist.remove(pattern))
+    }
+
+    fn record_audit(&self, mut entry: AuditEntry) -> StorageResult<()> {
+        let _guard = self.audit_lock.lock().unwrap();
+        if let Some((key_bytes, _)) = self.rocks.range_rev(AUDIT, ..).next().transpose()? {
+            let last = db_complete::<Cursor>(&key_bytes)?.to_raw_u64();
+            entry.at = entry.at.max(last + 1);
+        }
+        let val = serde_json::to_vec(&entry).map_err(EncodingError::JsonError)?;
+        self.rocks.db.put_cf(
+            &self.rocks.cf(AUDIT),
+            Cursor::from_raw_u64(entry.at).to_db_bytes()?,
+            val,
+        )?;
+        Ok(())
+    }
+
+    fn get_audit_log(&self, before: Option<u64>, limit: usize) -> StorageResult<Vec<AuditEntry>> {
+        let end = match before {
+            Some(before) => Bound::Excluded(Cursor::from_raw_u64(before).to_db_bytes()?),
+            None => Bound::Unbounded,
+        };
+        let mut entries = Vec::new();
+        for kv in self
+            .rocks
+            .range_rev(AUDIT, (Bound::Unbounded, end))
+            .take(limit)
+        {
+            let (_, val_bytes) = kv?;
+            entries.push(serde_json::from_slice(&val_bytes).map_err(EncodingError::JsonError)?);
+        }
+        Ok(entries)
+    }
+}
+
+#[async_trait]
+impl StoreAdmin for RocksWriter {
+    async fn purge_collection(
+        &self,
+        _collection: &Nsid,
+        _dry_run: bool,
+    ) -> StorageResult<PurgeReport> {
+        Err(StorageError::Unsupported("purging collections"))
+    }
+    async fn estimate_key_space(
+        &self,
+        _sample_every: u64,
+        _limit: usize,
+    ) -> StorageResult<KeySpaceReport> {
+        // rocksdb's own per-column-family estimates are in the storage stats
+        Err(StorageError::Unsupported("key space estimates"))
+    }
+    async fn rebuild_feeds(&self) -> StorageResult<RebuildFeedsReport> {
+        Err(StorageError::Unsupported("rebuilding feeds"))
+    }
+    async fn background_status(&self) -> StorageResult<BackgroundStatus> {
+        Ok(self.schedule.status())
+    }
+    async fn trigger_background(&self, task: BackgroundTask) -> StorageResult<bool> {
+        Ok(self.schedule.trigger(task))
+    }
+    async fn set_background_intervals(&self, intervals: BackgroundIntervals) -> StorageResult<()> {
+        intervals.validate().map_err(StorageError::BadStateError)?;
+        self.schedule.update(|s| s.intervals = Some(intervals));
+        self.schedule.reconfigure.notify_one();
+        Ok(())
+    }
+    async fn account_deletes_status(&self) -> StorageResult<AccountDeletesStatus> {
+        let s = self.clone();
+        server::spawn_blocking(move || RocksWriter::account_deletes_status(&s)).await?
+    }
+    async fn get_denylist(&self) -> StorageResult<Vec<DenyRule>> {
+        Ok(RocksWriter::get_denylist(self))
+    }
+    async fn deny_collections(&self, rule: DenyRule) -> StorageResult<()> {
+        let s = self.clone();
+        server::spawn_blocking(move || RocksWriter::deny_collections(&s, rule)).await?
+    }
+    async fn allow_collections(&self, pattern: &str) -> StorageResult<bool> {
+        let s = self.clone();
+        let pattern = pattern.to_string();
+        server::spawn_blocking(move || RocksWriter::allow_collections(&s, &pattern)).await?
+    }
+    async fn record_audit(&self, entry: AuditEntry) -> StorageResult<()> {
+        let s = self.clone();
+        server::spawn_blocking(move || RocksWriter::record_audit(&s, entry)).await?
+    }
+    async fn get_audit_log(
+        &self,
+        before: Option<u64>,
+        limit: usize,
+    ) -> StorageResult<Vec<AuditEntry>> {
+        let s = self.clone();
+        server::spawn_blocking(move || RocksWriter::get_audit_log(&s, before, limit)).await?
+    }
+    async fn noisy_dids(
+        &self,
+        hours: usize,
+        min_records: u64,
+        min_share: f64,
+        limit: usize,
+    ) -> StorageResult<NoisyDidsReport> {
+        Ok(self.noisy.report(hours, min_records, min_share, limit))
+    }
+}
+
+impl StoreWriter<RocksBackground> for RocksWriter {
+    type Spill = RocksSpill;
+
+    fn spill_queue(&self) -> StorageResult<RocksSpill> {
+        Ok(self.spill.clone())
+    }
+
+    fn jetstream_shard_cursor(&self, shard: &JetstreamShard) -> StorageResult<Option<Cursor>> {
+        self.rocks
+            .get(
+                GLOBAL,
+                JetstreamShardCursorKey::new(shard.id()).to_db_bytes()?,
+            )?
+            .map(|bytes| db_complete::<JetstreamCursorValue>(&bytes))
+            .transpose()
+    }
+
+    fn background_tasks(&mut self, reroll: bool) -> StorageResult<RocksBackground> {
+        if self.bg_taken.swap(true, Ordering::SeqCst) {
+            return Err(StorageError::BackgroundAlreadyStarted);
+        }
+        if reroll {
+            log::info!("reroll: resetting rollup cursor...");
+            self.rocks
+                .put_static::<NewRollupCursorKey>(Cursor::from_start())?;
+            log::info!("reroll: clearing trim cursors...");
+            let global = self.rocks.cf(GLOBAL);
+            let mut batch = WriteBatch::default();
+            for kv in self.rocks.prefix(
+                GLOBAL,
+                TrimCollectionCursorKey::from_prefix_to_db_bytes(&Default::default())?,
+            ) {
+                let (k, _) = kv?;
+                batch.delete_cf(&global, k);
+            }
+            let n = batch.len();
+            self.rocks.db.write(batch)?;
+            log::info!("reroll: cleared {n} trim cursors.");
+        }
+        Ok(RocksBackground(self.clone()))
+    }
+
+    fn insert_batch<const LIMIT: usize>(
+        &mut self,
+        mut event_batch: EventBatch<LIMIT>,
+    ) -> StorageResult<()> {
+        if event_batch.is_empty() {
+            return Ok(());
+        }
+
+        let (global, feeds, records, rollups, queues) = (
+            self.rocks.cf(GLOBAL),
+            self.rocks.cf(FEEDS),
+            self.rocks.cf(RECORDS),
+            self.rocks.cf(ROLLUPS),
+            self.rocks.cf(QUEUES),
+        );
+        let mut batch = WriteBatch::default();
+
+        let latest = event_batch.latest_cursor().unwrap();
+        // like fjall's: a lagging shard's batch can't move the live counts cursor backwards
+        let latest = if self.shards.is_empty() {
+            latest
+        } else {
+            match self
+                .rocks
+                .get_static::<JetstreamCursorKey, JetstreamCursorValue>()?
+            {
+                Some(stored) if stored > latest => stored,
+                _ => latest,
+            }
+        };
+        let shard_cursors = self.shard_cursors(&event_batch);
+        self.nsid_limits.apply(&mut event_batch);
+        self.plugins.run(latest, &event_batch);
+        let mut stored_batch = self.plugins.want_stored().then(StoredBatch::default);
+
+        let denylist = self.denylist.read().unwrap();
+
+        for (nsid, commits) in event_batch.commits_by_nsid {
+            // a denied collection's new records aren't stored, but deletes still
+            // apply, so records stored before it was denied can still go
+            let denied = denylist.check(&nsid);
+            if let Some(rule) = denied {
+                counter!("storage_denylist_skipped_commits", "keep_counts" => rule.keep_counts.to_string())
+                    .increment(commits.commits.iter().filter(|c| !c.action.is_cut()).count() as u64);
+            }
+            // only the latest commit for each record applies, same as fjall
+            let mut latest_first = commits.commits;
+            latest_first.sort_by_key(|c| std::cmp::Reverse(c.cursor.to_raw_u64()));
+            let mut seen_locations = HashSet::new();
+
+            for commit in latest_first {
+                if denied.is_some() && !commit.action.is_cut() {
+                    continue;
+                }
+                let location_key: RecordLocationKey = (&commit, &nsid).into();
+                let location_key_bytes = location_key.to_db_bytes()?;
+                if !seen_locations.insert(location_key_bytes.clone()) {
+                    counter!("storage_insert_batch_superseded_commits").increment(1);
+                    continue;
+                }
+
+                match commit.action {
+                    CommitAction::Cut => {
+                        let Some(stored) = self.rocks.get(RECORDS, &location_key_bytes)? else {
+                            continue;
+                        };
+                        let (meta, _) = RecordLocationMeta::from_db_bytes(&stored)?;
+                        if meta.cursor().to_raw_u64() > commit.cursor.to_raw_u64() {
+                            continue;
+                        }
+                        let feed_key = NsidRecordFeedKey::from_pair(nsid.clone(), meta.cursor());
+                        batch.delete_cf(&feeds, feed_key.to_db_bytes()?);
+                        batch.delete_cf(&records, &location_key_bytes);
+                        if let Some(stored_batch) = &mut stored_batch {
+                            stored_batch
+                                .removed
+                                .push((commit.did, nsid.clone(), commit.rkey));
+                        }
+                    }
+                    CommitAction::Put(put_action) => {
+                        if let Some(stored) = self.rocks.get(RECORDS, &location_key_bytes)? {
+                            let (meta, _) = RecordLocationMeta::from_db_bytes(&stored)?;
+                            if meta.cursor().to_raw_u64() > commit.cursor.to_raw_u64() {
+                                // the stored version is newer than this put (replay?)
+                                counter!("storage_insert_batch_stale_puts").increment(1);
+                                continue;
+                            }
+                        }
+                        let feed_key = NsidRecordFeedKey::from_pair(nsid.clone(), commit.cursor);
+                        let feed_val: NsidRecordFeedVal =
+                            (&commit.did, &commit.rkey, commit.rev.as_str()).into();
+                        batch.put_cf(&feeds, feed_key.to_db_bytes()?, feed_val.to_db_bytes()?);
+
+                        if let Some(stored_batch) = &mut stored_batch {
+                            stored_batch.records.push(UFOsRecord {
+                                cursor: commit.cursor,
+                                did: commit.did.clone(),
+                                collection: nsid.clone(),
+                                rkey: commit.rkey.clone(),
+                                rev: commit.rev.clone(),
+                                record: put_action.record.clone(),
+                                is_update: put_action.is_update,
+                                redaction_version: None,
+                                diff: None,
+                                transforms: None,
+                            });
+                        }
+                        let location_val: RecordLocationVal = (
+                            commit.cursor,
+                            commit.rev.as_str(),
+                            put_action,
+                            None,
+                            None,
+                            None,
+                        )
+                            .into();
+                        batch.put_cf(&records, &location_key_bytes, location_val.to_db_bytes()?);
+                    }
+                }
+            }
+            if denied.is_some_and(|rule| !rule.keep_counts) {
+                continue;
+            }
+            let live_counts_key: LiveCountsKey = (latest, &nsid).into();
+            let counts_value = CountsValue::new(
+                CommitCounts {
+                    creates: commits.creates as u64,
+                    updates: commits.updates as u64,
+                    deletes: commits.deletes as u64,
+                },
+                commits.dids_estimate,
+            );
+            batch.put_cf(
+                &rollups,
+                live_counts_key.to_db_bytes()?,
+                counts_value.to_db_bytes()?,
+            );
+            if let Some(stored_batch) = &mut stored_batch {
+                stored_batch.counts.push(CountsDelta {
+                    collection: nsid.to_string(),
+                    cursor: latest.to_raw_u64(),
+                    creates: commits.creates as u64,
+                    updates: commits.updates as u64,
+                    deletes: commits.deletes as u64,
+                });
+            }
+        }
+
+        if event_batch.overflowed_collections > 0 {
+            let key_bytes = OverflowedCollectionsKey::new(latest.into()).to_db_bytes()?;
+            let mut overflowed: OverflowedCollectionsVal = self
+                .rocks
+                .get(ROLLUPS, &key_bytes)?
+                .as_deref()
+                .map(db_complete)
+                .transpose()?
+                .unwrap_or_default();
+            overflowed.batches += event_batch.overflowed_collections as u64;
+            batch.put_cf(&rollups, key_bytes, overflowed.to_db_bytes()?);
+        }
+
+        if let Some(gap) = event_batch.cursor_gap {
+            batch.put_cf(
+                &global,
+                DbStaticStr::<CursorGapKey>::default().to_db_bytes()?,
+                CursorGapValue::from(gap).to_db_bytes()?,
+            );
+        }
+
+        if !event_batch.event_kinds.is_empty() {
+            let key_bytes = EventKindsKey::new(latest.into()).to_db_bytes()?;
+            let mut kinds: EventKindsVal = self
+                .rocks
+                .get(ROLLUPS, &key_bytes)?
+                .as_deref()
+                .map(db_complete)
+                .transpose()?
+                .unwrap_or_default();
+            kinds.commits += event_batch.event_kinds.commits;
+            kinds.identities += event_batch.event_kinds.identities;
+            kinds.accounts += event_batch.event_kinds.accounts;
+            batch.put_cf(&rollups, key_bytes, kinds.to_db_bytes()?);
+        }
+
+        for remove in event_batch.account_removes {
+            let queue_key = DeleteAccountQueueKey::new(remove.cursor);
+            let queue_val: DeleteAccountQueueVal = remove.did;
+            batch.put_cf(&queues, queue_key.to_db_bytes()?, queue_val.to_db_bytes()?);
+        }
+
+        self.spill.inserted_through(&mut batch, latest)?;
+        batch.put_cf(
+            &global,
+            DbStaticStr::<JetstreamCursorKey>::default().to_db_bytes()?,
+            latest.to_db_bytes()?,
+        );
+        for (shard, cursor) in shard_cursors {
+            batch.put_cf(
+                &global,
+                JetstreamShardCursorKey::new(shard).to_db_bytes()?,
+                cursor.to_db_bytes()?,
+            );
+        }
+
+        histogram!("storage_insert_batch_db_batch_items").record(batch.len() as f64);
+        self.rocks.db.write(batch)?;
+
+        if let Some(stored_batch) = stored_batch {
+            self.plugins.run_stored(&stored_batch);
+        }
+        Ok(())
+    }
+
+    fn step_rollup(&mut self) -> StorageResult<(usize, HashSet<Nsid>)> {
+        let mut dirty_nsids = HashSet::new();
+
+        let rollup_cursor = self
+            .rocks
+            .get_static::<NewRollupCursorKey, NewRollupCursorValue>()?
+            .ok_or(StorageError::BadStateError(
+                "Could not find current rollup cursor".to_string(),
+            ))?;
+
+        let live_counts_range = LiveCountsKey::range_from_cursor(rollup_cursor)?;
+        let mut timely_iter = self.rocks.range(ROLLUPS, live_counts_range).peekable();
+
+        let timely_next = timely_iter
+            .peek_mut()
+            .map(|kv| -> StorageResult<LiveCountsKey> {
+                match kv {
+                    Err(e) => Err(std::mem::replace(e, StorageError::Stolen))?,
+                    Ok((key_bytes, _)) => Ok(db_complete::<LiveCountsKey>(key_bytes)?),
+                }
+            })
+            .transpose()?;
+
+        let delete_accounts_range =
+            DeleteAccountQueueKey::new(rollup_cursor).range_to_prefix_end()?;
+        let next_delete = self
+            .rocks
+            .range(QUEUES, delete_accounts_range)
+            .next()
+            .transpose()?
+            .map(|(key_bytes, _)| {
+                db_complete::<DeleteAccountQueueKey>(&key_bytes).map(|k| k.suffix)
+            })
+            .transpose()?;
+
+        let cursors_stepped = match (timely_next, next_delete) {
+            (Some(timely), Some(delete_cursor)) if timely.cursor() < delete_cursor => {
+                let (n, dirty) = self.rollup_live_counts(
+                    timely_iter,
+                    Some(delete_cursor),
+                    MAX_BATCHED_ROLLUP_COUNTS,
+                )?;
+                dirty_nsids.extend(dirty);
+                n
+            }
+            (Some(_), None) => {
+                let (n, dirty) =
+                    self.rollup_live_counts(timely_iter, None, MAX_BATCHED_ROLLUP_COUNTS)?;
+                dirty_nsids.extend(dirty);
+                n
+            }
+            (timely, Some(_)) => {
+                self.rollup_delete_accounts(rollup_cursor, timely.map(|t| t.cursor()))?
+            }
+            (None, None) => 0,
+        };
+
+        Ok((cursors_stepped, dirty_nsids))
+    }
+
+    fn trim_collection(
+        &mut self,
+        collection: &Nsid,
+        limit: usize,
+        full_scan: bool,
+    ) -> StorageResult<(usize, usize, bool)> {
+        let mut dangling_feed_keys_cleaned = 0;
+        let mut records_deleted = 0;
+
+        let live_range = if full_scan {
+            let start = NsidRecordFeedKey::from_prefix_to_db_bytes(collection)?;
+            let end = NsidRecordFeedKey::prefix_range_end(collection)?;
+            start..end
+        } else {
+            let trim_cursor = self
+                .rocks
+                .get(
+                    GLOBAL,
+                    TrimCollectionCursorKey::new(collection.clone()).to_db_bytes()?,
+                )?
+                .map(|value_bytes| db_complete(&value_bytes))
+                .transpose()?
+                .unwrap_or(Cursor::from_start());
+            NsidRecordFeedKey::from_pair(collection.clone(), trim_cursor).range_to_prefix_end()?
+        };
+
+        let (feeds, records) = (self.rocks.cf(FEEDS), self.rocks.cf(RECORDS));
+
+        // newest first: keep the latest `limit` live records, cleaning up
+        // danglers among them, and find the newest feed entry to drop.
+        let mut live_records_found = 0;
+        let mut cutoff = None;
+        let mut current_cursor: Option<Cursor> = None;
+        let mut batch = WriteBatch::default();
+        for kv in self.rocks.range_rev(FEEDS, live_range.clone()) {
+            let (key_bytes, val_bytes) = kv?;
+            let feed_key = db_complete::<NsidRecordFeedKey>(&key_bytes)?;
+            let feed_val = db_complete::<NsidRecordFeedVal>(&val_bytes)?;
+            let location_key: RecordLocationKey = (&feed_key, &feed_val).into();
+            let location_key_bytes = location_key.to_db_bytes()?;
+
+            let Some(location_val_bytes) = self.rocks.get(RECORDS, &location_key_bytes)? else {
+                batch.delete_cf(&feeds, key_bytes);
+                dangling_feed_keys_cleaned += 1;
+                continue;
+            };
+
+            let (meta, _) = RecordLocationMeta::from_db_bytes(&location_val_bytes)?;
+            current_cursor = Some(meta.cursor());
+
+            if meta.cursor() != feed_key.cursor() {
+                batch.delete_cf(&feeds, key_bytes);
+                dangling_feed_keys_cleaned += 1;
+                continue;
+            }
+            if meta.rev != feed_val.rev() {
+                log::warn!("record lookup: cursor match but rev did not...? removing.");
+                batch.delete_cf(&records, location_key_bytes);
+                batch.delete_cf(&feeds, key_bytes);
+                dangling_feed_keys_cleaned += 1;
+                continue;
+            }
+
+            live_records_found += 1;
+            if live_records_found > limit {
+                cutoff = Some((key_bytes, feed_key.cursor()));
+                break;
+            }
+        }
+        self.rocks.db.write(batch)?;
+
+        // everything at or below the cutoff goes. unlike fjall, the feed
+        // entries can go with a single range tombstone: only the records
+        // they point to need deleting one by one.
+        let mut ended_early = false;
+        if let Some((cutoff_key, cutoff_cursor)) = cutoff {
+            let range = (
+                Bound::Included(live_range.start.clone()),
+                Bound::Included(cutoff_key.to_vec()),
+            );
+            let mut batch = WriteBatch::default();
+            let mut removed = 0;
+            let mut last_removed: Option<Box<[u8]>> = None;
+            for (i, kv) in self.rocks.range(FEEDS, range).enumerate() {
+                if i >= MAX_TRIM_RANGE_ITEMS {
+                    log::info!(
+                        "trim: stopping at {i} for {:?} (was at {}), will resume next time",
+                        collection.to_string(),
+                        current_cursor
+                            .map(|c| c
+                                .elapsed()
+                                .map(nice_duration)
+                                .unwrap_or("[not past]".into()))
+                            .unwrap_or("??".into()),
+                    );
+                    ended_early = true;
+                    break;
+                }
+                let (key_bytes, val_bytes) = kv?;
+                let feed_key = db_complete::<NsidRecordFeedKey>(&key_bytes)?;
+                let feed_val = db_complete::<NsidRecordFeedVal>(&val_bytes)?;
+                let location_key: RecordLocationKey = (&feed_key, &feed_val).into();
+                let location_key_bytes = location_key.to_db_bytes()?;
+                current_cursor = Some(feed_key.cursor());
+
+                let current = self
+                    .rocks
+                    .get(RECORDS, &location_key_bytes)?
+                    .map(|bytes| RecordLocationMeta::from_db_bytes(&bytes))
+                    .transpose()?
+                    .is_some_and(|(meta, _)| meta.cursor() == feed_key.cursor());
+                if current {
+                    batch.delete_cf(&records, location_key_bytes);
+                    records_deleted += 1;
+                } else {
+                    dangling_feed_keys_cleaned += 1;
+                }
+                removed += 1;
+                last_removed = Some(key_bytes);
+
+                if batch.len() >= MAX_BATCHED_TRIM_ITEMS {
+                    self.rocks.db.write(std::mem::take(&mut batch))?;
+                }
+            }
+            if let Some(last) = last_removed {
+                batch.delete_range_cf(&feeds, &live_range.start, next_key(&last));
+            }
+            self.rocks.db.write(batch)?;
+            histogram!("storage_trim_range_removed").record(removed as f64);
+
+            if !ended_early {
+                self.rocks.db.put_cf(
+                    &self.rocks.cf(GLOBAL),
+                    TrimCollectionCursorKey::new(collection.clone()).to_db_bytes()?,
+                    cutoff_cursor.to_db_bytes()?,
+                )?;
+            }
+        }
+
+        log::trace!("trim_collection ({collection:?}) removed {dangling_feed_keys_cleaned} dangling feed entries and {records_deleted} records (ended early? {ended_early})");
+        Ok((dangling_feed_keys_cleaned, records_deleted, ended_early))
+    }
+
+    fn delete_account(&mut self, did: &Did) -> StorageResult<usize> {
+        self.delete_account_records(did)
+    }
+}
+
+/// Batches consumed but not yet inserted, replayed at startup
+///
+/// Same keys and values as `FjallSpill`.
+#[derive(Clone)]
+pub struct RocksSpill {
+    rocks: Rocks,
+    next: Arc<AtomicU64>,
+}
+
+impl RocksSpill {
+    fn open(rocks: Rocks) -> StorageResult<Self> {
+        let next = match rocks.range_rev(SPILL, ..).next().transpose()? {
+            Some((key, _)) => spill_position(&key)? + 1,
+            None => 0,
+        };
+        if next > 0 {
+            log::info!("found queued batches from a previous run, they will be replayed");
+        }
+        Ok(Self {
+            rocks,
+            next: Arc::new(AtomicU64::new(next)),
+        })
+    }
+
+    /// Drop queued batches covered by an insert, in the same db batch
+    fn inserted_through(&self, batch: &mut WriteBatch, cursor: Cursor) -> StorageResult<()> {
+        let spill = self.rocks.cf(SPILL);
+        for kv in self.rocks.range(SPILL, ..) {
+            let (key, val) = kv?;
+            let (queued, _) = Cursor::from_db_bytes(&val)?;
+            if queued > cursor {
+                break;
+            }
+            batch.delete_cf(&spill, key);
+        }
+        Ok(())
+    }
+}
+
+fn spill_position(key: &[u8]) -> StorageResult<u64> {
+    let bytes: [u8; 8] = key.try_into().map_err(EncodingError::BadSlice)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+impl SpillQueue for RocksSpill {
+    fn push(&self, latest: Option<Cursor>, batch: Vec<u8>) -> StorageResult<u64> {
+        let position = self.next.fetch_add(1, Ordering::SeqCst);
+        // empty batches have no cursor: they're dropped with the next insert
+        let mut val = latest.unwrap_or(Cursor::from_start()).to_db_bytes()?;
+        val.extend(batch);
+        self.rocks
+            .db
+            .put_cf(&self.rocks.cf(SPILL), position.to_be_bytes(), val)?;
+        Ok(position)
+    }
+    fn get(&self, position: u64) -> StorageResult<Option<Vec<u8>>> {
+        let Some(val) = self.rocks.get(SPILL, position.to_be_bytes())? else {
+            return Ok(None);
+        };
+        let (_, n) = Cursor::from_db_bytes(&val)?;
+        Ok(Some(val[n..].to_vec()))
+    }
+    fn queued(&self) -> StorageResult<Vec<u64>> {
+        self.rocks
+            .range(SPILL, ..)
+            .map(|kv| spill_position(&kv?.0))
+            .collect()
+    }
+    fn latest_cursor(&self) -> StorageResult<Option<Cursor>> {
+        let Some((_, val)) = self.rocks.range_rev(SPILL, ..).next().transpose()? else {
+            return Ok(None);
+        };
+        Ok(Some(Cursor::from_db_bytes(&val)?.0))
+    }
+}
+
+pub struct RocksBackground(RocksWriter);
+
+#[async_trait]
+impl StoreBackground for RocksBackground {
+    /// Rollups and trims on the same schedule as fjall's loop
+    ///
+    /// There's no consistency sampling or scrubbing here, and nothing to trim
+    /// for the change feed or watchlists.
+    async fn run(mut self, backfill: bool) -> StorageResult<()> {
+        let mut dirty_nsids = HashSet::new();
+        // rolled up since the last trim tick, for cache invalidation
+        let mut changed_nsids = HashSet::new();
+
+        let mut intervals = self.0.schedule.intervals(backfill);
+        let mut rollup = rollup_interval(&intervals);
+        let mut trim = trim_interval(&intervals);
+
+        let schedule = ScheduleRunning::new(self.0.schedule.clone());
+
+        loop {
+            tokio::select! {
+                _ = rollup.tick() => {
+                    let mut db = self.0.clone();
+                    let (n, dirty) = tokio::task::spawn_blocking(move || db.step_rollup()).await??;
+                    counter!("storage_rollup_steps").increment(1);
+                    counter!("storage_rollup_items").increment(n as u64);
+                    let mut next_rollup = rollup.period();
+                    if n == 0 {
+                        next_rollup = Duration::from_millis(intervals.rollup_idle_ms);
+                        rollup.reset_after(next_rollup); // we're caught up, take a break
+                    }
+                    if self.0.dirty.is_some() {
+                        changed_nsids.extend(dirty.iter().cloned());
+                    }
+                    dirty_nsids.extend(dirty);
+                    log::trace!("rolled up {n} items ({} collections now dirty)", dirty_nsids.len());
+                    schedule.0.update(|s| {
+                        s.next_rollup = Some(Instant::now() + next_rollup);
+                        s.last_rollup_items = n;
+                        s.dirty_nsids = dirty_nsids.len();
+                    });
+                },
+                _ = schedule.0.rollup_now.notified() => {
+                    log::info!("rollup triggered from the admin api");
+                    rollup.reset_immediately();
+                },
+                _ = schedule.0.trim_now.notified() => {
+                    log::info!("trim triggered from the admin api");
+                    trim.reset_immediately();
+                },
+                _ = schedule.0.reconfigure.notified() => {
+                    intervals = schedule.0.intervals(backfill);
+                    log::info!("background intervals changed to {intervals:?}");
+                    rollup = rollup_interval(&intervals);
+                    rollup.reset();
+                    trim = trim_interval(&intervals);
+                    trim.reset();
+                },
+                _ = trim.tick() => {
+                    if let Some(tap) = &self.0.dirty {
+                        tap.offer(std::mem::take(&mut changed_nsids));
+                    }
+                    let n = dirty_nsids.len();
+                    log::trace!("trimming {n} nsids: {dirty_nsids:?}");
+                    let t0 = Instant::now();
+                    let (mut total_danglers, mut total_deleted) = (0, 0);
+                    let mut completed = HashSet::new();
+                    for collection in &dirty_nsids {
+                        let mut db = self.0.clone();
+                        let c = collection.clone();
+                        let limit = self.0.trim_limit(&c);
+                        let (danglers, deleted, ended_early) = tokio::task::spawn_blocking(move || db.trim_collection(&c, limit, false)).await??;
+                        total_danglers += danglers;
+                        total_deleted += deleted;
+                        if !ended_early {
+                            completed.insert(collection.clone());
+                        }
+                        if total_deleted > 10_000_000 {
+                            log::info!("trim stopped early, more than 10M records already deleted.");
+                            break;
+                        }
+                    }
+                    let dt = t0.elapsed();
+                    log::trace!("finished trimming {n} nsids in {dt:?}: {total_danglers} dangling and {total_deleted} total removed.");
+                    histogram!("storage_trim_dirty_nsids").record(completed.len() as f64);
+                    histogram!("storage_trim_duration").record(dt.as_micros() as f64);
+                    counter!("storage_trim_removed", "dangling" => "true").increment(total_danglers as u64);
+                    counter!("storage_trim_removed", "dangling" => "false").increment(total_deleted.saturating_sub(total_danglers) as u64);
+                    for c in completed {
+                        dirty_nsids.remove(&c);
+                    }
+                    let tracked = self.0.popularity.prune(Instant::now());
+                    gauge!("storage_read_popularity_tracked").set(tracked as f64);
+                    schedule.0.update(|s| {
+                        s.next_trim = Some(Instant::now() + trim.period());
+                        s.last_trim = Some(dt);
+                        s.dirty_nsids = dirty_nsids.len();
+                    });
+                },
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{RecordKey, UFOsCommit};
+    use jetstream::events::{CommitEvent, CommitOp};
+    use serde_json::value::RawValue;
+
+    const TEST_BATCH_LIMIT: usize = 16;
+    const BUDGET: ScanBudget = ScanBudget::new(1_000_000);
+
+    // rocks has no temporary mode, so the tempdir has to outlive the db
+    fn rocks_db() -> (tempfile::TempDir, RocksReader, RocksWriter) {
+        let dir = tempfile::tempdir().unwrap();
+        let (read, write, _, _) = RocksStorage::init(
+            dir.path(),
+            "offline test (no real jetstream endpoint)".to_string(),
+            false,
+            RocksConfig::default(),
+        )
+        .unwrap();
+        (dir, read, write)
+    }
+
+    fn create(
+        batch: &mut EventBatch<TEST_BATCH_LIMIT>,
+        did: &str,
+        collection: &str,
+        rkey: &str,
+        cursor: u64,
+    ) -> Nsid {
+        let event = CommitEvent {
+            collection: Nsid::new(collection.to_string()).unwrap(),
+            rkey: RecordKey::new(rkey.to_string()).unwrap(),
+            rev: "asdf".to_string(),
+            operation: CommitOp::Create,
+            record: Some(RawValue::from_string("{}".to_string()).unwrap()),
+            cid: Some(
+                "bafyreidofvwoqvd2cnzbun6dkzgfucxh57tirf3ohhde7lsvh4fu3jehgy"
+                    .parse()
+                    .unwrap(),
+            ),
+        };
+        let (commit, collection) = UFOsCommit::from_commit_info(
+            event,
+            Did::new(did.to_string()).unwrap(),
+            Cursor::from_raw_u64(cursor),
+        )
+        .unwrap();
+        batch
+            .commits_by_nsid
+            .entry(collection.clone())
+            .or_default()
+            .truncating_insert(commit, &[0u8; 16])
+            .unwrap();
+        collection
+    }
+
+    #[test]
+    fn test_insert_and_roll_up() -> anyhow::Result<()> {
+        let (_dir, read, mut write) = rocks_db();
+
+        let mut batch = EventBatch::default();
+        let collection = create(&mut batch, "did:plc:person-a", "a.b.c", "asdf", 100);
+        create(&mut batch, "did:plc:person-b", "a.b.c", "fdsa", 101);
+        write.insert_batch(batch)?;
+        write.step_rollup()?;
+
+        let JustCount {
+            creates,
+            dids_estimate,
+            ..
+        } = read.get_collection_counts(&collection, Cursor::from_start().into(), None)?;
+        assert_eq!(creates, 2);
+        assert_eq!(dids_estimate, 2);
+
+        let (records, _, _) =
+            read.get_records_by_collections([collection].into(), 10, false, BUDGET)?;
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].rkey.as_str(), "fdsa");
+        assert!(!records[0].is_update);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_account() -> anyhow::Result<()> {
+        let (_dir, read, mut write) = rocks_db();
+
+        let mut batch = EventBatch::default();
+        let collection = create(&mut batch, "did:plc:person-a", "a.a.a", "rkey-aaa", 10_000);
+        for i in 1..=2 {
+            create(
+                &mut batch,
+                "did:plc:person-b",
+                "a.a.a",
+                &format!("rkey-bbb-{i}"),
+                11_000 + i,
+            );
+        }
+        write.insert_batch(batch)?;
+
+        let records_deleted =
+            write.delete_account(&Did::new("did:plc:person-b".to_string()).unwrap())?;
+        assert_eq!(records_deleted, 2);
+
+        let (records, _, _) =
+            read.get_records_by_collections([collection].into(), 100, false, BUDGET)?;
+        assert_eq!(records.len(), 1);
+
+        Ok(())
+    }
+}
@@ -1,11 +1,13 @@
 use crate::db_types::{
-    DbBytes, DbConcat, DbStaticStr, EncodingError, EncodingResult, SerdeBytes, StaticStr,
-    UseBincodePlz,
+    bincode_conf, DbBytes, DbConcat, DbStaticStr, EncodingError, EncodingResult, SerdeBytes,
+    StaticStr, UseBincodePlz,
 };
-use crate::{Cursor, Did, JustCount, Nsid, PutAction, RecordKey, UFOsCommit};
+use crate::error::WindowError;
+use crate::{Cursor, CursorGap, Did, JustCount, Nsid, PutAction, RecordKey, UFOsCommit};
 use bincode::{Decode, Encode};
-use cardinality_estimator_safe::Sketch;
+use microcosm_estimates::DidsSketch;
 use std::ops::{Bound, Range};
+use ufos_core::keys::{ALL_TIME_ROLLUP_PREFIX, HOURLY_ROLLUP_PREFIX, WEEKLY_ROLLUP_PREFIX};
 
 macro_rules! static_str {
     ($prefix:expr, $name:ident) => {
@@ -23,9 +25,19 @@ macro_rules! static_str {
 static_str!("js_cursor", JetstreamCursorKey);
 pub type JetstreamCursorValue = Cursor;
 
+static_str!("js_shard_cursor", _JetstreamShardCursorStaticStr);
+type JetstreamShardCursorPrefix = DbStaticStr<_JetstreamShardCursorStaticStr>;
+/// key format: ["js_shard_cursor"|shard id(String)]
+pub type JetstreamShardCursorKey = DbConcat<JetstreamShardCursorPrefix, String>;
+impl JetstreamShardCursorKey {
+    pub fn new(shard_id: String) -> Self {
+        Self::from_pair(Default::default(), shard_id)
+    }
+}
+
 // key format: ["sketch_secret"]
 static_str!("sketch_secret", SketchSecretKey);
-pub type SketchSecretPrefix = [u8; 16];
+pub type SketchSecretPrefix = microcosm_estimates::SketchSecret;
 
 // key format: ["rollup_cursor"]
 static_str!("rollup_cursor", NewRollupCursorKey);
@@ -43,6 +55,55 @@ impl TrimCollectionCursorKey {
 }
 pub type TrimCollectionCursorVal = Cursor;
 
+static_str!("denylist", _DenylistStaticStr);
+type DenylistPrefix = DbStaticStr<_DenylistStaticStr>;
+/// key format: ["denylist"|pattern(String)]
+pub type DenylistKey = DbConcat<DenylistPrefix, String>;
+impl DenylistKey {
+    pub fn new(pattern: &str) -> Self {
+        Self::from_pair(Default::default(), pattern.to_string())
+    }
+    pub fn pattern(&self) -> &str {
+        &self.suffix
+    }
+}
+#[derive(Debug, PartialEq, Encode, Decode)]
+pub struct DenylistVal {
+    pub keep_counts: bool,
+}
+impl UseBincodePlz for DenylistVal {}
+
+// key format: ["changes_trimmed"]
+static_str!("changes_trimmed", ChangesTrimmedKey);
+/// value format: [latest cursor of the last batch trimmed from the change feed]
+pub type ChangesTrimmedValue = Cursor;
+
+// key format: ["rebuild_feeds"]
+static_str!("rebuild_feeds", RebuildFeedsKey);
+/// value format: [records key of the last record rebuilt into the feeds (empty before the first)]
+pub type RebuildFeedsValue = Vec<u8>;
+
+// key format: ["cursor_gap"]
+static_str!("cursor_gap", CursorGapKey);
+/// value format: [last cursor before the gap(Cursor)|first cursor after it(Cursor)]
+pub type CursorGapValue = DbConcat<Cursor, Cursor>;
+impl From<CursorGap> for CursorGapValue {
+    fn from(gap: CursorGap) -> Self {
+        Self::from_pair(
+            Cursor::from_raw_u64(gap.last_seen),
+            Cursor::from_raw_u64(gap.resumed_at),
+        )
+    }
+}
+impl From<CursorGapValue> for CursorGap {
+    fn from(v: CursorGapValue) -> Self {
+        Self {
+            last_seen: v.prefix.to_raw_u64(),
+            resumed_at: v.suffix.to_raw_u64(),
+        }
+    }
+}
+
 // key format: ["js_endpoint"]
 static_str!("takeoff", TakeoffKey);
 pub type TakeoffValue = Cursor;
@@ -132,18 +193,100 @@ impl From<(&NsidRecordFeedKey, &NsidRecordFeedVal)> for RecordLocationKey {
     }
 }
 
+/// An older version of a record, for collections that keep version history
+///
+/// The record's location is a prefix, so its versions sort right after it,
+/// oldest first. The value is the old [`RecordLocationVal`], as it was stored.
+pub type RecordVersionKey = DbConcat<RecordLocationKey, Cursor>;
+
 #[derive(Debug, PartialEq, Encode, Decode)]
 pub struct RecordLocationMeta {
     cursor: u64, // ugh no bincode impl
     pub is_update: bool,
     pub rev: String,
+    /// version of the redaction config that was applied to the record, if any
+    pub redaction_version: Option<u32>,
+    /// json merge patch from the previously stored version, for updates
+    pub diff: Option<String>,
+    /// names of the configured transforms that ran on the record, in order
+    pub transforms: Option<Vec<String>>,
 }
 impl RecordLocationMeta {
     pub fn cursor(&self) -> Cursor {
         Cursor::from_raw_u64(self.cursor)
     }
 }
-impl UseBincodePlz for RecordLocationMeta {}
+/// Record meta as it was stored before transforms were added
+#[derive(Debug, PartialEq, Encode, Decode)]
+struct DiffedRecordLocationMeta {
+    cursor: u64,
+    is_update: bool,
+    rev: String,
+    redaction_version: Option<u32>,
+    diff: Option<String>,
+}
+impl UseBincodePlz for DiffedRecordLocationMeta {}
+/// Record meta as it was stored before diffs were added
+#[derive(Debug, PartialEq, Encode, Decode)]
+struct RedactedRecordLocationMeta {
+    cursor: u64,
+    is_update: bool,
+    rev: String,
+    redaction_version: Option<u32>,
+}
+impl UseBincodePlz for RedactedRecordLocationMeta {}
+/// Record meta as it was stored before redaction versions were added
+#[derive(Debug, PartialEq, Encode, Decode)]
+struct LegacyRecordLocationMeta {
+    cursor: u64,
+    is_update: bool,
+    rev: String,
+}
+impl UseBincodePlz for LegacyRecordLocationMeta {}
+impl DbBytes for RecordLocationMeta {
+    fn to_db_bytes(&self) -> EncodingResult<Vec<u8>> {
+        Ok(bincode::encode_to_vec(self, bincode_conf())?)
+    }
+    fn from_db_bytes(bytes: &[u8]) -> Result<(Self, usize), EncodingError> {
+        // older metas are followed directly by the record json, whose first
+        // byte is never a valid Option tag, so they can't be confused.
+        if let Ok(decoded) = bincode::decode_from_slice(bytes, bincode_conf()) {
+            return Ok(decoded);
+        }
+        if let Ok((diffed, n)) = DiffedRecordLocationMeta::from_db_bytes(bytes) {
+            let meta = Self {
+                cursor: diffed.cursor,
+                is_update: diffed.is_update,
+                rev: diffed.rev,
+                redaction_version: diffed.redaction_version,
+                diff: diffed.diff,
+                transforms: None,
+            };
+            return Ok((meta, n));
+        }
+        if let Ok((redacted, n)) = RedactedRecordLocationMeta::from_db_bytes(bytes) {
+            let meta = Self {
+                cursor: redacted.cursor,
+                is_update: redacted.is_update,
+                rev: redacted.rev,
+                redaction_version: redacted.redaction_version,
+                diff: None,
+                transforms: None,
+            };
+            return Ok((meta, n));
+        }
+        let (legacy, n) = LegacyRecordLocationMeta::from_db_bytes(bytes)?;
+        let meta = Self {
+            cursor: legacy.cursor,
+            is_update: legacy.is_update,
+            rev: legacy.rev,
+            redaction_version: None,
+            diff: None,
+            transforms: None,
+        };
+        Ok((meta, n))
+    }
+}
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct RecordRawValue(Vec<u8>);
@@ -171,12 +314,25 @@ impl TryFrom<RecordRawValue> for Box<serde_json::value::RawValue> {
 }
 
 pub type RecordLocationVal = DbConcat<RecordLocationMeta, RecordRawValue>;
-impl From<(Cursor, &str, PutAction)> for RecordLocationVal {
-    fn from((cursor, rev, put): (Cursor, &str, PutAction)) -> Self {
+type RecordLocationValParts<'a> = (
+    Cursor,
+    &'a str,
+    PutAction,
+    Option<u32>,
+    Option<String>,
+    Option<Vec<String>>,
+);
+impl From<RecordLocationValParts<'_>> for RecordLocationVal {
+    fn from(
+        (cursor, rev, put, redaction_version, diff, transforms): RecordLocationValParts<'_>,
+    ) -> Self {
         let meta = RecordLocationMeta {
             cursor: cursor.to_raw_u64(),
             is_update: put.is_update,
             rev: rev.to_string(),
+            redaction_version,
+            diff,
+            transforms,
         };
         Self::from_pair(meta, put.record.into())
     }
@@ -210,23 +366,11 @@ impl From<(Cursor, &Nsid)> for LiveCountsKey {
     }
 }
 
-#[derive(Debug, Clone, Copy, Default, PartialEq, Decode, Encode)]
-pub struct CommitCounts {
-    pub creates: u64,
-    pub updates: u64,
-    pub deletes: u64,
-}
-impl CommitCounts {
-    pub fn merge(&mut self, other: &Self) {
-        self.creates += other.creates;
-        self.updates += other.updates;
-        self.deletes += other.deletes;
-    }
-}
+pub use ufos_core::counts::CommitCounts;
 impl UseBincodePlz for CommitCounts {}
 
 #[derive(Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
-pub struct EstimatedDidsValue(pub Sketch<14>);
+pub struct EstimatedDidsValue(pub DidsSketch);
 impl SerdeBytes for EstimatedDidsValue {}
 impl DbBytes for EstimatedDidsValue {
     #[cfg(test)]
@@ -250,7 +394,7 @@ impl DbBytes for EstimatedDidsValue {
 
 pub type CountsValue = DbConcat<CommitCounts, EstimatedDidsValue>;
 impl CountsValue {
-    pub fn new(counts: CommitCounts, dids: Sketch<14>) -> Self {
+    pub fn new(counts: CommitCounts, dids: DidsSketch) -> Self {
         Self {
             prefix: counts,
             suffix: EstimatedDidsValue(dids),
@@ -259,13 +403,26 @@ impl CountsValue {
     pub fn counts(&self) -> CommitCounts {
         self.prefix
     }
-    pub fn dids(&self) -> &Sketch<14> {
+    pub fn dids(&self) -> &DidsSketch {
         &self.suffix.0
     }
     pub fn merge(&mut self, other: &Self) {
         self.prefix.merge(&other.prefix);
         self.suffix.0.merge(&other.suffix.0);
     }
+    /// Scale the counts by `num / den`, rounding down. The dids sketch can't be
+    /// split, so it's kept whole.
+    pub fn scaled(&self, num: u64, den: u64) -> Self {
+        let scale = |n: u64| ((n as u128 * num as u128) / den.max(1) as u128) as u64;
+        let mut out = Self::default();
+        out.suffix.0.merge(&self.suffix.0);
+        out.prefix = CommitCounts {
+            creates: scale(self.prefix.creates),
+            updates: scale(self.prefix.updates),
+            deletes: scale(self.prefix.deletes),
+        };
+        out
+    }
 }
 impl From<&CountsValue> for JustCount {
     fn from(cv: &CountsValue) -> Self {
@@ -278,7 +435,7 @@ impl From<&CountsValue> for JustCount {
             creates,
             updates,
             deletes,
-            dids_estimate: cv.dids().estimate() as u64,
+            dids_estimate: microcosm_estimates::estimate(cv.dids()),
         }
     }
 }
@@ -293,6 +450,28 @@ impl DeleteAccountQueueKey {
 }
 pub type DeleteAccountQueueVal = Did;
 
+static_str!("delete_progress", _DeleteAccountProgressStaticStr);
+type DeleteAccountProgressPrefix = DbStaticStr<_DeleteAccountProgressStaticStr>;
+/// key format: ["delete_progress"|did(Did)]
+pub type DeleteAccountProgressKey = DbConcat<DeleteAccountProgressPrefix, Did>;
+impl DeleteAccountProgressKey {
+    pub fn new(did: Did) -> Self {
+        Self::from_pair(Default::default(), did)
+    }
+    pub fn did(&self) -> &Did {
+        &self.suffix
+    }
+}
+/// Checkpoint for an account delete that took more than one db batch
+#[derive(Debug, PartialEq, Encode, Decode)]
+pub struct DeleteAccountProgressVal {
+    /// Records removed so far, over every run
+    pub records_deleted: u64,
+    /// Records key of the last record removed, to resume after
+    pub last_key: Vec<u8>,
+}
+impl UseBincodePlz for DeleteAccountProgressVal {}
+
 /// big-endian encoded u64 for LSM prefix-fiendly key
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct KeyRank(u64);
@@ -320,6 +499,20 @@ impl From<KeyRank> for u64 {
     }
 }
 
+/// Continuation point in a ranked collection listing: the last (rank, nsid) returned
+pub type RankCursor = DbConcat<KeyRank, Nsid>;
+impl RankCursor {
+    pub fn new(rank: KeyRank, nsid: &Nsid) -> Self {
+        Self::from_pair(rank, nsid.clone())
+    }
+    pub fn rank(&self) -> KeyRank {
+        self.prefix
+    }
+    pub fn nsid(&self) -> &Nsid {
+        &self.suffix
+    }
+}
+
 pub type BucketedRankRecordsKey<P, C> =
     DbConcat<DbConcat<DbStaticStr<P>, C>, DbConcat<KeyRank, Nsid>>;
 impl<P, C> BucketedRankRecordsKey<P, C>
@@ -344,6 +537,12 @@ where
         let prefix: DbConcat<DbStaticStr<P>, C> = DbConcat::from_pair(Default::default(), cursor);
         Ok(Bound::Excluded(Self::prefix_range_end(&prefix)?))
     }
+    /// end bound for a reverse scan that continues after (`rank`, `nsid`)
+    pub fn end_before(cursor: C, rank: KeyRank, nsid: &Nsid) -> EncodingResult<Bound<Vec<u8>>> {
+        Ok(Bound::Excluded(
+            Self::new(cursor, rank, nsid).to_db_bytes()?,
+        ))
+    }
 }
 impl<P: StaticStr, C: DbBytes> WithCollection for BucketedRankRecordsKey<P, C> {
     fn collection(&self) -> &Nsid {
@@ -356,7 +555,7 @@ impl<P: StaticStr, C: DbBytes> WithRank for BucketedRankRecordsKey<P, C> {
     }
 }
 
-static_str!("hourly_counts", _HourlyRollupStaticStr);
+static_str!(HOURLY_ROLLUP_PREFIX, _HourlyRollupStaticStr);
 pub type HourlyRollupStaticPrefix = DbStaticStr<_HourlyRollupStaticStr>;
 pub type HourlyRollupKeyHourPrefix = DbConcat<HourlyRollupStaticPrefix, HourTruncatedCursor>;
 pub type HourlyRollupKey = DbConcat<HourlyRollupKeyHourPrefix, Nsid>;
@@ -419,7 +618,7 @@ pub type HourlyRecordsKey = BucketedRankRecordsKey<_HourlyRecordsStaticStr, Hour
 static_str!("hourly_rank_dids", _HourlyDidsStaticStr);
 pub type HourlyDidsKey = BucketedRankRecordsKey<_HourlyDidsStaticStr, HourTruncatedCursor>;
 
-static_str!("weekly_counts", _WeeklyRollupStaticStr);
+static_str!(WEEKLY_ROLLUP_PREFIX, _WeeklyRollupStaticStr);
 pub type WeeklyRollupStaticPrefix = DbStaticStr<_WeeklyRollupStaticStr>;
 pub type WeeklyRollupKeyWeekPrefix = DbConcat<WeeklyRollupStaticPrefix, WeekTruncatedCursor>;
 pub type WeeklyRollupKey = DbConcat<WeeklyRollupKeyWeekPrefix, Nsid>;
@@ -482,7 +681,7 @@ pub type WeeklyRecordsKey = BucketedRankRecordsKey<_WeeklyRecordsStaticStr, Week
 static_str!("weekly_rank_dids", _WeeklyDidsStaticStr);
 pub type WeeklyDidsKey = BucketedRankRecordsKey<_WeeklyDidsStaticStr, WeekTruncatedCursor>;
 
-static_str!("ever_counts", _AllTimeRollupStaticStr);
+static_str!(ALL_TIME_ROLLUP_PREFIX, _AllTimeRollupStaticStr);
 pub type AllTimeRollupStaticPrefix = DbStaticStr<_AllTimeRollupStaticStr>;
 pub type AllTimeRollupKey = DbConcat<AllTimeRollupStaticPrefix, Nsid>;
 pub type AllTimeRollupPre = DbConcat<AllTimeRollupStaticPrefix, Vec<u8>>;
@@ -548,6 +747,10 @@ where
             Self::prefix_range_end(&Default::default())?,
         ))
     }
+    /// end bound for a reverse scan that continues after (`rank`, `nsid`)
+    pub fn end_before(rank: KeyRank, nsid: &Nsid) -> EncodingResult<Bound<Vec<u8>>> {
+        Ok(Bound::Excluded(Self::new(rank, nsid).to_db_bytes()?))
+    }
 }
 impl<P: StaticStr> WithCollection for AllTimeRankRecordsKey<P> {
     fn collection(&self) -> &Nsid {
@@ -566,6 +769,178 @@ pub type AllTimeRecordsKey = AllTimeRankRecordsKey<_AllTimeRecordsStaticStr>;
 static_str!("ever_rank_dids", _AllTimeDidsStaticStr);
 pub type AllTimeDidsKey = AllTimeRankRecordsKey<_AllTimeDidsStaticStr>;
 
+static_str!("first_seen", _CollectionFirstSeenStaticStr);
+pub type CollectionFirstSeenKey = DbConcat<DbStaticStr<_CollectionFirstSeenStaticStr>, Nsid>;
+impl CollectionFirstSeenKey {
+    pub fn new(nsid: &Nsid) -> Self {
+        Self::from_pair(Default::default(), nsid.clone())
+    }
+}
+pub type CollectionFirstSeenVal = Cursor;
+
+static_str!("overflowed_collections", _OverflowedCollectionsStaticStr);
+pub type OverflowedCollectionsKey =
+    DbConcat<DbStaticStr<_OverflowedCollectionsStaticStr>, HourTruncatedCursor>;
+impl OverflowedCollectionsKey {
+    pub fn new(hour: HourTruncatedCursor) -> Self {
+        Self::from_pair(Default::default(), hour)
+    }
+    pub fn hour(&self) -> HourTruncatedCursor {
+        self.suffix
+    }
+}
+#[derive(Debug, Default, PartialEq, Encode, Decode)]
+pub struct OverflowedCollectionsVal {
+    /// how many batches were sent early because they had too many collections
+    pub batches: u64,
+}
+impl UseBincodePlz for OverflowedCollectionsVal {}
+
+static_str!("event_kinds", _EventKindsStaticStr);
+pub type EventKindsKey = DbConcat<DbStaticStr<_EventKindsStaticStr>, HourTruncatedCursor>;
+impl EventKindsKey {
+    pub fn new(hour: HourTruncatedCursor) -> Self {
+        Self::from_pair(Default::default(), hour)
+    }
+    pub fn hour(&self) -> HourTruncatedCursor {
+        self.suffix
+    }
+}
+#[derive(Debug, Default, PartialEq, Encode, Decode)]
+pub struct EventKindsVal {
+    pub commits: u64,
+    pub identities: u64,
+    pub accounts: u64,
+}
+impl UseBincodePlz for EventKindsVal {}
+
+static_str!("watch_hit", _WatchHitStaticStr);
+pub type WatchHitPrefix = DbConcat<DbStaticStr<_WatchHitStaticStr>, String>;
+pub type WatchHitCursorPrefix = DbConcat<WatchHitPrefix, Cursor>;
+/// key format: ["watch_hit"|watchlist(String)|js_cursor|did|collection|rkey]
+pub type WatchHitKey = DbConcat<WatchHitCursorPrefix, RecordLocationKey>;
+impl WatchHitKey {
+    pub fn new(name: &str, cursor: Cursor, location: RecordLocationKey) -> Self {
+        Self::from_pair(Self::at(name, cursor), location)
+    }
+    pub fn prefix(name: &str) -> WatchHitPrefix {
+        WatchHitPrefix::from_pair(Default::default(), name.to_string())
+    }
+    /// For range bounds: sorts before every hit at or after the cursor
+    pub fn at(name: &str, cursor: Cursor) -> WatchHitCursorPrefix {
+        WatchHitCursorPrefix::from_pair(Self::prefix(name), cursor)
+    }
+    pub fn cursor(&self) -> Cursor {
+        self.prefix.suffix
+    }
+    pub fn location(&self) -> &RecordLocationKey {
+        &self.suffix
+    }
+}
+#[derive(Debug, PartialEq, Encode, Decode)]
+pub struct WatchHitVal {
+    /// the keywords and regexes that matched
+    pub matched: Vec<String>,
+}
+impl UseBincodePlz for WatchHitVal {}
+
+static_str!("watch_hourly", _WatchHourlyStaticStr);
+pub type WatchHourlyPrefix = DbConcat<DbStaticStr<_WatchHourlyStaticStr>, String>;
+/// key format: ["watch_hourly"|watchlist(String)|hour]
+pub type WatchHourlyKey = DbConcat<WatchHourlyPrefix, HourTruncatedCursor>;
+impl WatchHourlyKey {
+    pub fn new(name: &str, hour: HourTruncatedCursor) -> Self {
+        Self::from_pair(Self::prefix(name), hour)
+    }
+    pub fn prefix(name: &str) -> WatchHourlyPrefix {
+        WatchHourlyPrefix::from_pair(Default::default(), name.to_string())
+    }
+    pub fn hour(&self) -> HourTruncatedCursor {
+        self.suffix
+    }
+}
+#[derive(Debug, Default, PartialEq, Encode, Decode)]
+pub struct WatchHourlyVal {
+    pub hits: u64,
+}
+impl UseBincodePlz for WatchHourlyVal {}
+
+static_str!("unique_seen", _UniqueSeenStaticStr);
+pub type UniqueSeenPrefix = DbConcat<DbStaticStr<_UniqueSeenStaticStr>, Nsid>;
+/// key format: ["unique_seen"|collection|did|rkey]
+pub type UniqueSeenKey = DbConcat<UniqueSeenPrefix, DbConcat<Did, RecordKey>>;
+impl UniqueSeenKey {
+    pub fn new(collection: &Nsid, did: &Did, rkey: &RecordKey) -> Self {
+        Self::from_pair(
+            Self::prefix(collection),
+            DbConcat::from_pair(did.clone(), rkey.clone()),
+        )
+    }
+    pub fn prefix(collection: &Nsid) -> UniqueSeenPrefix {
+        UniqueSeenPrefix::from_pair(Default::default(), collection.clone())
+    }
+}
+
+static_str!("unique_hourly", _UniqueHourlyStaticStr);
+pub type UniqueHourlyPrefix = DbConcat<DbStaticStr<_UniqueHourlyStaticStr>, Nsid>;
+/// key format: ["unique_hourly"|collection|hour]
+pub type UniqueHourlyKey = DbConcat<UniqueHourlyPrefix, HourTruncatedCursor>;
+impl UniqueHourlyKey {
+    pub fn new(collection: &Nsid, hour: HourTruncatedCursor) -> Self {
+        Self::from_pair(Self::prefix(collection), hour)
+    }
+    pub fn prefix(collection: &Nsid) -> UniqueHourlyPrefix {
+        UniqueHourlyPrefix::from_pair(Default::default(), collection.clone())
+    }
+}
+#[derive(Debug, Default, PartialEq, Encode, Decode)]
+pub struct UniqueHourlyVal {
+    /// creates of records never seen before, plus all the unchecked ones
+    pub records: u64,
+    /// creates dropped from their batch before they could be checked
+    pub unchecked: u64,
+}
+impl UseBincodePlz for UniqueHourlyVal {}
+
+static_str!("deleted_at", _DeletedAtStaticStr);
+/// key format: ["deleted_at"|did|collection|rkey]
+pub type RecordTombstoneKey = DbConcat<DbStaticStr<_DeletedAtStaticStr>, RecordLocationKey>;
+impl RecordTombstoneKey {
+    pub fn new(location: RecordLocationKey) -> Self {
+        Self::from_pair(Default::default(), location)
+    }
+}
+/// the cursor of the delete
+pub type RecordTombstoneVal = Cursor;
+
+static_str!("deleted_expiry", _DeletedExpiryStaticStr);
+pub type TombstoneExpiryPrefix = DbConcat<DbStaticStr<_DeletedExpiryStaticStr>, Cursor>;
+/// key format: ["deleted_expiry"|js_cursor|did|collection|rkey]
+pub type TombstoneExpiryKey = DbConcat<TombstoneExpiryPrefix, RecordLocationKey>;
+impl TombstoneExpiryKey {
+    pub fn new(cursor: Cursor, location: RecordLocationKey) -> Self {
+        Self::from_pair(Self::at(cursor), location)
+    }
+    /// For range bounds: sorts before every tombstone at or after the cursor
+    pub fn at(cursor: Cursor) -> TombstoneExpiryPrefix {
+        TombstoneExpiryPrefix::from_pair(Default::default(), cursor)
+    }
+    pub fn cursor(&self) -> Cursor {
+        self.prefix.suffix
+    }
+    pub fn into_location(self) -> RecordLocationKey {
+        self.suffix
+    }
+}
+
+/// key format: [partition(String)|original key(bytes)]
+pub type QuarantineKey = DbConcat<String, Vec<u8>>;
+impl QuarantineKey {
+    pub fn new(partition: &str, key: Vec<u8>) -> Self {
+        Self::from_pair(partition.to_string(), key)
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Hash, PartialOrd, Eq)]
 pub struct TruncatedCursor<const MOD: u64>(u64);
 impl<const MOD: u64> TruncatedCursor<MOD> {
@@ -615,6 +990,15 @@ impl<const MOD: u64> TruncatedCursor<MOD> {
         }
         Self(self.0 - MOD)
     }
+    /// Round down to the start of a `period`-long bucket, counting buckets from
+    /// the unix epoch shifted by `offset` (both in microseconds)
+    ///
+    /// Fails if the bucket start isn't itself truncated to `MOD`.
+    pub fn bucket_start(&self, period: u64, offset: i64) -> Result<Self, EncodingError> {
+        let shifted = self.0 as i128 - offset as i128;
+        let start = shifted.div_euclid(period as i128) * period as i128 + offset as i128;
+        Self::try_from_raw_u64(start.max(0) as u64)
+    }
 }
 impl<const MOD: u64> From<TruncatedCursor<MOD>> for Cursor {
     fn from(truncated: TruncatedCursor<MOD>) -> Self {
@@ -626,6 +1010,18 @@ impl<const MOD: u64> From<Cursor> for TruncatedCursor<MOD> {
         Self::truncate_cursor(cursor)
     }
 }
+impl<const MOD: u64> TryFrom<u64> for TruncatedCursor<MOD> {
+    type Error = EncodingError;
+    /// Unlike from a [`Cursor`], raw values must already be aligned
+    fn try_from(time_us: u64) -> Result<Self, Self::Error> {
+        Self::try_from_raw_u64(time_us)
+    }
+}
+impl<const MOD: u64> From<TruncatedCursor<MOD>> for u64 {
+    fn from(truncated: TruncatedCursor<MOD>) -> Self {
+        truncated.0
+    }
+}
 impl<const MOD: u64> DbBytes for TruncatedCursor<MOD> {
     fn to_db_bytes(&self) -> Result<Vec<u8>, EncodingError> {
         let as_cursor: Cursor = (*self).into();
@@ -644,7 +1040,61 @@ pub type HourTruncatedCursor = TruncatedCursor<HOUR_IN_MICROS>;
 pub const WEEK_IN_MICROS: u64 = HOUR_IN_MICROS * 24 * 7;
 pub type WeekTruncatedCursor = TruncatedCursor<WEEK_IN_MICROS>;
 
-#[derive(Debug, PartialEq)]
+/// Whole hours from `since` (inclusive) to `until` (exclusive), open where `None`
+///
+/// Windows can't end before they start. One that starts and ends on the same
+/// hour is empty.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct HourWindow {
+    since: Option<HourTruncatedCursor>,
+    until: Option<HourTruncatedCursor>,
+}
+impl HourWindow {
+    pub fn new(
+        since: Option<HourTruncatedCursor>,
+        until: Option<HourTruncatedCursor>,
+    ) -> Result<Self, WindowError> {
+        if let (Some(since), Some(until)) = (since, until) {
+            if until < since {
+                return Err(WindowError::Backwards {
+                    since: since.to_raw_u64(),
+                    until: until.to_raw_u64(),
+                });
+            }
+        }
+        Ok(Self { since, until })
+    }
+    /// Every hour ever tracked
+    pub fn all_time() -> Self {
+        Self::default()
+    }
+    /// From an hour on, with no end
+    pub fn starting(since: HourTruncatedCursor) -> Self {
+        Self {
+            since: Some(since),
+            until: None,
+        }
+    }
+    /// The hours holding two exact times: each is rounded down to its hour
+    pub fn covering(since: Option<Cursor>, until: Option<Cursor>) -> Result<Self, WindowError> {
+        Self::new(since.map(Into::into), until.map(Into::into))
+    }
+    /// From raw microsecond bounds, which must already be on an hour
+    pub fn try_from_raw(since: Option<u64>, until: Option<u64>) -> Result<Self, WindowError> {
+        Self::new(
+            since.map(TryInto::try_into).transpose()?,
+            until.map(TryInto::try_into).transpose()?,
+        )
+    }
+    pub fn since(&self) -> Option<HourTruncatedCursor> {
+        self.since
+    }
+    pub fn until(&self) -> Option<HourTruncatedCursor> {
+        self.until
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum CursorBucket {
     Hour(HourTruncatedCursor),
     Week(WeekTruncatedCursor),
@@ -679,8 +1129,10 @@ impl CursorBucket {
 #[cfg(test)]
 mod test {
     use super::{
-        CommitCounts, CountsValue, Cursor, CursorBucket, Did, EncodingError, HourTruncatedCursor,
-        HourlyRollupKey, Nsid, Sketch, HOUR_IN_MICROS, WEEK_IN_MICROS,
+        CommitCounts, CountsValue, Cursor, CursorBucket, Did, DidsSketch, DiffedRecordLocationMeta,
+        EncodingError, HourTruncatedCursor, HourWindow, HourlyRollupKey, LegacyRecordLocationMeta,
+        Nsid, RecordLocationMeta, RecordLocationVal, RecordRawValue, RedactedRecordLocationMeta,
+        HOUR_IN_MICROS, WEEK_IN_MICROS,
     };
     use crate::db_types::DbBytes;
     use cardinality_estimator_safe::Element;
@@ -702,9 +1154,76 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_legacy_record_location_meta() -> Result<(), EncodingError> {
+        let legacy = LegacyRecordLocationMeta {
+            cursor: 1_700_000_000_000_000,
+            is_update: true,
+            rev: "rev-a".to_string(),
+        };
+        let mut bytes = legacy.to_db_bytes()?;
+        bytes.extend_from_slice(br#"{"a":1}"#);
+
+        let (val, n) = RecordLocationVal::from_db_bytes(&bytes)?;
+        assert_eq!(n, bytes.len());
+        assert_eq!(
+            val.prefix,
+            RecordLocationMeta {
+                cursor: 1_700_000_000_000_000,
+                is_update: true,
+                rev: "rev-a".to_string(),
+                redaction_version: None,
+                diff: None,
+                transforms: None,
+            }
+        );
+        assert_eq!(val.suffix, RecordRawValue(br#"{"a":1}"#.to_vec()));
+
+        let redacted = RedactedRecordLocationMeta {
+            cursor: 1_700_000_000_000_000,
+            is_update: true,
+            rev: "rev-a".to_string(),
+            redaction_version: Some(3),
+        };
+        let mut bytes = redacted.to_db_bytes()?;
+        bytes.extend_from_slice(br#"{"a":1}"#);
+        let (val, _) = RecordLocationVal::from_db_bytes(&bytes)?;
+        assert_eq!(val.prefix.redaction_version, Some(3));
+        assert_eq!(val.prefix.diff, None);
+        assert_eq!(val.suffix, RecordRawValue(br#"{"a":1}"#.to_vec()));
+
+        let diffed = DiffedRecordLocationMeta {
+            cursor: 1_700_000_000_000_000,
+            is_update: true,
+            rev: "rev-a".to_string(),
+            redaction_version: Some(3),
+            diff: Some(r#"{"a":1}"#.to_string()),
+        };
+        let mut bytes = diffed.to_db_bytes()?;
+        bytes.extend_from_slice(br#"{"a":1}"#);
+        let (val, _) = RecordLocationVal::from_db_bytes(&bytes)?;
+        assert_eq!(val.prefix.redaction_version, Some(3));
+        assert_eq!(val.prefix.diff, Some(r#"{"a":1}"#.to_string()));
+        assert_eq!(val.prefix.transforms, None);
+        assert_eq!(val.suffix, RecordRawValue(br#"{"a":1}"#.to_vec()));
+
+        let current = RecordLocationMeta {
+            transforms: Some(vec!["normalize".to_string()]),
+            ..val.prefix
+        };
+        let mut bytes = current.to_db_bytes()?;
+        bytes.extend_from_slice(br#"{"a":1}"#);
+        let (val, _) = RecordLocationVal::from_db_bytes(&bytes)?;
+        assert_eq!(val.prefix.redaction_version, Some(3));
+        assert_eq!(val.prefix.diff, Some(r#"{"a":1}"#.to_string()));
+        assert_eq!(val.prefix.transforms, Some(vec!["normalize".to_string()]));
+        assert_eq!(val.suffix, RecordRawValue(br#"{"a":1}"#.to_vec()));
+        Ok(())
+    }
+
     #[test]
     fn test_by_hourly_rollup_value() -> Result<(), EncodingError> {
-        let mut estimator = Sketch::<14>::default();
+        let mut estimator = DidsSketch::default();
         fn to_element(d: Did) -> Element<14> {
             Element::from_digest_oneshot::<Sha256>(d.to_string().as_bytes())
         }
@@ -745,6 +1264,35 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_core_decodes_rollups() -> Result<(), EncodingError> {
+        let nsid = Nsid::new("ab.cd.efg".to_string()).unwrap();
+        let hour =
+            HourTruncatedCursor::truncate_cursor(Cursor::from_raw_u64(1_743_778_483_483_895));
+        let key = HourlyRollupKey::new(hour, &nsid).to_db_bytes()?;
+        let decoded = ufos_core::RollupKey::from_bytes(&key).unwrap();
+        assert_eq!(
+            decoded,
+            Some(ufos_core::RollupKey::Hourly {
+                hour: Cursor::from(hour).to_raw_u64(),
+                nsid: "ab.cd.efg".to_string(),
+            })
+        );
+
+        let mut estimator = DidsSketch::default();
+        estimator.insert(Element::from_digest_oneshot::<Sha256>(b"did:plc:a"));
+        let counts = CommitCounts {
+            creates: 2,
+            deletes: 1,
+            ..Default::default()
+        };
+        let value = CountsValue::new(counts, estimator.clone()).to_db_bytes()?;
+        let decoded = ufos_core::RollupCounts::from_bytes(&value).unwrap();
+        assert_eq!(decoded.counts, counts);
+        assert_eq!(decoded.dids, estimator);
+        Ok(())
+    }
+
     #[test]
     fn test_hour_truncated_cursor() {
         let us = Cursor::from_raw_u64(1_743_778_483_483_895);
@@ -765,6 +1313,44 @@ mod test {
         assert_eq!(diff, 0);
     }
 
+    #[test]
+    fn test_hour_truncated_cursor_from_raw() {
+        let hour = 1_743_775_200_000_000;
+        assert_eq!(
+            HourTruncatedCursor::try_from(hour).map(u64::from).unwrap(),
+            hour
+        );
+        assert!(matches!(
+            HourTruncatedCursor::try_from(hour + 1),
+            Err(EncodingError::InvalidTruncated(HOUR_IN_MICROS, 1))
+        ));
+    }
+
+    #[test]
+    fn test_hour_window() {
+        let hour = 1_743_775_200_000_000;
+        let window = HourWindow::try_from_raw(Some(hour), Some(hour + HOUR_IN_MICROS)).unwrap();
+        assert_eq!(window.since().map(u64::from), Some(hour));
+        assert_eq!(window.until().map(u64::from), Some(hour + HOUR_IN_MICROS));
+
+        // misaligned raw bounds are refused, but exact times round down
+        assert!(HourWindow::try_from_raw(Some(hour + 1), None).is_err());
+        let window = HourWindow::covering(Some(Cursor::from_raw_u64(hour + 1)), None).unwrap();
+        assert_eq!(window.since().map(u64::from), Some(hour));
+
+        // empty is fine, backwards isn't
+        assert!(HourWindow::try_from_raw(Some(hour), Some(hour)).is_ok());
+        assert!(HourWindow::try_from_raw(Some(hour + HOUR_IN_MICROS), Some(hour)).is_err());
+        assert!(HourWindow::covering(
+            Some(Cursor::from_raw_u64(hour + HOUR_IN_MICROS)),
+            Some(Cursor::from_raw_u64(hour + HOUR_IN_MICROS - 1)),
+        )
+        .is_err());
+
+        assert_eq!(HourWindow::all_time().since(), None);
+        assert_eq!(HourWindow::all_time().until(), None);
+    }
+
     #[test]
     fn test_spanning_nothing() {
         let from = Cursor::from_raw_u64(1_743_775_200_000_000).into();
@@ -836,4 +1422,28 @@ mod test {
             ]
         );
     }
+
+    #[test]
+    fn test_bucket_start() {
+        const DAY: u64 = HOUR_IN_MICROS * 24;
+        let t = HourTruncatedCursor::truncate_raw_u64(3 * DAY + 13 * HOUR_IN_MICROS);
+
+        assert_eq!(t.bucket_start(DAY, 0).unwrap().to_raw_u64(), 3 * DAY);
+
+        // local midnight at UTC-5 is 05:00 UTC
+        let minus_five = 5 * HOUR_IN_MICROS as i64;
+        assert_eq!(
+            t.bucket_start(DAY, minus_five).unwrap().to_raw_u64(),
+            3 * DAY + 5 * HOUR_IN_MICROS
+        );
+
+        // local midnight at UTC+14 is 10:00 UTC the day before
+        let plus_fourteen = -14 * HOUR_IN_MICROS as i64;
+        assert_eq!(
+            t.bucket_start(DAY, plus_fourteen).unwrap().to_raw_u64(),
+            3 * DAY + 10 * HOUR_IN_MICROS
+        );
+
+        assert!(t.bucket_start(DAY, 1).is_err());
+    }
 }
@@ -0,0 +1,340 @@
+use crate::error::TransformConfigError;
+use crate::redaction::JsonPath;
+use crate::{Nsid, NsidPrefix};
+use serde::Deserialize;
+use serde_json::value::RawValue;
+use serde_json::{Map, Value};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+/// Transform config, as loaded from a json file
+///
+/// ```json
+/// {
+///   "rules": [
+///     {
+///       "collection": "com.example.profile",
+///       "transforms": [
+///         { "type": "keep_fields", "fields": ["displayName", "description"] },
+///         { "type": "cap_size", "max_bytes": 4096 }
+///       ]
+///     },
+///     {
+///       "collection": "com.example.forms.*",
+///       "transforms": [
+///         { "type": "redact", "paths": ["answers.*.email"] },
+///         { "type": "normalize" }
+///       ]
+///     }
+///   ]
+/// }
+/// ```
+///
+/// Transforms run in order, after redaction. When several rules match a
+/// collection, the exact rule's transforms run first, then groups' in config
+/// order. The names of the transforms that ran are stored with each record.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TransformConfig {
+    pub rules: Vec<TransformRule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TransformRule {
+    /// Either an exact collection NSID, or a group prefix ending with `.*`
+    pub collection: String,
+    pub transforms: Vec<TransformSpec>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TransformSpec {
+    /// Remove dot-separated paths, like a redaction rule
+    Redact { paths: Vec<String> },
+    /// Remove every top-level field except these (`$type` is always kept)
+    KeepFields { fields: Vec<String> },
+    /// Replace records over this size with a stub: just their `$type` and
+    /// `$capped` with the original size in bytes
+    CapSize { max_bytes: usize },
+    /// Re-encode as compact json with object keys sorted
+    Normalize,
+}
+
+impl TransformConfig {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, TransformConfigError> {
+        let f = std::fs::File::open(path)?;
+        Ok(serde_json::from_reader(std::io::BufReader::new(f))?)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Transform {
+    Redact(Vec<JsonPath>),
+    KeepFields(HashSet<String>),
+    CapSize(usize),
+    Normalize,
+}
+
+impl Transform {
+    fn new(spec: TransformSpec) -> Result<Self, TransformConfigError> {
+        Ok(match spec {
+            TransformSpec::Redact { paths } => Self::Redact(
+                paths
+                    .iter()
+                    .map(|p| {
+                        JsonPath::parse(p).map_err(|_| TransformConfigError::BadPath(p.clone()))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?,
+            ),
+            TransformSpec::KeepFields { fields } => Self::KeepFields(fields.into_iter().collect()),
+            TransformSpec::CapSize { max_bytes } => {
+                if max_bytes == 0 {
+                    return Err(TransformConfigError::ZeroCapSize);
+                }
+                Self::CapSize(max_bytes)
+            }
+            TransformSpec::Normalize => Self::Normalize,
+        })
+    }
+    /// The name stored in record metadata
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Redact(_) => "redact",
+            Self::KeepFields(_) => "keep_fields",
+            Self::CapSize(_) => "cap_size",
+            Self::Normalize => "normalize",
+        }
+    }
+    /// Returns true if the value (or how it will be encoded) changed
+    fn apply(&self, value: &mut Value, size: usize) -> bool {
+        match self {
+            Self::Redact(paths) => paths
+                .iter()
+                .fold(false, |removed, path| path.remove_from(value) || removed),
+            Self::KeepFields(fields) => {
+                let Value::Object(o) = value else {
+                    return false;
+                };
+                let before = o.len();
+                o.retain(|k, _| k == "$type" || fields.contains(k));
+                o.len() < before
+            }
+            Self::CapSize(max_bytes) => {
+                if size <= *max_bytes {
+                    return false;
+                }
+                let mut stub = Map::new();
+                if let Some(t) = value.get("$type") {
+                    stub.insert("$type".to_string(), t.clone());
+                }
+                stub.insert("$capped".to_string(), size.into());
+                *value = Value::Object(stub);
+                true
+            }
+            Self::Normalize => {
+                sort_keys(value);
+                true
+            }
+        }
+    }
+}
+
+/// Sort object keys everywhere, regardless of how serde_json's map is ordered
+fn sort_keys(value: &mut Value) {
+    match value {
+        Value::Object(o) => {
+            let mut entries: Vec<_> = std::mem::take(o).into_iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            for (k, mut v) in entries {
+                sort_keys(&mut v);
+                o.insert(k, v);
+            }
+        }
+        Value::Array(a) => a.iter_mut().for_each(sort_keys),
+        _ => {}
+    }
+}
+
+/// Compiled transform rules, applied to records before they are stored
+#[derive(Debug, Default, Clone)]
+pub struct Transformer {
+    exact: HashMap<String, Vec<Transform>>,
+    prefixes: Vec<(String, Vec<Transform>)>,
+}
+
+impl Transformer {
+    pub fn new(config: TransformConfig) -> Result<Self, TransformConfigError> {
+        let mut me = Self::default();
+        for rule in config.rules {
+            let transforms = rule
+                .transforms
+                .into_iter()
+                .map(Transform::new)
+                .collect::<Result<Vec<_>, _>>()?;
+            if let Some(prefix) = rule.collection.strip_suffix(".*") {
+                NsidPrefix::new(prefix)?;
+                // keep the dot so that we only match full segments
+                let prefix = format!("{prefix}.");
+                me.prefixes.push((prefix, transforms));
+            } else {
+                Nsid::new(rule.collection.clone())
+                    .map_err(|e| TransformConfigError::BadCollection(rule.collection.clone(), e))?;
+                me.exact
+                    .entry(rule.collection)
+                    .or_default()
+                    .extend(transforms);
+            }
+        }
+        Ok(me)
+    }
+    fn transforms_for<'a>(&'a self, collection: &'a Nsid) -> impl Iterator<Item = &'a Transform> {
+        let exact = self.exact.get(collection.as_str()).into_iter().flatten();
+        let prefixed = self
+            .prefixes
+            .iter()
+            .filter(|(prefix, _)| collection.as_str().starts_with(prefix))
+            .flat_map(|(_, transforms)| transforms);
+        exact.chain(prefixed)
+    }
+    /// Check if any transforms apply to a collection
+    pub fn applies_to(&self, collection: &Nsid) -> bool {
+        self.transforms_for(collection).next().is_some()
+    }
+    /// Run a collection's transforms over a record
+    ///
+    /// Returns `None` if none apply, otherwise the (possibly unchanged)
+    /// record along with the names of the transforms that ran, in order.
+    pub fn transform(
+        &self,
+        collection: &Nsid,
+        record: &RawValue,
+    ) -> Result<Option<(Box<RawValue>, Vec<String>)>, serde_json::Error> {
+        if !self.applies_to(collection) {
+            return Ok(None);
+        }
+        let mut value: Value = serde_json::from_str(record.get())?;
+        let mut size = record.get().len();
+        let mut changed = false;
+        let mut applied = vec![];
+        for transform in self.transforms_for(collection) {
+            if transform.apply(&mut value, size) {
+                changed = true;
+                size = serde_json::to_string(&value)?.len();
+            }
+            applied.push(transform.name().to_string());
+        }
+        let transformed = if changed {
+            serde_json::value::to_raw_value(&value)?
+        } else {
+            record.to_owned()
+        };
+        Ok(Some((transformed, applied)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nsid(s: &str) -> Nsid {
+        Nsid::new(s.to_string()).unwrap()
+    }
+
+    fn transformer(config: &str) -> Transformer {
+        Transformer::new(serde_json::from_str(config).unwrap()).unwrap()
+    }
+
+    fn transform(t: &Transformer, collection: &str, record: &str) -> Option<(String, Vec<String>)> {
+        let raw = RawValue::from_string(record.to_string()).unwrap();
+        t.transform(&nsid(collection), &raw)
+            .unwrap()
+            .map(|(v, applied)| (v.get().to_string(), applied))
+    }
+
+    #[test]
+    fn test_bad_config() {
+        let bad = |config: &str| {
+            Transformer::new(serde_json::from_str::<TransformConfig>(config).unwrap()).is_err()
+        };
+        assert!(!bad(
+            r#"{"rules": [{"collection": "a.b.c", "transforms": [{"type": "normalize"}]}]}"#
+        ));
+        assert!(bad(
+            r#"{"rules": [{"collection": "a.*", "transforms": [{"type": "normalize"}]}]}"#
+        ));
+        assert!(bad(
+            r#"{"rules": [{"collection": "a.b.c", "transforms": [{"type": "redact", "paths": ["a..b"]}]}]}"#
+        ));
+        assert!(bad(
+            r#"{"rules": [{"collection": "a.b.c", "transforms": [{"type": "cap_size", "max_bytes": 0}]}]}"#
+        ));
+        assert!(serde_json::from_str::<TransformConfig>(
+            r#"{"rules": [{"collection": "a.b.c", "transforms": [{"type": "nope"}]}]}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_transforms_in_order() {
+        let t = transformer(
+            r#"{"rules": [
+                {"collection": "a.b.*", "transforms": [{"type": "cap_size", "max_bytes": 40}]},
+                {"collection": "a.b.c", "transforms": [
+                    {"type": "redact", "paths": ["contact.email"]},
+                    {"type": "keep_fields", "fields": ["contact", "text"]}
+                ]}
+            ]}"#,
+        );
+        let applied = vec![
+            "redact".to_string(),
+            "keep_fields".to_string(),
+            "cap_size".to_string(),
+        ];
+        assert_eq!(
+            transform(
+                &t,
+                "a.b.c",
+                r#"{"$type":"a.b.c","contact":{"email":"x","city":"c"},"extra":1}"#
+            ),
+            Some((
+                r#"{"$type":"a.b.c","contact":{"city":"c"}}"#.to_string(),
+                applied.clone()
+            ))
+        );
+        // cap_size sees the size after the earlier transforms
+        assert_eq!(
+            transform(
+                &t,
+                "a.b.c",
+                r#"{"$type":"a.b.c","text":"long enough to get capped","extra":1}"#
+            ),
+            Some((r#"{"$type":"a.b.c","$capped":52}"#.to_string(), applied))
+        );
+    }
+
+    #[test]
+    fn test_normalize() {
+        let t = transformer(
+            r#"{"rules": [{"collection": "a.b.c", "transforms": [{"type": "normalize"}]}]}"#,
+        );
+        assert_eq!(
+            transform(&t, "a.b.c", r#"{ "b": [ {"z": 1, "y": 2} ], "a": 1 }"#),
+            Some((
+                r#"{"a":1,"b":[{"y":2,"z":1}]}"#.to_string(),
+                vec!["normalize".to_string()]
+            ))
+        );
+    }
+
+    #[test]
+    fn test_unmatched_unchanged() {
+        let t = transformer(
+            r#"{"rules": [{"collection": "a.b.c", "transforms": [{"type": "cap_size", "max_bytes": 100}]}]}"#,
+        );
+        assert_eq!(transform(&t, "a.b.d", r#"{"a": 1}"#), None);
+        // transforms ran but changed nothing: original bytes are kept
+        assert_eq!(
+            transform(&t, "a.b.c", r#"{"a": 1}"#),
+            Some((r#"{"a": 1}"#.to_string(), vec!["cap_size".to_string()]))
+        );
+    }
+}
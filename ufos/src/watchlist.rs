@@ -0,0 +1,335 @@
+use crate::db_types::{EncodingError, EncodingResult};
+use crate::error::WatchlistConfigError;
+use crate::{Nsid, NsidPrefix};
+use regex::RegexSet;
+use serde::Deserialize;
+use serde_json::value::RawValue;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::path::Path;
+use std::time::Duration;
+
+/// How long hits are kept by default
+pub const DEFAULT_HIT_RETENTION_DAYS: u64 = 7;
+
+const MAX_NAME_LEN: usize = 64;
+
+/// Watchlist config, as loaded from a json file
+///
+/// ```json
+/// {
+///   "watchlists": [
+///     {
+///       "name": "giveaway-scams",
+///       "collection": "app.bsky.feed.*",
+///       "keywords": ["free crypto", "claim your airdrop"],
+///       "regexes": ["(?i)dm me .* (usdt|btc)"]
+///     }
+///   ],
+///   "retention_days": 7
+/// }
+/// ```
+///
+/// Keywords match case-insensitively anywhere in any string value of a
+/// record, and regexes are tried against each string value. Matching happens
+/// on records as they will be stored, after redaction and transforms.
+#[derive(Debug, Clone, Deserialize)]
+pub struct WatchlistConfig {
+    pub watchlists: Vec<WatchlistRule>,
+    /// How long to keep hits. Hourly counts are kept forever.
+    ///
+    /// default: 7
+    pub retention_days: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WatchlistRule {
+    /// Identifies the watchlist in the api: lowercase letters, digits, `-` and `_`
+    pub name: String,
+    /// Either an exact collection NSID, or a group prefix ending with `.*`
+    pub collection: String,
+    #[serde(default)]
+    pub keywords: Vec<String>,
+    #[serde(default)]
+    pub regexes: Vec<String>,
+}
+
+impl WatchlistConfig {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, WatchlistConfigError> {
+        let f = std::fs::File::open(path)?;
+        Ok(serde_json::from_reader(std::io::BufReader::new(f))?)
+    }
+}
+
+/// A record matched a watchlist
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatchMatch {
+    pub name: String,
+    /// The keywords and regexes that matched, as configured
+    pub matched: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum CollectionMatch {
+    Exact(String),
+    /// with the trailing dot, so that only full segments match
+    Group(String),
+}
+
+impl CollectionMatch {
+    /// Either an exact collection NSID, or a group prefix ending with `.*`
+    pub(crate) fn new(pattern: &str) -> EncodingResult<Self> {
+        if let Some(prefix) = pattern.strip_suffix(".*") {
+            NsidPrefix::new(prefix)?;
+            Ok(Self::Group(format!("{prefix}.")))
+        } else {
+            Nsid::new(pattern.to_string()).map_err(EncodingError::BadAtriumStringType)?;
+            Ok(Self::Exact(pattern.to_string()))
+        }
+    }
+    pub(crate) fn matches(&self, collection: &Nsid) -> bool {
+        match self {
+            Self::Exact(c) => collection.as_str() == c,
+            Self::Group(prefix) => collection.as_str().starts_with(prefix),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Watchlist {
+    name: String,
+    collection: CollectionMatch,
+    /// (as configured, lowercased)
+    keywords: Vec<(String, String)>,
+    regexes: RegexSet,
+}
+
+impl Watchlist {
+    fn new(rule: WatchlistRule) -> Result<Self, WatchlistConfigError> {
+        let name_ok = !rule.name.is_empty()
+            && rule.name.len() <= MAX_NAME_LEN
+            && rule
+                .name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+        if !name_ok {
+            return Err(WatchlistConfigError::BadName(rule.name));
+        }
+        let collection = if let Some(prefix) = rule.collection.strip_suffix(".*") {
+            NsidPrefix::new(prefix)?;
+            CollectionMatch::Group(format!("{prefix}."))
+        } else {
+            Nsid::new(rule.collection.clone())
+                .map_err(|e| WatchlistConfigError::BadCollection(rule.collection.clone(), e))?;
+            CollectionMatch::Exact(rule.collection)
+        };
+        if rule.keywords.iter().any(|k| k.is_empty()) {
+            return Err(WatchlistConfigError::EmptyKeyword(rule.name));
+        }
+        if rule.keywords.is_empty() && rule.regexes.is_empty() {
+            return Err(WatchlistConfigError::NothingToMatch(rule.name));
+        }
+        let regexes = RegexSet::new(&rule.regexes)?;
+        let keywords = rule
+            .keywords
+            .into_iter()
+            .map(|k| {
+                let lower = k.to_lowercase();
+                (k, lower)
+            })
+            .collect();
+        Ok(Self {
+            name: rule.name,
+            collection,
+            keywords,
+            regexes,
+        })
+    }
+
+    /// The keywords and regexes that match any of the strings
+    fn check(&self, strings: &[(&str, String)]) -> Vec<String> {
+        let mut matched = vec![];
+        for (keyword, lower) in &self.keywords {
+            if strings.iter().any(|(_, s)| s.contains(lower.as_str())) {
+                matched.push(keyword.clone());
+            }
+        }
+        let mut hit_regexes = HashSet::new();
+        for (s, _) in strings {
+            hit_regexes.extend(self.regexes.matches(s).iter());
+        }
+        let mut hit_regexes: Vec<_> = hit_regexes.into_iter().collect();
+        hit_regexes.sort();
+        matched.extend(
+            hit_regexes
+                .into_iter()
+                .map(|i| self.regexes.patterns()[i].clone()),
+        );
+        matched
+    }
+}
+
+/// Collect every string value in a record, skipping `$type`s
+fn collect_strings<'a>(value: &'a Value, out: &mut Vec<(&'a str, String)>) {
+    match value {
+        Value::String(s) => out.push((s, s.to_lowercase())),
+        Value::Array(a) => a.iter().for_each(|v| collect_strings(v, out)),
+        Value::Object(o) => o
+            .iter()
+            .filter(|(k, _)| *k != "$type")
+            .for_each(|(_, v)| collect_strings(v, out)),
+        _ => {}
+    }
+}
+
+/// Compiled watchlists, checked against records as they are stored
+#[derive(Debug, Clone)]
+pub struct Watchlists {
+    lists: Vec<Watchlist>,
+    retention: Duration,
+}
+
+impl Watchlists {
+    pub fn new(config: WatchlistConfig) -> Result<Self, WatchlistConfigError> {
+        let mut names = HashSet::new();
+        let mut lists = vec![];
+        for rule in config.watchlists {
+            if !names.insert(rule.name.clone()) {
+                return Err(WatchlistConfigError::DuplicateName(rule.name));
+            }
+            lists.push(Watchlist::new(rule)?);
+        }
+        let days = config.retention_days.unwrap_or(DEFAULT_HIT_RETENTION_DAYS);
+        if days == 0 {
+            return Err(WatchlistConfigError::ZeroRetention);
+        }
+        Ok(Self {
+            lists,
+            retention: Duration::from_secs(days * 86_400),
+        })
+    }
+    /// The names of all configured watchlists, in config order
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.lists.iter().map(|l| l.name.as_str())
+    }
+    /// How long to keep hits
+    pub fn retention(&self) -> Duration {
+        self.retention
+    }
+    /// Check if any watchlists apply to a collection
+    pub fn watches(&self, collection: &Nsid) -> bool {
+        self.lists.iter().any(|l| l.collection.matches(collection))
+    }
+    /// Check a record against the collection's watchlists
+    ///
+    /// Returns the watchlists that matched, in config order.
+    pub fn check(
+        &self,
+        collection: &Nsid,
+        record: &RawValue,
+    ) -> Result<Vec<WatchMatch>, serde_json::Error> {
+        if !self.watches(collection) {
+            return Ok(vec![]);
+        }
+        let value: Value = serde_json::from_str(record.get())?;
+        let mut strings = vec![];
+        collect_strings(&value, &mut strings);
+        Ok(self
+            .lists
+            .iter()
+            .filter(|l| l.collection.matches(collection))
+            .filter_map(|l| {
+                let matched = l.check(&strings);
+                (!matched.is_empty()).then(|| WatchMatch {
+                    name: l.name.clone(),
+                    matched,
+                })
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nsid(s: &str) -> Nsid {
+        Nsid::new(s.to_string()).unwrap()
+    }
+
+    fn watchlists(config: &str) -> Watchlists {
+        Watchlists::new(serde_json::from_str(config).unwrap()).unwrap()
+    }
+
+    fn check(w: &Watchlists, collection: &str, record: &str) -> Vec<WatchMatch> {
+        let raw = RawValue::from_string(record.to_string()).unwrap();
+        w.check(&nsid(collection), &raw).unwrap()
+    }
+
+    #[test]
+    fn test_bad_config() {
+        let bad = |config: &str| {
+            Watchlists::new(serde_json::from_str::<WatchlistConfig>(config).unwrap()).is_err()
+        };
+        assert!(!bad(
+            r#"{"watchlists": [{"name": "ok", "collection": "a.b.c", "keywords": ["x"]}]}"#
+        ));
+        assert!(bad(
+            r#"{"watchlists": [{"name": "Not OK", "collection": "a.b.c", "keywords": ["x"]}]}"#
+        ));
+        assert!(bad(
+            r#"{"watchlists": [{"name": "ok", "collection": "a.*", "keywords": ["x"]}]}"#
+        ));
+        assert!(bad(
+            r#"{"watchlists": [{"name": "ok", "collection": "a.b.c"}]}"#
+        ));
+        assert!(bad(
+            r#"{"watchlists": [{"name": "ok", "collection": "a.b.c", "keywords": [""]}]}"#
+        ));
+        assert!(bad(
+            r#"{"watchlists": [{"name": "ok", "collection": "a.b.c", "regexes": ["("]}]}"#
+        ));
+        assert!(bad(r#"{"watchlists": [
+                {"name": "ok", "collection": "a.b.c", "keywords": ["x"]},
+                {"name": "ok", "collection": "a.b.d", "keywords": ["y"]}
+            ]}"#));
+        assert!(bad(
+            r#"{"watchlists": [{"name": "ok", "collection": "a.b.c", "keywords": ["x"]}], "retention_days": 0}"#
+        ));
+    }
+
+    #[test]
+    fn test_matches() {
+        let w = watchlists(
+            r#"{"watchlists": [
+                {"name": "crypto", "collection": "a.b.*", "keywords": ["Free Crypto"], "regexes": ["\\busdt\\b"]},
+                {"name": "exact", "collection": "a.b.c", "regexes": ["^hello"]}
+            ]}"#,
+        );
+        assert_eq!(
+            check(
+                &w,
+                "a.b.c",
+                r#"{"$type":"a.b.c","text":"get FREE crypto now","tags":["send usdt"]}"#
+            ),
+            vec![WatchMatch {
+                name: "crypto".to_string(),
+                matched: vec!["Free Crypto".to_string(), "\\busdt\\b".to_string()],
+            }]
+        );
+        assert_eq!(
+            check(&w, "a.b.c", r#"{"nested":{"deep":["hello there"]}}"#),
+            vec![WatchMatch {
+                name: "exact".to_string(),
+                matched: vec!["^hello".to_string()],
+            }]
+        );
+        // regexes are case-sensitive unless they say otherwise
+        assert_eq!(check(&w, "a.b.c", r#"{"text":"Hello"}"#), vec![]);
+        // keys and $type are not matched
+        assert_eq!(check(&w, "a.b.c", r#"{"$type":"hello","hello":1}"#), vec![]);
+        // only full segments of groups match
+        assert!(!w.watches(&nsid("a.bc.d")));
+        assert_eq!(check(&w, "a.b.d", r#"{"text":"hello usdt"}"#).len(), 1);
+    }
+}
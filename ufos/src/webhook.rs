@@ -0,0 +1,470 @@
+//! Push sampled records to external URLs
+//!
+//! A lightweight alternative to running a firehose consumer: each hook gets
+//! the records stored for its collections, batched into signed json POSTs.
+
+use crate::error::WebhookConfigError;
+use crate::plugin::{IngestPlugin, StoredBatch};
+use crate::watchlist::CollectionMatch;
+use crate::Nsid;
+use hmac::{Hmac, Mac};
+use metrics::{counter, describe_counter, Unit};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use sha2::Sha256;
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::{channel, error::TrySendError, Receiver, Sender};
+use tokio::task::JoinSet;
+
+const DEFAULT_MAX_BATCH: usize = 100;
+const DEFAULT_MAX_WAIT: Duration = Duration::from_secs(10);
+/// Records waiting for delivery per hook, before new ones get dropped
+const QUEUE_SIZE: usize = 10_000;
+const MAX_ATTEMPTS: u32 = 6;
+const MIN_RETRY_WAIT: Duration = Duration::from_secs(1);
+const MAX_RETRY_WAIT: Duration = Duration::from_secs(60);
+
+/// Webhook config, as loaded from a json file
+///
+/// ```json
+/// {
+///   "hooks": [
+///     {
+///       "name": "profiles",
+///       "collection": "app.bsky.actor.profile",
+///       "url": "https://example.com/ufos-hook",
+///       "secret": "shared with the receiver",
+///       "max_batch": 50,
+///       "max_wait_secs": 5
+///     }
+///   ]
+/// }
+/// ```
+///
+/// Records are delivered as they are stored (after redaction and transforms),
+/// as `{"hook": name, "records": [{did, collection, rkey, rev, time_us, record}]}`.
+///
+/// With a secret, each POST has an `x-ufos-timestamp` header (unix seconds)
+/// and an `x-ufos-signature` header: `sha256=` followed by the hex
+/// HMAC-SHA256 of the timestamp, a `.`, and the body.
+///
+/// Network errors, 5xx and 429 responses are retried with backoff a few
+/// times before the batch is dropped. Delivery is best-effort: a hook that
+/// falls too far behind has new records dropped, and queued records are lost
+/// on restart.
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebhookConfig {
+    pub hooks: Vec<WebhookRule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebhookRule {
+    /// Identifies the hook in payloads, logs, and metrics
+    pub name: String,
+    /// Either an exact collection NSID, or a group prefix ending with `.*`
+    pub collection: String,
+    pub url: String,
+    /// Sign payloads with this, if set
+    pub secret: Option<String>,
+    /// Most records per POST
+    ///
+    /// default: 100
+    pub max_batch: Option<usize>,
+    /// Longest to hold records back while filling a batch
+    ///
+    /// default: 10
+    pub max_wait_secs: Option<u64>,
+}
+
+impl WebhookConfig {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, WebhookConfigError> {
+        let f = std::fs::File::open(path)?;
+        Ok(serde_json::from_reader(std::io::BufReader::new(f))?)
+    }
+}
+
+/// A stored record, as delivered to hooks
+#[derive(Debug, Clone, Serialize)]
+pub struct WebhookRecord {
+    pub did: String,
+    pub collection: String,
+    pub rkey: String,
+    pub rev: String,
+    pub time_us: u64,
+    pub record: Box<RawValue>,
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    hook: &'a str,
+    records: &'a [WebhookRecord],
+}
+
+#[derive(Debug)]
+struct TapHook {
+    name: String,
+    collection: CollectionMatch,
+    sender: Sender<WebhookRecord>,
+}
+
+/// Hands stored records over to the hooks for their collections
+///
+/// Cheap to clone. Deliveries end once every clone is dropped.
+#[derive(Debug, Clone)]
+pub struct WebhookTap(Arc<Vec<TapHook>>);
+
+impl WebhookTap {
+    /// Check if any hooks want records from a collection
+    pub fn watches(&self, collection: &Nsid) -> bool {
+        self.0.iter().any(|h| h.collection.matches(collection))
+    }
+    /// Queue a record for each matching hook
+    ///
+    /// Never waits: if a hook's queue is full, the record is dropped for it.
+    pub fn offer(&self, collection: &Nsid, record: WebhookRecord) {
+        for hook in self.0.iter().filter(|h| h.collection.matches(collection)) {
+            match hook.sender.try_send(record.clone()) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    counter!("webhook_dropped_records", "hook" => hook.name.clone(), "reason" => "queue_full")
+                        .increment(1);
+                }
+                Err(TrySendError::Closed(_)) => {
+                    counter!("webhook_dropped_records", "hook" => hook.name.clone(), "reason" => "closed")
+                        .increment(1);
+                }
+            }
+        }
+    }
+}
+
+/// Offers records from each committed batch to the hooks watching their collection
+impl IngestPlugin for WebhookTap {
+    fn name(&self) -> &'static str {
+        "webhooks"
+    }
+    fn wants_stored(&self) -> bool {
+        true
+    }
+    fn stored(&self, batch: &StoredBatch) {
+        for r in batch.records.iter().filter(|r| self.watches(&r.collection)) {
+            self.offer(
+                &r.collection,
+                WebhookRecord {
+                    did: r.did.to_string(),
+                    collection: r.collection.to_string(),
+                    rkey: r.rkey.to_string(),
+                    rev: r.rev.clone(),
+                    time_us: r.cursor.to_raw_u64(),
+                    record: r.record.clone(),
+                },
+            );
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Delivery {
+    name: String,
+    url: reqwest::Url,
+    secret: Option<String>,
+    max_batch: usize,
+    max_wait: Duration,
+    receiver: Receiver<WebhookRecord>,
+}
+
+enum DeliveryFailure {
+    Retry(String),
+    GiveUp(String),
+}
+
+/// Configured webhooks: a tap for the writer, and their deliveries to run
+#[derive(Debug)]
+pub struct Webhooks {
+    tap: WebhookTap,
+    deliveries: Vec<Delivery>,
+}
+
+impl Webhooks {
+    pub fn new(config: WebhookConfig) -> Result<Self, WebhookConfigError> {
+        let mut names = HashSet::new();
+        let mut tap = vec![];
+        let mut deliveries = vec![];
+        for rule in config.hooks {
+            if rule.name.is_empty() {
+                return Err(WebhookConfigError::EmptyName);
+            }
+            if !names.insert(rule.name.clone()) {
+                return Err(WebhookConfigError::DuplicateName(rule.name));
+            }
+            let collection = CollectionMatch::new(&rule.collection)
+                .map_err(|e| WebhookConfigError::BadCollection(rule.collection.clone(), e))?;
+            let url = reqwest::Url::parse(&rule.url)
+                .map_err(|e| WebhookConfigError::BadUrl(rule.url.clone(), e.to_string()))?;
+            if !matches!(url.scheme(), "http" | "https") {
+                return Err(WebhookConfigError::BadScheme(rule.url));
+            }
+            let max_batch = rule.max_batch.unwrap_or(DEFAULT_MAX_BATCH);
+            if max_batch == 0 {
+                return Err(WebhookConfigError::ZeroBatch(rule.name));
+            }
+            let (sender, receiver) = channel(QUEUE_SIZE);
+            tap.push(TapHook {
+                name: rule.name.clone(),
+                collection,
+                sender,
+            });
+            deliveries.push(Delivery {
+                name: rule.name,
+                url,
+                secret: rule.secret,
+                max_batch,
+                max_wait: rule
+                    .max_wait_secs
+                    .map(Duration::from_secs)
+                    .unwrap_or(DEFAULT_MAX_WAIT),
+                receiver,
+            });
+        }
+        Ok(Self {
+            tap: WebhookTap(Arc::new(tap)),
+            deliveries,
+        })
+    }
+    /// For the writer to offer stored records to
+    pub fn tap(&self) -> WebhookTap {
+        self.tap.clone()
+    }
+    /// Deliver records to every hook until the taps are all dropped
+    pub async fn deliver(self) -> anyhow::Result<()> {
+        describe_counter!(
+            "webhook_delivered_records",
+            Unit::Count,
+            "records successfully posted to webhooks"
+        );
+        describe_counter!(
+            "webhook_dropped_records",
+            Unit::Count,
+            "records never delivered to a webhook, by reason"
+        );
+        describe_counter!(
+            "webhook_failed_posts",
+            Unit::Count,
+            "webhook POST attempts that failed (including ones retried later)"
+        );
+        let client = reqwest::Client::builder()
+            .user_agent(concat!("ufos/", env!("CARGO_PKG_VERSION"), " (webhook)"))
+            .timeout(Duration::from_secs(30))
+            .build()?;
+        // our own tap clone would keep the deliveries waiting forever
+        drop(self.tap);
+        let mut tasks = JoinSet::new();
+        for delivery in self.deliveries {
+            log::info!("delivering webhook {:?} to {}", delivery.name, delivery.url);
+            tasks.spawn(delivery.run(client.clone()));
+        }
+        tasks.join_all().await;
+        Ok(())
+    }
+}
+
+impl Delivery {
+    async fn run(mut self, client: reqwest::Client) {
+        let mut batch = Vec::with_capacity(self.max_batch);
+        while let Some(first) = self.receiver.recv().await {
+            batch.push(first);
+            let deadline = tokio::time::Instant::now() + self.max_wait;
+            while batch.len() < self.max_batch {
+                match tokio::time::timeout_at(deadline, self.receiver.recv()).await {
+                    Ok(Some(record)) => batch.push(record),
+                    Ok(None) | Err(_) => break,
+                }
+            }
+            self.send(&client, &batch).await;
+            batch.clear();
+        }
+        log::info!("webhook {:?}: no more records to deliver", self.name);
+    }
+
+    async fn send(&self, client: &reqwest::Client, records: &[WebhookRecord]) {
+        let body = match serde_json::to_vec(&Payload {
+            hook: &self.name,
+            records,
+        }) {
+            Ok(body) => body,
+            Err(e) => {
+                log::error!("webhook {:?}: failed to encode payload: {e}", self.name);
+                return;
+            }
+        };
+        let mut retry_wait = MIN_RETRY_WAIT;
+        for attempt in 1..=MAX_ATTEMPTS {
+            let failure = match self.post(client, body.clone()).await {
+                Ok(()) => {
+                    counter!("webhook_delivered_records", "hook" => self.name.clone())
+                        .increment(records.len() as u64);
+                    return;
+                }
+                Err(failure) => failure,
+            };
+            counter!("webhook_failed_posts", "hook" => self.name.clone()).increment(1);
+            match failure {
+                DeliveryFailure::GiveUp(e) => {
+                    log::warn!("webhook {:?}: giving up on a batch: {e}", self.name);
+                    break;
+                }
+                DeliveryFailure::Retry(e) if attempt < MAX_ATTEMPTS => {
+                    log::warn!(
+                        "webhook {:?}: attempt {attempt} failed, retrying in {retry_wait:?}: {e}",
+                        self.name
+                    );
+                    tokio::time::sleep(retry_wait).await;
+                    retry_wait = (retry_wait * 2).min(MAX_RETRY_WAIT);
+                }
+                DeliveryFailure::Retry(e) => {
+                    log::warn!(
+                        "webhook {:?}: giving up on a batch after {attempt} attempts: {e}",
+                        self.name
+                    );
+                }
+            }
+        }
+        counter!("webhook_dropped_records", "hook" => self.name.clone(), "reason" => "failed")
+            .increment(records.len() as u64);
+    }
+
+    async fn post(&self, client: &reqwest::Client, body: Vec<u8>) -> Result<(), DeliveryFailure> {
+        let mut req = client
+            .post(self.url.clone())
+            .header(CONTENT_TYPE, "application/json");
+        if let Some(secret) = &self.secret {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+                .to_string();
+            let signature = sign(secret.as_bytes(), &timestamp, &body);
+            req = req
+                .header("x-ufos-timestamp", timestamp)
+                .header("x-ufos-signature", signature);
+        }
+        let status = req
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| DeliveryFailure::Retry(e.to_string()))?
+            .status();
+        if status.is_success() {
+            Ok(())
+        } else if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            Err(DeliveryFailure::Retry(format!("response status {status}")))
+        } else {
+            Err(DeliveryFailure::GiveUp(format!("response status {status}")))
+        }
+    }
+}
+
+/// `sha256=` and the hex HMAC-SHA256 of `{timestamp}.{body}`
+fn sign(secret: &[u8], timestamp: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("hmac takes keys of any size");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    let hex: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    format!("sha256={hex}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn webhooks(config: &str) -> Result<Webhooks, WebhookConfigError> {
+        Webhooks::new(serde_json::from_str(config).unwrap())
+    }
+
+    fn record(rkey: &str) -> WebhookRecord {
+        WebhookRecord {
+            did: "did:plc:inze6wrmsm7pjl7yta3oig77".to_string(),
+            collection: "a.b.c".to_string(),
+            rkey: rkey.to_string(),
+            rev: "rev".to_string(),
+            time_us: 1,
+            record: RawValue::from_string("{}".to_string()).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_bad_config() {
+        assert!(webhooks(
+            r#"{"hooks": [{"name": "a", "collection": "a.b.c", "url": "https://example.com/"}]}"#
+        )
+        .is_ok());
+        assert!(webhooks(
+            r#"{"hooks": [{"name": "", "collection": "a.b.c", "url": "https://example.com/"}]}"#
+        )
+        .is_err());
+        assert!(webhooks(
+            r#"{"hooks": [{"name": "a", "collection": "a.*", "url": "https://example.com/"}]}"#
+        )
+        .is_err());
+        assert!(
+            webhooks(r#"{"hooks": [{"name": "a", "collection": "a.b.c", "url": "nope"}]}"#)
+                .is_err()
+        );
+        assert!(webhooks(
+            r#"{"hooks": [{"name": "a", "collection": "a.b.c", "url": "ftp://example.com/"}]}"#
+        )
+        .is_err());
+        assert!(webhooks(r#"{"hooks": [{"name": "a", "collection": "a.b.c", "url": "https://example.com/", "max_batch": 0}]}"#).is_err());
+        assert!(webhooks(
+            r#"{"hooks": [
+                {"name": "a", "collection": "a.b.c", "url": "https://example.com/"},
+                {"name": "a", "collection": "a.b.d", "url": "https://example.com/"}
+            ]}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_tap() {
+        let mut hooks = webhooks(
+            r#"{"hooks": [
+                {"name": "exact", "collection": "a.b.c", "url": "https://example.com/"},
+                {"name": "group", "collection": "a.b.*", "url": "https://example.com/"}
+            ]}"#,
+        )
+        .unwrap();
+        let tap = hooks.tap();
+        let abc = Nsid::new("a.b.c".to_string()).unwrap();
+        let xyz = Nsid::new("x.y.z".to_string()).unwrap();
+        assert!(tap.watches(&abc));
+        assert!(!tap.watches(&xyz));
+        tap.offer(&abc, record("one"));
+        tap.offer(&xyz, record("two"));
+        let exact = &mut hooks.deliveries[0].receiver;
+        assert_eq!(exact.try_recv().unwrap().rkey, "one");
+        assert!(exact.try_recv().is_err());
+        let group = &mut hooks.deliveries[1].receiver;
+        assert_eq!(group.try_recv().unwrap().rkey, "one");
+        assert!(group.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_sign() {
+        // echo -n '1700000000.{}' | openssl dgst -sha256 -hmac secret
+        assert_eq!(
+            sign(b"secret", "1700000000", b"{}"),
+            "sha256=b8569b78799ff9e3cbff0fc2d63a33a2b57f3282abd07c37ae5e8e7d79a5f163"
+        );
+    }
+}